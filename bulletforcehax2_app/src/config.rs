@@ -9,6 +9,20 @@ const ARG_PORT: Opt<u16> = opt("port", 48897);
 const ARG_PROFILE_DIR: Opt<&str> = opt("browser-profile", "bfhax_data/browser_profile");
 const ARG_GAME_DIR: Opt<&str> = opt("game-files", "bfhax_data/game_files");
 const ARG_LOG_DIR: Opt<&str> = opt("logs", "bfhax_data/logs");
+const ARG_ROOM_NOTES: Opt<&str> = opt("room-notes", "bfhax_data/room_notes.json");
+const ARG_MATCH_HISTORY: Opt<&str> = opt("match-history", "bfhax_data/match_history.json");
+const ARG_WATCHLIST: Opt<&str> = opt("watchlist", "bfhax_data/watchlist.json");
+const ARG_SETTINGS_PROFILES: Opt<&str> = opt("settings-profiles", "bfhax_data/profiles");
+const ARG_MAP_ANNOTATIONS: Opt<&str> = opt("map-annotations", "bfhax_data/map_annotations.json");
+const ARG_STREAM_LAYOUTS: Opt<&str> = opt("stream-layouts", "bfhax_data/stream_layouts.json");
+const ARG_POPULATION_HISTORY: Opt<&str> = opt("population-history", "bfhax_data/population");
+const ARG_POPULATION_INTERVAL: Opt<u64> = opt("population-interval-secs", 60);
+const ARG_OTLP_ENDPOINT: Opt<&str> = opt("otlp-endpoint", "");
+const ARG_PUBLIC_FEED: Opt<&str> = opt("public-feed", "");
+const ARG_SLOW_HANDLER: Opt<u64> = opt("slow-handler-ms", 20);
+const ARG_SLOW_TRANSIT: Opt<u64> = opt("slow-transit-ms", 50);
+const ARG_DRY_RUN: Opt<bool> = opt("dry-run", false);
+const ARG_OBSERVE_ONLY: Opt<bool> = opt("observe-only", false);
 const ARG_OPEN_DEVTOOLS: Opt<bool> = opt("open-devtools", false);
 const ARG_HAX: Opt<bool> = opt("hax", false);
 
@@ -19,6 +33,20 @@ pub struct Config {
     pub profile_dir: PathBuf,
     pub game_dir: PathBuf,
     pub log_dir: PathBuf,
+    pub room_notes_file: PathBuf,
+    pub match_history_file: PathBuf,
+    pub watchlist_file: PathBuf,
+    pub settings_profile_dir: PathBuf,
+    pub map_annotations_file: PathBuf,
+    pub stream_layouts_file: PathBuf,
+    pub population_history_dir: PathBuf,
+    pub population_interval_secs: u64,
+    pub otlp_endpoint: String,
+    pub public_feed: String,
+    pub slow_handler_ms: u64,
+    pub slow_transit_ms: u64,
+    pub dry_run: bool,
+    pub observe_only: bool,
     pub open_devtools: bool,
     pub hax: bool,
 }
@@ -44,6 +72,34 @@ pub struct PartialConfig {
     pub game_dir: Option<PathBuf>,
     #[serde(rename = "logs")]
     pub log_dir: Option<PathBuf>,
+    #[serde(rename = "room-notes")]
+    pub room_notes_file: Option<PathBuf>,
+    #[serde(rename = "match-history")]
+    pub match_history_file: Option<PathBuf>,
+    #[serde(rename = "watchlist")]
+    pub watchlist_file: Option<PathBuf>,
+    #[serde(rename = "settings-profiles")]
+    pub settings_profile_dir: Option<PathBuf>,
+    #[serde(rename = "map-annotations")]
+    pub map_annotations_file: Option<PathBuf>,
+    #[serde(rename = "stream-layouts")]
+    pub stream_layouts_file: Option<PathBuf>,
+    #[serde(rename = "population-history")]
+    pub population_history_dir: Option<PathBuf>,
+    #[serde(rename = "population-interval-secs")]
+    pub population_interval_secs: Option<u64>,
+    #[serde(rename = "otlp-endpoint")]
+    pub otlp_endpoint: Option<String>,
+    #[serde(rename = "public-feed")]
+    pub public_feed: Option<String>,
+    #[serde(rename = "slow-handler-ms")]
+    pub slow_handler_ms: Option<u64>,
+    #[serde(rename = "slow-transit-ms")]
+    pub slow_transit_ms: Option<u64>,
+    #[serde(rename = "dry-run")]
+    pub dry_run: Option<bool>,
+    #[serde(rename = "observe-only")]
+    pub observe_only: Option<bool>,
     #[serde(rename = "open-devtools")]
     pub open_devtools: Option<bool>,
     #[serde(rename = "hax")]
@@ -58,6 +114,28 @@ impl Config {
             profile_dir: new.profile_dir.unwrap_or(self.profile_dir),
             game_dir: new.game_dir.unwrap_or(self.game_dir),
             log_dir: new.log_dir.unwrap_or(self.log_dir),
+            room_notes_file: new.room_notes_file.unwrap_or(self.room_notes_file),
+            match_history_file: new.match_history_file.unwrap_or(self.match_history_file),
+            watchlist_file: new.watchlist_file.unwrap_or(self.watchlist_file),
+            settings_profile_dir: new
+                .settings_profile_dir
+                .unwrap_or(self.settings_profile_dir),
+            map_annotations_file: new
+                .map_annotations_file
+                .unwrap_or(self.map_annotations_file),
+            stream_layouts_file: new.stream_layouts_file.unwrap_or(self.stream_layouts_file),
+            population_history_dir: new
+                .population_history_dir
+                .unwrap_or(self.population_history_dir),
+            population_interval_secs: new
+                .population_interval_secs
+                .unwrap_or(self.population_interval_secs),
+            otlp_endpoint: new.otlp_endpoint.unwrap_or(self.otlp_endpoint),
+            public_feed: new.public_feed.unwrap_or(self.public_feed),
+            slow_handler_ms: new.slow_handler_ms.unwrap_or(self.slow_handler_ms),
+            slow_transit_ms: new.slow_transit_ms.unwrap_or(self.slow_transit_ms),
+            dry_run: new.dry_run.unwrap_or(self.dry_run),
+            observe_only: new.observe_only.unwrap_or(self.observe_only),
             open_devtools: new.open_devtools.unwrap_or(self.open_devtools),
             hax: new.hax.unwrap_or(self.hax),
         }
@@ -72,6 +150,20 @@ impl Default for Config {
             profile_dir: PathBuf::from(ARG_PROFILE_DIR.value),
             game_dir: PathBuf::from(ARG_GAME_DIR.value),
             log_dir: PathBuf::from(ARG_LOG_DIR.value),
+            room_notes_file: PathBuf::from(ARG_ROOM_NOTES.value),
+            match_history_file: PathBuf::from(ARG_MATCH_HISTORY.value),
+            watchlist_file: PathBuf::from(ARG_WATCHLIST.value),
+            settings_profile_dir: PathBuf::from(ARG_SETTINGS_PROFILES.value),
+            map_annotations_file: PathBuf::from(ARG_MAP_ANNOTATIONS.value),
+            stream_layouts_file: PathBuf::from(ARG_STREAM_LAYOUTS.value),
+            population_history_dir: PathBuf::from(ARG_POPULATION_HISTORY.value),
+            population_interval_secs: ARG_POPULATION_INTERVAL.value,
+            otlp_endpoint: ARG_OTLP_ENDPOINT.value.to_string(),
+            public_feed: ARG_PUBLIC_FEED.value.to_string(),
+            slow_handler_ms: ARG_SLOW_HANDLER.value,
+            slow_transit_ms: ARG_SLOW_TRANSIT.value,
+            dry_run: ARG_DRY_RUN.value,
+            observe_only: ARG_OBSERVE_ONLY.value,
             open_devtools: ARG_OPEN_DEVTOOLS.value,
             hax: ARG_HAX.value,
         }
@@ -86,6 +178,36 @@ impl From<ArgMatches> for PartialConfig {
             profile_dir: matches.get_one::<PathBuf>(ARG_PROFILE_DIR.name).cloned(),
             game_dir: matches.get_one::<PathBuf>(ARG_GAME_DIR.name).cloned(),
             log_dir: matches.get_one::<PathBuf>(ARG_LOG_DIR.name).cloned(),
+            room_notes_file: matches.get_one::<PathBuf>(ARG_ROOM_NOTES.name).cloned(),
+            match_history_file: matches.get_one::<PathBuf>(ARG_MATCH_HISTORY.name).cloned(),
+            watchlist_file: matches.get_one::<PathBuf>(ARG_WATCHLIST.name).cloned(),
+            settings_profile_dir: matches
+                .get_one::<PathBuf>(ARG_SETTINGS_PROFILES.name)
+                .cloned(),
+            map_annotations_file: matches
+                .get_one::<PathBuf>(ARG_MAP_ANNOTATIONS.name)
+                .cloned(),
+            stream_layouts_file: matches.get_one::<PathBuf>(ARG_STREAM_LAYOUTS.name).cloned(),
+            population_history_dir: matches
+                .get_one::<PathBuf>(ARG_POPULATION_HISTORY.name)
+                .cloned(),
+            population_interval_secs: matches
+                .get_one::<u64>(ARG_POPULATION_INTERVAL.name)
+                .cloned(),
+            otlp_endpoint: matches.get_one::<String>(ARG_OTLP_ENDPOINT.name).cloned(),
+            public_feed: matches.get_one::<String>(ARG_PUBLIC_FEED.name).cloned(),
+            slow_handler_ms: matches.get_one::<u64>(ARG_SLOW_HANDLER.name).cloned(),
+            slow_transit_ms: matches.get_one::<u64>(ARG_SLOW_TRANSIT.name).cloned(),
+            dry_run: (matches.value_source(ARG_DRY_RUN.name) == Some(ValueSource::CommandLine))
+                .then(|| matches.get_one::<bool>(ARG_DRY_RUN.name).cloned().unwrap()),
+            observe_only: (matches.value_source(ARG_OBSERVE_ONLY.name)
+                == Some(ValueSource::CommandLine))
+            .then(|| {
+                matches
+                    .get_one::<bool>(ARG_OBSERVE_ONLY.name)
+                    .cloned()
+                    .unwrap()
+            }),
             open_devtools: (matches.value_source(ARG_OPEN_DEVTOOLS.name)
                 == Some(ValueSource::CommandLine))
             .then(|| {
@@ -171,6 +293,116 @@ fn build_command() -> Command {
                 .required(false)
                 .value_parser(value_parser!(PathBuf)),
         )
+        .arg(
+            Arg::new(ARG_ROOM_NOTES.name)
+                .long(ARG_ROOM_NOTES.name)
+                .value_name("PATH")
+                .help(format!("Sets the file where notes on lobby rooms get stored. [default: {}]", ARG_ROOM_NOTES.value))
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new(ARG_MATCH_HISTORY.name)
+                .long(ARG_MATCH_HISTORY.name)
+                .value_name("PATH")
+                .help(format!("Sets the file where summaries of played matches get stored. [default: {}]", ARG_MATCH_HISTORY.value))
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new(ARG_WATCHLIST.name)
+                .long(ARG_WATCHLIST.name)
+                .value_name("PATH")
+                .help(format!("Sets the file where the players to be alerted about get stored. [default: {}]", ARG_WATCHLIST.value))
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new(ARG_SETTINGS_PROFILES.name)
+                .long(ARG_SETTINGS_PROFILES.name)
+                .value_name("PATH")
+                .help(format!("Sets the directory where named profiles of the feature settings get stored. [default: {}]", ARG_SETTINGS_PROFILES.value))
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new(ARG_MAP_ANNOTATIONS.name)
+                .long(ARG_MAP_ANNOTATIONS.name)
+                .value_name("PATH")
+                .help(format!("Sets the file with your own callouts for each map, on top of the built-in ones. [default: {}]", ARG_MAP_ANNOTATIONS.value))
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new(ARG_STREAM_LAYOUTS.name)
+                .long(ARG_STREAM_LAYOUTS.name)
+                .value_name("PATH")
+                .help(format!("Sets the file with your own layouts of the players' position updates, on top of the built-in ones. [default: {}]", ARG_STREAM_LAYOUTS.value))
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new(ARG_POPULATION_HISTORY.name)
+                .long(ARG_POPULATION_HISTORY.name)
+                .value_name("PATH")
+                .help(format!("Sets the directory where the daily player and room counts of the lobby get stored. [default: {}]", ARG_POPULATION_HISTORY.value))
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new(ARG_POPULATION_INTERVAL.name)
+                .long(ARG_POPULATION_INTERVAL.name)
+                .value_name("SECONDS")
+                .help(format!("How often to count the players and rooms in the lobby, 0 to disable. [default: {}]", ARG_POPULATION_INTERVAL.value))
+                .required(false)
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            Arg::new(ARG_OTLP_ENDPOINT.name)
+                .long(ARG_OTLP_ENDPOINT.name)
+                .value_name("URL")
+                .help("Exports the spans of joining a game to this OpenTelemetry collector, eg. http://localhost:4318. Needs the otel feature.")
+                .required(false)
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            Arg::new(ARG_PUBLIC_FEED.name)
+                .long(ARG_PUBLIC_FEED.name)
+                .value_name("PATH")
+                .help("Writes joins, leaves, kills and our position as JSON lines to the recorder listening on this unix socket or named pipe, eg. \\\\.\\pipe\\bfhax-feed on Windows.")
+                .required(false)
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            Arg::new(ARG_SLOW_HANDLER.name)
+                .long(ARG_SLOW_HANDLER.name)
+                .value_name("MILLISECONDS")
+                .help(format!("Logs a warning when handling a single message takes longer than this, 0 to disable. [default: {}]", ARG_SLOW_HANDLER.value))
+                .required(false)
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            Arg::new(ARG_SLOW_TRANSIT.name)
+                .long(ARG_SLOW_TRANSIT.name)
+                .value_name("MILLISECONDS")
+                .help(format!("Logs a warning when 1% of messages spend longer than this in the proxy, 0 to disable. [default: {}]", ARG_SLOW_TRANSIT.value))
+                .required(false)
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            Arg::new(ARG_DRY_RUN.name)
+                .long(ARG_DRY_RUN.name)
+                .help("Only log what the features would change instead of changing the traffic.")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new(ARG_OBSERVE_ONLY.name)
+                .long(ARG_OBSERVE_ONLY.name)
+                .help("Forward all traffic unchanged and never inject messages, whatever else is enabled.")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new(ARG_OPEN_DEVTOOLS.name)
                 .long(ARG_OPEN_DEVTOOLS.name)
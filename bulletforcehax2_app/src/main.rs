@@ -8,7 +8,13 @@ mod config;
 mod version_manager;
 mod web_server;
 
-use bulletforcehax2_lib::hax::BulletForceHax;
+use std::{net::SocketAddr, time::Duration};
+
+use bulletforcehax2_lib::{
+    diagnostics,
+    hax::{public_feed::PublicFeed, shutdown::SESSION_PROFILE, BulletForceHax},
+    ProxyConfig,
+};
 use bulletforcehax2_ui::BulletForceHaxMenu;
 use tao_egui::WindowCreationSettings;
 use tracing::{debug, error, info};
@@ -25,6 +31,9 @@ use wry::{
 
 use crate::web_server::WebServer;
 
+/// How long shutting down waits for the game's connections to close.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+
 #[tokio::main]
 async fn main() {
     if let Err(err) = real_main().await {
@@ -55,6 +64,69 @@ async fn real_main() -> anyhow::Result<()> {
     let mut hax = BulletForceHax::default();
     let hax_web_services = if config.hax {
         info!("Initializing hax");
+        {
+            let state = hax.get_state();
+            let mut state = futures::executor::block_on(state.lock());
+            state.load_room_notes(&config.room_notes_file);
+            state.load_match_history(&config.match_history_file);
+            state.load_watchlist(&config.watchlist_file);
+            state.load_profiles(&config.settings_profile_dir);
+            // the settings are saved as this profile when shutting down
+            match state.load_profile(SESSION_PROFILE) {
+                Ok(warnings) => {
+                    for warning in warnings {
+                        tracing::warn!("Restoring the last session's settings: {warning}");
+                    }
+                }
+                Err(e) => debug!("Not restoring the last session's settings: {e}"),
+            }
+            state.load_map_annotations(&config.map_annotations_file);
+            state.load_stream_layouts(&config.stream_layouts_file);
+            state.load_population_history(&config.population_history_dir);
+            state.update_settings(|settings| {
+                settings.debug.slow_handler_threshold = (config.slow_handler_ms > 0)
+                    .then(|| Duration::from_millis(config.slow_handler_ms));
+                settings.dry_run.enabled = config.dry_run;
+                settings.observe_only = config.observe_only;
+            });
+            state.relay.set_warn_threshold(
+                (config.slow_transit_ms > 0).then(|| Duration::from_millis(config.slow_transit_ms)),
+            );
+            state.game_server_routes.set_endpoint(
+                format!("ws://127.0.0.1:{}/socket", config.port)
+                    .parse()
+                    .expect("local websocket endpoint should be a valid uri"),
+            );
+            if !config.public_feed.is_empty() {
+                info!(
+                    path = config.public_feed.as_str(),
+                    "Writing the public feed"
+                );
+                state.public_feed = PublicFeed::start(&config.public_feed);
+            }
+            #[cfg(feature = "shared_state")]
+            {
+                use bulletforcehax2_lib::hax::shared_state::SharedStateExporter;
+                let path = SharedStateExporter::default_path("bulletforcehax2");
+                match SharedStateExporter::create(path.clone()) {
+                    Ok(exporter) => {
+                        info!(
+                            path = format!("{}", path.display()),
+                            "Exporting players to shared memory"
+                        );
+                        state.shared_state = Some(exporter);
+                    }
+                    Err(e) => tracing::warn!(
+                        "Could not create shared state region at {}: {e}",
+                        path.display()
+                    ),
+                }
+            }
+        }
+        if config.population_interval_secs > 0 {
+            // runs for as long as the app does
+            hax.start_population_sampler(Duration::from_secs(config.population_interval_secs));
+        }
         vec![
             ("/request", hax.get_webrequest_proxy()),
             ("/socket", hax.get_websocket_proxy()),
@@ -63,13 +135,49 @@ async fn real_main() -> anyhow::Result<()> {
         vec![]
     };
 
+    if config.hax {
+        // find out what's wrong before the user starts wondering why nothing happens
+        let report = diagnostics::preflight(&diagnostics::PreflightOptions {
+            proxy_ports: vec![config.port],
+            ..Default::default()
+        })
+        .await;
+        report.log();
+
+        // finish the capture files and save the settings on ctrl-c too, not only when the window is closed
+        let hax = hax.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                info!("Received ctrl-c, shutting down");
+                hax.shutdown(SHUTDOWN_TIMEOUT).await;
+                #[cfg(feature = "otel")]
+                bulletforcehax2_lib::telemetry::shutdown();
+                std::process::exit(0);
+            }
+        });
+    }
+
     let web_server = WebServer::new(
         config.port,
         hax_web_services,
         asset_server::create_service(version_info, config.clone()),
     );
 
-    web_server.start_server();
+    if config.hax {
+        // the proxy listens on its own, so its listeners can be changed while it runs
+        let state = hax.get_state();
+        let mut state = state.lock().await;
+        state.proxy.set_service(web_server.into_service());
+        let proxy_config = ProxyConfig {
+            listen_addresses: vec![SocketAddr::from(([127, 0, 0, 1], config.port))],
+            ..Default::default()
+        };
+        if let Err(e) = state.apply_proxy_config(proxy_config, false).await {
+            error!("Could not start the proxy: {e}");
+        }
+    } else {
+        web_server.start_server();
+    }
 
     // create menu structure
     let mut file_submenu = MenuBar::new();
@@ -126,10 +234,14 @@ async fn real_main() -> anyhow::Result<()> {
         );
 
         let state = hax.get_state();
-        (egui_window, BulletForceHaxMenu::new(state))
+        (
+            egui_window,
+            BulletForceHaxMenu::new(state, hax.ui_snapshots()),
+        )
     });
 
     // start event loop
+    let hax_enabled = config.hax;
     event_loop.run(move |event, _, control_flow| {
         if let Some((egui_window, hax_app)) = &mut hax_app {
             *control_flow = egui_window
@@ -145,6 +257,11 @@ async fn real_main() -> anyhow::Result<()> {
                 window_id, event, ..
             } if webview.window().id() == window_id => match &event {
                 WindowEvent::CloseRequested | WindowEvent::Destroyed => {
+                    if hax_enabled {
+                        futures::executor::block_on(hax.shutdown(SHUTDOWN_TIMEOUT));
+                    }
+                    #[cfg(feature = "otel")]
+                    bulletforcehax2_lib::telemetry::shutdown();
                     *control_flow = ControlFlow::Exit;
                 }
                 _ => (),
@@ -232,6 +349,19 @@ fn init_logging(config: &config::Config) -> tracing_appender::non_blocking::Work
 
     let subscriber = subscriber.with(file_layer).with(console_layer);
 
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(match config.otlp_endpoint.as_str() {
+        "" => None,
+        endpoint => bulletforcehax2_lib::telemetry::otlp_layer(endpoint)
+            // logging isn't set up yet
+            .map_err(|e| eprintln!("Could not set up the OpenTelemetry exporter: {e}"))
+            .ok(),
+    });
+    #[cfg(not(feature = "otel"))]
+    if !config.otlp_endpoint.is_empty() {
+        eprintln!("otlp-endpoint is set, but the app was built without the otel feature");
+    }
+
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
     #[cfg(debug_assertions)]
@@ -29,9 +29,8 @@ impl WebServer {
         tokio::spawn(async move { self.block_on_server().await });
     }
 
-    async fn block_on_server(self) {
-        let addr = SocketAddr::from(([127, 0, 0, 1], self.port));
-
+    /// The service that dispatches requests to the service of their path, for serving it on other listeners.
+    pub fn into_service(self) -> WebService {
         let mut services = self
             .services
             .iter()
@@ -50,7 +49,12 @@ impl WebServer {
             paths.len()
         });
 
-        let server = Server::bind(&addr).serve(Shared::new(service));
+        BoxCloneService::new(service)
+    }
+
+    async fn block_on_server(self) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], self.port));
+        let server = Server::bind(&addr).serve(Shared::new(self.into_service()));
 
         debug!("http server created");
         if let Err(e) = server.await {
@@ -0,0 +1,107 @@
+//! Times how long the lobby features take to rewrite a game list of 300 rooms.
+//!
+//! Usage: `cargo run --release --example bench_game_list -- [iterations]`
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bulletforcehax2_lib::{
+    hax::{HaxState, VersionInfo},
+    indexmap::indexmap,
+    Direction, WebSocketServer,
+};
+use futures_util::lock::Mutex;
+use photon_lib::{
+    highlevel::constants::{event_code, game_property_key, parameter_code},
+    photon_data_type::PhotonDataType,
+    photon_message::{EventData, PhotonMessage},
+    PhotonHashmap,
+};
+
+const ROOMS: usize = 300;
+
+fn room(i: usize) -> PhotonHashmap {
+    let string = |s: &str| PhotonDataType::String(s.into());
+    indexmap! {
+        PhotonDataType::Byte(game_property_key::MAX_PLAYERS) => PhotonDataType::Byte(12),
+        PhotonDataType::Byte(game_property_key::IS_OPEN) => PhotonDataType::Boolean(true),
+        PhotonDataType::Byte(game_property_key::PLAYER_COUNT) => PhotonDataType::Byte((i % 12) as u8),
+        string("roomName") => string(&format!("Room number {i}")),
+        string("password") => string(if i.is_multiple_of(10) { "secret" } else { "" }),
+        string("storeID") => string(if i.is_multiple_of(3) { "BALYZE_MOBILE" } else { "BALYZE_WEB" }),
+        string("gameVersion") => string(if i.is_multiple_of(5) { "1.89.0" } else { "1.90.0" }),
+        string("mapName") => string("Urban"),
+        string("switchingmap") => PhotonDataType::Boolean(false),
+        string("meanKD") => PhotonDataType::Integer(1),
+        string("seasonID") => string(""),
+        string("eventcode") => PhotonDataType::Integer(0),
+    }
+}
+
+fn game_list() -> Vec<u8> {
+    let games = (0..ROOMS)
+        .map(|i| {
+            (
+                PhotonDataType::String(format!("room-{i}")),
+                PhotonDataType::Hashtable(room(i)),
+            )
+        })
+        .collect();
+    let mut bytes = vec![];
+    PhotonMessage::EventData(EventData {
+        code: event_code::GAME_LIST,
+        parameters: indexmap! { parameter_code::GAME_LIST => PhotonDataType::Hashtable(games) },
+    })
+    .to_websocket_bytes(&mut bytes)
+    .expect("game list should serialize");
+    bytes
+}
+
+fn main() {
+    let iterations = std::env::args()
+        .nth(1)
+        .map(|n| n.parse::<u32>().expect("iterations should be a number"))
+        .unwrap_or(200);
+
+    let mut state = HaxState::default();
+    state.update_settings(|settings| {
+        settings.show_mobile_games = true;
+        settings.show_other_versions = true;
+        settings.strip_passwords = true;
+    });
+    state.global_state.version = Some(VersionInfo {
+        game_version: "1.90.0".into(),
+        photon_version: "1.0".into(),
+    });
+    let state = Arc::new(Mutex::new(state));
+
+    let message = game_list();
+    let mut total = Duration::ZERO;
+    for _ in 0..iterations {
+        let mut data = message.clone();
+        let start = Instant::now();
+        HaxState::websocket_hook(
+            state.clone(),
+            &mut data,
+            WebSocketServer::LobbyServer,
+            Direction::ServerToClient,
+        )
+        .expect("hook should handle the game list");
+        total += start.elapsed();
+    }
+
+    println!(
+        "{ROOMS} rooms, {} bytes: {:?} per game list over {iterations} iterations",
+        message.len(),
+        total / iterations
+    );
+    print!(
+        "{}",
+        futures::executor::block_on(state.lock())
+            .stats
+            .handler_timings
+            .timings()
+    );
+}
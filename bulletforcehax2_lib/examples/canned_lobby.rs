@@ -0,0 +1,76 @@
+//! Feeds a generated game list through the lobby features and prints the rooms the way the client would list them.
+//! Useful to see what the lobby features do without connecting to the game.
+//!
+//! Usage: `cargo run --example canned_lobby -- [rooms] [seed]`
+
+use std::sync::Arc;
+
+use bulletforcehax2_lib::{
+    hax::{lobby_sort::LobbySort, HaxState, VersionInfo},
+    protocol::properties::BulletForceRoomProperties,
+    testgen::GameListBuilder,
+    Direction, WebSocketServer,
+};
+use futures_util::lock::Mutex;
+use photon_lib::{
+    highlevel::{
+        structs::{RoomInfoList, RoomInfoView},
+        PhotonParameterMapConversion,
+    },
+    photon_data_type::PhotonDataType,
+    photon_message::PhotonMessage,
+};
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let rooms = args.next().map(|n| n.parse()).transpose()?.unwrap_or(20);
+    let seed = args.next().map(|n| n.parse()).transpose()?.unwrap_or(0);
+
+    let mut state = HaxState::default();
+    state.update_settings(|settings| {
+        settings.show_mobile_games = true;
+        settings.show_other_versions = true;
+        settings.strip_passwords = true;
+        settings.lobby_sort = Some(LobbySort::PlayerCount);
+    });
+    state.global_state.version = VersionInfo::parse("1.90.0_1.99");
+    let state = Arc::new(Mutex::new(state));
+
+    let mut data = GameListBuilder::new(rooms)
+        .with_seed(seed)
+        .to_websocket_bytes();
+    HaxState::websocket_hook(
+        state.clone(),
+        &mut data,
+        WebSocketServer::LobbyServer,
+        Direction::ServerToClient,
+    )?;
+
+    let mut event = match PhotonMessage::from_websocket_bytes(&mut data.as_slice())? {
+        PhotonMessage::EventData(event) => event,
+        other => anyhow::bail!("expected the game list, got {other:?}"),
+    };
+    for room in RoomInfoList::from_map(&mut event.parameters)?
+        .games
+        .values()
+    {
+        let room = match room {
+            PhotonDataType::Hashtable(room) => RoomInfoView(room),
+            _ => continue,
+        };
+        println!(
+            "{:>2}/{:<2} {:<10} {}",
+            room.player_count().copied().unwrap_or_default(),
+            room.max_players().copied().unwrap_or_default(),
+            room.map_name().unwrap_or("?"),
+            room.room_name().unwrap_or("?"),
+        );
+    }
+
+    println!();
+    print!(
+        "{}",
+        futures::executor::block_on(state.lock()).status_report(false)
+    );
+    Ok(())
+}
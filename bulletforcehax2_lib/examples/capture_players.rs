@@ -0,0 +1,64 @@
+//! Prints the player list at a given offset into a capture file.
+//!
+//! Usage: `cargo run --example capture_players -- <capture file> <offset in seconds>`
+
+use std::{path::PathBuf, time::Duration};
+
+use bulletforcehax2_lib::{hax::timeline::reconstruct_state_at, inspect::capture::Capture};
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (path, offset) = match (args.next(), args.next()) {
+        (Some(path), Some(offset)) => (PathBuf::from(path), offset.parse::<f64>()?),
+        _ => anyhow::bail!("usage: capture_players <capture file> <offset in seconds>"),
+    };
+
+    let capture = Capture::load(&path)?;
+    let start = match capture.start_time() {
+        Some(t) => t,
+        None => anyhow::bail!("capture is empty"),
+    };
+    println!(
+        "{} messages over {:.1}s",
+        capture.messages.len(),
+        capture.duration().as_secs_f64()
+    );
+
+    let state = reconstruct_state_at(&capture, start + Duration::from_secs_f64(offset));
+    let (_, gameplay) = match &state.gameplay_state {
+        Some(x) => x,
+        None => {
+            println!("not in a game at {offset}s");
+            return Ok(());
+        }
+    };
+
+    println!(
+        "room {} as actor {}",
+        gameplay.room_name.as_deref().unwrap_or("?"),
+        gameplay
+            .actor_nr
+            .map(|a| a.to_string())
+            .unwrap_or_else(|| "?".into())
+    );
+    for (actor_id, player) in &gameplay.players {
+        let position = match &player.position {
+            Some(x) => format!("{:.2}, {:.2}, {:.2}", x.0, x.1, x.2),
+            None => "?".into(),
+        };
+        println!(
+            "{actor_id:>4} team {:<4} health {:<6} at {position:<24} {}",
+            player
+                .team_number
+                .map(|t| t.to_string())
+                .unwrap_or_default(),
+            player.health.map(|h| format!("{h:.2}")).unwrap_or_default(),
+            player.nickname.as_deref().unwrap_or("")
+        );
+    }
+    for error in &state.stats.recent_errors {
+        println!("error while replaying: {error}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,48 @@
+//! Replays a capture file through the handlers and prints a report of the session, without running the proxy.
+//!
+//! Usage: `cargo run --example capture_report -- [--json] [--max-parse-failures <n>] <capture file>`
+//!
+//! Exits with status 1 if more than `n` messages failed to parse (default 0), so it can check captures in CI.
+
+use std::{path::PathBuf, process::ExitCode};
+
+use bulletforcehax2_lib::{hax::session_report::SessionReport, inspect::capture::Capture};
+
+const USAGE: &str = "usage: capture_report [--json] [--max-parse-failures <n>] <capture file>";
+
+fn main() -> anyhow::Result<ExitCode> {
+    let mut json = false;
+    let mut max_parse_failures = 0;
+    let mut path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--max-parse-failures" => match args.next().map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) => max_parse_failures = n,
+                _ => anyhow::bail!(USAGE),
+            },
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(PathBuf::from(arg)),
+            _ => anyhow::bail!(USAGE),
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => anyhow::bail!(USAGE),
+    };
+
+    let report = SessionReport::analyze(&Capture::load(&path)?);
+    match json {
+        true => println!("{}", report.to_json()),
+        false => print!("{report}"),
+    }
+
+    if report.parse_failures.len() > max_parse_failures {
+        eprintln!(
+            "{} messages failed to parse, at most {max_parse_failures} allowed",
+            report.parse_failures.len()
+        );
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
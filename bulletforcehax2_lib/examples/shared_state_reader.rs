@@ -0,0 +1,76 @@
+//! Prints the players exported by the shared state exporter whenever they change, the way an external overlay would
+//! read them.
+//!
+//! Usage: `cargo run --features shared_state --example shared_state_reader -- [region name]`
+
+use std::{fs::File, sync::atomic::Ordering, time::Duration};
+
+use bulletforcehax2_lib::{
+    hax::shared_state::{player_flags, read_snapshot, SharedRegion, SharedStateExporter},
+    protocol::loadout::LoadoutItem,
+};
+use memmap2::Mmap;
+
+fn main() -> anyhow::Result<()> {
+    let name = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "bulletforcehax2".into());
+    let path = SharedStateExporter::default_path(&name);
+
+    let file = File::open(&path)?;
+    // SAFETY: the exporter only writes to the file through its own mapping, read_snapshot detects torn reads
+    let mmap = unsafe { Mmap::map(&file)? };
+    let region = match SharedRegion::from_bytes(&mmap) {
+        Some(r) => r,
+        None => anyhow::bail!("{} is not a shared state region", path.display()),
+    };
+
+    let mut last_generation = None;
+    loop {
+        let generation = region.header.generation.load(Ordering::Acquire);
+        if last_generation != Some(generation) {
+            last_generation = Some(generation);
+            match read_snapshot(region) {
+                Some(snapshot) => {
+                    println!("{} players", snapshot.player_count);
+                    for player in snapshot.players() {
+                        let known = |flag| player.flags & flag != 0;
+                        println!(
+                            "  {}: name hash {:016x}, team {}, health {}, position {}, loadout {}{}",
+                            player.actor_id,
+                            player.name_hash,
+                            match known(player_flags::HAS_TEAM) {
+                                true => player.team.to_string(),
+                                false => "?".into(),
+                            },
+                            match known(player_flags::HAS_HEALTH) {
+                                true => format!("{:.2}", player.health),
+                                false => "?".into(),
+                            },
+                            match (known(player_flags::HAS_POSITION), player.callout()) {
+                                (true, Some(callout)) => format!("{:?} at {callout}", player.position),
+                                (true, None) => format!("{:?}", player.position),
+                                (false, _) => "?".into(),
+                            },
+                            match known(player_flags::HAS_LOADOUT) {
+                                true => format!(
+                                    "{} / {} ({} camo)",
+                                    LoadoutItem::weapon(player.primary_weapon.into()),
+                                    LoadoutItem::weapon(player.secondary_weapon.into()),
+                                    LoadoutItem::camo(player.camo.into()),
+                                ),
+                                false => "?".into(),
+                            },
+                            match known(player_flags::LOCAL_PLAYER) {
+                                true => " (you)",
+                                false => "",
+                            },
+                        );
+                    }
+                }
+                None => println!("could not read a consistent snapshot, retrying"),
+            }
+        }
+        std::thread::sleep(Duration::from_millis(7));
+    }
+}
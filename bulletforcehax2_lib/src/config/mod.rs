@@ -0,0 +1,3 @@
+//! Helpers for the files BulletForceHaxV2 persists.
+
+pub mod versioned;
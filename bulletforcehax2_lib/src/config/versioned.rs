@@ -0,0 +1,260 @@
+//! Versioned JSON documents that are migrated to the current format when loaded.
+//!
+//! Every document carries a [VERSION_KEY] field. Loading runs the [migrations](Versioned::MIGRATIONS) from the version
+//! in the file up to the current one, and saving always writes the current version. Documents from a newer version of
+//! the program are refused, so they aren't overwritten with an older format.
+//!
+//! Documents kept in files are read with [read_optional] and saved with [write], which replaces the file atomically so
+//! a crash while saving leaves the previous document instead of a truncated one.
+
+use std::{io::Write, path::Path};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+/// The field holding the version of a document.
+pub const VERSION_KEY: &str = "schema_version";
+
+/// Turns a document of one version into a document of the next version.
+pub type Migration = fn(Value) -> anyhow::Result<Value>;
+
+/// A document that is persisted with a version.
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// What the document holds, for error messages.
+    const NAME: &'static str;
+    /// The migrations from each version to the next, in order. The first one migrates version 1 to version 2.
+    ///
+    /// Documents without a [VERSION_KEY] are version 1, as they were written before documents were versioned.
+    const MIGRATIONS: &'static [Migration];
+
+    /// The version documents are saved with.
+    fn current_version() -> u32 {
+        Self::MIGRATIONS.len() as u32 + 1
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum VersionError {
+    #[error("{name} are not valid JSON: {source}")]
+    Parse {
+        name: &'static str,
+        source: serde_json::Error,
+    },
+    #[error("{name} have an invalid {VERSION_KEY}: {found}")]
+    InvalidVersion { name: &'static str, found: Value },
+    /// The document was written by a newer version of the program.
+    #[error("{name} have version {found}, but only versions up to {current} are supported. Update BulletForceHaxV2 to load them")]
+    NewerVersion {
+        name: &'static str,
+        found: u32,
+        current: u32,
+    },
+    #[error("could not migrate {name} from version {from} to {}: {source:#}", from + 1)]
+    Migration {
+        name: &'static str,
+        from: u32,
+        source: anyhow::Error,
+    },
+    #[error("{name} don't match the current format: {source}")]
+    Deserialize {
+        name: &'static str,
+        source: serde_json::Error,
+    },
+}
+
+/// Migrates a document to the current version and deserializes it.
+pub fn from_value<T: Versioned>(mut value: Value) -> Result<T, VersionError> {
+    let current = T::current_version();
+    let version = match value.as_object_mut().and_then(|o| o.remove(VERSION_KEY)) {
+        None => 1,
+        Some(found) => match found.as_u64().and_then(|v| u32::try_from(v).ok()) {
+            Some(v) if v >= 1 => v,
+            _ => {
+                return Err(VersionError::InvalidVersion {
+                    name: T::NAME,
+                    found,
+                })
+            }
+        },
+    };
+    if version > current {
+        return Err(VersionError::NewerVersion {
+            name: T::NAME,
+            found: version,
+            current,
+        });
+    }
+
+    for (from, migration) in (version..).zip(&T::MIGRATIONS[version as usize - 1..]) {
+        value = migration(value).map_err(|source| VersionError::Migration {
+            name: T::NAME,
+            from,
+            source,
+        })?;
+    }
+
+    serde_json::from_value(value).map_err(|source| VersionError::Deserialize {
+        name: T::NAME,
+        source,
+    })
+}
+
+/// Parses a document and migrates it to the current version, see [from_value].
+pub fn from_slice<T: Versioned>(bytes: &[u8]) -> Result<T, VersionError> {
+    let value = serde_json::from_slice(bytes).map_err(|source| VersionError::Parse {
+        name: T::NAME,
+        source,
+    })?;
+    from_value(value)
+}
+
+/// Serializes a document with the current version.
+pub fn to_value<T: Versioned>(document: &T) -> serde_json::Result<Value> {
+    let mut value = serde_json::to_value(document)?;
+    if let Some(object) = value.as_object_mut() {
+        object.insert(VERSION_KEY.into(), T::current_version().into());
+    }
+    Ok(value)
+}
+
+/// Serializes a document with the current version as pretty-printed JSON.
+pub fn to_vec_pretty<T: Versioned>(document: &T) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec_pretty(&to_value(document)?)
+}
+
+/// Reads a document from a file and migrates it to the current version, see [from_slice]. A missing file is [None].
+pub fn read_optional<T: Versioned>(path: &Path) -> anyhow::Result<Option<T>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("read {} from {path:?}", T::NAME)),
+    };
+    Ok(Some(from_slice(&bytes)?))
+}
+
+/// Saves a document with the current version, creating the directory it's in.
+///
+/// The document is written to a temporary file next to the destination first and then renamed over it, so the file
+/// holds either the old or the new document at any time.
+pub fn write<T: Versioned>(path: &Path, document: &T) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let bytes = to_vec_pretty(document)?;
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = std::fs::File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(&bytes)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result.with_context(|| format!("write {} to {path:?}", T::NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+
+    use super::{from_value, read_optional, to_value, write, Migration, VersionError, Versioned};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Toggles {
+        enabled: bool,
+        count: u32,
+    }
+
+    fn rename_active(mut value: Value) -> anyhow::Result<Value> {
+        let object = value.as_object_mut().unwrap();
+        let active = object.remove("active").unwrap_or(Value::Bool(false));
+        object.insert("enabled".into(), active);
+        Ok(value)
+    }
+
+    fn add_count(mut value: Value) -> anyhow::Result<Value> {
+        value["count"] = 1.into();
+        Ok(value)
+    }
+
+    impl Versioned for Toggles {
+        const NAME: &'static str = "toggles";
+        const MIGRATIONS: &'static [Migration] = &[rename_active, add_count];
+    }
+
+    #[test]
+    fn migrates_every_version() {
+        let expected = Toggles {
+            enabled: true,
+            count: 1,
+        };
+        for document in [
+            json!({ "active": true }),
+            json!({ "schema_version": 1, "active": true }),
+            json!({ "schema_version": 2, "enabled": true }),
+            json!({ "schema_version": 3, "enabled": true, "count": 1 }),
+        ] {
+            assert_eq!(from_value::<Toggles>(document).unwrap(), expected);
+        }
+
+        assert_eq!(
+            to_value(&expected).unwrap(),
+            json!({ "schema_version": 3, "enabled": true, "count": 1 })
+        );
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        let error = from_value::<Toggles>(json!({ "schema_version": 4 })).unwrap_err();
+        assert!(matches!(
+            error,
+            VersionError::NewerVersion {
+                found: 4,
+                current: 3,
+                ..
+            }
+        ));
+        assert_eq!(
+            error.to_string(),
+            "toggles have version 4, but only versions up to 3 are supported. Update BulletForceHaxV2 to load them"
+        );
+
+        assert!(matches!(
+            from_value::<Toggles>(json!({ "schema_version": "2" })),
+            Err(VersionError::InvalidVersion { .. })
+        ));
+    }
+
+    #[test]
+    fn replaces_files() {
+        let dir = std::env::temp_dir().join(format!("bfhax-versioned-{}", std::process::id()));
+        let path = dir.join("nested").join("toggles.json");
+        assert!(read_optional::<Toggles>(&path).unwrap().is_none());
+
+        for count in [1, 2] {
+            let toggles = Toggles {
+                enabled: true,
+                count,
+            };
+            write(&path, &toggles).unwrap();
+            assert_eq!(read_optional::<Toggles>(&path).unwrap(), Some(toggles));
+        }
+        // only the document is left behind
+        let files = std::fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(files, ["toggles.json"]);
+
+        std::fs::write(&path, b"{").unwrap();
+        assert!(read_optional::<Toggles>(&path).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,456 @@
+//! Checks that can be run before starting the game, to find out why the proxy would not work.
+
+use std::{
+    fmt::Display,
+    future::Future,
+    net::TcpListener,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use futures_util::SinkExt;
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    Body, Client, Uri,
+};
+use photon_lib::{
+    highlevel::{
+        constants::{operation_code, parameter_code},
+        parameters::Parameters,
+    },
+    photon_message::{OperationRequest, PhotonMessage},
+};
+use tracing::{info, warn};
+
+use crate::proxy::watchdog::{reconnect_upstream, UpstreamTarget};
+
+/// The port the app serves the game and the websocket proxy on by default.
+pub const DEFAULT_PROXY_PORT: u16 = 48897;
+/// The web API the game uses for accounts and matchmaking.
+pub const DEFAULT_MATCHMAKING_URL: &str = "https://server.blayzegames.com/";
+/// Photon's public name server over secure websockets.
+pub const DEFAULT_NAME_SERVER_URL: &str = "wss://ns.exitgames.com:19093/";
+
+/// The websocket subprotocol of Photon's binary protocol, as requested by the game.
+const PHOTON_SUBPROTOCOL: &str = "GpBinaryV16";
+
+/// What to check, and where.
+#[derive(Debug, Clone)]
+pub struct PreflightOptions {
+    /// The local ports the proxy will listen on.
+    pub proxy_ports: Vec<u16>,
+    pub matchmaking_url: Uri,
+    pub name_server_url: Uri,
+    /// If set, authenticate with the name server to check that it accepts these credentials.
+    pub credentials: Option<PreflightCredentials>,
+    /// How long each check may take.
+    pub timeout: Duration,
+}
+
+impl Default for PreflightOptions {
+    fn default() -> Self {
+        Self {
+            proxy_ports: vec![DEFAULT_PROXY_PORT],
+            matchmaking_url: Uri::from_static(DEFAULT_MATCHMAKING_URL),
+            name_server_url: Uri::from_static(DEFAULT_NAME_SERVER_URL),
+            credentials: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The parameters of the authentication request sent to the name server.
+#[derive(Debug, Clone)]
+pub struct PreflightCredentials {
+    pub app_id: String,
+    pub app_version: String,
+    pub user_id: Option<String>,
+    pub region: Option<String>,
+}
+
+impl PreflightCredentials {
+    fn to_request_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut request = OperationRequest {
+            operation_code: operation_code::AUTHENTICATE,
+            parameters: Default::default(),
+        };
+        let mut parameters = Parameters(&mut request.parameters);
+        parameters.set(parameter_code::APPLICATION_ID, self.app_id.clone());
+        parameters.set_app_version(self.app_version.clone());
+        if let Some(user_id) = &self.user_id {
+            parameters.set_user_id(user_id.clone());
+        }
+        if let Some(region) = &self.region {
+            parameters.set_region(region.clone());
+        }
+
+        let mut bytes = vec![];
+        PhotonMessage::OperationRequest(request).to_websocket_bytes(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckStatus {
+    Passed,
+    Failed(String),
+    /// The check was not run, with the reason why.
+    Skipped(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct PreflightCheck {
+    pub name: String,
+    pub status: CheckStatus,
+    /// How long the check took.
+    pub duration: Duration,
+    /// What the user can do about a failed check.
+    pub remediation: &'static str,
+}
+
+impl PreflightCheck {
+    pub fn passed(&self) -> bool {
+        self.status == CheckStatus::Passed
+    }
+}
+
+impl Display for PreflightCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.status {
+            CheckStatus::Passed => write!(f, "[ok] {} ({:?})", self.name, self.duration),
+            CheckStatus::Failed(reason) => write!(
+                f,
+                "[failed] {}: {reason} ({:?})\n  {}",
+                self.name, self.duration, self.remediation
+            ),
+            CheckStatus::Skipped(reason) => write!(f, "[skipped] {}: {reason}", self.name),
+        }
+    }
+}
+
+/// The result of [preflight].
+#[derive(Debug, Clone, Default)]
+pub struct PreflightReport {
+    pub checks: Vec<PreflightCheck>,
+}
+
+impl PreflightReport {
+    /// Whether no check failed. Skipped checks don't count as failures.
+    pub fn passed(&self) -> bool {
+        self.failed_checks().next().is_none()
+    }
+
+    pub fn failed_checks(&self) -> impl Iterator<Item = &PreflightCheck> {
+        self.checks
+            .iter()
+            .filter(|c| matches!(c.status, CheckStatus::Failed(_)))
+    }
+
+    /// Logs every check, failed ones as warnings along with what to do about them.
+    pub fn log(&self) {
+        for check in &self.checks {
+            match &check.status {
+                CheckStatus::Passed => info!(
+                    check = check.name.as_str(),
+                    duration = format!("{:?}", check.duration),
+                    "Preflight check passed"
+                ),
+                CheckStatus::Failed(reason) => warn!(
+                    check = check.name.as_str(),
+                    reason = reason.as_str(),
+                    remediation = check.remediation,
+                    "Preflight check failed"
+                ),
+                CheckStatus::Skipped(reason) => info!(
+                    check = check.name.as_str(),
+                    reason = reason.as_str(),
+                    "Preflight check skipped"
+                ),
+            }
+        }
+    }
+}
+
+impl Display for PreflightReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for check in &self.checks {
+            writeln!(f, "{check}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the proxy can start and that the servers the game needs are reachable.
+///
+/// Every check runs, even if an earlier one failed, so the report shows everything that is wrong at once.
+pub async fn preflight(options: &PreflightOptions) -> PreflightReport {
+    let mut report = PreflightReport::default();
+
+    for port in &options.proxy_ports {
+        report.checks.push(
+            run_check(
+                format!("proxy port {port}"),
+                "Another program (or another instance of BulletForceHax) is using this port. Close it, or choose \
+                 another port with --port.",
+                options.timeout,
+                async { check_port(*port) },
+            )
+            .await,
+        );
+    }
+
+    report.checks.push(
+        run_check(
+            format!("matchmaking endpoint {}", options.matchmaking_url),
+            "The game's web API can't be reached. Check your internet connection, and that no firewall or \
+             antivirus blocks BulletForceHax.",
+            options.timeout,
+            check_http(&options.matchmaking_url),
+        )
+        .await,
+    );
+
+    let name_server_name = format!("name server {}", options.name_server_url);
+    let name_server = run_check(
+        name_server_name,
+        "The Photon name server can't be reached. Check your internet connection, and that websocket connections \
+         on port 19093 are not blocked.",
+        options.timeout,
+        check_websocket(&options.name_server_url),
+    )
+    .await;
+    let name_server_reachable = name_server.passed();
+    report.checks.push(name_server);
+
+    let auth_name = "name server authentication".to_string();
+    let auth_remediation =
+        "The name server rejected the authentication. The app id or game version may be \
+                            outdated, try updating the game files.";
+    let auth = match (&options.credentials, name_server_reachable) {
+        (None, _) => PreflightCheck {
+            name: auth_name,
+            status: CheckStatus::Skipped("no credentials given".into()),
+            duration: Duration::ZERO,
+            remediation: auth_remediation,
+        },
+        (Some(_), false) => PreflightCheck {
+            name: auth_name,
+            status: CheckStatus::Skipped("name server is not reachable".into()),
+            duration: Duration::ZERO,
+            remediation: auth_remediation,
+        },
+        (Some(credentials), true) => {
+            run_check(
+                auth_name,
+                auth_remediation,
+                options.timeout,
+                check_authentication(&options.name_server_url, credentials, options.timeout),
+            )
+            .await
+        }
+    };
+    report.checks.push(auth);
+
+    report
+}
+
+async fn run_check(
+    name: String,
+    remediation: &'static str,
+    timeout: Duration,
+    check: impl Future<Output = anyhow::Result<()>>,
+) -> PreflightCheck {
+    let start = Instant::now();
+    let status = match tokio::time::timeout(timeout, check).await {
+        Ok(Ok(())) => CheckStatus::Passed,
+        Ok(Err(e)) => CheckStatus::Failed(format!("{e:#}")),
+        Err(_) => CheckStatus::Failed(format!("timed out after {timeout:?}")),
+    };
+    PreflightCheck {
+        name,
+        status,
+        duration: start.elapsed(),
+        remediation,
+    }
+}
+
+fn check_port(port: u16) -> anyhow::Result<()> {
+    TcpListener::bind(("127.0.0.1", port)).with_context(|| format!("bind to port {port}"))?;
+    Ok(())
+}
+
+async fn check_http(url: &Uri) -> anyhow::Result<()> {
+    let client = Client::builder().build::<_, Body>(hyper_tls::HttpsConnector::new());
+    let response = client
+        .get(url.clone())
+        .await
+        .with_context(|| format!("request {url}"))?;
+
+    // any answer means the server is reachable, except for the server saying it's broken
+    if response.status().is_server_error() {
+        anyhow::bail!("server responded with {}", response.status());
+    }
+    Ok(())
+}
+
+fn name_server_target(url: &Uri) -> UpstreamTarget {
+    UpstreamTarget {
+        uri: url.clone(),
+        headers: vec![
+            (
+                HeaderName::from_static("sec-websocket-version"),
+                HeaderValue::from_static("13"),
+            ),
+            (
+                HeaderName::from_static("sec-websocket-protocol"),
+                HeaderValue::from_static(PHOTON_SUBPROTOCOL),
+            ),
+        ],
+    }
+}
+
+async fn check_websocket(url: &Uri) -> anyhow::Result<()> {
+    let (mut ws, _) = tokio_tungstenite::connect_async(name_server_target(url).to_request(None))
+        .await
+        .with_context(|| format!("connect to {url}"))?;
+    _ = ws.close(None).await;
+    Ok(())
+}
+
+async fn check_authentication(
+    url: &Uri,
+    credentials: &PreflightCredentials,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let request = credentials.to_request_bytes()?;
+    let (mut sink, _) =
+        reconnect_upstream(&name_server_target(url), Some(request), timeout).await?;
+    _ = sink.close().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::{preflight, CheckStatus, PreflightCredentials, PreflightOptions};
+
+    const AUTH_RESPONSE: &[u8] = &[0xF3, 0x03, 0xE6, 0x00, 0x00, 0x2A, 0x00, 0x00];
+    const AUTH_REJECTED: &[u8] = &[0xF3, 0x03, 0xE6, 0x7F, 0xFF, 0x2A, 0x00, 0x00];
+
+    /// Answers every HTTP request with the given status line.
+    async fn mock_http(status: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut tcp, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                _ = tcp.read(&mut buf).await;
+                let response = format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\n\r\n");
+                _ = tcp.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{addr}/")
+    }
+
+    /// Accepts websocket connections and answers authentication requests with the given response.
+    async fn mock_name_server(auth_response: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+                    while let Some(Ok(message)) = ws.next().await {
+                        if let Message::Binary(_) = message {
+                            _ = ws.send(Message::Binary(auth_response.to_vec())).await;
+                        }
+                    }
+                });
+            }
+        });
+        format!("ws://{addr}/")
+    }
+
+    /// A port that nothing listens on.
+    async fn closed_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    fn credentials() -> PreflightCredentials {
+        PreflightCredentials {
+            app_id: "app".into(),
+            app_version: "1.0_1.100".into(),
+            user_id: None,
+            region: Some("eu".into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn all_checks_pass() {
+        let options = PreflightOptions {
+            proxy_ports: vec![closed_port().await],
+            matchmaking_url: mock_http("404 Not Found").await.parse().unwrap(),
+            name_server_url: mock_name_server(AUTH_RESPONSE).await.parse().unwrap(),
+            credentials: Some(credentials()),
+            timeout: Duration::from_secs(5),
+        };
+
+        let report = preflight(&options).await;
+        assert!(report.passed(), "{report}");
+        assert!(report
+            .checks
+            .iter()
+            .all(|c| c.status == CheckStatus::Passed));
+    }
+
+    #[tokio::test]
+    async fn reports_each_failure() {
+        let busy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let options = PreflightOptions {
+            proxy_ports: vec![busy.local_addr().unwrap().port()],
+            matchmaking_url: mock_http("503 Service Unavailable").await.parse().unwrap(),
+            name_server_url: format!("ws://127.0.0.1:{}/", closed_port().await)
+                .parse()
+                .unwrap(),
+            credentials: Some(credentials()),
+            timeout: Duration::from_secs(5),
+        };
+
+        let report = preflight(&options).await;
+        let statuses = report
+            .checks
+            .iter()
+            .map(|c| std::mem::discriminant(&c.status))
+            .collect::<Vec<_>>();
+        let failed = std::mem::discriminant(&CheckStatus::Failed(String::new()));
+        let skipped = std::mem::discriminant(&CheckStatus::Skipped(String::new()));
+        assert_eq!(statuses, [failed, failed, failed, skipped]);
+        assert_eq!(report.failed_checks().count(), 3);
+        assert!(report.to_string().contains("--port"));
+    }
+
+    #[tokio::test]
+    async fn rejected_authentication() {
+        let options = PreflightOptions {
+            proxy_ports: vec![],
+            matchmaking_url: mock_http("200 OK").await.parse().unwrap(),
+            name_server_url: mock_name_server(AUTH_REJECTED).await.parse().unwrap(),
+            credentials: Some(credentials()),
+            timeout: Duration::from_secs(5),
+        };
+
+        let report = preflight(&options).await;
+        let failed = report.failed_checks().collect::<Vec<_>>();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].name, "name server authentication");
+    }
+}
@@ -0,0 +1,48 @@
+//! The error type exposed by this crate.
+
+use photon_lib::{ReadError, WriteError};
+use thiserror::Error;
+
+use crate::proxy::Direction;
+
+/// An error that can occur while running BulletForceHaxV2.
+#[derive(Debug, Error)]
+pub enum HaxError {
+    /// A websocket message could not be parsed by [photon_lib].
+    #[error("failed to parse photon message of {len} bytes at offset {offset}: {source}")]
+    ProtocolParse {
+        source: ReadError,
+        /// The length of the raw message.
+        len: usize,
+        /// The offset in the raw message at which parsing failed.
+        offset: usize,
+    },
+    /// A (modified) message could not be serialized by [photon_lib].
+    #[error("failed to serialize photon message: {0}")]
+    ProtocolWrite(#[from] WriteError),
+    /// A piece of state that was required to handle a message was not present, eg. because the connection it belongs
+    /// to was already closed.
+    #[error("state was not available: {0}")]
+    StateLock(&'static str),
+    /// A message could not be injected because the connection is not available.
+    #[error("could not inject message: {0}")]
+    InjectionUnavailable(String),
+    /// A message was not injected because the proxy is set to observe only or paused, see
+    /// [Settings::is_inert](crate::hax::settings::Settings::is_inert).
+    #[error("not injecting messages while observing only")]
+    ObserveOnly,
+    /// A capture file could not be read.
+    #[error("could not read capture: {0}")]
+    CaptureRead(#[from] std::io::Error),
+    /// A message handler returned an error.
+    #[error("handler for message code {code:?} ({direction}) failed: {source:#}")]
+    HandlerFailed {
+        /// The code of the operation or event, if the message has one.
+        code: Option<u8>,
+        direction: Direction,
+        source: anyhow::Error,
+    },
+    /// The given configuration is not valid.
+    #[error("invalid configuration: {0}")]
+    Config(String),
+}
@@ -0,0 +1,279 @@
+//! How the properties of the players in the room changed, to tell who renamed themselves mid-match.
+//!
+//! The values of a player are compared before and after every update of their properties, from the join response,
+//! join events and property changes. The first values seen of a player are recorded too, with no old value, so the
+//! history tells what a player was called before they renamed.
+
+use std::{collections::VecDeque, fmt::Display, time::SystemTime};
+
+use photon_lib::indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use super::PlayerActor;
+
+/// How many changes to keep per player. The oldest ones are forgotten first.
+const MAX_CHANGES_PER_ACTOR: usize = 16;
+
+/// How many changes to keep for all players together. The oldest ones are forgotten first, whoever they belong to.
+const MAX_CHANGES: usize = 512;
+
+/// The properties whose changes are recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActorField {
+    Nickname,
+    UserId,
+    Team,
+}
+
+impl Display for ActorField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ActorField::Nickname => write!(f, "nickname"),
+            ActorField::UserId => write!(f, "user id"),
+            ActorField::Team => write!(f, "team"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PropertyChange {
+    pub field: ActorField,
+    /// The value before, [None] if this is the first value seen.
+    pub old: Option<String>,
+    pub new: Option<String>,
+    /// The server time of the change, if the server clock was known.
+    pub server_time: Option<i32>,
+    pub timestamp: SystemTime,
+}
+
+impl Display for PropertyChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let new = self.new.as_deref().unwrap_or("nothing");
+        match &self.old {
+            Some(old) => write!(f, "{} changed from {old} to {new}", self.field),
+            None => write!(f, "{} was {new}", self.field),
+        }
+    }
+}
+
+/// The values of a player that are tracked, taken before an update to compare against.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ActorFields([Option<String>; 3]);
+
+impl ActorFields {
+    const FIELDS: [ActorField; 3] = [ActorField::Nickname, ActorField::UserId, ActorField::Team];
+
+    pub fn of(player: &PlayerActor) -> Self {
+        // the join event carries an empty nickname before the real one is set
+        let nickname = player.nickname.clone().filter(|n| !n.is_empty());
+        Self([
+            nickname,
+            player.user_id.clone(),
+            player.team_number.map(|t| t.to_string()),
+        ])
+    }
+}
+
+/// A nickname change of a player, returned so it can be reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rename {
+    pub actor_id: i32,
+    pub old: String,
+    pub new: String,
+}
+
+/// The recent property changes of each player, see the [module docs](self).
+#[derive(Debug, Default)]
+pub struct ActorHistory {
+    /// The changes by actor, oldest first, each with a sequence number to find the oldest change overall by.
+    actors: IndexMap<i32, VecDeque<(u64, PropertyChange)>>,
+    next_sequence: u64,
+    len: usize,
+}
+
+impl ActorHistory {
+    /// Records what changed between the values of a player before an update and after it. Returns their rename, if
+    /// they changed an earlier nickname.
+    pub fn observe(
+        &mut self,
+        actor_id: i32,
+        before: &ActorFields,
+        after: &PlayerActor,
+        server_time: Option<i32>,
+        timestamp: SystemTime,
+    ) -> Option<Rename> {
+        let after = ActorFields::of(after);
+        let mut rename = None;
+        for ((field, old), new) in ActorFields::FIELDS.iter().zip(&before.0).zip(after.0) {
+            // values are only ever set, never cleared
+            if new.is_none() || *old == new {
+                continue;
+            }
+            if let (ActorField::Nickname, Some(old), Some(new)) = (field, old, &new) {
+                rename = Some(Rename {
+                    actor_id,
+                    old: old.clone(),
+                    new: new.clone(),
+                });
+            }
+            self.push(
+                actor_id,
+                PropertyChange {
+                    field: *field,
+                    old: old.clone(),
+                    new,
+                    server_time,
+                    timestamp,
+                },
+            );
+        }
+        rename
+    }
+
+    fn push(&mut self, actor_id: i32, change: PropertyChange) {
+        let changes = self.actors.entry(actor_id).or_default();
+        if changes.len() >= MAX_CHANGES_PER_ACTOR {
+            changes.pop_front();
+            self.len -= 1;
+        }
+        changes.push_back((self.next_sequence, change));
+        self.next_sequence += 1;
+        self.len += 1;
+
+        if self.len > MAX_CHANGES {
+            let oldest = self
+                .actors
+                .iter()
+                .filter_map(|(actor_id, changes)| Some((changes.front()?.0, *actor_id)))
+                .min()
+                .map(|(_, actor_id)| actor_id);
+            if let Some(actor_id) = oldest {
+                let changes = &mut self.actors[&actor_id];
+                changes.pop_front();
+                if changes.is_empty() {
+                    self.actors.shift_remove(&actor_id);
+                }
+                self.len -= 1;
+            }
+        }
+    }
+
+    /// The recorded changes of a player, oldest first.
+    pub fn changes(&self, actor_id: i32) -> impl DoubleEndedIterator<Item = &PropertyChange> {
+        self.actors
+            .get(&actor_id)
+            .into_iter()
+            .flat_map(|changes| changes.iter().map(|(_, change)| change))
+    }
+
+    /// The last nickname recorded for a player, which is still known after they left.
+    pub fn last_nickname(&self, actor_id: i32) -> Option<&str> {
+        self.changes(actor_id)
+            .rev()
+            .find(|c| c.field == ActorField::Nickname)
+            .and_then(|c| c.new.as_deref())
+    }
+
+    /// How many changes are kept for all players together.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::{
+        ActorField, ActorFields, ActorHistory, Rename, MAX_CHANGES, MAX_CHANGES_PER_ACTOR,
+    };
+    use crate::hax::PlayerActor;
+
+    fn named(nickname: &str) -> PlayerActor {
+        PlayerActor {
+            nickname: Some(nickname.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Renames a player from `old` to `new`.
+    fn rename(history: &mut ActorHistory, actor_id: i32, old: &str, new: &str) -> Option<Rename> {
+        let before = ActorFields::of(&named(old));
+        history.observe(actor_id, &before, &named(new), None, SystemTime::now())
+    }
+
+    #[test]
+    fn records_first_values_and_renames() {
+        let mut history = ActorHistory::default();
+        let joined = PlayerActor {
+            user_id: Some("user".into()),
+            team_number: Some(1),
+            ..named("")
+        };
+        let before = ActorFields::of(&PlayerActor::default());
+        assert_eq!(
+            history.observe(2, &before, &joined, Some(100), SystemTime::now()),
+            None
+        );
+        // the empty nickname of the join event isn't a name to rename from
+        assert_eq!(rename(&mut history, 2, "", "first"), None);
+        assert_eq!(
+            rename(&mut history, 2, "first", "second"),
+            Some(Rename {
+                actor_id: 2,
+                old: "first".into(),
+                new: "second".into(),
+            })
+        );
+        assert_eq!(rename(&mut history, 2, "second", "second"), None);
+
+        let changes = history
+            .changes(2)
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            [
+                "user id was user",
+                "team was 1",
+                "nickname was first",
+                "nickname changed from first to second",
+            ]
+        );
+        assert_eq!(history.changes(2).next().unwrap().server_time, Some(100));
+        assert_eq!(history.last_nickname(2), Some("second"));
+        assert_eq!(history.last_nickname(3), None);
+    }
+
+    #[test]
+    fn evicts_per_actor_and_overall() {
+        let mut history = ActorHistory::default();
+        for i in 0..MAX_CHANGES_PER_ACTOR + 5 {
+            rename(&mut history, 1, &i.to_string(), &(i + 1).to_string());
+        }
+        assert_eq!(history.len(), MAX_CHANGES_PER_ACTOR);
+        let first = history.changes(1).next().unwrap();
+        assert_eq!(first.old.as_deref(), Some("5"));
+        assert_eq!(first.field, ActorField::Nickname);
+
+        // the changes of other players push out the oldest ones, those of actor 1
+        let others = MAX_CHANGES / MAX_CHANGES_PER_ACTOR;
+        for actor_id in 2..others as i32 + 2 {
+            for i in 0..MAX_CHANGES_PER_ACTOR {
+                rename(&mut history, actor_id, &i.to_string(), &(i + 1).to_string());
+            }
+        }
+        assert_eq!(history.len(), MAX_CHANGES);
+        assert_eq!(history.changes(1).count(), 0);
+        assert_eq!(history.last_nickname(1), None);
+        assert_eq!(
+            history.changes(others as i32 + 1).count(),
+            MAX_CHANGES_PER_ACTOR
+        );
+    }
+}
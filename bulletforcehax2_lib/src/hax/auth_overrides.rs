@@ -0,0 +1,213 @@
+//! Rewrites what the client tells the server when authenticating, such as pinning the app version to keep
+//! matchmaking with an older client.
+//!
+//! The app version, region and user id are only replaced in requests that already have them, as the name server and
+//! the lobby expect different parameters. Custom authentication data is merged into the query string of
+//! [CLIENT_AUTHENTICATION_PARAMS](parameter_code::CLIENT_AUTHENTICATION_PARAMS), which is added if the client doesn't
+//! send any. Note that adding parameters changes their count, which is rejected when
+//! [validating rewrites](super::DebugSettings::validate_rewrites).
+
+use photon_lib::{
+    highlevel::{constants::parameter_code, parameters::Parameters},
+    indexmap::IndexMap,
+    ParameterMap,
+};
+
+/// The authentication parameters to send instead of the client's ones. Fields that are [None] are left alone.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuthOverrides {
+    /// The app version, in the `<game version>_<photon version>` format the client uses.
+    pub app_version: Option<String>,
+    pub region: Option<String>,
+    pub user_id: Option<String>,
+    /// Keys to set in the custom authentication parameters. Keys the client sent that aren't in here are kept.
+    pub auth_data: IndexMap<String, String>,
+}
+
+impl AuthOverrides {
+    /// Applies the overrides to the parameters of an AUTHENTICATE request. Returns whether anything changed.
+    ///
+    /// Parameters that aren't overridden, and the order of all of them, are left as they were.
+    pub fn apply(&self, parameters: &mut ParameterMap) -> bool {
+        let mut parameters = Parameters(parameters);
+        let mut changed = false;
+
+        if let (Some(version), Some(original)) = (&self.app_version, parameters.app_version()) {
+            if version != original {
+                parameters.set_app_version(version.clone());
+                changed = true;
+            }
+        }
+        if let (Some(region), Some(original)) = (&self.region, parameters.region()) {
+            if region != original {
+                parameters.set_region(region.clone());
+                changed = true;
+            }
+        }
+        if let (Some(user_id), Some(original)) = (&self.user_id, parameters.user_id()) {
+            if user_id != original {
+                parameters.set_user_id(user_id.clone());
+                changed = true;
+            }
+        }
+
+        if !self.auth_data.is_empty() {
+            let original = parameters
+                .get_as::<&str>(parameter_code::CLIENT_AUTHENTICATION_PARAMS)
+                .unwrap_or_default();
+            let merged = merge_query(original, &self.auth_data);
+            if merged != original {
+                parameters.set(parameter_code::CLIENT_AUTHENTICATION_PARAMS, merged);
+                changed = true;
+            }
+        }
+
+        changed
+    }
+}
+
+/// Sets the given keys in a `key=value&key=value` query string, keeping the other pairs and their order.
+fn merge_query(query: &str, values: &IndexMap<String, String>) -> String {
+    let mut pairs = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => (pair.to_string(), String::new()),
+        })
+        .collect::<IndexMap<_, _>>();
+    for (key, value) in values {
+        pairs.insert(escape(key), escape(value));
+    }
+    pairs
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Percent-encodes everything but the unreserved characters of a URI.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                escaped.push(byte as char)
+            }
+            _ => escaped.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use photon_lib::{
+        highlevel::constants::{operation_code, parameter_code},
+        indexmap::{indexmap, IndexMap},
+        photon_data_type::PhotonDataType,
+        photon_message::{OperationRequest, PhotonMessage},
+        ParameterMap,
+    };
+
+    use super::{merge_query, AuthOverrides};
+    use crate::{
+        hax::timeline::Replay,
+        inspect::CapturedMessage,
+        proxy::{Direction, WebSocketServer},
+    };
+
+    fn authenticate(parameters: ParameterMap) -> Vec<u8> {
+        let mut raw = vec![];
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: operation_code::AUTHENTICATE,
+            parameters,
+        })
+        .to_websocket_bytes(&mut raw)
+        .unwrap();
+        raw
+    }
+
+    fn lobby_parameters() -> ParameterMap {
+        indexmap! {
+            parameter_code::APP_VERSION => PhotonDataType::String("1.90.0_1.99".into()),
+            parameter_code::APPLICATION_ID => PhotonDataType::String("some app".into()),
+            parameter_code::USER_ID => PhotonDataType::String("me".into()),
+            parameter_code::CLIENT_AUTHENTICATION_PARAMS => PhotonDataType::String("token=abc&platform=web".into()),
+            parameter_code::TOKEN => PhotonDataType::String("secret".into()),
+        }
+    }
+
+    fn forward(replay: &mut Replay, raw: Vec<u8>) -> Vec<u8> {
+        replay
+            .forward(&CapturedMessage {
+                timestamp: SystemTime::now(),
+                server: WebSocketServer::LobbyServer,
+                direction: Direction::ClientToServer,
+                raw,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn merges_auth_data() {
+        let values = indexmap! {
+            "platform".to_string() => "desktop".to_string(),
+            "note".to_string() => "a&b c".to_string(),
+        };
+        assert_eq!(
+            merge_query("token=abc&platform=web", &values),
+            "token=abc&platform=desktop&note=a%26b%20c"
+        );
+        assert_eq!(merge_query("", &values), "platform=desktop&note=a%26b%20c");
+        assert_eq!(
+            merge_query("platform=desktop", &IndexMap::new()),
+            "platform=desktop"
+        );
+    }
+
+    #[test]
+    fn rewrites_lobby_authentication() {
+        let mut replay = Replay::default();
+
+        // without overrides the message is forwarded as is
+        let original = authenticate(lobby_parameters());
+        assert_eq!(forward(&mut replay, original.clone()), original);
+
+        replay.state().update_settings(|s| {
+            s.auth_overrides = Some(AuthOverrides {
+                app_version: Some("1.89.0_1.99".into()),
+                region: Some("eu".into()),
+                auth_data: indexmap! { "platform".to_string() => "desktop".to_string() },
+                ..Default::default()
+            })
+        });
+        let forwarded = forward(&mut replay, original);
+
+        // only the overridden values change, everything else keeps its bytes and position
+        let mut expected = lobby_parameters();
+        expected[&parameter_code::APP_VERSION] = PhotonDataType::String("1.89.0_1.99".into());
+        expected[&parameter_code::CLIENT_AUTHENTICATION_PARAMS] =
+            PhotonDataType::String("token=abc&platform=desktop".into());
+        assert_eq!(forwarded, authenticate(expected));
+
+        let state = replay.state();
+        assert!(state.stats.recent_errors.is_empty());
+        // version forcing has to match the rooms against the version of the client
+        assert_eq!(
+            state.global_state.version.as_ref().unwrap().game_version,
+            "1.90.0"
+        );
+        assert_eq!(
+            state
+                .global_state
+                .server_version
+                .as_ref()
+                .unwrap()
+                .game_version,
+            "1.89.0"
+        );
+    }
+}
@@ -0,0 +1,307 @@
+//! Which features can currently do anything, and why the others can't.
+//!
+//! Most features depend on some state, such as the game version or a game connection. Toggling them while that is
+//! missing silently does nothing, so the UI uses this report to grey them out with an explanation.
+
+use std::{fmt::Display, time::Instant};
+
+use super::{features::REGISTRY, GameplayState, HaxState};
+use crate::proxy::WebSocketServer;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Availability {
+    Available,
+    /// The feature can't work right now, with a reason that can be shown to the user.
+    Unavailable(String),
+}
+
+impl Availability {
+    pub fn is_available(&self) -> bool {
+        matches!(self, Availability::Available)
+    }
+}
+
+impl Display for Availability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Availability::Available => write!(f, "available"),
+            Availability::Unavailable(reason) => write!(f, "unavailable ({reason})"),
+        }
+    }
+}
+
+/// The state the predicates look at.
+pub(super) struct Context<'a> {
+    pub hax: &'a HaxState,
+    pub gameplay: Option<&'a GameplayState>,
+    pub now: Instant,
+}
+
+/// Checks whether a feature can work, returning the reason if it can't. What each feature needs is registered in
+/// [features](super::features).
+pub(super) type Predicate = fn(&Context) -> Result<(), String>;
+
+/// Why the other features don't work while observing only.
+const OBSERVING_ONLY: &str = "observing only, nothing is changed or injected";
+
+pub(super) fn in_game<'a>(c: &Context<'a>) -> Result<&'a GameplayState, String> {
+    c.gameplay
+        .ok_or_else(|| "no game server connection, not in a game".into())
+}
+
+pub(super) fn readable(c: &Context, server: WebSocketServer) -> Result<(), String> {
+    if c.hax.encryption.is_active(server) {
+        return Err(format!("the {server} connection is encrypted"));
+    }
+    match c.hax.parse_breaker.connection(server).is_passthrough() {
+        true => Err(format!(
+            "messages of the {server} connection don't parse, the game may have updated"
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Whether each feature can currently work, in a fixed order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureAvailability {
+    pub features: Vec<(&'static str, Availability)>,
+    /// Whether the proxy observes only, see [Settings::is_inert](super::settings::Settings::is_inert).
+    pub observe_only: bool,
+    /// The availability of the features that aren't listed.
+    unlisted: Availability,
+}
+
+/// Nothing listed and everything available, for before the availability was computed.
+impl Default for FeatureAvailability {
+    fn default() -> Self {
+        Self {
+            features: vec![],
+            observe_only: false,
+            unlisted: Availability::Available,
+        }
+    }
+}
+
+impl FeatureAvailability {
+    fn compute(context: &Context) -> Self {
+        let observe_only = context.hax.settings().is_inert();
+        let features = REGISTRY
+            .iter()
+            .filter_map(|r| {
+                let availability = match (r.requires?)(context) {
+                    Ok(()) if observe_only && r.modifies_traffic => {
+                        Availability::Unavailable(OBSERVING_ONLY.into())
+                    }
+                    Ok(()) => Availability::Available,
+                    Err(reason) => Availability::Unavailable(reason),
+                };
+                Some((r.name, availability))
+            })
+            .collect();
+        Self {
+            features,
+            observe_only,
+            unlisted: match observe_only {
+                true => Availability::Unavailable(OBSERVING_ONLY.into()),
+                false => Availability::Available,
+            },
+        }
+    }
+
+    /// The availability of a feature by its [name](super::bandwidth::feature). Features that don't depend on anything
+    /// are not listed, and are available unless observing only.
+    pub fn get(&self, name: &str) -> &Availability {
+        self.features
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, a)| a)
+            .unwrap_or(&self.unlisted)
+    }
+}
+
+impl Display for FeatureAvailability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.observe_only {
+            writeln!(f, "OBSERVE ONLY: all messages are forwarded unchanged")?;
+        }
+        for (name, availability) in &self.features {
+            writeln!(f, "{name}: {availability}")?;
+        }
+        Ok(())
+    }
+}
+
+impl HaxState {
+    /// Checks which features can currently work.
+    pub fn feature_availability(&self) -> FeatureAvailability {
+        FeatureAvailability::compute(&Context {
+            hax: self,
+            gameplay: self.gameplay_state.as_ref().map(|(_, state)| state),
+            now: Instant::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use photon_lib::{ordered_float::OrderedFloat, primitives::Vector3};
+
+    use super::{Context, FeatureAvailability};
+    use crate::{
+        hax::{bandwidth::feature, GameplayState, HaxState, PlayerActor, VersionInfo},
+        proxy::{Direction, WebSocketServer},
+    };
+
+    fn report(hax: &HaxState, gameplay: Option<&GameplayState>) -> String {
+        FeatureAvailability::compute(&Context {
+            hax,
+            gameplay,
+            now: Instant::now(),
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn before_connecting() {
+        let hax = HaxState::default();
+        assert_eq!(
+            report(&hax, None),
+            "\
+password stripping: available
+mobile games: available
+version forcing: unavailable (game_version unknown, not yet authenticated with the lobby)
+lobby sort: available
+room notes: available
+match phase: available
+queue jump: available
+room probe: available
+region forcing: available
+identity randomizer: available
+name spoofing: available
+platform spoof: available
+property firewall: available
+ghost join: available
+stealth host: available
+room rename: unavailable (no game server connection, not in a game)
+property refresh: unavailable (no game server connection, not in a game)
+all interest groups: available
+RPC muting: unavailable (no game server connection, not in a game)
+injected messages: unavailable (no game server connection, not in a game)
+ESP: unavailable (no game server connection, not in a game)
+"
+        );
+        assert!(hax
+            .feature_availability()
+            .get(feature::AUTO_RESPONSES)
+            .is_available());
+    }
+
+    #[test]
+    fn in_game_without_positions() {
+        let mut hax = HaxState::default();
+        hax.global_state.version = Some(VersionInfo {
+            game_version: "1.89.0".into(),
+            photon_version: "1.99".into(),
+        });
+        hax.encryption
+            .record(WebSocketServer::NameServer, Direction::ClientToServer);
+
+        let mut gameplay = GameplayState::default();
+        gameplay.players.insert(1, PlayerActor::default());
+        assert_eq!(
+            report(&hax, Some(&gameplay)),
+            "\
+password stripping: available
+mobile games: available
+version forcing: available
+lobby sort: available
+room notes: available
+match phase: available
+queue jump: available
+room probe: available
+region forcing: unavailable (the nameserver connection is encrypted)
+identity randomizer: available
+name spoofing: available
+platform spoof: available
+property firewall: available
+ghost join: available
+stealth host: available
+room rename: available
+property refresh: available
+all interest groups: available
+RPC muting: available
+injected messages: available
+ESP: unavailable (server time unknown, no serialized updates received yet)
+"
+        );
+
+        gameplay.server_clock.observe(1000, Instant::now());
+        assert_eq!(
+            FeatureAvailability::compute(&Context {
+                hax: &hax,
+                gameplay: Some(&gameplay),
+                now: Instant::now(),
+            })
+            .get(feature::ESP)
+            .to_string(),
+            "unavailable (no player positions decoded yet, the serialized data of this game build may not be supported)"
+        );
+
+        gameplay.players.get_mut(&1).unwrap().position = Some(Vector3(
+            OrderedFloat(0.0),
+            OrderedFloat(0.0),
+            OrderedFloat(0.0),
+        ));
+        let report = report(&hax, Some(&gameplay));
+        assert!(report.contains("ESP: available"), "{report}");
+    }
+
+    #[test]
+    fn observing_only() {
+        let hax = HaxState::default();
+        hax.update_settings(|s| s.observe_only = true);
+        let gameplay = GameplayState::default();
+
+        let report = report(&hax, Some(&gameplay));
+        assert!(
+            report.starts_with("OBSERVE ONLY: all messages are forwarded unchanged\n"),
+            "{report}"
+        );
+        for line in [
+            "password stripping: unavailable (observing only, nothing is changed or injected)",
+            "injected messages: unavailable (observing only, nothing is changed or injected)",
+            // reasons that would apply anyway come first
+            "version forcing: unavailable (game_version unknown, not yet authenticated with the lobby)",
+            "ESP: unavailable (server time unknown, no serialized updates received yet)",
+        ] {
+            assert!(report.contains(line), "{report}");
+        }
+        assert!(!hax
+            .feature_availability()
+            .get(feature::AUTO_RESPONSES)
+            .is_available());
+    }
+
+    #[test]
+    fn encrypted_game_server() {
+        let mut hax = HaxState::default();
+        hax.encryption
+            .record(WebSocketServer::GameServer, Direction::ServerToClient);
+        let gameplay = GameplayState::default();
+
+        let report = report(&hax, Some(&gameplay));
+        for line in [
+            "name spoofing: unavailable (the game connection is encrypted)",
+            "ghost join: unavailable (the game connection is encrypted)",
+            "stealth host: unavailable (the game connection is encrypted)",
+            "room rename: unavailable (the game connection is encrypted)",
+            "all interest groups: unavailable (the game connection is encrypted)",
+            "RPC muting: unavailable (the game connection is encrypted)",
+            "injected messages: available",
+        ] {
+            assert!(report.contains(line), "{report}");
+        }
+    }
+}
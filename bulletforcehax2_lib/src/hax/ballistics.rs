@@ -0,0 +1,329 @@
+//! Where to aim at moving players, for analysing fights. Nothing here changes what the client sends.
+//!
+//! A shot fired now reaches the other players only after our [one-way latency](super::extrapolation::LatencyTracker),
+//! and then needs time to fly to its target, during which the target keeps moving. Taking the target's position `P`
+//! as [extrapolated](super::extrapolation) to the current server time, its velocity `V`, our latency `L`, our
+//! position `S` and the projectile speed `s`, the projectile meets the target after a flight time `t` if the target's
+//! position at that time is `s·t` away from us:
+//!
+//! ```text
+//! |D + V·t| = s·t    where D = P + V·L - S
+//! ```
+//!
+//! Squaring both sides gives a quadratic equation in `t`:
+//!
+//! ```text
+//! (V·V - s²)·t² + 2·(D·V)·t + D·D = 0
+//! ```
+//!
+//! Its smallest positive root is the earliest time the projectile can meet the target, and the shot is aimed at
+//! `P + V·(L + t)`. Without a positive root there is no way to hit the target: it moves away from us at least as fast
+//! as the projectile flies. Targets that are faster than the projectile can still be hit if they move towards us.
+
+use std::time::{Duration, Instant};
+
+use photon_lib::{ordered_float::OrderedFloat, primitives::Vector3};
+
+use super::HaxState;
+
+/// Flight times and speeds closer to zero than this are treated as zero.
+const EPSILON: f32 = 1e-6;
+
+/// Where to aim to hit a target.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Intercept {
+    /// Where the projectile meets the target.
+    pub aim_point: Vector3,
+    /// How far the aim point is ahead of where the target is now.
+    pub lead: Vector3,
+    /// How long the projectile flies until it meets the target.
+    pub time_of_flight: Duration,
+}
+
+/// The lead on a player of the other team.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeadSolution {
+    pub actor_id: i32,
+    pub nickname: Option<String>,
+    /// Where the player is now, as extrapolated from their last positions.
+    pub position: Vector3,
+    /// Where to aim, or [None] if the projectile can't catch up with them.
+    pub intercept: Option<Intercept>,
+}
+
+/// Solves for where to aim a projectile of the given speed to hit a target moving at a constant velocity. Positions
+/// are in units and speeds in units per second. See the [module documentation](self) for the math.
+pub fn solve(
+    shooter: (f32, f32, f32),
+    target: (f32, f32, f32),
+    velocity: (f32, f32, f32),
+    projectile_speed: f32,
+    latency: Duration,
+) -> Option<Intercept> {
+    if projectile_speed <= EPSILON {
+        return None;
+    }
+    let latency = latency.as_secs_f32();
+    // where the target is when the shot reaches the others, relative to us
+    let d = sub(add(target, scale(velocity, latency)), shooter);
+
+    let a = dot(velocity, velocity) - projectile_speed * projectile_speed;
+    let b = 2.0 * dot(d, velocity);
+    let c = dot(d, d);
+    let time_of_flight = if c <= EPSILON {
+        // the target is where we are
+        0.0
+    } else if a.abs() <= EPSILON {
+        // the target is exactly as fast as the projectile, so the equation is linear
+        let t = -c / b;
+        if !t.is_finite() || t <= 0.0 {
+            return None;
+        }
+        t
+    } else {
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let root = discriminant.sqrt();
+        [(-b - root) / (2.0 * a), (-b + root) / (2.0 * a)]
+            .into_iter()
+            .filter(|t| *t > 0.0)
+            .min_by(f32::total_cmp)?
+    };
+
+    let (px, py, pz) = target;
+    let (ax, ay, az) = add(target, scale(velocity, latency + time_of_flight));
+    Some(Intercept {
+        aim_point: vector(ax, ay, az),
+        lead: vector(ax - px, ay - py, az - pz),
+        time_of_flight: Duration::from_secs_f32(time_of_flight),
+    })
+}
+
+impl HaxState {
+    /// The leads on all players of the other team whose position is known and recent, for a projectile of the given
+    /// speed in units per second. Empty if we aren't in a game or don't know our own position yet.
+    pub fn compute_leads(&self, projectile_speed: f32) -> Vec<LeadSolution> {
+        let (_, state) = match &self.gameplay_state {
+            Some(x) => x,
+            None => return vec![],
+        };
+        let us = match state.player_id.and_then(|id| state.players.get(&id)) {
+            Some(us) => us,
+            None => return vec![],
+        };
+        let shooter = match &us.position {
+            Some(position) => position.floats(),
+            None => return vec![],
+        };
+        let latency = state.latency.one_way().unwrap_or_default();
+
+        state
+            .extrapolate_players(Instant::now(), &self.extrapolation)
+            .into_iter()
+            .filter(|(_, extrapolated)| !extrapolated.stale)
+            .filter_map(|(actor_id, extrapolated)| {
+                let player = state.players.get(&actor_id)?;
+                let teammate = us.team_number.is_some() && player.team_number == us.team_number;
+                let dead = player.health.is_some_and(|health| health <= 0.0);
+                if Some(actor_id) == state.player_id || teammate || dead {
+                    return None;
+                }
+                let velocity = player.positions.velocity(&self.extrapolation)?;
+                let intercept = solve(
+                    shooter,
+                    extrapolated.position.floats(),
+                    velocity,
+                    projectile_speed,
+                    latency,
+                );
+                Some(LeadSolution {
+                    actor_id,
+                    nickname: player.nickname.clone(),
+                    position: extrapolated.position,
+                    intercept,
+                })
+            })
+            .collect()
+    }
+}
+
+fn add(a: (f32, f32, f32), b: (f32, f32, f32)) -> (f32, f32, f32) {
+    (a.0 + b.0, a.1 + b.1, a.2 + b.2)
+}
+
+fn sub(a: (f32, f32, f32), b: (f32, f32, f32)) -> (f32, f32, f32) {
+    (a.0 - b.0, a.1 - b.1, a.2 - b.2)
+}
+
+fn scale(a: (f32, f32, f32), factor: f32) -> (f32, f32, f32) {
+    (a.0 * factor, a.1 * factor, a.2 * factor)
+}
+
+fn dot(a: (f32, f32, f32), b: (f32, f32, f32)) -> f32 {
+    a.0 * b.0 + a.1 * b.1 + a.2 * b.2
+}
+
+fn vector(x: f32, y: f32, z: f32) -> Vector3 {
+    Vector3(OrderedFloat(x), OrderedFloat(y), OrderedFloat(z))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{solve, Intercept};
+
+    const ORIGIN: (f32, f32, f32) = (0.0, 0.0, 0.0);
+
+    fn assert_intercept(
+        intercept: Option<Intercept>,
+        aim_point: (f32, f32, f32),
+        time_of_flight: f32,
+    ) {
+        let intercept = intercept.expect("target should be hittable");
+        let (x, y, z) = intercept.aim_point.floats();
+        let close = |a: f32, b: f32| (a - b).abs() < 0.001;
+        assert!(
+            close(x, aim_point.0) && close(y, aim_point.1) && close(z, aim_point.2),
+            "aimed at {:?} instead of {aim_point:?}",
+            (x, y, z)
+        );
+        assert!(
+            close(intercept.time_of_flight.as_secs_f32(), time_of_flight),
+            "flew {:?} instead of {time_of_flight}s",
+            intercept.time_of_flight
+        );
+    }
+
+    #[test]
+    fn stationary_target() {
+        let intercept = solve(ORIGIN, (10.0, 0.0, 0.0), ORIGIN, 100.0, Duration::ZERO);
+        assert_intercept(intercept.clone(), (10.0, 0.0, 0.0), 0.1);
+        assert_eq!(intercept.unwrap().lead.floats(), (0.0, 0.0, 0.0));
+        // latency doesn't matter if the target doesn't move
+        let intercept = solve(
+            ORIGIN,
+            (10.0, 0.0, 0.0),
+            ORIGIN,
+            100.0,
+            Duration::from_secs(1),
+        );
+        assert_intercept(intercept, (10.0, 0.0, 0.0), 0.1);
+    }
+
+    #[test]
+    fn crossing_target() {
+        // |(30, 40t)| = 50t  =>  900 + 1600t² = 2500t²  =>  t = 1
+        let intercept = solve(
+            ORIGIN,
+            (30.0, 0.0, 0.0),
+            (0.0, 40.0, 0.0),
+            50.0,
+            Duration::ZERO,
+        );
+        assert_intercept(intercept.clone(), (30.0, 40.0, 0.0), 1.0);
+        assert_eq!(intercept.unwrap().lead.floats(), (0.0, 40.0, 0.0));
+    }
+
+    #[test]
+    fn latency_moves_the_target_first() {
+        // half a second at 40 units per second moves the target from (30, -20) to (30, 0), then it's as above
+        let intercept = solve(
+            ORIGIN,
+            (30.0, -20.0, 0.0),
+            (0.0, 40.0, 0.0),
+            50.0,
+            Duration::from_millis(500),
+        );
+        assert_intercept(intercept, (30.0, 40.0, 0.0), 1.0);
+    }
+
+    #[test]
+    fn target_moving_towards_and_away() {
+        // 100 - 10t = 40t  =>  t = 2
+        let towards = solve(
+            ORIGIN,
+            (100.0, 0.0, 0.0),
+            (-10.0, 0.0, 0.0),
+            40.0,
+            Duration::ZERO,
+        );
+        assert_intercept(towards, (80.0, 0.0, 0.0), 2.0);
+        // 100 + 10t = 30t  =>  t = 5
+        let away = solve(
+            ORIGIN,
+            (100.0, 0.0, 0.0),
+            (10.0, 0.0, 0.0),
+            30.0,
+            Duration::ZERO,
+        );
+        assert_intercept(away, (150.0, 0.0, 0.0), 5.0);
+        // the shooter doesn't have to be at the origin
+        let away = solve(
+            (0.0, 5.0, 5.0),
+            (100.0, 5.0, 5.0),
+            (10.0, 0.0, 0.0),
+            30.0,
+            Duration::ZERO,
+        );
+        assert_intercept(away, (150.0, 5.0, 5.0), 5.0);
+    }
+
+    #[test]
+    fn targets_faster_than_the_projectile() {
+        // nothing catches up with a target that moves away faster than the projectile
+        let away = solve(
+            ORIGIN,
+            (100.0, 0.0, 0.0),
+            (50.0, 0.0, 0.0),
+            30.0,
+            Duration::ZERO,
+        );
+        assert_eq!(away, None);
+        // or that passes by too fast: |(30, 100t)| = 50t has no solution
+        let crossing = solve(
+            ORIGIN,
+            (30.0, 0.0, 0.0),
+            (0.0, 100.0, 0.0),
+            50.0,
+            Duration::ZERO,
+        );
+        assert_eq!(crossing, None);
+        // but one that comes towards us runs into it: 100 - 50t = 30t  =>  t = 1.25
+        let towards = solve(
+            ORIGIN,
+            (100.0, 0.0, 0.0),
+            (-50.0, 0.0, 0.0),
+            30.0,
+            Duration::ZERO,
+        );
+        assert_intercept(towards, (37.5, 0.0, 0.0), 1.25);
+    }
+
+    #[test]
+    fn target_as_fast_as_the_projectile() {
+        // 100 - 10t = 10t  =>  t = 5
+        let towards = solve(
+            ORIGIN,
+            (100.0, 0.0, 0.0),
+            (-10.0, 0.0, 0.0),
+            10.0,
+            Duration::ZERO,
+        );
+        assert_intercept(towards, (50.0, 0.0, 0.0), 5.0);
+        let away = solve(
+            ORIGIN,
+            (100.0, 0.0, 0.0),
+            (10.0, 0.0, 0.0),
+            10.0,
+            Duration::ZERO,
+        );
+        assert_eq!(away, None);
+        assert_eq!(
+            solve(ORIGIN, (1.0, 0.0, 0.0), ORIGIN, 0.0, Duration::ZERO),
+            None
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! Accounting of the extra traffic caused by each feature.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use photon_lib::indexmap::IndexMap;
+
+/// How many minutes of history to keep.
+const MAX_BUCKETS: usize = 60;
+
+/// The names of features that modify or inject traffic.
+pub mod feature {
+    pub const PASSWORD_STRIPPING: &str = "password stripping";
+    pub const VERSION_FORCING: &str = "version forcing";
+    pub const MOBILE_GAMES: &str = "mobile games";
+    /// Several lobby features applied to the same message.
+    pub const LOBBY_REWRITES: &str = "lobby rewrites";
+    pub const REGION_FORCING: &str = "region forcing";
+    pub const AUTH_OVERRIDES: &str = "auth overrides";
+    pub const NAME_SPOOFING: &str = "name spoofing";
+    /// Presenting a generated user id and nickname, see [identity_randomizer](super::super::identity_randomizer).
+    pub const IDENTITY_RANDOMIZER: &str = "identity randomizer";
+    /// Reporting another platform, device or store than the client's, see [platform_spoof](super::super::platform_spoof).
+    pub const PLATFORM_SPOOF: &str = "platform spoof";
+    pub const PROPERTY_FIREWALL: &str = "property firewall";
+    pub const GHOST_JOIN: &str = "ghost join";
+    /// Holding joins of full lobby rooms until a slot frees, see [hold](super::super::hold).
+    pub const QUEUE_JUMP: &str = "queue jump";
+    pub const STEALTH_HOST: &str = "stealth host";
+    /// Renaming the room we host, see [room_rename](super::super::room_rename).
+    pub const ROOM_RENAME: &str = "room rename";
+    /// Asking the server for every player's properties, see [property_refresh](super::super::property_refresh).
+    pub const PROPERTY_REFRESH: &str = "property refresh";
+    /// Finding out who is in a lobby room, see [room_probe](super::super::room_probe).
+    pub const ROOM_PROBE: &str = "room probe";
+    pub const ALL_INTEREST_GROUPS: &str = "all interest groups";
+    pub const ROOM_NOTES: &str = "room notes";
+    pub const LOBBY_SORT: &str = "lobby sort";
+    /// Annotating lobby rooms with how far along their round is.
+    pub const MATCH_PHASE: &str = "match phase";
+    pub const GAME_SERVER_ROUTING: &str = "game server routing";
+    /// Listing the rooms of an old capture in the lobby.
+    pub const REPLAYED_ROOMS: &str = "replayed rooms";
+    pub const RPC_MUTING: &str = "RPC muting";
+    pub const SIMULATION: &str = "simulation";
+    pub const INJECTED_MESSAGES: &str = "injected messages";
+    pub const AUTO_RESPONSES: &str = "auto-responses";
+    /// Showing where other players are. This only reads traffic, so it never shows up in bandwidth reports.
+    pub const ESP: &str = "ESP";
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FeatureUsage {
+    /// How many messages were rewritten.
+    pub rewritten_messages: u64,
+    /// The difference in size between rewritten and original messages. Negative if the rewrites made them smaller.
+    pub rewrite_delta_bytes: i64,
+    /// How many messages were injected.
+    pub injected_messages: u64,
+    pub injected_bytes: u64,
+}
+
+impl FeatureUsage {
+    fn add(&mut self, other: &FeatureUsage) {
+        self.rewritten_messages += other.rewritten_messages;
+        self.rewrite_delta_bytes += other.rewrite_delta_bytes;
+        self.injected_messages += other.injected_messages;
+        self.injected_bytes += other.injected_bytes;
+    }
+
+    /// The net amount of extra bytes sent because of this feature.
+    pub fn extra_bytes(&self) -> i64 {
+        self.rewrite_delta_bytes + self.injected_bytes as i64
+    }
+}
+
+/// The usage of all features during a single minute.
+#[derive(Debug, Clone)]
+pub struct MinuteBucket {
+    /// Minutes since the unix epoch.
+    pub minute: u64,
+    pub features: IndexMap<&'static str, FeatureUsage>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthReport {
+    /// Usage over the lifetime of the program, per feature.
+    pub totals: IndexMap<&'static str, FeatureUsage>,
+    /// Usage during the last hour, oldest first. Minutes without any usage are skipped.
+    pub minutes: Vec<MinuteBucket>,
+}
+
+#[derive(Debug, Default)]
+struct BandwidthUsage {
+    totals: IndexMap<&'static str, FeatureUsage>,
+    buckets: VecDeque<MinuteBucket>,
+}
+
+impl BandwidthUsage {
+    fn record(&mut self, feature: &'static str, usage: FeatureUsage, now: SystemTime) {
+        self.totals.entry(feature).or_default().add(&usage);
+
+        let minute = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() / 60)
+            .unwrap_or_default();
+        if self.buckets.back().map(|b| b.minute) != Some(minute) {
+            if self.buckets.len() >= MAX_BUCKETS {
+                self.buckets.pop_front();
+            }
+            self.buckets.push_back(MinuteBucket {
+                minute,
+                features: IndexMap::new(),
+            });
+        }
+        let bucket = self.buckets.back_mut().expect("bucket was just ensured");
+        bucket.features.entry(feature).or_default().add(&usage);
+    }
+}
+
+/// Records the traffic caused by features. Cheap to clone, all clones share the same data.
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthMeter {
+    usage: Arc<Mutex<BandwidthUsage>>,
+}
+
+impl BandwidthMeter {
+    pub fn record_rewrite(&self, feature: &'static str, original_len: usize, new_len: usize) {
+        self.record_rewrite_at(feature, original_len, new_len, SystemTime::now())
+    }
+
+    pub fn record_injection(&self, feature: &'static str, len: usize) {
+        self.record_injection_at(feature, len, SystemTime::now())
+    }
+
+    fn record_rewrite_at(
+        &self,
+        feature: &'static str,
+        original_len: usize,
+        new_len: usize,
+        now: SystemTime,
+    ) {
+        let usage = FeatureUsage {
+            rewritten_messages: 1,
+            rewrite_delta_bytes: new_len as i64 - original_len as i64,
+            ..Default::default()
+        };
+        self.lock().record(feature, usage, now);
+    }
+
+    fn record_injection_at(&self, feature: &'static str, len: usize, now: SystemTime) {
+        let usage = FeatureUsage {
+            injected_messages: 1,
+            injected_bytes: len as u64,
+            ..Default::default()
+        };
+        self.lock().record(feature, usage, now);
+    }
+
+    pub fn report(&self) -> BandwidthReport {
+        let usage = self.lock();
+        BandwidthReport {
+            totals: usage.totals.clone(),
+            minutes: usage.buckets.iter().cloned().collect(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BandwidthUsage> {
+        // the data is only ever updated in a single statement, so it stays consistent even if poisoned
+        self.usage.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{feature, BandwidthMeter};
+
+    #[test]
+    fn aggregates_per_minute() {
+        let meter = BandwidthMeter::default();
+        let start = UNIX_EPOCH + Duration::from_secs(60 * 1000);
+
+        meter.record_rewrite_at(feature::PASSWORD_STRIPPING, 100, 90, start);
+        meter.record_rewrite_at(
+            feature::PASSWORD_STRIPPING,
+            100,
+            95,
+            start + Duration::from_secs(30),
+        );
+        meter.record_injection_at(
+            feature::INJECTED_MESSAGES,
+            20,
+            start + Duration::from_secs(61),
+        );
+
+        let report = meter.report();
+        let stripping = report.totals[feature::PASSWORD_STRIPPING];
+        assert_eq!(stripping.rewritten_messages, 2);
+        assert_eq!(stripping.rewrite_delta_bytes, -15);
+        assert_eq!(report.totals[feature::INJECTED_MESSAGES].extra_bytes(), 20);
+
+        assert_eq!(report.minutes.len(), 2);
+        assert_eq!(report.minutes[0].minute, 1000);
+        assert_eq!(report.minutes[0].features.len(), 1);
+        assert_eq!(report.minutes[1].minute, 1001);
+        assert_eq!(
+            report.minutes[1].features[feature::INJECTED_MESSAGES].injected_bytes,
+            20
+        );
+    }
+}
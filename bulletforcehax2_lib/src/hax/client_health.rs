@@ -0,0 +1,324 @@
+//! Detects when the game client itself is lagging, from how regularly it sends its own updates.
+//!
+//! While our player is alive, the client serializes it at a fixed rate, usually 10 to 20 times a second, and sends
+//! movement RPCs in between. If those stop or slow down while updates from other players keep arriving, the
+//! connection is fine and it's the client that stutters, eg. because the browser tab is throttled.
+//!
+//! This works like [link_quality](super::link_quality), but for the other direction. Without other players in the
+//! room there is nothing to compare against, so nothing is flagged.
+
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+/// The RPCs the client sends when moving, counted as updates alongside its serialized data.
+pub const MOVEMENT_RPCS: [&str; 2] = ["DoJump", "ChangeCrouchState"];
+
+#[derive(Debug, Clone)]
+pub struct ClientHealthSettings {
+    /// How many of the most recent updates the metrics are calculated over.
+    pub window: usize,
+    /// An interval longer than this counts as a gap.
+    pub gap_threshold: Duration,
+    /// The client is stuttering if the average interval is longer than this.
+    pub stuttering_interval: Duration,
+    /// The client is stuttering if there are more gaps than this in the window.
+    pub stuttering_gaps: usize,
+    /// The client is frozen if it sent nothing for this long, while updates from others keep arriving.
+    pub frozen_after: Duration,
+}
+
+impl Default for ClientHealthSettings {
+    fn default() -> Self {
+        Self {
+            window: 20,
+            gap_threshold: Duration::from_millis(300),
+            stuttering_interval: Duration::from_millis(150),
+            stuttering_gaps: 2,
+            frozen_after: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientHealth {
+    #[default]
+    Good,
+    Stuttering,
+    Frozen,
+}
+
+impl Display for ClientHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientHealth::Good => write!(f, "good"),
+            ClientHealth::Stuttering => write!(f, "stuttering"),
+            ClientHealth::Frozen => write!(f, "frozen"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientHealthStats {
+    pub health: ClientHealth,
+    /// The average time between updates.
+    pub mean_interval: Duration,
+    /// How many intervals in the window were longer than [ClientHealthSettings::gap_threshold].
+    pub gaps: usize,
+    /// How long ago the client sent its last update.
+    pub since_last: Duration,
+}
+
+impl ClientHealthStats {
+    /// Updates per second, or 0 if there is only one update so far.
+    pub fn rate(&self) -> f64 {
+        match self.mean_interval.is_zero() {
+            true => 0.0,
+            false => 1.0 / self.mean_interval.as_secs_f64(),
+        }
+    }
+}
+
+impl Display for ClientHealthStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({:.1} updates/s, {} gaps, last {} ms ago)",
+            self.health,
+            self.rate(),
+            self.gaps,
+            self.since_last.as_millis()
+        )
+    }
+}
+
+/// The times the client sent its own updates.
+#[derive(Debug, Clone, Default)]
+pub struct CadenceTracker {
+    last: Option<Instant>,
+    intervals: VecDeque<Duration>,
+    /// The health when last classified, to detect transitions.
+    reported: ClientHealth,
+}
+
+impl CadenceTracker {
+    pub fn record(&mut self, now: Instant, settings: &ClientHealthSettings) {
+        if let Some(last) = self.last {
+            while self.intervals.len() >= settings.window.max(1) {
+                self.intervals.pop_front();
+            }
+            self.intervals
+                .push_back(now.saturating_duration_since(last));
+        }
+        self.last = Some(now);
+    }
+
+    /// Our player died, so the client stops sending updates until it respawns. The next update starts a new
+    /// stream, the time spent dead isn't counted.
+    pub fn despawned(&mut self) {
+        self.last = None;
+        self.intervals.clear();
+    }
+
+    /// Calculates the metrics at the given moment. `latest_incoming` is the last time an update from another player
+    /// arrived, the client is only flagged while those keep arriving.
+    ///
+    /// Returns [None] if the client sent no updates since it (re)spawned.
+    pub fn stats(
+        &self,
+        now: Instant,
+        latest_incoming: Option<Instant>,
+        settings: &ClientHealthSettings,
+    ) -> Option<ClientHealthStats> {
+        let last = self.last?;
+        let since_last = now.saturating_duration_since(last);
+
+        let mean_interval = match self.intervals.len() as u32 {
+            0 => Duration::ZERO,
+            n => self.intervals.iter().sum::<Duration>() / n,
+        };
+        let gaps = self
+            .intervals
+            .iter()
+            .filter(|i| **i > settings.gap_threshold)
+            .count();
+
+        let health = if !incoming_flowing(now, latest_incoming, settings) {
+            ClientHealth::Good
+        } else if since_last >= settings.frozen_after {
+            ClientHealth::Frozen
+        } else if mean_interval > settings.stuttering_interval || gaps > settings.stuttering_gaps {
+            ClientHealth::Stuttering
+        } else {
+            ClientHealth::Good
+        };
+
+        Some(ClientHealthStats {
+            health,
+            mean_interval,
+            gaps,
+            since_last,
+        })
+    }
+
+    /// Classifies the client and returns the previous and the new health if it changed.
+    ///
+    /// Nothing changes while no updates from others arrive, as it can't be told apart from our own connection
+    /// stalling.
+    pub fn update(
+        &mut self,
+        now: Instant,
+        latest_incoming: Option<Instant>,
+        settings: &ClientHealthSettings,
+    ) -> Option<(ClientHealth, ClientHealth)> {
+        if !incoming_flowing(now, latest_incoming, settings) {
+            return None;
+        }
+        let health = self.stats(now, latest_incoming, settings)?.health;
+        let previous = std::mem::replace(&mut self.reported, health);
+        (previous != health).then_some((previous, health))
+    }
+}
+
+fn incoming_flowing(
+    now: Instant,
+    latest_incoming: Option<Instant>,
+    settings: &ClientHealthSettings,
+) -> bool {
+    latest_incoming
+        .map(|t| now.saturating_duration_since(t) < settings.frozen_after)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{CadenceTracker, ClientHealth, ClientHealthSettings};
+
+    fn tracker(start: Instant, sent_ms: &[u64]) -> CadenceTracker {
+        let settings = ClientHealthSettings::default();
+        let mut tracker = CadenceTracker::default();
+        for ms in sent_ms {
+            tracker.record(start + Duration::from_millis(*ms), &settings);
+        }
+        tracker
+    }
+
+    fn every(interval_ms: u64, count: u64) -> Vec<u64> {
+        (0..count).map(|i| i * interval_ms).collect()
+    }
+
+    #[test]
+    fn steady_cadence_is_good() {
+        let settings = ClientHealthSettings::default();
+        let start = Instant::now();
+        for interval in [50, 100, 150] {
+            let tracker = tracker(start, &every(interval, 20));
+            let now = start + Duration::from_millis(interval * 19 + 10);
+            let stats = tracker.stats(now, Some(now), &settings).unwrap();
+            assert_eq!(stats.health, ClientHealth::Good, "{interval} ms");
+            assert_eq!(stats.mean_interval, Duration::from_millis(interval));
+        }
+
+        let stats = tracker(start, &every(50, 20))
+            .stats(start + Duration::from_millis(950), None, &settings)
+            .unwrap();
+        assert_eq!(stats.rate(), 20.0);
+        assert_eq!(
+            stats.to_string(),
+            "good (20.0 updates/s, 0 gaps, last 0 ms ago)"
+        );
+        assert_eq!(
+            CadenceTracker::default().stats(start, None, &settings),
+            None
+        );
+    }
+
+    #[test]
+    fn slow_or_gappy_cadence_is_stuttering() {
+        let settings = ClientHealthSettings::default();
+        let start = Instant::now();
+
+        // just below 7 updates a second
+        let slow = tracker(start, &every(151, 10));
+        let now = start + Duration::from_millis(151 * 9);
+        let stats = slow.stats(now, Some(now), &settings).unwrap();
+        assert_eq!(stats.health, ClientHealth::Stuttering);
+        assert_eq!(stats.gaps, 0);
+
+        // a fast stream with hitches, the mean stays low
+        let sent = [0, 50, 100, 401, 450, 500, 801, 850, 900, 1201, 1250];
+        let mut sent = sent.to_vec();
+        sent.extend((1..10).map(|i| 1250 + i * 20));
+        let gappy = tracker(start, &sent);
+        let now = start + Duration::from_millis(1430);
+        let stats = gappy.stats(now, Some(now), &settings).unwrap();
+        assert_eq!(stats.gaps, 3);
+        assert!(stats.mean_interval <= settings.stuttering_interval);
+        assert_eq!(stats.health, ClientHealth::Stuttering);
+
+        // gaps exactly at the threshold don't count, and two gaps are tolerated
+        let mut sent = vec![0, 300, 601, 902];
+        sent.extend((1..18).map(|i| 902 + i * 20));
+        let borderline = tracker(start, &sent);
+        let now = start + Duration::from_millis(1242);
+        let stats = borderline.stats(now, Some(now), &settings).unwrap();
+        assert_eq!(stats.gaps, 2);
+        assert_eq!(stats.health, ClientHealth::Good);
+    }
+
+    #[test]
+    fn frozen_only_while_others_flow() {
+        let settings = ClientHealthSettings::default();
+        let start = Instant::now();
+        let mut client = tracker(start, &every(50, 10));
+        let last = start + Duration::from_millis(450);
+
+        let almost = last + settings.frozen_after - Duration::from_millis(1);
+        assert_eq!(client.update(almost, Some(almost), &settings), None);
+        let frozen = last + settings.frozen_after;
+        assert_eq!(
+            client.update(frozen, Some(frozen), &settings),
+            Some((ClientHealth::Good, ClientHealth::Frozen))
+        );
+        // the transition is only reported once
+        let later = frozen + Duration::from_secs(1);
+        assert_eq!(client.update(later, Some(later), &settings), None);
+
+        // the client catches up, the long pause still drags the average down
+        client.record(later, &settings);
+        assert_eq!(
+            client.update(later, Some(later), &settings),
+            Some((ClientHealth::Frozen, ClientHealth::Stuttering))
+        );
+
+        // nobody else sent anything either, so it's probably our connection
+        let mut client = tracker(start, &every(50, 10));
+        assert_eq!(client.update(frozen, Some(last), &settings), None);
+        let stats = client.stats(frozen, Some(last), &settings).unwrap();
+        assert_eq!(stats.health, ClientHealth::Good);
+        assert_eq!(client.update(frozen, None, &settings), None);
+    }
+
+    #[test]
+    fn dying_is_not_freezing() {
+        let settings = ClientHealthSettings::default();
+        let start = Instant::now();
+        let mut client = tracker(start, &every(50, 10));
+        client.despawned();
+
+        let respawn = start + Duration::from_secs(10);
+        assert_eq!(client.update(respawn, Some(respawn), &settings), None);
+        for i in 0..10 {
+            client.record(respawn + Duration::from_millis(i * 50), &settings);
+        }
+        let now = respawn + Duration::from_millis(460);
+        let stats = client.stats(now, Some(now), &settings).unwrap();
+        assert_eq!(stats.health, ClientHealth::Good);
+        assert_eq!(stats.mean_interval, Duration::from_millis(50));
+        assert_eq!(client.update(now, Some(now), &settings), None);
+    }
+}
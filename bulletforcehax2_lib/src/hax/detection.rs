@@ -0,0 +1,585 @@
+//! Flags players that are likely cheating, based on what their traffic says they do.
+//!
+//! Each heuristic adds to a per-actor suspicion score when it sees something that shouldn't be possible, and the score
+//! decays over time so a single false positive doesn't stick. What counts as impossible changes with game updates, so
+//! every heuristic can be turned off and its thresholds tuned through [DetectionSettings].
+//!
+//! The headshot heuristic needs to be told about kills through [CheatDetector::observe_kill]. Kills and whether they
+//! were headshots aren't parsed from traffic yet, so it has no effect until they are.
+
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use photon_lib::{indexmap::IndexMap, primitives::Vector3};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Heuristic {
+    /// Moving faster than possible between two serialized positions.
+    Speed,
+    /// Shooting faster than any weapon can.
+    FireRate,
+    /// Killing almost exclusively with headshots.
+    Headshots,
+    /// Someone losing health to a player that didn't send a damage RPC.
+    UnexplainedDamage,
+}
+
+impl Display for Heuristic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Heuristic::Speed => write!(f, "speed"),
+            Heuristic::FireRate => write!(f, "fire rate"),
+            Heuristic::Headshots => write!(f, "headshots"),
+            Heuristic::UnexplainedDamage => write!(f, "unexplained damage"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SpeedSettings {
+    pub enabled: bool,
+    /// The fastest a player can move, in units per second.
+    pub max_speed: f32,
+    /// Extra distance allowed on top of `max_speed`, to absorb jitter in the timestamps.
+    pub tolerance: f32,
+    /// Samples further apart than this are not compared, as a lot can happen in between.
+    pub max_interval: Duration,
+    /// How much a violation adds to the score.
+    pub weight: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct FireRateSettings {
+    pub enabled: bool,
+    /// The RPCs that are sent for every shot.
+    pub shot_methods: Vec<String>,
+    /// The highest fire rate of any weapon, in shots per second. We don't know which weapon is used, so this is a
+    /// single cap for all of them.
+    pub max_shots_per_second: f32,
+    /// The window the fire rate is measured over.
+    pub window: Duration,
+    pub weight: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct HeadshotSettings {
+    pub enabled: bool,
+    /// The ratio is only judged after this many kills.
+    pub min_kills: u32,
+    /// A headshot ratio above this is suspicious.
+    pub max_ratio: f32,
+    pub weight: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct DamageSettings {
+    pub enabled: bool,
+    /// The RPCs the attacker sends when they hit someone.
+    pub damage_methods: Vec<String>,
+    /// How long before the health change the damage RPC may have arrived.
+    pub window: Duration,
+    pub weight: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct DetectionSettings {
+    /// A [HaxEvent::SuspectedCheater](super::events::HaxEvent::SuspectedCheater) is emitted when a score reaches this.
+    pub threshold: f32,
+    /// How long it takes for a score to halve.
+    pub half_life: Duration,
+    pub speed: SpeedSettings,
+    pub fire_rate: FireRateSettings,
+    pub headshots: HeadshotSettings,
+    pub unexplained_damage: DamageSettings,
+}
+
+impl Default for DetectionSettings {
+    fn default() -> Self {
+        Self {
+            threshold: 10.0,
+            half_life: Duration::from_secs(60),
+            speed: SpeedSettings {
+                enabled: true,
+                max_speed: 15.0,
+                tolerance: 1.0,
+                max_interval: Duration::from_secs(1),
+                weight: 1.0,
+            },
+            fire_rate: FireRateSettings {
+                enabled: true,
+                shot_methods: vec!["RpcShoot".into()],
+                max_shots_per_second: 20.0,
+                window: Duration::from_secs(1),
+                weight: 2.0,
+            },
+            headshots: HeadshotSettings {
+                enabled: true,
+                min_kills: 10,
+                max_ratio: 0.9,
+                weight: 5.0,
+            },
+            unexplained_damage: DamageSettings {
+                enabled: true,
+                damage_methods: vec!["PlayerHitPlayer".into()],
+                window: Duration::from_secs(2),
+                weight: 2.0,
+            },
+        }
+    }
+}
+
+/// A score reached the threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Detection {
+    pub actor_id: i32,
+    pub score: f32,
+    /// The heuristic that pushed the score over the threshold.
+    pub heuristic: Heuristic,
+}
+
+/// How suspicious an actor is right now.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SuspicionScore {
+    pub score: f32,
+    /// How much each heuristic contributed, decayed like the score.
+    pub by_heuristic: IndexMap<Heuristic, f32>,
+}
+
+#[derive(Debug, Default)]
+struct ActorTrack {
+    score: SuspicionScore,
+    updated: Option<Instant>,
+    /// Whether the score is above the threshold, so crossing it is only reported once.
+    flagged: bool,
+    /// The last position and health, and the server time they were sent at.
+    last_sample: Option<(i32, Vector3, f32)>,
+    last_health: Option<f32>,
+    shots: VecDeque<Instant>,
+    kills: u32,
+    headshots: u32,
+    last_damage_rpc: Option<Instant>,
+}
+
+impl ActorTrack {
+    fn decay(&mut self, now: Instant, half_life: Duration) {
+        if let Some(updated) = self.updated {
+            let elapsed = now.saturating_duration_since(updated).as_secs_f32();
+            let factor = 0.5f32.powf(elapsed / half_life.as_secs_f32().max(f32::EPSILON));
+            self.score.score *= factor;
+            for contribution in self.score.by_heuristic.values_mut() {
+                *contribution *= factor;
+            }
+        }
+        self.updated = Some(now);
+    }
+
+    fn add(
+        &mut self,
+        actor_id: i32,
+        heuristic: Heuristic,
+        weight: f32,
+        now: Instant,
+        settings: &DetectionSettings,
+    ) -> Option<Detection> {
+        self.decay(now, settings.half_life);
+        if self.score.score < settings.threshold {
+            self.flagged = false;
+        }
+
+        self.score.score += weight;
+        *self.score.by_heuristic.entry(heuristic).or_default() += weight;
+
+        if self.score.score >= settings.threshold && !self.flagged {
+            self.flagged = true;
+            return Some(Detection {
+                actor_id,
+                score: self.score.score,
+                heuristic,
+            });
+        }
+        None
+    }
+}
+
+/// The suspicion scores of the actors in a game.
+#[derive(Debug, Default)]
+pub struct CheatDetector {
+    actors: IndexMap<i32, ActorTrack>,
+}
+
+impl CheatDetector {
+    /// How many actors are being tracked.
+    pub fn len(&self) -> usize {
+        self.actors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actors.is_empty()
+    }
+
+    /// Feeds a serialized position of a player, sent at the given server time.
+    pub fn observe_movement(
+        &mut self,
+        actor_id: i32,
+        server_time: i32,
+        position: &Vector3,
+        health: f32,
+        now: Instant,
+        settings: &DetectionSettings,
+    ) -> Option<Detection> {
+        let track = self.actors.entry(actor_id).or_default();
+        let previous = track
+            .last_sample
+            .replace((server_time, position.clone(), health));
+        let speed = &settings.speed;
+        let (previous_time, previous_position, previous_health) = match previous {
+            Some(p) if speed.enabled => p,
+            _ => return None,
+        };
+
+        let interval = server_time.wrapping_sub(previous_time);
+        // players teleport when they respawn, which is when they go from dead to alive
+        if interval <= 0
+            || interval as u128 > speed.max_interval.as_millis()
+            || previous_health <= 0.0
+            || health <= 0.0
+        {
+            return None;
+        }
+
+        let allowed = speed.max_speed * interval as f32 / 1000.0 + speed.tolerance;
+        match distance(&previous_position, position) > allowed {
+            true => track.add(actor_id, Heuristic::Speed, speed.weight, now, settings),
+            false => None,
+        }
+    }
+
+    /// Feeds an RPC that the given actor sent.
+    pub fn observe_rpc(
+        &mut self,
+        actor_id: i32,
+        method_name: &str,
+        now: Instant,
+        settings: &DetectionSettings,
+    ) -> Option<Detection> {
+        let track = self.actors.entry(actor_id).or_default();
+
+        let damage = &settings.unexplained_damage;
+        if damage.damage_methods.iter().any(|m| m == method_name) {
+            track.last_damage_rpc = Some(now);
+        }
+
+        let fire_rate = &settings.fire_rate;
+        if !fire_rate.enabled || !fire_rate.shot_methods.iter().any(|m| m == method_name) {
+            return None;
+        }
+        while matches!(track.shots.front(), Some(t) if now.saturating_duration_since(*t) >= fire_rate.window)
+        {
+            track.shots.pop_front();
+        }
+        track.shots.push_back(now);
+
+        let max_shots = fire_rate.max_shots_per_second * fire_rate.window.as_secs_f32();
+        match track.shots.len() as f32 > max_shots {
+            true => {
+                // judge the next window on its own, instead of flagging every following shot
+                track.shots.clear();
+                track.add(
+                    actor_id,
+                    Heuristic::FireRate,
+                    fire_rate.weight,
+                    now,
+                    settings,
+                )
+            }
+            false => None,
+        }
+    }
+
+    /// Feeds the health of a player, and who they say last damaged them.
+    pub fn observe_health(
+        &mut self,
+        actor_id: i32,
+        health: f32,
+        last_damager_id: i32,
+        now: Instant,
+        settings: &DetectionSettings,
+    ) -> Option<Detection> {
+        let track = self.actors.entry(actor_id).or_default();
+        let previous = track.last_health.replace(health);
+        let damage = &settings.unexplained_damage;
+        match previous {
+            // fall damage and the like have no damager
+            Some(previous)
+                if damage.enabled
+                    && health < previous
+                    && last_damager_id > 0
+                    && last_damager_id != actor_id => {}
+            _ => return None,
+        }
+
+        let attacker = self.actors.entry(last_damager_id).or_default();
+        let explained = attacker
+            .last_damage_rpc
+            .is_some_and(|t| now.saturating_duration_since(t) <= damage.window);
+        match explained {
+            true => None,
+            false => attacker.add(
+                last_damager_id,
+                Heuristic::UnexplainedDamage,
+                damage.weight,
+                now,
+                settings,
+            ),
+        }
+    }
+
+    /// Feeds a kill by the given actor.
+    pub fn observe_kill(
+        &mut self,
+        killer_id: i32,
+        headshot: bool,
+        now: Instant,
+        settings: &DetectionSettings,
+    ) -> Option<Detection> {
+        let track = self.actors.entry(killer_id).or_default();
+        track.kills += 1;
+        if headshot {
+            track.headshots += 1;
+        }
+
+        let headshots = &settings.headshots;
+        if !headshots.enabled || track.kills < headshots.min_kills {
+            return None;
+        }
+        let ratio = track.headshots as f32 / track.kills as f32;
+        match ratio > headshots.max_ratio {
+            true => {
+                // start counting again, so a streak is reported once per `min_kills` kills
+                track.kills = 0;
+                track.headshots = 0;
+                track.add(
+                    killer_id,
+                    Heuristic::Headshots,
+                    headshots.weight,
+                    now,
+                    settings,
+                )
+            }
+            false => None,
+        }
+    }
+
+    /// Forgets an actor, eg. because they left the game.
+    pub fn remove(&mut self, actor_id: i32) {
+        self.actors.shift_remove(&actor_id);
+    }
+
+    /// The decayed scores of all actors with a score, keyed by actor id.
+    pub fn scores(
+        &self,
+        now: Instant,
+        settings: &DetectionSettings,
+    ) -> IndexMap<i32, SuspicionScore> {
+        self.actors
+            .iter()
+            .filter(|(_, track)| track.updated.is_some())
+            .map(|(actor_id, track)| {
+                let mut track = ActorTrack {
+                    score: track.score.clone(),
+                    updated: track.updated,
+                    ..Default::default()
+                };
+                track.decay(now, settings.half_life);
+                (*actor_id, track.score)
+            })
+            .collect()
+    }
+}
+
+fn distance(a: &Vector3, b: &Vector3) -> f32 {
+    let (ax, ay, az) = a.floats();
+    let (bx, by, bz) = b.floats();
+    ((ax - bx).powi(2) + (ay - by).powi(2) + (az - bz).powi(2)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use photon_lib::{ordered_float::OrderedFloat, primitives::Vector3};
+
+    use super::{CheatDetector, DetectionSettings, Heuristic};
+
+    fn at(x: f32) -> Vector3 {
+        Vector3(OrderedFloat(x), OrderedFloat(0.0), OrderedFloat(0.0))
+    }
+
+    /// Feeds positions along the x axis, 100ms apart.
+    fn run_movement(detector: &mut CheatDetector, xs: &[f32], settings: &DetectionSettings) {
+        let now = Instant::now();
+        for (i, x) in xs.iter().enumerate() {
+            detector.observe_movement(1, i as i32 * 100, &at(*x), 100.0, now, settings);
+        }
+    }
+
+    fn score(
+        detector: &CheatDetector,
+        actor_id: i32,
+        now: Instant,
+        settings: &DetectionSettings,
+    ) -> f32 {
+        detector
+            .scores(now, settings)
+            .get(&actor_id)
+            .map_or(0.0, |s| s.score)
+    }
+
+    #[test]
+    fn speed() {
+        let settings = DetectionSettings::default();
+
+        // 10 units per second
+        let mut detector = CheatDetector::default();
+        run_movement(&mut detector, &[0.0, 1.0, 2.0, 3.0, 4.0], &settings);
+        assert_eq!(score(&detector, 1, Instant::now(), &settings), 0.0);
+
+        // 50 units per second for two samples
+        let mut detector = CheatDetector::default();
+        run_movement(&mut detector, &[0.0, 1.0, 6.0, 11.0, 12.0], &settings);
+        let scores = detector.scores(Instant::now(), &settings);
+        let speed = scores[&1].by_heuristic[&Heuristic::Speed];
+        assert!((speed - 2.0).abs() < 0.01, "{speed}");
+
+        // respawning teleports, but isn't suspicious
+        let mut detector = CheatDetector::default();
+        let now = Instant::now();
+        detector.observe_movement(1, 0, &at(0.0), 0.0, now, &settings);
+        detector.observe_movement(1, 100, &at(500.0), 100.0, now, &settings);
+        assert_eq!(score(&detector, 1, now, &settings), 0.0);
+
+        // disabled heuristics don't score
+        let mut settings = DetectionSettings::default();
+        settings.speed.enabled = false;
+        let mut detector = CheatDetector::default();
+        run_movement(&mut detector, &[0.0, 100.0, 200.0], &settings);
+        assert_eq!(score(&detector, 1, Instant::now(), &settings), 0.0);
+    }
+
+    #[test]
+    fn fire_rate() {
+        let mut settings = DetectionSettings::default();
+        settings.fire_rate.max_shots_per_second = 10.0;
+        let start = Instant::now();
+
+        // 10 shots per second is fine
+        let mut detector = CheatDetector::default();
+        for i in 0..30 {
+            let now = start + Duration::from_millis(i * 100);
+            assert_eq!(detector.observe_rpc(1, "RpcShoot", now, &settings), None);
+        }
+        assert_eq!(score(&detector, 1, start, &settings), 0.0);
+
+        // 40 shots per second for two seconds
+        let mut detector = CheatDetector::default();
+        for i in 0..80 {
+            let now = start + Duration::from_millis(i * 25);
+            detector.observe_rpc(1, "RpcShoot", now, &settings);
+            detector.observe_rpc(1, "RpcShowHitmarker", now, &settings);
+        }
+        let scores = detector.scores(start + Duration::from_secs(2), &settings);
+        let fire_rate = scores[&1].by_heuristic[&Heuristic::FireRate];
+        assert!(
+            fire_rate >= 2.0 * settings.fire_rate.weight * 0.9,
+            "{fire_rate}"
+        );
+    }
+
+    #[test]
+    fn headshots() {
+        let settings = DetectionSettings {
+            threshold: 5.0,
+            ..Default::default()
+        };
+        let now = Instant::now();
+
+        // half the kills are headshots
+        let mut detector = CheatDetector::default();
+        for i in 0..20 {
+            assert_eq!(detector.observe_kill(1, i % 2 == 0, now, &settings), None);
+        }
+
+        // every kill is a headshot, which crosses the threshold
+        let mut detection = None;
+        for _ in 0..10 {
+            detection = detection.or(detector.observe_kill(2, true, now, &settings));
+        }
+        let detection = detection.expect("a detection");
+        assert_eq!(detection.actor_id, 2);
+        assert_eq!(detection.heuristic, Heuristic::Headshots);
+        assert_eq!(detector.scores(now, &settings).get(&1), None);
+    }
+
+    #[test]
+    fn unexplained_damage() {
+        let settings = DetectionSettings::default();
+        let start = Instant::now();
+        let mut detector = CheatDetector::default();
+
+        // actor 2 hits actor 1 after sending a damage RPC
+        detector.observe_health(1, 100.0, 0, start, &settings);
+        detector.observe_rpc(2, "PlayerHitPlayer", start, &settings);
+        let later = start + Duration::from_millis(200);
+        assert_eq!(detector.observe_health(1, 70.0, 2, later, &settings), None);
+        assert_eq!(score(&detector, 2, later, &settings), 0.0);
+
+        // actor 3 damages actor 1 without one
+        let later = start + Duration::from_secs(5);
+        detector.observe_health(1, 40.0, 3, later, &settings);
+        let scores = detector.scores(later, &settings);
+        assert_eq!(
+            scores[&3].by_heuristic[&Heuristic::UnexplainedDamage],
+            settings.unexplained_damage.weight
+        );
+
+        // healing isn't damage
+        detector.observe_health(1, 100.0, 3, later, &settings);
+        assert_eq!(
+            score(&detector, 3, later, &settings),
+            settings.unexplained_damage.weight
+        );
+    }
+
+    #[test]
+    fn scores_decay_and_cross_threshold_again() {
+        let settings = DetectionSettings {
+            threshold: 3.0,
+            half_life: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let mut detector = CheatDetector::default();
+
+        let hit = |detector: &mut CheatDetector, now| {
+            detector.observe_health(1, 100.0, 0, now, &settings);
+            detector.observe_health(1, 50.0, 2, now, &settings)
+        };
+        assert_eq!(hit(&mut detector, start), None);
+        let detection = hit(&mut detector, start).expect("crossed the threshold");
+        assert_eq!(detection.score, 4.0);
+        // still above, so not reported again
+        assert_eq!(hit(&mut detector, start), None);
+
+        let later = start + Duration::from_secs(10);
+        let decayed = score(&detector, 2, later, &settings);
+        assert!((decayed - 3.0).abs() < 0.01, "{decayed}");
+
+        let much_later = start + Duration::from_secs(60);
+        assert!(score(&detector, 2, much_later, &settings) < 0.1);
+        assert_eq!(hit(&mut detector, much_later), None);
+        assert!(hit(&mut detector, much_later).is_some());
+    }
+}
@@ -0,0 +1,585 @@
+//! Why a connection to one of the servers closed.
+//!
+//! When the servers kick the client, eg. for being idle, running an old version or being banned, the socket just dies
+//! from the player's point of view. Right before that, Photon usually says why: a DisconnectMessage or a failed
+//! operation response with a debug message, and the close frame has a code and sometimes a reason. These are
+//! collected per server as [DisconnectSignal]s while the connection is open, and classified into a
+//! [DisconnectReason] once it closes. The last [Disconnect] of each server is kept on the
+//! [state](super::HaxState), emitted as [HaxEvent::Disconnected](super::events::HaxEvent::Disconnected) and added to
+//! the [match summary](super::match_summary::MatchSummary) when the game server connection closed.
+//!
+//! Like the [restriction detector](super::restriction_detector), the classification is a table of rules in
+//! [DisconnectSettings], so new kick messages can be recognized without touching the code that collects them.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    time::{Duration, Instant, SystemTime},
+};
+
+use photon_lib::{highlevel::constants::error_code, photon_message::PhotonMessage};
+use serde::{Deserialize, Serialize};
+
+use crate::proxy::{Direction, WebSocketServer};
+
+/// How many signals to keep per connection, the oldest are dropped first.
+const MAX_SIGNALS: usize = 16;
+
+/// Why a connection closed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectReason {
+    /// The server closed the connection on purpose. `reason` is what it said, or the name of the rule if it said
+    /// nothing.
+    ServerKick { reason: String },
+    /// The server dropped the client for being inactive.
+    Idle,
+    /// One side didn't understand what the other sent.
+    ProtocolError,
+    /// The connection broke without being closed.
+    NetworkFailure,
+    /// The client closed the connection itself.
+    ClientInitiated,
+}
+
+impl Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisconnectReason::ServerKick { reason } => write!(f, "kicked by the server: {reason}"),
+            DisconnectReason::Idle => write!(f, "kicked for being idle"),
+            DisconnectReason::ProtocolError => write!(f, "protocol error"),
+            DisconnectReason::NetworkFailure => write!(f, "connection lost"),
+            DisconnectReason::ClientInitiated => write!(f, "closed by the client"),
+        }
+    }
+}
+
+/// Something seen on a connection that may tell why it closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectSignal {
+    /// The server sent a DisconnectMessage.
+    DisconnectMessage {
+        code: i16,
+        debug_message: Option<String>,
+    },
+    /// An operation response from the server failed.
+    FailedResponse {
+        operation_code: u8,
+        return_code: i16,
+        debug_message: Option<String>,
+    },
+    /// The first close frame of the connection, from the given direction. Only the first one is kept, the other side
+    /// just answers it. `code` is [None] if the frame had no payload.
+    Close {
+        direction: Direction,
+        code: Option<u16>,
+        reason: String,
+    },
+    /// Reading from one side of the connection failed.
+    StreamError(String),
+}
+
+impl DisconnectSignal {
+    /// What the server or the client said about it, if anything.
+    fn message(&self) -> Option<&str> {
+        match self {
+            DisconnectSignal::DisconnectMessage { debug_message, .. }
+            | DisconnectSignal::FailedResponse { debug_message, .. } => debug_message.as_deref(),
+            DisconnectSignal::Close { reason, .. } if !reason.is_empty() => Some(reason),
+            DisconnectSignal::Close { .. } | DisconnectSignal::StreamError(_) => None,
+        }
+    }
+}
+
+/// What a [DisconnectRule] looks for in the signals of a connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisconnectPattern {
+    /// A DisconnectMessage with one of the codes, or with any code if there are none.
+    DisconnectCode { codes: Vec<i16> },
+    /// A failed operation response with one of the return codes.
+    ReturnCode { codes: Vec<i16> },
+    /// A DisconnectMessage, failed response or close frame whose message contains one of these, compared without
+    /// case.
+    Message { contains: Vec<String> },
+    /// The first close frame came from the given direction with one of the codes, or with any code if there are
+    /// none.
+    CloseCode {
+        direction: Direction,
+        codes: Vec<u16>,
+    },
+    /// Reading from the connection failed.
+    StreamError,
+}
+
+impl DisconnectPattern {
+    fn matches(&self, signal: &DisconnectSignal) -> bool {
+        match (self, signal) {
+            (
+                DisconnectPattern::DisconnectCode { codes },
+                DisconnectSignal::DisconnectMessage { code, .. },
+            ) => codes.is_empty() || codes.contains(code),
+            (
+                DisconnectPattern::ReturnCode { codes },
+                DisconnectSignal::FailedResponse { return_code, .. },
+            ) => codes.contains(return_code),
+            (DisconnectPattern::Message { contains }, signal) => {
+                let message = match signal.message() {
+                    Some(message) => message.to_lowercase(),
+                    None => return false,
+                };
+                contains.iter().any(|c| message.contains(&c.to_lowercase()))
+            }
+            (
+                DisconnectPattern::CloseCode { direction, codes },
+                DisconnectSignal::Close {
+                    direction: closed_from,
+                    code,
+                    ..
+                },
+            ) => {
+                direction == closed_from
+                    && (codes.is_empty() || code.is_some_and(|c| codes.contains(&c)))
+            }
+            (DisconnectPattern::StreamError, DisconnectSignal::StreamError(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// The kind of [DisconnectReason] a rule gives, without the details taken from the signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectKind {
+    ServerKick,
+    Idle,
+    ProtocolError,
+    NetworkFailure,
+    ClientInitiated,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisconnectRule {
+    pub name: String,
+    pub enabled: bool,
+    pub pattern: DisconnectPattern,
+    pub kind: DisconnectKind,
+}
+
+impl DisconnectRule {
+    fn new(name: &str, pattern: DisconnectPattern, kind: DisconnectKind) -> Self {
+        Self {
+            name: name.into(),
+            enabled: true,
+            pattern,
+            kind,
+        }
+    }
+
+    fn reason(&self, signal: &DisconnectSignal) -> DisconnectReason {
+        match self.kind {
+            DisconnectKind::ServerKick => DisconnectReason::ServerKick {
+                reason: signal.message().unwrap_or(&self.name).into(),
+            },
+            DisconnectKind::Idle => DisconnectReason::Idle,
+            DisconnectKind::ProtocolError => DisconnectReason::ProtocolError,
+            DisconnectKind::NetworkFailure => DisconnectReason::NetworkFailure,
+            DisconnectKind::ClientInitiated => DisconnectReason::ClientInitiated,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DisconnectSettings {
+    /// Only signals this long before the connection closed are looked at, earlier failed responses are usually
+    /// unrelated, eg. a full room.
+    pub window: Duration,
+    /// Checked in order, the first rule that matches any signal decides. If none does, the connection was lost.
+    pub rules: Vec<DisconnectRule>,
+}
+
+impl Default for DisconnectSettings {
+    fn default() -> Self {
+        let words = |words: &[&str]| DisconnectPattern::Message {
+            contains: words.iter().map(|w| w.to_string()).collect(),
+        };
+        Self {
+            window: Duration::from_secs(10),
+            // what the server said comes first, the client closing the connection is usually its answer to that
+            rules: vec![
+                DisconnectRule::new(
+                    "idle",
+                    words(&["idle", "inactiv", "timeout", "timed out"]),
+                    DisconnectKind::Idle,
+                ),
+                DisconnectRule::new(
+                    "version mismatch",
+                    words(&["version"]),
+                    DisconnectKind::ServerKick,
+                ),
+                DisconnectRule::new(
+                    "banned",
+                    words(&["banned", "blocked"]),
+                    DisconnectKind::ServerKick,
+                ),
+                DisconnectRule::new(
+                    "authentication refused",
+                    DisconnectPattern::ReturnCode {
+                        codes: vec![
+                            error_code::INVALID_AUTHENTICATION,
+                            error_code::CUSTOM_AUTHENTICATION_FAILED,
+                            error_code::USER_BLOCKED,
+                        ],
+                    },
+                    DisconnectKind::ServerKick,
+                ),
+                DisconnectRule::new(
+                    "invalid operation",
+                    DisconnectPattern::ReturnCode {
+                        codes: vec![error_code::INVALID_OPERATION],
+                    },
+                    DisconnectKind::ProtocolError,
+                ),
+                DisconnectRule::new(
+                    "disconnect message",
+                    DisconnectPattern::DisconnectCode { codes: vec![] },
+                    DisconnectKind::ServerKick,
+                ),
+                // protocol error, unsupported data, invalid payload and message too big
+                DisconnectRule::new(
+                    "protocol error",
+                    DisconnectPattern::CloseCode {
+                        direction: Direction::ServerToClient,
+                        codes: vec![1002, 1003, 1007, 1009],
+                    },
+                    DisconnectKind::ProtocolError,
+                ),
+                DisconnectRule::new(
+                    "client closed",
+                    DisconnectPattern::CloseCode {
+                        direction: Direction::ClientToServer,
+                        codes: vec![],
+                    },
+                    DisconnectKind::ClientInitiated,
+                ),
+                DisconnectRule::new(
+                    "connection lost",
+                    DisconnectPattern::StreamError,
+                    DisconnectKind::NetworkFailure,
+                ),
+                DisconnectRule::new(
+                    "closed by the server",
+                    DisconnectPattern::CloseCode {
+                        direction: Direction::ServerToClient,
+                        codes: vec![],
+                    },
+                    DisconnectKind::ServerKick,
+                ),
+            ],
+        }
+    }
+}
+
+/// Finds the reason for the signals of a closed connection, and the name of the rule that gave it.
+pub fn classify(
+    signals: &[&DisconnectSignal],
+    settings: &DisconnectSettings,
+) -> (DisconnectReason, Option<String>) {
+    for rule in settings.rules.iter().filter(|r| r.enabled) {
+        if let Some(signal) = signals.iter().find(|s| rule.pattern.matches(s)) {
+            return (rule.reason(signal), Some(rule.name.clone()));
+        }
+    }
+    (DisconnectReason::NetworkFailure, None)
+}
+
+/// A closed connection and why it closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disconnect {
+    pub server: WebSocketServer,
+    pub reason: DisconnectReason,
+    /// The rule that gave the reason, [None] if no rule matched.
+    pub rule: Option<String>,
+    pub at: SystemTime,
+}
+
+impl Display for Disconnect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.server, self.reason)
+    }
+}
+
+#[derive(Debug, Default)]
+struct ConnectionSignals {
+    signals: VecDeque<(Instant, DisconnectSignal)>,
+    /// Whether a close frame was already seen.
+    closing: bool,
+}
+
+/// The signals of each server's current connection, and the last disconnect of each server.
+#[derive(Debug, Default)]
+pub struct DisconnectTracker {
+    open: HashMap<WebSocketServer, ConnectionSignals>,
+    last: HashMap<WebSocketServer, Disconnect>,
+}
+
+impl DisconnectTracker {
+    /// Keeps what a message from the server says about the connection closing.
+    pub fn observe(&mut self, server: WebSocketServer, message: &PhotonMessage, now: Instant) {
+        let signal = match message {
+            PhotonMessage::DisconnectMessage(m) => DisconnectSignal::DisconnectMessage {
+                code: m.code,
+                debug_message: m.debug_message.clone(),
+            },
+            PhotonMessage::OperationResponse(r) | PhotonMessage::InternalOperationResponse(r)
+                if r.return_code != error_code::OK =>
+            {
+                DisconnectSignal::FailedResponse {
+                    operation_code: r.operation_code,
+                    return_code: r.return_code,
+                    debug_message: r.debug_message.clone(),
+                }
+            }
+            _ => return,
+        };
+        self.record(server, signal, now);
+    }
+
+    /// Keeps a signal of the server's current connection. Close frames after the first are left out.
+    pub fn record(&mut self, server: WebSocketServer, signal: DisconnectSignal, now: Instant) {
+        let connection = self.open.entry(server).or_default();
+        if let DisconnectSignal::Close { .. } = signal {
+            if connection.closing {
+                return;
+            }
+            connection.closing = true;
+        }
+        if connection.signals.len() >= MAX_SIGNALS {
+            connection.signals.pop_front();
+        }
+        connection.signals.push_back((now, signal));
+    }
+
+    /// Forgets the signals of the server's previous connection, a new one was opened.
+    pub fn connection_opened(&mut self, server: WebSocketServer) {
+        self.open.remove(&server);
+    }
+
+    /// Classifies the signals of the server's connection that just closed, and keeps the result as its last
+    /// disconnect.
+    pub fn connection_closed(
+        &mut self,
+        server: WebSocketServer,
+        now: Instant,
+        settings: &DisconnectSettings,
+    ) -> Disconnect {
+        let connection = self.open.remove(&server).unwrap_or_default();
+        let recent = connection
+            .signals
+            .iter()
+            .filter(|(at, _)| now.saturating_duration_since(*at) <= settings.window)
+            .map(|(_, signal)| signal)
+            .collect::<Vec<_>>();
+        let (reason, rule) = classify(&recent, settings);
+        let disconnect = Disconnect {
+            server,
+            reason,
+            rule,
+            at: SystemTime::now(),
+        };
+        self.last.insert(server, disconnect.clone());
+        disconnect
+    }
+
+    /// Why the last connection to the server closed.
+    pub fn last(&self, server: WebSocketServer) -> Option<&Disconnect> {
+        self.last.get(&server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use futures_util::lock::Mutex;
+    use photon_lib::{
+        highlevel::constants::{error_code, operation_code, parameter_code},
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{DisconnectMessage, OperationResponse, PhotonMessage},
+    };
+
+    use super::{DisconnectReason, DisconnectSettings, DisconnectSignal, DisconnectTracker};
+    use crate::{
+        hax::{events::HaxEvent, match_summary::MatchSummary, HaxState},
+        proxy::{Direction, WebSocketServer},
+        testsupport::{next_event, ProxiedConnection},
+    };
+
+    fn failed_auth() -> PhotonMessage {
+        PhotonMessage::OperationResponse(OperationResponse {
+            operation_code: operation_code::AUTHENTICATE,
+            return_code: error_code::USER_BLOCKED,
+            debug_message: None,
+            parameters: indexmap! {},
+        })
+    }
+
+    #[test]
+    fn only_recent_signals_count() {
+        let settings = DisconnectSettings::default();
+        let start = Instant::now();
+        let server = WebSocketServer::NameServer;
+        let mut tracker = DisconnectTracker::default();
+        tracker.observe(server, &failed_auth(), start);
+        let client_closed = DisconnectSignal::Close {
+            direction: Direction::ClientToServer,
+            code: Some(1000),
+            reason: String::new(),
+        };
+        let later = start + settings.window + Duration::from_secs(1);
+        tracker.record(server, client_closed, later);
+        // the server answering the close doesn't make it the one that closed
+        let server_closed = DisconnectSignal::Close {
+            direction: Direction::ServerToClient,
+            code: Some(1008),
+            reason: "policy".into(),
+        };
+        tracker.record(server, server_closed, later);
+
+        let disconnect = tracker.connection_closed(server, later, &settings);
+        assert_eq!(disconnect.reason, DisconnectReason::ClientInitiated);
+        assert_eq!(disconnect.rule.as_deref(), Some("client closed"));
+        assert_eq!(tracker.last(server), Some(&disconnect));
+
+        // a refused authentication right before the connection closes is why it did
+        tracker.observe(server, &failed_auth(), later);
+        tracker.record(server, DisconnectSignal::StreamError("reset".into()), later);
+        assert_eq!(
+            tracker.connection_closed(server, later, &settings).reason,
+            DisconnectReason::ServerKick {
+                reason: "authentication refused".into()
+            }
+        );
+        // with nothing to go by, the connection was lost
+        let closed = tracker.connection_closed(server, later, &settings);
+        assert_eq!(closed.reason, DisconnectReason::NetworkFailure);
+        assert_eq!(closed.rule, None);
+    }
+
+    fn join_room(room_name: &str) -> PhotonMessage {
+        PhotonMessage::OperationResponse(OperationResponse {
+            operation_code: operation_code::JOIN_GAME,
+            return_code: 0,
+            debug_message: None,
+            parameters: indexmap! {
+                parameter_code::ROOM_NAME => PhotonDataType::String(room_name.into()),
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn kick_ends_up_in_the_match_summary() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut events = state.lock().await.events.subscribe();
+        let mut conn = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        conn.server.send_all([
+            join_room("room"),
+            PhotonMessage::DisconnectMessage(DisconnectMessage {
+                code: 0,
+                debug_message: Some("Client version mismatch".into()),
+                parameters: indexmap! {},
+            }),
+        ]);
+        conn.client_recv().await;
+        conn.client_recv().await;
+        conn.server.close(1000, "");
+
+        let kicked = DisconnectReason::ServerKick {
+            reason: "Client version mismatch".into(),
+        };
+        let disconnect = next_event(&mut events, |e| match e {
+            HaxEvent::Disconnected(disconnect) => Some(disconnect),
+            _ => None,
+        })
+        .await;
+        assert_eq!(disconnect.server, WebSocketServer::GameServer);
+        assert_eq!(disconnect.reason, kicked);
+        assert_eq!(disconnect.rule.as_deref(), Some("version mismatch"));
+        let summary: MatchSummary = next_event(&mut events, |e| match e {
+            HaxEvent::MatchFinished(summary) => Some(summary),
+            _ => None,
+        })
+        .await;
+        assert_eq!(summary.disconnect, Some(kicked));
+        assert!(summary.to_string().starts_with(
+            "Match in room (disconnected, kicked by the server: Client version mismatch) lasted"
+        ));
+
+        let report = state.lock().await.status_report(false);
+        assert!(report.contains(
+            "game server: disconnected (kicked by the server: Client version mismatch)\n"
+        ));
+    }
+
+    /// How a connection to the lobby ends.
+    enum Ending {
+        ServerCloses(u16, &'static str),
+        ServerVanishes,
+        ClientCloses,
+    }
+
+    async fn disconnect_reason(ending: Ending) -> DisconnectReason {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut events = state.lock().await.events.subscribe();
+        let conn = ProxiedConnection::connect(state.clone(), WebSocketServer::LobbyServer).await;
+        match ending {
+            Ending::ServerCloses(code, reason) => conn.server.close(code, reason),
+            Ending::ServerVanishes => conn.server.drop_connection(),
+            Ending::ClientCloses => conn.close().await,
+        }
+
+        let disconnect = next_event(&mut events, |e| match e {
+            HaxEvent::Disconnected(disconnect) => Some(disconnect),
+            _ => None,
+        })
+        .await;
+        assert_eq!(disconnect.server, WebSocketServer::LobbyServer);
+        let hax = state.lock().await;
+        assert_eq!(
+            hax.last_disconnect(WebSocketServer::LobbyServer),
+            Some(&disconnect)
+        );
+        disconnect.reason
+    }
+
+    #[tokio::test]
+    async fn close_sequences_are_told_apart() {
+        assert_eq!(
+            disconnect_reason(Ending::ServerCloses(1000, "Idle timeout")).await,
+            DisconnectReason::Idle
+        );
+        assert_eq!(
+            disconnect_reason(Ending::ServerCloses(1002, "")).await,
+            DisconnectReason::ProtocolError
+        );
+        assert_eq!(
+            disconnect_reason(Ending::ServerCloses(1008, "")).await,
+            DisconnectReason::ServerKick {
+                reason: "closed by the server".into()
+            }
+        );
+        assert_eq!(
+            disconnect_reason(Ending::ServerVanishes).await,
+            DisconnectReason::NetworkFailure
+        );
+        assert_eq!(
+            disconnect_reason(Ending::ClientCloses).await,
+            DisconnectReason::ClientInitiated
+        );
+    }
+}
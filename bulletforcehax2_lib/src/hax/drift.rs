@@ -0,0 +1,437 @@
+//! Notices game updates by comparing the property keys and RPCs seen in traffic against a [GameProtocolProfile].
+
+use std::collections::BTreeSet;
+
+use photon_lib::{
+    highlevel::{
+        constants::{event_code, operation_code, parameter_code, pun_event_code},
+        parameters::Parameters,
+    },
+    photon_data_type::PhotonDataType,
+    photon_message::PhotonMessage,
+    PhotonHashmap,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{loadout::Loadout, profile::GameProtocolProfile};
+
+/// How many distinct keys of each kind are remembered, so garbage data can't grow the sets forever.
+const MAX_OBSERVED: usize = 512;
+
+/// The property keys and RPCs that were seen over a session.
+#[derive(Debug, Default)]
+pub struct DriftDetector {
+    room_property_keys: BTreeSet<String>,
+    actor_property_keys: BTreeSet<String>,
+    rpc_indices: BTreeSet<u8>,
+    /// RPCs that were called by name instead of by index.
+    rpc_method_names: BTreeSet<String>,
+    unknown_weapon_ids: BTreeSet<i32>,
+    unknown_camo_ids: BTreeSet<i32>,
+    /// Whether anything new was seen since the last call to [Self::take_changed].
+    changed: bool,
+}
+
+impl DriftDetector {
+    pub fn observe(&mut self, message: &PhotonMessage) {
+        match message {
+            // checked first, as serialize events make up most of the traffic
+            PhotonMessage::EventData(event)
+                if event.code == pun_event_code::SEND_SERIALIZE
+                    || event.code == pun_event_code::SEND_SERIALIZE_RELIABLE => {}
+            PhotonMessage::EventData(event) => {
+                let parameters = Parameters(&event.parameters);
+                match event.code {
+                    event_code::GAME_LIST | event_code::GAME_LIST_UPDATE => {
+                        let games = parameters.get_as::<&PhotonHashmap>(parameter_code::GAME_LIST);
+                        for room in games.into_iter().flat_map(|games| games.values()) {
+                            if let PhotonDataType::Hashtable(room) = room {
+                                self.observe_room_properties(room);
+                            }
+                        }
+                    }
+                    event_code::JOIN => {
+                        if let Some(properties) = parameters.player_properties() {
+                            self.observe_actor_properties(properties);
+                        }
+                    }
+                    event_code::PROPERTIES_CHANGED => {
+                        if let Some(properties) = parameters.properties() {
+                            match parameters.target_actor_nr() {
+                                Some(actor_nr) if actor_nr != 0 => {
+                                    self.observe_actor_properties(properties)
+                                }
+                                _ => self.observe_room_properties(properties),
+                            }
+                        }
+                    }
+                    pun_event_code::RPC => {
+                        if let Some(call) = parameters.get_as(parameter_code::DATA) {
+                            self.observe_rpc(call);
+                        }
+                    }
+                    pun_event_code::INSTANTIATION => {
+                        if let Some(data) = parameters.get_as(parameter_code::DATA) {
+                            self.observe_instantiation(data);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            PhotonMessage::OperationRequest(request) => {
+                let parameters = Parameters(&request.parameters);
+                match request.operation_code {
+                    operation_code::SET_PROPERTIES => {
+                        if let Some(properties) = parameters.properties() {
+                            match parameters.actor_nr() {
+                                Some(_) => self.observe_actor_properties(properties),
+                                None => self.observe_room_properties(properties),
+                            }
+                        }
+                    }
+                    operation_code::RAISE_EVENT
+                        if parameters.code() == Some(pun_event_code::RPC) =>
+                    {
+                        if let Some(call) = parameters.get_as(parameter_code::DATA) {
+                            self.observe_rpc(call);
+                        }
+                    }
+                    operation_code::RAISE_EVENT
+                        if parameters.code() == Some(pun_event_code::INSTANTIATION) =>
+                    {
+                        if let Some(data) = parameters.get_as(parameter_code::DATA) {
+                            self.observe_instantiation(data);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            PhotonMessage::OperationResponse(response)
+                if response.operation_code == operation_code::JOIN_GAME
+                    || response.operation_code == operation_code::CREATE_GAME =>
+            {
+                let parameters = Parameters(&response.parameters);
+                if let Some(properties) = parameters.game_properties() {
+                    self.observe_room_properties(properties);
+                }
+                // keyed by actor number
+                for properties in parameters
+                    .player_properties()
+                    .into_iter()
+                    .flat_map(|p| p.values())
+                {
+                    if let PhotonDataType::Hashtable(properties) = properties {
+                        self.observe_actor_properties(properties);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn observe_room_properties(&mut self, properties: &PhotonHashmap) {
+        for key in properties.keys() {
+            if let PhotonDataType::String(key) = key {
+                self.changed |= insert_bounded(&mut self.room_property_keys, key);
+            }
+        }
+    }
+
+    fn observe_actor_properties(&mut self, properties: &PhotonHashmap) {
+        for key in properties.keys() {
+            if let PhotonDataType::String(key) = key {
+                self.changed |= insert_bounded(&mut self.actor_property_keys, key);
+            }
+        }
+
+        let mut loadout = Loadout::default();
+        loadout.merge_properties(properties.iter().filter_map(|(key, value)| match key {
+            PhotonDataType::String(key) => Some((key.as_str(), value)),
+            _ => None,
+        }));
+        self.observe_loadout(&loadout);
+    }
+
+    /// Records the loadout a player spawns with, see
+    /// [InstantiationEventData](photon_lib::highlevel::structs::InstantiationEventData).
+    fn observe_instantiation(&mut self, data: &PhotonHashmap) {
+        let is_player = matches!(
+            data.get(&PhotonDataType::Byte(0)),
+            Some(PhotonDataType::String(prefab)) if prefab == "PlayerBody"
+        );
+        if let (true, Some(PhotonDataType::ObjectArray(data))) =
+            (is_player, data.get(&PhotonDataType::Byte(5)))
+        {
+            let mut loadout = Loadout::default();
+            loadout.merge_instantiation_data(data);
+            self.observe_loadout(&loadout);
+        }
+    }
+
+    fn observe_loadout(&mut self, loadout: &Loadout) {
+        for id in loadout.unknown_weapon_ids() {
+            if self.unknown_weapon_ids.len() < MAX_OBSERVED {
+                self.changed |= self.unknown_weapon_ids.insert(id);
+            }
+        }
+        if let Some(id) = loadout.unknown_camo_id() {
+            if self.unknown_camo_ids.len() < MAX_OBSERVED {
+                self.changed |= self.unknown_camo_ids.insert(id);
+            }
+        }
+    }
+
+    /// Records the method of an RPC call, see [RpcCall](photon_lib::highlevel::structs::RpcCall).
+    fn observe_rpc(&mut self, call: &PhotonHashmap) {
+        if let Some(PhotonDataType::Byte(index)) = call.get(&PhotonDataType::Byte(5)) {
+            if self.rpc_indices.len() < MAX_OBSERVED {
+                self.changed |= self.rpc_indices.insert(*index);
+            }
+        } else if let Some(PhotonDataType::String(name)) = call.get(&PhotonDataType::Byte(3)) {
+            self.changed |= insert_bounded(&mut self.rpc_method_names, name);
+        }
+    }
+
+    /// Whether anything was seen for the first time since the last call.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
+    }
+
+    /// Compares what was seen against a profile.
+    pub fn report(&self, profile: &GameProtocolProfile) -> UpdateDriftReport {
+        let method_names = &profile.rpc_method_names;
+        let called_methods = self
+            .rpc_indices
+            .iter()
+            .filter_map(|i| method_names.get(*i as usize))
+            .chain(&self.rpc_method_names)
+            .collect::<BTreeSet<_>>();
+
+        UpdateDriftReport {
+            profile: profile.name.clone(),
+            unknown_room_properties: unknown(&self.room_property_keys, &profile.room_property_keys),
+            missing_room_properties: missing(&self.room_property_keys, &profile.room_property_keys),
+            unknown_actor_properties: unknown(
+                &self.actor_property_keys,
+                &profile.actor_property_keys,
+            ),
+            missing_actor_properties: missing(
+                &self.actor_property_keys,
+                &profile.actor_property_keys,
+            ),
+            unknown_rpc_indices: self
+                .rpc_indices
+                .iter()
+                .copied()
+                .filter(|i| *i as usize >= method_names.len())
+                .collect(),
+            unknown_rpc_method_names: self
+                .rpc_method_names
+                .iter()
+                .filter(|name| !method_names.contains(name))
+                .cloned()
+                .collect(),
+            uncalled_rpc_method_names: method_names
+                .iter()
+                .filter(|name| !called_methods.contains(name))
+                .cloned()
+                .collect(),
+            unknown_weapon_ids: self.unknown_weapon_ids.iter().copied().collect(),
+            unknown_camo_ids: self.unknown_camo_ids.iter().copied().collect(),
+        }
+    }
+}
+
+/// The differences between what a [GameProtocolProfile] expects and what was seen in traffic.
+///
+/// Unknown entries were seen but are not in the profile, which likely means the game updated. Missing entries are in
+/// the profile but were not seen, which can also mean the session was just too short to see them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpdateDriftReport {
+    /// The name of the profile that was compared against.
+    pub profile: String,
+    pub unknown_room_properties: Vec<String>,
+    pub missing_room_properties: Vec<String>,
+    pub unknown_actor_properties: Vec<String>,
+    pub missing_actor_properties: Vec<String>,
+    /// RPC indices that are out of range of the profile's method list.
+    pub unknown_rpc_indices: Vec<u8>,
+    pub unknown_rpc_method_names: Vec<String>,
+    pub uncalled_rpc_method_names: Vec<String>,
+    /// Weapon ids that are not in [WEAPON_NAMES](crate::protocol::loadout::WEAPON_NAMES).
+    #[serde(default)]
+    pub unknown_weapon_ids: Vec<i32>,
+    /// Camo ids that are not in [CAMO_NAMES](crate::protocol::loadout::CAMO_NAMES).
+    #[serde(default)]
+    pub unknown_camo_ids: Vec<i32>,
+}
+
+impl UpdateDriftReport {
+    /// The report as pretty-printed json, to attach to issues.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("drift report should serialize")
+    }
+
+    /// Whether anything was seen that the profile does not know about.
+    pub fn has_unknowns(&self) -> bool {
+        !self.unknown_room_properties.is_empty()
+            || !self.unknown_actor_properties.is_empty()
+            || !self.unknown_rpc_indices.is_empty()
+            || !self.unknown_rpc_method_names.is_empty()
+            || !self.unknown_weapon_ids.is_empty()
+            || !self.unknown_camo_ids.is_empty()
+    }
+}
+
+fn insert_bounded(set: &mut BTreeSet<String>, key: &str) -> bool {
+    if set.len() >= MAX_OBSERVED || set.contains(key) {
+        return false;
+    }
+    set.insert(key.to_string())
+}
+
+fn unknown(observed: &BTreeSet<String>, known: &[String]) -> Vec<String> {
+    observed
+        .iter()
+        .filter(|key| !known.contains(key))
+        .cloned()
+        .collect()
+}
+
+fn missing(observed: &BTreeSet<String>, known: &[String]) -> Vec<String> {
+    known
+        .iter()
+        .filter(|key| !observed.contains(*key))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::{
+        highlevel::constants::{event_code, operation_code, parameter_code, pun_event_code},
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationRequest, PhotonMessage},
+    };
+
+    use super::DriftDetector;
+    use crate::protocol::profile::GameProtocolProfile;
+
+    fn profile() -> GameProtocolProfile {
+        GameProtocolProfile {
+            name: "test".into(),
+            room_property_keys: vec!["roomName".into(), "storeID".into()],
+            actor_property_keys: vec!["teamNumber".into()],
+            rpc_method_names: vec!["Chat".into(), "RpcShoot".into(), "RpcDie".into()],
+        }
+    }
+
+    fn rpc_event(call: PhotonDataType) -> PhotonMessage {
+        PhotonMessage::EventData(EventData {
+            code: pun_event_code::RPC,
+            parameters: indexmap! {
+                parameter_code::DATA => call,
+            },
+        })
+    }
+
+    #[test]
+    fn reports_unknown_and_missing() {
+        let mut detector = DriftDetector::default();
+        detector.observe(&PhotonMessage::EventData(EventData {
+            code: event_code::GAME_LIST,
+            parameters: indexmap! {
+                parameter_code::GAME_LIST => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::String("room".into()) => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Byte(255) => PhotonDataType::Byte(12),
+                        PhotonDataType::String("roomName".into()) => PhotonDataType::String("room".into()),
+                        PhotonDataType::String("newThing".into()) => PhotonDataType::Boolean(true),
+                    }),
+                }),
+            },
+        }));
+        detector.observe(&PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: operation_code::SET_PROPERTIES,
+            parameters: indexmap! {
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                parameter_code::PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::String("teamNumber".into()) => PhotonDataType::Byte(1),
+                }),
+            },
+        }));
+        detector.observe(&rpc_event(PhotonDataType::Hashtable(indexmap! {
+            PhotonDataType::Byte(0) => PhotonDataType::Integer(1001),
+            PhotonDataType::Byte(5) => PhotonDataType::Byte(1),
+        })));
+        detector.observe(&rpc_event(PhotonDataType::Hashtable(indexmap! {
+            PhotonDataType::Byte(0) => PhotonDataType::Integer(1001),
+            PhotonDataType::Byte(5) => PhotonDataType::Byte(40),
+        })));
+        detector.observe(&rpc_event(PhotonDataType::Hashtable(indexmap! {
+            PhotonDataType::Byte(0) => PhotonDataType::Integer(1001),
+            PhotonDataType::Byte(3) => PhotonDataType::String("Chat".into()),
+        })));
+
+        let report = detector.report(&profile());
+        assert!(report.has_unknowns());
+        assert_eq!(report.unknown_room_properties, ["newThing"]);
+        assert_eq!(report.missing_room_properties, ["storeID"]);
+        assert!(report.unknown_actor_properties.is_empty());
+        assert!(report.missing_actor_properties.is_empty());
+        assert_eq!(report.unknown_rpc_indices, [40]);
+        assert!(report.unknown_rpc_method_names.is_empty());
+        assert_eq!(report.uncalled_rpc_method_names, ["RpcDie"]);
+
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(
+            serde_json::from_str::<super::UpdateDriftReport>(&json).unwrap(),
+            report
+        );
+    }
+
+    #[test]
+    fn tracks_changes() {
+        let mut detector = DriftDetector::default();
+        let message = rpc_event(PhotonDataType::Hashtable(indexmap! {
+            PhotonDataType::Byte(5) => PhotonDataType::Byte(2),
+        }));
+
+        detector.observe(&message);
+        assert!(detector.take_changed());
+        detector.observe(&message);
+        assert!(!detector.take_changed());
+    }
+
+    #[test]
+    fn reports_unknown_loadout_ids() {
+        let mut detector = DriftDetector::default();
+        detector.observe(&PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: operation_code::SET_PROPERTIES,
+            parameters: indexmap! {
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                parameter_code::PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::String("primaryWeapon".into()) => PhotonDataType::Integer(250),
+                    PhotonDataType::String("secondaryWeapon".into()) => PhotonDataType::Integer(9),
+                }),
+            },
+        }));
+        detector.observe(&PhotonMessage::EventData(EventData {
+            code: pun_event_code::INSTANTIATION,
+            parameters: indexmap! {
+                parameter_code::DATA => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::Byte(0) => PhotonDataType::String("PlayerBody".into()),
+                    PhotonDataType::Byte(5) => PhotonDataType::ObjectArray(vec![
+                        PhotonDataType::ByteArray(vec![0, 9, 77]),
+                    ]),
+                }),
+            },
+        }));
+
+        let report = detector.report(&GameProtocolProfile::builtin());
+        assert!(report.unknown_actor_properties.is_empty());
+        assert_eq!(report.unknown_weapon_ids, [250]);
+        assert_eq!(report.unknown_camo_ids, [77]);
+        assert!(report.has_unknowns());
+    }
+}
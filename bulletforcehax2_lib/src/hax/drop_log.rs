@@ -0,0 +1,180 @@
+//! A record of the messages that features decided not to forward.
+
+use std::{collections::VecDeque, fmt::Display, time::SystemTime};
+
+use photon_lib::indexmap::IndexMap;
+
+use super::{property_firewall::PropertyTarget, room_probe::ProbeMethod};
+use crate::proxy::{Direction, WebSocketServer};
+
+/// How many dropped messages to keep.
+const MAX_ENTRIES: usize = 200;
+
+/// Why a message was dropped instead of forwarded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DropReason {
+    /// A cosmetic RPC was muted. Holds the sender and method name.
+    MutedRpc { sender: i32, method_name: String },
+    /// All properties of a SET_PROPERTIES request were blocked by the property firewall. Holds how many there were.
+    StrippedProperties {
+        target: PropertyTarget,
+        count: usize,
+    },
+    /// Our player's instantiation was dropped so other clients don't spawn us. Holds the instantiation id.
+    GhostJoin { instantiation_id: i32 },
+    /// The client tried to join a room that was replayed from a capture, which the server doesn't know. Holds the
+    /// room's id.
+    ReplayedRoomJoin { room_id: String },
+    /// The response to a room rename, which the client didn't ask for. Holds whether the server accepted it.
+    RoomRename { accepted: bool },
+    /// The response to a refresh of the actor properties, which the client didn't ask for. Holds whether the server
+    /// sent them.
+    PropertyRefresh { accepted: bool },
+    /// The lobby's answer to a probe of a room, which the client didn't ask for. Holds whether the lobby accepted it.
+    RoomProbe { method: ProbeMethod, accepted: bool },
+}
+
+impl DropReason {
+    /// The name of the feature that dropped the message, see [feature](super::bandwidth::feature).
+    pub fn feature(&self) -> &'static str {
+        match self {
+            DropReason::MutedRpc { .. } => super::bandwidth::feature::RPC_MUTING,
+            DropReason::StrippedProperties { .. } => super::bandwidth::feature::PROPERTY_FIREWALL,
+            DropReason::GhostJoin { .. } => super::bandwidth::feature::GHOST_JOIN,
+            DropReason::ReplayedRoomJoin { .. } => super::bandwidth::feature::REPLAYED_ROOMS,
+            DropReason::RoomRename { .. } => super::bandwidth::feature::ROOM_RENAME,
+            DropReason::PropertyRefresh { .. } => super::bandwidth::feature::PROPERTY_REFRESH,
+            DropReason::RoomProbe { .. } => super::bandwidth::feature::ROOM_PROBE,
+        }
+    }
+
+    /// A human-readable description of what was dropped.
+    pub fn detail(&self) -> String {
+        match self {
+            DropReason::MutedRpc {
+                sender,
+                method_name,
+            } => format!("{method_name} from actor {sender}"),
+            DropReason::StrippedProperties { target, count } => {
+                format!("all {count} properties of {target}")
+            }
+            DropReason::GhostJoin { instantiation_id } => {
+                format!("our player instantiation {instantiation_id}")
+            }
+            DropReason::ReplayedRoomJoin { room_id } => format!("joining {room_id}"),
+            DropReason::RoomRename { accepted: true } => "the response to our rename".into(),
+            DropReason::RoomRename { accepted: false } => "the rejection of our rename".into(),
+            DropReason::PropertyRefresh { accepted: true } => "the properties we asked for".into(),
+            DropReason::PropertyRefresh { accepted: false } => {
+                "the refusal to send the properties we asked for".into()
+            }
+            DropReason::RoomProbe {
+                method,
+                accepted: true,
+            } => format!("the answer to our {method} probe"),
+            DropReason::RoomProbe {
+                method,
+                accepted: false,
+            } => format!("the refusal of our {method} probe"),
+        }
+    }
+}
+
+impl Display for DropReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.feature(), self.detail())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DroppedMessage {
+    pub timestamp: SystemTime,
+    pub server: WebSocketServer,
+    pub direction: Direction,
+    /// The operation or event code of the message, if it has one.
+    pub code: Option<u8>,
+    pub reason: DropReason,
+}
+
+/// The most recent dropped messages, and how many were dropped by each feature in total.
+#[derive(Debug, Default)]
+pub struct DropLog {
+    entries: VecDeque<DroppedMessage>,
+    counts: IndexMap<&'static str, u64>,
+}
+
+impl DropLog {
+    pub fn record(&mut self, message: DroppedMessage) {
+        *self.counts.entry(message.reason.feature()).or_default() += 1;
+
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(message);
+    }
+
+    /// The most recent dropped messages, oldest first.
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &DroppedMessage> + ExactSizeIterator {
+        self.entries.iter()
+    }
+
+    /// How many messages each feature dropped, including the ones that are no longer in [Self::entries].
+    pub fn counts(&self) -> &IndexMap<&'static str, u64> {
+        &self.counts
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::{DropLog, DropReason, DroppedMessage, MAX_ENTRIES};
+    use crate::{
+        hax::bandwidth::feature,
+        proxy::{Direction, WebSocketServer},
+    };
+
+    fn muted(sender: i32) -> DroppedMessage {
+        DroppedMessage {
+            timestamp: SystemTime::now(),
+            server: WebSocketServer::GameServer,
+            direction: Direction::ServerToClient,
+            code: Some(200),
+            reason: DropReason::MutedRpc {
+                sender,
+                method_name: "PlayTaunt".into(),
+            },
+        }
+    }
+
+    #[test]
+    fn bounded_with_total_counts() {
+        let mut log = DropLog::default();
+        for sender in 0..MAX_ENTRIES as i32 + 5 {
+            log.record(muted(sender));
+        }
+
+        assert_eq!(log.entries().len(), MAX_ENTRIES);
+        assert_eq!(
+            log.entries().next().map(|m| &m.reason),
+            Some(&DropReason::MutedRpc {
+                sender: 5,
+                method_name: "PlayTaunt".into()
+            })
+        );
+        assert_eq!(log.counts()[feature::RPC_MUTING], MAX_ENTRIES as u64 + 5);
+        assert_eq!(log.total(), MAX_ENTRIES as u64 + 5);
+    }
+
+    #[test]
+    fn reason_display() {
+        assert_eq!(
+            muted(3).reason.to_string(),
+            "RPC muting: PlayTaunt from actor 3"
+        );
+    }
+}
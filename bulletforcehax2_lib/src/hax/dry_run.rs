@@ -0,0 +1,247 @@
+//! Running features without letting them touch the traffic, to see what a rewrite would do before trusting it.
+//!
+//! In dry run, handlers run and update the state as usual, but the messages they change or drop are forwarded as they
+//! came in. What would have happened is recorded in a [DryRunLog] instead. Messages that features send on their own,
+//! such as the requests queued when a feature is toggled, are still sent.
+
+use std::{collections::VecDeque, fmt::Display, time::SystemTime};
+
+use photon_lib::{indexmap::IndexMap, photon_message::PhotonMessage};
+
+use super::{drop_log::DropReason, features};
+use crate::{
+    inspect::{message_code, message_parameters, message_type_name},
+    proxy::{Direction, WebSocketServer},
+};
+
+/// How many entries to keep.
+const MAX_ENTRIES: usize = 200;
+
+/// Which features run in dry run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DryRunSettings {
+    /// Whether all features run in dry run, unless overridden in [Self::features].
+    pub enabled: bool,
+    /// Per-feature overrides of [Self::enabled], keyed by [feature](super::bandwidth::feature) name. See
+    /// [HaxState::list_features](super::HaxState::list_features) for which features there are.
+    pub features: IndexMap<&'static str, bool>,
+}
+
+impl DryRunSettings {
+    /// Whether the changes of a feature should only be logged.
+    pub fn is_dry(&self, feature: &str) -> bool {
+        self.features.get(feature).copied().unwrap_or(self.enabled)
+    }
+}
+
+/// What a feature would have done to a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WouldHave {
+    Changed {
+        feature: &'static str,
+        before_summary: String,
+        after_summary: String,
+    },
+    Dropped(DropReason),
+    Held {
+        feature: &'static str,
+        /// The condition the message would have waited for.
+        condition: String,
+    },
+}
+
+impl WouldHave {
+    pub fn feature(&self) -> &'static str {
+        match self {
+            WouldHave::Changed { feature, .. } => feature,
+            WouldHave::Dropped(reason) => reason.feature(),
+            WouldHave::Held { feature, .. } => feature,
+        }
+    }
+}
+
+impl Display for WouldHave {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WouldHave::Changed {
+                feature,
+                before_summary,
+                after_summary,
+            } => write!(
+                f,
+                "{feature} would have changed {before_summary} to {after_summary}"
+            ),
+            WouldHave::Dropped(reason) => write!(
+                f,
+                "{} would have dropped {}",
+                reason.feature(),
+                reason.detail()
+            ),
+            WouldHave::Held { feature, condition } => {
+                write!(f, "{feature} would have held the message {condition}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DryRunEntry {
+    pub timestamp: SystemTime,
+    pub server: WebSocketServer,
+    pub direction: Direction,
+    /// The operation or event code of the message, if it has one.
+    pub code: Option<u8>,
+    pub outcome: WouldHave,
+}
+
+/// The most recent changes that were only logged, and how many there were for each feature in total.
+#[derive(Debug, Default)]
+pub struct DryRunLog {
+    entries: VecDeque<DryRunEntry>,
+    counts: IndexMap<&'static str, u64>,
+}
+
+impl DryRunLog {
+    pub fn record(&mut self, entry: DryRunEntry) {
+        debug_assert!(
+            features::is_registered(entry.outcome.feature()),
+            "dry run attributed to unregistered feature {}",
+            entry.outcome.feature()
+        );
+        *self.counts.entry(entry.outcome.feature()).or_default() += 1;
+
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// The most recent entries, oldest first.
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &DryRunEntry> + ExactSizeIterator {
+        self.entries.iter()
+    }
+
+    /// How many messages each feature would have changed or dropped, including the ones that are no longer in
+    /// [Self::entries].
+    pub fn counts(&self) -> &IndexMap<&'static str, u64> {
+        &self.counts
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.counts.clear();
+    }
+}
+
+/// Describes a message in a few words, such as `OperationResponse 229, 1200 bytes`.
+pub fn summarize(message: &PhotonMessage, len: usize) -> String {
+    match message_code(message) {
+        Some(code) => format!("{} {code}, {len} bytes", message_type_name(message)),
+        None => format!("{}, {len} bytes", message_type_name(message)),
+    }
+}
+
+/// Describes a rewritten message like [summarize], along with which of its top-level parameters differ from the
+/// original.
+pub fn summarize_change(before: &PhotonMessage, after: &PhotonMessage, len: usize) -> String {
+    let mut summary = summarize(after, len);
+    if let (Some(before), Some(after)) = (message_parameters(before), message_parameters(after)) {
+        let changed = after
+            .iter()
+            .filter(|(key, value)| before.get(*key) != Some(*value))
+            .map(|(key, _)| *key)
+            .chain(
+                before
+                    .keys()
+                    .filter(|key| !after.contains_key(*key))
+                    .copied(),
+            )
+            .map(|key| key.to_string())
+            .collect::<Vec<_>>();
+        if !changed.is_empty() {
+            summary.push_str(&format!(", changed parameters {}", changed.join(", ")));
+        }
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use photon_lib::{
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{OperationResponse, PhotonMessage},
+    };
+
+    use super::{summarize_change, DryRunEntry, DryRunLog, DryRunSettings, WouldHave};
+    use crate::{
+        hax::{bandwidth::feature, drop_log::DropReason},
+        proxy::{Direction, WebSocketServer},
+    };
+
+    #[test]
+    fn overrides_and_counts() {
+        let mut settings = DryRunSettings::default();
+        assert!(!settings.is_dry(feature::LOBBY_REWRITES));
+        settings.enabled = true;
+        settings.features.insert(feature::RPC_MUTING, false);
+        assert!(settings.is_dry(feature::LOBBY_REWRITES));
+        assert!(!settings.is_dry(feature::RPC_MUTING));
+
+        let mut log = DryRunLog::default();
+        for sender in 0..205 {
+            log.record(DryRunEntry {
+                timestamp: SystemTime::now(),
+                server: WebSocketServer::GameServer,
+                direction: Direction::ServerToClient,
+                code: Some(200),
+                outcome: WouldHave::Dropped(DropReason::MutedRpc {
+                    sender,
+                    method_name: "PlayTaunt".into(),
+                }),
+            });
+        }
+        assert_eq!(log.entries().len(), 200);
+        assert_eq!(log.counts()[feature::RPC_MUTING], 205);
+        assert_eq!(
+            log.entries().next().unwrap().outcome.to_string(),
+            "RPC muting would have dropped PlayTaunt from actor 5"
+        );
+    }
+
+    #[test]
+    fn change_summary() {
+        let response = |parameters| {
+            PhotonMessage::OperationResponse(OperationResponse {
+                operation_code: 229,
+                return_code: 0,
+                debug_message: None,
+                parameters,
+            })
+        };
+        let before = response(indexmap! {
+            1 => PhotonDataType::Integer(1),
+            2 => PhotonDataType::Integer(2),
+            3 => PhotonDataType::Integer(3),
+        });
+        let after = response(indexmap! {
+            1 => PhotonDataType::Integer(1),
+            2 => PhotonDataType::Integer(5),
+            4 => PhotonDataType::Integer(4),
+        });
+        assert_eq!(
+            summarize_change(&before, &after, 20),
+            "OperationResponse 229, 20 bytes, changed parameters 2, 4, 3"
+        );
+        assert_eq!(
+            summarize_change(&before, &before, 20),
+            "OperationResponse 229, 20 bytes"
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Keeps track of Photon-encrypted messages.
+//!
+//! Photon encrypts some operations, such as the token exchange, with a key negotiated by the client and server. We
+//! don't have that key, so these messages are forwarded as-is and features that read or rewrite them can't work.
+
+use crate::proxy::{Direction, WebSocketServer};
+
+/// How many encrypted messages were seen in each direction.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EncryptedCounts {
+    pub client_to_server: u64,
+    pub server_to_client: u64,
+}
+
+impl EncryptedCounts {
+    pub fn total(&self) -> u64 {
+        self.client_to_server + self.server_to_client
+    }
+
+    fn record(&mut self, direction: Direction) {
+        match direction {
+            Direction::ClientToServer => self.client_to_server += 1,
+            Direction::ServerToClient => self.server_to_client += 1,
+        }
+    }
+}
+
+/// The encrypted messages seen on the open connections, and over the lifetime of the program.
+#[derive(Debug, Default, Clone)]
+pub struct EncryptionTracker {
+    nameserver: EncryptedCounts,
+    lobby: EncryptedCounts,
+    gameplay: EncryptedCounts,
+    total: EncryptedCounts,
+}
+
+impl EncryptionTracker {
+    pub fn record(&mut self, server: WebSocketServer, direction: Direction) {
+        self.connection_mut(server).record(direction);
+        self.total.record(direction);
+    }
+
+    /// Forgets the messages of a connection, the next connection to that server may not use encryption.
+    pub fn connection_closed(&mut self, server: WebSocketServer) {
+        *self.connection_mut(server) = EncryptedCounts::default();
+    }
+
+    /// The encrypted messages seen on the current connection to this server.
+    pub fn connection(&self, server: WebSocketServer) -> EncryptedCounts {
+        match server {
+            WebSocketServer::NameServer => self.nameserver,
+            WebSocketServer::LobbyServer => self.lobby,
+            WebSocketServer::GameServer => self.gameplay,
+        }
+    }
+
+    /// The encrypted messages seen over all connections, including closed ones.
+    pub fn total(&self) -> EncryptedCounts {
+        self.total
+    }
+
+    /// Whether the current connection to this server has sent or received encrypted messages.
+    pub fn is_active(&self, server: WebSocketServer) -> bool {
+        self.connection(server).total() > 0
+    }
+
+    /// The servers whose current connection uses encryption.
+    pub fn active_connections(&self) -> impl Iterator<Item = WebSocketServer> + '_ {
+        [
+            WebSocketServer::NameServer,
+            WebSocketServer::LobbyServer,
+            WebSocketServer::GameServer,
+        ]
+        .into_iter()
+        .filter(|server| self.is_active(*server))
+    }
+
+    fn connection_mut(&mut self, server: WebSocketServer) -> &mut EncryptedCounts {
+        match server {
+            WebSocketServer::NameServer => &mut self.nameserver,
+            WebSocketServer::LobbyServer => &mut self.lobby,
+            WebSocketServer::GameServer => &mut self.gameplay,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::lock::Mutex;
+
+    use super::EncryptedCounts;
+    use crate::{
+        hax::HaxState,
+        proxy::{Direction, WebSocketServer},
+    };
+
+    /// An encrypted operation request, as the client sends for the token exchange.
+    const ENCRYPTED_REQUEST: &[u8] = &[0xf3, 0x82, 0xa1, 0xb2, 0xc3, 0xd4];
+    /// An encrypted operation response.
+    const ENCRYPTED_RESPONSE: &[u8] = &[0xf3, 0x83, 0x0f, 0x1e, 0x2d, 0x3c, 0x4b, 0x5a];
+    /// A plain init response.
+    const PLAIN: &[u8] = &[0xf3, 0x01, 0x00];
+
+    fn feed(
+        state: &Arc<Mutex<HaxState>>,
+        fixture: &[u8],
+        server: WebSocketServer,
+        direction: Direction,
+    ) -> Vec<u8> {
+        let mut data = fixture.to_vec();
+        let forward = HaxState::websocket_hook(state.clone(), &mut data, server, direction)
+            .expect("encrypted messages should not fail the hook");
+        assert!(forward);
+        data
+    }
+
+    #[test]
+    fn encrypted_messages_pass_through() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+
+        for (fixture, direction) in [
+            (ENCRYPTED_REQUEST, Direction::ClientToServer),
+            (ENCRYPTED_RESPONSE, Direction::ServerToClient),
+            (ENCRYPTED_RESPONSE, Direction::ServerToClient),
+        ] {
+            let forwarded = feed(&state, fixture, WebSocketServer::NameServer, direction);
+            assert_eq!(forwarded, fixture);
+        }
+        feed(
+            &state,
+            PLAIN,
+            WebSocketServer::LobbyServer,
+            Direction::ServerToClient,
+        );
+
+        let mut hax = futures::executor::block_on(state.lock());
+        assert!(hax.is_encrypted_traffic_present());
+        assert_eq!(
+            hax.encryption.connection(WebSocketServer::NameServer),
+            EncryptedCounts {
+                client_to_server: 1,
+                server_to_client: 2,
+            }
+        );
+        assert!(!hax.encryption.is_active(WebSocketServer::LobbyServer));
+        assert_eq!(
+            hax.encryption.active_connections().collect::<Vec<_>>(),
+            [WebSocketServer::NameServer]
+        );
+
+        hax.encryption
+            .connection_closed(WebSocketServer::NameServer);
+        assert!(!hax.is_encrypted_traffic_present());
+        assert_eq!(hax.encryption.total().total(), 3);
+    }
+}
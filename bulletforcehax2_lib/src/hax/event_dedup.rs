@@ -0,0 +1,199 @@
+//! Skips state changes for events the server delivered twice.
+//!
+//! After reconnecting to the game server, or with a flaky connection, reliable events such as JOIN and instantiations
+//! sometimes arrive a second time. Applying them again can bring back players that left in between. Only the events
+//! that change the state are checked, repeated events like serialize updates are expected.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use photon_lib::highlevel::constants::{event_code, pun_event_code};
+
+/// How long an event is remembered. Duplicates arriving later than this are applied again.
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// What identifies an event, see [EventKey::new].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventKey {
+    pub code: u8,
+    /// The actor id for JOIN and LEAVE, the view id for instantiations.
+    pub id: i32,
+    pub server_timestamp: Option<i32>,
+}
+
+impl EventKey {
+    /// Creates a key for an event that changes the state. Returns [None] for events that are allowed to repeat.
+    pub fn new(code: u8, id: i32, server_timestamp: Option<i32>) -> Option<Self> {
+        match code {
+            event_code::JOIN | event_code::LEAVE | pun_event_code::INSTANTIATION => Some(Self {
+                code,
+                id,
+                server_timestamp,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The state-changing events that were processed recently.
+#[derive(Debug, Default)]
+pub struct EventDedup {
+    recent: VecDeque<(EventKey, Instant)>,
+    duplicates: u64,
+}
+
+impl EventDedup {
+    /// Remembers the event, returning false if it was already processed within the last few seconds.
+    pub fn is_new(&mut self, key: EventKey, now: Instant) -> bool {
+        while let Some((_, seen)) = self.recent.front() {
+            if now.saturating_duration_since(*seen) <= WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+
+        if self.recent.iter().any(|(k, _)| *k == key) {
+            self.duplicates += 1;
+            return false;
+        }
+        self.recent.push_back((key, now));
+        true
+    }
+
+    /// How many events are remembered.
+    pub fn len(&self) -> usize {
+        self.recent.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.recent.is_empty()
+    }
+
+    /// How many duplicate events were skipped.
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use photon_lib::{
+        highlevel::{
+            constants::{event_code, operation_code, parameter_code, pun_event_code},
+            structs::InstantiationEventData,
+            PhotonMapConversion,
+        },
+        indexmap::{indexmap, IndexMap},
+        photon_data_type::PhotonDataType,
+        photon_message::{OperationRequest, OperationResponse, PhotonMessage},
+        PhotonHashmap,
+    };
+
+    use super::{EventDedup, EventKey};
+    use crate::{
+        hax::timeline::Replay,
+        inspect::CapturedMessage,
+        proxy::Direction,
+        testsupport::{captured, event},
+    };
+
+    #[test]
+    fn forgets_old_events() {
+        let mut dedup = EventDedup::default();
+        let now = Instant::now();
+        let join = EventKey::new(event_code::JOIN, 2, None).unwrap();
+
+        assert!(dedup.is_new(join, now));
+        assert!(!dedup.is_new(join, now + Duration::from_secs(1)));
+        assert!(dedup.is_new(EventKey::new(event_code::LEAVE, 2, None).unwrap(), now));
+        assert!(dedup.is_new(join, now + Duration::from_secs(11)));
+        assert_eq!(dedup.duplicates(), 1);
+
+        assert_eq!(EventKey::new(pun_event_code::SEND_SERIALIZE, 2, None), None);
+    }
+
+    fn join_sequence() -> Vec<CapturedMessage> {
+        let mut data = PhotonHashmap::new();
+        InstantiationEventData {
+            prefab_name: "PlayerBody".into(),
+            position: None,
+            rotation: None,
+            group: None,
+            views_ids: None,
+            incoming_instantiation_data: None,
+            server_time: 1000,
+            instantiation_id: 2001,
+            obj_level_prefix: None,
+            custom_properties: IndexMap::new(),
+        }
+        .into_map(&mut data);
+
+        vec![
+            event(
+                event_code::JOIN,
+                indexmap! {
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(2),
+                    parameter_code::ACTOR_LIST => PhotonDataType::Array(vec![
+                        PhotonDataType::Integer(1),
+                        PhotonDataType::Integer(2),
+                    ]),
+                },
+            ),
+            event(
+                pun_event_code::INSTANTIATION,
+                indexmap! {
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(2),
+                    parameter_code::DATA => PhotonDataType::Hashtable(data),
+                },
+            ),
+        ]
+    }
+
+    #[test]
+    fn skips_repeated_join_sequence() {
+        let mut replay = Replay::default();
+        replay.feed(&captured(
+            Direction::ClientToServer,
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::AUTHENTICATE,
+                parameters: indexmap! {},
+            }),
+        ));
+        replay.feed(&captured(
+            Direction::ServerToClient,
+            PhotonMessage::OperationResponse(OperationResponse {
+                operation_code: operation_code::JOIN_GAME,
+                return_code: 0,
+                debug_message: None,
+                parameters: indexmap! {
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                    parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                    parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                },
+            }),
+        ));
+
+        let leave = event(
+            event_code::LEAVE,
+            indexmap! { parameter_code::ACTOR_NR => PhotonDataType::Integer(2) },
+        );
+        let mut messages = join_sequence();
+        messages.push(leave);
+        messages.extend(join_sequence());
+        for message in &messages {
+            // duplicates are still forwarded to the client
+            assert_eq!(replay.forward(message).as_ref(), Some(&message.raw));
+        }
+
+        let state = replay.state();
+        assert!(state.stats.recent_errors.is_empty());
+        let (_, gameplay) = state.gameplay_state.as_ref().unwrap();
+        // the repeated join doesn't bring back the player that left
+        assert_eq!(gameplay.players.keys().copied().collect::<Vec<_>>(), [1]);
+        assert_eq!(gameplay.event_dedup.duplicates(), 2);
+    }
+}
@@ -0,0 +1,163 @@
+//! Lifecycle events that can be observed by consumers of this library.
+
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+use super::{
+    client_health::ClientHealth,
+    detection::Heuristic,
+    disconnects::Disconnect,
+    host_migration::MasterChange,
+    match_summary::MatchSummary,
+    property_refresh::RefreshOutcome,
+    restriction_detector::Evidence,
+    room_expectations::RoomMismatch,
+    room_probe::{ProbeMethod, RoomProbe},
+    room_property_history::RoomPropertyChange,
+    selftest::SelfTestReport,
+    server_migration::Migration,
+    shutdown::ShutdownReport,
+    watchlist::WatchEntry,
+    web_requests::WebExchange,
+};
+use crate::proxy::{listeners::ProxyConfigChange, WebSocketServer};
+
+/// An event that occured in BulletForceHaxV2.
+#[derive(Debug, Clone)]
+pub enum HaxEvent {
+    /// The upstream server has not answered any pings for the given duration.
+    UpstreamSilent {
+        server: Option<WebSocketServer>,
+        silent_for: Duration,
+    },
+    /// The upstream connection was transparently replaced by a new one.
+    UpstreamReconnected { server: Option<WebSocketServer> },
+    /// Reconnecting to the upstream server failed.
+    UpstreamReconnectFailed {
+        server: Option<WebSocketServer>,
+        reason: String,
+    },
+    /// The client connection was closed by the proxy, so the game can show its own reconnect UI.
+    ClientDisconnected { server: Option<WebSocketServer> },
+    /// A self-test finished. See [SelfTestReport::passed] to check whether any assumptions were broken.
+    SelfTestFinished(SelfTestReport),
+    /// A player in the room changed their nickname, see [actor_history](super::actor_history).
+    PlayerRenamed {
+        actor_id: i32,
+        old: String,
+        new: String,
+    },
+    /// A player stopped sending updates while other players keep sending theirs.
+    PlayerTimingOut { actor_id: i32 },
+    /// The game client started or stopped lagging itself, see [client_health](super::client_health).
+    ClientHealthChanged {
+        previous: ClientHealth,
+        current: ClientHealth,
+    },
+    /// A player's suspicion score reached [DetectionSettings::threshold](super::detection::DetectionSettings).
+    SuspectedCheater {
+        actor_id: i32,
+        score: f32,
+        /// The heuristic that pushed the score over the threshold.
+        heuristic: Heuristic,
+    },
+    /// The servers' responses suggest that our account is restricted, the score reached
+    /// [RestrictionSettings::threshold](super::restriction_detector::RestrictionSettings).
+    PossiblyRestricted {
+        score: f32,
+        /// Why, oldest first.
+        evidence: Vec<Evidence>,
+    },
+    /// All features were [paused or resumed](super::HaxState::set_global_pause).
+    GlobalPauseChanged {
+        paused: bool,
+        /// How many held messages were sent or dropped when pausing.
+        flushed: usize,
+    },
+    /// Too many messages of a connection failed to parse, they are forwarded without being handled until parsing
+    /// works again. See [parse_breaker](super::parse_breaker).
+    ParsePassthroughStarted {
+        server: WebSocketServer,
+        failure_rate: f32,
+    },
+    /// Messages of a connection in passthrough parse again and are handled again.
+    ParsePassthroughEnded {
+        server: WebSocketServer,
+        skipped: u64,
+    },
+    /// We left a room or lost the connection to it. The summary was added to the
+    /// [match history](super::HaxState::match_history).
+    MatchFinished(MatchSummary),
+    /// The client connected to another game server while still connected to the previous one. The game on the
+    /// previous one was finished first. See [server_migration](super::server_migration).
+    GameServerMigrated(Migration),
+    /// After being moved to another game server, the client joined the same room again, so the match continues.
+    MigratedMatchResumed { room_name: String },
+    /// The master client of the room moved to another player, eg. because the host left. See
+    /// [host_migration](super::host_migration).
+    MasterClientChanged(MasterChange),
+    /// A change of the [ProxyConfig](crate::proxy::listeners::ProxyConfig) was applied.
+    ProxyConfigChanged(ProxyConfigChange),
+    /// A player on the [watchlist](super::watchlist) showed up. Check the entry's level to decide how loudly to
+    /// report it.
+    WatchedPlayerSeen {
+        entry: WatchEntry,
+        user_id: Option<String>,
+        nickname: Option<String>,
+        /// The room they're in or hosting, if known.
+        room: Option<String>,
+        /// Where they were seen, [WebSocketServer::LobbyServer] for room hosts.
+        server: WebSocketServer,
+    },
+    /// The game sent a request to or got a response from a tracked web endpoint, see
+    /// [web_requests](super::web_requests).
+    WebRequest(WebExchange),
+    /// The room we joined differs from what the lobby listed, see [room_expectations](super::room_expectations).
+    RoomMismatch(RoomMismatch),
+    /// A [schedule](super::scheduler) ran its actions.
+    ScheduleRan { name: String },
+    /// A connection to one of the servers closed, and why, see [disconnects](super::disconnects).
+    Disconnected(Disconnect),
+    /// The server answered a [refresh](super::HaxState::refresh_actor_properties) of the players' properties, which
+    /// were merged into the players.
+    ActorPropertiesRefreshed(RefreshOutcome),
+    /// A [probe](super::HaxState::probe_room) found who is in a lobby room.
+    RoomProbed(RoomProbe),
+    /// A probe of a lobby room failed.
+    RoomProbeFailed {
+        room_id: String,
+        method: ProbeMethod,
+        reason: String,
+    },
+    /// A [watched](super::room_property_history::RoomPropertySettings::watched) property of the room we're in
+    /// changed.
+    RoomPropertyChanged(RoomPropertyChange),
+    /// The proxy was [shut down](super::BulletForceHax::shutdown). This is the last event.
+    ShutDown(ShutdownReport),
+}
+
+/// A broadcast channel for [HaxEvent]s.
+///
+/// Events are dropped if nobody is subscribed.
+pub struct EventBus {
+    sender: broadcast::Sender<HaxEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(64);
+        Self { sender }
+    }
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> broadcast::Receiver<HaxEvent> {
+        self.sender.subscribe()
+    }
+
+    pub fn emit(&self, event: HaxEvent) {
+        // an error only means there are no subscribers
+        _ = self.sender.send(event);
+    }
+}
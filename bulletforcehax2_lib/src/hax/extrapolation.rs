@@ -0,0 +1,355 @@
+//! Predicts where players are now, based on the positions they last sent.
+//!
+//! Positions arrive late by the network latency plus PUN's send interval. Each position is stamped with the server
+//! time at which it was serialized, so with an estimate of the current server time we can project it forward. The
+//! latency of our own connection is measured from the client's pings, see [LatencyTracker].
+
+use std::time::{Duration, Instant};
+
+use photon_lib::{indexmap::IndexMap, ordered_float::OrderedFloat, primitives::Vector3};
+
+/// How many unanswered pings to remember. Older ones get forgotten.
+const MAX_PENDING_PINGS: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct ExtrapolationSettings {
+    /// The highest speed a player can move at, in units per second. Faster velocities are scaled down to this.
+    pub max_speed: f32,
+    /// How far ahead of the last sample positions are projected. Samples older than this are considered stale.
+    pub max_horizon: Duration,
+    /// Moving further than this between two samples is treated as a teleport (eg. a respawn), which resets the
+    /// velocity.
+    pub teleport_distance: f32,
+}
+
+impl Default for ExtrapolationSettings {
+    fn default() -> Self {
+        Self {
+            max_speed: 15.0,
+            max_horizon: Duration::from_millis(500),
+            teleport_distance: 10.0,
+        }
+    }
+}
+
+/// A position, stamped with the server time it was sent at in milliseconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionSample {
+    pub position: Vector3,
+    pub server_time: i32,
+}
+
+/// The result of [PositionHistory::extrapolate].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Extrapolated {
+    pub position: Vector3,
+    /// The last sample is older than [ExtrapolationSettings::max_horizon], so this is the last known position
+    /// instead of a prediction.
+    pub stale: bool,
+}
+
+/// The last two positions of an object.
+#[derive(Debug, Clone, Default)]
+pub struct PositionHistory {
+    previous: Option<PositionSample>,
+    latest: Option<PositionSample>,
+}
+
+impl PositionHistory {
+    pub fn push(&mut self, sample: PositionSample, settings: &ExtrapolationSettings) {
+        self.previous = match self.latest.take() {
+            Some(latest)
+                if distance(&latest.position, &sample.position) <= settings.teleport_distance =>
+            {
+                Some(latest)
+            }
+            // a teleport, the velocity across it is meaningless
+            _ => None,
+        };
+        self.latest = Some(sample);
+    }
+
+    pub fn latest(&self) -> Option<&PositionSample> {
+        self.latest.as_ref()
+    }
+
+    /// The velocity between the last two positions in units per second, zero if only one is known.
+    pub fn velocity(&self, settings: &ExtrapolationSettings) -> Option<(f32, f32, f32)> {
+        let latest = self.latest.as_ref()?;
+        Some(match &self.previous {
+            Some(previous) => velocity(previous, latest, settings.max_speed),
+            None => (0.0, 0.0, 0.0),
+        })
+    }
+
+    /// Projects the latest position forward to the given server time.
+    pub fn extrapolate(
+        &self,
+        server_now: i32,
+        settings: &ExtrapolationSettings,
+    ) -> Option<Extrapolated> {
+        let latest = self.latest.as_ref()?;
+        let (x, y, z) = latest.position.floats();
+
+        // server time wraps around, so compare with wrapping arithmetic
+        let age_ms = server_now.wrapping_sub(latest.server_time).max(0);
+        if age_ms as u128 > settings.max_horizon.as_millis() {
+            return Some(Extrapolated {
+                position: latest.position.clone(),
+                stale: true,
+            });
+        }
+
+        let (vx, vy, vz) = self.velocity(settings).unwrap_or_default();
+        let age = age_ms as f32 / 1000.0;
+        Some(Extrapolated {
+            position: vector(x + vx * age, y + vy * age, z + vz * age),
+            stale: false,
+        })
+    }
+}
+
+/// Estimates the current server time from the timestamps the server sends.
+#[derive(Debug, Clone, Default)]
+pub struct ServerClock {
+    /// The local time and the server time at that moment.
+    reference: Option<(Instant, i32)>,
+}
+
+impl ServerClock {
+    /// Feeds a server timestamp that was just received.
+    ///
+    /// Timestamps are delayed by the network by varying amounts, so the least delayed one is kept: a new timestamp
+    /// replaces the reference if it is ahead of the current estimate.
+    pub fn observe(&mut self, server_time: i32, now: Instant) {
+        match self.server_now(now) {
+            Some(estimate) if server_time.wrapping_sub(estimate) <= 0 => (),
+            _ => self.reference = Some((now, server_time)),
+        }
+    }
+
+    /// The estimated server time at the given moment, if any timestamps were observed yet.
+    pub fn server_now(&self, now: Instant) -> Option<i32> {
+        let (local, server) = self.reference?;
+        let elapsed = now.saturating_duration_since(local).as_millis() as i32;
+        Some(server.wrapping_add(elapsed))
+    }
+}
+
+/// Measures the round trip time to the server from the client's pings and the server's answers to them.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyTracker {
+    /// Pings that weren't answered yet, keyed by the client's timestamp.
+    pending: IndexMap<i32, Instant>,
+    /// The round trip time, smoothed over the recent pings.
+    smoothed: Option<Duration>,
+}
+
+impl LatencyTracker {
+    /// Feeds a ping the client sent with the given timestamp.
+    pub fn observe_ping(&mut self, client_time: i32, now: Instant) {
+        if self.pending.len() >= MAX_PENDING_PINGS {
+            self.pending.shift_remove_index(0);
+        }
+        self.pending.insert(client_time, now);
+    }
+
+    /// Feeds the server's answer to the ping with the given timestamp.
+    ///
+    /// Round trips are smoothed the way TCP smooths them, each new one makes up an eighth of the estimate, so a single
+    /// late answer doesn't throw it off.
+    pub fn observe_answer(&mut self, client_time: i32, now: Instant) {
+        let index = match self.pending.get_index_of(&client_time) {
+            Some(index) => index,
+            None => return,
+        };
+        let (_, sent) = self
+            .pending
+            .get_index(index)
+            .expect("index was just looked up");
+        let sample = now.saturating_duration_since(*sent);
+        // everything sent before this ping was answered too, or got lost
+        self.pending.drain(..=index);
+        self.smoothed = Some(match self.smoothed {
+            Some(smoothed) => (smoothed * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    /// The smoothed round trip time, if any ping was answered yet.
+    pub fn round_trip(&self) -> Option<Duration> {
+        self.smoothed
+    }
+
+    /// How long messages take from us to the server, taken to be half the round trip.
+    pub fn one_way(&self) -> Option<Duration> {
+        self.smoothed.map(|rtt| rtt / 2)
+    }
+}
+
+fn distance(a: &Vector3, b: &Vector3) -> f32 {
+    let (ax, ay, az) = a.floats();
+    let (bx, by, bz) = b.floats();
+    ((ax - bx).powi(2) + (ay - by).powi(2) + (az - bz).powi(2)).sqrt()
+}
+
+/// The velocity between two samples in units per second, scaled down to `max_speed`.
+fn velocity(from: &PositionSample, to: &PositionSample, max_speed: f32) -> (f32, f32, f32) {
+    let dt_ms = to.server_time.wrapping_sub(from.server_time);
+    if dt_ms <= 0 {
+        return (0.0, 0.0, 0.0);
+    }
+    let dt = dt_ms as f32 / 1000.0;
+
+    let (fx, fy, fz) = from.position.floats();
+    let (tx, ty, tz) = to.position.floats();
+    let (vx, vy, vz) = ((tx - fx) / dt, (ty - fy) / dt, (tz - fz) / dt);
+
+    let speed = (vx * vx + vy * vy + vz * vz).sqrt();
+    if speed > max_speed {
+        let scale = max_speed / speed;
+        (vx * scale, vy * scale, vz * scale)
+    } else {
+        (vx, vy, vz)
+    }
+}
+
+fn vector(x: f32, y: f32, z: f32) -> Vector3 {
+    Vector3(OrderedFloat(x), OrderedFloat(y), OrderedFloat(z))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        vector, ExtrapolationSettings, LatencyTracker, PositionHistory, PositionSample, ServerClock,
+    };
+
+    fn history(samples: &[((f32, f32, f32), i32)]) -> PositionHistory {
+        let mut history = PositionHistory::default();
+        for ((x, y, z), server_time) in samples {
+            history.push(
+                PositionSample {
+                    position: vector(*x, *y, *z),
+                    server_time: *server_time,
+                },
+                &ExtrapolationSettings::default(),
+            );
+        }
+        history
+    }
+
+    fn assert_close(actual: (f32, f32, f32), expected: (f32, f32, f32)) {
+        let close = |a: f32, b: f32| (a - b).abs() < 0.001;
+        assert!(
+            close(actual.0, expected.0)
+                && close(actual.1, expected.1)
+                && close(actual.2, expected.2),
+            "{actual:?} != {expected:?}"
+        );
+    }
+
+    #[test]
+    fn projects_forward() {
+        let settings = ExtrapolationSettings::default();
+        // 1 unit per 100ms along x
+        let history = history(&[((0.0, 0.0, 0.0), 1000), ((1.0, 0.0, 0.0), 1100)]);
+
+        let result = history.extrapolate(1300, &settings).unwrap();
+        assert!(!result.stale);
+        assert_close(result.position.floats(), (3.0, 0.0, 0.0));
+
+        // over the server time wrapping around
+        let history = history_wrapping();
+        let result = history.extrapolate(i32::MIN + 49, &settings).unwrap();
+        assert_close(result.position.floats(), (2.0, 0.0, 0.0));
+    }
+
+    fn history_wrapping() -> PositionHistory {
+        history(&[
+            ((0.0, 0.0, 0.0), i32::MAX - 150),
+            ((1.0, 0.0, 0.0), i32::MAX - 50),
+        ])
+    }
+
+    #[test]
+    fn clamps_velocity() {
+        let settings = ExtrapolationSettings::default();
+        // 5 units in 100ms is faster than max_speed, but not a teleport
+        let history = history(&[((0.0, 0.0, 0.0), 1000), ((0.0, 0.0, 5.0), 1100)]);
+
+        let result = history.extrapolate(1200, &settings).unwrap();
+        assert_close(
+            result.position.floats(),
+            (0.0, 0.0, 5.0 + settings.max_speed / 10.0),
+        );
+    }
+
+    #[test]
+    fn teleport_resets_velocity() {
+        let settings = ExtrapolationSettings::default();
+        let history = history(&[
+            ((0.0, 0.0, 0.0), 1000),
+            ((1.0, 0.0, 0.0), 1100),
+            ((100.0, 0.0, 0.0), 1200),
+        ]);
+
+        let result = history.extrapolate(1400, &settings).unwrap();
+        assert!(!result.stale);
+        assert_close(result.position.floats(), (100.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn stale_returns_last_known() {
+        let settings = ExtrapolationSettings::default();
+        let history = history(&[((0.0, 0.0, 0.0), 1000), ((1.0, 0.0, 0.0), 1100)]);
+
+        let result = history.extrapolate(1100 + 501, &settings).unwrap();
+        assert!(result.stale);
+        assert_close(result.position.floats(), (1.0, 0.0, 0.0));
+
+        assert_eq!(PositionHistory::default().extrapolate(0, &settings), None);
+    }
+
+    #[test]
+    fn server_clock_keeps_least_delayed() {
+        let start = Instant::now();
+        let mut clock = ServerClock::default();
+        assert_eq!(clock.server_now(start), None);
+
+        clock.observe(5000, start);
+        // arrived 40ms late
+        clock.observe(5060, start + Duration::from_millis(100));
+        assert_eq!(
+            clock.server_now(start + Duration::from_millis(200)),
+            Some(5200)
+        );
+        // arrived earlier than expected, so the first one was delayed
+        clock.observe(5320, start + Duration::from_millis(300));
+        assert_eq!(
+            clock.server_now(start + Duration::from_millis(400)),
+            Some(5420)
+        );
+    }
+
+    #[test]
+    fn latency_is_smoothed() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let mut latency = LatencyTracker::default();
+        latency.observe_ping(100, start);
+        latency.observe_ping(200, start + ms(100));
+        // answers to pings we didn't see are ignored
+        latency.observe_answer(50, start + ms(120));
+        assert_eq!(latency.round_trip(), None);
+
+        latency.observe_answer(100, start + ms(80));
+        assert_eq!(latency.round_trip(), Some(ms(80)));
+        assert_eq!(latency.one_way(), Some(ms(40)));
+
+        // a late answer moves the estimate by an eighth of the difference
+        latency.observe_answer(200, start + ms(340));
+        assert_eq!(latency.round_trip(), Some(ms(100)));
+    }
+}
@@ -0,0 +1,735 @@
+//! The registry of all features, so everything that names them agrees on what there is.
+//!
+//! Each feature is registered once in [REGISTRY] under its [name](super::bandwidth::feature), together with what it
+//! looks at, whether it changes traffic and what it needs to work. The [availability report](super::availability),
+//! the [dry run](super::dry_run) attribution and the [bandwidth report](super::bandwidth) all go by these names, and
+//! [HaxState::list_features] describes the current state of each of them.
+
+use std::fmt::Display;
+
+use photon_lib::highlevel::constants::{event_code, operation_code, pun_event_code};
+
+use super::{
+    availability::{in_game, readable, Context, Predicate},
+    bandwidth::feature,
+    settings::Settings,
+    HaxState,
+};
+use crate::{inspect::query::MessageKind, proxy::WebSocketServer};
+
+/// What part of the game a feature acts on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureCategory {
+    /// Connecting to the name server and the game servers.
+    Connection,
+    Lobby,
+    Game,
+    /// Sending messages neither side sent.
+    Injection,
+}
+
+impl Display for FeatureCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FeatureCategory::Connection => write!(f, "connection"),
+            FeatureCategory::Lobby => write!(f, "lobby"),
+            FeatureCategory::Game => write!(f, "game"),
+            FeatureCategory::Injection => write!(f, "injection"),
+        }
+    }
+}
+
+/// Messages a feature looks at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObservedMessages {
+    pub server: WebSocketServer,
+    pub kind: MessageKind,
+    /// The operation or event codes. Empty if the feature looks at messages of any code.
+    pub codes: &'static [u8],
+}
+
+/// A feature as it is registered, see [REGISTRY].
+pub(super) struct Registration {
+    pub name: &'static str,
+    pub category: FeatureCategory,
+    pub observes: &'static [ObservedMessages],
+    pub modifies_traffic: bool,
+    pub enabled: fn(&HaxState, &Settings) -> bool,
+    /// The current configuration in a few words, empty if there is nothing to configure.
+    pub config: fn(&HaxState, &Settings) -> String,
+    /// What the feature needs to work. Features without requirements are not listed in the availability report.
+    pub requires: Option<Predicate>,
+}
+
+/// The current state of a feature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureDescriptor {
+    pub name: &'static str,
+    pub category: FeatureCategory,
+    /// Whether the feature is switched on. Features that only act when asked to, such as injecting messages, are
+    /// always enabled.
+    pub enabled: bool,
+    pub observes: &'static [ObservedMessages],
+    /// Whether the feature can change, drop, hold or inject messages. The others only read them.
+    pub modifies_traffic: bool,
+    /// Whether the changes of the feature are only logged, see [dry_run](super::dry_run).
+    pub dry_run: bool,
+    /// The current configuration in a few words, empty if there is nothing to configure.
+    pub config: String,
+}
+
+const fn requests(server: WebSocketServer, codes: &'static [u8]) -> ObservedMessages {
+    ObservedMessages {
+        server,
+        kind: MessageKind::Request,
+        codes,
+    }
+}
+
+const fn responses(server: WebSocketServer, codes: &'static [u8]) -> ObservedMessages {
+    ObservedMessages {
+        server,
+        kind: MessageKind::Response,
+        codes,
+    }
+}
+
+const fn events(server: WebSocketServer, codes: &'static [u8]) -> ObservedMessages {
+    ObservedMessages {
+        server,
+        kind: MessageKind::Event,
+        codes,
+    }
+}
+
+const GAME_LISTS: &[ObservedMessages] = &[events(
+    WebSocketServer::LobbyServer,
+    &[event_code::GAME_LIST, event_code::GAME_LIST_UPDATE],
+)];
+
+const NAME_SERVER_AUTH: &[u8] = &[
+    operation_code::AUTHENTICATE,
+    operation_code::AUTHENTICATE_ONCE,
+];
+
+fn lobby_readable(c: &Context) -> Result<(), String> {
+    readable(c, WebSocketServer::LobbyServer)
+}
+
+fn game_readable(c: &Context) -> Result<(), String> {
+    readable(c, WebSocketServer::GameServer)
+}
+
+fn nothing(_: &HaxState, _: &Settings) -> String {
+    String::new()
+}
+
+fn always(_: &HaxState, _: &Settings) -> bool {
+    true
+}
+
+/// The lobby rewrites that are switched on, which [feature::LOBBY_REWRITES] stands for when several change a message.
+fn lobby_rewrites(hax: &HaxState, settings: &Settings) -> Vec<&'static str> {
+    [
+        (settings.show_mobile_games, feature::MOBILE_GAMES),
+        (settings.show_other_versions, feature::VERSION_FORCING),
+        (settings.strip_passwords, feature::PASSWORD_STRIPPING),
+        (hax.room_notes.iter().next().is_some(), feature::ROOM_NOTES),
+        (settings.lobby_phase_annotations, feature::MATCH_PHASE),
+    ]
+    .into_iter()
+    .filter(|(enabled, _)| *enabled)
+    .map(|(_, name)| name)
+    .collect()
+}
+
+/// Every feature, in the order they are reported in.
+pub(super) const REGISTRY: [Registration; 27] = [
+    Registration {
+        name: feature::PASSWORD_STRIPPING,
+        category: FeatureCategory::Lobby,
+        observes: GAME_LISTS,
+        modifies_traffic: true,
+        enabled: |_, s| s.strip_passwords,
+        config: nothing,
+        requires: Some(lobby_readable),
+    },
+    Registration {
+        name: feature::MOBILE_GAMES,
+        category: FeatureCategory::Lobby,
+        observes: GAME_LISTS,
+        modifies_traffic: true,
+        enabled: |_, s| s.show_mobile_games,
+        config: nothing,
+        requires: Some(lobby_readable),
+    },
+    Registration {
+        name: feature::VERSION_FORCING,
+        category: FeatureCategory::Lobby,
+        observes: GAME_LISTS,
+        modifies_traffic: true,
+        enabled: |_, s| s.show_other_versions,
+        config: |hax, _| match &hax.global_state.version {
+            Some(version) => format!("to {}", version.game_version),
+            None => "game version unknown".into(),
+        },
+        requires: Some(|c| {
+            readable(c, WebSocketServer::LobbyServer)?;
+            match &c.hax.global_state.version {
+                Some(_) => Ok(()),
+                None => Err("game_version unknown, not yet authenticated with the lobby".into()),
+            }
+        }),
+    },
+    Registration {
+        name: feature::LOBBY_SORT,
+        category: FeatureCategory::Lobby,
+        observes: &[events(
+            WebSocketServer::LobbyServer,
+            &[event_code::GAME_LIST],
+        )],
+        modifies_traffic: true,
+        enabled: |_, s| s.lobby_sort.is_some(),
+        config: |_, s| {
+            s.lobby_sort
+                .map(|sort| sort.to_string())
+                .unwrap_or_default()
+        },
+        requires: Some(lobby_readable),
+    },
+    Registration {
+        name: feature::ROOM_NOTES,
+        category: FeatureCategory::Lobby,
+        observes: GAME_LISTS,
+        modifies_traffic: true,
+        enabled: |hax, _| hax.room_notes.iter().next().is_some(),
+        config: |hax, _| format!("{} notes", hax.room_notes.iter().count()),
+        requires: Some(lobby_readable),
+    },
+    Registration {
+        name: feature::MATCH_PHASE,
+        category: FeatureCategory::Lobby,
+        observes: GAME_LISTS,
+        modifies_traffic: true,
+        enabled: |_, s| s.lobby_phase_annotations,
+        config: nothing,
+        requires: Some(lobby_readable),
+    },
+    Registration {
+        name: feature::LOBBY_REWRITES,
+        category: FeatureCategory::Lobby,
+        observes: GAME_LISTS,
+        modifies_traffic: true,
+        enabled: |hax, s| !lobby_rewrites(hax, s).is_empty(),
+        config: |hax, s| lobby_rewrites(hax, s).join(", "),
+        requires: None,
+    },
+    Registration {
+        name: feature::QUEUE_JUMP,
+        category: FeatureCategory::Lobby,
+        observes: &[requests(
+            WebSocketServer::LobbyServer,
+            &[operation_code::JOIN_GAME],
+        )],
+        modifies_traffic: true,
+        enabled: |_, s| s.queue_jump,
+        config: nothing,
+        requires: Some(lobby_readable),
+    },
+    Registration {
+        name: feature::REPLAYED_ROOMS,
+        category: FeatureCategory::Lobby,
+        observes: &[requests(
+            WebSocketServer::LobbyServer,
+            &[operation_code::JOIN_GAME],
+        )],
+        modifies_traffic: true,
+        enabled: |hax, _| {
+            hax.lobby_state
+                .as_ref()
+                .is_some_and(|(_, lobby)| !lobby.replayed_rooms.is_empty())
+        },
+        config: |hax, _| {
+            let rooms = hax
+                .lobby_state
+                .as_ref()
+                .map_or(0, |(_, lobby)| lobby.replayed_rooms.len());
+            format!("{rooms} rooms prefixed with {}", hax.replayed_rooms.prefix)
+        },
+        requires: None,
+    },
+    Registration {
+        name: feature::ROOM_PROBE,
+        category: FeatureCategory::Lobby,
+        observes: &[
+            requests(
+                WebSocketServer::LobbyServer,
+                &[operation_code::FIND_FRIENDS, operation_code::JOIN_GAME],
+            ),
+            responses(
+                WebSocketServer::LobbyServer,
+                &[operation_code::FIND_FRIENDS, operation_code::JOIN_GAME],
+            ),
+        ],
+        modifies_traffic: true,
+        enabled: always,
+        config: |hax, _| {
+            let settings = &hax.room_probe_settings;
+            format!(
+                "{} at a time, {} s apart",
+                settings.max_concurrent,
+                settings.min_interval.as_secs()
+            )
+        },
+        requires: Some(lobby_readable),
+    },
+    Registration {
+        name: feature::REGION_FORCING,
+        category: FeatureCategory::Connection,
+        observes: &[requests(WebSocketServer::NameServer, NAME_SERVER_AUTH)],
+        modifies_traffic: true,
+        enabled: |_, s| s.forced_region.0 && !s.forced_region.1.is_empty(),
+        config: |_, s| s.forced_region.1.clone(),
+        requires: Some(|c| readable(c, WebSocketServer::NameServer)),
+    },
+    Registration {
+        name: feature::AUTH_OVERRIDES,
+        category: FeatureCategory::Connection,
+        observes: &[
+            requests(WebSocketServer::NameServer, NAME_SERVER_AUTH),
+            requests(
+                WebSocketServer::LobbyServer,
+                &[operation_code::AUTHENTICATE],
+            ),
+        ],
+        modifies_traffic: true,
+        enabled: |_, s| s.auth_overrides.is_some(),
+        config: |_, s| match &s.auth_overrides {
+            Some(overrides) => [
+                overrides.app_version.as_ref().map(|_| "app version"),
+                overrides.region.as_ref().map(|_| "region"),
+                overrides.user_id.as_ref().map(|_| "user id"),
+                (!overrides.auth_data.is_empty()).then_some("auth data"),
+            ]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(", "),
+            None => String::new(),
+        },
+        requires: None,
+    },
+    Registration {
+        name: feature::IDENTITY_RANDOMIZER,
+        category: FeatureCategory::Connection,
+        observes: &[
+            requests(WebSocketServer::NameServer, NAME_SERVER_AUTH),
+            responses(WebSocketServer::NameServer, NAME_SERVER_AUTH),
+            requests(
+                WebSocketServer::LobbyServer,
+                &[operation_code::AUTHENTICATE],
+            ),
+            responses(
+                WebSocketServer::LobbyServer,
+                &[operation_code::AUTHENTICATE],
+            ),
+            requests(
+                WebSocketServer::GameServer,
+                &[
+                    operation_code::AUTHENTICATE,
+                    operation_code::JOIN_GAME,
+                    operation_code::CREATE_GAME,
+                    operation_code::SET_PROPERTIES,
+                ],
+            ),
+            responses(
+                WebSocketServer::GameServer,
+                &[operation_code::JOIN_GAME, operation_code::CREATE_GAME],
+            ),
+            events(
+                WebSocketServer::GameServer,
+                &[event_code::JOIN, event_code::PROPERTIES_CHANGED],
+            ),
+        ],
+        modifies_traffic: true,
+        enabled: |_, s| s.identity_randomizer,
+        config: |hax, _| match hax.identity.identity() {
+            Some(identity) => identity.nickname.clone(),
+            None => String::new(),
+        },
+        requires: Some(game_readable),
+    },
+    Registration {
+        name: feature::GAME_SERVER_ROUTING,
+        category: FeatureCategory::Connection,
+        observes: &[
+            responses(
+                WebSocketServer::LobbyServer,
+                &[
+                    operation_code::JOIN_GAME,
+                    operation_code::CREATE_GAME,
+                    operation_code::JOIN_RANDOM_GAME,
+                ],
+            ),
+            // any response can move us to another game server
+            responses(WebSocketServer::GameServer, &[]),
+        ],
+        modifies_traffic: true,
+        enabled: |hax, _| hax.game_server_routes.endpoint().is_some(),
+        config: |hax, _| {
+            hax.game_server_routes
+                .endpoint()
+                .map(|endpoint| endpoint.to_string())
+                .unwrap_or_default()
+        },
+        requires: None,
+    },
+    Registration {
+        name: feature::NAME_SPOOFING,
+        category: FeatureCategory::Game,
+        observes: &[requests(
+            WebSocketServer::GameServer,
+            &[operation_code::SET_PROPERTIES],
+        )],
+        modifies_traffic: true,
+        enabled: |_, s| s.spoofed_name.0,
+        config: |_, s| s.spoofed_name.1.clone(),
+        requires: Some(game_readable),
+    },
+    Registration {
+        name: feature::PLATFORM_SPOOF,
+        category: FeatureCategory::Game,
+        observes: &[requests(
+            WebSocketServer::GameServer,
+            &[operation_code::JOIN_GAME, operation_code::SET_PROPERTIES],
+        )],
+        modifies_traffic: true,
+        enabled: |_, s| s.platform_spoof.is_some(),
+        config: |_, s| match &s.platform_spoof {
+            Some(spoof) => [&spoof.platform, &spoof.device_model, &spoof.store_id]
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", "),
+            None => String::new(),
+        },
+        requires: Some(game_readable),
+    },
+    Registration {
+        name: feature::PROPERTY_FIREWALL,
+        category: FeatureCategory::Game,
+        observes: &[requests(
+            WebSocketServer::GameServer,
+            &[operation_code::SET_PROPERTIES],
+        )],
+        modifies_traffic: true,
+        enabled: |hax, _| {
+            !hax.property_firewall.actor_blocklist.is_empty()
+                || !hax.property_firewall.game_blocklist.is_empty()
+        },
+        config: |hax, _| {
+            format!(
+                "{} actor keys, {} room keys",
+                hax.property_firewall.actor_blocklist.len(),
+                hax.property_firewall.game_blocklist.len()
+            )
+        },
+        requires: Some(game_readable),
+    },
+    Registration {
+        name: feature::GHOST_JOIN,
+        category: FeatureCategory::Game,
+        observes: &[requests(
+            WebSocketServer::GameServer,
+            &[operation_code::JOIN_GAME, operation_code::RAISE_EVENT],
+        )],
+        modifies_traffic: true,
+        enabled: |_, s| s.ghost_join,
+        config: nothing,
+        requires: Some(|c| {
+            readable(c, WebSocketServer::GameServer)?;
+            match c.gameplay {
+                Some(state) if state.ghost.spawns_unrecognized() => Err(
+                    "our player was never instantiated as a PlayerBody, this game version spawns players differently"
+                        .into(),
+                ),
+                _ => Ok(()),
+            }
+        }),
+    },
+    Registration {
+        name: feature::STEALTH_HOST,
+        category: FeatureCategory::Game,
+        observes: &[
+            requests(WebSocketServer::LobbyServer, &[operation_code::CREATE_GAME]),
+            requests(
+                WebSocketServer::GameServer,
+                &[operation_code::CREATE_GAME, operation_code::SET_PROPERTIES],
+            ),
+        ],
+        modifies_traffic: true,
+        enabled: |hax, _| hax.stealth_host,
+        config: nothing,
+        requires: Some(|c| {
+            readable(c, WebSocketServer::LobbyServer)?;
+            readable(c, WebSocketServer::GameServer)
+        }),
+    },
+    Registration {
+        name: feature::ROOM_RENAME,
+        category: FeatureCategory::Game,
+        observes: &[
+            requests(
+                WebSocketServer::GameServer,
+                &[operation_code::SET_PROPERTIES],
+            ),
+            responses(
+                WebSocketServer::GameServer,
+                &[operation_code::SET_PROPERTIES],
+            ),
+        ],
+        modifies_traffic: true,
+        enabled: always,
+        config: nothing,
+        requires: Some(|c| {
+            in_game(c)?;
+            readable(c, WebSocketServer::GameServer)
+        }),
+    },
+    Registration {
+        name: feature::PROPERTY_REFRESH,
+        category: FeatureCategory::Game,
+        observes: &[
+            requests(
+                WebSocketServer::GameServer,
+                &[operation_code::GET_PROPERTIES],
+            ),
+            responses(
+                WebSocketServer::GameServer,
+                &[operation_code::GET_PROPERTIES],
+            ),
+        ],
+        modifies_traffic: true,
+        enabled: always,
+        config: nothing,
+        requires: Some(|c| {
+            in_game(c)?;
+            readable(c, WebSocketServer::GameServer)
+        }),
+    },
+    Registration {
+        name: feature::ALL_INTEREST_GROUPS,
+        category: FeatureCategory::Game,
+        observes: &[requests(
+            WebSocketServer::GameServer,
+            &[operation_code::CHANGE_GROUPS],
+        )],
+        modifies_traffic: true,
+        enabled: |hax, _| hax.receive_all_groups,
+        config: nothing,
+        requires: Some(game_readable),
+    },
+    Registration {
+        name: feature::RPC_MUTING,
+        category: FeatureCategory::Game,
+        observes: &[events(WebSocketServer::GameServer, &[pun_event_code::RPC])],
+        modifies_traffic: true,
+        enabled: |_, s| s.mute_all_cosmetic || !s.muted_actors.is_empty(),
+        config: |_, s| match s.mute_all_cosmetic {
+            true => "all players".into(),
+            false => format!("{} players", s.muted_actors.len()),
+        },
+        requires: Some(|c| {
+            in_game(c)?;
+            readable(c, WebSocketServer::GameServer)
+        }),
+    },
+    Registration {
+        name: feature::INJECTED_MESSAGES,
+        category: FeatureCategory::Injection,
+        observes: &[],
+        modifies_traffic: true,
+        enabled: always,
+        config: nothing,
+        requires: Some(|c| in_game(c).map(|_| ())),
+    },
+    Registration {
+        name: feature::SIMULATION,
+        category: FeatureCategory::Injection,
+        observes: &[],
+        modifies_traffic: true,
+        enabled: |_, _| cfg!(feature = "simulation"),
+        config: nothing,
+        requires: None,
+    },
+    Registration {
+        name: feature::AUTO_RESPONSES,
+        category: FeatureCategory::Injection,
+        observes: &[],
+        modifies_traffic: true,
+        enabled: always,
+        config: nothing,
+        requires: None,
+    },
+    Registration {
+        name: feature::ESP,
+        category: FeatureCategory::Game,
+        observes: &[events(
+            WebSocketServer::GameServer,
+            &[
+                pun_event_code::SEND_SERIALIZE,
+                pun_event_code::SEND_SERIALIZE_RELIABLE,
+            ],
+        )],
+        modifies_traffic: false,
+        enabled: always,
+        config: nothing,
+        requires: Some(|c| {
+            let state = in_game(c)?;
+            readable(c, WebSocketServer::GameServer)?;
+            if state.server_clock.server_now(c.now).is_none() {
+                return Err("server time unknown, no serialized updates received yet".into());
+            }
+            match state.players.values().any(|p| p.position.is_some()) {
+                true => Ok(()),
+                false => Err(
+                    "no player positions decoded yet, the serialized data of this game build may not be supported"
+                        .into(),
+                ),
+            }
+        }),
+    },
+];
+
+/// Where a feature is in [REGISTRY], or [None] if there is no feature by that name.
+pub fn position(name: &str) -> Option<usize> {
+    REGISTRY.iter().position(|r| r.name == name)
+}
+
+/// Whether there is a feature by that name. Features attribute their changes by name, a name that isn't registered
+/// is a typo that would show up as a feature of its own in the reports.
+pub fn is_registered(name: &str) -> bool {
+    position(name).is_some()
+}
+
+impl HaxState {
+    /// Describes all features, in the same order as the [availability report](Self::feature_availability).
+    pub fn list_features(&self) -> Vec<FeatureDescriptor> {
+        let settings = self.settings();
+        REGISTRY
+            .iter()
+            .map(|r| FeatureDescriptor {
+                name: r.name,
+                category: r.category,
+                enabled: (r.enabled)(self, &settings),
+                observes: r.observes,
+                modifies_traffic: r.modifies_traffic,
+                dry_run: r.modifies_traffic
+                    && (settings.is_inert() || settings.dry_run.is_dry(r.name)),
+                config: (r.config)(self, &settings),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{is_registered, FeatureCategory, REGISTRY};
+    use crate::hax::{bandwidth::feature, HaxState};
+
+    /// The modules that attribute the messages they change, drop, hold or inject to a feature.
+    const HANDLER_SOURCES: [&str; 9] = [
+        include_str!("hax_impl.rs"),
+        include_str!("transforms.rs"),
+        include_str!("drop_log.rs"),
+        include_str!("hold.rs"),
+        include_str!("mod.rs"),
+        include_str!("host_migration.rs"),
+        include_str!("stealth_host.rs"),
+        include_str!("replayed_rooms.rs"),
+        include_str!("simulation.rs"),
+    ];
+
+    /// The feature names the handlers refer to, with the constant names turned into the names they stand for, such as
+    /// `RPC_MUTING` into `rpc muting`. Tests are left out, they use feature names without handling anything.
+    fn wired_features() -> HashSet<String> {
+        let mut wired = HashSet::new();
+        for source in HANDLER_SOURCES {
+            let source = match source.find("#[cfg(test)]\nmod tests") {
+                Some(end) => &source[..end],
+                None => source,
+            };
+            for (start, _) in source.match_indices("feature::") {
+                let name = source[start + "feature::".len()..]
+                    .chars()
+                    .take_while(|c| c.is_ascii_uppercase() || *c == '_')
+                    .collect::<String>();
+                if !name.is_empty() {
+                    wired.insert(normalize(&name));
+                }
+            }
+        }
+        wired
+    }
+
+    fn normalize(name: &str) -> String {
+        name.to_lowercase().replace(['_', '-'], " ")
+    }
+
+    #[test]
+    fn registry_matches_the_handlers() {
+        let registered = REGISTRY
+            .iter()
+            .map(|r| normalize(r.name))
+            .collect::<HashSet<_>>();
+        assert_eq!(registered.len(), REGISTRY.len(), "names must be unique");
+
+        let wired = wired_features();
+        for name in &wired {
+            assert!(registered.contains(name), "{name} is not registered");
+        }
+        // features that only act when asked to, or only read, don't attribute anything to themselves
+        for r in REGISTRY
+            .iter()
+            .filter(|r| r.modifies_traffic && r.category != FeatureCategory::Injection)
+        {
+            assert!(
+                wired.contains(&normalize(r.name)),
+                "{} is registered but no handler attributes changes to it",
+                r.name
+            );
+            assert!(!r.observes.is_empty(), "{} observes nothing", r.name);
+        }
+        assert!(!is_registered("password stripper"));
+    }
+
+    #[test]
+    fn describes_the_current_settings() {
+        let hax = HaxState::default();
+        hax.update_settings(|s| {
+            s.strip_passwords = true;
+            s.spoofed_name = (true, "streamer".into());
+            s.dry_run.features.insert(feature::NAME_SPOOFING, true);
+        });
+
+        let features = hax.list_features();
+        assert_eq!(features.len(), REGISTRY.len());
+        let get = |name| features.iter().find(|f| f.name == name).unwrap();
+
+        let spoofing = get(feature::NAME_SPOOFING);
+        assert!(spoofing.enabled && spoofing.dry_run);
+        assert_eq!(spoofing.config, "streamer");
+        assert_eq!(spoofing.category, FeatureCategory::Game);
+
+        let stripping = get(feature::PASSWORD_STRIPPING);
+        assert!(stripping.enabled && !stripping.dry_run);
+        assert_eq!(get(feature::LOBBY_REWRITES).config, "password stripping");
+
+        assert!(!get(feature::GHOST_JOIN).enabled);
+        let esp = get(feature::ESP);
+        assert!(!esp.modifies_traffic && !esp.dry_run);
+    }
+}
@@ -0,0 +1,353 @@
+//! Routing of game server connections through the proxy.
+//!
+//! The lobby tells the client which game server to connect to. We replace that address with one that points to our
+//! own websocket endpoint and contains a token, and remember which upstream server the token stands for. When the
+//! client connects to that address, the proxy looks the token up and connects to the original game server.
+
+use std::time::{Duration, Instant};
+
+use hyper::Uri;
+use photon_lib::indexmap::IndexMap;
+use tracing::{debug, warn};
+
+/// The path segment that precedes the token in a local game server address.
+const ROUTE_PATH: &str = "/game/";
+
+/// How long a route stays valid after it was last used.
+const ROUTE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How many routes to remember at most. The least recently used ones get forgotten first.
+const MAX_ROUTES: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct GameServerRoute {
+    /// The game server address the lobby originally sent.
+    pub upstream: Uri,
+    /// The last time the route was handed out or connected to.
+    pub last_used: Instant,
+}
+
+/// Maps tokens in local game server addresses to the upstream game servers they stand for.
+#[derive(Debug, Default)]
+pub struct GameServerRoutes {
+    /// Our own websocket endpoint, such as `ws://127.0.0.1:48897/socket`. Addresses are only rewritten if this is set.
+    endpoint: Option<Uri>,
+    /// The routes by token, least recently used first.
+    routes: IndexMap<String, GameServerRoute>,
+    next_token: u64,
+}
+
+impl GameServerRoutes {
+    /// Sets the endpoint clients can reach the websocket proxy on, which enables rewriting game server addresses.
+    pub fn set_endpoint(&mut self, endpoint: Uri) {
+        self.endpoint = Some(endpoint);
+    }
+
+    pub fn endpoint(&self) -> Option<&Uri> {
+        self.endpoint.as_ref()
+    }
+
+    /// The routes that have not expired yet, least recently used first.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &GameServerRoute)> {
+        self.routes
+            .iter()
+            .map(|(token, route)| (token.as_str(), route))
+    }
+
+    /// Creates a route to the given upstream address and returns the local address the client should connect to
+    /// instead.
+    ///
+    /// Returns `None` if no endpoint is set or the upstream address is not a valid uri. Registering the same upstream
+    /// twice returns the same local address.
+    pub fn register(&mut self, upstream: &str, now: Instant) -> Option<String> {
+        self.expire(now);
+
+        let endpoint = self.endpoint.as_ref()?;
+        let upstream = match upstream.parse::<Uri>() {
+            Ok(uri) => uri,
+            Err(e) => {
+                warn!(
+                    upstream,
+                    "Not routing game server with invalid address: {e}"
+                );
+                return None;
+            }
+        };
+
+        let existing = self.routes.iter().position(|(_, r)| r.upstream == upstream);
+        let token = match existing {
+            Some(index) => {
+                let (token, _) = self.routes.shift_remove_index(index)?;
+                token
+            }
+            None => {
+                self.next_token += 1;
+                format!("{:x}", self.next_token)
+            }
+        };
+
+        let local = format!(
+            "{}{ROUTE_PATH}{token}",
+            endpoint.to_string().trim_end_matches('/')
+        );
+        debug!(
+            token,
+            upstream = upstream.to_string(),
+            "Registered game server route"
+        );
+
+        if self.routes.len() >= MAX_ROUTES {
+            self.routes.shift_remove_index(0);
+        }
+        self.routes.insert(
+            token,
+            GameServerRoute {
+                upstream,
+                last_used: now,
+            },
+        );
+
+        Some(local)
+    }
+
+    /// Looks up the upstream game server for a local address created by [Self::register].
+    ///
+    /// The address can either be complete, as passed through the url shim of the game client, or only be the path
+    /// of a request that connected to the local address directly.
+    pub fn resolve(&mut self, address: &Uri, now: Instant) -> Option<Uri> {
+        self.expire(now);
+
+        if let Some(authority) = address.authority() {
+            if self.endpoint.as_ref()?.authority() != Some(authority) {
+                return None;
+            }
+        }
+
+        let (_, token) = address.path().rsplit_once(ROUTE_PATH)?;
+        // re-insert to keep the order least recently used first
+        let (token, mut route) = self.routes.shift_remove_entry(token)?;
+        route.last_used = now;
+        let upstream = route.upstream.clone();
+        self.routes.insert(token, route);
+
+        Some(upstream)
+    }
+
+    /// Forgets routes that have not been used for a while.
+    fn expire(&mut self, now: Instant) {
+        self.routes
+            .retain(|_, route| now.saturating_duration_since(route.last_used) < ROUTE_TTL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use futures_util::{lock::Mutex, SinkExt, StreamExt};
+    use hyper::{server::conn::Http, Uri};
+    use photon_lib::{
+        highlevel::constants::{operation_code, parameter_code},
+        indexmap::IndexMap,
+        photon_data_type::PhotonDataType,
+        photon_message::{OperationResponse, PhotonMessage},
+    };
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::{GameServerRoutes, ROUTE_TTL};
+    use crate::{
+        hax::{BulletForceHax, HaxState},
+        proxy::{Direction, WebSocketServer},
+    };
+
+    fn routes() -> GameServerRoutes {
+        let mut routes = GameServerRoutes::default();
+        routes.set_endpoint("ws://127.0.0.1:48897/socket".parse().unwrap());
+        routes
+    }
+
+    #[test]
+    fn resolves_full_address_and_path() {
+        let now = Instant::now();
+        let mut routes = routes();
+
+        let local = routes.register("wss://gs1.example.com:19091", now).unwrap();
+        assert!(local.starts_with("ws://127.0.0.1:48897/socket/game/"));
+
+        let upstream: Uri = "wss://gs1.example.com:19091".parse().unwrap();
+        let full: Uri = local.parse().unwrap();
+        assert_eq!(routes.resolve(&full, now), Some(upstream.clone()));
+        let path: Uri = full.path().parse().unwrap();
+        assert_eq!(routes.resolve(&path, now), Some(upstream));
+
+        let other_host: Uri = local.replace("127.0.0.1", "10.0.0.1").parse().unwrap();
+        assert_eq!(routes.resolve(&other_host, now), None);
+    }
+
+    #[test]
+    fn multiple_routes() {
+        let now = Instant::now();
+        let mut routes = routes();
+
+        let first = routes.register("ws://gs1.example.com:2083", now).unwrap();
+        let second = routes.register("ws://gs2.example.com:2083", now).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(
+            routes.register("ws://gs1.example.com:2083", now),
+            Some(first.clone())
+        );
+
+        assert_eq!(
+            routes.resolve(&first.parse().unwrap(), now),
+            Some("ws://gs1.example.com:2083".parse().unwrap())
+        );
+        assert_eq!(
+            routes.resolve(&second.parse().unwrap(), now),
+            Some("ws://gs2.example.com:2083".parse().unwrap())
+        );
+        assert_eq!(routes.iter().count(), 2);
+    }
+
+    #[test]
+    fn expires_unused_routes() {
+        let now = Instant::now();
+        let mut routes = routes();
+
+        let stale = routes.register("ws://gs1.example.com:2083", now).unwrap();
+        let used = routes.register("ws://gs2.example.com:2083", now).unwrap();
+
+        let later = now + ROUTE_TTL / 2;
+        assert!(routes.resolve(&used.parse().unwrap(), later).is_some());
+
+        let much_later = now + ROUTE_TTL + Duration::from_secs(1);
+        assert_eq!(routes.resolve(&stale.parse().unwrap(), much_later), None);
+        assert!(routes.resolve(&used.parse().unwrap(), much_later).is_some());
+    }
+
+    #[test]
+    fn no_endpoint_no_rewrite() {
+        let mut routes = GameServerRoutes::default();
+        assert_eq!(
+            routes.register("ws://gs1.example.com:2083", Instant::now()),
+            None
+        );
+    }
+
+    /// Starts a websocket server that greets every client with its name.
+    async fn mock_game_server(name: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+                    ws.send(Message::Text(name.into())).await.unwrap();
+                    // keep the connection open until the client is done
+                    _ = ws.next().await;
+                });
+            }
+        });
+
+        format!("ws://{addr}")
+    }
+
+    /// Starts the websocket proxy on a random port and returns its address.
+    async fn start_proxy(hax: &mut BulletForceHax) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = hax.get_websocket_proxy();
+
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let service = service.clone();
+                tokio::spawn(async move {
+                    _ = Http::new()
+                        .serve_connection(tcp, service)
+                        .with_upgrades()
+                        .await;
+                });
+            }
+        });
+
+        format!("ws://{addr}/socket")
+    }
+
+    /// Passes a lobby join response for the given game server through the hook, and returns the rewritten address.
+    fn join_game_through_hook(state: Arc<Mutex<HaxState>>, game_server: &str) -> String {
+        let mut parameters = IndexMap::new();
+        parameters.insert(
+            parameter_code::ADDRESS,
+            PhotonDataType::String(game_server.into()),
+        );
+        let mut data = vec![];
+        PhotonMessage::OperationResponse(OperationResponse {
+            operation_code: operation_code::JOIN_GAME,
+            return_code: 0,
+            debug_message: None,
+            parameters,
+        })
+        .to_websocket_bytes(&mut data)
+        .unwrap();
+
+        let forward = HaxState::websocket_hook(
+            state,
+            &mut data,
+            WebSocketServer::LobbyServer,
+            Direction::ServerToClient,
+        )
+        .unwrap();
+        assert!(forward);
+
+        match PhotonMessage::from_websocket_bytes(&mut data.as_slice()).unwrap() {
+            PhotonMessage::OperationResponse(mut resp) => {
+                match resp.parameters.remove(&parameter_code::ADDRESS) {
+                    Some(PhotonDataType::String(address)) => address,
+                    other => panic!("unexpected address {other:?}"),
+                }
+            }
+            other => panic!("unexpected message {other:?}"),
+        }
+    }
+
+    async fn greeting(url: &str) -> Message {
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let message = ws.next().await.unwrap().unwrap();
+        ws.close(None).await.unwrap();
+        message
+    }
+
+    #[tokio::test]
+    async fn routes_clients_to_upstreams() {
+        let first_upstream = mock_game_server("first").await;
+        let second_upstream = mock_game_server("second").await;
+
+        let mut hax = BulletForceHax::default();
+        let endpoint = start_proxy(&mut hax).await;
+        let state = hax.get_state();
+        state
+            .lock()
+            .await
+            .game_server_routes
+            .set_endpoint(endpoint.parse().unwrap());
+
+        let first_local = join_game_through_hook(state.clone(), &first_upstream);
+        let second_local = join_game_through_hook(state.clone(), &second_upstream);
+        assert!(first_local.starts_with(&endpoint));
+        assert!(second_local.starts_with(&endpoint));
+
+        // through the url shim of the game client
+        assert_eq!(
+            greeting(&format!("{endpoint}?{second_local}")).await,
+            Message::Text("second".into())
+        );
+        // connecting to the rewritten address directly
+        assert_eq!(greeting(&first_local).await, Message::Text("first".into()));
+    }
+}
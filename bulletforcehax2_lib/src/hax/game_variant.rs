@@ -0,0 +1,166 @@
+//! The game variants whose rooms are listed in the BulletForce lobby.
+//!
+//! Besides BulletForce itself, the lobby lists rooms of the "newfps" variant. They hold the same information, but
+//! under lowercase property keys, and their game version is prefixed with `newfps-`. Lobby features read and write
+//! room properties through [BulletForceRoomProperties](crate::protocol::properties::BulletForceRoomProperties),
+//! which looks up the keys of the room's variant, so they work the same for both.
+
+pub use crate::protocol::properties::{GameVariant, PropertyKeys};
+
+/// Which variants the lobby features apply to. Rooms of other variants are passed through untouched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VariantSettings {
+    pub newfps: bool,
+}
+
+impl Default for VariantSettings {
+    fn default() -> Self {
+        Self { newfps: true }
+    }
+}
+
+impl VariantSettings {
+    pub fn is_enabled(&self, variant: GameVariant) -> bool {
+        match variant {
+            GameVariant::BulletForce => true,
+            GameVariant::NewFps => self.newfps,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "proxy"))]
+mod tests {
+    use photon_lib::{
+        highlevel::{
+            constants::{event_code, game_property_key, operation_code, parameter_code},
+            structs::{RoomInfoList, RoomInfoView},
+            PhotonParameterMapConversion,
+        },
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationRequest, PhotonMessage},
+        PhotonHashmap,
+    };
+
+    use super::{GameVariant, VariantSettings};
+    use crate::{
+        hax::timeline::Replay,
+        proxy::{Direction, WebSocketServer},
+        testsupport::captured_from,
+    };
+
+    #[test]
+    fn enabled_variants() {
+        let settings = VariantSettings { newfps: false };
+        assert!(settings.is_enabled(GameVariant::BulletForce));
+        assert!(!settings.is_enabled(GameVariant::NewFps));
+        assert!(VariantSettings::default().is_enabled(GameVariant::NewFps));
+    }
+
+    /// A passworded mobile room of an older version, with the variant's own property keys.
+    fn room(variant: GameVariant, name: &str, version: &str) -> PhotonDataType {
+        let keys = variant.keys();
+        let string = |s: &str| PhotonDataType::String(s.into());
+        PhotonDataType::Hashtable(indexmap! {
+            PhotonDataType::Byte(game_property_key::PLAYER_COUNT) => PhotonDataType::Byte(3),
+            string(keys.room_name) => string(name),
+            string(keys.password) => string("1234"),
+            string(keys.store_id) => string("BALYZE_MOBILE"),
+            string(keys.game_version) => string(version),
+            string(keys.map_name) => string("Urban"),
+        })
+    }
+
+    /// Replays a game list with a room of each variant, returning the rooms as the client receives them.
+    fn replay_game_list(replay: &mut Replay) -> PhotonHashmap {
+        replay.feed(&captured_from(
+            WebSocketServer::LobbyServer,
+            Direction::ClientToServer,
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::AUTHENTICATE,
+                parameters: indexmap! {
+                    parameter_code::APP_VERSION => PhotonDataType::String("1.90.0_1.99".into()),
+                },
+            }),
+        ));
+        let game_list = captured_from(
+            WebSocketServer::LobbyServer,
+            Direction::ServerToClient,
+            PhotonMessage::EventData(EventData {
+                code: event_code::GAME_LIST,
+                parameters: indexmap! {
+                    parameter_code::GAME_LIST => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::String("bf".into()) => room(GameVariant::BulletForce, "Pros", "1.89.0"),
+                        PhotonDataType::String("nf".into()) => room(GameVariant::NewFps, "Chill", "newfps-0.4.1"),
+                    }),
+                },
+            }),
+        );
+        let raw = replay.forward(&game_list).unwrap();
+        let mut event = match PhotonMessage::from_websocket_bytes(&mut raw.as_slice()).unwrap() {
+            PhotonMessage::EventData(event) => event,
+            other => panic!("expected the game list, got {other:?}"),
+        };
+        RoomInfoList::from_map(&mut event.parameters).unwrap().games
+    }
+
+    fn view<'a>(games: &'a PhotonHashmap, id: &str) -> RoomInfoView<&'a PhotonHashmap> {
+        match &games[&PhotonDataType::String(id.into())] {
+            PhotonDataType::Hashtable(room) => RoomInfoView(room),
+            other => panic!("expected a room, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn rewrites_both_variants() {
+        let mut replay = Replay::default();
+        replay.state().update_settings(|s| {
+            s.strip_passwords = true;
+            s.show_mobile_games = true;
+            s.show_other_versions = true;
+        });
+        let games = replay_game_list(&mut replay);
+
+        let bulletforce = view(&games, "bf");
+        assert_eq!(
+            bulletforce.custom_str("roomName"),
+            Some("[p] [1.89.0] [M] Pros")
+        );
+        assert_eq!(bulletforce.custom_str("gameVersion"), Some("1.90.0"));
+
+        let newfps = view(&games, "nf");
+        // the client can't play newfps rooms, so their version is left alone
+        assert_eq!(newfps.custom_str("roomname"), Some("[p] [M] Chill"));
+        assert_eq!(newfps.custom_str("password"), Some(""));
+        assert_eq!(newfps.custom_str("storeid"), Some("BALYZE_WEB"));
+        assert_eq!(newfps.custom_str("gameversion"), Some("newfps-0.4.1"));
+
+        let state = replay.state();
+        let (_, lobby) = state.lobby_state.as_ref().unwrap();
+        let cached = lobby.rooms.get("nf").unwrap();
+        assert_eq!(cached.variant, GameVariant::NewFps);
+        assert_eq!(cached.view().custom_str("password"), Some("1234"));
+        assert_eq!(
+            lobby.rooms.get("bf").unwrap().variant,
+            GameVariant::BulletForce
+        );
+    }
+
+    #[test]
+    fn skips_disabled_variant() {
+        let mut replay = Replay::default();
+        replay.state().update_settings(|s| {
+            s.strip_passwords = true;
+            s.lobby_variants.newfps = false;
+        });
+        let games = replay_game_list(&mut replay);
+
+        assert_eq!(view(&games, "bf").custom_str("roomName"), Some("[p] Pros"));
+        let newfps = view(&games, "nf");
+        assert_eq!(newfps.custom_str("roomname"), Some("Chill"));
+        assert_eq!(newfps.custom_str("password"), Some("1234"));
+        // skipped rooms are still cached
+        let state = replay.state();
+        assert_eq!(state.lobby_state.as_ref().unwrap().1.rooms.len(), 2);
+    }
+}
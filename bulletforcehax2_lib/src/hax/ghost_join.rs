@@ -0,0 +1,264 @@
+//! Joins rooms without spawning a player for the other clients, to watch the traffic of a match.
+//!
+//! Photon has no spectators, so the server still counts us as an actor and shows us in the room's player count. What
+//! can be hidden is the player body: the client spawns it by raising an instantiation event, and dropping that event
+//! means the other clients never create it. Our own client still spawns locally and keeps sending serialized updates
+//! for the body, which the other clients ignore as PUN drops updates for views it doesn't know. The custom properties
+//! of the join request, such as the team and loadout, are stripped so we don't show up on scoreboards either. The
+//! Photon-defined properties are kept, as the server needs them to accept the join.
+
+use photon_lib::{
+    highlevel::structs::InstantiationEventData, indexmap::IndexMap,
+    photon_data_type::PhotonDataType, PhotonHashmap,
+};
+
+/// The prefab the client instantiates for its player.
+pub const PLAYER_PREFAB: &str = "PlayerBody";
+
+/// How many instantiations to remember the decision for. Retries come right after the original, so only the latest
+/// ones matter.
+const MAX_DECISIONS: usize = 64;
+
+/// Removes the custom properties from the player properties of a JOIN_GAME request. Returns how many were removed.
+pub fn strip_join_properties(player_properties: &mut PhotonHashmap) -> usize {
+    let before = player_properties.len();
+    player_properties.retain(|key, _| !matches!(key, PhotonDataType::String(_)));
+    before - player_properties.len()
+}
+
+/// Which of our player's instantiations were dropped on a game connection.
+#[derive(Debug, Default)]
+pub struct GhostJoin {
+    /// Whether each player instantiation was dropped, keyed by instantiation id. The client may send the same
+    /// instantiation again, which has to be handled the same way even if ghost joining was toggled in between.
+    decisions: IndexMap<i32, bool>,
+    /// Whether our client sent serialized updates for its player.
+    serialized_own_player: bool,
+}
+
+impl GhostJoin {
+    /// Whether an instantiation raised by our client should be dropped.
+    pub fn should_drop_instantiation(
+        &mut self,
+        data: &InstantiationEventData,
+        enabled: bool,
+    ) -> bool {
+        if data.prefab_name != PLAYER_PREFAB {
+            return false;
+        }
+        if let Some(dropped) = self.decisions.get(&data.instantiation_id) {
+            return *dropped;
+        }
+        if self.decisions.len() >= MAX_DECISIONS {
+            self.decisions.shift_remove_index(0);
+        }
+        self.decisions.insert(data.instantiation_id, enabled);
+        enabled
+    }
+
+    /// The instantiation ids of the player bodies that the other clients never saw.
+    pub fn dropped_instantiations(&self) -> impl Iterator<Item = i32> + '_ {
+        self.decisions
+            .iter()
+            .filter(|(_, dropped)| **dropped)
+            .map(|(id, _)| *id)
+    }
+
+    /// How many instantiation decisions are remembered.
+    pub fn len(&self) -> usize {
+        self.decisions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.decisions.is_empty()
+    }
+
+    pub fn observe_own_serialize(&mut self) {
+        self.serialized_own_player = true;
+    }
+
+    /// Whether our player is being serialized without ever having been instantiated as a [PLAYER_PREFAB], which
+    /// means this game version spawns players in a way that can't be hidden.
+    pub fn spawns_unrecognized(&self) -> bool {
+        self.serialized_own_player && self.decisions.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::{
+        highlevel::{
+            constants::{operation_code, parameter_code, pun_event_code},
+            structs::{InstantiationEventData, RaiseEvent},
+            PhotonMapConversion, PhotonParameterMapConversion,
+        },
+        indexmap::{indexmap, IndexMap},
+        photon_data_type::PhotonDataType,
+        photon_message::{OperationResponse, PhotonMessage},
+        PhotonHashmap,
+    };
+
+    use super::PLAYER_PREFAB;
+    use crate::{
+        hax::{bandwidth::feature, drop_log::DropReason, timeline::Replay},
+        inspect::CapturedMessage,
+        proxy::Direction,
+        testsupport::{captured, request},
+    };
+
+    fn join_response() -> CapturedMessage {
+        captured(
+            Direction::ServerToClient,
+            PhotonMessage::OperationResponse(OperationResponse {
+                operation_code: operation_code::JOIN_GAME,
+                return_code: 0,
+                debug_message: None,
+                parameters: indexmap! {
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                    parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                    parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                },
+            }),
+        )
+    }
+
+    fn raise_event(code: u8, data: PhotonHashmap) -> CapturedMessage {
+        let mut parameters = IndexMap::new();
+        RaiseEvent::new(code, PhotonDataType::Hashtable(data)).into_map(&mut parameters);
+        request(operation_code::RAISE_EVENT, parameters)
+    }
+
+    fn instantiation(prefab_name: &str, instantiation_id: i32) -> CapturedMessage {
+        let mut data = PhotonHashmap::new();
+        InstantiationEventData {
+            prefab_name: prefab_name.into(),
+            position: None,
+            rotation: None,
+            group: None,
+            views_ids: None,
+            incoming_instantiation_data: None,
+            server_time: 1000,
+            instantiation_id,
+            obj_level_prefix: None,
+            custom_properties: IndexMap::new(),
+        }
+        .into_map(&mut data);
+        raise_event(pun_event_code::INSTANTIATION, data)
+    }
+
+    /// Runs the messages through the hooks like the proxy does, returning what would be forwarded.
+    fn forward(replay: &mut Replay, messages: &[CapturedMessage]) -> Vec<Option<PhotonMessage>> {
+        messages
+            .iter()
+            .map(|message| {
+                replay
+                    .forward(message)
+                    .map(|raw| PhotonMessage::from_websocket_bytes(&mut raw.as_slice()).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hides_own_player_from_join_sequence() {
+        let mut replay = Replay::default();
+        replay.state().update_settings(|s| s.ghost_join = true);
+
+        let join = request(
+            operation_code::JOIN_GAME,
+            indexmap! {
+                parameter_code::ROOM_NAME => PhotonDataType::String("room".into()),
+                parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::Byte(255) => PhotonDataType::String("me".into()),
+                    PhotonDataType::String("teamNumber".into()) => PhotonDataType::Byte(1),
+                }),
+            },
+        );
+        let forwarded = forward(
+            &mut replay,
+            &[
+                request(operation_code::AUTHENTICATE, indexmap! {}),
+                join,
+                join_response(),
+                instantiation("Match Manager", 1),
+                instantiation(PLAYER_PREFAB, 1001),
+                // the client resending the same instantiation
+                instantiation(PLAYER_PREFAB, 1001),
+            ],
+        );
+        assert!(forwarded[..4].iter().all(Option::is_some));
+        assert_eq!(forwarded[4], None);
+        assert_eq!(forwarded[5], None);
+        match &forwarded[1] {
+            Some(PhotonMessage::OperationRequest(request)) => assert_eq!(
+                request.parameters[&parameter_code::PLAYER_PROPERTIES],
+                PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::Byte(255) => PhotonDataType::String("me".into()),
+                })
+            ),
+            other => panic!("expected the join request, got {other:?}"),
+        }
+
+        // turning it off only affects new spawns, retries of the hidden one stay hidden
+        replay.state().update_settings(|s| s.ghost_join = false);
+        let forwarded = forward(
+            &mut replay,
+            &[
+                instantiation(PLAYER_PREFAB, 1001),
+                instantiation(PLAYER_PREFAB, 1002),
+            ],
+        );
+        assert_eq!(forwarded[0], None);
+        assert!(forwarded[1].is_some());
+
+        let state = replay.state();
+        assert!(state.stats.recent_errors.is_empty());
+        let (_, gameplay) = state.gameplay_state.as_ref().unwrap();
+        assert_eq!(
+            gameplay.ghost.dropped_instantiations().collect::<Vec<_>>(),
+            [1001]
+        );
+        assert_eq!(state.drop_log.entries().len(), 3);
+        assert!(state.drop_log.entries().all(|m| matches!(
+            m.reason,
+            DropReason::GhostJoin {
+                instantiation_id: 1001
+            }
+        )));
+        assert!(state
+            .feature_availability()
+            .get(feature::GHOST_JOIN)
+            .is_available());
+    }
+
+    #[test]
+    fn flags_players_spawned_differently() {
+        let mut replay = Replay::default();
+        replay.state().update_settings(|s| s.ghost_join = true);
+
+        // our player's view sends updates without having been instantiated
+        let serialize = raise_event(
+            pun_event_code::SEND_SERIALIZE,
+            indexmap! {
+                PhotonDataType::Byte(0) => PhotonDataType::Integer(12345),
+                PhotonDataType::Byte(1) => PhotonDataType::Short(0),
+                PhotonDataType::Byte(10) => PhotonDataType::ObjectArray(vec![
+                    PhotonDataType::Integer(1001),
+                    PhotonDataType::Boolean(false),
+                    PhotonDataType::Null,
+                ]),
+            },
+        );
+        let forwarded = forward(
+            &mut replay,
+            &[
+                request(operation_code::AUTHENTICATE, indexmap! {}),
+                join_response(),
+                serialize,
+            ],
+        );
+        assert!(forwarded.iter().all(Option::is_some));
+
+        let availability = replay.state().feature_availability();
+        assert!(!availability.get(feature::GHOST_JOIN).is_available());
+    }
+}
@@ -0,0 +1,280 @@
+//! How long the message handlers take, to find out which one is slow when the proxy hiccups.
+
+use std::{
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use photon_lib::indexmap::IndexMap;
+use tracing::warn;
+
+use crate::proxy::{Direction, WebSocketServer};
+
+/// The parts of the websocket hook that are timed.
+pub mod handler {
+    /// The handlers that only read messages, such as the drift detector and the self-test.
+    pub const OBSERVERS: &str = "observers";
+    pub const NAME_SERVER: &str = "name server";
+    pub const LOBBY: &str = "lobby";
+    pub const GAME: &str = "game";
+    /// Serializing the message a handler changed.
+    pub const SERIALIZE_CHANGE: &str = "serialize change";
+}
+
+/// How many buckets a histogram has. Bucket `i` holds durations below `2^i` microseconds, the last one holds the rest.
+const BUCKETS: usize = 24;
+
+/// A histogram of durations, with power of two buckets in microseconds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TimingHistogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    total_micros: u64,
+    max_micros: u64,
+}
+
+impl TimingHistogram {
+    pub fn record(&mut self, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.total_micros = self.total_micros.saturating_add(micros);
+        self.max_micros = self.max_micros.max(micros);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_micros(self.total_micros / self.count))
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_micros)
+    }
+
+    /// An upper bound of the duration that the given fraction of calls stayed under, from `0.0` to `1.0`.
+    ///
+    /// This is the upper edge of the bucket the percentile falls in, so it can be up to twice the actual value.
+    pub fn percentile(&self, fraction: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let target = ((self.count as f64 * fraction.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                let upper = match bucket {
+                    0 => 0,
+                    b if b == BUCKETS - 1 => self.max_micros,
+                    b => (1u64 << b) - 1,
+                };
+                return Some(Duration::from_micros(upper.min(self.max_micros)));
+            }
+        }
+        Some(self.max())
+    }
+
+    /// The number of calls per bucket, with the exclusive upper bound of each bucket. The last bound is [None].
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.buckets.iter().enumerate().map(|(i, count)| {
+            let upper = (i < BUCKETS - 1).then(|| Duration::from_micros(1 << i));
+            (upper, *count)
+        })
+    }
+}
+
+/// A single handler invocation, for the tracing span and the slow handler warning.
+#[derive(Debug, Clone, Copy)]
+pub struct HandlerCall {
+    pub handler: &'static str,
+    pub server: WebSocketServer,
+    pub direction: Direction,
+    /// The type of the message, such as `EventData`.
+    pub message_type: Option<&'static str>,
+    /// The operation or event code of the message.
+    pub code: Option<u8>,
+}
+
+/// The timings of each handler, keyed by [handler] name.
+#[derive(Debug, Clone, Default)]
+pub struct HandlerTimings {
+    handlers: IndexMap<&'static str, TimingHistogram>,
+}
+
+impl HandlerTimings {
+    pub fn record(&mut self, handler: &'static str, elapsed: Duration) {
+        self.handlers.entry(handler).or_default().record(elapsed);
+    }
+
+    /// Records a call, and warns if it took longer than the threshold. Returns whether it was slow.
+    pub fn record_call(
+        &mut self,
+        call: &HandlerCall,
+        elapsed: Duration,
+        slow_threshold: Option<Duration>,
+    ) -> bool {
+        self.record(call.handler, elapsed);
+        match slow_threshold {
+            Some(threshold) if elapsed > threshold => {
+                warn!(
+                    handler = call.handler,
+                    server = format!("{}", call.server),
+                    direction = format!("{}", call.direction),
+                    message_type = call.message_type,
+                    code = call.code,
+                    elapsed_us = elapsed.as_micros() as u64,
+                    threshold_us = threshold.as_micros() as u64,
+                    "Slow message handler"
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn get(&self, handler: &str) -> Option<&TimingHistogram> {
+        self.handlers.get(handler)
+    }
+
+    /// The handlers in the order they were first timed.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &TimingHistogram)> {
+        self.handlers
+            .iter()
+            .map(|(name, histogram)| (*name, histogram))
+    }
+}
+
+/// Records [HandlerTimings] without going through the state's lock, so timing a handler doesn't wait on the UI. Cheap
+/// to clone, all clones share the same timings.
+#[derive(Debug, Clone, Default)]
+pub struct HandlerTimer {
+    timings: Arc<Mutex<HandlerTimings>>,
+}
+
+impl HandlerTimer {
+    /// See [HandlerTimings::record_call].
+    pub fn record_call(
+        &self,
+        call: &HandlerCall,
+        elapsed: Duration,
+        slow_threshold: Option<Duration>,
+    ) -> bool {
+        self.lock().record_call(call, elapsed, slow_threshold)
+    }
+
+    /// The timings recorded so far.
+    pub fn timings(&self) -> HandlerTimings {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HandlerTimings> {
+        // a call is recorded in a single statement, so the timings stay consistent even if poisoned
+        self.timings.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Display for HandlerTimings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, histogram) in self.iter() {
+            let p99 = histogram.percentile(0.99).unwrap_or_default();
+            writeln!(
+                f,
+                "{name}: {} calls, mean {:?}, p99 <= {p99:?}, max {:?}",
+                histogram.count(),
+                histogram.mean().unwrap_or_default(),
+                histogram.max()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{handler, HandlerCall, HandlerTimer, HandlerTimings, TimingHistogram};
+    use crate::proxy::{Direction, WebSocketServer};
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = TimingHistogram::default();
+        assert_eq!(histogram.percentile(0.5), None);
+
+        for micros in [0, 1, 3, 3, 100, 100, 100, 100, 100, 5000] {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count(), 10);
+        assert_eq!(histogram.mean(), Some(Duration::from_micros(550)));
+        assert_eq!(histogram.max(), Duration::from_millis(5));
+        // 100us falls in the bucket from 64 to 127us
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_micros(127)));
+        assert_eq!(histogram.percentile(1.0), Some(Duration::from_millis(5)));
+        assert_eq!(histogram.percentile(0.0), Some(Duration::ZERO));
+        assert_eq!(histogram.buckets().map(|(_, n)| n).sum::<u64>(), 10);
+
+        // durations past the last bucket are still counted
+        histogram.record(Duration::from_secs(3600));
+        assert_eq!(histogram.buckets().last().unwrap(), (None, 1));
+        assert_eq!(histogram.percentile(1.0), Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn timings_per_handler() {
+        let mut timings = HandlerTimings::default();
+        timings.record(handler::GAME, Duration::from_micros(10));
+        timings.record(handler::OBSERVERS, Duration::from_micros(2));
+        timings.record(handler::GAME, Duration::from_micros(30));
+
+        let call = HandlerCall {
+            handler: handler::LOBBY,
+            server: WebSocketServer::LobbyServer,
+            direction: Direction::ServerToClient,
+            message_type: Some("EventData"),
+            code: Some(230),
+        };
+        let threshold = Some(Duration::from_millis(5));
+        assert!(!timings.record_call(&call, Duration::from_millis(1), threshold));
+        assert!(timings.record_call(&call, Duration::from_millis(8), threshold));
+        assert!(!timings.record_call(&call, Duration::from_millis(8), None));
+
+        assert_eq!(timings.get(handler::LOBBY).unwrap().count(), 3);
+        assert_eq!(timings.get(handler::GAME).unwrap().count(), 2);
+        assert!(timings.get(handler::NAME_SERVER).is_none());
+        assert_eq!(
+            timings.to_string(),
+            "\
+game: 2 calls, mean 20µs, p99 <= 30µs, max 30µs
+observers: 1 calls, mean 2µs, p99 <= 2µs, max 2µs
+lobby: 3 calls, mean 5.666ms, p99 <= 8ms, max 8ms
+"
+        );
+    }
+
+    #[test]
+    fn timer_clones_share_timings() {
+        let timer = HandlerTimer::default();
+        let call = HandlerCall {
+            handler: handler::GAME,
+            server: WebSocketServer::GameServer,
+            direction: Direction::ServerToClient,
+            message_type: None,
+            code: None,
+        };
+        timer
+            .clone()
+            .record_call(&call, Duration::from_micros(10), None);
+        timer.record_call(&call, Duration::from_micros(30), None);
+        let timings = timer.timings();
+        assert_eq!(timings.get(handler::GAME).unwrap().count(), 2);
+
+        // the copy doesn't change anymore
+        timer.record_call(&call, Duration::from_micros(50), None);
+        assert_eq!(timings.get(handler::GAME).unwrap().count(), 2);
+    }
+}
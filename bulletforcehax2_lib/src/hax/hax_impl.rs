@@ -1,66 +1,316 @@
-use std::{ops::DerefMut, sync::Arc};
+use std::{
+    any::Any,
+    cell::OnceCell,
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
 
-use futures_util::lock::Mutex;
+use futures_util::lock::{Mutex, MutexGuard};
 use photon_lib::{
+    display::Bounded,
     highlevel::{
         constants::{event_code, operation_code, parameter_code, pun_event_code},
+        parameters::Parameters,
         structs::{
-            DestroyEvent, DestroyEventData, InstantiationEvent, InstantiationEventData,
+            AuthenticateResponse, ChangeGroupsRequest, DestroyEvent, DestroyEventData,
+            GetPropertiesResponse, GetRegionsResponse, InstantiationEvent, InstantiationEventData,
             JoinGameRequest, JoinGameResponseSuccess, LeaveEvent, Player, PropertiesChangedEvent,
-            RaiseEvent, RoomInfo, RoomInfoList, RpcCall, RpcEvent, SendSerializeEvent,
-            SetPropertiesOperationRequest,
+            RaiseEvent, RoomInfoList, RoomInfoView, RpcCall, RpcEvent, SendSerializeEvent,
+            SetPropertiesOperationRequest, WellKnownActorProperties, WellKnownRoomProperties,
         },
         PhotonMapConversion, PhotonParameterMapConversion,
     },
     photon_data_type::PhotonDataType,
     photon_message::PhotonMessage,
+    ParseMode,
 };
-use tracing::{debug, trace, warn};
+use tracing::{debug, info, trace, trace_span, warn};
 
-use super::VersionInfo;
+use super::{bandwidth::feature, validation::validate_rewrite, VersionInfo};
 use crate::{
-    hax::{HaxState, PlayerActor},
-    protocol::{player_script::PlayerScript, rpc::get_rpc_method_name},
-    proxy::{Direction, WebSocketServer},
+    error::HaxError,
+    hax::{
+        actor_history::{ActorFields, ActorHistory, Rename},
+        client_health::{ClientHealthSettings, MOVEMENT_RPCS},
+        detection::Detection,
+        drop_log::{DropReason, DroppedMessage},
+        dry_run::{self, DryRunEntry, WouldHave},
+        event_dedup::EventKey,
+        events::{EventBus, HaxEvent},
+        ghost_join,
+        handler_timing::{handler, HandlerCall, HandlerTimer},
+        hold::{self, Hold, HookVerdict},
+        host_migration,
+        journal::{room_field, ChangedKey, Section},
+        lobby_sort::sort_games,
+        match_phase::RoundTracker,
+        match_summary::{MatchEnd, MatchTracker},
+        parse_breaker::{BreakerTransition, ParseDecision},
+        population::AppStats,
+        property_firewall::PropertyTarget,
+        property_refresh::{RefreshError, RefreshOutcome, RefreshResponse, Refusal},
+        public_feed::{FeedMessage, FeedPlayer},
+        replayed_rooms,
+        room_expectations::RoomExpectation,
+        room_names::{Annotation, AnnotationKind, ComposedName},
+        room_overlay::RoomView,
+        room_probe::{self, ProbeAnswer},
+        room_property_history::{emit_room_property_changes, RoomPropertyHistory},
+        room_rename::PropertiesResponse,
+        settings::Settings,
+        stealth_host,
+        transforms::{message_feature, transform_game_list, LobbySettings, RoomContext},
+        web_requests::{SessionContext, StreamedBody, WebEndpoint, WebExchange, WebPhase},
+        GameplayState, HaxState, PlayerActor,
+    },
+    inspect::CapturedMessage,
+    protocol::{
+        player_script::PlayerScript,
+        properties::{BulletForceActorProperties, BulletForceRoomProperties},
+        rpc::{get_rpc_method_name, is_cosmetic_method},
+    },
+    proxy::{
+        watchdog::{PING_CLIENT_TIME_PARAMETER, PING_OPERATION_CODE},
+        Direction, WebSocketServer,
+    },
 };
 
 #[allow(dead_code)]
 enum WebSocketHookAction {
-    /// Replace the original message with the given one
-    Change(PhotonMessage),
-    /// Drop this message completely, don't forward it to the client/server
-    Drop,
+    /// Replace the original message with the given one. Also holds the name of the feature that made the change, see
+    /// [feature].
+    Change(PhotonMessage, &'static str),
+    /// Drop this message completely, don't forward it to the client/server. The reason ends up in the drop log.
+    Drop(DropReason),
+    /// Forward the message, possibly changed, once the condition of the hold is met. See [hold](super::hold).
+    Hold(Hold),
     /// Do nothing, just pass along the original message
     DoNothing,
 }
 
+/// Gets the message a panic was started with, if it was given one.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match (
+        payload.downcast_ref::<&str>(),
+        payload.downcast_ref::<String>(),
+    ) {
+        (Some(message), _) => message,
+        (_, Some(message)) => message,
+        _ => "<no message>",
+    }
+}
+
+/// The state as the handlers see it, locked the first time it's used.
+///
+/// Messages that don't touch the state never wait for it, and [Self::release] lets go of it around work that doesn't
+/// need it, like transforming a large game list. It's locked again the next time it's used.
+struct StateGuard<'a> {
+    shared: &'a Mutex<HaxState>,
+    guard: OnceCell<MutexGuard<'a, HaxState>>,
+}
+
+impl<'a> StateGuard<'a> {
+    fn new(shared: &'a Mutex<HaxState>) -> Self {
+        Self {
+            shared,
+            guard: OnceCell::new(),
+        }
+    }
+
+    fn release(&mut self) {
+        self.guard.take();
+    }
+}
+
+impl Deref for StateGuard<'_> {
+    type Target = HaxState;
+
+    fn deref(&self) -> &HaxState {
+        self.guard
+            .get_or_init(|| futures::executor::block_on(self.shared.lock()))
+    }
+}
+
+impl DerefMut for StateGuard<'_> {
+    fn deref_mut(&mut self) -> &mut HaxState {
+        if self.guard.get().is_none() {
+            let _ = self
+                .guard
+                .set(futures::executor::block_on(self.shared.lock()));
+        }
+        self.guard.get_mut().expect("locked above")
+    }
+}
+
 impl HaxState {
     #[allow(clippy::ptr_arg)]
     pub fn webrequest_hook_onrequest(
-        _hax: Arc<Mutex<Self>>,
-        _url: &hyper::Uri,
-        _bytes: &mut Vec<u8>,
-    ) -> anyhow::Result<()> {
+        hax: Arc<Mutex<Self>>,
+        session: &SessionContext,
+        url: &hyper::Uri,
+        bytes: &mut Vec<u8>,
+    ) -> Result<(), HaxError> {
+        Self::record_web_exchange(hax, session, WebPhase::Request, url, bytes);
         Ok(())
     }
 
     #[allow(clippy::ptr_arg)]
     pub fn webrequest_hook_onresponse(
-        _hax: Arc<Mutex<Self>>,
-        _url: &hyper::Uri,
-        _bytes: &mut Vec<u8>,
-    ) -> anyhow::Result<()> {
+        hax: Arc<Mutex<Self>>,
+        session: &SessionContext,
+        url: &hyper::Uri,
+        bytes: &mut Vec<u8>,
+    ) -> Result<(), HaxError> {
+        Self::record_web_exchange(hax, session, WebPhase::Response, url, bytes);
         Ok(())
     }
 
+    /// Runs the streaming hooks once a body was forwarded completely. Its chunks went through [StreamedBody::chunk].
+    pub async fn webrequest_hook_onstreamed(hax: Arc<Mutex<Self>>, body: StreamedBody) {
+        let digest = match body.asset {
+            Some(asset) => asset.finish(&body.session, &body.url),
+            None => return,
+        };
+        debug!(
+            url = digest.url,
+            size = digest.size,
+            sha256 = digest.sha256,
+            "Downloaded game asset"
+        );
+        if let Some(previous) = hax.lock().await.web_assets.record(digest) {
+            info!(
+                url = previous.url,
+                previous = previous.sha256,
+                "Game asset changed since it was last downloaded"
+            );
+        }
+    }
+
+    /// Keeps requests to tracked endpoints for the session timeline, see [web_requests](super::web_requests).
+    fn record_web_exchange(
+        hax: Arc<Mutex<Self>>,
+        session: &SessionContext,
+        phase: WebPhase,
+        url: &hyper::Uri,
+        bytes: &[u8],
+    ) {
+        let endpoint = match WebEndpoint::matching(url) {
+            Some(endpoint) => endpoint,
+            None => return,
+        };
+        let exchange = WebExchange::new(session, endpoint, phase, url, bytes);
+        debug!(
+            exchange = format!("{exchange}"),
+            "Web request to a tracked endpoint"
+        );
+        let mut hax = futures::executor::block_on(hax.lock());
+        hax.web_requests.record(exchange.clone());
+        hax.events.emit(HaxEvent::WebRequest(exchange));
+    }
+
     /// Runs logic on this websocket message and returns whether the given data should be forwarded on.
+    ///
+    /// Messages the handlers [hold](super::hold) count as forwarded, as there is no relay to hold them in. See
+    /// [Self::websocket_hook_verdict] for the proxy's view.
     pub fn websocket_hook(
         hax: Arc<Mutex<Self>>,
         data: &mut Vec<u8>,
         server: WebSocketServer,
         direction: Direction,
-    ) -> anyhow::Result<bool> {
-        let photon_message = PhotonMessage::from_websocket_bytes(&mut data.as_slice())?;
+    ) -> Result<bool, HaxError> {
+        let verdict = Self::websocket_hook_verdict(hax, data, server, direction)?;
+        Ok(!matches!(verdict, HookVerdict::Drop))
+    }
+
+    /// Runs logic on this websocket message and returns what to do with the given data.
+    pub fn websocket_hook_verdict(
+        shared: Arc<Mutex<Self>>,
+        data: &mut Vec<u8>,
+        server: WebSocketServer,
+        direction: Direction,
+    ) -> Result<HookVerdict, HaxError> {
+        // The state is only locked while it's changed. Parsing and serializing happen without it, so a reader holding
+        // the state isn't kept waiting by a large message, and the message isn't kept waiting by the reader as long.
+        let captured = CapturedMessage {
+            timestamp: SystemTime::now(),
+            server,
+            direction,
+            raw: data.clone(),
+        };
+        // one snapshot of the settings for the whole message, so the handlers agree on them
+        let (settings, timer, decision) = {
+            let mut hax = futures::executor::block_on(shared.lock());
+            match direction {
+                Direction::ClientToServer => hax.stats.messages_client_to_server += 1,
+                Direction::ServerToClient => hax.stats.messages_server_to_client += 1,
+            }
+            if server == WebSocketServer::GameServer {
+                hax.record_match_traffic(direction, data.len());
+            }
+            hax.capture_sinks.record(&captured);
+            hax.recent_messages.push(captured);
+
+            // we can't decrypt these, so there's nothing to do but forward them
+            if PhotonMessage::is_encrypted_websocket_bytes(data) {
+                hax.encryption.record(server, direction);
+                debug!(
+                    server = format!("{server}"),
+                    direction = format!("{direction}"),
+                    "Forwarding encrypted message"
+                );
+                return Ok(HookVerdict::Forward);
+            }
+            let breaker_settings = hax.parse_breaker_settings.clone();
+            let decision = hax
+                .parse_breaker
+                .connection_mut(server)
+                .decide(Instant::now(), &breaker_settings);
+            (hax.settings(), hax.stats.handler_timings.clone(), decision)
+        };
+        if decision == ParseDecision::Skip {
+            return Ok(HookVerdict::Forward);
+        }
+
+        // unknown data types the parser can frame are passed through as they are, see PhotonDataType::Unknown
+        let parsed = {
+            let mut remaining = data.as_slice();
+            PhotonMessage::from_websocket_bytes_with_mode(&mut remaining, ParseMode::Lenient)
+                .map_err(|source| HaxError::ProtocolParse {
+                    source,
+                    len: data.len(),
+                    offset: data.len() - remaining.len(),
+                })
+        };
+        #[cfg(test)]
+        if let Ok(message) = &parsed {
+            tests::pause_after_parsing(message);
+        }
+        let unknown_count = parsed.as_ref().map_or(0, |m| m.unknown_count());
+        {
+            let mut hax = futures::executor::block_on(shared.lock());
+            let breaker_settings = hax.parse_breaker_settings.clone();
+            let transition = hax
+                .parse_breaker
+                .connection_mut(server)
+                .record(parsed.is_ok(), &breaker_settings);
+            if let Some(transition) = transition {
+                emit_breaker_transition(&hax.events, server, transition);
+            }
+            hax.stats.unknown_values += unknown_count as u64;
+        }
+        let has_unknown = unknown_count > 0;
+        let mut photon_message = match (decision, parsed) {
+            // probes are only there to find out whether parsing works again, they're never handled
+            (ParseDecision::Probe, Ok(_)) => return Ok(HookVerdict::Forward),
+            (ParseDecision::Probe, Err(e)) => {
+                debug!(server = format!("{server}"), "Probe failed to parse: {e}");
+                return Ok(HookVerdict::Forward);
+            }
+            (_, parsed) => parsed?,
+        };
 
         let debug_info = match &photon_message {
             PhotonMessage::OperationRequest(r) => Some(("OperationRequest", r.operation_code)),
@@ -78,124 +328,741 @@ impl HaxState {
         if let Some((name, code)) = debug_info {
             debug!(name, code, direction = format!("{direction}"), "Message");
 
-            // We're logging message_data with "full" formatting here.
+            // We're logging message_data with pretty formatting here.
             // It's a trace log which should only be logged to file and accessed in a structured
-            // manner such as through json.
+            // manner such as through json. It's bounded, as eg. a full game list is megabytes.
             trace!(
                 message_type = name,
                 message_code = code,
-                message_data = format!("{photon_message:#?}"),
+                message_data =
+                    Bounded::new(&photon_message, settings.debug.message_display).to_string(),
                 direction = format!("{direction}"),
                 "Message data"
             );
         }
 
-        let action = match server {
-            WebSocketServer::LobbyServer => Self::match_packet_lobby(hax, photon_message)?,
-            WebSocketServer::GameServer => Self::match_packet_game(hax, photon_message)?,
+        // The handlers always see our own identity and the servers only ever the generated one, so what the server
+        // sends is swapped before the handlers run and what the client sends after, see [identity_randomizer].
+        // messages don't clone, but the original is still there to parse another copy from
+        let copy = || {
+            PhotonMessage::from_websocket_bytes_with_mode(&mut data.as_slice(), ParseMode::Lenient)
+                .ok()
+        };
+        let randomized_copy = match (settings.identity_randomizer, direction, &photon_message) {
+            (true, Direction::ClientToServer, PhotonMessage::OperationRequest(_)) => copy(),
+            _ => None,
+        };
+
+        let mut hax = StateGuard::new(&shared);
+        let identity_swapped = match (randomized_copy, direction) {
+            (Some(mut randomized), _) => {
+                let own_actor = hax
+                    .gameplay_state
+                    .as_ref()
+                    .and_then(|(_, state)| state.player_id);
+                hax.identity
+                    .randomize(&mut randomized, own_actor)
+                    .then_some(randomized)
+            }
+            (None, Direction::ServerToClient) if settings.identity_randomizer => {
+                // only the identity is needed to restore the copy, so it is parsed without the state
+                match hax.identity.restore(&mut photon_message) {
+                    true => Some(hax.identity.clone()),
+                    false => None,
+                }
+                .and_then(|identity| {
+                    hax.release();
+                    copy().map(|mut restored| {
+                        identity.restore(&mut restored);
+                        restored
+                    })
+                })
+            }
+            (None, _) => None,
+        };
+
+        let call = |handler| HandlerCall {
+            handler,
+            server,
+            direction,
+            message_type: debug_info.map(|(name, _)| name),
+            code: debug_info.map(|(_, code)| code),
+        };
+
+        Self::timed(&timer, &settings, call(handler::OBSERVERS), || {
+            let hax = &mut *hax;
+            hax.observe_selftest(&photon_message);
+            hax.drift.observe(&photon_message);
+            hax.join_trace.observe(server, direction, &photon_message);
+            if direction == Direction::ServerToClient {
+                hax.observe_restrictions(server, &photon_message);
+                hax.disconnects
+                    .observe(server, &photon_message, Instant::now());
+            }
+        });
+
+        let dispatch = match server {
+            WebSocketServer::NameServer => handler::NAME_SERVER,
+            WebSocketServer::LobbyServer => handler::LOBBY,
+            WebSocketServer::GameServer => handler::GAME,
+        };
+        // A panicking handler shouldn't take down the relay. The state it was working on may be left half-updated,
+        // but the mutex doesn't poison so the next message can still be handled, which is the lesser evil.
+        let action = Self::timed(&timer, &settings, call(dispatch), || {
+            catch_unwind(AssertUnwindSafe(|| {
+                #[cfg(test)]
+                tests::match_packet_panicking(&photon_message);
+
+                match server {
+                    WebSocketServer::NameServer => {
+                        Self::match_packet_nameserver(&mut hax, &settings, photon_message)
+                    }
+                    WebSocketServer::LobbyServer => {
+                        Self::match_packet_lobby(&shared, &mut hax, &settings, photon_message)
+                    }
+                    WebSocketServer::GameServer => {
+                        Self::match_packet_game(&mut hax, &settings, photon_message)
+                    }
+                }
+            }))
+        })
+        .unwrap_or_else(|payload| {
+            hax.stats.handler_panics += 1;
+            Err(anyhow::anyhow!(
+                "handler panicked: {}",
+                panic_message(payload.as_ref())
+            ))
+        });
+
+        // handlers use anyhow internally, but may bubble up a typed error
+        let action = action.map_err(|e| match e.downcast::<HaxError>() {
+            Ok(e) => e,
+            Err(source) => {
+                // the handler took the message, but the original is still there to show in the status report
+                hax.release();
+                if let Ok(message) = PhotonMessage::from_websocket_bytes(&mut data.as_slice()) {
+                    let shown = Bounded::new(&message, settings.debug.message_display).compact();
+                    hax.stats.last_failed_message = Some(shown.to_string());
+                }
+                HaxError::HandlerFailed {
+                    code: debug_info.map(|(_, code)| code),
+                    direction,
+                    source,
+                }
+            }
+        })?;
+
+        #[cfg(feature = "shared_state")]
+        if server == WebSocketServer::GameServer {
+            hax.publish_shared_state();
+        }
+
+        let action = {
+            // the handler may have changed what held messages wait for
+            hax.holds.wake();
+            hax.publish_ui_snapshot_if_due(Instant::now());
+            match (action, direction, debug_info) {
+                (
+                    WebSocketHookAction::DoNothing,
+                    Direction::ClientToServer,
+                    Some(("OperationRequest", code)),
+                ) => match hax.holds.take_armed(server, code) {
+                    Some(hold) => WebSocketHookAction::Hold(hold),
+                    None => WebSocketHookAction::DoNothing,
+                },
+                (action, _, _) => action,
+            }
+        };
+        let action = match (action, identity_swapped) {
+            (WebSocketHookAction::Change(mut message, feature), Some(_))
+                if direction == Direction::ClientToServer =>
+            {
+                let hax = &mut *hax;
+                let own_actor = hax
+                    .gameplay_state
+                    .as_ref()
+                    .and_then(|(_, state)| state.player_id);
+                hax.identity.randomize(&mut message, own_actor);
+                WebSocketHookAction::Change(message, feature)
+            }
+            (WebSocketHookAction::DoNothing, Some(message)) => {
+                WebSocketHookAction::Change(message, feature::IDENTITY_RANDOMIZER)
+            }
+            // held messages are forwarded as they are once the hold ends, so they're swapped now
+            (WebSocketHookAction::Hold(hold), Some(message))
+                if !settings.is_inert()
+                    && !settings.dry_run.is_dry(feature::IDENTITY_RANDOMIZER) =>
+            {
+                hax.release();
+                let mut buf = vec![];
+                message.to_websocket_bytes(&mut buf)?;
+                hax.bandwidth
+                    .record_rewrite(feature::IDENTITY_RANDOMIZER, data.len(), buf.len());
+                *data = buf;
+                WebSocketHookAction::Hold(hold)
+            }
+            (action, _) => action,
         };
+        hax.release();
 
+        // Observing only and pausing are enforced here rather than by each feature, so a feature that forgets to check can't change
+        // anything. Everything they would have done is logged like in a dry run.
+        let is_dry = |feature| settings.is_inert() || settings.dry_run.is_dry(feature);
         match action {
-            WebSocketHookAction::Change(new_message) => {
+            WebSocketHookAction::Change(new_message, feature) => {
                 let mut buf: Vec<u8> = vec![];
-                new_message.to_websocket_bytes(&mut buf)?;
+                Self::timed(&timer, &settings, call(handler::SERIALIZE_CHANGE), || {
+                    new_message.to_websocket_bytes(&mut buf)
+                })?;
+
+                if has_unknown && !reads_back(&buf, &new_message) {
+                    hax.stats.rejected_rewrites += 1;
+                    warn!(
+                        direction = format!("{direction}"),
+                        feature,
+                        "Modified message moved values of unknown types, forwarding original instead"
+                    );
+                    return Ok(HookVerdict::Forward);
+                }
+                if settings.debug.validate_rewrites {
+                    if let Err(problems) = validate_rewrite(data.as_slice(), &buf, &new_message) {
+                        hax.stats.rejected_rewrites += 1;
+                        warn!(
+                            direction = format!("{direction}"),
+                            problems = format!("{problems:#?}"),
+                            "Modified message failed validation, forwarding original instead"
+                        );
+                        return Ok(HookVerdict::Forward);
+                    }
+                }
+
+                if is_dry(feature) {
+                    // the handler took the parsed message, but the original is still there to compare against
+                    let (before_summary, after_summary) =
+                        match PhotonMessage::from_websocket_bytes(&mut data.as_slice()) {
+                            Ok(original) => (
+                                dry_run::summarize(&original, data.len()),
+                                dry_run::summarize_change(&original, &new_message, buf.len()),
+                            ),
+                            Err(_) => (
+                                format!("{} bytes", data.len()),
+                                dry_run::summarize(&new_message, buf.len()),
+                            ),
+                        };
+                    hax.dry_run_log.record(DryRunEntry {
+                        timestamp: SystemTime::now(),
+                        server,
+                        direction,
+                        code: debug_info.map(|(_, code)| code),
+                        outcome: WouldHave::Changed {
+                            feature,
+                            before_summary,
+                            after_summary,
+                        },
+                    });
+                    return Ok(HookVerdict::Forward);
+                }
+
+                hax.bandwidth.record_rewrite(feature, data.len(), buf.len());
+                if server == WebSocketServer::GameServer {
+                    if let Some((_, state)) = &mut hax.gameplay_state {
+                        state.match_tracker.record_modification(feature);
+                    }
+                }
                 *data = buf;
             }
-            WebSocketHookAction::Drop => return Ok(false),
+            WebSocketHookAction::Drop(reason) => {
+                if is_dry(reason.feature()) {
+                    hax.dry_run_log.record(DryRunEntry {
+                        timestamp: SystemTime::now(),
+                        server,
+                        direction,
+                        code: debug_info.map(|(_, code)| code),
+                        outcome: WouldHave::Dropped(reason),
+                    });
+                    return Ok(HookVerdict::Forward);
+                }
+                if server == WebSocketServer::GameServer {
+                    if let Some((_, state)) = &mut hax.gameplay_state {
+                        state.match_tracker.record_modification(reason.feature());
+                    }
+                }
+                hax.drop_log.record(DroppedMessage {
+                    timestamp: SystemTime::now(),
+                    server,
+                    direction,
+                    code: debug_info.map(|(_, code)| code),
+                    reason,
+                });
+                return Ok(HookVerdict::Drop);
+            }
+            WebSocketHookAction::Hold(hold) => {
+                if is_dry(hold.feature) {
+                    hax.dry_run_log.record(DryRunEntry {
+                        timestamp: SystemTime::now(),
+                        server,
+                        direction,
+                        code: debug_info.map(|(_, code)| code),
+                        outcome: WouldHave::Held {
+                            feature: hold.feature,
+                            condition: hold.condition.to_string(),
+                        },
+                    });
+                    return Ok(HookVerdict::Forward);
+                }
+                if server == WebSocketServer::GameServer {
+                    if let Some((_, state)) = &mut hax.gameplay_state {
+                        state.match_tracker.record_modification(hold.feature);
+                    }
+                }
+                debug!(
+                    direction = format!("{direction}"),
+                    feature = hold.feature,
+                    "Holding message {}",
+                    hold.condition
+                );
+                return Ok(HookVerdict::Hold(hold));
+            }
             WebSocketHookAction::DoNothing => (),
         }
 
-        Ok(true)
+        Ok(HookVerdict::Forward)
+    }
+
+    /// Runs a handler in a tracing span, and records how long it took in [HaxStats::handler_timings].
+    ///
+    /// The span is only created when trace logging is enabled, timing the handler costs two clock reads.
+    fn timed<T>(
+        timer: &HandlerTimer,
+        settings: &Settings,
+        call: HandlerCall,
+        f: impl FnOnce() -> T,
+    ) -> T {
+        let span = trace_span!(
+            "handler",
+            handler = call.handler,
+            server = %call.server,
+            direction = %call.direction,
+            code = call.code,
+            elapsed_us = tracing::field::Empty,
+        );
+        let start = Instant::now();
+        let result = span.in_scope(f);
+        let elapsed = start.elapsed();
+        span.record("elapsed_us", elapsed.as_micros() as u64);
+
+        timer.record_call(&call, elapsed, settings.debug.slow_handler_threshold);
+        result
+    }
+
+    fn match_packet_nameserver(
+        hax: &mut Self,
+        settings: &Settings,
+        photon_message: PhotonMessage,
+    ) -> anyhow::Result<WebSocketHookAction> {
+        match photon_message {
+            PhotonMessage::OperationRequest(mut operation_request) => {
+                match operation_request.operation_code {
+                    operation_code::AUTHENTICATE | operation_code::AUTHENTICATE_ONCE => {
+                        let (forced_enabled, forced_region) = settings.forced_region.clone();
+
+                        let mut parameters = Parameters(&mut operation_request.parameters);
+                        let mut changes_made = false;
+                        if let Some(region) = parameters.region() {
+                            let mut region = region.to_string();
+                            if forced_enabled
+                                && !forced_region.is_empty()
+                                && region != forced_region
+                            {
+                                debug!(
+                                    original = region.as_str(),
+                                    forced = forced_region.as_str(),
+                                    "Forcing region"
+                                );
+                                parameters.set_region(forced_region.clone());
+                                region = forced_region;
+                                changes_made = true;
+                            }
+
+                            if let Some((_, state)) = &mut hax.nameserver_state {
+                                state.region = Some(region);
+                            }
+                        }
+
+                        let overridden = match &settings.auth_overrides {
+                            Some(overrides) => overrides.apply(&mut operation_request.parameters),
+                            None => false,
+                        };
+                        if overridden {
+                            let region = Parameters(&operation_request.parameters)
+                                .region()
+                                .map(str::to_string);
+                            if let (Some((_, state)), Some(region)) =
+                                (&mut hax.nameserver_state, region)
+                            {
+                                state.region = Some(region);
+                            }
+                            return Ok(WebSocketHookAction::Change(
+                                PhotonMessage::OperationRequest(operation_request),
+                                feature::AUTH_OVERRIDES,
+                            ));
+                        }
+                        if changes_made {
+                            return Ok(WebSocketHookAction::Change(
+                                PhotonMessage::OperationRequest(operation_request),
+                                feature::REGION_FORCING,
+                            ));
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            PhotonMessage::OperationResponse(mut operation_response) => {
+                match operation_response.operation_code {
+                    operation_code::GET_REGIONS if operation_response.return_code == 0 => {
+                        let resp =
+                            GetRegionsResponse::from_map(&mut operation_response.parameters)?;
+                        if resp.regions.len() != resp.addresses.len() {
+                            warn!(
+                                regions = resp.regions.len(),
+                                addresses = resp.addresses.len(),
+                                "Region and address count in GetRegions response do not match"
+                            );
+                        }
+
+                        hax.global_state.regions = resp
+                            .iter_regions()
+                            .map(|(region, address)| (region.clone(), address.clone()))
+                            .collect();
+
+                        debug!(
+                            regions = format!("{:?}", hax.global_state.regions),
+                            "Received region list"
+                        );
+                    }
+                    operation_code::AUTHENTICATE | operation_code::AUTHENTICATE_ONCE
+                        if operation_response.return_code == 0 =>
+                    {
+                        let resp =
+                            AuthenticateResponse::from_map(&mut operation_response.parameters)?;
+                        debug!(
+                            address = format!("{:?}", resp.address),
+                            cluster = format!("{:?}", resp.cluster),
+                            "Name server authenticate response"
+                        );
+
+                        if let Some(user_id) = resp.user_id {
+                            hax.global_state.user_id = Some(user_id);
+                        }
+                        if let Some(address) = resp.address {
+                            hax.global_state.master_server_address = Some(address);
+                        }
+                        if let Some((_, state)) = &hax.nameserver_state {
+                            if let Some(region) = state.region.clone() {
+                                hax.global_state.region = Some(region);
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+
+        Ok(WebSocketHookAction::DoNothing)
     }
 
     fn match_packet_lobby(
-        hax: Arc<Mutex<Self>>,
+        shared: &Arc<Mutex<Self>>,
+        hax: &mut StateGuard,
+        settings: &Settings,
         photon_message: PhotonMessage,
     ) -> anyhow::Result<WebSocketHookAction> {
         match photon_message {
-            PhotonMessage::OperationRequest(operation_request) => {
+            PhotonMessage::OperationRequest(mut operation_request) => {
                 match operation_request.operation_code {
                     operation_code::AUTHENTICATE => {
-                        let mut hax = futures::executor::block_on(hax.lock());
+                        let parameters = Parameters(&operation_request.parameters);
 
-                        if let Some(PhotonDataType::String(app_version)) = operation_request
-                            .parameters
-                            .get(&parameter_code::APP_VERSION)
+                        if let Some(app_version) = parameters.app_version() {
+                            hax.global_state.version = VersionInfo::parse(app_version);
+                        }
+
+                        let changed = match &settings.auth_overrides {
+                            Some(overrides) => overrides.apply(&mut operation_request.parameters),
+                            None => false,
+                        };
+                        if let Some((_, lobby)) = &mut hax.lobby_state {
+                            lobby.probes.authenticating(&operation_request.parameters);
+                        }
+                        let parameters = Parameters(&operation_request.parameters);
+                        hax.global_state.server_version = match parameters.app_version() {
+                            Some(app_version) if changed => VersionInfo::parse(app_version),
+                            _ => None,
+                        };
+                        if let Some(user_id) = parameters.user_id() {
+                            hax.global_state.user_id = Some(user_id.to_string());
+                        }
+
+                        // the game list follows right after authenticating
+                        if hax.selftest.run_on_connect
+                            && hax.selftest_report().is_none()
+                            && !hax.is_selftest_running()
                         {
-                            let version = app_version.split_once('_');
-                            hax.global_state.version = version.map(|(game, photon)| VersionInfo {
-                                game_version: game.to_string(),
-                                photon_version: photon.to_string(),
-                            });
+                            hax.run_selftest();
                         }
 
-                        if let Some(PhotonDataType::String(user_id)) =
-                            operation_request.parameters.get(&parameter_code::USER_ID)
+                        if changed {
+                            debug!(
+                                client_version = format!("{:?}", hax.global_state.version),
+                                server_version = format!("{:?}", hax.global_state.server_version),
+                                "Overrode authentication parameters"
+                            );
+                            return Ok(WebSocketHookAction::Change(
+                                PhotonMessage::OperationRequest(operation_request),
+                                feature::AUTH_OVERRIDES,
+                            ));
+                        }
+                    }
+                    operation_code::JOIN_GAME => {
+                        let room_id = match Parameters(&operation_request.parameters).room_name() {
+                            Some(room_id) => room_id.to_string(),
+                            None => {
+                                hax.forwarded_to_lobby(operation_request.operation_code);
+                                return Ok(WebSocketHookAction::DoNothing);
+                            }
+                        };
+                        // kept for the game server's response to the join, see room_expectations
+                        let expected = hax.lobby_state.as_ref().and_then(|(_, lobby)| {
+                            let room = lobby.rooms.get(&room_id)?;
+                            Some(RoomExpectation::from_listing(&room_id, room))
+                        });
+                        hax.expected_room = expected;
+                        if let Some((proxy, lobby)) = &hax.lobby_state {
+                            // the server doesn't know the room, so answer for it
+                            if lobby.replayed_rooms.contains(&room_id) {
+                                debug!(room_id, "Refusing to join a replayed room");
+                                if let Err(e) = proxy.queue_client(
+                                    replayed_rooms::join_failure(&room_id),
+                                    feature::REPLAYED_ROOMS,
+                                ) {
+                                    warn!(room_id, "Could not answer the join: {e}");
+                                }
+                                return Ok(WebSocketHookAction::Drop(
+                                    DropReason::ReplayedRoomJoin { room_id },
+                                ));
+                            }
+                        }
+                        // the server answers it, also when held, see room_probe
+                        hax.forwarded_to_lobby(operation_request.operation_code);
+                        if settings.queue_jump {
+                            if let Some(hold) = hold::queue_jump(&room_id, hax) {
+                                debug!(room_id, "Waiting for a free slot in the full room");
+                                return Ok(WebSocketHookAction::Hold(hold));
+                            }
+                        }
+                    }
+                    operation_code::FIND_FRIENDS => {
+                        hax.forwarded_to_lobby(operation_request.operation_code);
+                    }
+                    operation_code::CREATE_GAME => {
+                        let stealth_host = hax.stealth_host;
+                        if stealth_host
+                            && stealth_host::hide_created_room(&mut operation_request.parameters)
                         {
-                            hax.global_state.user_id = Some(user_id.clone());
+                            debug!("Creating the room hidden from the lobby");
+                            return Ok(WebSocketHookAction::Change(
+                                PhotonMessage::OperationRequest(operation_request),
+                                feature::STEALTH_HOST,
+                            ));
                         }
                     }
                     _ => (),
                 }
             }
-            PhotonMessage::EventData(mut event) => match event.code {
-                event_code::GAME_LIST | event_code::GAME_LIST_UPDATE => {
-                    let (strip_passwords, show_mobile, show_all_versions, game_version) = {
-                        let hax = futures::executor::block_on(hax.lock());
-                        (
-                            hax.strip_passwords,
-                            hax.show_mobile_games,
-                            hax.show_other_versions,
-                            hax.global_state.version.clone(),
-                        )
-                    };
-                    let mut game_list = RoomInfoList::from_map(&mut event.parameters)?;
-                    let mut changes_made = false;
-
-                    for (k, v) in game_list.games.iter_mut() {
-                        if let (
-                            PhotonDataType::String(game_name),
-                            PhotonDataType::Hashtable(props),
-                        ) = (k, v)
+            PhotonMessage::OperationResponse(mut operation_response) => {
+                if let operation_code::FIND_FRIENDS | operation_code::JOIN_GAME =
+                    operation_response.operation_code
+                {
+                    let answer = hax.answer_room_probe(&operation_response);
+                    if let ProbeAnswer::Drop(reason, join) = answer {
+                        if let Some(join) = join {
+                            tokio::spawn(room_probe::join_and_leave(shared.clone(), join));
+                        }
+                        return Ok(WebSocketHookAction::Drop(reason));
+                    }
+                }
+                match operation_response.operation_code {
+                    operation_code::AUTHENTICATE if operation_response.return_code == 0 => {
+                        if let Some((_, lobby)) = &mut hax.lobby_state {
+                            // a string or bytes, see AuthenticateResponse
+                            let token = operation_response.parameters.get(&parameter_code::TOKEN);
+                            lobby.probes.authenticated(token);
+                        }
+                    }
+                    operation_code::JOIN_GAME
+                    | operation_code::CREATE_GAME
+                    | operation_code::JOIN_RANDOM_GAME
+                        if operation_response.return_code == 0 =>
+                    {
+                        // point the client at our proxy instead of the game server it was told about
+                        if let Some(PhotonDataType::String(address)) = operation_response
+                            .parameters
+                            .get_mut(&parameter_code::ADDRESS)
                         {
-                            let mut room_info = RoomInfo::from_map(props)?;
-
-                            // NOTE: BulletForce has `gameVersion` as key so this wont match
-                            if let Some(PhotonDataType::String(version)) =
-                                room_info.custom_properties.get("gameversion")
+                            if let Some(local) =
+                                hax.game_server_routes.register(address, Instant::now())
                             {
-                                if version.starts_with("newfps-") {
-                                    continue;
-                                }
+                                debug!(
+                                    upstream = address.as_str(),
+                                    local = local.as_str(),
+                                    "Routing game server through proxy"
+                                );
+                                *address = local;
+                                return Ok(WebSocketHookAction::Change(
+                                    PhotonMessage::OperationResponse(operation_response),
+                                    feature::GAME_SERVER_ROUTING,
+                                ));
                             }
-
-                            trace!("room {game_name}: {room_info:?}");
-
-                            if show_mobile {
-                                force_games_web(&mut room_info);
-                                changes_made = true;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            PhotonMessage::EventData(mut event) => match event.code {
+                event_code::GAME_LIST | event_code::GAME_LIST_UPDATE => {
+                    let mut game_list = RoomInfoList::from_map(&mut event.parameters)?;
+                    let (settings, room_notes, lobby_sort, mut contexts) = {
+                        let hax = &mut **hax;
+                        let now = Instant::now();
+                        let mut contexts = HashMap::new();
+                        if let Some((_, lobby)) = &mut hax.lobby_state {
+                            let dropped = match event.code {
+                                event_code::GAME_LIST => lobby.rooms.replace(&game_list.games, now),
+                                _ => lobby.rooms.update(&game_list.games, now),
+                            };
+                            let listed = game_list.games.keys().filter_map(|id| match id {
+                                PhotonDataType::String(id) => Some(id.clone()),
+                                _ => None,
+                            });
+                            for id in dropped.into_iter().chain(listed) {
+                                hax.journal
+                                    .record(Section::Lobby, ChangedKey::LobbyRoom(id));
                             }
-                            if show_all_versions {
-                                if let Some(version) = &game_version {
-                                    force_games_current_ver(&mut room_info, &version.game_version);
-                                    changes_made = true;
-                                } else {
-                                    warn!("Tried to adjust game version of lobby games but it was not known");
+                            hax.room_overlay.observe(
+                                &game_list.games,
+                                &lobby.rooms,
+                                event.code == event_code::GAME_LIST_UPDATE,
+                                now,
+                                &hax.room_overlay_settings,
+                            );
+                            for id in game_list.games.keys() {
+                                if let PhotonDataType::String(id) = id {
+                                    let mut context = RoomContext {
+                                        name: hax.room_overlay.get(id).and_then(|e| e.name.clone()),
+                                        ..Default::default()
+                                    };
+                                    let prefix = &hax.replayed_rooms.prefix;
+                                    if lobby.replayed_rooms.contains(id) && !prefix.is_empty() {
+                                        context
+                                            .annotations
+                                            .push(Annotation::new(AnnotationKind::Replay, prefix));
+                                    }
+                                    if let (Some(room), true) =
+                                        (lobby.rooms.get(id), settings.lobby_phase_annotations)
+                                    {
+                                        let view = RoomView::new(id, room, &hax.room_overlay);
+                                        context.annotations.extend(view.phase(now).annotation());
+                                    }
+                                    contexts.insert(id.clone(), context);
                                 }
                             }
-                            if strip_passwords {
-                                strip_password(&mut room_info);
-                                changes_made = true;
+                        }
+                        for (id, room) in &game_list.games {
+                            let (id, room) = match (id, room) {
+                                (PhotonDataType::String(id), PhotonDataType::Hashtable(room)) => {
+                                    (id, RoomInfoView(room))
+                                }
+                                _ => continue,
+                            };
+                            if let Some(host) = room.host_user_id() {
+                                hax.watch_player(
+                                    Some(host),
+                                    None,
+                                    Some(room.room_name().unwrap_or(id)),
+                                    WebSocketServer::LobbyServer,
+                                );
                             }
+                        }
+                        if settings.show_other_versions && hax.global_state.version.is_none() {
+                            warn!(
+                                "Tried to adjust game version of lobby games but it was not known"
+                            );
+                        }
+                        let lobby_settings = LobbySettings {
+                            show_mobile_games: settings.show_mobile_games,
+                            strip_passwords: settings.strip_passwords,
+                            forced_version: match settings.show_other_versions {
+                                true => hax
+                                    .global_state
+                                    .version
+                                    .as_ref()
+                                    .map(|v| v.game_version.clone()),
+                                false => None,
+                            },
+                            variants: settings.lobby_variants,
+                            names: hax.room_names.clone(),
+                        };
+                        (
+                            lobby_settings,
+                            hax.room_notes.clone(),
+                            settings.lobby_sort,
+                            contexts,
+                        )
+                    };
+                    // a full game list is megabytes, so the state isn't held while it's changed
+                    hax.release();
+                    let mut features = vec![];
+
+                    // updates only hold the changed rooms, sorting them would not sort the list
+                    if let (Some(sort), event_code::GAME_LIST) = (lobby_sort, event.code) {
+                        sort_games(&mut game_list.games, sort, &room_notes);
+                        features.push(feature::LOBBY_SORT);
+                    }
 
-                            room_info.into_map(props);
+                    let transformed =
+                        transform_game_list(&mut game_list.games, &settings, |game_name, room| {
+                            trace!("{} room {game_name}: {:?}", room.variant().name(), room.0);
+                            let context = contexts.remove(game_name).unwrap_or_default();
+                            // look up the note by the name the room was listed with, before any feature changed it
+                            let name = room.room_name().unwrap_or(game_name);
+                            let name = ComposedName::base(context.name.as_ref(), name);
+                            RoomContext {
+                                flag: room_notes.find_for_room(name, room).and_then(|n| n.flag),
+                                ..context
+                            }
+                        });
+                    features.extend(transformed.features);
+                    {
+                        for (id, name) in transformed.names {
+                            hax.room_overlay.set_name(&id, name);
                         }
                     }
 
                     // prevent doing work if we didnt actually change anything
-                    if changes_made {
+                    if let Some(feature) = message_feature(features) {
                         game_list.into_map(&mut event.parameters);
-                        return Ok(WebSocketHookAction::Change(PhotonMessage::EventData(event)));
+                        return Ok(WebSocketHookAction::Change(
+                            PhotonMessage::EventData(event),
+                            feature,
+                        ));
+                    }
+                }
+                event_code::APP_STATS => {
+                    let stats = AppStats::from_parameters(&event.parameters);
+                    trace!(stats = format!("{stats:?}"), "Received app stats");
+                    if let Some((_, lobby)) = &mut hax.lobby_state {
+                        lobby.app_stats = Some(stats);
                     }
                 }
                 _ => (),
@@ -207,16 +1074,106 @@ impl HaxState {
     }
 
     fn match_packet_game(
-        hax: Arc<Mutex<Self>>,
+        hax: &mut Self,
+        settings: &Settings,
         photon_message: PhotonMessage,
     ) -> anyhow::Result<WebSocketHookAction> {
+        // events replayed from the room's cache when joining it, see late_join
+        let mut historical = false;
+        if let PhotonMessage::EventData(event) = &photon_message {
+            let parameters = Parameters(&event.parameters);
+            if let Some((_, state)) = &mut hax.gameplay_state {
+                historical = state.late_join.observe(event.code, Instant::now());
+                if let (Some(group), Some(actor_id)) = (parameters.group(), parameters.actor_nr()) {
+                    if let Some(player) = state.players.get_mut(&actor_id) {
+                        player.interest_group = Some(group);
+                    }
+                }
+            }
+        }
+
         match photon_message {
             PhotonMessage::OperationRequest(mut operation_request) => {
                 match operation_request.operation_code {
                     operation_code::JOIN_GAME => {
                         let props = &mut operation_request.parameters;
-                        let _req = JoinGameRequest::from_map(props)?;
-                        debug!(request = format!("_req:?"), "Game Join Request");
+                        let mut req = JoinGameRequest::from_map(props)?;
+                        debug!(request = format!("{req:?}"), "Game Join Request");
+
+                        if let Some(properties) = &req.player_properties {
+                            if let Some((_, state)) = &mut hax.gameplay_state {
+                                state.own_platform.merge(
+                                    WellKnownActorProperties::new(properties).client_platform(),
+                                );
+                            }
+                        }
+
+                        let mut changed_by = None;
+                        if settings.ghost_join {
+                            let stripped = req
+                                .player_properties
+                                .as_mut()
+                                .map_or(0, ghost_join::strip_join_properties);
+                            if stripped > 0 {
+                                debug!(
+                                    stripped,
+                                    "Stripped custom player properties for ghost join"
+                                );
+                                changed_by = Some(feature::GHOST_JOIN);
+                            }
+                        }
+                        if let (Some(spoof), Some(properties)) =
+                            (&settings.platform_spoof, &mut req.player_properties)
+                        {
+                            if spoof.apply(properties) {
+                                changed_by = changed_by.or(Some(feature::PLATFORM_SPOOF));
+                            }
+                        }
+                        if let Some(feature) = changed_by {
+                            req.into_map(&mut operation_request.parameters);
+                            return Ok(WebSocketHookAction::Change(
+                                PhotonMessage::OperationRequest(operation_request),
+                                feature,
+                            ));
+                        }
+                    }
+
+                    operation_code::CREATE_GAME => {
+                        let stealth_host = hax.stealth_host;
+                        if let Some((_, state)) = &mut hax.gameplay_state {
+                            state.hosting = true;
+                            if let Some(PhotonDataType::Hashtable(properties)) = operation_request
+                                .parameters
+                                .get(&parameter_code::GAME_PROPERTIES)
+                            {
+                                state.observe_room_title(&WellKnownRoomProperties::new(properties));
+                            }
+                        }
+                        if stealth_host
+                            && stealth_host::hide_created_room(&mut operation_request.parameters)
+                        {
+                            return Ok(WebSocketHookAction::Change(
+                                PhotonMessage::OperationRequest(operation_request),
+                                feature::STEALTH_HOST,
+                            ));
+                        }
+                    }
+
+                    operation_code::LEAVE => {
+                        debug!("Leaving room");
+                        hax.finish_match(MatchEnd::Left);
+                        if let Some((_, state)) = &mut hax.gameplay_state {
+                            hax.journal
+                                .record_room_changed(state.players.keys().copied());
+                            state.clear_room();
+                        }
+                    }
+
+                    operation_code::GET_PROPERTIES => {
+                        // the server answers in order, see property_refresh
+                        if let Some((_, state)) = &mut hax.gameplay_state {
+                            state.pending_refreshes.forwarded();
+                        }
                     }
 
                     operation_code::SET_PROPERTIES => {
@@ -224,41 +1181,132 @@ impl HaxState {
                             &mut operation_request.parameters,
                         )?;
 
+                        let (stripped, stealth_host) = {
+                            let stripped =
+                                hax.property_firewall.filter(&mut req, SystemTime::now());
+                            // the server answers everything that isn't dropped, see room_rename
+                            if let (false, Some((_, state))) = (
+                                stripped > 0 && req.properties.is_empty(),
+                                &mut hax.gameplay_state,
+                            ) {
+                                state.pending_properties.forwarded();
+                            }
+                            (stripped, hax.stealth_host)
+                        };
+                        if stripped > 0 && req.properties.is_empty() {
+                            return Ok(WebSocketHookAction::Drop(DropReason::StrippedProperties {
+                                target: match req.actor_nr {
+                                    Some(actor_nr) => PropertyTarget::Actor(actor_nr),
+                                    None => PropertyTarget::Game,
+                                },
+                                count: stripped,
+                            }));
+                        }
+
                         if let Some(actor) = req.actor_nr {
                             // properties are for actor, not for room
-                            let mut player_props = Player::from_map(&mut req.properties)?;
+                            let mut player_props = Player::from_map(&mut req.properties.clone())?;
 
-                            let mut hax = futures::executor::block_on(hax.lock());
                             let (_, state) = match &mut hax.gameplay_state {
                                 Some(x) => x,
-                                _ => anyhow::bail!("gameplay state is None"),
+                                _ => {
+                                    return Err(HaxError::StateLock("gameplay state is None").into())
+                                }
                             };
 
                             if let Some(player) = state.players.get_mut(&actor) {
                                 player.merge_player(&player_props);
+                                hax.journal
+                                    .record(Section::Players, ChangedKey::Actor(actor));
+                            }
+                            if state.player_id == Some(actor) {
+                                state.own_platform.merge(player_props.client_platform());
                             }
 
+                            let mut changed_by = None;
                             if let (Some(nick), (true, new_nick)) =
-                                (&mut player_props.nickname, &hax.spoofed_name)
+                                (&mut player_props.nickname, &settings.spoofed_name)
                             {
                                 *nick = new_nick.clone();
-
                                 player_props.into_map(&mut req.properties);
+                                changed_by = Some(feature::NAME_SPOOFING);
+                            }
+                            // after the name, which writes the unspoofed custom properties back
+                            if let Some(spoof) = &settings.platform_spoof {
+                                if spoof.apply(&mut req.properties) {
+                                    changed_by = changed_by.or(Some(feature::PLATFORM_SPOOF));
+                                }
+                            }
+                            if let Some(feature) = changed_by {
                                 req.into_map(&mut operation_request.parameters);
+                                return Ok(WebSocketHookAction::Change(
+                                    PhotonMessage::OperationRequest(operation_request),
+                                    feature,
+                                ));
+                            }
+                        }
+
+                        // only room properties affect the visibility
+                        let hidden = req.actor_nr.is_none()
+                            && stealth_host
+                            && stealth_host::hide_room_properties(&mut req.properties);
+                        if stripped > 0 || hidden {
+                            req.into_map(&mut operation_request.parameters);
+                            return Ok(WebSocketHookAction::Change(
+                                PhotonMessage::OperationRequest(operation_request),
+                                match stripped {
+                                    0 => feature::STEALTH_HOST,
+                                    _ => feature::PROPERTY_FIREWALL,
+                                },
+                            ));
+                        }
+                    }
+
+                    operation_code::CHANGE_GROUPS => {
+                        let mut req =
+                            ChangeGroupsRequest::from_map(&mut operation_request.parameters)?;
+                        let receive_all = hax.receives_all_groups();
+                        let (_, state) = match &mut hax.gameplay_state {
+                            Some(x) => x,
+                            _ => return Err(HaxError::StateLock("gameplay state is None").into()),
+                        };
+                        state.requested_groups.apply(&req);
+                        debug!(
+                            remove = format!("{:?}", req.remove),
+                            add = format!("{:?}", req.add),
+                            requested = format!("{}", state.requested_groups),
+                            "Change interest groups"
+                        );
 
-                                return Ok(WebSocketHookAction::Change(
-                                    PhotonMessage::OperationRequest(operation_request),
-                                ));
-                            }
+                        if receive_all {
+                            // Joining everything again so groups created since are joined too. Rewrites can't change
+                            // the parameter count, leaving all groups first is harmless as they're all joined again.
+                            req = ChangeGroupsRequest {
+                                remove: (req.remove.is_some() && req.add.is_some()).then(Vec::new),
+                                add: Some(vec![]),
+                            };
+                            state.interest_groups.apply(&req);
+                            req.into_map(&mut operation_request.parameters);
+                            return Ok(WebSocketHookAction::Change(
+                                PhotonMessage::OperationRequest(operation_request),
+                                feature::ALL_INTEREST_GROUPS,
+                            ));
                         }
+                        state.interest_groups.apply(&req);
                     }
 
                     operation_code::RAISE_EVENT => {
                         let req = RaiseEvent::from_map(&mut operation_request.parameters)?;
+                        let target = req.target();
 
                         debug!(
                             event_code = req.event_code,
                             data = format!("{:?}", req.data),
+                            cache = format!("{:?}", req.cache),
+                            receiver_group = format!("{:?}", req.receiver_group),
+                            interest_group = format!("{:?}", req.interest_group),
+                            target_actors = format!("{:?}", req.actor_list),
+                            target = format!("{target}"),
                             "Raise event"
                         );
 
@@ -289,8 +1337,26 @@ impl HaxState {
                                     "Instantiation"
                                 );
 
-                                let hax = futures::executor::block_on(hax.lock());
-                                merge_instantiation(hax, sender, &event_data)?;
+                                merge_instantiation(hax, sender, &event_data, false)?;
+
+                                // our client still spawns locally, the others just never hear of it
+                                let ghost_join = settings.ghost_join;
+                                if let Some((_, state)) = &mut hax.gameplay_state {
+                                    if state
+                                        .ghost
+                                        .should_drop_instantiation(&event_data, ghost_join)
+                                    {
+                                        debug!(
+                                            instantiation_id = event_data.instantiation_id,
+                                            "Dropping our player instantiation for ghost join"
+                                        );
+                                        return Ok(WebSocketHookAction::Drop(
+                                            DropReason::GhostJoin {
+                                                instantiation_id: event_data.instantiation_id,
+                                            },
+                                        ));
+                                    }
+                                }
                             }
                             pun_event_code::SEND_SERIALIZE
                             | pun_event_code::SEND_SERIALIZE_RELIABLE => {
@@ -306,24 +1372,68 @@ impl HaxState {
                                             anyhow::anyhow!("Cound not parse serialized data")
                                         })?;
 
-                                let mut hax = futures::executor::block_on(hax.lock());
                                 let (_, state) = match &mut hax.gameplay_state {
                                     Some(x) => x,
-                                    _ => anyhow::bail!("gameplay state is None"),
+                                    _ => {
+                                        return Err(
+                                            HaxError::StateLock("gameplay state is None").into()
+                                        )
+                                    }
                                 };
 
+                                let layout =
+                                    hax.stream_layouts.for_profile(&hax.protocol_profile.name);
+                                let mut own_alive = None;
                                 for obj in serialized_data {
+                                    if state.projectiles.on_serialize(&obj, Instant::now()) {
+                                        continue;
+                                    }
+
                                     let actor_id = obj.get_view_id().get_owner_id();
+                                    if state.player_id == Some(actor_id) {
+                                        state.ghost.observe_own_serialize();
+                                    }
                                     if let Some(actor) = state.players.get_mut(&actor_id) {
                                         let player_script =
-                                            PlayerScript::from_object_array(&obj.data_stream)?;
+                                            PlayerScript::decode(&obj.data_stream, layout)?;
                                         trace!(
                                             actor_id,
                                             player_script = format!("{player_script:?}"),
                                             "SendSerialize for actor"
                                         );
 
-                                        actor.merge_player_script(&player_script);
+                                        let score = (actor.kills, actor.deaths);
+                                        let death = actor.merge_player_script(&player_script);
+                                        if (actor.kills, actor.deaths) != score {
+                                            hax.journal.record(
+                                                Section::Scoreboard,
+                                                ChangedKey::Actor(actor_id),
+                                            );
+                                        }
+                                        if let Some(death) = death {
+                                            let killer = death.killer;
+                                            state.kill_feed.record(
+                                                actor_id,
+                                                death,
+                                                state.map_name.as_deref(),
+                                                &hax.map_annotations,
+                                                SystemTime::now(),
+                                            );
+                                            hax.public_feed.record(
+                                                FeedMessage::Kill {
+                                                    victim: actor_id,
+                                                    killer,
+                                                },
+                                                Instant::now(),
+                                            );
+                                        }
+                                        // the client only serializes its own player
+                                        state.match_tracker.record_own_script(&player_script);
+                                        own_alive = Some(player_script.health > 0);
+                                        hax.public_feed.record(
+                                            FeedMessage::position(&player_script.position),
+                                            Instant::now(),
+                                        );
                                     }
                                     trace!(
                                         direction = "client",
@@ -332,6 +1442,13 @@ impl HaxState {
                                         "SendSerialize"
                                     );
                                 }
+
+                                let now = Instant::now();
+                                match own_alive {
+                                    Some(false) => state.cadence.despawned(),
+                                    _ => state.cadence.record(now, &hax.client_health),
+                                }
+                                update_client_health(state, &hax.events, now, &hax.client_health);
                             }
                             pun_event_code::RPC => {
                                 // client->server RPC call
@@ -355,9 +1472,30 @@ impl HaxState {
                                     method_name = method_name.to_string(),
                                     sender,
                                     parameters,
+                                    target = format!("{target}"),
                                     direction = "server",
                                     "RPC call"
                                 );
+
+                                hax.rpc_usage.record(
+                                    Direction::ClientToServer,
+                                    sender,
+                                    &data,
+                                    SystemTime::now(),
+                                );
+                                match &mut hax.gameplay_state {
+                                    Some((_, state)) if MOVEMENT_RPCS.contains(&&*method_name) => {
+                                        let now = Instant::now();
+                                        state.cadence.record(now, &hax.client_health);
+                                        update_client_health(
+                                            state,
+                                            &hax.events,
+                                            now,
+                                            &hax.client_health,
+                                        );
+                                    }
+                                    _ => (),
+                                }
                             }
                             _ => (),
                         }
@@ -367,18 +1505,76 @@ impl HaxState {
             }
             PhotonMessage::OperationResponse(mut operation_response) => {
                 match operation_response.operation_code {
+                    // the game server is moving us to another one, see server_migration
+                    _ if operation_response
+                        .parameters
+                        .contains_key(&parameter_code::ADDRESS) =>
+                    {
+                        if let (Some((_, state)), Some(PhotonDataType::String(address))) = (
+                            &mut hax.gameplay_state,
+                            operation_response
+                                .parameters
+                                .get_mut(&parameter_code::ADDRESS),
+                        ) {
+                            info!(
+                                address = address.as_str(),
+                                "Game server announced a move to another game server"
+                            );
+                            state.announced_redirect = Some(address.clone());
+                            if let Some(local) =
+                                hax.game_server_routes.register(address, Instant::now())
+                            {
+                                *address = local;
+                                return Ok(WebSocketHookAction::Change(
+                                    PhotonMessage::OperationResponse(operation_response),
+                                    feature::GAME_SERVER_ROUTING,
+                                ));
+                            }
+                        }
+                    }
                     operation_code::JOIN_GAME if operation_response.return_code == 0 => {
                         let props = &mut operation_response.parameters;
                         let mut resp = JoinGameResponseSuccess::from_map(props)?;
                         debug!(response = format!("resp:?"), "Game Join Response");
-                        let mut hax = futures::executor::block_on(hax.lock());
+                        let expected = hax.expected_room.take();
                         let (_, state) = match &mut hax.gameplay_state {
                             Some(x) => x,
-                            _ => anyhow::bail!("gameplay state is None"),
+                            _ => return Err(HaxError::StateLock("gameplay state is None").into()),
                         };
 
+                        // joining another room over the same connection starts a new match
+                        let mut changed_actors = vec![];
+                        if state.match_tracker.is_finished() {
+                            state.match_tracker = MatchTracker::default();
+                            changed_actors.extend(state.players.keys().copied());
+                            state.players.clear();
+                            state.actor_history = ActorHistory::default();
+                            state.room_properties = RoomPropertyHistory::default();
+                        }
                         state.player_id = Some(resp.actor_nr);
-
+                        state.room_name = resp.room_name.clone();
+                        let resumed = state
+                            .migrated_from
+                            .take()
+                            .and_then(|from| from.continued_by(resp.room_name.as_deref()));
+                        if let Some(started) = resumed {
+                            state.match_tracker.continue_match(started);
+                        }
+                        state.round = RoundTracker::default();
+                        state.map_name = None;
+                        state.late_join.open(Instant::now());
+                        state.observe_room_properties(&resp.game_properties, Instant::now());
+                        let property_changes = state
+                            .room_properties
+                            .joined(&resp.game_properties, SystemTime::now());
+                        let master = host_migration::master_client_id(&resp.game_properties);
+                        state.room_mismatch = expected
+                            .filter(|e| resp.room_name.iter().all(|n| *n == e.room_id))
+                            .and_then(|e| e.check(&resp.game_properties, Instant::now()));
+                        let mismatch = state.room_mismatch.clone();
+
+                        let server_time = state.server_clock.server_now(Instant::now());
+                        let mut renames = vec![];
                         for (key, value) in &mut resp.player_properties {
                             let actor_id = match key {
                                 PhotonDataType::Integer(key) => *key,
@@ -395,43 +1591,235 @@ impl HaxState {
                             actor.merge_player(&player);
 
                             debug!(actor_id, "Found new actor");
+                            let before = state
+                                .players
+                                .get(&actor_id)
+                                .map(ActorFields::of)
+                                .unwrap_or_default();
+                            renames.extend(state.actor_history.observe(
+                                actor_id,
+                                &before,
+                                &actor,
+                                server_time,
+                                SystemTime::now(),
+                            ));
                             state.players.insert(actor_id, actor);
+                            changed_actors.push(actor_id);
                         }
+                        state.restore_own_platform();
+                        // actors are numbered in the order they joined, so the first one created the room
+                        state.room_creator = state.players.contains_key(&1).then_some(1);
 
                         tracing::info!(
                             players = format!("{:?}", state.players),
                             "Player info after join"
                         );
+                        let room_name = state.room_name.clone();
+                        let seen = state
+                            .players
+                            .values()
+                            .map(|p| (p.user_id.clone(), p.nickname.clone()))
+                            .collect::<Vec<_>>();
+                        for (user_id, nickname) in seen {
+                            hax.watch_player(
+                                user_id.as_deref(),
+                                nickname.as_deref(),
+                                room_name.as_deref(),
+                                WebSocketServer::GameServer,
+                            );
+                        }
+                        hax.journal.record_room_changed(changed_actors);
+                        if let Some(mismatch) = mismatch {
+                            warn!("Joined room differs from the lobby: {mismatch}");
+                            hax.events.emit(HaxEvent::RoomMismatch(mismatch));
+                        }
+                        let joined =
+                            hax.gameplay_state
+                                .as_ref()
+                                .map(|(_, state)| FeedMessage::RoomJoined {
+                                    room_name: state.room_name.clone(),
+                                    map_name: state.map_name.clone(),
+                                    actor_id: resp.actor_nr,
+                                    players: state
+                                        .players
+                                        .iter()
+                                        .map(|(actor_id, player)| FeedPlayer {
+                                            actor_id: *actor_id,
+                                            nickname: player.nickname.clone(),
+                                        })
+                                        .collect(),
+                                });
+                        if let Some(joined) = joined {
+                            hax.public_feed.record(joined, Instant::now());
+                        }
+                        if let Some(master) = master {
+                            hax.on_master_changed(master);
+                        }
+                        emit_renames(&hax.events, renames);
+                        emit_room_property_changes(
+                            &hax.events,
+                            &hax.room_property_settings,
+                            property_changes,
+                        );
+                        if let (Some(_), Some(room_name)) = (resumed, room_name) {
+                            info!(room_name, "Resumed the match after migrating");
+                            hax.events
+                                .emit(HaxEvent::MigratedMatchResumed { room_name });
+                        }
+                    }
+                    operation_code::SET_PROPERTIES => {
+                        let (_, state) = match &mut hax.gameplay_state {
+                            Some(x) => x,
+                            _ => return Err(HaxError::StateLock("gameplay state is None").into()),
+                        };
+                        if let PropertiesResponse::Rename { previous } =
+                            state.pending_properties.respond()
+                        {
+                            let accepted = operation_response.return_code == 0;
+                            if !accepted {
+                                warn!(
+                                    return_code = operation_response.return_code,
+                                    debug_message = operation_response.debug_message,
+                                    previous,
+                                    "The server rejected renaming the room"
+                                );
+                                state.room_title = previous;
+                            }
+                            return Ok(WebSocketHookAction::Drop(DropReason::RoomRename {
+                                accepted,
+                            }));
+                        }
+                    }
+                    operation_code::GET_PROPERTIES => {
+                        let (_, state) = match &mut hax.gameplay_state {
+                            Some(x) => x,
+                            _ => return Err(HaxError::StateLock("gameplay state is None").into()),
+                        };
+                        let game_properties = operation_response
+                            .parameters
+                            .get(&parameter_code::GAME_PROPERTIES)
+                            .filter(|_| operation_response.return_code == 0);
+                        if let Some(PhotonDataType::Hashtable(properties)) = game_properties {
+                            let changes =
+                                state
+                                    .room_properties
+                                    .observe(properties, None, SystemTime::now());
+                            emit_room_property_changes(
+                                &hax.events,
+                                &hax.room_property_settings,
+                                changes,
+                            );
+                        }
+                        let (actors, sent) = match state.pending_refreshes.respond() {
+                            RefreshResponse::Refresh { actors, sent } => (actors, sent),
+                            RefreshResponse::Forward => return Ok(WebSocketHookAction::DoNothing),
+                        };
+
+                        let accepted = operation_response.return_code == 0;
+                        if accepted {
+                            let response = GetPropertiesResponse::from_map(
+                                &mut operation_response.parameters,
+                            )?;
+                            let outcome = merge_refreshed_properties(hax, actors, sent, response);
+                            info!(
+                                missing = format!("{:?}", outcome.missing),
+                                "Refreshed actor properties: {outcome}"
+                            );
+                            hax.events.emit(HaxEvent::ActorPropertiesRefreshed(outcome));
+                        } else {
+                            let refusal = Refusal {
+                                return_code: operation_response.return_code,
+                                debug_message: operation_response.debug_message,
+                            };
+                            warn!("The server refused to send the actor properties: {refusal}");
+                            hax.stats
+                                .record_error(RefreshError::Refused(refusal.clone()));
+                            state.pending_refreshes.refused(refusal);
+                        }
+                        return Ok(WebSocketHookAction::Drop(DropReason::PropertyRefresh {
+                            accepted,
+                        }));
                     }
                     _ => (),
                 }
             }
             PhotonMessage::EventData(mut event) => match event.code {
                 event_code::JOIN => {
-                    let mut hax = futures::executor::block_on(hax.lock());
                     let (_, state) = match &mut hax.gameplay_state {
                         Some(x) => x,
-                        _ => anyhow::bail!("gameplay state is None"),
+                        _ => return Err(HaxError::StateLock("gameplay state is None").into()),
                     };
 
-                    let actor_nr = event.parameters.get_mut(&parameter_code::ACTOR_NR);
-                    if let Some(PhotonDataType::Integer(actor_nr)) = actor_nr {
-                        state.actor_nr = Some(*actor_nr);
+                    let parameters = Parameters(&event.parameters);
+                    if let Some(actor_nr) = parameters.actor_nr() {
+                        if !is_new_event(state, event.code, actor_nr, None) {
+                            return Ok(WebSocketHookAction::DoNothing);
+                        }
+                        state.actor_nr = Some(actor_nr);
                     }
 
-                    let actor_list = event.parameters.get_mut(&parameter_code::ACTOR_LIST);
-                    if let Some(PhotonDataType::Array(array)) = actor_list {
+                    let mut joined = vec![];
+                    if let Some(array) = parameters.actor_list() {
                         for id in array {
                             if let PhotonDataType::Integer(id) = id {
-                                state
-                                    .players
-                                    .entry(*id)
-                                    .or_insert_with(PlayerActor::default);
+                                if !state.players.contains_key(id) {
+                                    state.players.insert(*id, PlayerActor::default());
+                                    joined.push(*id);
+                                }
                             }
                         }
                     }
+                    hax.journal
+                        .record_actors(Section::Players, joined.iter().copied());
 
                     // PLAYER_PROPERTIES field is pretty useless, only contains empty string as nickname
+                    if let Some(PhotonDataType::Hashtable(props)) =
+                        event.parameters.get(&parameter_code::PLAYER_PROPERTIES)
+                    {
+                        let mut player = Player::from_map(&mut props.clone())?;
+                        // so it doesn't replace a nickname we already know
+                        if player.nickname.as_deref() == Some("") {
+                            player.nickname = None;
+                        }
+                        let mut rename = None;
+                        let joined = parameters
+                            .actor_nr()
+                            .and_then(|id| Some((id, state.players.get_mut(&id)?)));
+                        if let Some((actor_id, actor)) = joined {
+                            let before = ActorFields::of(actor);
+                            actor.merge_player(&player);
+                            rename = state.actor_history.observe(
+                                actor_id,
+                                &before,
+                                actor,
+                                state.server_clock.server_now(Instant::now()),
+                                SystemTime::now(),
+                            );
+                            hax.journal
+                                .record(Section::Players, ChangedKey::Actor(actor_id));
+                        }
+                        let room_name = state.room_name.clone();
+                        hax.watch_player(
+                            player.user_id.as_deref(),
+                            player.nickname.as_deref(),
+                            room_name.as_deref(),
+                            WebSocketServer::GameServer,
+                        );
+                        emit_renames(&hax.events, rename);
+                    }
+
+                    if let Some((_, state)) = &hax.gameplay_state {
+                        for actor_id in joined {
+                            let nickname = state
+                                .players
+                                .get(&actor_id)
+                                .and_then(|p| p.nickname.clone());
+                            hax.public_feed.record(
+                                FeedMessage::PlayerJoined { actor_id, nickname },
+                                Instant::now(),
+                            );
+                        }
+                    }
                 }
                 event_code::LEAVE => {
                     let event = LeaveEvent::from_map(&mut event.parameters)?;
@@ -443,13 +1831,27 @@ impl HaxState {
                         "Leave"
                     );
 
-                    let mut hax = futures::executor::block_on(hax.lock());
                     let (_, state) = match &mut hax.gameplay_state {
                         Some(x) => x,
-                        _ => anyhow::bail!("gameplay state is None"),
+                        _ => return Err(HaxError::StateLock("gameplay state is None").into()),
                     };
+                    if !is_new_event(state, event_code::LEAVE, sender, None) {
+                        return Ok(WebSocketHookAction::DoNothing);
+                    }
 
-                    state.players.remove(&sender);
+                    if let Some(player) = state.players.remove(&sender) {
+                        state.match_tracker.record_left_player(sender, &player);
+                        hax.public_feed
+                            .record(FeedMessage::PlayerLeft { actor_id: sender }, Instant::now());
+                        hax.journal
+                            .record(Section::Players, ChangedKey::Actor(sender));
+                        hax.journal
+                            .record(Section::Scoreboard, ChangedKey::Actor(sender));
+                    }
+                    state.detector.remove(sender);
+                    if let Some(master) = event.master_client_id {
+                        hax.on_master_changed(master);
+                    }
                 }
                 event_code::PROPERTIES_CHANGED => {
                     let mut event = PropertiesChangedEvent::from_map(&mut event.parameters)?;
@@ -462,10 +1864,9 @@ impl HaxState {
                     );
 
                     if event.target_actor_number != 0 {
-                        let mut hax = futures::executor::block_on(hax.lock());
                         let (_, state) = match &mut hax.gameplay_state {
                             Some(x) => x,
-                            _ => anyhow::bail!("gameplay state is None"),
+                            _ => return Err(HaxError::StateLock("gameplay state is None").into()),
                         };
 
                         let player = state
@@ -475,7 +1876,51 @@ impl HaxState {
 
                         let player_props = Player::from_map(&mut event.properties)?;
 
+                        let before = ActorFields::of(player);
                         player.merge_player(&player_props);
+                        let rename = state.actor_history.observe(
+                            event.target_actor_number,
+                            &before,
+                            player,
+                            state.server_clock.server_now(Instant::now()),
+                            SystemTime::now(),
+                        );
+                        let (user_id, nickname) = (player.user_id.clone(), player.nickname.clone());
+                        state.restore_own_platform();
+                        let room_name = state.room_name.clone();
+                        hax.journal.record(
+                            Section::Players,
+                            ChangedKey::Actor(event.target_actor_number),
+                        );
+                        hax.watch_player(
+                            user_id.as_deref(),
+                            nickname.as_deref(),
+                            room_name.as_deref(),
+                            WebSocketServer::GameServer,
+                        );
+                        emit_renames(&hax.events, rename);
+                    } else {
+                        if let Some((_, state)) = &mut hax.gameplay_state {
+                            let map_name = state.map_name.clone();
+                            state.observe_room_properties(&event.properties, Instant::now());
+                            let changes = state.room_properties.observe(
+                                &event.properties,
+                                event.sender_actor,
+                                SystemTime::now(),
+                            );
+                            emit_room_property_changes(
+                                &hax.events,
+                                &hax.room_property_settings,
+                                changes,
+                            );
+                            if state.map_name != map_name {
+                                hax.journal
+                                    .record(Section::Room, ChangedKey::Field(room_field::MAP_NAME));
+                            }
+                        }
+                        if let Some(master) = host_migration::master_client_id(&event.properties) {
+                            hax.on_master_changed(master);
+                        }
                     }
                 }
                 // NOTE: this only destroys the game object
@@ -489,6 +1934,13 @@ impl HaxState {
                         direction = "client",
                         "Destroy"
                     );
+
+                    let (_, state) = match &mut hax.gameplay_state {
+                        Some(x) => x,
+                        _ => return Err(HaxError::StateLock("gameplay state is None").into()),
+                    };
+
+                    state.projectiles.on_destroy(event_data.get_view_id());
                 }
                 pun_event_code::INSTANTIATION => {
                     let mut event = InstantiationEvent::from_map(&mut event.parameters)?;
@@ -501,32 +1953,106 @@ impl HaxState {
                         "Instantiation"
                     );
 
-                    let hax = futures::executor::block_on(hax.lock());
-                    merge_instantiation(hax, sender, &event_data)?;
+                    if let Some((_, state)) = &mut hax.gameplay_state {
+                        let (id, server_time) =
+                            (event_data.instantiation_id, event_data.server_time);
+                        if !is_new_event(
+                            state,
+                            pun_event_code::INSTANTIATION,
+                            id,
+                            Some(server_time),
+                        ) {
+                            return Ok(WebSocketHookAction::DoNothing);
+                        }
+                    }
+                    merge_instantiation(hax, sender, &event_data, historical)?;
                 }
                 pun_event_code::SEND_SERIALIZE | pun_event_code::SEND_SERIALIZE_RELIABLE => {
                     let event = SendSerializeEvent::from_map(&mut event.parameters)?;
                     let serialized_data = event
                         .get_serialized_data()
                         .ok_or_else(|| anyhow::anyhow!("SendSerialize data error"))?;
+                    let server_timestamp = event.get_server_timestamp();
 
-                    let mut hax = futures::executor::block_on(hax.lock());
+                    let extrapolation = hax.extrapolation.clone();
+                    let link_quality = hax.link_quality.clone();
+                    let detection = hax.detection.clone();
+                    let now = Instant::now();
                     let (_, state) = match &mut hax.gameplay_state {
                         Some(x) => x,
-                        _ => anyhow::bail!("gameplay state is None"),
+                        _ => return Err(HaxError::StateLock("gameplay state is None").into()),
                     };
+                    if let Some(server_timestamp) = server_timestamp {
+                        state.server_clock.observe(server_timestamp, Instant::now());
+                    }
+                    let layout = hax.stream_layouts.for_profile(&hax.protocol_profile.name);
 
                     for obj in serialized_data {
+                        if state.projectiles.on_serialize(&obj, Instant::now()) {
+                            continue;
+                        }
+
                         let actor_id = obj.get_view_id().get_owner_id();
                         if let Some(actor) = state.players.get_mut(&actor_id) {
-                            let player_script = PlayerScript::from_object_array(&obj.data_stream)?;
+                            let player_script = PlayerScript::decode(&obj.data_stream, layout)?;
                             trace!(
                                 actor_id,
                                 player_script = format!("{player_script:?}"),
                                 "SendSerialize for actor"
                             );
 
-                            actor.merge_player_script(&player_script);
+                            let score = (actor.kills, actor.deaths);
+                            let death = actor.merge_player_script(&player_script);
+                            if (actor.kills, actor.deaths) != score {
+                                hax.journal
+                                    .record(Section::Scoreboard, ChangedKey::Actor(actor_id));
+                            }
+                            if let Some(death) = death {
+                                let killer = death.killer;
+                                hax.public_feed.record(
+                                    FeedMessage::Kill {
+                                        victim: actor_id,
+                                        killer,
+                                    },
+                                    now,
+                                );
+                                let entry = state.kill_feed.record(
+                                    actor_id,
+                                    death,
+                                    state.map_name.as_deref(),
+                                    &hax.map_annotations,
+                                    SystemTime::now(),
+                                );
+                                debug!("Kill feed: {entry}");
+                            }
+                            if let Some(server_timestamp) = server_timestamp {
+                                actor.record_position(server_timestamp, &extrapolation);
+                            }
+                            actor.link.record(now, &link_quality);
+
+                            let health = player_script.health as f32 / 100.0;
+                            let detections = [
+                                server_timestamp.and_then(|t| {
+                                    state.detector.observe_movement(
+                                        actor_id,
+                                        t,
+                                        &player_script.position,
+                                        health,
+                                        now,
+                                        &detection,
+                                    )
+                                }),
+                                state.detector.observe_health(
+                                    actor_id,
+                                    health,
+                                    player_script.last_damager_id,
+                                    now,
+                                    &detection,
+                                ),
+                            ];
+                            for d in detections.into_iter().flatten() {
+                                emit_detection(&hax.events, d);
+                            }
                         }
                         trace!(
                             direction = "client",
@@ -535,6 +2061,12 @@ impl HaxState {
                             "SendSerialize"
                         );
                     }
+
+                    for actor_id in state.update_link_quality(now, &link_quality) {
+                        debug!(actor_id, "Player is timing out");
+                        hax.events.emit(HaxEvent::PlayerTimingOut { actor_id });
+                    }
+                    update_client_health(state, &hax.events, now, &hax.client_health);
                 }
                 pun_event_code::RPC => {
                     let mut event = RpcEvent::from_map(&mut event.parameters)?;
@@ -557,9 +2089,79 @@ impl HaxState {
                         direction = "client",
                         "RPC call"
                     );
+
+                    {
+                        // the view owner is not always the one calling the RPC
+                        let caller = event.sender_actor.unwrap_or(sender);
+                        hax.rpc_usage.record(
+                            Direction::ServerToClient,
+                            caller,
+                            &data,
+                            SystemTime::now(),
+                        );
+                        // a burst of buffered RPCs would look like an impossible fire rate
+                        if let Some((_, state)) =
+                            hax.gameplay_state.as_mut().filter(|_| !historical)
+                        {
+                            let detection = state.detector.observe_rpc(
+                                caller,
+                                &method_name,
+                                Instant::now(),
+                                &hax.detection,
+                            );
+                            if let Some(d) = detection {
+                                emit_detection(&hax.events, d);
+                            }
+                        }
+                    }
+
+                    // PUN sends a single RPC per event, so muting means dropping the whole event
+                    if is_cosmetic_method(&method_name)
+                        && (settings.mute_all_cosmetic || settings.muted_actors.contains(&sender))
+                    {
+                        hax.stats.record_muted_rpc(sender);
+                        trace!(
+                            method_name = method_name.to_string(),
+                            sender,
+                            "Dropping muted RPC"
+                        );
+                        return Ok(WebSocketHookAction::Drop(DropReason::MutedRpc {
+                            sender,
+                            method_name: method_name.to_string(),
+                        }));
+                    }
                 }
                 _ => (),
             },
+            PhotonMessage::InternalOperationRequest(request)
+                if request.operation_code == PING_OPERATION_CODE =>
+            {
+                if let Some(PhotonDataType::Integer(time)) =
+                    request.parameters.get(&PING_CLIENT_TIME_PARAMETER)
+                {
+                    if let Some((_, state)) = &mut hax.gameplay_state {
+                        state.latency.observe_ping(*time, Instant::now());
+                    }
+                }
+            }
+            PhotonMessage::InternalOperationResponse(response)
+                if response.operation_code == PING_OPERATION_CODE =>
+            {
+                if let Some(PhotonDataType::Integer(time)) =
+                    response.parameters.get(&PING_CLIENT_TIME_PARAMETER)
+                {
+                    if let Some((_, state)) = &mut hax.gameplay_state {
+                        state.latency.observe_answer(*time, Instant::now());
+                    }
+                }
+            }
+            PhotonMessage::PingResult(result) => {
+                if let Some((_, state)) = &mut hax.gameplay_state {
+                    state
+                        .latency
+                        .observe_answer(result.client_sent_time(), Instant::now());
+                }
+            }
             // unhandled
             _ => (),
         }
@@ -568,20 +2170,197 @@ impl HaxState {
     }
 }
 
+fn emit_detection(events: &EventBus, detection: Detection) {
+    info!(
+        actor_id = detection.actor_id,
+        score = detection.score,
+        heuristic = format!("{}", detection.heuristic),
+        "Player is suspected of cheating"
+    );
+    events.emit(HaxEvent::SuspectedCheater {
+        actor_id: detection.actor_id,
+        score: detection.score,
+        heuristic: detection.heuristic,
+    });
+}
+
+fn emit_breaker_transition(
+    events: &EventBus,
+    server: WebSocketServer,
+    transition: BreakerTransition,
+) {
+    match transition {
+        BreakerTransition::Tripped { failure_rate } => {
+            warn!(
+                server = format!("{server}"),
+                failure_rate,
+                "Most messages can't be parsed anymore, the game probably updated. Forwarding messages without \
+                 handling them until they parse again, features relying on this connection are paused and its \
+                 state is stale"
+            );
+            events.emit(HaxEvent::ParsePassthroughStarted {
+                server,
+                failure_rate,
+            });
+        }
+        BreakerTransition::Recovered { skipped } => {
+            info!(
+                server = format!("{server}"),
+                skipped, "Messages parse again, handling them again"
+            );
+            events.emit(HaxEvent::ParsePassthroughEnded { server, skipped });
+        }
+    }
+}
+
+/// Whether a rewritten message parses back to itself. Values of unknown types are only read if nothing follows them,
+/// so a rewrite that adds something after one would make the rest of the message unreadable.
+fn reads_back(bytes: &[u8], message: &PhotonMessage) -> bool {
+    PhotonMessage::from_websocket_bytes_with_mode(&mut &*bytes, ParseMode::Lenient)
+        .is_ok_and(|parsed| &parsed == message)
+}
+
+/// Whether a state-changing event from the server wasn't already processed. Duplicates are still forwarded.
+fn is_new_event(
+    state: &mut GameplayState,
+    code: u8,
+    id: i32,
+    server_timestamp: Option<i32>,
+) -> bool {
+    let key = match EventKey::new(code, id, server_timestamp) {
+        Some(key) => key,
+        None => return true,
+    };
+    let is_new = state.event_dedup.is_new(key, Instant::now());
+    if !is_new {
+        debug!(code, id, server_timestamp, "Skipping duplicate event");
+    }
+    is_new
+}
+
+/// Classifies the client's own cadence and reports it if that changed, see [client_health](super::client_health).
+fn update_client_health(
+    state: &mut GameplayState,
+    events: &EventBus,
+    now: Instant,
+    settings: &ClientHealthSettings,
+) {
+    if let Some((previous, current)) = state.update_client_health(now, settings) {
+        info!(
+            previous = format!("{previous}"),
+            current = format!("{current}"),
+            "Game client cadence changed"
+        );
+        events.emit(HaxEvent::ClientHealthChanged { previous, current });
+    }
+}
+
+/// Merges the properties sent in answer to a refresh of the given actors into the players. Players we didn't know of
+/// are added, as the server only sends players that are in the room.
+fn merge_refreshed_properties(
+    hax: &mut HaxState,
+    actors: Vec<i32>,
+    sent: Instant,
+    mut response: GetPropertiesResponse,
+) -> RefreshOutcome {
+    let now = Instant::now();
+    let mut updated = vec![];
+    let mut renames = vec![];
+    let mut watched = vec![];
+    if let Some((_, state)) = &mut hax.gameplay_state {
+        for (actor_id, properties) in response.player_properties.iter_mut().flatten() {
+            let (actor_id, properties) = match (actor_id, properties) {
+                (PhotonDataType::Integer(actor_id), PhotonDataType::Hashtable(properties)) => {
+                    (*actor_id, properties)
+                }
+                _ => continue,
+            };
+            let properties = match Player::from_map(properties) {
+                Ok(properties) => properties,
+                Err(e) => {
+                    warn!(actor_id, "Could not read refreshed actor properties: {e}");
+                    continue;
+                }
+            };
+
+            let player = state.players.entry(actor_id).or_default();
+            let before = ActorFields::of(player);
+            player.merge_player(&properties);
+            player.properties_refreshed = Some(now);
+            renames.extend(state.actor_history.observe(
+                actor_id,
+                &before,
+                player,
+                state.server_clock.server_now(now),
+                SystemTime::now(),
+            ));
+            watched.push((player.user_id.clone(), player.nickname.clone()));
+            updated.push(actor_id);
+        }
+        state.restore_own_platform();
+
+        let room_name = state.room_name.clone();
+        for actor_id in &updated {
+            hax.journal
+                .record(Section::Players, ChangedKey::Actor(*actor_id));
+        }
+        for (user_id, nickname) in watched {
+            hax.watch_player(
+                user_id.as_deref(),
+                nickname.as_deref(),
+                room_name.as_deref(),
+                WebSocketServer::GameServer,
+            );
+        }
+        emit_renames(&hax.events, renames);
+    }
+
+    RefreshOutcome {
+        missing: actors
+            .into_iter()
+            .filter(|actor_id| !updated.contains(actor_id))
+            .collect(),
+        updated,
+        round_trip: now.saturating_duration_since(sent),
+    }
+}
+
+fn emit_renames(events: &EventBus, renames: impl IntoIterator<Item = Rename>) {
+    for Rename { actor_id, old, new } in renames {
+        info!(actor_id, old, new, "Player renamed");
+        events.emit(HaxEvent::PlayerRenamed { actor_id, old, new });
+    }
+}
+
 fn merge_instantiation(
-    mut hax: impl DerefMut<Target = HaxState>,
+    hax: &mut HaxState,
     sender: i32,
     event_data: &InstantiationEventData,
-) -> anyhow::Result<()> {
+    historical: bool,
+) -> Result<(), HaxError> {
+    let projectile_settings = hax.projectiles.clone();
     let (_, state) = match &mut hax.gameplay_state {
         Some(x) => x,
-        _ => anyhow::bail!("gameplay state is None"),
+        _ => return Err(HaxError::StateLock("gameplay state is None")),
     };
 
+    // cached projectiles were thrown before we joined, and are most likely gone
+    if historical && projectile_settings.is_projectile(&event_data.prefab_name) {
+        return Ok(());
+    }
+    if state
+        .projectiles
+        .on_instantiation(&projectile_settings, event_data, Instant::now())
+    {
+        return Ok(());
+    }
+
     match event_data.prefab_name.as_ref() {
         "PlayerBody" => {
             let x = state.players.entry(sender).or_default();
             x.merge_instantiation_data(event_data);
+            hax.journal
+                .record(Section::Players, ChangedKey::Actor(sender));
         }
         "Match Manager" => {
             state.match_manager_view_id = Some(event_data.instantiation_id);
@@ -592,66 +2371,853 @@ fn merge_instantiation(
     Ok(())
 }
 
-fn strip_password(room_info: &mut RoomInfo) {
-    let has_password = match room_info.custom_properties.get("password") {
-        Some(PhotonDataType::String(s)) => !s.is_empty(),
-        _ => false,
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Barrier,
+        },
+        time::Duration,
     };
 
-    if has_password {
-        if let Some(PhotonDataType::String(name)) = room_info.custom_properties.get_mut("roomName")
-        {
-            *name = format!("[p] {name}");
+    use futures_util::lock::Mutex;
+    use photon_lib::{
+        highlevel::{
+            constants::{operation_code, parameter_code, pun_event_code},
+            structs::{RaiseEvent, RoomInfoList, RoomInfoView, RpcCall},
+            PhotonMapConversion, PhotonParameterMapConversion,
+        },
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationRequest, OperationResponse, PhotonMessage},
+        ParseMode,
+    };
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::reads_back;
+    use crate::{
+        error::HaxError,
+        hax::{
+            bandwidth::feature,
+            drop_log::DropReason,
+            dry_run::DryRunSettings,
+            events::HaxEvent,
+            parse_breaker::ParseBreakerSettings,
+            settings::{Settings, SharedSettings},
+            GlobalState, HaxState, VersionInfo,
+        },
+        proxy::{websocket_proxy::WebSocketProxy, Direction, WebSocketServer},
+        testgen::GameListBuilder,
+    };
+
+    /// An operation code the game doesn't use, requests with it make [match_packet_panicking] panic.
+    const PANICKING_OPERATION: u8 = 0xEE;
+
+    /// Stands in for a buggy handler.
+    pub(super) fn match_packet_panicking(photon_message: &PhotonMessage) {
+        if let PhotonMessage::OperationRequest(request) = photon_message {
+            if request.operation_code == PANICKING_OPERATION {
+                panic!("deliberate panic for operation {}", request.operation_code);
+            }
         }
+    }
 
-        if let Some(PhotonDataType::String(password)) =
-            room_info.custom_properties.get_mut("password")
-        {
-            *password = "".to_string();
+    /// An event code the game doesn't use, the hook pauses after parsing events with it, see [pause_after_parsing].
+    const PAUSING_EVENT: u8 = 0xEE;
+    static PAUSED: Barrier = Barrier::new(2);
+    static RESUMED: Barrier = Barrier::new(2);
+
+    /// Lets a test look at the state between [PAUSED] and [RESUMED], while the hook is done parsing a message.
+    pub(super) fn pause_after_parsing(photon_message: &PhotonMessage) {
+        if let PhotonMessage::EventData(event) = photon_message {
+            if event.code == PAUSING_EVENT {
+                PAUSED.wait();
+                RESUMED.wait();
+            }
         }
-    };
-}
+    }
+
+    fn event_with_code(code: u8) -> Vec<u8> {
+        let mut bytes = vec![];
+        PhotonMessage::EventData(EventData {
+            code,
+            parameters: indexmap! {},
+        })
+        .to_websocket_bytes(&mut bytes)
+        .unwrap();
+        bytes
+    }
 
-fn force_games_web(room_info: &mut RoomInfo) {
-    let store_id = room_info.custom_properties.get("storeID").cloned();
+    fn operation_request(operation_code: u8) -> Vec<u8> {
+        let mut bytes = vec![];
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code,
+            parameters: indexmap! {},
+        })
+        .to_websocket_bytes(&mut bytes)
+        .unwrap();
+        bytes
+    }
 
-    // adjust name if not web
-    if let Some(PhotonDataType::String(name)) = room_info.custom_properties.get_mut("roomName") {
-        if let Some(PhotonDataType::String(store_id)) = store_id {
-            *name = match store_id.as_str() {
-                "BALYZE_WEB" => name.to_string(),
-                "BALYZE_MOBILE" => format!("[M] {name}"),
-                v => format!("[{v}] {name}"),
+    #[test]
+    fn handler_panic_is_isolated() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let original = operation_request(PANICKING_OPERATION);
+        let mut data = original.clone();
+
+        let result = HaxState::websocket_hook(
+            state.clone(),
+            &mut data,
+            WebSocketServer::GameServer,
+            Direction::ClientToServer,
+        );
+        match result {
+            Err(HaxError::HandlerFailed {
+                code,
+                direction,
+                source,
+            }) => {
+                assert_eq!(code, Some(PANICKING_OPERATION));
+                assert_eq!(direction, Direction::ClientToServer);
+                assert_eq!(
+                    source.to_string(),
+                    "handler panicked: deliberate panic for operation 238"
+                );
             }
+            other => panic!("expected a failed handler, got {other:?}"),
         }
+        // the original message is left for the proxy to forward
+        assert_eq!(data, original);
+        assert_eq!(
+            futures::executor::block_on(state.lock())
+                .stats
+                .handler_panics,
+            1
+        );
+
+        // the state is still usable for the next message
+        let mut data = operation_request(PANICKING_OPERATION - 1);
+        let forward = HaxState::websocket_hook(
+            state.clone(),
+            &mut data,
+            WebSocketServer::GameServer,
+            Direction::ClientToServer,
+        )
+        .unwrap();
+        assert!(forward);
     }
 
-    // force game to web so it shows up in the list
-    if let Some(PhotonDataType::String(x)) = room_info.custom_properties.get_mut("storeID") {
-        *x = "BALYZE_WEB".into();
+    #[test]
+    fn unknown_types_are_passed_through() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        // an operation request whose last parameter has the unknown type 0x99
+        let mut original = vec![];
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: 42,
+            parameters: indexmap! {
+                0x01 => PhotonDataType::Integer(5),
+                0xf5 => PhotonDataType::Unknown { type_code: 0x99, raw_bytes: vec![0x0a, 0x0b, 0x0c] },
+            },
+        })
+        .to_websocket_bytes(&mut original)
+        .unwrap();
+        let mut data = original.clone();
+        let forward = HaxState::websocket_hook(
+            state.clone(),
+            &mut data,
+            WebSocketServer::GameServer,
+            Direction::ClientToServer,
+        )
+        .unwrap();
+        assert!(forward);
+        assert_eq!(data, original);
+        assert_eq!(
+            futures::executor::block_on(state.lock())
+                .stats
+                .unknown_values,
+            1
+        );
+
+        // unknown values can be kept through a rewrite, as long as nothing ends up after them
+        let mut message = PhotonMessage::from_websocket_bytes_with_mode(
+            &mut original.as_slice(),
+            ParseMode::Lenient,
+        )
+        .unwrap();
+        let reserialize = |message: &PhotonMessage| {
+            let mut bytes = vec![];
+            message.to_websocket_bytes(&mut bytes).unwrap();
+            bytes
+        };
+        if let PhotonMessage::OperationRequest(request) = &mut message {
+            request.parameters[0] = PhotonDataType::Integer(6);
+        }
+        assert!(reads_back(&reserialize(&message), &message));
+        if let PhotonMessage::OperationRequest(request) = &mut message {
+            request.parameters.insert(0x02, PhotonDataType::Byte(1));
+        }
+        assert!(!reads_back(&reserialize(&message), &message));
     }
-}
 
-/// Forces all games to the current version so they appear in the lobby list.
-///
-/// Note that this only handle BulletForce games which use `gameVersion` as key, the "newgame" game uses `gameversion`
-/// (no uppercase 'v') which we dont match. This is intended.
-fn force_games_current_ver(room_info: &mut RoomInfo, target_version: &str) {
-    let actual_version = match room_info.custom_properties.get("gameVersion").cloned() {
-        Some(PhotonDataType::String(version)) => version,
-        _ => return,
-    };
+    #[test]
+    fn passthrough_after_parse_failures() {
+        let hax = HaxState {
+            parse_breaker_settings: ParseBreakerSettings {
+                window: 9,
+                min_samples: 9,
+                probe_interval: Duration::ZERO,
+                probe_successes: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut events = hax.events.subscribe();
+        let state = Arc::new(Mutex::new(hax));
+        let hook = |bytes: &[u8]| {
+            let mut data = bytes.to_vec();
+            HaxState::websocket_hook(
+                state.clone(),
+                &mut data,
+                WebSocketServer::GameServer,
+                Direction::ClientToServer,
+            )
+        };
+        let valid = operation_request(PANICKING_OPERATION - 1);
+        let garbage = [0x00, 0x01, 0x02, 0x03];
+
+        // two out of three messages are garbage
+        for i in 0..8 {
+            match i % 3 {
+                0 => assert!(hook(&valid).unwrap()),
+                _ => assert!(matches!(
+                    hook(&garbage),
+                    Err(HaxError::ProtocolParse { .. })
+                )),
+            }
+        }
+        assert!(events.try_recv().is_err());
+        // the ninth message fills the window
+        assert!(matches!(
+            hook(&garbage),
+            Err(HaxError::ProtocolParse { .. })
+        ));
+        match events.try_recv() {
+            Ok(HaxEvent::ParsePassthroughStarted {
+                server: WebSocketServer::GameServer,
+                failure_rate,
+            }) => assert!(failure_rate > 0.6),
+            other => panic!("expected passthrough to start, got {other:?}"),
+        }
 
-    if actual_version != target_version {
-        if let Some(PhotonDataType::String(name)) = room_info.custom_properties.get_mut("roomName")
+        // in passthrough nothing errors and handlers don't run
+        assert!(hook(&garbage).unwrap());
+        assert!(hook(&operation_request(PANICKING_OPERATION)).unwrap());
+        assert!(hook(&garbage).unwrap());
+        assert!(hook(&valid).unwrap());
         {
-            *name = format!("[{actual_version}] {name}");
+            let hax = futures::executor::block_on(state.lock());
+            assert_eq!(hax.stats.handler_panics, 0);
+            let breaker = hax.parse_breaker.connection(WebSocketServer::GameServer);
+            assert!(breaker.is_passthrough() && breaker.is_stale());
+            assert!(!hax
+                .parse_breaker
+                .connection(WebSocketServer::LobbyServer)
+                .is_stale());
+        }
+        assert!(events.try_recv().is_err());
+
+        // two successful probes in a row end it
+        assert!(hook(&valid).unwrap());
+        assert!(matches!(
+            events.try_recv(),
+            Ok(HaxEvent::ParsePassthroughEnded {
+                server: WebSocketServer::GameServer,
+                skipped: 0,
+            })
+        ));
+        assert!(matches!(
+            hook(&operation_request(PANICKING_OPERATION)),
+            Err(HaxError::HandlerFailed { .. })
+        ));
+        let hax = futures::executor::block_on(state.lock());
+        let breaker = hax.parse_breaker.connection(WebSocketServer::GameServer);
+        assert!(!breaker.is_passthrough() && breaker.is_stale());
+    }
+
+    #[test]
+    fn garbage_is_a_parse_error_at_its_offset() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        // an operation request whose only parameter is an integer cut off after its first byte
+        let mut data = vec![0xf3, 0x02, 42, 0x00, 0x01, 0x01, b'i', 0x00];
+        match HaxState::websocket_hook(
+            state,
+            &mut data,
+            WebSocketServer::GameServer,
+            Direction::ClientToServer,
+        ) {
+            Err(HaxError::ProtocolParse { len, offset, .. }) => {
+                assert_eq!(len, 8);
+                assert_eq!(offset, 7);
+            }
+            other => panic!("expected a parse error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn typed_handler_errors_are_passed_through() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        // the join response of a game server connection the state doesn't know about
+        let mut data = response_bytes(
+            operation_code::JOIN_GAME,
+            indexmap! {
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+            },
+        );
+        let result = HaxState::websocket_hook(
+            state.clone(),
+            &mut data,
+            WebSocketServer::GameServer,
+            Direction::ServerToClient,
+        );
+        assert!(
+            matches!(result, Err(HaxError::StateLock("gameplay state is None"))),
+            "{result:?}"
+        );
+        // only handlers that failed with an error of their own are shown as failed
+        assert!(futures::executor::block_on(state.lock())
+            .stats
+            .last_failed_message
+            .is_none());
+    }
+
+    #[test]
+    fn rewrites_generated_game_list() {
+        let state = Arc::new(Mutex::new(HaxState {
+            settings: SharedSettings::new(Settings {
+                show_mobile_games: true,
+                show_other_versions: true,
+                strip_passwords: true,
+                ..Default::default()
+            }),
+            global_state: GlobalState {
+                version: VersionInfo::parse("1.90.0_1.99"),
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let builder = GameListBuilder::new(100).with_seed(3);
+        let mut data = builder.to_websocket_bytes();
+        let forward = HaxState::websocket_hook(
+            state,
+            &mut data,
+            WebSocketServer::LobbyServer,
+            Direction::ServerToClient,
+        )
+        .unwrap();
+        assert!(forward);
+
+        let mut event = match PhotonMessage::from_websocket_bytes(&mut data.as_slice()).unwrap() {
+            PhotonMessage::EventData(event) => event,
+            other => panic!("expected the game list, got {other:?}"),
+        };
+        let games = RoomInfoList::from_map(&mut event.parameters).unwrap().games;
+        let original = builder.games();
+        assert_eq!(games.len(), original.len());
+        for (id, room) in &games {
+            let (room, original) = match (room, &original[id]) {
+                (PhotonDataType::Hashtable(room), PhotonDataType::Hashtable(original)) => {
+                    (RoomInfoView(room), RoomInfoView(original))
+                }
+                other => panic!("expected rooms, got {other:?}"),
+            };
+            let name = room.custom_str("roomName").unwrap();
+            assert_eq!(room.custom_str("gameVersion"), Some("1.90.0"));
+            assert_eq!(room.custom_str("password"), Some(""));
+            if original.custom_str("storeID") != Some("BALYZE_WEB") {
+                assert!(
+                    name.contains("[M] ") || name.contains("[BALYZE_IOS] "),
+                    "{name}"
+                );
+            }
+            if original
+                .custom_str("password")
+                .is_some_and(|p| !p.is_empty())
+            {
+                assert!(name.starts_with("[p] "), "{name}");
+            }
+            assert!(name.ends_with(original.custom_str("roomName").unwrap()));
+        }
+    }
+
+    #[test]
+    fn settings_changes_dont_block_the_hook() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let settings = futures::executor::block_on(state.lock()).shared_settings();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        // stands in for the UI, which reads the settings every frame and changes them now and then
+        let ui = std::thread::spawn({
+            let (settings, stop) = (settings.clone(), stop.clone());
+            move || {
+                let mut toggles = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    let snapshot = settings.load();
+                    settings.update(|s| s.strip_passwords = !s.strip_passwords);
+                    assert_eq!(settings.load().strip_passwords, !snapshot.strip_passwords);
+                    toggles += 1;
+                }
+                toggles
+            }
+        });
+
+        let builder = GameListBuilder::new(50).with_seed(5);
+        let locked = builder
+            .games()
+            .values()
+            .filter(|room| match room {
+                PhotonDataType::Hashtable(room) => RoomInfoView(room)
+                    .custom_str("password")
+                    .is_some_and(|p| !p.is_empty()),
+                _ => false,
+            })
+            .count();
+        assert!(locked > 0);
+        for _ in 0..20 {
+            let mut data = builder.to_websocket_bytes();
+            HaxState::websocket_hook(
+                state.clone(),
+                &mut data,
+                WebSocketServer::LobbyServer,
+                Direction::ServerToClient,
+            )
+            .unwrap();
+
+            // the whole list was handled with one snapshot, so either all passwords were stripped or none
+            let mut event = match PhotonMessage::from_websocket_bytes(&mut data.as_slice()).unwrap()
+            {
+                PhotonMessage::EventData(event) => event,
+                other => panic!("expected the game list, got {other:?}"),
+            };
+            let still_locked = RoomInfoList::from_map(&mut event.parameters)
+                .unwrap()
+                .games
+                .values()
+                .filter(|room| match room {
+                    PhotonDataType::Hashtable(room) => RoomInfoView(room)
+                        .custom_str("password")
+                        .is_some_and(|p| !p.is_empty()),
+                    _ => false,
+                })
+                .count();
+            assert!(
+                still_locked == 0 || still_locked == locked,
+                "{still_locked}"
+            );
+        }
+
+        stop.store(true, Ordering::Relaxed);
+        assert!(ui.join().unwrap() > 0);
+    }
+
+    #[test]
+    fn ui_reads_dont_wait_on_parsing() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let hook = std::thread::spawn({
+            let state = state.clone();
+            move || {
+                let mut data = event_with_code(PAUSING_EVENT);
+                HaxState::websocket_hook(
+                    state,
+                    &mut data,
+                    WebSocketServer::GameServer,
+                    Direction::ServerToClient,
+                )
+                .unwrap()
+            }
+        });
+
+        // stands in for the UI, which reads the state while the hook is busy with a message
+        PAUSED.wait();
+        let read = state
+            .try_lock()
+            .map(|hax| hax.stats.messages_server_to_client);
+        RESUMED.wait();
+        assert!(hook.join().unwrap());
+        assert_eq!(read, Some(1), "the hook held the state while parsing");
+    }
+
+    fn cosmetic_rpc_event(sender: i32) -> Vec<u8> {
+        rpc_event(sender, "ColorRpc")
+    }
+
+    fn rpc_event(sender: i32, method_name: &str) -> Vec<u8> {
+        let mut call = indexmap! {};
+        RpcCall {
+            net_view_id: sender * 1000 + 1,
+            other_side_prefix: None,
+            server_timestamp: None,
+            method_name: Some(method_name.into()),
+            in_method_parameters: None,
+            rpc_index: None,
+            custom_properties: indexmap! {},
+        }
+        .into_map(&mut call);
+        let mut bytes = vec![];
+        PhotonMessage::EventData(EventData {
+            code: pun_event_code::RPC,
+            parameters: indexmap! {
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(sender),
+                parameter_code::CUSTOM_EVENT_CONTENT => PhotonDataType::Hashtable(call),
+            },
+        })
+        .to_websocket_bytes(&mut bytes)
+        .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn cosmetic_rpcs_of_muted_actors_are_dropped() {
+        let state = Arc::new(Mutex::new(HaxState {
+            settings: SharedSettings::new(Settings {
+                muted_actors: [3].into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }));
+        let hook = |original: Vec<u8>| {
+            let mut data = original.clone();
+            let forward = HaxState::websocket_hook(
+                state.clone(),
+                &mut data,
+                WebSocketServer::GameServer,
+                Direction::ServerToClient,
+            )
+            .unwrap();
+            assert_eq!(data, original);
+            forward
+        };
+
+        assert!(!hook(cosmetic_rpc_event(3)));
+        // other actors and RPCs that do something are left alone
+        assert!(hook(cosmetic_rpc_event(4)));
+        assert!(hook(rpc_event(3, "RpcSendChatMessage")));
+
+        let hax = futures::executor::block_on(state.lock());
+        let dropped = hax.drop_log.entries().collect::<Vec<_>>();
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].code, Some(pun_event_code::RPC));
+        assert!(
+            matches!(
+                &dropped[0].reason,
+                DropReason::MutedRpc { sender: 3, method_name } if method_name == "ColorRpc"
+            ),
+            "{:?}",
+            dropped[0].reason
+        );
+        assert_eq!(hax.stats.muted_rpcs, indexmap! { 3 => 1 });
+    }
+
+    #[test]
+    fn dry_run_forwards_original_bytes() {
+        let state = Arc::new(Mutex::new(HaxState {
+            settings: SharedSettings::new(Settings {
+                show_mobile_games: true,
+                show_other_versions: true,
+                strip_passwords: true,
+                mute_all_cosmetic: true,
+                dry_run: DryRunSettings {
+                    enabled: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            global_state: GlobalState {
+                version: VersionInfo::parse("1.90.0_1.99"),
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let capture = [
+            (
+                WebSocketServer::LobbyServer,
+                GameListBuilder::new(100).with_seed(3).to_websocket_bytes(),
+            ),
+            (WebSocketServer::GameServer, cosmetic_rpc_event(3)),
+            (
+                WebSocketServer::LobbyServer,
+                GameListBuilder::new(20).with_seed(4).to_websocket_bytes(),
+            ),
+        ];
+        for (server, original) in &capture {
+            let mut data = original.clone();
+            let forward = HaxState::websocket_hook(
+                state.clone(),
+                &mut data,
+                *server,
+                Direction::ServerToClient,
+            )
+            .unwrap();
+            assert!(forward);
+            assert_eq!(&data, original);
         }
 
-        if let Some(PhotonDataType::String(new_version)) =
-            room_info.custom_properties.get_mut("gameVersion")
         {
-            *new_version = target_version.to_string();
+            let hax = futures::executor::block_on(state.lock());
+            assert_eq!(hax.dry_run_log.total(), 3);
+            assert_eq!(hax.dry_run_log.counts()[feature::LOBBY_REWRITES], 2);
+            assert_eq!(hax.dry_run_log.counts()[feature::RPC_MUTING], 1);
+            assert_eq!(hax.drop_log.total(), 0);
+            assert!(hax.bandwidth.report().totals.is_empty());
+            // state tracking goes on as usual
+            assert_eq!(hax.stats.muted_rpcs.values().sum::<u64>(), 1);
+            let outcomes = hax
+                .dry_run_log
+                .entries()
+                .map(|e| e.outcome.to_string())
+                .collect::<Vec<_>>();
+            assert!(
+                outcomes[0].starts_with("lobby rewrites would have changed EventData 230, "),
+                "{}",
+                outcomes[0]
+            );
+            assert!(
+                outcomes[0].contains("changed parameters 222"),
+                "{}",
+                outcomes[0]
+            );
+            assert_eq!(
+                outcomes[1],
+                "RPC muting would have dropped ColorRpc from actor 3"
+            );
         }
-    };
+
+        // a feature can be taken out of the dry run
+        futures::executor::block_on(state.lock())
+            .update_settings(|s| s.dry_run.features.insert(feature::RPC_MUTING, false));
+        let mut data = cosmetic_rpc_event(3);
+        let forward = HaxState::websocket_hook(
+            state.clone(),
+            &mut data,
+            WebSocketServer::GameServer,
+            Direction::ServerToClient,
+        )
+        .unwrap();
+        assert!(!forward);
+        let hax = futures::executor::block_on(state.lock());
+        assert_eq!(hax.dry_run_log.total(), 3);
+        assert_eq!(hax.drop_log.total(), 1);
+    }
+
+    #[test]
+    fn observe_only_forwards_every_message_unchanged() {
+        let state = Arc::new(Mutex::new(HaxState {
+            settings: SharedSettings::new(Settings {
+                show_mobile_games: true,
+                show_other_versions: true,
+                strip_passwords: true,
+                mute_all_cosmetic: true,
+                observe_only: true,
+                ..Default::default()
+            }),
+            global_state: GlobalState {
+                version: VersionInfo::parse("1.90.0_1.99"),
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let capture = [
+            (
+                WebSocketServer::LobbyServer,
+                GameListBuilder::new(100).with_seed(3).to_websocket_bytes(),
+            ),
+            (WebSocketServer::GameServer, cosmetic_rpc_event(3)),
+        ];
+        for (server, original) in &capture {
+            let mut data = original.clone();
+            let forward = HaxState::websocket_hook(
+                state.clone(),
+                &mut data,
+                *server,
+                Direction::ServerToClient,
+            )
+            .unwrap();
+            assert!(forward);
+            assert_eq!(&data, original);
+        }
+
+        let hax = futures::executor::block_on(state.lock());
+        assert_eq!(hax.dry_run_log.total(), 2);
+        assert_eq!(hax.drop_log.total(), 0);
+        assert!(hax.bandwidth.report().totals.is_empty());
+
+        // injecting is refused too, however the message is sent
+        let proxy = WebSocketProxy::detached(
+            WebSocketServer::GameServer,
+            hax.bandwidth.clone(),
+            hax.shared_settings(),
+        );
+        let message = PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: 0,
+            parameters: indexmap! {},
+        });
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            assert!(matches!(
+                proxy
+                    .send_client(Message::Binary(cosmetic_rpc_event(3)), feature::SIMULATION)
+                    .await,
+                Err(HaxError::ObserveOnly)
+            ));
+            assert!(matches!(
+                proxy
+                    .raise_event(
+                        RaiseEvent::new(1, PhotonDataType::Hashtable(indexmap! {})),
+                        feature::INJECTED_MESSAGES
+                    )
+                    .await,
+                Err(HaxError::ObserveOnly)
+            ));
+            assert!(matches!(
+                proxy.queue_server(message, feature::STEALTH_HOST),
+                Err(HaxError::ObserveOnly)
+            ));
+        });
+        assert!(hax.bandwidth.report().totals.is_empty());
+    }
+
+    fn response_bytes(operation_code: u8, parameters: photon_lib::ParameterMap) -> Vec<u8> {
+        let mut bytes = vec![];
+        PhotonMessage::OperationResponse(OperationResponse {
+            operation_code,
+            return_code: 0,
+            debug_message: None,
+            parameters,
+        })
+        .to_websocket_bytes(&mut bytes)
+        .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn nameserver_regions_are_kept() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut data = response_bytes(
+            operation_code::GET_REGIONS,
+            indexmap! {
+                parameter_code::REGION => PhotonDataType::StringArray(vec!["eu".into(), "us".into()]),
+                parameter_code::ADDRESS => PhotonDataType::StringArray(vec![
+                    "wss://eu.example.com:19090".into(),
+                    "wss://us.example.com:19090".into(),
+                ]),
+            },
+        );
+        let forward = HaxState::websocket_hook(
+            state.clone(),
+            &mut data,
+            WebSocketServer::NameServer,
+            Direction::ServerToClient,
+        )
+        .unwrap();
+        assert!(forward);
+        assert_eq!(
+            futures::executor::block_on(state.lock())
+                .global_state
+                .regions,
+            indexmap! {
+                "eu".to_string() => "wss://eu.example.com:19090".to_string(),
+                "us".to_string() => "wss://us.example.com:19090".to_string(),
+            }
+        );
+
+        // regions without an address are left out
+        let mut data = response_bytes(
+            operation_code::GET_REGIONS,
+            indexmap! {
+                parameter_code::REGION => PhotonDataType::StringArray(vec!["asia".into(), "jp".into()]),
+                parameter_code::ADDRESS => PhotonDataType::StringArray(vec!["wss://asia.example.com:19090".into()]),
+            },
+        );
+        let forward = HaxState::websocket_hook(
+            state.clone(),
+            &mut data,
+            WebSocketServer::NameServer,
+            Direction::ServerToClient,
+        )
+        .unwrap();
+        assert!(forward);
+        assert_eq!(
+            futures::executor::block_on(state.lock())
+                .global_state
+                .regions,
+            indexmap! { "asia".to_string() => "wss://asia.example.com:19090".to_string() }
+        );
+    }
+
+    #[test]
+    fn nameserver_authenticate_is_forced_to_a_region() {
+        let authenticate = |region: &str| {
+            let mut bytes = vec![];
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::AUTHENTICATE,
+                parameters: indexmap! {
+                    parameter_code::APP_VERSION => PhotonDataType::String("1.90.0_1.99".into()),
+                    parameter_code::REGION => PhotonDataType::String(region.into()),
+                },
+            })
+            .to_websocket_bytes(&mut bytes)
+            .unwrap();
+            bytes
+        };
+        let region_of =
+            |data: &[u8]| match PhotonMessage::from_websocket_bytes(&mut &data[..]).unwrap() {
+                PhotonMessage::OperationRequest(request) => {
+                    request.parameters[&parameter_code::REGION].clone()
+                }
+                other => panic!("expected the authenticate request, got {other:?}"),
+            };
+        let state = Arc::new(Mutex::new(HaxState {
+            settings: SharedSettings::new(Settings {
+                forced_region: (true, "us".into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }));
+
+        let mut data = authenticate("eu");
+        let forward = HaxState::websocket_hook(
+            state.clone(),
+            &mut data,
+            WebSocketServer::NameServer,
+            Direction::ClientToServer,
+        )
+        .unwrap();
+        assert!(forward);
+        assert_eq!(region_of(&data), PhotonDataType::String("us".into()));
+        assert_eq!(
+            futures::executor::block_on(state.lock())
+                .bandwidth
+                .report()
+                .totals[feature::REGION_FORCING]
+                .rewritten_messages,
+            1
+        );
+
+        // the region the client asked for is left alone when forcing is turned off
+        futures::executor::block_on(state.lock()).update_settings(|s| s.forced_region.0 = false);
+        let mut data = authenticate("eu");
+        let original = data.clone();
+        HaxState::websocket_hook(
+            state.clone(),
+            &mut data,
+            WebSocketServer::NameServer,
+            Direction::ClientToServer,
+        )
+        .unwrap();
+        assert_eq!(data, original);
+    }
 }
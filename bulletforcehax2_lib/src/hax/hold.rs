@@ -0,0 +1,588 @@
+//! Postponing messages until a condition is met.
+//!
+//! Besides changing or dropping a message, a handler can [hold](Hold) it. The relay of the message's direction then
+//! waits until the [HoldCondition] is met, and forwards the message as the hooks left it. What happens to the messages
+//! behind a held one depends on its [HoldOrdering]: either they wait too, so the other side sees them in the order
+//! they were sent, or they go ahead and the held message is sent whenever it's released. Holds never span directions,
+//! the other direction of the connection keeps flowing either way.
+//!
+//! Every hold ends after its [timeout](Hold::timeout), capped at [MAX_HOLD], and the message is forwarded then. A
+//! condition that's never met only delays the message, it can't stall the connection for good.
+//!
+//! Conditions are checked again whenever the hook handled a message on any connection, when a hold is released through
+//! [HaxState::release_hold], and when a deadline passes. A [global pause](HaxState::set_global_pause) ends all holds
+//! at once, sending or dropping the held messages as [HaxState::pause_flush] says.
+
+use std::{
+    collections::HashSet,
+    fmt::{Debug, Display},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures_util::lock::Mutex;
+use photon_lib::highlevel::structs::RoomInfoView;
+use tokio::sync::watch;
+use tracing::{debug, warn};
+
+use super::{bandwidth::feature, HaxState};
+use crate::proxy::{Direction, WebSocketServer};
+
+/// The longest any message is held.
+pub const MAX_HOLD: Duration = Duration::from_secs(30);
+
+/// Checks whether a held message may go, see [HoldCondition::UntilEvent].
+pub type HoldPredicate = Arc<dyn Fn(&HaxState) -> bool + Send + Sync>;
+
+/// Identifies a hold that is released by calling [HaxState::release_hold].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HoldToken(u64);
+
+#[derive(Clone)]
+pub enum HoldCondition {
+    /// Until the predicate holds for the state, which is checked after every handled message.
+    UntilEvent(HoldPredicate),
+    UntilDeadline(Instant),
+    /// Until [HaxState::release_hold] is called with the token.
+    UntilReleasedByApi(HoldToken),
+}
+
+impl Debug for HoldCondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HoldCondition::UntilEvent(_) => write!(f, "UntilEvent(..)"),
+            HoldCondition::UntilDeadline(deadline) => write!(f, "UntilDeadline({deadline:?})"),
+            HoldCondition::UntilReleasedByApi(token) => write!(f, "UntilReleasedByApi({token:?})"),
+        }
+    }
+}
+
+impl Display for HoldCondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HoldCondition::UntilEvent(_) => write!(f, "until a condition is met"),
+            HoldCondition::UntilDeadline(deadline) => write!(
+                f,
+                "for {:?}",
+                deadline.saturating_duration_since(Instant::now())
+            ),
+            HoldCondition::UntilReleasedByApi(HoldToken(token)) => {
+                write!(f, "until hold {token} is released")
+            }
+        }
+    }
+}
+
+/// What happens to the messages behind a held one in the same direction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HoldOrdering {
+    /// They wait for the held message, so the order is kept.
+    #[default]
+    Block,
+    /// They go ahead, and the held message is sent whenever it's released.
+    Bypass,
+}
+
+/// What a handler returns to hold a message, see [hold](self).
+#[derive(Debug, Clone)]
+pub struct Hold {
+    pub condition: HoldCondition,
+    pub ordering: HoldOrdering,
+    /// When to forward the message anyway. Capped at [MAX_HOLD].
+    pub timeout: Duration,
+    /// The feature that held the message, see [feature].
+    pub feature: &'static str,
+}
+
+impl Hold {
+    /// Holds a message and the ones behind it, for at most [MAX_HOLD].
+    pub fn new(condition: HoldCondition, feature: &'static str) -> Self {
+        Self {
+            condition,
+            ordering: HoldOrdering::Block,
+            timeout: MAX_HOLD,
+            feature,
+        }
+    }
+
+    /// Lets the messages behind the held one go ahead.
+    pub fn bypass(self) -> Self {
+        Self {
+            ordering: HoldOrdering::Bypass,
+            ..self
+        }
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: timeout.min(MAX_HOLD),
+            ..self
+        }
+    }
+}
+
+/// What the proxy does with a message after the hooks ran, see [HaxState::websocket_hook_verdict].
+#[derive(Debug)]
+pub enum HookVerdict {
+    Forward,
+    Drop,
+    /// Forward it once the hold ends.
+    Hold(Hold),
+}
+
+/// How a hold ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HoldOutcome {
+    /// The condition was met.
+    Released,
+    /// The timeout passed first.
+    TimedOut,
+    /// A global pause ended the hold, and the message isn't sent.
+    Dropped,
+}
+
+/// What a [global pause](HaxState::set_global_pause) does with the messages that are held or about to be.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PauseFlush {
+    /// Send them right away, as if their condition was met.
+    #[default]
+    Send,
+    /// Drop them, so whatever held them has no effect at all.
+    Drop,
+}
+
+/// A hold for the next client request with an operation code, see [HaxState::hold_next_request].
+#[derive(Debug)]
+struct ArmedHold {
+    server: WebSocketServer,
+    operation_code: u8,
+    hold: Hold,
+}
+
+/// The holds of all connections.
+#[derive(Debug)]
+pub struct HoldRegistry {
+    next_token: u64,
+    /// The tokens released through the API whose hold didn't end yet.
+    released: HashSet<HoldToken>,
+    armed: Vec<ArmedHold>,
+    /// Tells the held messages to check their condition again.
+    wake: watch::Sender<()>,
+    /// How many messages are held right now.
+    held: usize,
+    /// How many holds ended because their timeout passed.
+    timed_out: u64,
+    /// How often the holds were flushed, so a held message notices a flush that happened while it waited.
+    flushes: u64,
+    /// What the last flush did with the held messages.
+    flush_policy: PauseFlush,
+}
+
+impl Default for HoldRegistry {
+    fn default() -> Self {
+        Self {
+            next_token: 1,
+            released: HashSet::new(),
+            armed: vec![],
+            wake: watch::channel(()).0,
+            held: 0,
+            timed_out: 0,
+            flushes: 0,
+            flush_policy: PauseFlush::default(),
+        }
+    }
+}
+
+impl HoldRegistry {
+    /// Makes the held messages check their condition again, if there are any.
+    pub(crate) fn wake(&self) {
+        if self.held > 0 {
+            // fails only if nothing is waiting
+            _ = self.wake.send(());
+        }
+    }
+
+    /// Takes the hold armed for a client request on the given server, if any.
+    pub(crate) fn take_armed(
+        &mut self,
+        server: WebSocketServer,
+        operation_code: u8,
+    ) -> Option<Hold> {
+        let index = self
+            .armed
+            .iter()
+            .position(|a| a.server == server && a.operation_code == operation_code)?;
+        Some(self.armed.remove(index).hold)
+    }
+
+    /// Ends every hold and forgets the armed ones. Returns how many messages were held or about to be.
+    pub(crate) fn flush(&mut self, policy: PauseFlush) -> usize {
+        let flushed = self.held + self.armed.len();
+        self.armed.clear();
+        self.flushes += 1;
+        self.flush_policy = policy;
+        // fails only if nothing is waiting
+        _ = self.wake.send(());
+        flushed
+    }
+
+    /// How many messages are held right now.
+    pub fn held(&self) -> usize {
+        self.held
+    }
+
+    /// How many holds ended because their timeout passed instead of their condition being met.
+    pub fn timed_out(&self) -> u64 {
+        self.timed_out
+    }
+}
+
+impl HaxState {
+    /// A new token for a [HoldCondition::UntilReleasedByApi] hold.
+    pub fn hold_token(&mut self) -> HoldToken {
+        let token = HoldToken(self.holds.next_token);
+        self.holds.next_token += 1;
+        token
+    }
+
+    /// Releases the holds waiting for the token. Releasing a token before its message is held releases the message
+    /// right away once it is.
+    pub fn release_hold(&mut self, token: HoldToken) {
+        self.holds.released.insert(token);
+        // always wake, the message may be on its way to being held
+        _ = self.holds.wake.send(());
+    }
+
+    /// Holds the next request with the given operation code that the client sends to the server.
+    pub fn hold_next_request(&mut self, server: WebSocketServer, operation_code: u8, hold: Hold) {
+        debug!(
+            server = format!("{server}"),
+            operation_code, "Holding the next request {}", hold.condition
+        );
+        self.holds.armed.push(ArmedHold {
+            server,
+            operation_code,
+            hold,
+        });
+    }
+
+    pub fn holds(&self) -> &HoldRegistry {
+        &self.holds
+    }
+
+    fn hold_condition_met(&self, condition: &HoldCondition, now: Instant) -> bool {
+        match condition {
+            HoldCondition::UntilEvent(predicate) => predicate(self),
+            HoldCondition::UntilDeadline(deadline) => now >= *deadline,
+            HoldCondition::UntilReleasedByApi(token) => self.holds.released.contains(token),
+        }
+    }
+}
+
+/// Waits until a held message may go, and logs how the hold ended.
+pub(crate) async fn wait_for_release(
+    state: &Mutex<HaxState>,
+    hold: &Hold,
+    server: WebSocketServer,
+    direction: Direction,
+) -> HoldOutcome {
+    let started = Instant::now();
+    let timeout_at = started + hold.timeout.min(MAX_HOLD);
+    let wake_at = match hold.condition {
+        HoldCondition::UntilDeadline(deadline) => deadline.min(timeout_at),
+        _ => timeout_at,
+    };
+    let (mut wake, flushes) = {
+        let mut hax = state.lock().await;
+        hax.holds.held += 1;
+        (hax.holds.wake.subscribe(), hax.holds.flushes)
+    };
+
+    let outcome = loop {
+        let now = Instant::now();
+        {
+            let hax = state.lock().await;
+            if hax.holds.flushes != flushes {
+                break match hax.holds.flush_policy {
+                    PauseFlush::Send => HoldOutcome::Released,
+                    PauseFlush::Drop => HoldOutcome::Dropped,
+                };
+            }
+            if hax.hold_condition_met(&hold.condition, now) {
+                break HoldOutcome::Released;
+            }
+        }
+        if now >= timeout_at {
+            break HoldOutcome::TimedOut;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep_until(wake_at.into()) => (),
+            changed = wake.changed() => {
+                // the sender lives as long as the state, but don't spin if it's gone
+                if changed.is_err() {
+                    tokio::time::sleep_until(wake_at.into()).await;
+                }
+            }
+        }
+    };
+
+    let mut hax = state.lock().await;
+    hax.holds.held -= 1;
+    if let HoldCondition::UntilReleasedByApi(token) = &hold.condition {
+        hax.holds.released.remove(token);
+    }
+    let held_for = started.elapsed();
+    match outcome {
+        HoldOutcome::Released => debug!(
+            server = format!("{server}"),
+            direction = format!("{direction}"),
+            feature = hold.feature,
+            "Releasing message held for {held_for:?}"
+        ),
+        HoldOutcome::Dropped => debug!(
+            server = format!("{server}"),
+            direction = format!("{direction}"),
+            feature = hold.feature,
+            "Dropping message held for {held_for:?}, as all features were paused"
+        ),
+        HoldOutcome::TimedOut => {
+            hax.holds.timed_out += 1;
+            warn!(
+                server = format!("{server}"),
+                direction = format!("{direction}"),
+                feature = hold.feature,
+                "Forwarding message held {} after {held_for:?}, as it timed out",
+                hold.condition
+            );
+        }
+    }
+    outcome
+}
+
+/// Holds a request to join a full lobby room until the room has a free slot, so the client gets in as soon as someone
+/// leaves. Rooms that disappear from the lobby release the request too, so the client gets the server's answer.
+pub(crate) fn queue_jump(room_id: &str, hax: &HaxState) -> Option<Hold> {
+    if !room_is_full(hax, room_id)? {
+        return None;
+    }
+    let room_id = room_id.to_string();
+    let predicate: HoldPredicate = Arc::new(move |hax| room_is_full(hax, &room_id) != Some(true));
+    // the client sends nothing else while joining, but its pings shouldn't wait
+    Some(Hold::new(HoldCondition::UntilEvent(predicate), feature::QUEUE_JUMP).bypass())
+}
+
+/// Whether a lobby room is full, if it's in the lobby.
+fn room_is_full(hax: &HaxState, room_id: &str) -> Option<bool> {
+    let (_, lobby) = hax.lobby_state.as_ref()?;
+    let room = RoomInfoView(&lobby.rooms.get(room_id)?.properties);
+    match (room.player_count(), room.max_players()) {
+        (Some(count), Some(&max)) if max > 0 => Some(*count >= max),
+        _ => Some(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use futures_util::lock::Mutex;
+    use photon_lib::{
+        highlevel::constants::game_property_key,
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{OperationRequest, PhotonMessage},
+        PhotonHashmap,
+    };
+
+    use super::{queue_jump, Hold, HoldCondition, HoldOrdering, PauseFlush};
+    use crate::{
+        hax::{bandwidth::feature, HaxState, LobbyState, WebSocketProxy},
+        proxy::WebSocketServer,
+        testsupport::ProxiedConnection,
+    };
+
+    fn request(operation_code: u8) -> PhotonMessage {
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code,
+            parameters: indexmap! {},
+        })
+    }
+
+    fn operation_code(message: PhotonMessage) -> u8 {
+        match message {
+            PhotonMessage::OperationRequest(request) => request.operation_code,
+            other => panic!("expected a request, got {other:?}"),
+        }
+    }
+
+    fn room(player_count: u8, removed: bool) -> PhotonHashmap {
+        indexmap! {
+            PhotonDataType::String("room".into()) => PhotonDataType::Hashtable(indexmap! {
+                PhotonDataType::Byte(game_property_key::PLAYER_COUNT) => PhotonDataType::Byte(player_count),
+                PhotonDataType::Byte(game_property_key::MAX_PLAYERS) => PhotonDataType::Byte(4),
+                PhotonDataType::Byte(game_property_key::REMOVED) => PhotonDataType::Boolean(removed),
+            }),
+        }
+    }
+
+    #[test]
+    fn queue_jump_waits_for_a_free_slot() {
+        let mut hax = HaxState {
+            lobby_state: Some((
+                WebSocketProxy::detached(
+                    WebSocketServer::LobbyServer,
+                    Default::default(),
+                    Default::default(),
+                ),
+                LobbyState::default(),
+            )),
+            ..Default::default()
+        };
+        let rooms = |hax: &mut HaxState, games| {
+            let lobby = &mut hax.lobby_state.as_mut().unwrap().1;
+            lobby.rooms.update(&games, Instant::now());
+        };
+
+        assert!(
+            queue_jump("room", &hax).is_none(),
+            "unknown rooms aren't held"
+        );
+        rooms(&mut hax, room(3, false));
+        assert!(queue_jump("room", &hax).is_none());
+
+        rooms(&mut hax, room(4, false));
+        let hold = queue_jump("room", &hax).unwrap();
+        assert_eq!(hold.ordering, HoldOrdering::Bypass);
+        let now = Instant::now();
+        assert!(!hax.hold_condition_met(&hold.condition, now));
+        rooms(&mut hax, room(3, false));
+        assert!(hax.hold_condition_met(&hold.condition, now));
+
+        rooms(&mut hax, room(4, false));
+        let hold = queue_jump("room", &hax).unwrap();
+        rooms(&mut hax, room(4, true));
+        assert!(hax.hold_condition_met(&hold.condition, now));
+    }
+
+    #[tokio::test]
+    async fn deadline_keeps_the_order() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut conn = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(200);
+        state.lock().await.hold_next_request(
+            WebSocketServer::GameServer,
+            42,
+            Hold::new(HoldCondition::UntilDeadline(deadline), feature::QUEUE_JUMP),
+        );
+
+        conn.client_send(request(42)).await;
+        conn.client_send(request(43)).await;
+        assert_eq!(operation_code(conn.server.recv().await), 42);
+        assert!(Instant::now() >= deadline);
+        assert_eq!(operation_code(conn.server.recv().await), 43);
+        assert_eq!(state.lock().await.holds().timed_out(), 0);
+    }
+
+    #[tokio::test]
+    async fn released_through_the_api() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut conn = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        let token = {
+            let mut hax = state.lock().await;
+            let token = hax.hold_token();
+            hax.hold_next_request(
+                WebSocketServer::GameServer,
+                42,
+                Hold::new(
+                    HoldCondition::UntilReleasedByApi(token),
+                    feature::QUEUE_JUMP,
+                )
+                .bypass(),
+            );
+            token
+        };
+
+        conn.client_send(request(42)).await;
+        conn.client_send(request(43)).await;
+        assert_eq!(operation_code(conn.server.recv().await), 43);
+
+        state.lock().await.release_hold(token);
+        assert_eq!(operation_code(conn.server.recv().await), 42);
+        let hax = state.lock().await;
+        assert_eq!(hax.holds().timed_out(), 0);
+        assert_eq!(hax.holds().held(), 0);
+    }
+
+    #[tokio::test]
+    async fn pausing_flushes_held_messages() {
+        for (policy, expected) in [
+            (PauseFlush::Send, vec![42, 44]),
+            (PauseFlush::Drop, vec![44]),
+        ] {
+            let state = Arc::new(Mutex::new(HaxState {
+                pause_flush: policy,
+                ..Default::default()
+            }));
+            let mut conn =
+                ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+            {
+                let mut hax = state.lock().await;
+                for operation_code in [42, 44] {
+                    let token = hax.hold_token();
+                    hax.hold_next_request(
+                        WebSocketServer::GameServer,
+                        operation_code,
+                        Hold::new(
+                            HoldCondition::UntilReleasedByApi(token),
+                            feature::QUEUE_JUMP,
+                        )
+                        .bypass(),
+                    );
+                }
+            }
+
+            conn.client_send(request(42)).await;
+            conn.client_send(request(43)).await;
+            assert_eq!(operation_code(conn.server.recv().await), 43);
+            state.lock().await.set_global_pause(true);
+            // its armed hold was flushed too
+            conn.client_send(request(44)).await;
+
+            let mut received = vec![];
+            for _ in 0..expected.len() {
+                received.push(operation_code(conn.server.recv().await));
+            }
+            received.sort();
+            assert_eq!(received, expected, "{policy:?}");
+            let hax = state.lock().await;
+            assert_eq!(hax.holds().held(), 0);
+            assert_eq!(hax.holds().timed_out(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn forwarded_after_the_timeout() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut conn = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        {
+            let mut hax = state.lock().await;
+            let token = hax.hold_token();
+            let hold = Hold::new(
+                HoldCondition::UntilReleasedByApi(token),
+                feature::QUEUE_JUMP,
+            )
+            .with_timeout(Duration::from_millis(200));
+            hax.hold_next_request(WebSocketServer::GameServer, 42, hold);
+        }
+
+        let started = Instant::now();
+        conn.client_send(request(42)).await;
+        assert_eq!(operation_code(conn.server.recv().await), 42);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(state.lock().await.holds().timed_out(), 1);
+    }
+}
@@ -0,0 +1,296 @@
+//! Following the room's master client as it moves between players.
+//!
+//! The player that created a room starts out as its master client. When they leave, Photon makes another player the
+//! master and tells the others in two ways: the leave event carries the new master's actor id, and the room's
+//! properties get a new master client id. Either can arrive first, or only one of them, so both end up in
+//! [HaxState::on_master_changed], which updates everything that depends on who hosts the room:
+//!
+//! - [GameplayState::hosting](super::GameplayState::hosting), so host-only features like
+//!   [stealth hosting](super::stealth_host) work for rooms we inherited and stop for rooms we lost
+//! - the [room note](super::room_notes) on the new host, which is looked up by their user id
+//! - the [watchlist](super::watchlist), which is checked against the new host without a cooldown, as being hosted
+//!   by them matters even if they were reported earlier
+//!
+//! The player that created the room stays known as
+//! [GameplayState::room_creator](super::GameplayState::room_creator) if they were still in the room when we joined.
+
+use photon_lib::{
+    highlevel::structs::WellKnownRoomProperties, photon_message::PhotonMessage, PhotonHashmap,
+};
+use tracing::{info, warn};
+
+use super::{
+    bandwidth::feature,
+    events::HaxEvent,
+    journal::{room_field, ChangedKey, Section},
+    room_notes::{RoomKey, RoomNote},
+    stealth_host,
+    watchlist::WatchEntry,
+    HaxState,
+};
+
+/// The master client of the room moved to another player.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MasterChange {
+    /// The actor id of the previous master client.
+    pub previous: i32,
+    /// The actor id of the new master client.
+    pub current: i32,
+    /// Whether we are the new master client.
+    pub is_us: bool,
+    pub user_id: Option<String>,
+    pub nickname: Option<String>,
+    /// The note on the new host, if there is one.
+    pub note: Option<RoomNote>,
+    /// The watchlist entry matching the new host, if any.
+    pub watch_entry: Option<WatchEntry>,
+}
+
+/// The master client's actor id from room properties, if they contain it.
+pub fn master_client_id(properties: &PhotonHashmap) -> Option<i32> {
+    WellKnownRoomProperties::new(properties)
+        .master_client_id()
+        .copied()
+}
+
+impl HaxState {
+    /// Records the master client of the current room. The first call after joining only records it, later calls
+    /// that change it update the features that depend on the host and emit [HaxEvent::MasterClientChanged].
+    pub(crate) fn on_master_changed(&mut self, master: i32) {
+        let stealth_host = self.stealth_host;
+        let (proxy, state) = match &mut self.gameplay_state {
+            Some(x) => x,
+            None => return,
+        };
+        let previous = match state.master_client.replace(master) {
+            Some(previous) if previous == master => return,
+            previous => previous,
+        };
+        self.journal
+            .record(Section::Room, ChangedKey::Field(room_field::MASTER_CLIENT));
+
+        let was_hosting = state.hosting;
+        if let Some(player_id) = state.player_id {
+            state.hosting = player_id == master;
+        }
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return,
+        };
+
+        if state.hosting && !was_hosting && stealth_host {
+            let queued = proxy.queue_server(
+                PhotonMessage::OperationRequest(stealth_host::visibility_request(false)),
+                feature::STEALTH_HOST,
+            );
+            match queued {
+                Ok(()) => state.pending_properties.forwarded(),
+                Err(e) => warn!("Could not hide the room we now host: {e}"),
+            }
+        }
+
+        let player = state.players.get(&master);
+        let user_id = player.and_then(|p| p.user_id.clone());
+        let nickname = player.and_then(|p| p.nickname.clone());
+        let note = user_id
+            .as_ref()
+            .and_then(|id| self.room_notes.get(&RoomKey::HostUserId(id.clone())))
+            .cloned();
+        let watch_entry = self
+            .watchlist
+            .entries()
+            .iter()
+            .find(|e| e.target.matches(user_id.as_deref(), nickname.as_deref()))
+            .cloned();
+
+        info!(
+            previous,
+            current = master,
+            is_us = state.hosting,
+            user_id,
+            nickname,
+            note = note.as_ref().map(|n| n.note.as_str()),
+            watched = watch_entry.is_some(),
+            "Master client changed"
+        );
+        self.events
+            .emit(HaxEvent::MasterClientChanged(MasterChange {
+                previous,
+                current: master,
+                is_us: state.hosting,
+                user_id,
+                nickname,
+                note,
+                watch_entry,
+            }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::{
+        highlevel::constants::{
+            actor_properties, event_code, game_property_key, operation_code, parameter_code,
+        },
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{OperationRequest, OperationResponse, PhotonMessage},
+    };
+
+    use super::MasterChange;
+    use crate::{
+        hax::{
+            events::HaxEvent,
+            room_notes::{RoomFlag, RoomKey, RoomNote},
+            timeline::Replay,
+            watchlist::{AlertLevel, WatchEntry, WatchTarget},
+        },
+        inspect::CapturedMessage,
+        proxy::Direction,
+        testsupport::{captured, event},
+    };
+
+    fn player(nickname: &str, user_id: &str) -> PhotonDataType {
+        PhotonDataType::Hashtable(indexmap! {
+            PhotonDataType::Byte(actor_properties::PLAYER_NAME) => PhotonDataType::String(nickname.into()),
+            PhotonDataType::Byte(actor_properties::USER_ID) => PhotonDataType::String(user_id.into()),
+        })
+    }
+
+    /// Joins a room created by actor 1 as actor 2, with actor 3 in it as well.
+    fn join_mid_match() -> Replay {
+        let mut replay = Replay::default();
+        replay.feed(&captured(
+            Direction::ClientToServer,
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::AUTHENTICATE,
+                parameters: indexmap! {},
+            }),
+        ));
+        replay.feed(&captured(
+            Direction::ServerToClient,
+            PhotonMessage::OperationResponse(OperationResponse {
+                operation_code: operation_code::JOIN_GAME,
+                return_code: 0,
+                debug_message: None,
+                parameters: indexmap! {
+                    parameter_code::ROOM_NAME => PhotonDataType::String("room".into()),
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(2),
+                    parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Integer(1) => player("creator", "creator-id"),
+                        PhotonDataType::Integer(2) => player("us", "our-id"),
+                        PhotonDataType::Integer(3) => player("cheater", "cheater-id"),
+                    }),
+                    parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Byte(game_property_key::MASTER_CLIENT_ID) => PhotonDataType::Integer(1),
+                    }),
+                },
+            }),
+        ));
+        replay
+    }
+
+    fn leave(actor: i32, master: i32) -> CapturedMessage {
+        event(
+            event_code::LEAVE,
+            indexmap! {
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(actor),
+                parameter_code::MASTER_CLIENT_ID => PhotonDataType::Integer(master),
+            },
+        )
+    }
+
+    fn master_property(master: i32) -> CapturedMessage {
+        event(
+            event_code::PROPERTIES_CHANGED,
+            indexmap! {
+                parameter_code::TARGET_ACTOR_NR => PhotonDataType::Integer(0),
+                parameter_code::PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::Byte(game_property_key::MASTER_CLIENT_ID) => PhotonDataType::Integer(master),
+                }),
+            },
+        )
+    }
+
+    #[test]
+    fn host_leaving_makes_us_master() {
+        let mut replay = join_mid_match();
+        let mut events = replay.state().events.subscribe();
+        {
+            let state = replay.state();
+            let game = &state.gameplay_state.as_ref().unwrap().1;
+            assert_eq!(game.master_client, Some(1));
+            assert_eq!(game.room_creator, Some(1));
+            assert!(!game.hosting);
+        }
+        // joining only records the master
+        assert!(events.try_recv().is_err());
+
+        replay.feed(&leave(1, 2));
+        // the property change that follows is the same change
+        replay.feed(&master_property(2));
+
+        let state = replay.state();
+        assert!(state.stats.recent_errors.is_empty());
+        let game = &state.gameplay_state.as_ref().unwrap().1;
+        assert_eq!(game.master_client, Some(2));
+        assert_eq!(game.room_creator, Some(1));
+        assert!(game.hosting);
+        match events.try_recv() {
+            Ok(HaxEvent::MasterClientChanged(change)) => assert_eq!(
+                change,
+                MasterChange {
+                    previous: 1,
+                    current: 2,
+                    is_us: true,
+                    user_id: Some("our-id".into()),
+                    nickname: Some("us".into()),
+                    note: None,
+                    watch_entry: None,
+                }
+            ),
+            other => panic!("expected a master change, got {other:?}"),
+        }
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn new_host_is_matched_against_notes_and_watchlist() {
+        let mut replay = join_mid_match();
+        let note = RoomNote {
+            flag: Some(RoomFlag::Blocked),
+            note: "kicks everyone".into(),
+        };
+        let entry = WatchEntry {
+            target: WatchTarget::UserId("cheater-id".into()),
+            note: "aimbot".into(),
+            level: AlertLevel::Warning,
+        };
+        let mut events = {
+            let mut state = replay.state();
+            state
+                .set_room_note(RoomKey::HostUserId("cheater-id".into()), note.clone())
+                .unwrap();
+            // the watchlist already reported them when we joined, which doesn't hide the new host
+            state.add_watch_entry(entry.clone()).unwrap();
+            state.events.subscribe()
+        };
+
+        // only the property change arrives, eg. because the master was handed over without anyone leaving
+        replay.feed(&master_property(3));
+
+        let state = replay.state();
+        let game = &state.gameplay_state.as_ref().unwrap().1;
+        assert_eq!(game.master_client, Some(3));
+        assert!(!game.hosting);
+        match events.try_recv() {
+            Ok(HaxEvent::MasterClientChanged(change)) => {
+                assert_eq!((change.previous, change.current), (1, 3));
+                assert!(!change.is_us);
+                assert_eq!(change.note, Some(note));
+                assert_eq!(change.watch_entry, Some(entry));
+            }
+            other => panic!("expected a master change, got {other:?}"),
+        }
+    }
+}
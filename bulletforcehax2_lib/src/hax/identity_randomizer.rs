@@ -0,0 +1,583 @@
+//! Presenting a fresh identity to the servers every session.
+//!
+//! When the client authenticates, a user id and a nickname are generated for the session, and from then on every
+//! message the client sends carries those instead of its own: the user id when authenticating, and both in the
+//! actor properties of join requests and of our own SetProperties. The servers echo them back, in the authenticate
+//! response and in the actor properties of joins and property changes, and the client must keep recognizing itself
+//! there, so the generated values are replaced with the real ones on the way back.
+//!
+//! The identity is derived from a seed picked when the state is created, so reconnecting within a session presents
+//! the same identity again, while the next session gets another one. The [RandomIdentity] also keeps the real values
+//! the client sent, which the status report shows next to the generated ones.
+
+use std::hash::{BuildHasher, Hasher};
+
+use photon_lib::{
+    highlevel::constants::{actor_properties, event_code, operation_code, parameter_code},
+    photon_data_type::PhotonDataType,
+    photon_message::PhotonMessage,
+    ParameterMap,
+};
+use sha2::{Digest, Sha256};
+
+/// The words generated nicknames are made of: an adjective, a noun and two digits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameWords {
+    pub adjectives: Vec<String>,
+    pub nouns: Vec<String>,
+}
+
+impl Default for NameWords {
+    fn default() -> Self {
+        let words = |words: &[&str]| words.iter().map(|w| w.to_string()).collect();
+        Self {
+            adjectives: words(&[
+                "Swift", "Silent", "Brave", "Lucky", "Rusty", "Clever", "Grim", "Jolly", "Sneaky",
+                "Mighty", "Calm", "Wild",
+            ]),
+            nouns: words(&[
+                "Falcon", "Badger", "Otter", "Viper", "Moose", "Raven", "Panda", "Lynx", "Walrus",
+                "Gecko", "Bison", "Heron",
+            ]),
+        }
+    }
+}
+
+/// The identity presented to the servers this session, see [identity_randomizer](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RandomIdentity {
+    pub user_id: String,
+    pub nickname: String,
+    /// The user id the client sent, if it sent one.
+    pub real_user_id: Option<String>,
+    /// The nickname the client last sent, if it sent one yet.
+    pub real_nickname: Option<String>,
+}
+
+/// Generates the session's identity and swaps it in and out of messages, see [identity_randomizer](self).
+#[derive(Debug, Clone)]
+pub struct IdentityRandomizer {
+    pub words: NameWords,
+    seed: u64,
+    identity: Option<RandomIdentity>,
+}
+
+impl Default for IdentityRandomizer {
+    fn default() -> Self {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u128(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        Self::with_seed(hasher.finish())
+    }
+}
+
+impl IdentityRandomizer {
+    /// A randomizer that always generates the same identity for the same seed and words.
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            words: NameWords::default(),
+            seed,
+            identity: None,
+        }
+    }
+
+    /// The identity of this session, once the client authenticated with the randomizer on.
+    pub fn identity(&self) -> Option<&RandomIdentity> {
+        self.identity.as_ref()
+    }
+
+    /// Puts the generated identity into a message the client sends, generating it when the client authenticates.
+    /// Returns whether the message changed.
+    ///
+    /// Actor properties are only replaced in join requests and in SetProperties of `own_actor`.
+    pub fn randomize(&mut self, message: &mut PhotonMessage, own_actor: Option<i32>) -> bool {
+        let request = match message {
+            PhotonMessage::OperationRequest(request) => request,
+            _ => return false,
+        };
+        let is_authentication = matches!(
+            request.operation_code,
+            operation_code::AUTHENTICATE | operation_code::AUTHENTICATE_ONCE
+        );
+        if is_authentication && self.identity.is_none() {
+            self.identity = Some(self.generate());
+        }
+        let identity = match &mut self.identity {
+            Some(identity) => identity,
+            None => return false,
+        };
+
+        let parameters = &mut request.parameters;
+        let mut changed = replace_parameter(parameters, parameter_code::USER_ID, |real| {
+            identity
+                .real_user_id
+                .get_or_insert_with(|| real.to_string());
+            Some(identity.user_id.clone())
+        });
+        changed |= replace_parameter(parameters, parameter_code::NICK_NAME, |real| {
+            identity.real_nickname = Some(real.to_string());
+            Some(identity.nickname.clone())
+        });
+
+        let properties = match request.operation_code {
+            operation_code::JOIN_GAME
+            | operation_code::CREATE_GAME
+            | operation_code::JOIN_RANDOM_GAME => {
+                parameters.get_mut(&parameter_code::PLAYER_PROPERTIES)
+            }
+            operation_code::SET_PROPERTIES
+                if own_actor.is_some()
+                    && parameters.get(&parameter_code::ACTOR_NR)
+                        == own_actor.map(PhotonDataType::Integer).as_ref() =>
+            {
+                parameters.get_mut(&parameter_code::PROPERTIES)
+            }
+            _ => None,
+        };
+        if let Some(PhotonDataType::Hashtable(properties)) = properties {
+            for (key, value) in properties.iter_mut() {
+                let (real, generated) = match key {
+                    PhotonDataType::Byte(actor_properties::PLAYER_NAME) => {
+                        (&mut identity.real_nickname, &identity.nickname)
+                    }
+                    PhotonDataType::Byte(actor_properties::USER_ID) => {
+                        (&mut identity.real_user_id, &identity.user_id)
+                    }
+                    _ => continue,
+                };
+                match value {
+                    PhotonDataType::String(value) if value != generated => {
+                        *real = Some(std::mem::replace(value, generated.clone()));
+                        changed = true;
+                    }
+                    _ => (),
+                }
+            }
+        }
+        changed
+    }
+
+    /// Puts the real identity back into a message the server sends. Returns whether the message changed.
+    pub fn restore(&self, message: &mut PhotonMessage) -> bool {
+        let identity = match &self.identity {
+            Some(identity) => identity,
+            None => return false,
+        };
+        let parameters = match message {
+            PhotonMessage::OperationResponse(response)
+                if matches!(
+                    response.operation_code,
+                    operation_code::AUTHENTICATE
+                        | operation_code::AUTHENTICATE_ONCE
+                        | operation_code::JOIN_GAME
+                        | operation_code::CREATE_GAME
+                        | operation_code::JOIN_RANDOM_GAME
+                ) =>
+            {
+                &mut response.parameters
+            }
+            PhotonMessage::EventData(event)
+                if matches!(
+                    event.code,
+                    event_code::JOIN | event_code::PROPERTIES_CHANGED
+                ) =>
+            {
+                &mut event.parameters
+            }
+            _ => return false,
+        };
+
+        let restore = |value: &str, generated: &str, real: &Option<String>| match real {
+            Some(real) if value == generated => Some(real.clone()),
+            _ => None,
+        };
+        let mut changed = replace_parameter(parameters, parameter_code::USER_ID, |value| {
+            restore(value, &identity.user_id, &identity.real_user_id)
+        });
+        changed |= replace_parameter(parameters, parameter_code::NICK_NAME, |value| {
+            restore(value, &identity.nickname, &identity.real_nickname)
+        });
+        for value in parameters.values_mut() {
+            changed |= restore_properties(value, identity);
+        }
+        changed
+    }
+
+    fn generate(&self) -> RandomIdentity {
+        let digest = |purpose: &[u8]| {
+            let mut hasher = Sha256::new();
+            hasher.update(self.seed.to_le_bytes());
+            hasher.update(purpose);
+            hasher.finalize()
+        };
+
+        let mut uuid = digest(b"user id");
+        // a version 4 uuid, as random ones are
+        uuid[6] = (uuid[6] & 0x0f) | 0x40;
+        uuid[8] = (uuid[8] & 0x3f) | 0x80;
+        let hex = uuid[..16]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        let user_id = format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        );
+
+        let name = digest(b"nickname");
+        let pick = |words: &[String], byte: u8| match words.len() {
+            0 => String::new(),
+            len => words[byte as usize % len].clone(),
+        };
+        let nickname = format!(
+            "{}{}{:02}",
+            pick(&self.words.adjectives, name[0]),
+            pick(&self.words.nouns, name[1]),
+            name[2] % 100
+        );
+
+        RandomIdentity {
+            user_id,
+            nickname,
+            real_user_id: None,
+            real_nickname: None,
+        }
+    }
+}
+
+/// Replaces a string parameter with what `f` returns for it, if anything.
+fn replace_parameter(
+    parameters: &mut ParameterMap,
+    code: u8,
+    f: impl FnOnce(&str) -> Option<String>,
+) -> bool {
+    match parameters.get_mut(&code) {
+        Some(PhotonDataType::String(value)) => match f(value) {
+            Some(replacement) if replacement != *value => {
+                *value = replacement;
+                true
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Restores the identity in actor properties, which the server nests in tables of actors in join responses.
+fn restore_properties(value: &mut PhotonDataType, identity: &RandomIdentity) -> bool {
+    let properties = match value {
+        PhotonDataType::Hashtable(properties) => properties,
+        _ => return false,
+    };
+    let mut changed = false;
+    for (key, value) in properties.iter_mut() {
+        let (generated, real) = match key {
+            PhotonDataType::Byte(actor_properties::PLAYER_NAME) => {
+                (&identity.nickname, &identity.real_nickname)
+            }
+            PhotonDataType::Byte(actor_properties::USER_ID) => {
+                (&identity.user_id, &identity.real_user_id)
+            }
+            _ => {
+                changed |= restore_properties(value, identity);
+                continue;
+            }
+        };
+        match (value, real) {
+            (PhotonDataType::String(value), Some(real)) if value == generated => {
+                *value = real.clone();
+                changed = true;
+            }
+            _ => (),
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use photon_lib::{
+        highlevel::constants::{event_code, operation_code, parameter_code},
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationRequest, OperationResponse, PhotonMessage},
+        ParameterMap,
+    };
+
+    use super::IdentityRandomizer;
+    use crate::{
+        hax::{bandwidth::feature, timeline::Replay},
+        inspect::CapturedMessage,
+        proxy::{Direction, WebSocketServer},
+    };
+
+    fn captured(
+        server: WebSocketServer,
+        direction: Direction,
+        message: PhotonMessage,
+    ) -> CapturedMessage {
+        let mut raw = vec![];
+        message.to_websocket_bytes(&mut raw).unwrap();
+        CapturedMessage {
+            timestamp: SystemTime::now(),
+            server,
+            direction,
+            raw,
+        }
+    }
+
+    fn request(
+        server: WebSocketServer,
+        operation_code: u8,
+        parameters: ParameterMap,
+    ) -> CapturedMessage {
+        captured(
+            server,
+            Direction::ClientToServer,
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code,
+                parameters,
+            }),
+        )
+    }
+
+    fn response(
+        server: WebSocketServer,
+        operation_code: u8,
+        parameters: ParameterMap,
+    ) -> CapturedMessage {
+        captured(
+            server,
+            Direction::ServerToClient,
+            PhotonMessage::OperationResponse(OperationResponse {
+                operation_code,
+                return_code: 0,
+                debug_message: None,
+                parameters,
+            }),
+        )
+    }
+
+    fn properties_changed(actor: i32, properties: PhotonDataType) -> CapturedMessage {
+        captured(
+            WebSocketServer::GameServer,
+            Direction::ServerToClient,
+            PhotonMessage::EventData(EventData {
+                code: event_code::PROPERTIES_CHANGED,
+                parameters: indexmap! {
+                    parameter_code::TARGET_ACTOR_NR => PhotonDataType::Integer(actor),
+                    parameter_code::PROPERTIES => properties,
+                },
+            }),
+        )
+    }
+
+    fn string(value: &str) -> PhotonDataType {
+        PhotonDataType::String(value.into())
+    }
+
+    /// The parameters of a message as it's forwarded.
+    fn forward(replay: &mut Replay, message: CapturedMessage) -> ParameterMap {
+        let raw = replay.forward(&message).unwrap();
+        match PhotonMessage::from_websocket_bytes(&mut raw.as_slice()).unwrap() {
+            PhotonMessage::OperationRequest(request) => request.parameters,
+            PhotonMessage::OperationResponse(response) => response.parameters,
+            PhotonMessage::EventData(event) => event.parameters,
+            other => panic!("unexpected message {other:?}"),
+        }
+    }
+
+    #[test]
+    fn generates_the_same_identity_per_seed() {
+        let identity = |seed| {
+            let mut randomizer = IdentityRandomizer::with_seed(seed);
+            let mut authenticate = PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::AUTHENTICATE,
+                parameters: indexmap! {},
+            });
+            assert!(!randomizer.randomize(&mut authenticate, None));
+            randomizer.identity().unwrap().clone()
+        };
+
+        let first = identity(1);
+        assert_eq!(first, identity(1));
+        assert_ne!(first.user_id, identity(2).user_id);
+        assert_eq!(first.user_id.len(), 36);
+        assert_eq!(first.user_id.as_bytes()[14], b'4');
+        assert!(first.nickname.len() > 2);
+        assert_eq!(first.real_user_id, None);
+    }
+
+    #[test]
+    fn swaps_identity_consistently() {
+        let mut replay = Replay::default();
+        replay.state().identity = IdentityRandomizer::with_seed(7);
+        replay
+            .state()
+            .update_settings(|s| s.identity_randomizer = true);
+
+        let authenticate = |server| {
+            request(
+                server,
+                operation_code::AUTHENTICATE,
+                indexmap! {
+                    parameter_code::APP_VERSION => string("1.90.0_1.99"),
+                    parameter_code::USER_ID => string("me"),
+                },
+            )
+        };
+        let sent = forward(&mut replay, authenticate(WebSocketServer::NameServer));
+        let identity = replay.state().identity.identity().unwrap().clone();
+        assert_eq!(sent[&parameter_code::USER_ID], string(&identity.user_id));
+        assert_eq!(sent[&parameter_code::APP_VERSION], string("1.90.0_1.99"));
+
+        // the server echoing the generated id is shown to the client as ours
+        let received = forward(
+            &mut replay,
+            response(
+                WebSocketServer::NameServer,
+                operation_code::AUTHENTICATE,
+                indexmap! {
+                    parameter_code::USER_ID => string(&identity.user_id),
+                    parameter_code::ADDRESS => string("wss://lobby.example.com"),
+                },
+            ),
+        );
+        assert_eq!(received[&parameter_code::USER_ID], string("me"));
+
+        // every later connection presents the same identity
+        for server in [WebSocketServer::LobbyServer, WebSocketServer::GameServer] {
+            let sent = forward(&mut replay, authenticate(server));
+            assert_eq!(sent[&parameter_code::USER_ID], string(&identity.user_id));
+        }
+
+        let sent = forward(
+            &mut replay,
+            request(
+                WebSocketServer::GameServer,
+                operation_code::JOIN_GAME,
+                indexmap! {
+                    parameter_code::ROOM_NAME => string("room"),
+                    parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Byte(255) => string("Sniper"),
+                        string("teamNumber") => PhotonDataType::Byte(1),
+                    }),
+                },
+            ),
+        );
+        assert_eq!(
+            sent[&parameter_code::PLAYER_PROPERTIES],
+            PhotonDataType::Hashtable(indexmap! {
+                PhotonDataType::Byte(255) => string(&identity.nickname),
+                string("teamNumber") => PhotonDataType::Byte(1),
+            })
+        );
+
+        // the actor list of the join response, with another player using the same name
+        let received = forward(
+            &mut replay,
+            response(
+                WebSocketServer::GameServer,
+                operation_code::JOIN_GAME,
+                indexmap! {
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(2),
+                    parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Integer(1) => PhotonDataType::Hashtable(indexmap! {
+                            PhotonDataType::Byte(255) => string("Sniper"),
+                            PhotonDataType::Byte(253) => string("someone"),
+                        }),
+                        PhotonDataType::Integer(2) => PhotonDataType::Hashtable(indexmap! {
+                            PhotonDataType::Byte(255) => string(&identity.nickname),
+                            PhotonDataType::Byte(253) => string(&identity.user_id),
+                        }),
+                    }),
+                    parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                },
+            ),
+        );
+        assert_eq!(
+            received[&parameter_code::PLAYER_PROPERTIES],
+            PhotonDataType::Hashtable(indexmap! {
+                PhotonDataType::Integer(1) => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::Byte(255) => string("Sniper"),
+                    PhotonDataType::Byte(253) => string("someone"),
+                }),
+                PhotonDataType::Integer(2) => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::Byte(255) => string("Sniper"),
+                    PhotonDataType::Byte(253) => string("me"),
+                }),
+            })
+        );
+
+        // renaming ourselves keeps the generated name, only our own properties are swapped
+        let set_name = |actor, name| {
+            request(
+                WebSocketServer::GameServer,
+                operation_code::SET_PROPERTIES,
+                indexmap! {
+                    parameter_code::PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Byte(255) => string(name),
+                    }),
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(actor),
+                    parameter_code::BROADCAST => PhotonDataType::Boolean(true),
+                },
+            )
+        };
+        let sent = forward(&mut replay, set_name(2, "Sniper2"));
+        assert_eq!(
+            sent[&parameter_code::PROPERTIES],
+            PhotonDataType::Hashtable(
+                indexmap! { PhotonDataType::Byte(255) => string(&identity.nickname) }
+            )
+        );
+        let sent = forward(&mut replay, set_name(1, "Sniper2"));
+        assert_eq!(
+            sent[&parameter_code::PROPERTIES],
+            PhotonDataType::Hashtable(indexmap! { PhotonDataType::Byte(255) => string("Sniper2") })
+        );
+
+        let received = forward(
+            &mut replay,
+            properties_changed(
+                2,
+                PhotonDataType::Hashtable(
+                    indexmap! { PhotonDataType::Byte(255) => string(&identity.nickname) },
+                ),
+            ),
+        );
+        assert_eq!(
+            received[&parameter_code::PROPERTIES],
+            PhotonDataType::Hashtable(indexmap! { PhotonDataType::Byte(255) => string("Sniper2") })
+        );
+
+        let state = replay.state();
+        assert!(state.stats.recent_errors.is_empty());
+        assert_eq!(state.global_state.user_id.as_deref(), Some("me"));
+        let (_, gameplay) = state.gameplay_state.as_ref().unwrap();
+        assert_eq!(gameplay.players[&2].nickname.as_deref(), Some("Sniper2"));
+        assert_eq!(
+            state.identity.identity().unwrap().real_nickname.as_deref(),
+            Some("Sniper2")
+        );
+        assert!(
+            state.bandwidth.report().totals[feature::IDENTITY_RANDOMIZER].rewritten_messages > 0
+        );
+
+        let report = state.status_report(true);
+        assert!(report.contains(&format!(
+            "presented as: {} with user id {}, instead of <redacted>",
+            identity.nickname, identity.user_id
+        )));
+        assert!(report.contains("[randomize identity]"));
+    }
+}
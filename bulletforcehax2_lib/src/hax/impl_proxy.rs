@@ -8,7 +8,11 @@ use tracing::{debug, info, warn};
 
 use super::{BulletForceHax, HaxState};
 use crate::{
-    hax::{GameplayState, LobbyState},
+    hax::{
+        journal::{ChangedKey, Section},
+        match_summary::MatchEnd,
+        ui_snapshot, GameplayState, LobbyState, NameServerState,
+    },
     proxy::{websocket_proxy::WebSocketProxy, WebSocketServer},
 };
 
@@ -28,18 +32,56 @@ impl BulletForceHax {
         tokio::spawn(async move {
             Self::store_new_connections_in_state_vars(state, new_connection_recv).await;
         });
+        tokio::spawn(ui_snapshot::run_publisher(self.state.clone()));
 
         crate::proxy::websocket_proxy::create_service(new_connection_send, self.state.clone())
     }
 
     // bookkeeping to ensure the websocket connection gets written and unwritten to the right variable
-    async fn store_new_connections_in_state_vars(
+    pub(crate) async fn store_new_connections_in_state_vars(
         state: Arc<Mutex<HaxState>>,
         mut new_connection_recv: Receiver<WebSocketProxy>,
     ) {
         debug!("Received new websocket proxy to store in state variable");
         while let Some(mut conn) = new_connection_recv.recv().await {
             match conn.get_server_type() {
+                // name server
+                Some(WebSocketServer::NameServer) => {
+                    let notify_closed = conn.take_notify_closed();
+
+                    let state = state.clone();
+                    {
+                        let mut locked_state = state.lock().await;
+                        if locked_state.nameserver_state.is_some() {
+                            warn!("name server socket connection created while one already existed! did it not get cleared correctly?");
+                        }
+                        locked_state
+                            .disconnects
+                            .connection_opened(WebSocketServer::NameServer);
+                        locked_state.nameserver_state = Some((conn, NameServerState::default()));
+                    }
+
+                    match notify_closed {
+                        Some(n) => {
+                            // create task to clear the socket variable when the connection dies
+                            tokio::spawn(async move {
+                                // wait for the socket to close
+                                n.notified().await;
+
+                                info!("name server websocket closed");
+                                let mut locked_state = state.lock().await;
+                                if locked_state.nameserver_state.is_none() {
+                                    warn!("name server socket connection was closed but it did not exist yet");
+                                }
+                                locked_state.nameserver_state = None;
+                                locked_state.connection_disconnected(WebSocketServer::NameServer);
+                                locked_state.encryption.connection_closed(WebSocketServer::NameServer);
+                                locked_state.parse_breaker.connection_closed(WebSocketServer::NameServer);
+                            });
+                        }
+                        None => warn!("A name server websocket task was created but no closed Notify was found. Detecting socket closing will not work"),
+                    }
+                }
                 // lobby
                 Some(WebSocketServer::LobbyServer) => {
                     let notify_closed = conn.take_notify_closed();
@@ -50,6 +92,9 @@ impl BulletForceHax {
                         if locked_state.lobby_state.is_some() {
                             warn!("lobby socket connection created while one already existed! did it not get cleared correctly?");
                         }
+                        locked_state
+                            .disconnects
+                            .connection_opened(WebSocketServer::LobbyServer);
                         locked_state.lobby_state = Some((conn, LobbyState::default()));
                     }
 
@@ -65,7 +110,14 @@ impl BulletForceHax {
                                 if locked_state.lobby_state.is_none() {
                                     warn!("lobby socket connection was closed but it did not exist yet");
                                 }
-                                locked_state.lobby_state = None;
+                                locked_state.connection_disconnected(WebSocketServer::LobbyServer);
+                                if let Some((_, lobby)) = locked_state.lobby_state.take() {
+                                    for (id, _) in lobby.rooms.iter() {
+                                        locked_state.journal.record(Section::Lobby, ChangedKey::LobbyRoom(id.to_string()));
+                                    }
+                                }
+                                locked_state.encryption.connection_closed(WebSocketServer::LobbyServer);
+                                locked_state.parse_breaker.connection_closed(WebSocketServer::LobbyServer);
                             });
                         }
                         None => warn!("A lobby websocket task was created but no closed Notify was found. Detecting socket closing will not work"),
@@ -76,12 +128,19 @@ impl BulletForceHax {
                     let notify_closed = conn.take_notify_closed();
 
                     let state = state.clone();
+                    let id = conn.id();
                     {
                         let mut locked_state = state.lock().await;
-                        if locked_state.gameplay_state.is_some() {
-                            warn!("gameplay socket connection created while one already existed! did it not get cleared correctly?");
-                        }
-                        locked_state.gameplay_state = Some((conn, GameplayState::default()));
+                        // the old connection is still open, so the server moved us to another game server
+                        let migrated_from = locked_state.migrate_game_server(conn.get_port());
+                        locked_state
+                            .disconnects
+                            .connection_opened(WebSocketServer::GameServer);
+                        let gameplay = GameplayState {
+                            migrated_from,
+                            ..Default::default()
+                        };
+                        locked_state.gameplay_state = Some((conn, gameplay));
                     }
 
                     match notify_closed {
@@ -93,10 +152,28 @@ impl BulletForceHax {
 
                                 info!("gameplay websocket closed");
                                 let mut locked_state = state.lock().await;
-                                if locked_state.gameplay_state.is_none() {
-                                    warn!("gameplay socket connection was closed but it did not exist yet");
+                                match &locked_state.gameplay_state {
+                                    None => warn!("gameplay socket connection was closed but it did not exist yet"),
+                                    Some((conn, _)) if conn.id() != id => {
+                                        // its game was closed out when the new connection came in
+                                        info!("gameplay websocket of the previous game server closed after migrating");
+                                        // its close frames got mixed into the new connection's signals, so both are dropped
+                                        locked_state.disconnects.connection_opened(WebSocketServer::GameServer);
+                                        return;
+                                    }
+                                    Some(_) => (),
+                                }
+                                locked_state.connection_disconnected(WebSocketServer::GameServer);
+                                // only produces a summary if we didn't leave the room before
+                                locked_state.finish_match(MatchEnd::Disconnected);
+                                if let Some((_, gameplay)) = locked_state.gameplay_state.take() {
+                                    locked_state.journal.record_room_changed(gameplay.players.keys().copied());
                                 }
-                                locked_state.gameplay_state = None;
+                                locked_state.encryption.connection_closed(WebSocketServer::GameServer);
+                                locked_state.parse_breaker.connection_closed(WebSocketServer::GameServer);
+                                #[cfg(feature = "shared_state")]
+                                locked_state.publish_shared_state();
+                                locked_state.log_drift_report();
                             });
                         }
                         None => warn!("A gameplay websocket task was created but no closed Notify was found. Detecting socket closing will not work"),
@@ -112,3 +189,257 @@ impl BulletForceHax {
         debug!("websocket proxy receiver closed");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use futures_util::lock::Mutex;
+    use photon_lib::{
+        highlevel::{
+            constants::{event_code, operation_code, parameter_code, pun_event_code},
+            structs::RoomInfoList,
+            PhotonParameterMapConversion,
+        },
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationResponse, PhotonMessage},
+    };
+
+    use crate::{
+        hax::{
+            events::HaxEvent,
+            match_summary::MatchEnd,
+            settings::{Settings, SharedSettings},
+            HaxState,
+        },
+        proxy::WebSocketServer,
+        testsupport::{next_event, ProxiedConnection},
+    };
+
+    /// An event the hooks don't handle, to check that everything sent before it was delivered or dropped.
+    fn marker() -> PhotonMessage {
+        PhotonMessage::EventData(EventData {
+            code: 123,
+            parameters: indexmap! {},
+        })
+    }
+
+    fn game_list() -> PhotonMessage {
+        PhotonMessage::EventData(EventData {
+            code: event_code::GAME_LIST,
+            parameters: indexmap! {
+                parameter_code::GAME_LIST => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::String("room".into()) => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::String("roomName".into()) => PhotonDataType::String("locked".into()),
+                        PhotonDataType::String("password".into()) => PhotonDataType::String("hunter2".into()),
+                    }),
+                }),
+            },
+        })
+    }
+
+    /// The name and password of the single room in a game list.
+    fn room_name_and_password(message: PhotonMessage) -> (String, String) {
+        let mut event = match message {
+            PhotonMessage::EventData(event) if event.code == event_code::GAME_LIST => event,
+            other => panic!("expected a game list, got {other:?}"),
+        };
+        let list = RoomInfoList::from_map(&mut event.parameters).unwrap();
+        let room = match list.games.values().next() {
+            Some(PhotonDataType::Hashtable(room)) => room,
+            other => panic!("expected a room, got {other:?}"),
+        };
+        let custom = |key: &str| match room.get(&PhotonDataType::String(key.into())) {
+            Some(PhotonDataType::String(value)) => value.clone(),
+            other => panic!("expected a string for {key}, got {other:?}"),
+        };
+        (custom("roomName"), custom("password"))
+    }
+
+    #[tokio::test]
+    async fn lobby_list_is_rewritten() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut conn =
+            ProxiedConnection::connect(state.clone(), WebSocketServer::LobbyServer).await;
+
+        conn.server.send(game_list());
+        assert_eq!(
+            room_name_and_password(conn.client_recv().await),
+            ("locked".into(), "hunter2".into())
+        );
+
+        state
+            .lock()
+            .await
+            .update_settings(|s| s.strip_passwords = true);
+        conn.server.send(game_list());
+        assert_eq!(
+            room_name_and_password(conn.client_recv().await),
+            ("[p] locked".into(), "".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn join_populates_players() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut conn = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+
+        let player = |name: &str| {
+            PhotonDataType::Hashtable(indexmap! {
+                PhotonDataType::Byte(255) => PhotonDataType::String(name.into()),
+            })
+        };
+        conn.server.send_all([
+            PhotonMessage::OperationResponse(OperationResponse {
+                operation_code: operation_code::JOIN_GAME,
+                return_code: 0,
+                debug_message: None,
+                parameters: indexmap! {
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                    parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Integer(1) => player("me"),
+                        PhotonDataType::Integer(2) => player("someone"),
+                    }),
+                    parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                },
+            }),
+            PhotonMessage::EventData(EventData {
+                code: event_code::JOIN,
+                parameters: indexmap! {
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(3),
+                    parameter_code::ACTOR_LIST => PhotonDataType::Array(
+                        (1..=3).map(PhotonDataType::Integer).collect()
+                    ),
+                },
+            }),
+        ]);
+        assert!(matches!(
+            conn.client_recv().await,
+            PhotonMessage::OperationResponse(_)
+        ));
+        assert!(matches!(
+            conn.client_recv().await,
+            PhotonMessage::EventData(_)
+        ));
+
+        let hax = state.lock().await;
+        let (_, gameplay) = hax.gameplay_state.as_ref().unwrap();
+        assert_eq!(gameplay.player_id, Some(1));
+        assert_eq!(gameplay.actor_nr, Some(3));
+        assert_eq!(
+            gameplay.players.keys().copied().collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        assert_eq!(gameplay.players[&2].nickname.as_deref(), Some("someone"));
+    }
+
+    fn join_room(room_name: &str) -> PhotonMessage {
+        PhotonMessage::OperationResponse(OperationResponse {
+            operation_code: operation_code::JOIN_GAME,
+            return_code: 0,
+            debug_message: None,
+            parameters: indexmap! {
+                parameter_code::ROOM_NAME => PhotonDataType::String(room_name.into()),
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::Integer(2) => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Byte(255) => PhotonDataType::String("someone".into()),
+                    }),
+                }),
+                parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn game_server_migration() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut events = state.lock().await.events.subscribe();
+        let mut old = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        old.server.send_all([
+            join_room("room"),
+            // the old server announces where to go
+            PhotonMessage::OperationResponse(OperationResponse {
+                operation_code: operation_code::AUTHENTICATE,
+                return_code: 0,
+                debug_message: None,
+                parameters: indexmap! {
+                    parameter_code::ADDRESS => PhotonDataType::String("wss://gs2.example:2083".into()),
+                },
+            }),
+        ]);
+        old.client_recv().await;
+        old.client_recv().await;
+
+        let mut new = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        let first = next_event(&mut events, |e| match e {
+            HaxEvent::MatchFinished(summary) => Some(summary),
+            _ => None,
+        })
+        .await;
+        assert_eq!(first.ended_by, MatchEnd::Migrated);
+        assert_eq!(first.room_name.as_deref(), Some("room"));
+        let migration = next_event(&mut events, |e| match e {
+            HaxEvent::GameServerMigrated(migration) => Some(migration),
+            _ => None,
+        })
+        .await;
+        assert_ne!(migration.from_port, migration.to_port);
+        assert_eq!(migration.room_name.as_deref(), Some("room"));
+        assert_eq!(
+            migration.announced.as_deref(),
+            Some("wss://gs2.example:2083")
+        );
+        {
+            let hax = state.lock().await;
+            let (_, gameplay) = hax.gameplay_state.as_ref().unwrap();
+            assert!(gameplay.players.is_empty());
+            assert_eq!(gameplay.room_name, None);
+        }
+
+        // the old connection closing must not clear the new one
+        old.close().await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(state.lock().await.gameplay_state.is_some());
+
+        new.server.send(join_room("room"));
+        new.client_recv().await;
+        let resumed = next_event(&mut events, |e| match e {
+            HaxEvent::MigratedMatchResumed { room_name } => Some(room_name),
+            _ => None,
+        })
+        .await;
+        assert_eq!(resumed, "room");
+        let second = state.lock().await.finish_match(MatchEnd::Left).unwrap();
+        assert_eq!(second.continues, Some(first.started));
+        assert_eq!(second.players.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn dropped_message_is_not_delivered() {
+        let state = Arc::new(Mutex::new(HaxState {
+            settings: SharedSettings::new(Settings {
+                mute_all_cosmetic: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }));
+        let mut conn = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+
+        conn.server.send_all([
+            PhotonMessage::EventData(EventData {
+                code: pun_event_code::RPC,
+                parameters: indexmap! {
+                    parameter_code::CUSTOM_EVENT_CONTENT => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Byte(0) => PhotonDataType::Integer(1001),
+                        PhotonDataType::Byte(3) => PhotonDataType::String("WeaponCamoChanged".into()),
+                    }),
+                },
+            }),
+            marker(),
+        ]);
+        assert_eq!(conn.client_recv().await, marker());
+        assert_eq!(state.lock().await.stats.muted_rpcs[&1], 1);
+    }
+}
@@ -0,0 +1,244 @@
+//! Keeps track of the interest groups the client is subscribed to.
+//!
+//! Events raised for an interest group other than 0 are only delivered to the players that subscribed to it through
+//! [CHANGE_GROUPS](photon_lib::highlevel::constants::operation_code::CHANGE_GROUPS).
+
+use std::{collections::BTreeSet, fmt::Display};
+
+use photon_lib::highlevel::structs::ChangeGroupsRequest;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterestGroups {
+    /// Subscribed to all groups that existed when joining them. The server doesn't tell us which ones those were.
+    all: bool,
+    /// Groups that were left after subscribing to all groups.
+    excluded: BTreeSet<u8>,
+    groups: BTreeSet<u8>,
+}
+
+impl InterestGroups {
+    /// Applies a request of the client, removing groups before adding others like the server does.
+    pub fn apply(&mut self, change: &ChangeGroupsRequest) {
+        match change.remove.as_deref() {
+            Some([]) => *self = Self::default(),
+            Some(groups) => {
+                for group in groups {
+                    self.groups.remove(group);
+                    if self.all {
+                        self.excluded.insert(*group);
+                    }
+                }
+            }
+            None => (),
+        }
+        match change.add.as_deref() {
+            Some([]) => {
+                self.all = true;
+                self.excluded.clear();
+            }
+            Some(groups) => {
+                for group in groups {
+                    self.groups.insert(*group);
+                    self.excluded.remove(group);
+                }
+            }
+            None => (),
+        }
+    }
+
+    /// Whether events raised for this group reach the client. Everyone receives group 0.
+    pub fn receives(&self, group: u8) -> bool {
+        group == 0 || self.groups.contains(&group) || (self.all && !self.excluded.contains(&group))
+    }
+
+    /// Whether the client subscribed to all groups, see [Self::receives] for single groups.
+    pub fn is_subscribed_to_all(&self) -> bool {
+        self.all
+    }
+
+    /// The groups the client explicitly subscribed to.
+    pub fn groups(&self) -> impl Iterator<Item = u8> + '_ {
+        self.groups.iter().copied()
+    }
+
+    /// The requests that subscribe to exactly these groups, whatever the client was subscribed to before.
+    pub fn to_requests(&self) -> Vec<ChangeGroupsRequest> {
+        let groups = (!self.groups.is_empty()).then(|| self.groups().collect());
+        if !self.all {
+            return vec![ChangeGroupsRequest {
+                remove: Some(vec![]),
+                add: groups,
+            }];
+        }
+        // a request joins either all groups or specific ones, so the explicit groups need a second one
+        vec![
+            ChangeGroupsRequest {
+                remove: Some(vec![]),
+                add: Some(vec![]),
+            },
+            ChangeGroupsRequest {
+                remove: (!self.excluded.is_empty())
+                    .then(|| self.excluded.iter().copied().collect()),
+                add: groups,
+            },
+        ]
+    }
+}
+
+impl Display for InterestGroups {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let groups = self.groups().map(|g| g.to_string()).collect::<Vec<_>>();
+        match (self.all, groups.is_empty()) {
+            (false, true) => write!(f, "none")?,
+            (false, false) => write!(f, "{}", groups.join(", "))?,
+            (true, _) => {
+                write!(f, "all")?;
+                if !self.excluded.is_empty() {
+                    let excluded = self.excluded.iter().map(|g| g.to_string());
+                    write!(f, " except {}", excluded.collect::<Vec<_>>().join(", "))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::{
+        highlevel::{
+            constants::{operation_code, parameter_code},
+            structs::ChangeGroupsRequest,
+            PhotonParameterMapConversion,
+        },
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, PhotonMessage},
+        ParameterMap,
+    };
+
+    use super::InterestGroups;
+    use crate::{
+        hax::{timeline::Replay, PlayerActor},
+        inspect::{message_options, CapturedMessage},
+        proxy::Direction,
+        testsupport::{captured, request},
+    };
+
+    fn change(remove: Option<&[u8]>, add: Option<&[u8]>) -> ChangeGroupsRequest {
+        ChangeGroupsRequest {
+            remove: remove.map(|r| r.to_vec()),
+            add: add.map(|a| a.to_vec()),
+        }
+    }
+
+    #[test]
+    fn subscriptions() {
+        let mut groups = InterestGroups::default();
+        assert_eq!(groups.to_string(), "none");
+        assert!(groups.receives(0));
+        assert!(!groups.receives(1));
+
+        groups.apply(&change(None, Some(&[1, 3])));
+        groups.apply(&change(Some(&[3]), Some(&[4])));
+        assert_eq!(groups.to_string(), "1, 4");
+        assert!(groups.receives(4) && !groups.receives(3));
+
+        // remove is applied before add
+        groups.apply(&change(Some(&[]), Some(&[2])));
+        assert_eq!(groups.to_string(), "2");
+
+        groups.apply(&change(Some(&[]), Some(&[])));
+        groups.apply(&change(Some(&[5]), None));
+        assert!(groups.is_subscribed_to_all());
+        assert_eq!(groups.to_string(), "all except 5");
+        assert!(groups.receives(6) && !groups.receives(5));
+
+        groups.apply(&change(None, Some(&[5])));
+        assert!(groups.receives(5));
+    }
+
+    #[test]
+    fn restores_subscriptions() {
+        let cases = [
+            vec![],
+            vec![change(None, Some(&[1, 3]))],
+            vec![change(None, Some(&[])), change(Some(&[2, 4]), None)],
+            vec![change(None, Some(&[])), change(Some(&[2]), Some(&[5]))],
+        ];
+        for changes in cases {
+            let mut groups = InterestGroups::default();
+            for change in &changes {
+                groups.apply(change);
+            }
+
+            // starting from something else entirely
+            let mut restored = InterestGroups::default();
+            restored.apply(&change(None, Some(&[7])));
+            for request in groups.to_requests() {
+                restored.apply(&request);
+            }
+            assert_eq!(restored, groups);
+        }
+    }
+
+    fn change_request(change: ChangeGroupsRequest) -> CapturedMessage {
+        let mut parameters = ParameterMap::new();
+        change.into_map(&mut parameters);
+        request(operation_code::CHANGE_GROUPS, parameters)
+    }
+
+    #[test]
+    fn receive_all_groups() {
+        let mut replay = Replay::default();
+        replay.feed(&request(operation_code::AUTHENTICATE, indexmap! {}));
+        replay.state().receive_all_groups = true;
+
+        let forwarded = replay
+            .forward(&change_request(change(Some(&[]), Some(&[2]))))
+            .unwrap();
+        match PhotonMessage::from_websocket_bytes(&mut forwarded.as_slice()).unwrap() {
+            PhotonMessage::OperationRequest(mut request) => assert_eq!(
+                ChangeGroupsRequest::from_map(&mut request.parameters).unwrap(),
+                change(Some(&[]), Some(&[]))
+            ),
+            other => panic!("expected a CHANGE_GROUPS request, got {other:?}"),
+        }
+
+        {
+            let mut state = replay.state();
+            let (_, gameplay) = state.gameplay_state.as_mut().unwrap();
+            assert!(gameplay.interest_groups.is_subscribed_to_all());
+            assert_eq!(gameplay.requested_groups.to_string(), "2");
+            gameplay.players.insert(
+                3,
+                PlayerActor {
+                    interest_group: Some(5),
+                    ..Default::default()
+                },
+            );
+            assert!(gameplay.expects_updates_from(3));
+
+            // going back to what the client asked for, there's no live connection to send it on
+            state.set_receive_all_groups(false);
+            let (_, gameplay) = state.gameplay_state.as_ref().unwrap();
+            assert_eq!(gameplay.interest_groups, gameplay.requested_groups);
+            assert!(!gameplay.expects_updates_from(3));
+        }
+
+        // events tell which group they were raised in
+        let event = PhotonMessage::EventData(EventData {
+            code: 200,
+            parameters: indexmap! {
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(3),
+                parameter_code::GROUP => PhotonDataType::Byte(2),
+            },
+        });
+        assert_eq!(message_options(&event).as_deref(), Some("group 2"));
+        replay.feed(&captured(Direction::ServerToClient, event));
+        let state = replay.state();
+        let (_, gameplay) = state.gameplay_state.as_ref().unwrap();
+        assert_eq!(gameplay.players[&3].interest_group, Some(2));
+        assert!(gameplay.expects_updates_from(3));
+    }
+}
@@ -0,0 +1,171 @@
+//! Spans covering the client's way into a game, to see where a slow join spends its time.
+//!
+//! A join flow starts with the first web request, lobby authentication or join request after the previous flow ended,
+//! and ends with the first serialize event from the game server, which is when the game starts showing other players.
+//! Everything in between gets a span under the flow's root span:
+//!
+//! - `web request` for every request through the web request proxy, eg. matchmaking
+//! - `lobby authenticate` from the authentication request to its response
+//! - `game list` when the first game list arrives
+//! - `join request` from the request to join or create a room until the first serialize event
+//! - `first serialize` when that event arrives
+//!
+//! Every span carries the session id, so the flows of one session can be found together. The spans are regular
+//! [tracing] spans with the [TARGET] target, the steps are all called `join step` with the names above in their
+//! `otel.name` field. With the `otel` feature they can be exported to OpenTelemetry, see
+//! [telemetry](crate::telemetry).
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use photon_lib::{
+    highlevel::constants::{event_code, operation_code, pun_event_code},
+    photon_message::PhotonMessage,
+};
+use tracing::{info_span, Span};
+
+use crate::proxy::{Direction, WebSocketServer};
+
+/// The target of all join flow spans.
+pub const TARGET: &str = "bulletforcehax2_lib::join_trace";
+
+#[derive(Debug)]
+pub struct JoinTrace {
+    session_id: String,
+    /// The root span of the join flow in progress, if any.
+    flow: Option<Span>,
+    authenticate: Option<Span>,
+    join: Option<Span>,
+    saw_game_list: bool,
+}
+
+impl Default for JoinTrace {
+    fn default() -> Self {
+        // unique enough to tell the sessions of one machine apart
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Self::new(format!(
+            "{:016x}",
+            nanos ^ ((std::process::id() as u64) << 32)
+        ))
+    }
+}
+
+impl JoinTrace {
+    pub fn new(session_id: String) -> Self {
+        Self {
+            session_id,
+            flow: None,
+            authenticate: None,
+            join: None,
+            saw_game_list: false,
+        }
+    }
+
+    /// The id that all spans of this session carry.
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// Whether a join flow is in progress.
+    pub fn in_progress(&self) -> bool {
+        self.flow.is_some()
+    }
+
+    /// The root span of the flow in progress, starting a new flow if there is none.
+    fn flow(&mut self) -> &Span {
+        let session_id = &self.session_id;
+        self.flow.get_or_insert_with(|| {
+            info_span!(target: TARGET, parent: None, "join flow", session_id = session_id.as_str())
+        })
+    }
+
+    /// A span for a web request to the given url, to be entered while the request is made.
+    pub fn web_request(&mut self, url: &str) -> Span {
+        let session_id = self.session_id.clone();
+        let flow = self.flow();
+        info_span!(
+            target: TARGET,
+            parent: flow,
+            "join step",
+            otel.name = "web request",
+            session_id = session_id.as_str(),
+            url
+        )
+    }
+
+    /// Starts or ends spans for a websocket message the proxy received.
+    pub fn observe(
+        &mut self,
+        server: WebSocketServer,
+        direction: Direction,
+        message: &PhotonMessage,
+    ) {
+        use Direction::*;
+        use WebSocketServer::*;
+
+        match (server, direction, message) {
+            (LobbyServer, ClientToServer, PhotonMessage::OperationRequest(request))
+                if request.operation_code == operation_code::AUTHENTICATE =>
+            {
+                self.authenticate = Some(self.child("lobby authenticate"));
+            }
+            (LobbyServer, ServerToClient, PhotonMessage::OperationResponse(response))
+                if response.operation_code == operation_code::AUTHENTICATE =>
+            {
+                self.authenticate = None;
+            }
+            // the list is sent again after leaving a room, which isn't part of a join
+            (LobbyServer, ServerToClient, PhotonMessage::EventData(event))
+                if event.code == event_code::GAME_LIST
+                    && self.in_progress()
+                    && !self.saw_game_list =>
+            {
+                self.saw_game_list = true;
+                self.child("game list");
+            }
+            (LobbyServer, ClientToServer, PhotonMessage::OperationRequest(request))
+                if matches!(
+                    request.operation_code,
+                    operation_code::JOIN_GAME
+                        | operation_code::CREATE_GAME
+                        | operation_code::JOIN_RANDOM_GAME
+                ) =>
+            {
+                self.join = Some(self.child("join request"));
+            }
+            (GameServer, ServerToClient, PhotonMessage::EventData(event))
+                if matches!(
+                    event.code,
+                    pun_event_code::SEND_SERIALIZE | pun_event_code::SEND_SERIALIZE_RELIABLE
+                ) && self.in_progress() =>
+            {
+                self.child("first serialize");
+                self.end();
+            }
+            _ => (),
+        }
+    }
+
+    /// Ends the flow in progress and its spans.
+    pub fn end(&mut self) {
+        // children first, so they end before their parent
+        self.authenticate = None;
+        self.join = None;
+        self.flow = None;
+        self.saw_game_list = false;
+    }
+
+    fn child(&mut self, name: &'static str) -> Span {
+        let session_id = self.session_id.clone();
+        let flow = self.flow();
+        info_span!(
+            target: TARGET,
+            parent: flow,
+            "join step",
+            otel.name = name,
+            session_id = session_id.as_str()
+        )
+    }
+}
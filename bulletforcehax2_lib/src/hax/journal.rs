@@ -0,0 +1,408 @@
+//! Which parts of the state changed, so views can update what changed since they last looked instead of diffing
+//! everything.
+//!
+//! Every tracked change bumps the state's revision and is noted in a bounded journal. A view remembers the revision
+//! it last saw and asks for the changes since, see [HaxState::changes_since](super::HaxState::changes_since). If the
+//! journal no longer goes back that far, the view is told to read everything again.
+//!
+//! Values that change with every serialized update, such as positions and health, aren't journaled. They would flush
+//! the journal within seconds, and views showing them redraw them anyway.
+
+use std::{collections::VecDeque, fmt::Display, sync::Arc};
+
+use photon_lib::indexmap::{IndexMap, IndexSet};
+
+use super::{lobby_cache::CachedRoom, settings::Settings, GameplayState, LobbyState, PlayerActor};
+
+/// How many changes to keep.
+const MAX_ENTRIES: usize = 1024;
+
+/// The parts of the state that changes are tracked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Section {
+    /// Who is in the room we're in, and their properties.
+    Players,
+    /// The room we're in.
+    Room,
+    /// The kills and deaths of the players in the room.
+    Scoreboard,
+    /// The rooms listed in the lobby.
+    Lobby,
+    Settings,
+}
+
+impl Display for Section {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Section::Players => write!(f, "players"),
+            Section::Room => write!(f, "room"),
+            Section::Scoreboard => write!(f, "scoreboard"),
+            Section::Lobby => write!(f, "lobby"),
+            Section::Settings => write!(f, "settings"),
+        }
+    }
+}
+
+/// What changed within a section.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ChangedKey {
+    Actor(i32),
+    LobbyRoom(String),
+    /// A field of a section that isn't keyed, such as the room's map.
+    Field(&'static str),
+}
+
+/// The room fields that are journaled.
+pub mod room_field {
+    pub const ROOM_NAME: &str = "room name";
+    pub const MAP_NAME: &str = "map name";
+    pub const MASTER_CLIENT: &str = "master client";
+}
+
+/// The most recent changes to the state, see the [module docs](self).
+#[derive(Debug, Default)]
+pub struct ChangeJournal {
+    revision: u64,
+    entries: VecDeque<(u64, Section, ChangedKey)>,
+    /// The settings as of the last sync. Settings are changed without the state's lock, so they're compared when
+    /// changes are asked for instead of being recorded as they change.
+    settings: Option<Arc<Settings>>,
+}
+
+impl ChangeJournal {
+    /// The revision of the latest change.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn record(&mut self, section: Section, key: ChangedKey) {
+        self.revision += 1;
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((self.revision, section, key));
+    }
+
+    pub fn record_actors(&mut self, section: Section, actors: impl IntoIterator<Item = i32>) {
+        for actor_id in actors {
+            self.record(section, ChangedKey::Actor(actor_id));
+        }
+    }
+
+    /// Records the room and the given players as changed, for when we join or leave a room.
+    pub fn record_room_changed(&mut self, actors: impl IntoIterator<Item = i32>) {
+        for actor_id in actors {
+            self.record(Section::Players, ChangedKey::Actor(actor_id));
+            self.record(Section::Scoreboard, ChangedKey::Actor(actor_id));
+        }
+        self.record(Section::Room, ChangedKey::Field(room_field::ROOM_NAME));
+        self.record(Section::Room, ChangedKey::Field(room_field::MAP_NAME));
+        self.record(Section::Room, ChangedKey::Field(room_field::MASTER_CLIENT));
+    }
+
+    /// Records a settings change if the settings differ from those of the last sync.
+    pub fn sync_settings(&mut self, settings: Arc<Settings>) {
+        let changed = match &self.settings {
+            Some(last) => !Arc::ptr_eq(last, &settings) && **last != *settings,
+            None => false,
+        };
+        self.settings = Some(settings);
+        if changed {
+            self.record(Section::Settings, ChangedKey::Field("settings"));
+        }
+    }
+
+    /// The keys changed after the given revision, by section. None if some of those changes were already forgotten,
+    /// or if the revision is newer than the journal.
+    pub fn changed_since(&self, revision: u64) -> Option<IndexMap<Section, IndexSet<ChangedKey>>> {
+        let oldest = self
+            .entries
+            .front()
+            .map_or(self.revision + 1, |(revision, _, _)| *revision);
+        if revision > self.revision || revision + 1 < oldest {
+            return None;
+        }
+
+        let mut changed = IndexMap::<Section, IndexSet<ChangedKey>>::new();
+        for (_, section, key) in self.entries.iter().filter(|(r, _, _)| *r > revision) {
+            changed.entry(*section).or_default().insert(key.clone());
+        }
+        Some(changed)
+    }
+}
+
+/// A player's score in the current round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScoreLine {
+    pub kills: Option<i16>,
+    pub deaths: Option<i16>,
+}
+
+/// The room fields as they are now, along with which of them changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomDelta {
+    pub changed: Vec<&'static str>,
+    pub room_name: Option<String>,
+    pub map_name: Option<String>,
+    pub master_client: Option<i32>,
+}
+
+/// What changed since a revision, with the current values of what changed. Entries that are None were removed.
+#[derive(Debug, Default)]
+pub struct StateDelta {
+    /// The revision to ask for the changes since next time.
+    pub revision: u64,
+    /// Whether the changes since the asked revision are no longer known. Everything should be read again, the
+    /// sections are left empty.
+    pub resync: bool,
+    pub players: IndexMap<i32, Option<PlayerActor>>,
+    pub room: Option<RoomDelta>,
+    pub scoreboard: IndexMap<i32, Option<ScoreLine>>,
+    pub lobby: IndexMap<String, Option<CachedRoom>>,
+    pub settings: Option<Arc<Settings>>,
+}
+
+impl StateDelta {
+    /// Reads the current values of what changed.
+    pub(super) fn read(
+        revision: u64,
+        changed: Option<IndexMap<Section, IndexSet<ChangedKey>>>,
+        gameplay: Option<&GameplayState>,
+        lobby: Option<&LobbyState>,
+        settings: Arc<Settings>,
+    ) -> Self {
+        let mut delta = StateDelta {
+            revision,
+            ..Default::default()
+        };
+        let changed = match changed {
+            Some(x) => x,
+            None => {
+                delta.resync = true;
+                return delta;
+            }
+        };
+        for (section, keys) in changed {
+            match section {
+                Section::Players => {
+                    for actor_id in actor_keys(&keys) {
+                        let player = gameplay.and_then(|s| s.players.get(&actor_id));
+                        delta.players.insert(actor_id, player.cloned());
+                    }
+                }
+                Section::Scoreboard => {
+                    for actor_id in actor_keys(&keys) {
+                        let player = gameplay.and_then(|s| s.players.get(&actor_id));
+                        let score = player.map(|p| ScoreLine {
+                            kills: p.kills,
+                            deaths: p.deaths,
+                        });
+                        delta.scoreboard.insert(actor_id, score);
+                    }
+                }
+                Section::Room => {
+                    delta.room = Some(RoomDelta {
+                        changed: keys
+                            .iter()
+                            .filter_map(|key| match key {
+                                ChangedKey::Field(field) => Some(*field),
+                                _ => None,
+                            })
+                            .collect(),
+                        room_name: gameplay.and_then(|s| s.room_name.clone()),
+                        map_name: gameplay.and_then(|s| s.map_name.clone()),
+                        master_client: gameplay.and_then(|s| s.master_client),
+                    });
+                }
+                Section::Lobby => {
+                    for key in keys {
+                        if let ChangedKey::LobbyRoom(id) = key {
+                            let room = lobby.and_then(|l| l.rooms.get(&id)).cloned();
+                            delta.lobby.insert(id, room);
+                        }
+                    }
+                }
+                Section::Settings => delta.settings = Some(settings.clone()),
+            }
+        }
+        delta
+    }
+
+    /// Whether nothing changed. A delta that needs a resync isn't empty.
+    pub fn is_empty(&self) -> bool {
+        !self.resync
+            && self.players.is_empty()
+            && self.room.is_none()
+            && self.scoreboard.is_empty()
+            && self.lobby.is_empty()
+            && self.settings.is_none()
+    }
+}
+
+fn actor_keys(keys: &IndexSet<ChangedKey>) -> impl Iterator<Item = i32> + '_ {
+    keys.iter().filter_map(|key| match key {
+        ChangedKey::Actor(actor_id) => Some(*actor_id),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::{
+        highlevel::constants::{event_code, operation_code, parameter_code},
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{OperationRequest, OperationResponse, PhotonMessage},
+    };
+
+    use super::{room_field, ChangeJournal, ChangedKey, Section, MAX_ENTRIES};
+    use crate::{
+        hax::timeline::Replay,
+        proxy::Direction,
+        testsupport::{captured, event},
+    };
+
+    fn nickname(name: &str) -> PhotonDataType {
+        PhotonDataType::Hashtable(indexmap! {
+            PhotonDataType::Byte(255) => PhotonDataType::String(name.into()),
+        })
+    }
+
+    /// Joins a room with actors 1 and 2 in it.
+    fn joined_room() -> Replay {
+        let mut replay = Replay::default();
+        replay.feed(&captured(
+            Direction::ClientToServer,
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::AUTHENTICATE,
+                parameters: indexmap! {},
+            }),
+        ));
+        replay.feed(&captured(
+            Direction::ServerToClient,
+            PhotonMessage::OperationResponse(OperationResponse {
+                operation_code: operation_code::JOIN_GAME,
+                return_code: 0,
+                debug_message: None,
+                parameters: indexmap! {
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                    parameter_code::ROOM_NAME => PhotonDataType::String("room".into()),
+                    parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Integer(1) => nickname("me"),
+                        PhotonDataType::Integer(2) => nickname("them"),
+                    }),
+                    parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                },
+            }),
+        ));
+        assert!(replay.state().stats.recent_errors.is_empty());
+        replay
+    }
+
+    #[test]
+    fn untouched_sections_stay_empty() {
+        let mut replay = joined_room();
+        let delta = replay.state().changes_since(0);
+        assert!(!delta.resync);
+        assert_eq!(delta.players.keys().collect::<Vec<_>>(), [&1, &2]);
+        let room = delta.room.unwrap();
+        assert_eq!(
+            room.changed,
+            [
+                room_field::ROOM_NAME,
+                room_field::MAP_NAME,
+                room_field::MASTER_CLIENT
+            ]
+        );
+        assert_eq!(room.room_name.as_deref(), Some("room"));
+        assert!(delta.lobby.is_empty());
+        assert!(delta.settings.is_none());
+
+        let seen = delta.revision;
+        assert!(replay.state().changes_since(seen).is_empty());
+
+        replay.feed(&event(
+            event_code::PROPERTIES_CHANGED,
+            indexmap! {
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(2),
+                parameter_code::TARGET_ACTOR_NR => PhotonDataType::Integer(2),
+                parameter_code::PROPERTIES => nickname("renamed"),
+            },
+        ));
+        let delta = replay.state().changes_since(seen);
+        assert_eq!(delta.players.len(), 1);
+        let player = delta.players[&2].as_ref().unwrap();
+        assert_eq!(player.nickname.as_deref(), Some("renamed"));
+        assert!(delta.room.is_none());
+        assert!(delta.scoreboard.is_empty());
+        assert!(delta.lobby.is_empty());
+        assert!(delta.settings.is_none());
+
+        let seen = delta.revision;
+        replay.state().update_settings(|s| s.strip_passwords = true);
+        replay.feed(&event(
+            event_code::LEAVE,
+            indexmap! { parameter_code::ACTOR_NR => PhotonDataType::Integer(2) },
+        ));
+        let delta = replay.state().changes_since(seen);
+        assert!(delta.settings.unwrap().strip_passwords);
+        assert!(delta.players[&2].is_none());
+        assert_eq!(delta.scoreboard[&2], None);
+        assert!(delta.room.is_none());
+    }
+
+    #[test]
+    fn falling_behind_the_journal_asks_for_a_resync() {
+        let mut replay = joined_room();
+        let seen = replay.state().changes_since(0).revision;
+        // one more change than the journal keeps
+        for _ in 0..=MAX_ENTRIES {
+            replay.feed(&event(
+                event_code::PROPERTIES_CHANGED,
+                indexmap! {
+                    parameter_code::TARGET_ACTOR_NR => PhotonDataType::Integer(2),
+                    parameter_code::PROPERTIES => nickname("them"),
+                },
+            ));
+        }
+
+        let delta = replay.state().changes_since(seen);
+        assert!(delta.resync);
+        assert!(!delta.is_empty());
+        assert!(delta.players.is_empty());
+        assert_eq!(delta.revision, seen + MAX_ENTRIES as u64 + 1);
+        // a view that read everything again continues from the new revision
+        assert!(replay.state().changes_since(delta.revision).is_empty());
+    }
+
+    #[test]
+    fn groups_changes_and_forgets_old_ones() {
+        let mut journal = ChangeJournal::default();
+        assert_eq!(journal.changed_since(0).unwrap().len(), 0);
+
+        journal.record(Section::Players, ChangedKey::Actor(1));
+        let seen = journal.revision();
+        journal.record_actors(Section::Players, [2, 1]);
+        journal.record(Section::Lobby, ChangedKey::LobbyRoom("room".into()));
+
+        let changed = journal.changed_since(seen).unwrap();
+        assert_eq!(
+            changed[&Section::Players].iter().collect::<Vec<_>>(),
+            [&ChangedKey::Actor(2), &ChangedKey::Actor(1)]
+        );
+        assert_eq!(changed[&Section::Lobby].len(), 1);
+        assert!(!changed.contains_key(&Section::Room));
+        assert!(journal.changed_since(journal.revision() + 1).is_none());
+
+        for i in 0..MAX_ENTRIES as i32 {
+            journal.record(Section::Scoreboard, ChangedKey::Actor(i));
+        }
+        assert!(journal.changed_since(seen).is_none());
+        // the oldest change still known is right after this one
+        let oldest_known = journal.revision() - MAX_ENTRIES as u64;
+        assert_eq!(
+            journal.changed_since(oldest_known).unwrap()[&Section::Scoreboard].len(),
+            MAX_ENTRIES
+        );
+    }
+}
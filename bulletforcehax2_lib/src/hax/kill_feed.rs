@@ -0,0 +1,231 @@
+//! The deaths in the current room, with who caused them and where they happened.
+//!
+//! Deaths are noticed when a player's death count goes up in their serialized [PlayerScript]. The script also names
+//! the player and weapon that last damaged them, which is taken as the killer. The place is where the victim was last
+//! seen alive, named by the [map annotations](super::map_annotations) if it's in a callout.
+
+use std::{collections::VecDeque, fmt::Display, time::SystemTime};
+
+use photon_lib::primitives::Vector3;
+
+use super::map_annotations::MapAnnotations;
+use crate::protocol::{loadout::LoadoutItem, player_script::PlayerScript};
+
+/// How many deaths to keep.
+const MAX_ENTRIES: usize = 50;
+
+/// A death, as noticed by [PlayerActor::merge_player_script](super::PlayerActor::merge_player_script).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Death {
+    /// The actor that last damaged the victim, if any.
+    pub killer: Option<i32>,
+    pub weapon: LoadoutItem,
+    /// Where the victim was last seen alive.
+    pub position: Option<Vector3>,
+}
+
+impl Death {
+    /// The death reported by a player script, if its death count is above the previous one.
+    pub fn from_script(
+        previous_deaths: Option<i16>,
+        previous_position: Option<Vector3>,
+        script: &PlayerScript,
+    ) -> Option<Self> {
+        // the first script of a player only tells us their count so far
+        if script.number_of_deaths <= previous_deaths? {
+            return None;
+        }
+        Some(Self {
+            killer: (script.last_damager_id > 0).then_some(script.last_damager_id),
+            weapon: LoadoutItem::weapon(script.weapon_last_damaged_from as i32),
+            position: previous_position,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KillFeedEntry {
+    pub timestamp: SystemTime,
+    pub victim: i32,
+    pub killer: Option<i32>,
+    pub weapon: LoadoutItem,
+    pub position: Option<Vector3>,
+    /// The callout the victim was in, see [MapAnnotations::callout_for].
+    pub callout: Option<String>,
+}
+
+impl KillFeedEntry {
+    /// Describes the death with the names of the players as they are now, so players that renamed since show up under
+    /// their new name. See [GameplayState::display_name](super::GameplayState::display_name).
+    pub fn describe(&self, name: impl Fn(i32) -> String) -> String {
+        let mut out = match self.killer {
+            Some(killer) if killer == self.victim => format!("{} killed themselves", name(killer)),
+            Some(killer) => format!(
+                "{} killed {} with {}",
+                name(killer),
+                name(self.victim),
+                self.weapon
+            ),
+            None => format!("{} died", name(self.victim)),
+        };
+        if let Some(callout) = &self.callout {
+            out.push_str(&format!(" at {callout}"));
+        }
+        out
+    }
+}
+
+impl Display for KillFeedEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.describe(|actor_id| format!("actor {actor_id}"))
+        )
+    }
+}
+
+/// The most recent deaths in the room, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct KillFeed {
+    entries: VecDeque<KillFeedEntry>,
+}
+
+impl KillFeed {
+    /// Adds a death of the victim on the given map, dropping the oldest one if there are too many.
+    pub fn record(
+        &mut self,
+        victim: i32,
+        death: Death,
+        map: Option<&str>,
+        annotations: &MapAnnotations,
+        timestamp: SystemTime,
+    ) -> &KillFeedEntry {
+        let callout = match (map, &death.position) {
+            (Some(map), Some(position)) => annotations.callout_for(map, position).map(String::from),
+            _ => None,
+        };
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(KillFeedEntry {
+            timestamp,
+            victim,
+            killer: death.killer,
+            weapon: death.weapon,
+            position: death.position,
+            callout,
+        });
+        self.entries.back().expect("entry was just pushed")
+    }
+
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &KillFeedEntry> + ExactSizeIterator {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use photon_lib::{
+        ordered_float::OrderedFloat,
+        primitives::{Quaternion, Vector3},
+    };
+
+    use super::{Death, KillFeed};
+    use crate::{hax::map_annotations::MapAnnotations, protocol::player_script::PlayerScript};
+
+    fn script(deaths: i16, last_damager_id: i32) -> PlayerScript {
+        PlayerScript {
+            pitch: 0,
+            yaw: 0,
+            move_angle: 0,
+            number_of_kills: 0,
+            number_of_deaths: deaths,
+            number_of_rounds: 0,
+            ping: 0,
+            last_local_hit_y: 0,
+            gun_game_score: 0,
+            velocity_x: 0,
+            velocity_y: 0,
+            velocity_z: 0,
+            health: 0,
+            accessory_type: 0,
+            barrel_type: 0,
+            sight_type: 0,
+            weapon_last_damaged_from: 1,
+            bitflags: 0,
+            last_damager_id,
+            position: Vector3(OrderedFloat(0.0), OrderedFloat(0.0), OrderedFloat(0.0)),
+            rotation: Quaternion(
+                OrderedFloat(0.0),
+                OrderedFloat(0.0),
+                OrderedFloat(0.0),
+                OrderedFloat(1.0),
+            ),
+        }
+    }
+
+    #[test]
+    fn records_deaths_with_callouts() {
+        let a_site = Vector3(OrderedFloat(24.0), OrderedFloat(0.5), OrderedFloat(30.0));
+        assert_eq!(Death::from_script(None, None, &script(3, 2)), None);
+        assert_eq!(Death::from_script(Some(3), None, &script(3, 2)), None);
+        let death = Death::from_script(Some(3), Some(a_site), &script(4, 2)).unwrap();
+        assert_eq!(death.killer, Some(2));
+
+        let annotations = MapAnnotations::builtin();
+        let mut feed = KillFeed::default();
+        let now = SystemTime::now();
+        let entry = feed.record(5, death.clone(), Some("Urban"), &annotations, now);
+        assert_eq!(entry.callout.as_deref(), Some("A site"));
+        assert_eq!(
+            entry.to_string(),
+            format!("actor 2 killed actor 5 with {} at A site", death.weapon)
+        );
+
+        // without a known map or position, there is no callout
+        let entry = feed.record(5, death.clone(), None, &annotations, now);
+        assert_eq!(entry.callout, None);
+        let unknown = Death::from_script(Some(0), None, &script(1, 0)).unwrap();
+        let entry = feed.record(7, unknown, Some("Urban"), &annotations, now);
+        assert_eq!(entry.to_string(), "actor 7 died");
+
+        for _ in 0..100 {
+            feed.record(5, death.clone(), None, &annotations, now);
+        }
+        assert_eq!(feed.len(), 50);
+    }
+
+    #[test]
+    fn describes_with_current_names() {
+        let death = Death::from_script(Some(0), None, &script(1, 2)).unwrap();
+        let mut feed = KillFeed::default();
+        let entry = feed
+            .record(
+                5,
+                death.clone(),
+                None,
+                &MapAnnotations::default(),
+                SystemTime::now(),
+            )
+            .clone();
+        let names = |actor_id| match actor_id {
+            2 => "renamed".to_string(),
+            _ => format!("actor {actor_id}"),
+        };
+        assert_eq!(
+            entry.describe(names),
+            format!("renamed killed actor 5 with {}", death.weapon)
+        );
+    }
+}
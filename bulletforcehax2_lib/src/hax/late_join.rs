@@ -0,0 +1,349 @@
+//! Telling the events Photon replays from the room's cache apart from live ones.
+//!
+//! When we join a room that is already in progress, the server first sends us the events cached in it: the
+//! instantiations of everything that still exists and the buffered RPCs, sometimes preceded by a
+//! [CACHE_SLICE_CHANGED](event_code::CACHE_SLICE_CHANGED) event. They arrive in a burst right after the JOIN_GAME
+//! response, but happened any time before. The burst is over once the other players' serialized updates come in, as
+//! those are never cached.
+//!
+//! Events in between are historical. They still update the state, as they are what tells us which players and objects
+//! exist, but the trackers that care about when something happened leave them out:
+//!
+//! - the [suspicion heuristics](super::detection) don't count buffered RPCs, a burst of old shots would look like an
+//!   impossible fire rate
+//! - the [projectile tracker](super::projectiles) doesn't track cached projectiles, they were thrown before we joined
+//!   and are most likely gone
+//!
+//! The [kill feed](super::kill_feed) and the [link quality](super::link_quality) only look at serialized updates, so
+//! they only ever see live traffic. The first update of each player only tells the kill feed their death count so
+//! far, which keeps deaths from before we joined out of it.
+
+use std::time::{Duration, Instant};
+
+use photon_lib::highlevel::constants::{event_code, pun_event_code};
+
+/// How long to wait for the first serialized update. Rooms where nobody else is spawned send none, so the window has
+/// to end some time.
+const MAX_DURATION: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Phase {
+    /// Not in a room, or in a room we created, which has no cache to replay.
+    #[default]
+    Idle,
+    Replaying {
+        since: Instant,
+        events: u32,
+    },
+    Live,
+}
+
+/// Whether the events of the current room are still replayed from its cache, see [late_join](self).
+#[derive(Debug, Clone, Default)]
+pub struct ReplayWindow {
+    phase: Phase,
+    /// How many events were replayed when we joined the current room.
+    replayed: Option<u32>,
+}
+
+impl ReplayWindow {
+    /// Starts the window, when joining a room.
+    pub fn open(&mut self, now: Instant) {
+        self.phase = Phase::Replaying {
+            since: now,
+            events: 0,
+        };
+        self.replayed = None;
+    }
+
+    /// Looks at an event from the game server, returning whether it is historical.
+    pub fn observe(&mut self, code: u8, now: Instant) -> bool {
+        if let Phase::Replaying { since, events } = self.phase {
+            if now.saturating_duration_since(since) >= MAX_DURATION {
+                self.close(events);
+            }
+        }
+        match (code, &mut self.phase) {
+            (pun_event_code::SEND_SERIALIZE | pun_event_code::SEND_SERIALIZE_RELIABLE, phase) => {
+                if let Phase::Replaying { events, .. } = *phase {
+                    self.close(events);
+                }
+                self.phase = Phase::Live;
+                false
+            }
+            (_, Phase::Replaying { events, .. }) => {
+                *events += 1;
+                true
+            }
+            // the JOIN_GAME response didn't parse, but the cache is being replayed
+            (event_code::CACHE_SLICE_CHANGED, Phase::Idle) => {
+                self.phase = Phase::Replaying {
+                    since: now,
+                    events: 1,
+                };
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn close(&mut self, events: u32) {
+        tracing::debug!(events, "Replay of the room's cached events is over");
+        self.phase = Phase::Live;
+        self.replayed = Some(events);
+    }
+
+    /// Whether events are currently replayed from the room's cache.
+    pub fn is_open(&self) -> bool {
+        matches!(self.phase, Phase::Replaying { .. })
+    }
+
+    /// How many events were replayed when joining the current room, once the replay is over.
+    pub fn replayed(&self) -> Option<u32> {
+        self.replayed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use photon_lib::{
+        highlevel::{
+            constants::{
+                actor_properties, event_code, operation_code, parameter_code, pun_event_code,
+            },
+            structs::{InstantiationEventData, RpcCall},
+            PhotonMapConversion,
+        },
+        indexmap::{indexmap, IndexMap},
+        ordered_float::OrderedFloat,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationRequest, OperationResponse, PhotonMessage},
+        primitives::{Quaternion, Vector3},
+        PhotonHashmap,
+    };
+
+    use super::{ReplayWindow, MAX_DURATION};
+    use crate::{
+        hax::timeline::Replay, inspect::CapturedMessage, protocol::player_script::PlayerScript,
+        proxy::Direction, testsupport::captured,
+    };
+
+    #[test]
+    fn window_ends_with_the_first_serialize() {
+        let start = Instant::now();
+        let mut window = ReplayWindow::default();
+        assert!(!window.observe(pun_event_code::RPC, start));
+
+        window.open(start);
+        assert!(window.observe(event_code::CACHE_SLICE_CHANGED, start));
+        assert!(window.observe(pun_event_code::INSTANTIATION, start));
+        assert!(window.is_open());
+        assert!(!window.observe(pun_event_code::SEND_SERIALIZE, start));
+        assert_eq!(window.replayed(), Some(2));
+
+        // slicing the cache later on is live, like everything else
+        assert!(!window.observe(event_code::CACHE_SLICE_CHANGED, start));
+        assert!(!window.observe(pun_event_code::RPC, start));
+    }
+
+    #[test]
+    fn window_ends_without_serializes() {
+        let start = Instant::now();
+        let mut window = ReplayWindow::default();
+        // the cache slice event alone is enough to tell
+        assert!(window.observe(event_code::CACHE_SLICE_CHANGED, start));
+        assert!(window.observe(pun_event_code::RPC, start + Duration::from_secs(1)));
+        assert!(!window.observe(pun_event_code::RPC, start + MAX_DURATION));
+        assert!(!window.is_open());
+        assert_eq!(window.replayed(), Some(2));
+    }
+
+    fn event(code: u8, sender: i32, data: (u8, PhotonHashmap)) -> CapturedMessage {
+        captured(
+            Direction::ServerToClient,
+            PhotonMessage::EventData(EventData {
+                code,
+                parameters: indexmap! {
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(sender),
+                    data.0 => PhotonDataType::Hashtable(data.1),
+                },
+            }),
+        )
+    }
+
+    fn instantiation(sender: i32, prefab_name: &str, instantiation_id: i32) -> CapturedMessage {
+        let mut data = PhotonHashmap::new();
+        InstantiationEventData {
+            prefab_name: prefab_name.into(),
+            position: None,
+            rotation: None,
+            group: None,
+            views_ids: None,
+            incoming_instantiation_data: None,
+            server_time: 1000,
+            instantiation_id,
+            obj_level_prefix: None,
+            custom_properties: IndexMap::new(),
+        }
+        .into_map(&mut data);
+        event(
+            pun_event_code::INSTANTIATION,
+            sender,
+            (parameter_code::DATA, data),
+        )
+    }
+
+    fn shot(sender: i32) -> CapturedMessage {
+        let mut call = PhotonHashmap::new();
+        RpcCall {
+            net_view_id: sender * 1000 + 1,
+            other_side_prefix: None,
+            server_timestamp: None,
+            method_name: Some("RpcShoot".into()),
+            in_method_parameters: None,
+            rpc_index: None,
+            custom_properties: IndexMap::new(),
+        }
+        .into_map(&mut call);
+        event(
+            pun_event_code::RPC,
+            sender,
+            (parameter_code::CUSTOM_EVENT_CONTENT, call),
+        )
+    }
+
+    fn serialize(sender: i32, deaths: i16) -> CapturedMessage {
+        let script = PlayerScript {
+            pitch: 0,
+            yaw: 0,
+            move_angle: 0,
+            number_of_kills: 0,
+            number_of_deaths: deaths,
+            number_of_rounds: 0,
+            ping: 0,
+            last_local_hit_y: 0,
+            gun_game_score: 0,
+            velocity_x: 0,
+            velocity_y: 0,
+            velocity_z: 0,
+            health: 10000,
+            accessory_type: 0,
+            barrel_type: 0,
+            sight_type: 0,
+            weapon_last_damaged_from: 1,
+            bitflags: 0,
+            last_damager_id: 1,
+            position: Vector3(OrderedFloat(0.0), OrderedFloat(0.0), OrderedFloat(0.0)),
+            rotation: Quaternion(
+                OrderedFloat(0.0),
+                OrderedFloat(0.0),
+                OrderedFloat(0.0),
+                OrderedFloat(1.0),
+            ),
+        };
+        let mut object = vec![
+            PhotonDataType::Integer(sender * 1000 + 1),
+            PhotonDataType::Boolean(false),
+            PhotonDataType::Null,
+        ];
+        object.extend(script.to_object_array());
+        let data = indexmap! {
+            PhotonDataType::Byte(0) => PhotonDataType::Integer(5000),
+            PhotonDataType::Byte(1) => PhotonDataType::Short(0),
+            PhotonDataType::Byte(10) => PhotonDataType::ObjectArray(object),
+        };
+        event(
+            pun_event_code::SEND_SERIALIZE,
+            sender,
+            (parameter_code::DATA, data),
+        )
+    }
+
+    /// Joins a room as actor 2, with actor 3 already playing in it.
+    fn join_in_progress() -> Replay {
+        let mut replay = Replay::default();
+        replay.feed(&captured(
+            Direction::ClientToServer,
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::AUTHENTICATE,
+                parameters: indexmap! {},
+            }),
+        ));
+        let player = |nickname: &str| {
+            PhotonDataType::Hashtable(indexmap! {
+                PhotonDataType::Byte(actor_properties::PLAYER_NAME) => PhotonDataType::String(nickname.into()),
+            })
+        };
+        replay.feed(&captured(
+            Direction::ServerToClient,
+            PhotonMessage::OperationResponse(OperationResponse {
+                operation_code: operation_code::JOIN_GAME,
+                return_code: 0,
+                debug_message: None,
+                parameters: indexmap! {
+                    parameter_code::ROOM_NAME => PhotonDataType::String("room".into()),
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(2),
+                    parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Integer(2) => player("us"),
+                        PhotonDataType::Integer(3) => player("veteran"),
+                    }),
+                    parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                },
+            }),
+        ));
+        replay
+    }
+
+    #[test]
+    fn cached_events_are_historical() {
+        let mut replay = join_in_progress();
+        let mut events = replay.state().events.subscribe();
+
+        // what a late join looks like: the cache slice, then everything actor 3 left in the cache
+        replay.feed(&captured(
+            Direction::ServerToClient,
+            PhotonMessage::EventData(EventData {
+                code: event_code::CACHE_SLICE_CHANGED,
+                parameters: indexmap! {
+                    parameter_code::CACHE_SLICE_INDEX => PhotonDataType::Integer(0),
+                },
+            }),
+        ));
+        replay.feed(&instantiation(3, "PlayerBody", 3001));
+        replay.feed(&instantiation(3, "Grenade", 3002));
+        for _ in 0..30 {
+            replay.feed(&shot(3));
+        }
+        {
+            let state = replay.state();
+            assert!(state.stats.recent_errors.is_empty());
+            let (_, game) = state.gameplay_state.as_ref().unwrap();
+            assert!(game.late_join.is_open());
+            // the state is still updated
+            assert!(game.players[&3].view_id.is_some());
+            assert_eq!(game.projectiles.len(), 0);
+            assert!(state.suspicion_scores().is_empty());
+        }
+
+        // live traffic starts, with actor 3 having died several times before we joined
+        replay.feed(&serialize(3, 5));
+        {
+            let state = replay.state();
+            assert!(state.stats.recent_errors.is_empty());
+            let (_, game) = state.gameplay_state.as_ref().unwrap();
+            assert_eq!(game.late_join.replayed(), Some(33));
+            assert_eq!(game.players[&3].deaths, Some(5));
+            assert!(game.kill_feed.entries().next().is_none());
+        }
+        assert!(events.try_recv().is_err());
+
+        // the same burst is suspicious when it's live
+        for _ in 0..30 {
+            replay.feed(&shot(3));
+        }
+        let state = replay.state();
+        assert!(state.suspicion_scores()[&3].score > 0.0);
+    }
+}
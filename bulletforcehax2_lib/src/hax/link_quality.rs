@@ -0,0 +1,243 @@
+//! Estimates how well other players are connected, from how regularly their serialized updates arrive.
+//!
+//! PUN sends serialized data at a fixed rate, so a player with a good connection produces a steady stream of
+//! updates. Lag shows up as jitter in the arrival times, and as gaps where several updates are missing at once.
+
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone)]
+pub struct LinkQualitySettings {
+    /// How many of the most recent arrivals the metrics are calculated over.
+    pub window: usize,
+    /// An interval longer than this counts as a gap.
+    pub gap_threshold: Duration,
+    /// A player is degraded if the jitter is above this.
+    pub degraded_jitter: Duration,
+    /// A player is degraded if there are more gaps than this in the window.
+    pub degraded_gaps: usize,
+    /// A player is timing out if they sent nothing for this long, while updates from others keep arriving.
+    pub timeout: Duration,
+}
+
+impl Default for LinkQualitySettings {
+    fn default() -> Self {
+        Self {
+            window: 32,
+            gap_threshold: Duration::from_millis(500),
+            degraded_jitter: Duration::from_millis(80),
+            degraded_gaps: 2,
+            timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkQuality {
+    Good,
+    Degraded,
+    TimingOut,
+}
+
+impl Display for LinkQuality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkQuality::Good => write!(f, "good"),
+            LinkQuality::Degraded => write!(f, "degraded"),
+            LinkQuality::TimingOut => write!(f, "timing out"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkQualityStats {
+    pub quality: LinkQuality,
+    /// The average time between updates.
+    pub mean_interval: Duration,
+    /// The mean deviation of the time between updates from [Self::mean_interval].
+    pub jitter: Duration,
+    /// How many intervals in the window were longer than [LinkQualitySettings::gap_threshold].
+    pub gaps: usize,
+    /// How long ago the last update arrived.
+    pub since_last: Duration,
+}
+
+/// The arrival times of a player's serialized updates.
+#[derive(Debug, Clone, Default)]
+pub struct ArrivalTracker {
+    last: Option<Instant>,
+    intervals: VecDeque<Duration>,
+    /// Whether the player was timing out when last classified, to detect transitions.
+    timing_out: bool,
+}
+
+impl ArrivalTracker {
+    pub fn record(&mut self, now: Instant, settings: &LinkQualitySettings) {
+        if let Some(last) = self.last {
+            while self.intervals.len() >= settings.window.max(1) {
+                self.intervals.pop_front();
+            }
+            self.intervals
+                .push_back(now.saturating_duration_since(last));
+        }
+        self.last = Some(now);
+    }
+
+    pub fn last_arrival(&self) -> Option<Instant> {
+        self.last
+    }
+
+    /// Calculates the metrics at the given moment. `latest_arrival` is the last time an update from anyone arrived,
+    /// if that was long ago too it's our own connection that's in trouble, so nobody is classified as timing out.
+    ///
+    /// Returns [None] if no updates arrived yet.
+    pub fn stats(
+        &self,
+        now: Instant,
+        latest_arrival: Option<Instant>,
+        settings: &LinkQualitySettings,
+    ) -> Option<LinkQualityStats> {
+        let last = self.last?;
+        let since_last = now.saturating_duration_since(last);
+
+        let (mean_interval, jitter) = match self.intervals.len() as u32 {
+            0 => (Duration::ZERO, Duration::ZERO),
+            n => {
+                let mean = self.intervals.iter().sum::<Duration>() / n;
+                let deviation = self
+                    .intervals
+                    .iter()
+                    .map(|i| i.abs_diff(mean))
+                    .sum::<Duration>()
+                    / n;
+                (mean, deviation)
+            }
+        };
+        let gaps = self
+            .intervals
+            .iter()
+            .filter(|i| **i > settings.gap_threshold)
+            .count();
+
+        let others_flowing = latest_arrival
+            .map(|t| now.saturating_duration_since(t) < settings.timeout)
+            .unwrap_or(false);
+        let quality = if since_last >= settings.timeout && others_flowing {
+            LinkQuality::TimingOut
+        } else if jitter > settings.degraded_jitter || gaps > settings.degraded_gaps {
+            LinkQuality::Degraded
+        } else {
+            LinkQuality::Good
+        };
+
+        Some(LinkQualityStats {
+            quality,
+            mean_interval,
+            jitter,
+            gaps,
+            since_last,
+        })
+    }
+
+    /// Classifies the player and returns whether they just started timing out.
+    pub fn update(
+        &mut self,
+        now: Instant,
+        latest_arrival: Option<Instant>,
+        settings: &LinkQualitySettings,
+    ) -> bool {
+        let timing_out = matches!(
+            self.stats(now, latest_arrival, settings),
+            Some(LinkQualityStats {
+                quality: LinkQuality::TimingOut,
+                ..
+            })
+        );
+        let started = timing_out && !self.timing_out;
+        self.timing_out = timing_out;
+        started
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{ArrivalTracker, LinkQuality, LinkQualitySettings};
+
+    fn tracker(start: Instant, arrivals_ms: &[u64]) -> ArrivalTracker {
+        let settings = LinkQualitySettings::default();
+        let mut tracker = ArrivalTracker::default();
+        for ms in arrivals_ms {
+            tracker.record(start + Duration::from_millis(*ms), &settings);
+        }
+        tracker
+    }
+
+    #[test]
+    fn steady_stream_is_good() {
+        let settings = LinkQualitySettings::default();
+        let start = Instant::now();
+        let arrivals = (0..20).map(|i| i * 100).collect::<Vec<_>>();
+        let tracker = tracker(start, &arrivals);
+
+        let now = start + Duration::from_millis(1950);
+        let stats = tracker.stats(now, Some(now), &settings).unwrap();
+        assert_eq!(stats.quality, LinkQuality::Good);
+        assert_eq!(stats.mean_interval, Duration::from_millis(100));
+        assert_eq!(stats.jitter, Duration::ZERO);
+        assert_eq!(stats.gaps, 0);
+    }
+
+    #[test]
+    fn bursty_stream_is_degraded() {
+        let settings = LinkQualitySettings::default();
+        let start = Instant::now();
+
+        // updates arriving in clumps
+        let jittery = tracker(start, &[0, 10, 20, 300, 310, 320, 600, 610, 620, 900]);
+        let now = start + Duration::from_millis(950);
+        let stats = jittery.stats(now, Some(now), &settings).unwrap();
+        assert!(stats.jitter > settings.degraded_jitter, "{stats:?}");
+        assert_eq!(stats.quality, LinkQuality::Degraded);
+
+        // a steady stream with several long gaps
+        let gappy = tracker(start, &[0, 100, 800, 900, 1600, 1700, 2400, 2500]);
+        let now = start + Duration::from_millis(2550);
+        let stats = gappy.stats(now, Some(now), &settings).unwrap();
+        assert_eq!(stats.gaps, 3);
+        assert_eq!(stats.quality, LinkQuality::Degraded);
+    }
+
+    #[test]
+    fn timing_out_only_while_others_flow() {
+        let settings = LinkQualitySettings::default();
+        let start = Instant::now();
+        let mut silent = tracker(start, &[0, 100, 200]);
+
+        let now = start + Duration::from_millis(200) + settings.timeout;
+        // someone else just sent an update
+        assert!(silent.update(now, Some(now), &settings));
+        // the transition is only reported once
+        assert!(!silent.update(now + Duration::from_secs(1), Some(now), &settings));
+
+        // nobody sent anything, so it's probably our own connection
+        let mut silent = tracker(start, &[0, 100, 200]);
+        assert!(!silent.update(now, Some(start + Duration::from_millis(200)), &settings));
+        let stats = silent
+            .stats(now, Some(start + Duration::from_millis(200)), &settings)
+            .unwrap();
+        assert_eq!(stats.quality, LinkQuality::Good);
+
+        // recovering and timing out again is reported again
+        let mut flaky = tracker(start, &[0, 100, 200]);
+        assert!(flaky.update(now, Some(now), &settings));
+        flaky.record(now, &settings);
+        assert!(!flaky.update(now, Some(now), &settings));
+        let later = now + settings.timeout;
+        assert!(flaky.update(later, Some(later), &settings));
+    }
+}
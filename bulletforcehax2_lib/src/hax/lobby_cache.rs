@@ -0,0 +1,212 @@
+//! The rooms the lobby listed, as the server sent them.
+//!
+//! Full [GAME_LIST](photon_lib::highlevel::constants::event_code::GAME_LIST) events replace the cache, and
+//! [GAME_LIST_UPDATE](photon_lib::highlevel::constants::event_code::GAME_LIST_UPDATE) events merge into it. Rooms
+//! churn constantly over a long session, so rooms that weren't updated in a while are forgotten, and the least
+//! recently updated ones go first if there are too many. What features derive from the listings is kept in the
+//! [room overlay](super::room_overlay), which outlives the cached rooms.
+
+use std::time::{Duration, Instant};
+
+use photon_lib::{
+    highlevel::structs::RoomInfoView, indexmap::IndexMap, photon_data_type::PhotonDataType,
+    PhotonHashmap,
+};
+
+use super::{game_variant::GameVariant, room_probe::RoomProbe};
+use crate::protocol::properties::BulletForceRoomProperties;
+
+/// How long a room stays cached after it was last listed or updated.
+const ROOM_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// How many rooms to cache at most.
+pub const MAX_ROOMS: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct CachedRoom {
+    /// The room's properties before any feature rewrote them.
+    pub properties: PhotonHashmap,
+    /// The game the room was created by.
+    pub variant: GameVariant,
+    /// When the room was last listed or updated.
+    pub last_seen: Instant,
+    /// Who was found in the room when it was last [probed](super::room_probe).
+    pub probe: Option<RoomProbe>,
+}
+
+impl CachedRoom {
+    pub fn view(&self) -> RoomInfoView<&PhotonHashmap> {
+        RoomInfoView(&self.properties)
+    }
+}
+
+/// The rooms by id, least recently updated first.
+#[derive(Debug, Default)]
+pub struct RoomCache {
+    rooms: IndexMap<String, CachedRoom>,
+    /// How many rooms were forgotten because they expired or the cache was full.
+    evicted: u64,
+}
+
+impl RoomCache {
+    /// Replaces the cache with the rooms of a full game list. Returns the ids of the rooms that were dropped, which
+    /// includes those that are listed again.
+    pub fn replace(&mut self, games: &PhotonHashmap, now: Instant) -> Vec<String> {
+        let mut dropped = self.rooms.drain(..).map(|(id, _)| id).collect::<Vec<_>>();
+        dropped.extend(self.merge(games, now));
+        dropped
+    }
+
+    /// Merges the rooms of a game list update. Updates only hold the properties that changed. Returns the ids of the
+    /// rooms that were evicted to make room.
+    pub fn update(&mut self, games: &PhotonHashmap, now: Instant) -> Vec<String> {
+        self.merge(games, now)
+    }
+
+    fn merge(&mut self, games: &PhotonHashmap, now: Instant) -> Vec<String> {
+        for (id, properties) in games {
+            let (id, properties) = match (id, properties) {
+                (PhotonDataType::String(id), PhotonDataType::Hashtable(properties)) => {
+                    (id, properties)
+                }
+                _ => continue,
+            };
+            let mut room = self.rooms.shift_remove(id).unwrap_or_else(|| CachedRoom {
+                properties: PhotonHashmap::new(),
+                variant: GameVariant::BulletForce,
+                last_seen: now,
+                probe: None,
+            });
+            if RoomInfoView(properties).removed() == Some(&true) {
+                continue;
+            }
+            for (key, value) in properties {
+                room.properties.insert(key.clone(), value.clone());
+            }
+            // updates usually leave the version out, so detect it from all properties we know of
+            room.variant = room.view().variant();
+            room.last_seen = now;
+            self.rooms.insert(id.clone(), room);
+        }
+        self.evict(now)
+    }
+
+    fn evict(&mut self, now: Instant) -> Vec<String> {
+        let expired = self
+            .rooms
+            .values()
+            .take_while(|r| now.saturating_duration_since(r.last_seen) > ROOM_TTL)
+            .count();
+        let overflow = self.rooms.len().saturating_sub(expired + MAX_ROOMS);
+        let evict = expired + overflow;
+        self.evicted += evict as u64;
+        self.rooms.drain(..evict).map(|(id, _)| id).collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&CachedRoom> {
+        self.rooms.get(id)
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut CachedRoom> {
+        self.rooms.get_mut(id)
+    }
+
+    /// The cached room with the id it is cached under.
+    pub fn get_key_value(&self, id: &str) -> Option<(&str, &CachedRoom)> {
+        self.rooms
+            .get_key_value(id)
+            .map(|(id, room)| (id.as_str(), room))
+    }
+
+    /// The cached rooms by id, least recently updated first.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &CachedRoom)> {
+        self.rooms.iter().map(|(id, room)| (id.as_str(), room))
+    }
+
+    pub fn len(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty()
+    }
+
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use photon_lib::{
+        highlevel::constants::game_property_key, indexmap::indexmap,
+        photon_data_type::PhotonDataType, PhotonHashmap,
+    };
+
+    use super::{RoomCache, MAX_ROOMS};
+
+    fn room(id: &str, properties: PhotonHashmap) -> PhotonHashmap {
+        indexmap! { PhotonDataType::String(id.into()) => PhotonDataType::Hashtable(properties) }
+    }
+
+    fn player_count(count: u8) -> PhotonHashmap {
+        indexmap! {
+            PhotonDataType::Byte(game_property_key::PLAYER_COUNT) => PhotonDataType::Byte(count),
+        }
+    }
+
+    #[test]
+    fn merges_updates() {
+        let mut cache = RoomCache::default();
+        let now = Instant::now();
+        let mut properties = player_count(3);
+        properties.insert(
+            PhotonDataType::String("roomName".into()),
+            PhotonDataType::String("room".into()),
+        );
+        cache.replace(&room("a", properties), now);
+        cache.update(&room("b", player_count(1)), now);
+        cache.update(&room("a", player_count(4)), now);
+
+        let a = cache.get("a").unwrap().view();
+        assert_eq!(a.player_count(), Some(&4));
+        assert_eq!(a.custom_str("roomName"), Some("room"));
+        assert_eq!(
+            cache.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            ["b", "a"]
+        );
+
+        let removed = indexmap! {
+            PhotonDataType::Byte(game_property_key::REMOVED) => PhotonDataType::Boolean(true),
+        };
+        cache.update(&room("b", removed), now);
+        assert_eq!(cache.len(), 1);
+
+        // rooms that weren't updated in a while are forgotten
+        cache.update(
+            &room("c", player_count(1)),
+            now + Duration::from_secs(16 * 60),
+        );
+        assert_eq!(cache.iter().map(|(id, _)| id).collect::<Vec<_>>(), ["c"]);
+        assert_eq!(cache.evicted(), 1);
+    }
+
+    #[test]
+    fn stays_under_cap() {
+        let mut cache = RoomCache::default();
+        let start = Instant::now();
+        for i in 0..10_000u64 {
+            let id = format!("room-{}", i % 3000);
+            let now = start + Duration::from_millis(i * 10);
+            cache.update(&room(&id, player_count((i % 12) as u8)), now);
+            assert!(cache.len() <= MAX_ROOMS);
+        }
+        assert_eq!(cache.len(), MAX_ROOMS);
+        // the most recently updated rooms are kept
+        assert!(cache.get("room-999").is_some());
+        assert!(cache.get("room-1000").is_none());
+        assert_eq!(cache.evicted(), 10_000 - MAX_ROOMS as u64);
+    }
+}
@@ -0,0 +1,255 @@
+//! Reorders the rooms in the lobby game list.
+//!
+//! The in-game browser shows rooms in the order they appear in the [RoomInfoList], which is an insertion-ordered map,
+//! so sorting its entries is enough to change the order the client shows.
+//!
+//! Only full [GAME_LIST](photon_lib::highlevel::constants::event_code::GAME_LIST) events are sorted.
+//! [GAME_LIST_UPDATE](photon_lib::highlevel::constants::event_code::GAME_LIST_UPDATE) events only hold the rooms that
+//! changed, and the client updates existing rooms in place and appends new ones, so they are left untouched. The
+//! order is restored the next time the full list is sent.
+//!
+//! [RoomInfoList]: photon_lib::highlevel::structs::RoomInfoList
+
+use std::{cmp::Ordering, fmt::Display};
+
+use photon_lib::{
+    highlevel::structs::RoomInfoView, photon_data_type::PhotonDataType, PhotonHashmap,
+};
+
+use super::room_notes::{RoomFlag, RoomNoteStore};
+use crate::protocol::properties::BulletForceRoomProperties;
+
+/// The order to show lobby rooms in.
+///
+/// Rooms that compare equal keep the order the server sent them in. There is no sorting by ping, rooms don't carry
+/// any latency information and all rooms in a game list are hosted in the same region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LobbySort {
+    /// Fullest rooms first.
+    PlayerCount,
+    /// Alphabetically by room name, ignoring case.
+    Name,
+    /// Alphabetically by map name, rooms without a known map last.
+    Map,
+    /// Rooms marked as favorite in the room notes first.
+    FavoritesFirst,
+}
+
+impl LobbySort {
+    pub const ALL: [LobbySort; 4] = [
+        LobbySort::PlayerCount,
+        LobbySort::Name,
+        LobbySort::Map,
+        LobbySort::FavoritesFirst,
+    ];
+
+    fn compare(&self, a: &SortKey, b: &SortKey) -> Ordering {
+        match self {
+            LobbySort::PlayerCount => b.player_count.cmp(&a.player_count),
+            LobbySort::Name => a.name.cmp(&b.name),
+            LobbySort::Map => match (&a.map, &b.map) {
+                (Some(a), Some(b)) => a.cmp(b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            },
+            LobbySort::FavoritesFirst => b.favorite.cmp(&a.favorite),
+        }
+    }
+}
+
+impl Display for LobbySort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LobbySort::PlayerCount => write!(f, "player count"),
+            LobbySort::Name => write!(f, "name"),
+            LobbySort::Map => write!(f, "map"),
+            LobbySort::FavoritesFirst => write!(f, "favorites first"),
+        }
+    }
+}
+
+/// The values of a room that are sorted on.
+struct SortKey {
+    name: String,
+    map: Option<String>,
+    player_count: u8,
+    favorite: bool,
+}
+
+impl SortKey {
+    fn new(game_name: &PhotonDataType, props: &PhotonDataType, room_notes: &RoomNoteStore) -> Self {
+        let game_name = match game_name {
+            PhotonDataType::String(name) => name.as_str(),
+            _ => "",
+        };
+        let room = match props {
+            PhotonDataType::Hashtable(props) => RoomInfoView(props),
+            _ => {
+                return Self {
+                    name: game_name.to_lowercase(),
+                    map: None,
+                    player_count: 0,
+                    favorite: false,
+                }
+            }
+        };
+
+        let name = room.room_name().unwrap_or(game_name);
+        let favorite = matches!(
+            room_notes.find_for_room(name, &room).and_then(|n| n.flag),
+            Some(RoomFlag::Favorite)
+        );
+
+        Self {
+            name: name.to_lowercase(),
+            map: room.map_name().map(str::to_lowercase),
+            player_count: room.player_count().copied().unwrap_or_default(),
+            favorite,
+        }
+    }
+}
+
+/// Sorts the games of a [RoomInfoList](photon_lib::highlevel::structs::RoomInfoList) in place.
+///
+/// This should happen before other lobby features rename rooms, so the original names are sorted on.
+pub fn sort_games(games: &mut PhotonHashmap, sort: LobbySort, room_notes: &RoomNoteStore) {
+    let mut entries = games
+        .drain(..)
+        .map(|(k, v)| (SortKey::new(&k, &v, room_notes), k, v))
+        .collect::<Vec<_>>();
+    // a stable sort, so equal rooms keep their order
+    entries.sort_by(|(a, _, _), (b, _, _)| sort.compare(a, b));
+    games.extend(entries.into_iter().map(|(_, k, v)| (k, v)));
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::{
+        highlevel::{
+            constants::{event_code, game_property_key},
+            structs::RoomInfoList,
+            PhotonParameterMapConversion,
+        },
+        indexmap::{indexmap, IndexMap},
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, PhotonMessage},
+    };
+
+    use super::{sort_games, LobbySort};
+    use crate::hax::room_notes::{RoomFlag, RoomKey, RoomNote, RoomNoteStore};
+
+    fn room(name: &str, map: Option<&str>, player_count: u8) -> (PhotonDataType, PhotonDataType) {
+        let mut props = indexmap! {
+            PhotonDataType::Byte(game_property_key::PLAYER_COUNT) => PhotonDataType::Byte(player_count),
+            PhotonDataType::String("roomName".into()) => PhotonDataType::String(name.into()),
+        };
+        if let Some(map) = map {
+            props.insert(
+                PhotonDataType::String("mapName".into()),
+                PhotonDataType::String(map.into()),
+            );
+        }
+        (
+            PhotonDataType::String(format!("id-{name}")),
+            PhotonDataType::Hashtable(props),
+        )
+    }
+
+    fn game_list() -> RoomInfoList {
+        RoomInfoList {
+            games: IndexMap::from_iter([
+                room("bravo", Some("Urban"), 4),
+                room("Alpha", None, 12),
+                room("charlie", Some("Outpost"), 4),
+                room("delta", Some("Urban"), 8),
+            ]),
+        }
+    }
+
+    /// Serializes the game list the way the hook forwards it, then reads back the room names in order.
+    fn serialized_order(game_list: RoomInfoList) -> Vec<String> {
+        let mut event = EventData {
+            code: event_code::GAME_LIST,
+            parameters: indexmap! {},
+        };
+        game_list.into_map(&mut event.parameters);
+        let mut bytes = vec![];
+        PhotonMessage::EventData(event)
+            .to_websocket_bytes(&mut bytes)
+            .unwrap();
+
+        let mut event = match PhotonMessage::from_websocket_bytes(&mut bytes.as_slice()).unwrap() {
+            PhotonMessage::EventData(event) => event,
+            other => panic!("expected event, got {other:?}"),
+        };
+        RoomInfoList::from_map(&mut event.parameters)
+            .unwrap()
+            .games
+            .keys()
+            .map(|k| match k {
+                PhotonDataType::String(k) => k.trim_start_matches("id-").to_string(),
+                other => panic!("expected string key, got {other:?}"),
+            })
+            .collect()
+    }
+
+    fn sorted(sort: LobbySort, room_notes: &RoomNoteStore) -> Vec<String> {
+        let mut game_list = game_list();
+        sort_games(&mut game_list.games, sort, room_notes);
+        serialized_order(game_list)
+    }
+
+    #[test]
+    fn serialized_order_matches_sort() {
+        let notes = RoomNoteStore::default();
+
+        // unsorted lists keep the order they were received in
+        assert_eq!(
+            serialized_order(game_list()),
+            ["bravo", "Alpha", "charlie", "delta"]
+        );
+        assert_eq!(
+            sorted(LobbySort::PlayerCount, &notes),
+            ["Alpha", "delta", "bravo", "charlie"]
+        );
+        assert_eq!(
+            sorted(LobbySort::Name, &notes),
+            ["Alpha", "bravo", "charlie", "delta"]
+        );
+        assert_eq!(
+            sorted(LobbySort::Map, &notes),
+            ["charlie", "bravo", "delta", "Alpha"]
+        );
+    }
+
+    #[test]
+    fn favorites_first() {
+        let mut notes = RoomNoteStore::default();
+        for name in ["delta", "charlie"] {
+            notes
+                .set(
+                    RoomKey::RoomName(name.into()),
+                    RoomNote {
+                        flag: Some(RoomFlag::Favorite),
+                        note: String::new(),
+                    },
+                )
+                .unwrap();
+        }
+        notes
+            .set(
+                RoomKey::RoomName("bravo".into()),
+                RoomNote {
+                    flag: Some(RoomFlag::Blocked),
+                    note: String::new(),
+                },
+            )
+            .unwrap();
+
+        assert_eq!(
+            sorted(LobbySort::FavoritesFirst, &notes),
+            ["charlie", "delta", "bravo", "Alpha"]
+        );
+    }
+}
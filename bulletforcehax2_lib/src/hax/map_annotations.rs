@@ -0,0 +1,262 @@
+//! Named locations on each map, such as "A site" or "tunnel", to say where something happened.
+//!
+//! A small [built-in](MapAnnotations::builtin) set of rough callouts ships with the program. A user file in the same
+//! format adds maps or replaces the built-in callouts of a map:
+//!
+//! ```json
+//! {
+//!   "schema_version": 1,
+//!   "maps": {
+//!     "Urban": [{ "name": "A site", "position": [24.0, 0.5, 31.0], "radius": 9.0 }]
+//!   }
+//! }
+//! ```
+//!
+//! Map names match the room's map property, ignoring case. A position is in a callout if it's within the callout's
+//! radius of its position, and the nearest one wins if several overlap.
+
+use std::{collections::BTreeMap, path::Path};
+
+use photon_lib::{indexmap::IndexMap, primitives::Vector3};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::config::versioned::{self, Migration, Versioned};
+
+/// The callouts that ship with the program.
+const BUILTIN: &str = include_str!("../../data/map_annotations.json");
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Callout {
+    pub name: String,
+    /// The center of the callout, in game coordinates.
+    pub position: [f32; 3],
+    /// How far from the center a position still counts as being in the callout.
+    pub radius: f32,
+}
+
+impl Callout {
+    fn distance(&self, position: [f32; 3]) -> f32 {
+        let [x, y, z] = self.position;
+        let [px, py, pz] = position;
+        ((x - px).powi(2) + (y - py).powi(2) + (z - pz).powi(2)).sqrt()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.position.iter().all(|c| c.is_finite()) && self.radius.is_finite() && self.radius > 0.0
+    }
+}
+
+/// Finds the callout a position is in.
+///
+/// If the position is in several callouts, the one whose center is nearest wins. On a tie the smaller callout wins,
+/// as it's the more specific one, and after that the one listed first.
+pub fn nearest_callout(callouts: &[Callout], position: [f32; 3]) -> Option<&Callout> {
+    let mut best: Option<(&Callout, f32)> = None;
+    for callout in callouts {
+        let distance = callout.distance(position);
+        if distance.is_nan() || distance > callout.radius {
+            continue;
+        }
+        let better = match best {
+            None => true,
+            Some((current, current_distance)) => {
+                distance < current_distance
+                    || (distance == current_distance && callout.radius < current.radius)
+            }
+        };
+        if better {
+            best = Some((callout, distance));
+        }
+    }
+    best.map(|(callout, _)| callout)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct MapAnnotationsFile {
+    #[serde(default)]
+    maps: BTreeMap<String, Vec<Callout>>,
+}
+
+impl Versioned for MapAnnotationsFile {
+    const NAME: &'static str = "map annotations";
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+/// The callouts of each map.
+#[derive(Debug, Clone)]
+pub struct MapAnnotations {
+    /// The callouts by lowercase map name.
+    maps: IndexMap<String, Vec<Callout>>,
+}
+
+impl Default for MapAnnotations {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl MapAnnotations {
+    /// The callouts that ship with the program.
+    pub fn builtin() -> Self {
+        let mut annotations = Self {
+            maps: IndexMap::new(),
+        };
+        let file = versioned::from_slice(BUILTIN.as_bytes())
+            .expect("built-in map annotations should be valid");
+        annotations.merge(file);
+        annotations
+    }
+
+    /// Loads the built-in callouts and the ones in the given file on top of them.
+    ///
+    /// This never fails. A missing file only leaves the built-in callouts, and a file that can't be read is logged and
+    /// ignored.
+    pub fn load(path: &Path) -> Self {
+        let mut annotations = Self::builtin();
+        match versioned::read_optional(path) {
+            Ok(Some(file)) => annotations.merge(file),
+            Ok(None) => debug!(
+                path = format!("{path:?}"),
+                "No map annotations file, using the built-in ones"
+            ),
+            Err(e) => warn!(
+                path = format!("{path:?}"),
+                "Could not read map annotations, using the built-in ones: {e:#}"
+            ),
+        }
+        annotations
+    }
+
+    /// Replaces the callouts of every map in the file. Callouts without a positive radius or with positions that
+    /// aren't numbers are left out.
+    fn merge(&mut self, file: MapAnnotationsFile) {
+        for (map, callouts) in file.maps {
+            let (valid, invalid): (Vec<_>, Vec<_>) =
+                callouts.into_iter().partition(Callout::is_valid);
+            for callout in invalid {
+                warn!(map, callout = callout.name, "Ignoring invalid map callout");
+            }
+            self.maps.insert(map.to_lowercase(), valid);
+        }
+    }
+
+    /// The maps that have callouts, in lowercase.
+    pub fn maps(&self) -> impl Iterator<Item = &str> {
+        self.maps.keys().map(String::as_str)
+    }
+
+    pub fn callouts(&self, map: &str) -> Option<&[Callout]> {
+        self.maps.get(&map.to_lowercase()).map(Vec::as_slice)
+    }
+
+    /// The name of the callout a position on the map is in, see [nearest_callout].
+    pub fn callout_for(&self, map: &str, position: &Vector3) -> Option<&str> {
+        let (x, y, z) = position.floats();
+        nearest_callout(self.callouts(map)?, [x, y, z]).map(|c| c.name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::{ordered_float::OrderedFloat, primitives::Vector3};
+
+    use super::{nearest_callout, Callout, MapAnnotations};
+
+    fn vector(x: f32, y: f32, z: f32) -> Vector3 {
+        Vector3(OrderedFloat(x), OrderedFloat(y), OrderedFloat(z))
+    }
+
+    fn callout(name: &str, position: [f32; 3], radius: f32) -> Callout {
+        Callout {
+            name: name.into(),
+            position,
+            radius,
+        }
+    }
+
+    #[test]
+    fn nearest_callout_math() {
+        let callouts = [
+            callout("courtyard", [0.0, 0.0, 0.0], 15.0),
+            callout("tower", [0.0, 10.0, 0.0], 6.0),
+            callout("well", [10.0, 0.0, 0.0], 5.0),
+            callout("gate", [-10.0, 0.0, 0.0], 5.0),
+        ];
+        let name = |position| nearest_callout(&callouts, position).map(|c| c.name.as_str());
+
+        assert_eq!(name([1.0, 2.0, 0.0]), Some("courtyard"));
+        // inside the courtyard too, but closer to the tower
+        assert_eq!(name([0.0, 8.0, 0.0]), Some("tower"));
+        assert_eq!(name([9.0, 0.0, 1.0]), Some("well"));
+        // the edge of a callout is still in it
+        assert_eq!(name([15.0, 0.0, 0.0]), Some("well"));
+        assert_eq!(name([0.0, 0.0, 15.0]), Some("courtyard"));
+
+        // out of range of everything
+        assert_eq!(name([0.0, 0.0, 15.1]), None);
+        assert_eq!(name([100.0, 0.0, 0.0]), None);
+        assert_eq!(name([f32::NAN, 0.0, 0.0]), None);
+        assert_eq!(nearest_callout(&[], [0.0; 3]), None);
+    }
+
+    #[test]
+    fn nearest_callout_ties() {
+        // equally far from both centers, the smaller callout is more specific
+        let callouts = [
+            callout("open area", [0.0, 0.0, 0.0], 20.0),
+            callout("crates", [4.0, 0.0, 0.0], 5.0),
+        ];
+        let position = [2.0, 0.0, 0.0];
+        assert_eq!(nearest_callout(&callouts, position).unwrap().name, "crates");
+
+        // same distance and size, the first one listed wins
+        let callouts = [
+            callout("left", [-1.0, 0.0, 0.0], 5.0),
+            callout("right", [1.0, 0.0, 0.0], 5.0),
+        ];
+        assert_eq!(nearest_callout(&callouts, [0.0; 3]).unwrap().name, "left");
+    }
+
+    #[test]
+    fn user_file_overrides_builtin_maps() {
+        let builtin = MapAnnotations::builtin();
+        assert!(builtin.maps().any(|map| map == "urban"));
+        let position = vector(24.0, 0.5, 30.0);
+        assert_eq!(builtin.callout_for("URBAN", &position), Some("A site"));
+        assert_eq!(builtin.callout_for("Urban", &vector(0.0, 0.0, 0.0)), None);
+        assert_eq!(builtin.callout_for("Harbor", &position), None);
+
+        let path =
+            std::env::temp_dir().join(format!("bfhax-map-annotations-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{
+                "schema_version": 1,
+                "maps": {
+                    "urban": [{ "name": "plaza", "position": [24.0, 0.5, 30.0], "radius": 3.0 }],
+                    "Harbor": [
+                        { "name": "docks", "position": [0.0, 0.0, 0.0], "radius": 10.0 },
+                        { "name": "broken", "position": [0.0, 0.0, 0.0], "radius": -1.0 }
+                    ]
+                }
+            }"#,
+        )
+        .unwrap();
+        let annotations = MapAnnotations::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(annotations.callout_for("Urban", &position), Some("plaza"));
+        assert_eq!(annotations.callouts("Harbor").unwrap().len(), 1);
+        assert_eq!(
+            annotations.callout_for("harbor", &vector(0.0, 0.0, 0.0)),
+            Some("docks")
+        );
+        // maps the file doesn't mention keep their built-in callouts
+        assert!(annotations.callouts("Outpost").is_some());
+
+        // files that can't be read leave the built-in callouts
+        let missing = MapAnnotations::load(&path);
+        assert_eq!(missing.callout_for("Urban", &position), Some("A site"));
+    }
+}
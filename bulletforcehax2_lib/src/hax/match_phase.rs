@@ -0,0 +1,657 @@
+//! Estimates how far along the round in a room is, so rooms that are about to end can be avoided.
+//!
+//! A round's progress is judged from its elapsed time against the [round time](BulletForceRoomProperties::round_time),
+//! and from the leading score against the [score limit](BulletForceRoomProperties::score_limit). The elapsed time
+//! comes from the room's round start time if it lists one, otherwise from when we saw the round start. Lobby rooms
+//! rarely give that much away, so for them the changes of the player count are used instead. Estimates without enough
+//! to go on are [MatchPhase::Unknown] rather than a guess.
+
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use photon_lib::{highlevel::structs::WellKnownRoomProperties, PhotonHashmap};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    room_names::{Annotation, AnnotationKind},
+    PlayerActor,
+};
+use crate::protocol::properties::BulletForceRoomProperties;
+
+/// Rounds that started less than this long ago are still warming up.
+const WARMUP: Duration = Duration::from_secs(30);
+
+/// Rounds with less than this left are ending.
+const ENDING: Duration = Duration::from_secs(60);
+
+/// Rounds whose leading score is this close to the score limit are ending.
+const ENDING_SCORE_FRACTION: f32 = 0.9;
+
+/// How long a round has to be going on before the score progression is extrapolated.
+const MIN_SCORING_TIME: Duration = Duration::from_secs(60);
+
+/// The smallest leading score that is extrapolated, fewer kills say little about the pace.
+const MIN_LEADING_SCORE: i32 = 3;
+
+/// How far back player count changes are looked at.
+const PLAYER_COUNT_WINDOW: Duration = Duration::from_secs(3 * 60);
+
+/// How many player count changes are kept per room.
+const MAX_PLAYER_COUNTS: usize = 32;
+
+/// Rooms with at least this many players that lose half of them at once are taken to be ending.
+const MIN_CROWD: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchPhase {
+    /// Not enough is known to tell.
+    Unknown,
+    /// The round just started.
+    Warmup,
+    Active,
+    /// The round is about to end, or the room is loading the next map.
+    Ending,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhaseEstimate {
+    pub phase: MatchPhase,
+    /// How much of the round is left, if the room has a limit to go by.
+    pub remaining: Option<Duration>,
+}
+
+impl PhaseEstimate {
+    pub const UNKNOWN: Self = Self {
+        phase: MatchPhase::Unknown,
+        remaining: None,
+    };
+
+    /// The annotation for the room's name in the lobby list, if there is anything worth showing.
+    pub fn annotation(&self) -> Option<Annotation> {
+        let annotation = |full: String, short: String| {
+            Annotation::new(AnnotationKind::Phase, full).abbreviated(short)
+        };
+        match (self.phase, self.remaining) {
+            (MatchPhase::Unknown, _) => None,
+            (MatchPhase::Ending, _) => Some(annotation("[ending]".into(), "[end]".into())),
+            (_, Some(remaining)) => {
+                let minutes = minutes(remaining);
+                Some(annotation(
+                    format!("[~{minutes}m left]"),
+                    format!("[~{minutes}m]"),
+                ))
+            }
+            (MatchPhase::Warmup, None) => Some(annotation("[warmup]".into(), "[warm]".into())),
+            (MatchPhase::Active, None) => None,
+        }
+    }
+}
+
+impl Display for PhaseEstimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.phase {
+            MatchPhase::Unknown => write!(f, "unknown")?,
+            MatchPhase::Warmup => write!(f, "warmup")?,
+            MatchPhase::Active => write!(f, "active")?,
+            MatchPhase::Ending => write!(f, "ending")?,
+        }
+        match self.remaining {
+            Some(remaining) => write!(f, ", ~{}m left", minutes(remaining)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Rounds to whole minutes, showing at least one.
+fn minutes(duration: Duration) -> u64 {
+    ((duration.as_secs() + 30) / 60).max(1)
+}
+
+/// What is known about a round at some moment.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RoundInputs {
+    pub switching_map: Option<bool>,
+    /// How long the round has been going on.
+    pub elapsed: Option<Duration>,
+    pub round_time: Option<Duration>,
+    pub score_limit: Option<i32>,
+    /// The score of the leading player or team.
+    pub leading_score: Option<i32>,
+}
+
+/// Estimates the phase of a round from what is known about it.
+pub fn estimate(inputs: &RoundInputs) -> PhaseEstimate {
+    if inputs.switching_map == Some(true) {
+        return PhaseEstimate {
+            phase: MatchPhase::Ending,
+            remaining: None,
+        };
+    }
+
+    let score_fraction = match (inputs.leading_score, inputs.score_limit) {
+        (Some(score), Some(limit)) => Some(score as f32 / limit as f32),
+        _ => None,
+    };
+    let elapsed = match inputs.elapsed {
+        Some(elapsed) => elapsed,
+        // how close the score is to the limit is all that can be said without knowing when the round started
+        None => {
+            return match score_fraction {
+                Some(fraction) if fraction >= ENDING_SCORE_FRACTION => PhaseEstimate {
+                    phase: MatchPhase::Ending,
+                    remaining: None,
+                },
+                _ => PhaseEstimate::UNKNOWN,
+            }
+        }
+    };
+
+    let by_time = inputs
+        .round_time
+        .map(|round_time| round_time.saturating_sub(elapsed));
+    let by_score = match (inputs.leading_score, inputs.score_limit) {
+        (Some(score), Some(limit)) if score >= MIN_LEADING_SCORE && elapsed >= MIN_SCORING_TIME => {
+            let per_point = elapsed.as_secs_f32() / score as f32;
+            Some(Duration::from_secs_f32(
+                per_point * limit.saturating_sub(score).max(0) as f32,
+            ))
+        }
+        _ => None,
+    };
+    let remaining = match (by_time, by_score) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+
+    let ending = remaining.is_some_and(|r| r < ENDING)
+        || score_fraction.is_some_and(|f| f >= ENDING_SCORE_FRACTION);
+    let phase = match (ending, elapsed < WARMUP) {
+        (true, _) => MatchPhase::Ending,
+        (false, true) => MatchPhase::Warmup,
+        (false, false) => MatchPhase::Active,
+    };
+    PhaseEstimate { phase, remaining }
+}
+
+/// The score of the leading team, or of the leading player if nobody is on a team.
+pub fn leading_score<'a>(players: impl IntoIterator<Item = &'a PlayerActor>) -> Option<i32> {
+    let mut teams = HashMap::<u8, i32>::new();
+    let mut best_player = None;
+    for player in players {
+        let kills = match player.kills {
+            Some(kills) => kills as i32,
+            None => continue,
+        };
+        match player.team_number {
+            Some(team) => *teams.entry(team).or_default() += kills,
+            None => best_player = best_player.max(Some(kills)),
+        }
+    }
+    teams.into_values().max().max(best_player)
+}
+
+/// Follows the rounds of a room through its properties.
+#[derive(Debug, Clone, Default)]
+pub struct RoundTracker {
+    /// When we saw the current round start.
+    started: Option<Instant>,
+    switching_map: Option<bool>,
+    round_time: Option<Duration>,
+    score_limit: Option<i32>,
+    round_start_time: Option<i32>,
+    /// When the player count changed, oldest first.
+    player_counts: VecDeque<(Instant, u8)>,
+}
+
+impl RoundTracker {
+    /// Marks the round as starting now, such as when the room was just created.
+    pub fn start_round(&mut self, now: Instant) {
+        self.started = Some(now);
+    }
+
+    /// Takes in room properties as listed or changed. Changes only hold the properties that changed.
+    pub fn observe<M: Borrow<PhotonHashmap>>(
+        &mut self,
+        properties: &WellKnownRoomProperties<M>,
+        now: Instant,
+    ) {
+        if let Some(switching) = properties.switching_map() {
+            match (self.switching_map, switching) {
+                (Some(true), false) => self.started = Some(now),
+                (_, true) => self.started = None,
+                _ => (),
+            }
+            self.switching_map = Some(switching);
+        }
+        if let Some(round_time) = properties.round_time() {
+            self.round_time = Some(round_time);
+        }
+        if let Some(score_limit) = properties.score_limit() {
+            self.score_limit = Some(score_limit);
+        }
+        if let Some(round_start_time) = properties.round_start_time() {
+            self.round_start_time = Some(round_start_time);
+        }
+
+        if let Some(&count) = properties.player_count() {
+            if self.player_counts.back().map(|(_, c)| *c) != Some(count) {
+                self.player_counts.push_back((now, count));
+            }
+            // keep the latest change even if it is old, it is what later changes start from
+            while self.player_counts.len() > MAX_PLAYER_COUNTS
+                || (self.player_counts.len() > 1
+                    && now.saturating_duration_since(self.player_counts[0].0) > PLAYER_COUNT_WINDOW)
+            {
+                self.player_counts.pop_front();
+            }
+        }
+    }
+
+    /// Estimates the phase of the current round. `server_now` is needed to make use of the round start time, and the
+    /// leading score can only be known from inside the room.
+    pub fn estimate(
+        &self,
+        now: Instant,
+        server_now: Option<i32>,
+        leading_score: Option<i32>,
+    ) -> PhaseEstimate {
+        let elapsed = match (self.round_start_time, server_now) {
+            (Some(start), Some(server_now)) => Some(Duration::from_millis(
+                server_now.wrapping_sub(start).max(0) as u64,
+            )),
+            _ => self
+                .started
+                .map(|started| now.saturating_duration_since(started)),
+        };
+        let estimate = estimate(&RoundInputs {
+            switching_map: self.switching_map,
+            elapsed,
+            round_time: self.round_time,
+            score_limit: self.score_limit,
+            leading_score,
+        });
+        match estimate.phase {
+            MatchPhase::Unknown => self.estimate_from_player_counts(now),
+            _ => estimate,
+        }
+    }
+
+    /// Guesses the phase from how the player count changed: rooms fill up at the start of a round, and many players
+    /// leave when it ends.
+    fn estimate_from_player_counts(&self, now: Instant) -> PhaseEstimate {
+        let recent = self
+            .player_counts
+            .iter()
+            .filter(|(at, _)| now.saturating_duration_since(*at) <= PLAYER_COUNT_WINDOW)
+            .collect::<Vec<_>>();
+        let (first, last) = match (recent.first(), recent.last()) {
+            (Some(first), Some(last)) if recent.len() > 1 => (first, last),
+            _ => return PhaseEstimate::UNKNOWN,
+        };
+        let peak = recent.iter().map(|(_, count)| *count).max().unwrap_or(0);
+
+        let phase = if peak >= MIN_CROWD
+            && last.1 <= peak / 2
+            && now.saturating_duration_since(last.0) <= ENDING
+        {
+            MatchPhase::Ending
+        } else if first.1 <= 1 && last.1 >= MIN_CROWD {
+            MatchPhase::Warmup
+        } else {
+            MatchPhase::Unknown
+        };
+        PhaseEstimate {
+            phase,
+            remaining: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use photon_lib::{
+        highlevel::{
+            constants::{event_code, game_property_key, operation_code, parameter_code},
+            structs::{RoomInfoList, RoomInfoView, WellKnownRoomProperties},
+            PhotonParameterMapConversion,
+        },
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationRequest, PhotonMessage},
+        PhotonHashmap,
+    };
+
+    use super::{leading_score, MatchPhase, PhaseEstimate, RoundTracker};
+    use crate::{
+        hax::{timeline::Replay, PlayerActor},
+        inspect::CapturedMessage,
+        proxy::{Direction, WebSocketServer},
+        testsupport::{captured_from, string},
+    };
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    /// Feeds the property changes to a tracker, each at its offset from the start.
+    fn track(start: Instant, changes: Vec<(u64, PhotonHashmap)>) -> RoundTracker {
+        let mut tracker = RoundTracker::default();
+        for (at, properties) in changes {
+            tracker.observe(&WellKnownRoomProperties::new(&properties), start + secs(at));
+        }
+        tracker
+    }
+
+    fn phase(estimate: PhaseEstimate) -> (MatchPhase, Option<u64>) {
+        (estimate.phase, estimate.remaining.map(|r| r.as_secs()))
+    }
+
+    #[test]
+    fn follows_round_time() {
+        let start = Instant::now();
+        let tracker = track(
+            start,
+            vec![
+                (
+                    0,
+                    indexmap! {
+                        string("switchingmap") => PhotonDataType::Boolean(true),
+                        string("roundTime") => PhotonDataType::Short(600),
+                    },
+                ),
+                (
+                    20,
+                    indexmap! { string("switchingmap") => PhotonDataType::Boolean(false) },
+                ),
+            ],
+        );
+        let at = |s| tracker.estimate(start + secs(s), None, None);
+
+        assert_eq!(phase(at(30)), (MatchPhase::Warmup, Some(590)));
+        assert_eq!(
+            at(30).annotation().map(|a| a.full).as_deref(),
+            Some("[~10m left]")
+        );
+        assert_eq!(phase(at(320)), (MatchPhase::Active, Some(300)));
+        assert_eq!(at(320).to_string(), "active, ~5m left");
+        assert_eq!(phase(at(590)), (MatchPhase::Ending, Some(30)));
+        assert_eq!(
+            at(590).annotation().map(|a| a.full).as_deref(),
+            Some("[ending]")
+        );
+
+        // joining in the middle of a round doesn't tell when it started
+        let joined = track(
+            start,
+            vec![(
+                0,
+                indexmap! {
+                    string("switchingmap") => PhotonDataType::Boolean(false),
+                    string("roundTime") => PhotonDataType::Short(600),
+                },
+            )],
+        );
+        assert_eq!(
+            joined.estimate(start + secs(60), None, None),
+            PhaseEstimate::UNKNOWN
+        );
+        assert_eq!(PhaseEstimate::UNKNOWN.annotation(), None);
+    }
+
+    #[test]
+    fn extrapolates_score() {
+        let start = Instant::now();
+        let tracker = track(
+            start,
+            vec![(
+                0,
+                indexmap! {
+                    string("scoreLimit") => PhotonDataType::Integer(50),
+                    string("roundStartTime") => PhotonDataType::Integer(100_000),
+                },
+            )],
+        );
+
+        // 10 kills in 2 minutes, the remaining 40 take another 8
+        let estimate = tracker.estimate(start, Some(220_000), Some(10));
+        assert_eq!(phase(estimate), (MatchPhase::Active, Some(480)));
+        // too early to tell the pace
+        assert_eq!(
+            phase(tracker.estimate(start, Some(130_000), Some(4))),
+            (MatchPhase::Active, None)
+        );
+        assert_eq!(
+            tracker.estimate(start, Some(110_000), Some(0)).phase,
+            MatchPhase::Warmup
+        );
+        assert_eq!(
+            tracker.estimate(start, Some(400_000), Some(46)).phase,
+            MatchPhase::Ending
+        );
+        // close to the limit is ending even without knowing the elapsed time
+        assert_eq!(
+            tracker.estimate(start, None, Some(46)).phase,
+            MatchPhase::Ending
+        );
+        assert_eq!(
+            tracker.estimate(start, None, Some(20)),
+            PhaseEstimate::UNKNOWN
+        );
+    }
+
+    fn player_count(count: u8) -> PhotonHashmap {
+        indexmap! {
+            PhotonDataType::Byte(game_property_key::PLAYER_COUNT) => PhotonDataType::Byte(count),
+        }
+    }
+
+    #[test]
+    fn falls_back_to_player_counts() {
+        let start = Instant::now();
+        let emptying = track(
+            start,
+            vec![
+                (0, player_count(10)),
+                (40, player_count(9)),
+                (70, player_count(4)),
+            ],
+        );
+        assert_eq!(
+            emptying.estimate(start + secs(80), None, None).phase,
+            MatchPhase::Ending
+        );
+        // a while after the players left, a new round may have started
+        assert_eq!(
+            emptying.estimate(start + secs(200), None, None),
+            PhaseEstimate::UNKNOWN
+        );
+
+        let filling = track(
+            start,
+            vec![
+                (0, player_count(1)),
+                (30, player_count(3)),
+                (50, player_count(6)),
+            ],
+        );
+        assert_eq!(
+            filling
+                .estimate(start + secs(60), None, None)
+                .annotation()
+                .map(|a| a.full)
+                .as_deref(),
+            Some("[warmup]")
+        );
+
+        let steady = track(start, vec![(0, player_count(8)), (30, player_count(7))]);
+        assert_eq!(
+            steady.estimate(start + secs(60), None, None),
+            PhaseEstimate::UNKNOWN
+        );
+        assert_eq!(
+            RoundTracker::default().estimate(start, None, None),
+            PhaseEstimate::UNKNOWN
+        );
+    }
+
+    #[test]
+    fn scores_teams() {
+        let player = |team_number, kills| PlayerActor {
+            team_number,
+            kills: Some(kills),
+            ..Default::default()
+        };
+        assert_eq!(
+            leading_score(&[player(Some(0), 5), player(Some(0), 4), player(Some(1), 7)]),
+            Some(9)
+        );
+        assert_eq!(leading_score(&[player(None, 5), player(None, 7)]), Some(7));
+        assert_eq!(leading_score(&[PlayerActor::default()]), None);
+    }
+
+    fn game_list(code: u8, id: &str, properties: PhotonHashmap) -> CapturedMessage {
+        captured_from(
+            WebSocketServer::LobbyServer,
+            Direction::ServerToClient,
+            PhotonMessage::EventData(EventData {
+                code,
+                parameters: indexmap! {
+                    parameter_code::GAME_LIST => PhotonDataType::Hashtable(indexmap! {
+                        string(id) => PhotonDataType::Hashtable(properties),
+                    }),
+                },
+            }),
+        )
+    }
+
+    fn room_name(replay: &mut Replay, message: &CapturedMessage, id: &str) -> Option<String> {
+        let raw = replay.forward(message).unwrap();
+        let mut event = match PhotonMessage::from_websocket_bytes(&mut raw.as_slice()).unwrap() {
+            PhotonMessage::EventData(event) => event,
+            other => panic!("expected a game list, got {other:?}"),
+        };
+        let games = RoomInfoList::from_map(&mut event.parameters).unwrap().games;
+        match &games[&string(id)] {
+            PhotonDataType::Hashtable(room) => {
+                RoomInfoView(room).custom_str("roomName").map(Into::into)
+            }
+            other => panic!("expected a room, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn annotates_lobby_rooms() {
+        let mut replay = Replay::default();
+        replay
+            .state()
+            .update_settings(|s| s.lobby_phase_annotations = true);
+        replay.feed(&captured_from(
+            WebSocketServer::LobbyServer,
+            Direction::ClientToServer,
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::AUTHENTICATE,
+                parameters: indexmap! {},
+            }),
+        ));
+
+        let listed = game_list(
+            event_code::GAME_LIST,
+            "old",
+            indexmap! {
+                string("roomName") => string("Old"),
+                string("roundTime") => PhotonDataType::Integer(600),
+            },
+        );
+        // nothing is known about rooms that were already running
+        assert_eq!(
+            room_name(&mut replay, &listed, "old").as_deref(),
+            Some("Old")
+        );
+
+        let created = game_list(
+            event_code::GAME_LIST_UPDATE,
+            "new",
+            indexmap! {
+                string("roomName") => string("New"),
+                string("roundTime") => PhotonDataType::Integer(600),
+            },
+        );
+        assert_eq!(
+            room_name(&mut replay, &created, "new").as_deref(),
+            Some("[~10m left] New")
+        );
+
+        let state = replay.state();
+        let now = Instant::now();
+        assert_eq!(
+            state.lobby_room("new").unwrap().phase(now).phase,
+            MatchPhase::Warmup
+        );
+        assert_eq!(
+            state.lobby_room("old").unwrap().phase(now),
+            PhaseEstimate::UNKNOWN
+        );
+    }
+
+    #[test]
+    fn annotations_dont_stack() {
+        let mut replay = Replay::default();
+        replay.state().update_settings(|s| {
+            s.lobby_phase_annotations = true;
+            s.strip_passwords = true;
+        });
+        replay.feed(&captured_from(
+            WebSocketServer::LobbyServer,
+            Direction::ClientToServer,
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::AUTHENTICATE,
+                parameters: indexmap! {},
+            }),
+        ));
+
+        let created = game_list(
+            event_code::GAME_LIST_UPDATE,
+            "new",
+            indexmap! {
+                string("roomName") => string("New"),
+                string("password") => string("1234"),
+                string("roundTime") => PhotonDataType::Integer(600),
+            },
+        );
+        assert_eq!(
+            room_name(&mut replay, &created, "new").as_deref(),
+            Some("[~10m left] [p] New")
+        );
+
+        // the update as the client got it, eg. when it's run through the hook again
+        let relisted = game_list(
+            event_code::GAME_LIST_UPDATE,
+            "new",
+            indexmap! {
+                string("roomName") => string("[~10m left] [p] New"),
+                string("password") => string(""),
+                string("roundTime") => PhotonDataType::Integer(600),
+            },
+        );
+        assert_eq!(
+            room_name(&mut replay, &relisted, "new").as_deref(),
+            Some("[~10m left] [p] New")
+        );
+        let state = replay.state();
+        let name = state
+            .lobby_room("new")
+            .unwrap()
+            .overlay
+            .unwrap()
+            .name
+            .clone();
+        assert_eq!(name.unwrap().original, "New");
+    }
+}
@@ -0,0 +1,757 @@
+//! A summary of every match we played, assembled when leaving the room.
+//!
+//! A [MatchTracker] lives in the [GameplayState](super::GameplayState) and collects what can't be read from the rest
+//! of the state afterwards: the traffic of the connection, our own score and ping, the players that left during the
+//! match and what features changed. When we leave the room, or when the game server connection closes before that,
+//! it is turned into a [MatchSummary] that is kept in the [MatchHistory].
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use photon_lib::indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use super::{
+    actor_history::{ActorHistory, PropertyChange},
+    bandwidth::BandwidthReport,
+    disconnects::DisconnectReason,
+    room_property_history::RoomPropertyChange,
+    PlayerActor,
+};
+use crate::{
+    config::versioned::{self, Migration, VersionError, Versioned},
+    protocol::player_script::PlayerScript,
+    proxy::Direction,
+};
+
+/// How many summaries are kept in the [MatchHistory]. The oldest ones are dropped first.
+pub const MAX_MATCHES: usize = 100;
+
+/// How many players that left a match to remember, the ones that left first are forgotten.
+const MAX_LEFT_PLAYERS: usize = 256;
+
+/// How a match ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchEnd {
+    /// The client asked to leave the room.
+    Left,
+    /// The game server connection closed while we were still in the room.
+    Disconnected,
+    /// The client was moved to another game server while still in the room, see
+    /// [server_migration](super::server_migration).
+    Migrated,
+}
+
+impl Display for MatchEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MatchEnd::Left => write!(f, "left"),
+            MatchEnd::Disconnected => write!(f, "disconnected"),
+            MatchEnd::Migrated => write!(f, "moved to another game server"),
+        }
+    }
+}
+
+/// Another player that was in the room at some point during the match.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncounteredPlayer {
+    pub actor_id: i32,
+    pub nickname: Option<String>,
+    pub user_id: Option<String>,
+    /// The properties they changed during the match, such as renames. See [actor_history](super::actor_history).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<PropertyChange>,
+}
+
+impl EncounteredPlayer {
+    fn new(actor_id: i32, player: &PlayerActor) -> Self {
+        Self {
+            actor_id,
+            nickname: player.nickname.clone(),
+            user_id: player.user_id.clone(),
+            changes: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MatchSummary {
+    pub room_name: Option<String>,
+    /// When the first message of the game server connection was seen.
+    pub started: SystemTime,
+    pub duration: Duration,
+    pub ended_by: MatchEnd,
+    /// Our kills, as last reported by our client. [None] if we never spawned.
+    pub kills: Option<i16>,
+    /// Our deaths, as last reported by our client. [None] if we never spawned.
+    pub deaths: Option<i16>,
+    /// Everyone else that was in the room, including the players that left before us.
+    pub players: Vec<EncounteredPlayer>,
+    /// The average of the pings our client reported, in milliseconds.
+    pub average_ping: Option<f32>,
+    /// The bytes the client sent to the game server, as received by the proxy.
+    pub bytes_up: u64,
+    /// The bytes the game server sent to the client, as received by the proxy.
+    pub bytes_down: u64,
+    /// How many messages each feature rewrote, dropped or injected during the match.
+    pub modifications: BTreeMap<String, u64>,
+    /// When the match started, if this part of it was played after being moved to another game server. The summaries
+    /// of all parts of the match have it, see [server_migration](super::server_migration).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub continues: Option<SystemTime>,
+    /// Why the game server connection closed, if the match [ended](MatchEnd::Disconnected) that way. See
+    /// [disconnects](super::disconnects).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disconnect: Option<DisconnectReason>,
+    /// How the room's properties changed while we were in it, oldest first. See
+    /// [room_property_history](super::room_property_history).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub room_property_changes: Vec<RoomPropertyChange>,
+}
+
+impl MatchSummary {
+    /// Kills per death. With no deaths, this is the number of kills.
+    pub fn kd_ratio(&self) -> Option<f32> {
+        let (kills, deaths) = (self.kills?, self.deaths?);
+        Some(kills as f32 / deaths.max(1) as f32)
+    }
+
+    /// How many messages were modified by any feature.
+    pub fn total_modifications(&self) -> u64 {
+        self.modifications.values().sum()
+    }
+}
+
+impl Display for MatchSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.duration.as_secs();
+        write!(
+            f,
+            "Match in {} ({}",
+            self.room_name.as_deref().unwrap_or("unknown room"),
+            self.ended_by,
+        )?;
+        if let Some(reason) = &self.disconnect {
+            write!(f, ", {reason}")?;
+        }
+        write!(f, ") lasted {}m {:02}s: ", secs / 60, secs % 60)?;
+        match (self.kills, self.deaths) {
+            (Some(kills), Some(deaths)) => write!(f, "{kills}/{deaths} K/D")?,
+            _ => write!(f, "no K/D")?,
+        }
+        write!(f, ", {} other players", self.players.len())?;
+        if let Some(ping) = self.average_ping {
+            write!(f, ", {ping:.0}ms avg ping")?;
+        }
+        write!(
+            f,
+            ", {:.1} KiB up / {:.1} KiB down, {} modified messages",
+            self.bytes_up as f64 / 1024.0,
+            self.bytes_down as f64 / 1024.0,
+            self.total_modifications(),
+        )?;
+        if !self.modifications.is_empty() {
+            let features = self
+                .modifications
+                .iter()
+                .map(|(feature, count)| format!("{feature}: {count}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            write!(f, " ({features})")?;
+        }
+        Ok(())
+    }
+}
+
+/// The injected messages per feature, to tell the injections of a match apart from earlier ones.
+pub(crate) fn injected_messages(report: &BandwidthReport) -> IndexMap<&'static str, u64> {
+    report
+        .totals
+        .iter()
+        .filter(|(_, usage)| usage.injected_messages > 0)
+        .map(|(feature, usage)| (*feature, usage.injected_messages))
+        .collect()
+}
+
+/// Collects the parts of a [MatchSummary] that aren't kept elsewhere in the state.
+#[derive(Debug, Default, Clone)]
+pub struct MatchTracker {
+    started: Option<(Instant, SystemTime)>,
+    finished: bool,
+    bytes_up: u64,
+    bytes_down: u64,
+    ping_total: u64,
+    ping_samples: u64,
+    kills: Option<i16>,
+    deaths: Option<i16>,
+    /// Players that left before the match ended, in the order they left.
+    left_players: Vec<EncounteredPlayer>,
+    /// Rewritten and dropped messages per feature.
+    modifications: IndexMap<&'static str, u64>,
+    /// The injected messages per feature when the match started, see [injected_messages].
+    injection_baseline: IndexMap<&'static str, u64>,
+    /// When the match this one continues started, see [Self::continue_match].
+    continues: Option<SystemTime>,
+}
+
+impl MatchTracker {
+    pub fn is_started(&self) -> bool {
+        self.started.is_some()
+    }
+
+    /// Whether the summary was already produced. Nothing is recorded after that.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Marks this match as the continuation of one that started at the given time, on the game server we were moved
+    /// away from.
+    pub fn continue_match(&mut self, started: SystemTime) {
+        self.continues = Some(started);
+    }
+
+    pub fn start(
+        &mut self,
+        now: Instant,
+        wall_clock: SystemTime,
+        injection_baseline: IndexMap<&'static str, u64>,
+    ) {
+        self.started = Some((now, wall_clock));
+        self.injection_baseline = injection_baseline;
+    }
+
+    pub fn record_traffic(&mut self, direction: Direction, len: usize) {
+        if self.finished {
+            return;
+        }
+        match direction {
+            Direction::ClientToServer => self.bytes_up += len as u64,
+            Direction::ServerToClient => self.bytes_down += len as u64,
+        }
+    }
+
+    /// Counts a message that a feature rewrote or dropped.
+    pub fn record_modification(&mut self, feature: &'static str) {
+        if !self.finished {
+            *self.modifications.entry(feature).or_default() += 1;
+        }
+    }
+
+    /// Takes the score and ping from a player script our client sent.
+    pub fn record_own_script(&mut self, script: &PlayerScript) {
+        if self.finished {
+            return;
+        }
+        self.kills = Some(script.number_of_kills);
+        self.deaths = Some(script.number_of_deaths);
+        // the client sends 0 until it measured its ping
+        if script.ping > 0 {
+            self.ping_total += script.ping as u64;
+            self.ping_samples += 1;
+        }
+    }
+
+    /// Remembers a player that left, so they still show up in the summary.
+    pub fn record_left_player(&mut self, actor_id: i32, player: &PlayerActor) {
+        if !self.finished {
+            if self.left_players.len() >= MAX_LEFT_PLAYERS {
+                self.left_players.remove(0);
+            }
+            self.left_players
+                .push(EncounteredPlayer::new(actor_id, player));
+        }
+    }
+
+    /// Produces the summary, or [None] if the match didn't start or was already summarized.
+    ///
+    /// `players` are the players still in the room, `own_actor` is left out of them. What they changed is taken from
+    /// `history`, leaving out the first values seen of them.
+    #[allow(clippy::too_many_arguments)]
+    pub fn finish<'a>(
+        &mut self,
+        room_name: Option<String>,
+        players: impl IntoIterator<Item = (&'a i32, &'a PlayerActor)>,
+        own_actor: Option<i32>,
+        history: &ActorHistory,
+        ended_by: MatchEnd,
+        now: Instant,
+        injections: &IndexMap<&'static str, u64>,
+    ) -> Option<MatchSummary> {
+        let (start, started) = match (self.started, self.finished) {
+            (Some(started), false) => started,
+            _ => return None,
+        };
+        self.finished = true;
+
+        let mut modifications = BTreeMap::new();
+        for (feature, count) in &self.modifications {
+            *modifications.entry(feature.to_string()).or_default() += count;
+        }
+        for (feature, count) in injections {
+            let before = self.injection_baseline.get(feature).copied().unwrap_or(0);
+            if *count > before {
+                *modifications.entry(feature.to_string()).or_default() += count - before;
+            }
+        }
+
+        let mut encountered = self.left_players.clone();
+        for (actor_id, player) in players {
+            if Some(*actor_id) != own_actor {
+                encountered.push(EncounteredPlayer::new(*actor_id, player));
+            }
+        }
+        for player in &mut encountered {
+            player.changes = history
+                .changes(player.actor_id)
+                .filter(|c| c.old.is_some())
+                .cloned()
+                .collect();
+        }
+
+        Some(MatchSummary {
+            room_name,
+            started,
+            duration: now.saturating_duration_since(start),
+            ended_by,
+            kills: self.kills,
+            deaths: self.deaths,
+            players: encountered,
+            average_ping: match self.ping_samples {
+                0 => None,
+                n => Some(self.ping_total as f32 / n as f32),
+            },
+            bytes_up: self.bytes_up,
+            bytes_down: self.bytes_down,
+            modifications,
+            continues: self.continues,
+            disconnect: None,
+            room_property_changes: vec![],
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct HistoryFile {
+    /// Oldest first.
+    matches: VecDeque<MatchSummary>,
+}
+
+impl Versioned for HistoryFile {
+    const NAME: &'static str = "match history";
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+/// The summaries of the last [MAX_MATCHES] matches.
+#[derive(Debug, Clone, Default)]
+pub struct MatchHistory {
+    /// Where the history is saved. If not set, the history is only kept in memory.
+    path: Option<PathBuf>,
+    data: HistoryFile,
+}
+
+impl MatchHistory {
+    /// Loads the history from the given file.
+    ///
+    /// This never fails. If the file is missing or corrupt, an empty history is returned that will overwrite the file
+    /// when a match finishes. A history written by a newer version is never overwritten, matches are only remembered
+    /// until the program exits then.
+    pub fn load(path: &Path) -> Self {
+        let data = match versioned::read_optional(path) {
+            Ok(Some(data)) => data,
+            Ok(None) => HistoryFile::default(),
+            Err(e) if matches!(e.downcast_ref(), Some(VersionError::NewerVersion { .. })) => {
+                warn!(
+                    path = format!("{path:?}"),
+                    "Could not load match history, new matches won't be saved: {e:#}"
+                );
+                return Self::default();
+            }
+            Err(e) => {
+                warn!(
+                    path = format!("{path:?}"),
+                    "Could not read match history, starting with an empty history: {e:#}"
+                );
+                HistoryFile::default()
+            }
+        };
+
+        Self {
+            path: Some(path.to_owned()),
+            data,
+        }
+    }
+
+    /// Where the history is saved, if anywhere.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub(super) fn save(&self) -> anyhow::Result<()> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        versioned::write(path, &self.data)?;
+        debug!("Saved match history");
+        Ok(())
+    }
+
+    /// Adds a summary, dropping the oldest one if there are too many, and saves the history.
+    pub fn push(&mut self, summary: MatchSummary) -> anyhow::Result<()> {
+        if self.data.matches.len() >= MAX_MATCHES {
+            self.data.matches.pop_front();
+        }
+        self.data.matches.push_back(summary);
+        self.save()
+    }
+
+    /// All summaries, oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &MatchSummary> {
+        self.data.matches.iter()
+    }
+
+    pub fn latest(&self) -> Option<&MatchSummary> {
+        self.data.matches.back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.matches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.matches.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use photon_lib::{
+        highlevel::{
+            constants::{
+                actor_properties, event_code, operation_code, parameter_code, pun_event_code,
+            },
+            structs::RaiseEvent,
+            PhotonParameterMapConversion,
+        },
+        indexmap::{indexmap, IndexMap},
+        ordered_float::OrderedFloat,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationRequest, OperationResponse, PhotonMessage},
+        primitives::{Quaternion, Vector3},
+    };
+
+    use super::{EncounteredPlayer, MatchEnd, MatchTracker};
+    use crate::{
+        hax::{
+            actor_history::ActorHistory, bandwidth::feature, events::HaxEvent, timeline::Replay,
+            PlayerActor,
+        },
+        inspect::CapturedMessage,
+        protocol::player_script::PlayerScript,
+        proxy::Direction,
+        testsupport::captured,
+    };
+
+    fn script(kills: i16, deaths: i16, ping: i16) -> PlayerScript {
+        PlayerScript {
+            pitch: 0,
+            yaw: 0,
+            move_angle: 0,
+            number_of_kills: kills,
+            number_of_deaths: deaths,
+            number_of_rounds: 0,
+            ping,
+            last_local_hit_y: 0,
+            gun_game_score: 0,
+            velocity_x: 0,
+            velocity_y: 0,
+            velocity_z: 0,
+            health: 10000,
+            accessory_type: 0,
+            barrel_type: 0,
+            sight_type: 0,
+            weapon_last_damaged_from: 0,
+            bitflags: 0,
+            last_damager_id: -1,
+            position: Vector3(OrderedFloat(0.0), OrderedFloat(0.0), OrderedFloat(0.0)),
+            rotation: Quaternion(
+                OrderedFloat(0.0),
+                OrderedFloat(0.0),
+                OrderedFloat(0.0),
+                OrderedFloat(1.0),
+            ),
+        }
+    }
+
+    fn player(nickname: &str) -> PlayerActor {
+        PlayerActor {
+            nickname: Some(nickname.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn summarizes_tracked_match() {
+        let start = Instant::now();
+        let started = UNIX_EPOCH + Duration::from_secs(1_665_000_000);
+        let mut tracker = MatchTracker::default();
+        tracker.start(start, started, indexmap! { feature::SIMULATION => 5 });
+
+        tracker.record_traffic(Direction::ClientToServer, 100);
+        tracker.record_traffic(Direction::ServerToClient, 2000);
+        tracker.record_own_script(&script(0, 0, 0));
+        tracker.record_own_script(&script(3, 1, 60));
+        tracker.record_own_script(&script(4, 2, 80));
+        tracker.record_modification(feature::RPC_MUTING);
+        tracker.record_modification(feature::RPC_MUTING);
+        tracker.record_left_player(2, &player("quitter"));
+
+        let players: IndexMap<i32, PlayerActor> = indexmap! {
+            1 => player("me"),
+            3 => player("stayer"),
+        };
+        let now = start + Duration::from_secs(125);
+        let injections = indexmap! { feature::SIMULATION => 8, feature::AUTO_RESPONSES => 1 };
+        let summary = tracker
+            .finish(
+                Some("room".into()),
+                &players,
+                Some(1),
+                &ActorHistory::default(),
+                MatchEnd::Left,
+                now,
+                &injections,
+            )
+            .unwrap();
+
+        assert_eq!(summary.started, started);
+        assert_eq!(summary.duration, Duration::from_secs(125));
+        assert_eq!((summary.kills, summary.deaths), (Some(4), Some(2)));
+        assert_eq!(summary.kd_ratio(), Some(2.0));
+        // the ping is only averaged once the client measured it
+        assert_eq!(summary.average_ping, Some(70.0));
+        assert_eq!((summary.bytes_up, summary.bytes_down), (100, 2000));
+        assert_eq!(
+            summary
+                .players
+                .iter()
+                .map(|p| p.nickname.as_deref().unwrap())
+                .collect::<Vec<_>>(),
+            ["quitter", "stayer"]
+        );
+        assert_eq!(
+            summary.modifications,
+            [
+                (feature::AUTO_RESPONSES.to_string(), 1),
+                (feature::RPC_MUTING.to_string(), 2),
+                (feature::SIMULATION.to_string(), 3),
+            ]
+            .into()
+        );
+        assert_eq!(
+            summary.to_string(),
+            "Match in room (left) lasted 2m 05s: 4/2 K/D, 2 other players, 70ms avg ping, 0.1 KiB up / 2.0 KiB \
+             down, 6 modified messages (RPC muting: 2, auto-responses: 1, simulation: 3)"
+        );
+
+        // a match is only summarized once
+        tracker.record_traffic(Direction::ClientToServer, 100);
+        assert!(tracker
+            .finish(
+                None,
+                &players,
+                None,
+                &ActorHistory::default(),
+                MatchEnd::Disconnected,
+                now,
+                &injections
+            )
+            .is_none());
+        assert!(MatchTracker::default()
+            .finish(
+                None,
+                &players,
+                None,
+                &ActorHistory::default(),
+                MatchEnd::Disconnected,
+                now,
+                &injections
+            )
+            .is_none());
+    }
+
+    fn request(operation_code: u8) -> PhotonMessage {
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code,
+            parameters: indexmap! {},
+        })
+    }
+
+    /// A match in which we score a kill, someone leaves, a cosmetic RPC is muted and we leave, as seen by the proxy.
+    fn short_match() -> Vec<CapturedMessage> {
+        let actor = |name: &str, user_id: &str| {
+            PhotonDataType::Hashtable(indexmap! {
+                PhotonDataType::Byte(actor_properties::PLAYER_NAME) => PhotonDataType::String(name.into()),
+                PhotonDataType::Byte(actor_properties::USER_ID) => PhotonDataType::String(user_id.into()),
+            })
+        };
+        let join = PhotonMessage::OperationResponse(OperationResponse {
+            operation_code: operation_code::JOIN_GAME,
+            return_code: 0,
+            debug_message: None,
+            parameters: indexmap! {
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                parameter_code::ROOM_NAME => PhotonDataType::String("pro lobby".into()),
+                parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::Integer(1) => actor("me", "u1"),
+                    PhotonDataType::Integer(2) => actor("quitter", "u2"),
+                    PhotonDataType::Integer(3) => actor("stayer", "u3"),
+                }),
+                parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+            },
+        });
+        let serialize = |kills: i16, ping: i16| {
+            let mut object = vec![
+                PhotonDataType::Integer(1001),
+                PhotonDataType::Boolean(false),
+                PhotonDataType::Null,
+            ];
+            object.extend(script(kills, 0, ping).to_object_array());
+            let data = PhotonDataType::Hashtable(indexmap! {
+                PhotonDataType::Byte(0) => PhotonDataType::Integer(12345),
+                PhotonDataType::Byte(1) => PhotonDataType::Short(0),
+                PhotonDataType::Byte(10) => PhotonDataType::ObjectArray(object),
+            });
+            let mut parameters = IndexMap::new();
+            RaiseEvent::new(pun_event_code::SEND_SERIALIZE, data).into_map(&mut parameters);
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::RAISE_EVENT,
+                parameters,
+            })
+        };
+        let leave = PhotonMessage::EventData(EventData {
+            code: event_code::LEAVE,
+            parameters: indexmap! {
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(2),
+            },
+        });
+        let muted_rpc = PhotonMessage::EventData(EventData {
+            code: pun_event_code::RPC,
+            parameters: indexmap! {
+                parameter_code::CUSTOM_EVENT_CONTENT => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::Byte(0) => PhotonDataType::Integer(3001),
+                    PhotonDataType::Byte(3) => PhotonDataType::String("WeaponCamoChanged".into()),
+                }),
+            },
+        });
+
+        vec![
+            captured(
+                Direction::ClientToServer,
+                request(operation_code::AUTHENTICATE),
+            ),
+            captured(Direction::ServerToClient, join),
+            captured(Direction::ClientToServer, serialize(0, 40)),
+            captured(Direction::ServerToClient, leave),
+            captured(Direction::ServerToClient, muted_rpc),
+            captured(Direction::ClientToServer, serialize(1, 60)),
+        ]
+    }
+
+    fn replay(messages: &[CapturedMessage]) -> Replay {
+        let mut replay = Replay::default();
+        replay
+            .state()
+            .update_settings(|s| s.mute_all_cosmetic = true);
+        for message in messages {
+            replay.feed(message);
+        }
+        replay
+    }
+
+    #[test]
+    fn replayed_match_is_summarized_on_leave() {
+        let mut messages = short_match();
+        messages.push(captured(
+            Direction::ClientToServer,
+            request(operation_code::LEAVE),
+        ));
+        let mut replay = Replay::default();
+        let mut events = replay.state().events.subscribe();
+        replay
+            .state()
+            .update_settings(|s| s.mute_all_cosmetic = true);
+        for message in &messages {
+            replay.feed(message);
+        }
+
+        let state = replay.state();
+        assert!(state.stats.recent_errors.is_empty());
+        assert_eq!(state.match_history.len(), 1);
+        let summary = state.match_history.latest().unwrap();
+        assert_eq!(summary.room_name.as_deref(), Some("pro lobby"));
+        assert_eq!(summary.ended_by, MatchEnd::Left);
+        assert_eq!((summary.kills, summary.deaths), (Some(1), Some(0)));
+        assert_eq!(summary.average_ping, Some(50.0));
+        assert_eq!(
+            summary.players,
+            [
+                EncounteredPlayer {
+                    actor_id: 2,
+                    nickname: Some("quitter".into()),
+                    user_id: Some("u2".into()),
+                    changes: vec![],
+                },
+                EncounteredPlayer {
+                    actor_id: 3,
+                    nickname: Some("stayer".into()),
+                    user_id: Some("u3".into()),
+                    changes: vec![],
+                },
+            ]
+        );
+        let bytes = |direction| {
+            messages
+                .iter()
+                .filter(|m| m.direction == direction)
+                .map(|m| m.raw.len() as u64)
+                .sum::<u64>()
+        };
+        assert_eq!(summary.bytes_up, bytes(Direction::ClientToServer));
+        assert_eq!(summary.bytes_down, bytes(Direction::ServerToClient));
+        assert_eq!(
+            summary.modifications,
+            [(feature::RPC_MUTING.to_string(), 1)].into()
+        );
+        assert!(matches!(
+            events.try_recv(),
+            Ok(HaxEvent::MatchFinished(s)) if &s == summary
+        ));
+    }
+
+    #[test]
+    fn disconnect_summarizes_what_was_seen() {
+        let replay = replay(&short_match()[..4]);
+        let mut state = replay.state();
+
+        let summary = state.finish_match(MatchEnd::Disconnected).unwrap();
+        assert_eq!(summary.ended_by, MatchEnd::Disconnected);
+        assert_eq!((summary.kills, summary.deaths), (Some(0), Some(0)));
+        assert_eq!(summary.players.len(), 2);
+        assert!(summary.modifications.is_empty());
+        assert_eq!(state.match_history.len(), 1);
+
+        // the connection closing after we left doesn't add another summary
+        assert!(state.finish_match(MatchEnd::Disconnected).is_none());
+        assert_eq!(state.match_history.len(), 1);
+    }
+}
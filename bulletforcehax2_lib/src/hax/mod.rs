@@ -1,22 +1,192 @@
 //! The main module of BulletForceHaxV2.
 
+pub mod actor_history;
+pub mod auth_overrides;
+pub mod availability;
+pub mod ballistics;
+pub mod bandwidth;
+pub mod client_health;
+pub mod detection;
+pub mod disconnects;
+pub mod drift;
+pub mod drop_log;
+pub mod dry_run;
+pub mod encryption;
+pub mod event_dedup;
+pub mod events;
+pub mod extrapolation;
+pub mod features;
+pub mod game_server_routes;
+pub mod game_variant;
+pub mod ghost_join;
+pub mod handler_timing;
 mod hax_impl;
+pub mod hold;
+pub mod host_migration;
+pub mod identity_randomizer;
 mod impl_proxy;
+pub mod interest_groups;
+pub mod join_trace;
+pub mod journal;
+pub mod kill_feed;
+pub mod late_join;
+pub mod link_quality;
+pub mod lobby_cache;
+pub mod lobby_sort;
+pub mod map_annotations;
+pub mod match_phase;
+pub mod match_summary;
+pub mod parse_breaker;
+pub mod platform_spoof;
+pub mod population;
+pub mod profiles;
+pub mod projectiles;
+pub mod property_firewall;
+pub mod property_refresh;
+pub mod public_feed;
+pub mod replayed_rooms;
+pub mod restriction_detector;
+pub mod room_expectations;
+pub mod room_names;
+pub mod room_notes;
+pub mod room_overlay;
+pub mod room_probe;
+pub mod room_property_history;
+pub mod room_rename;
+pub mod rpc_usage;
+pub mod scheduler;
+pub mod selftest;
+pub mod server_migration;
+pub mod session_report;
+pub mod session_timeline;
+pub mod settings;
+#[cfg(feature = "shared_state")]
+pub mod shared_state;
+pub mod shutdown;
+#[cfg(feature = "simulation")]
+pub mod simulation;
+mod status;
+pub mod stealth_host;
+pub mod timeline;
+pub mod transforms;
+pub mod ui_snapshot;
+mod validation;
+pub mod watchlist;
+pub mod web_assets;
+pub mod web_requests;
 
-use std::sync::Arc;
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use photon_lib::{
-    highlevel::structs::{InstantiationEventData, Player, ViewId},
+    display::DisplayLimits,
+    highlevel::{
+        constants::operation_code,
+        structs::{
+            ChangeGroupsRequest, InstantiationEventData, Player, ViewId, WellKnownRoomProperties,
+        },
+        PhotonParameterMapConversion,
+    },
     indexmap::IndexMap,
-    photon_data_type::PhotonDataType,
+    photon_message::{OperationRequest, PhotonMessage},
     primitives::Vector3,
+    PhotonHashmap,
 };
-use tracing::{trace, warn};
+use tracing::{debug, info, trace, warn};
 
-use crate::{protocol::player_script::PlayerScript, proxy::websocket_proxy::WebSocketProxy};
+use self::{
+    actor_history::{ActorHistory, PropertyChange},
+    bandwidth::{feature, BandwidthMeter, BandwidthReport},
+    client_health::{CadenceTracker, ClientHealth, ClientHealthSettings, ClientHealthStats},
+    detection::{CheatDetector, DetectionSettings, SuspicionScore},
+    disconnects::{
+        Disconnect, DisconnectReason, DisconnectSettings, DisconnectSignal, DisconnectTracker,
+    },
+    drift::{DriftDetector, UpdateDriftReport},
+    drop_log::DropLog,
+    dry_run::DryRunLog,
+    encryption::EncryptionTracker,
+    event_dedup::EventDedup,
+    events::{EventBus, HaxEvent},
+    extrapolation::{
+        Extrapolated, ExtrapolationSettings, LatencyTracker, PositionHistory, PositionSample,
+        ServerClock,
+    },
+    game_server_routes::GameServerRoutes,
+    game_variant::GameVariant,
+    ghost_join::GhostJoin,
+    handler_timing::HandlerTimer,
+    hold::{HoldRegistry, PauseFlush},
+    identity_randomizer::IdentityRandomizer,
+    interest_groups::InterestGroups,
+    join_trace::JoinTrace,
+    journal::{ChangeJournal, StateDelta},
+    kill_feed::{Death, KillFeed},
+    late_join::ReplayWindow,
+    link_quality::{ArrivalTracker, LinkQualitySettings, LinkQualityStats},
+    lobby_cache::RoomCache,
+    map_annotations::MapAnnotations,
+    match_phase::{PhaseEstimate, RoundTracker},
+    match_summary::{injected_messages, MatchEnd, MatchHistory, MatchSummary, MatchTracker},
+    parse_breaker::{ParseBreaker, ParseBreakerSettings},
+    population::{AppStats, PopulationHistory},
+    profiles::ProfileStore,
+    projectiles::{Projectile, ProjectileSettings, ProjectileTracker},
+    property_firewall::PropertyFirewall,
+    property_refresh::{PendingRefreshes, RefreshError},
+    public_feed::{FeedMessage, PublicFeed},
+    replayed_rooms::{ReplayedRoomSettings, ReplayedRooms},
+    restriction_detector::{Evidence, RestrictionDetector, RestrictionSettings},
+    room_expectations::{RoomExpectation, RoomMismatch},
+    room_names::RoomNameComposer,
+    room_notes::{RoomKey, RoomNote, RoomNoteStore},
+    room_overlay::{RoomOverlay, RoomOverlaySettings},
+    room_probe::{PendingProbes, ProbePacing, ProbeSettings},
+    room_property_history::{RoomPropertyChange, RoomPropertyHistory, RoomPropertySettings},
+    room_rename::{PendingProperties, RenameError},
+    rpc_usage::RpcUsageTable,
+    scheduler::Scheduler,
+    selftest::{SelfTest, SelfTestReport, SelfTestSettings},
+    server_migration::{MigratedFrom, Migration},
+    settings::{Settings, SharedSettings},
+    shutdown::ShutdownProgress,
+    ui_snapshot::{UiPublisher, UiSnapshotSettings},
+    watchlist::{WatchEntry, Watchlist},
+    web_assets::AssetLog,
+    web_requests::WebRequestLog,
+};
+use crate::{
+    error::HaxError,
+    inspect::{
+        capture::CaptureFormat,
+        sink::{CaptureSink, CaptureSinks, RotationPolicy},
+        MessageBuffer, Query,
+    },
+    protocol::{
+        loadout::Loadout,
+        player_script::PlayerScript,
+        profile::GameProtocolProfile,
+        properties::{BulletForceActorProperties, BulletForceRoomProperties, ClientPlatform},
+        stream_layout::{StreamLayout, StreamLayouts},
+    },
+    proxy::{
+        listeners::{ProxyConfig, ProxyConfigChange, ProxyListeners},
+        relay_metrics::{RelayMetrics, RelayReport},
+        websocket_proxy::WebSocketProxy,
+        Direction, WebSocketServer,
+    },
+};
 
 /// An instance of BulletForceHaxV2. It handles the webrequest and websocket proxies as well as the internal state.
-#[derive(Default)]
+///
+/// Clones share the state, eg. to [shut down](Self::shutdown) from another task.
+#[derive(Default, Clone)]
 pub struct BulletForceHax {
     state: Arc<futures_util::lock::Mutex<HaxState>>,
 }
@@ -32,26 +202,1061 @@ impl BulletForceHax {
 pub struct HaxState {
     // state
     pub global_state: GlobalState,
+    pub nameserver_state: Option<(WebSocketProxy, NameServerState)>,
     pub lobby_state: Option<(WebSocketProxy, LobbyState)>,
     pub gameplay_state: Option<(WebSocketProxy, GameplayState)>,
+    pub events: EventBus,
+    /// The addresses the proxy listens on and the upstream hosts it connects to instead of the requested ones.
+    pub proxy: ProxyListeners,
+    /// The upstream game servers that lobby responses were rewritten away from.
+    pub game_server_routes: GameServerRoutes,
+    /// The most recent websocket messages, as they were received by the proxy.
+    pub recent_messages: MessageBuffer,
+    /// The files that websocket messages are written to as they come in, see [sink](crate::inspect::sink).
+    pub capture_sinks: CaptureSinks,
+    pub stats: HaxStats,
+    /// The messages that features decided not to forward.
+    pub drop_log: DropLog,
+    /// The recent requests to tracked web endpoints, see [web_requests].
+    pub web_requests: WebRequestLog,
+    /// The last digest of each of the game's build files, see [web_assets].
+    pub web_assets: AssetLog,
+    /// The messages that features postponed, see [hold].
+    holds: HoldRegistry,
+    /// What features would have changed or dropped while running in dry run.
+    pub dry_run_log: DryRunLog,
+    /// The extra traffic caused by each feature.
+    pub bandwidth: BandwidthMeter,
+    /// How long messages spend in the proxy, and whether it falls behind.
+    pub relay: RelayMetrics,
+    /// The encrypted messages, which are forwarded without being parsed.
+    pub encryption: EncryptionTracker,
+    /// Which connections fail to parse too many messages to be handled.
+    pub parse_breaker: ParseBreaker,
+    /// The summaries of the last matches we played.
+    pub match_history: MatchHistory,
+    /// The spans of the client's way into a game, see [join_trace].
+    pub join_trace: JoinTrace,
+    /// The lobby listing of the room the client asked to join, until the game server answers the join, see
+    /// [room_expectations].
+    expected_room: Option<RoomExpectation>,
+    /// How busy the lobby was over time, see [population].
+    population: PopulationHistory,
+    /// Whether the servers treat us like a restricted account, see [restriction_detector].
+    restrictions: RestrictionDetector,
+    /// Why the connections to each server closed, see [disconnects].
+    disconnects: DisconnectTracker,
+    /// What features know about lobby rooms beyond their listing, see [room_overlay].
+    room_overlay: RoomOverlay,
+    /// Which players, rooms and settings changed recently, see [Self::changes_since].
+    pub journal: ChangeJournal,
+    selftest_run: Option<SelfTest>,
+    selftest_report: Option<SelfTestReport>,
+    /// The property keys and RPCs seen in traffic, to compare against [Self::protocol_profile].
+    drift: DriftDetector,
+    /// How often each RPC method was called, and by whom.
+    rpc_usage: RpcUsageTable,
+    /// Where the players of the current game are exported to for external overlays, if anywhere.
+    #[cfg(feature = "shared_state")]
+    pub shared_state: Option<shared_state::SharedStateExporter>,
+    /// The stable stream of what happens in the game for external recorders, see [public_feed].
+    pub public_feed: PublicFeed,
+    /// The copies of the state the overlay reads without locking it, see [ui_snapshot].
+    ui_publisher: UiPublisher,
+    /// Whether the proxy is being shut down, see [shutdown].
+    shutdown: ShutdownProgress,
 
     // features
-    pub show_mobile_games: bool,
-    pub show_other_versions: bool,
-    pub strip_passwords: bool,
-    pub spoofed_name: (bool, String),
+    /// The toggles read on every message, which can be read and changed without this state's lock, see [settings].
+    settings: SharedSettings,
+    /// Actor and room properties that the client is not allowed to send.
+    pub property_firewall: PropertyFirewall,
+    /// Subscribe to all interest groups whatever the client asks for, see [Self::set_receive_all_groups].
+    receive_all_groups: bool,
+    /// Keep the rooms we host out of the lobby's room list, see [Self::set_stealth_host].
+    stealth_host: bool,
+    /// Notes on lobby rooms. Favorite rooms are highlighted and blocked rooms are hidden.
+    room_notes: RoomNoteStore,
+    /// Players to be alerted about when they show up, see [watchlist].
+    watchlist: Watchlist,
+    /// Named profiles of the feature settings, see [profiles].
+    profiles: ProfileStore,
+    /// Toggles and injections that run on a timer or when something happens, see [scheduler].
+    scheduler: Scheduler,
+    /// Named locations on each map, see [map_annotations].
+    map_annotations: MapAnnotations,
+    /// The identity presented to the servers instead of ours, see [identity_randomizer].
+    pub identity: IdentityRandomizer,
+    /// When rooms were probed, see [room_probe].
+    room_probes: ProbePacing,
+
+    // settings
+    pub watchdog: WatchdogSettings,
+    pub projectiles: ProjectileSettings,
+    pub extrapolation: ExtrapolationSettings,
+    pub link_quality: LinkQualitySettings,
+    pub client_health: ClientHealthSettings,
+    pub detection: DetectionSettings,
+    pub restriction_detection: RestrictionSettings,
+    pub disconnect_rules: DisconnectSettings,
+    pub room_overlay_settings: RoomOverlaySettings,
+    /// How often rooms can be probed, see [Self::probe_room].
+    pub room_probe_settings: ProbeSettings,
+    /// Which room properties' changes are announced, see [room_property_history].
+    pub room_property_settings: RoomPropertySettings,
+    /// What [pausing](Self::set_global_pause) does with held messages.
+    pub pause_flush: PauseFlush,
+    pub parse_breaker_settings: ParseBreakerSettings,
+    pub ui_snapshot_settings: UiSnapshotSettings,
+    pub selftest: SelfTestSettings,
+    /// How rooms of old captures are shown in the lobby, see [BulletForceHax::inject_capture_rooms].
+    pub replayed_rooms: ReplayedRoomSettings,
+    /// How the lobby features' annotations are put in front of room names.
+    pub room_names: RoomNameComposer,
+    /// The property keys and RPCs the current game version is expected to use.
+    pub protocol_profile: GameProtocolProfile,
+    /// How the players' streams of each protocol profile are laid out, see [Self::load_stream_layouts].
+    pub stream_layouts: StreamLayouts,
+}
+
+impl HaxState {
+    /// The current feature toggles. See [settings] for why they're kept apart.
+    pub fn settings(&self) -> Arc<Settings> {
+        self.settings.load()
+    }
+
+    /// A handle to the feature toggles that can be kept to read or change them without locking this state.
+    pub fn shared_settings(&self) -> SharedSettings {
+        self.settings.clone()
+    }
+
+    /// Changes the feature toggles, see [SharedSettings::update].
+    pub fn update_settings<R>(&self, f: impl FnOnce(&mut Settings) -> R) -> R {
+        self.settings.update(f)
+    }
+
+    /// Pauses or resumes all features at once, eg. as a panic button while streaming.
+    ///
+    /// Pausing turns every toggle off and keeps the settings from before in [Settings::paused], which makes the hook
+    /// forward everything unchanged and refuse injections like when [observing only](Settings::observe_only). Held
+    /// messages are sent or dropped as [Self::pause_flush] says. Resuming puts the settings from before back exactly,
+    /// whatever was changed while paused, which is what sets this apart from turning everything off by hand. Does
+    /// nothing if already paused or resumed.
+    pub fn set_global_pause(&mut self, paused: bool) {
+        let changed = self
+            .settings
+            .update(|settings| match (paused, settings.paused.take()) {
+                (true, None) => {
+                    let previous = std::mem::take(settings);
+                    *settings = Settings {
+                        debug: previous.debug.clone(),
+                        paused: Some(Box::new(previous)),
+                        ..Default::default()
+                    };
+                    true
+                }
+                (false, Some(previous)) => {
+                    *settings = *previous;
+                    true
+                }
+                (_, previous) => {
+                    settings.paused = previous;
+                    false
+                }
+            });
+        if !changed {
+            return;
+        }
+
+        let flushed = match paused {
+            true => self.holds.flush(self.pause_flush),
+            false => 0,
+        };
+        match paused {
+            true => info!(
+                flushed,
+                policy = format!("{:?}", self.pause_flush),
+                "Paused all features"
+            ),
+            false => info!("Resumed all features"),
+        }
+        self.events
+            .emit(HaxEvent::GlobalPauseChanged { paused, flushed });
+    }
+
+    /// Whether all features are paused, see [Self::set_global_pause].
+    pub fn global_pause(&self) -> bool {
+        self.settings.load().paused.is_some()
+    }
+
+    /// What changed after the given revision, so views only have to update that. Views start out reading everything
+    /// at the [StateDelta::revision] of `changes_since(0)`, and read everything again when the delta asks for a
+    /// resync. See [journal].
+    pub fn changes_since(&mut self, revision: u64) -> StateDelta {
+        let settings = self.settings.load();
+        self.journal.sync_settings(settings.clone());
+        StateDelta::read(
+            self.journal.revision(),
+            self.journal.changed_since(revision),
+            self.gameplay_state.as_ref().map(|(_, s)| s),
+            self.lobby_state.as_ref().map(|(_, s)| s),
+            settings,
+        )
+    }
+
+    /// How a player's properties changed in the current room, oldest first. See [actor_history].
+    pub fn actor_history(&self, actor_id: i32) -> impl DoubleEndedIterator<Item = &PropertyChange> {
+        self.gameplay_state
+            .iter()
+            .flat_map(move |(_, state)| state.actor_history.changes(actor_id))
+    }
+
+    /// How the properties of the current room changed, oldest first. See [room_property_history].
+    pub fn room_property_history(&self) -> impl DoubleEndedIterator<Item = &RoomPropertyChange> {
+        self.gameplay_state
+            .iter()
+            .flat_map(|(_, state)| state.room_properties.changes())
+    }
+
+    /// Predicts where all players in the current game are right now, keyed by actor id.
+    pub fn extrapolated_players(&self) -> IndexMap<i32, Extrapolated> {
+        match &self.gameplay_state {
+            Some((_, state)) => state.extrapolate_players(Instant::now(), &self.extrapolation),
+            None => IndexMap::new(),
+        }
+    }
+
+    /// How regularly the game client sends its own updates, to tell whether it is lagging itself. [None] while not
+    /// in a game or not spawned.
+    pub fn client_health(&self) -> Option<ClientHealthStats> {
+        let (_, state) = self.gameplay_state.as_ref()?;
+        state.client_health(Instant::now(), &self.client_health)
+    }
+
+    /// How the room we're in differs from what the lobby listed, if it does. See [room_expectations].
+    pub fn room_mismatch(&self) -> Option<&RoomMismatch> {
+        let (_, state) = self.gameplay_state.as_ref()?;
+        state.room_mismatch.as_ref()
+    }
+
+    /// How well each player in the current game is connected, keyed by actor id.
+    pub fn player_link_quality(&self) -> IndexMap<i32, LinkQualityStats> {
+        match &self.gameplay_state {
+            Some((_, state)) => state.link_quality(Instant::now(), &self.link_quality),
+            None => IndexMap::new(),
+        }
+    }
+
+    /// How likely each player in the current game is to be cheating, keyed by actor id. Only players that were
+    /// suspicious at some point are included.
+    pub fn suspicion_scores(&self) -> IndexMap<i32, SuspicionScore> {
+        match &self.gameplay_state {
+            Some((_, state)) => state.detector.scores(Instant::now(), &self.detection),
+            None => IndexMap::new(),
+        }
+    }
+
+    /// The weapons each player in the current game spawned with, keyed by actor id. Players whose loadout wasn't sent
+    /// yet are left out.
+    pub fn player_loadouts(&self) -> IndexMap<i32, Loadout> {
+        self.gameplay_state
+            .iter()
+            .flat_map(|(_, state)| &state.players)
+            .filter(|(_, player)| !player.loadout.is_empty())
+            .map(|(actor_id, player)| (*actor_id, player.loadout.clone()))
+            .collect()
+    }
+
+    /// The interest groups our client subscribed to in the current game.
+    pub fn interest_groups(&self) -> Option<&InterestGroups> {
+        self.gameplay_state
+            .as_ref()
+            .map(|(_, state)| &state.interest_groups)
+    }
+
+    pub fn receives_all_groups(&self) -> bool {
+        self.receive_all_groups
+    }
+
+    /// Subscribes to all interest groups so events culled by area are received too, or goes back to the groups the
+    /// client asked for.
+    ///
+    /// The client's CHANGE_GROUPS requests are rewritten while this is enabled. If we're in a game, the change is also
+    /// sent to the server right away instead of waiting for the client's next request.
+    pub fn set_receive_all_groups(&mut self, enabled: bool) {
+        if self.receive_all_groups == enabled {
+            return;
+        }
+        self.receive_all_groups = enabled;
+        let (proxy, state) = match &mut self.gameplay_state {
+            Some(x) => x,
+            None => return,
+        };
+        let requests = match enabled {
+            true => vec![ChangeGroupsRequest {
+                remove: None,
+                add: Some(vec![]),
+            }],
+            false => state.requested_groups.to_requests(),
+        };
+        for request in requests {
+            let mut parameters = IndexMap::new();
+            request.clone().into_map(&mut parameters);
+            let queued = proxy.queue_server(
+                PhotonMessage::OperationRequest(OperationRequest {
+                    operation_code: operation_code::CHANGE_GROUPS,
+                    parameters,
+                }),
+                feature::ALL_INTEREST_GROUPS,
+            );
+            match queued {
+                Err(HaxError::ObserveOnly) => {
+                    warn!("Not changing the subscribed interest groups while observing only");
+                    self.receive_all_groups = !enabled;
+                    return;
+                }
+                Err(e) => warn!("Could not change the subscribed interest groups: {e}"),
+                Ok(()) => (),
+            }
+            state.interest_groups.apply(&request);
+        }
+        debug!(
+            enabled,
+            subscribed = format!("{}", state.interest_groups),
+            "Toggled receiving all interest groups"
+        );
+    }
+
+    /// Starts writing the websocket messages that match the filter to a capture file, or all of them if there's no
+    /// filter. Replaces the sink with the same name, if there is one. See [CaptureSinks::add].
+    pub fn add_capture_sink(
+        &mut self,
+        name: impl Into<String>,
+        filter: Option<Query>,
+        path: impl Into<PathBuf>,
+    ) -> std::io::Result<()> {
+        self.capture_sinks.add(CaptureSink {
+            name: name.into(),
+            filter,
+            path: path.into(),
+            rotation: RotationPolicy::default(),
+            format: CaptureFormat::default(),
+        })
+    }
+
+    /// Stops writing to the capture sink with the given name. Returns whether there was one.
+    pub fn remove_capture_sink(&mut self, name: &str) -> bool {
+        self.capture_sinks.remove(name)
+    }
+
+    pub fn stealth_host(&self) -> bool {
+        self.stealth_host
+    }
+
+    /// Hides the rooms we host from the lobby's room list while keeping them open to join by name, see
+    /// [stealth_host].
+    ///
+    /// If we're hosting the current game, the room's visibility is changed right away.
+    pub fn set_stealth_host(&mut self, enabled: bool) {
+        if self.stealth_host == enabled {
+            return;
+        }
+        self.stealth_host = enabled;
+        if let Some((proxy, state)) = &mut self.gameplay_state {
+            if state.hosting {
+                let queued = proxy.queue_server(
+                    PhotonMessage::OperationRequest(stealth_host::visibility_request(!enabled)),
+                    feature::STEALTH_HOST,
+                );
+                match queued {
+                    Ok(()) => state.pending_properties.forwarded(),
+                    Err(e) => warn!("Could not change the visibility of the hosted room: {e}"),
+                }
+            }
+        }
+        debug!(enabled, "Toggled stealth hosting");
+    }
+
+    /// Renames the room we host as the lobby shows it, see [room_rename]. The new name is shown right away and goes
+    /// back to the old one if the server rejects it.
+    pub fn rename_my_room(&mut self, new_name: &str) -> Result<(), RenameError> {
+        let now = Instant::now();
+        let (proxy, state) = match &mut self.gameplay_state {
+            Some((proxy, state)) if state.room_name.is_some() || state.hosting => (proxy, state),
+            _ => return Err(RenameError::NotInRoom),
+        };
+        if !state.hosting {
+            return Err(RenameError::NotHost);
+        }
+        let new_name = room_rename::validate_name(new_name)?;
+        state.pending_properties.check_cooldown(now)?;
+
+        proxy.queue_server(
+            PhotonMessage::OperationRequest(room_rename::rename_request(
+                state.room_variant,
+                new_name,
+            )),
+            feature::ROOM_RENAME,
+        )?;
+        let previous = state.room_title.replace(new_name.to_string());
+        info!(previous, new_name, "Renamed the hosted room");
+        state.pending_properties.renamed(previous, now);
+        Ok(())
+    }
+
+    /// Asks the game server for the properties of every player in the room, to replace mirrored ones that may be
+    /// stale. See [property_refresh]. Returns the actors that were asked for, the answer is merged into the players
+    /// when it arrives and announced as [HaxEvent::ActorPropertiesRefreshed].
+    ///
+    /// Fails while the last refresh wasn't answered, and for the rest of the room once the server refused one.
+    pub fn refresh_actor_properties(&mut self) -> Result<Vec<i32>, RefreshError> {
+        let now = Instant::now();
+        let (proxy, state) = match &mut self.gameplay_state {
+            Some((proxy, state)) if state.room_name.is_some() => (proxy, state),
+            _ => return Err(RefreshError::NotInRoom),
+        };
+        state.pending_refreshes.check()?;
+
+        let actors = state.players.keys().copied().collect::<Vec<_>>();
+        proxy.queue_server(
+            PhotonMessage::OperationRequest(property_refresh::refresh_request(actors.clone())),
+            feature::PROPERTY_REFRESH,
+        )?;
+        debug!(actors = actors.len(), "Refreshing actor properties");
+        state.pending_refreshes.refreshed(actors.clone(), now);
+        Ok(actors)
+    }
+
+    /// How many entries each collection of the state holds, to keep an eye on memory use in long sessions.
+    pub fn collection_sizes(&self) -> IndexMap<&'static str, usize> {
+        let mut sizes = IndexMap::new();
+        sizes.insert("recent messages", self.recent_messages.len());
+        sizes.insert("recent errors", self.stats.recent_errors.len());
+        sizes.insert("muted RPC counts", self.stats.muted_rpcs.len());
+        sizes.insert("dropped messages", self.drop_log.entries().len());
+        sizes.insert("web requests", self.web_requests.entries().len());
+        sizes.insert("web assets", self.web_assets.entries().len());
+        sizes.insert("dry run log", self.dry_run_log.entries().len());
+        sizes.insert("match history", self.match_history.len());
+        sizes.insert("population buckets", self.population.len());
+        sizes.insert("RPC usage", self.rpc_usage.len());
+        sizes.insert("game server routes", self.game_server_routes.iter().count());
+        sizes.insert("room notes", self.room_notes.iter().count());
+        sizes.insert("schedules", self.scheduler.schedules().len());
+        if let Some((_, lobby)) = &self.lobby_state {
+            sizes.insert("lobby rooms", lobby.rooms.len());
+        }
+        sizes.insert("room overlay", self.room_overlay.len());
+        if let Some((_, state)) = &self.gameplay_state {
+            sizes.insert("players", state.players.len());
+            sizes.insert("projectiles", state.projectiles.len());
+            sizes.insert("tracked suspects", state.detector.len());
+            sizes.insert("ghost join decisions", state.ghost.len());
+            sizes.insert("recent events", state.event_dedup.len());
+        }
+        sizes
+    }
+
+    /// The projectiles that are currently in flight in the current game.
+    pub fn active_projectiles(&self) -> impl Iterator<Item = &Projectile> {
+        let ttl = self.projectiles.ttl;
+        let now = Instant::now();
+        self.gameplay_state
+            .iter()
+            .flat_map(move |(_, state)| state.projectiles.active(ttl, now))
+    }
+
+    /// Loads the room notes from the given file, replacing the ones in memory. Changes are saved to that file.
+    pub fn load_room_notes(&mut self, path: &Path) {
+        self.room_notes = RoomNoteStore::load(path);
+    }
+
+    pub fn room_note(&self, key: &RoomKey) -> Option<&RoomNote> {
+        self.room_notes.get(key)
+    }
+
+    pub fn room_notes(&self) -> impl Iterator<Item = (RoomKey, &RoomNote)> {
+        self.room_notes.iter()
+    }
+
+    /// Adds or replaces the note for a room and saves it to disk.
+    pub fn set_room_note(&mut self, key: RoomKey, note: RoomNote) -> Result<(), HaxError> {
+        self.room_notes
+            .set(key, note)
+            .map_err(|e| HaxError::Config(format!("{e:#}")))
+    }
+
+    /// Removes the note for a room and saves the change to disk.
+    pub fn remove_room_note(&mut self, key: &RoomKey) -> Result<Option<RoomNote>, HaxError> {
+        self.room_notes
+            .remove(key)
+            .map_err(|e| HaxError::Config(format!("{e:#}")))
+    }
+
+    /// Loads the watchlist from the given file, replacing the one in memory. Changes are saved to that file.
+    pub fn load_watchlist(&mut self, path: &Path) {
+        self.watchlist = Watchlist::load(path);
+    }
+
+    pub fn watchlist(&self) -> &Watchlist {
+        &self.watchlist
+    }
+
+    /// Adds a player to the watchlist and saves it to disk.
+    pub fn add_watch_entry(&mut self, entry: WatchEntry) -> Result<(), HaxError> {
+        self.watchlist
+            .add(entry)
+            .map_err(|e| HaxError::Config(format!("{e:#}")))
+    }
+
+    /// Removes the watchlist entry at the given index and saves the change to disk.
+    pub fn remove_watch_entry(&mut self, index: usize) -> Result<Option<WatchEntry>, HaxError> {
+        self.watchlist
+            .remove(index)
+            .map_err(|e| HaxError::Config(format!("{e:#}")))
+    }
+
+    /// Sets how long to wait before alerting about the same player again, and saves it to disk.
+    pub fn set_watch_cooldown(&mut self, cooldown: Duration) -> Result<(), HaxError> {
+        self.watchlist
+            .set_cooldown(cooldown)
+            .map_err(|e| HaxError::Config(format!("{e:#}")))
+    }
+
+    /// Loads the built-in map callouts and the ones in the given file, replacing the ones in memory.
+    pub fn load_map_annotations(&mut self, path: &Path) {
+        self.map_annotations = MapAnnotations::load(path);
+    }
+
+    pub fn map_annotations(&self) -> &MapAnnotations {
+        &self.map_annotations
+    }
+
+    /// Loads the built-in stream layouts and the ones in the given file on top of them, see [stream_layout].
+    ///
+    /// [stream_layout]: crate::protocol::stream_layout
+    pub fn load_stream_layouts(&mut self, path: &Path) {
+        self.stream_layouts = StreamLayouts::load(path);
+    }
+
+    /// The layout the players' streams are read with, the one of [Self::protocol_profile].
+    pub fn player_stream_layout(&self) -> &StreamLayout {
+        self.stream_layouts.for_profile(&self.protocol_profile.name)
+    }
+
+    /// The callout a position is in on the map of the room we're in, see [map_annotations].
+    pub fn callout_for(&self, position: &Vector3) -> Option<&str> {
+        let (_, state) = self.gameplay_state.as_ref()?;
+        self.map_annotations
+            .callout_for(state.map_name.as_deref()?, position)
+    }
+
+    /// Estimates how far along the round in the room we're in is.
+    pub fn match_phase(&self) -> PhaseEstimate {
+        match &self.gameplay_state {
+            Some((_, state)) => state.match_phase(Instant::now()),
+            None => PhaseEstimate::UNKNOWN,
+        }
+    }
+
+    /// Alerts about a player if they are on the watchlist.
+    fn watch_player(
+        &mut self,
+        user_id: Option<&str>,
+        nickname: Option<&str>,
+        room: Option<&str>,
+        server: WebSocketServer,
+    ) {
+        let entry = match self.watchlist.check(user_id, nickname, Instant::now()) {
+            Some(entry) => entry.clone(),
+            None => return,
+        };
+        warn!(
+            user_id,
+            nickname,
+            room,
+            level = format!("{:?}", entry.level),
+            note = entry.note.as_str(),
+            "Watched player seen on {server}"
+        );
+        self.events.emit(HaxEvent::WatchedPlayerSeen {
+            entry,
+            user_id: user_id.map(str::to_string),
+            nickname: nickname.map(str::to_string),
+            room: room.map(str::to_string),
+            server,
+        });
+    }
+
+    /// Loads the match history from the given file, replacing the one in memory. New matches are saved to that file.
+    pub fn load_match_history(&mut self, path: &Path) {
+        self.match_history = MatchHistory::load(path);
+    }
+
+    /// Counts the traffic of the game server connection towards the current match, starting it if needed.
+    fn record_match_traffic(&mut self, direction: Direction, len: usize) {
+        let bandwidth = &self.bandwidth;
+        let tracker = match &mut self.gameplay_state {
+            Some((_, state)) => &mut state.match_tracker,
+            None => return,
+        };
+        if !tracker.is_started() {
+            tracker.start(
+                Instant::now(),
+                SystemTime::now(),
+                injected_messages(&bandwidth.report()),
+            );
+        }
+        tracker.record_traffic(direction, len);
+    }
+
+    /// Summarizes the current match, logs it and adds it to the [match history](Self::match_history).
+    ///
+    /// Does nothing if we're not in a room, or the match was already summarized.
+    pub(crate) fn finish_match(&mut self, ended_by: MatchEnd) -> Option<MatchSummary> {
+        self.public_feed.record(
+            FeedMessage::RoomLeft {
+                reason: ended_by.into(),
+            },
+            Instant::now(),
+        );
+        let injections = injected_messages(&self.bandwidth.report());
+        let (_, state) = self.gameplay_state.as_mut()?;
+        // the client joins a room right after connecting, without a room there is no match to summarize
+        state.player_id?;
+        let mut summary = state.match_tracker.finish(
+            state.room_name.clone(),
+            &state.players,
+            state.player_id,
+            &state.actor_history,
+            ended_by,
+            Instant::now(),
+            &injections,
+        )?;
+        if ended_by == MatchEnd::Disconnected {
+            summary.disconnect = self
+                .disconnects
+                .last(WebSocketServer::GameServer)
+                .map(|d| d.reason.clone());
+        }
+        summary.room_property_changes = state.room_properties.changes().cloned().collect();
+
+        info!(summary = format!("{summary:?}"), "{summary}");
+        self.events.emit(HaxEvent::MatchFinished(summary.clone()));
+        if let Err(e) = self.match_history.push(summary.clone()) {
+            warn!("Could not save match history: {e:#}");
+        }
+        Some(summary)
+    }
+
+    /// Closes out the game of the current game server connection, as the client connected to another game server on
+    /// the given port while it's still open. Returns what the new connection keeps of it, see [server_migration].
+    ///
+    /// Does nothing if there is no game server connection.
+    pub(crate) fn migrate_game_server(&mut self, to_port: u16) -> Option<MigratedFrom> {
+        let summary = self.finish_match(MatchEnd::Migrated);
+        let (old, gameplay) = self.gameplay_state.take()?;
+        self.journal
+            .record_room_changed(gameplay.players.keys().copied());
+        // the new connection does its own handshake
+        self.encryption
+            .connection_closed(WebSocketServer::GameServer);
+        self.parse_breaker
+            .connection_closed(WebSocketServer::GameServer);
+
+        let migration = Migration {
+            from_port: old.get_port(),
+            to_port,
+            room_name: gameplay.room_name.clone(),
+            announced: gameplay.announced_redirect,
+            timestamp: SystemTime::now(),
+        };
+        warn!(
+            migration = format!("{migration:?}"),
+            "Client was {migration}"
+        );
+        self.events.emit(HaxEvent::GameServerMigrated(migration));
+
+        Some(MigratedFrom {
+            room_name: gameplay.room_name,
+            match_started: summary.map(|s| s.continues.unwrap_or(s.started)),
+        })
+    }
+
+    /// Changes the listeners and upstream hosts of the proxy while it runs, see [ProxyListeners::apply].
+    ///
+    /// Removing the listener the current game connection came in on is refused unless `force` is set. Game server
+    /// addresses handed out afterwards point at the first listener if their old one was removed.
+    pub async fn apply_proxy_config(
+        &mut self,
+        config: ProxyConfig,
+        force: bool,
+    ) -> Result<Vec<ProxyConfigChange>, HaxError> {
+        let game_listener = self
+            .gameplay_state
+            .as_ref()
+            .and_then(|(proxy, _)| proxy.get_listener());
+        let changes = self.proxy.apply(config, force, game_listener).await?;
+        for change in &changes {
+            info!("Proxy config changed: {change}");
+            self.events
+                .emit(HaxEvent::ProxyConfigChanged(change.clone()));
+        }
+
+        let listeners = &self.proxy.config().listen_addresses;
+        let endpoint_listener = self
+            .game_server_routes
+            .endpoint()
+            .and_then(|e| e.authority()?.as_str().parse::<SocketAddr>().ok());
+        if let (Some(old), Some(new)) = (endpoint_listener, listeners.first()) {
+            if !listeners.contains(&old) {
+                let endpoint = self
+                    .game_server_routes
+                    .endpoint()
+                    .expect("endpoint was parsed");
+                let mut parts = endpoint.clone().into_parts();
+                parts.authority = Some(
+                    new.to_string()
+                        .parse()
+                        .expect("socket address should be a valid authority"),
+                );
+                match hyper::Uri::from_parts(parts) {
+                    Ok(uri) => self.game_server_routes.set_endpoint(uri),
+                    Err(e) => warn!("Could not move game server routes to {new}: {e}"),
+                }
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// Whether any open connection uses Photon encryption. Features can't read or rewrite the encrypted messages.
+    pub fn is_encrypted_traffic_present(&self) -> bool {
+        self.encryption.active_connections().next().is_some()
+    }
+
+    /// The traffic caused by each feature, listed in the order of [Self::list_features].
+    pub fn bandwidth_report(&self) -> BandwidthReport {
+        let mut report = self.bandwidth.report();
+        let order = |name: &str| {
+            debug_assert!(
+                features::is_registered(name),
+                "bandwidth recorded for unregistered feature {name}"
+            );
+            features::position(name).unwrap_or(usize::MAX)
+        };
+        report.totals.sort_by(|a, _, b, _| order(a).cmp(&order(b)));
+        for minute in &mut report.minutes {
+            minute
+                .features
+                .sort_by(|a, _, b, _| order(a).cmp(&order(b)));
+        }
+        report
+    }
+
+    pub fn relay_report(&self) -> RelayReport {
+        self.relay.report()
+    }
+
+    /// Starts checking our assumptions about the protocol against the traffic of the next few seconds.
+    ///
+    /// The result is available through [Self::selftest_report] and as a [HaxEvent::SelfTestFinished] event once it
+    /// is done. Does nothing if a self-test is already running.
+    pub fn run_selftest(&mut self) {
+        if self.selftest_run.is_none() {
+            debug!("Starting self-test");
+            self.selftest_run = Some(SelfTest::new(self.selftest.duration, Instant::now()));
+        }
+    }
+
+    pub fn is_selftest_running(&self) -> bool {
+        self.selftest_run.is_some()
+    }
+
+    /// The report of the last finished self-test.
+    pub fn selftest_report(&self) -> Option<&SelfTestReport> {
+        self.selftest_report.as_ref()
+    }
+
+    /// Compares the property keys and RPCs seen so far against [Self::protocol_profile].
+    pub fn drift_report(&self) -> UpdateDriftReport {
+        self.drift.report(&self.protocol_profile)
+    }
+
+    /// The RPC methods seen so far with how often they were called, to find out which ones the game actually uses.
+    pub fn rpc_usage(&self) -> &RpcUsageTable {
+        &self.rpc_usage
+    }
+
+    /// Logs the [drift report](Self::drift_report), if anything new was seen since it was last logged.
+    pub fn log_drift_report(&mut self) {
+        if !self.drift.take_changed() {
+            return;
+        }
+
+        let report = self.drift_report();
+        match report.has_unknowns() {
+            true => warn!(
+                report = report.to_json(),
+                "Traffic does not match the protocol profile, the game may have updated"
+            ),
+            false => debug!(
+                report = report.to_json(),
+                "Traffic matches the protocol profile"
+            ),
+        }
+    }
+
+    /// How confident we are that the servers restrict our account, see [restriction_detector].
+    pub fn restriction_score(&self) -> f32 {
+        self.restrictions
+            .score(Instant::now(), &self.restriction_detection)
+    }
+
+    /// Why the servers seem to restrict our account, oldest first.
+    pub fn restriction_evidence(
+        &self,
+    ) -> impl DoubleEndedIterator<Item = &Evidence> + ExactSizeIterator {
+        self.restrictions.evidence()
+    }
+
+    /// Forgets the restriction evidence, eg. after the account turned out to be fine.
+    pub fn clear_restriction_evidence(&mut self) {
+        self.restrictions.clear();
+    }
+
+    /// Why the last connection to the server closed, see [disconnects].
+    pub fn last_disconnect(&self, server: WebSocketServer) -> Option<&Disconnect> {
+        self.disconnects.last(server)
+    }
+
+    /// Keeps something the proxy saw of a connection that may tell why it closes, see [disconnects].
+    pub(crate) fn record_disconnect_signal(
+        &mut self,
+        server: WebSocketServer,
+        signal: DisconnectSignal,
+    ) {
+        self.disconnects.record(server, signal, Instant::now());
+    }
+
+    /// Classifies why the server's connection closed, announcing it. Has to be called before the match is finished,
+    /// so the reason ends up in its summary.
+    pub(crate) fn connection_disconnected(&mut self, server: WebSocketServer) {
+        let disconnect =
+            self.disconnects
+                .connection_closed(server, Instant::now(), &self.disconnect_rules);
+        match &disconnect.reason {
+            DisconnectReason::ServerKick { .. } | DisconnectReason::Idle => {
+                warn!(rule = disconnect.rule, "Disconnected from the {disconnect}")
+            }
+            _ => info!(rule = disconnect.rule, "Disconnected from the {disconnect}"),
+        }
+        self.events.emit(HaxEvent::Disconnected(disconnect));
+    }
+
+    /// Feeds a message from the server to the [restriction detector](restriction_detector), warning when it's
+    /// confident enough.
+    fn observe_restrictions(&mut self, server: WebSocketServer, message: &PhotonMessage) {
+        let warning = self.restrictions.observe(
+            server,
+            message,
+            Instant::now(),
+            SystemTime::now(),
+            &self.restriction_detection,
+        );
+        if let Some(warning) = warning {
+            warn!(
+                score = warning.score,
+                evidence = warning
+                    .evidence
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join("; "),
+                "The servers' responses suggest that this account is restricted"
+            );
+            self.events.emit(HaxEvent::PossiblyRestricted {
+                score: warning.score,
+                evidence: warning.evidence,
+            });
+        }
+    }
+
+    /// Feeds a message to the running self-test, and finishes it if its time is up.
+    fn observe_selftest(&mut self, message: &PhotonMessage) {
+        let selftest = match &mut self.selftest_run {
+            Some(s) => s,
+            None => return,
+        };
+
+        selftest.observe(message);
+
+        if selftest.is_finished(Instant::now()) {
+            let report = self
+                .selftest_run
+                .take()
+                .expect("self-test should be running")
+                .into_report();
+            match report.passed() {
+                true => info!("Self-test passed"),
+                false => {
+                    for check in report.failed_checks() {
+                        warn!(
+                            check = check.name,
+                            affects = check.affects,
+                            summary = check.summary,
+                            samples = format!("{:?}", check.failure_samples),
+                            "Self-test check failed, the game may have updated"
+                        );
+                    }
+                }
+            }
+            self.events.emit(HaxEvent::SelfTestFinished(report.clone()));
+            self.selftest_report = Some(report);
+        }
+    }
+}
+
+/// How many errors are kept in [HaxStats::recent_errors].
+const MAX_RECENT_ERRORS: usize = 10;
+
+/// How many actors to count muted RPCs for. Actor numbers keep growing in long-lived rooms.
+const MAX_MUTED_RPC_ACTORS: usize = 256;
+
+/// Counters for things that happened over the lifetime of the program.
+#[derive(Debug, Default, Clone)]
+pub struct HaxStats {
+    /// How many websocket messages the client sent, over all connections.
+    pub messages_client_to_server: u64,
+    /// How many websocket messages the servers sent, over all connections.
+    pub messages_server_to_client: u64,
+    /// How many modified messages failed validation, and were forwarded unmodified instead.
+    pub rejected_rewrites: u64,
+    /// How many times a message handler panicked. The original message is forwarded when that happens.
+    pub handler_panics: u64,
+    /// How many values of data types the parser doesn't know were passed through, see
+    /// [PhotonDataType::Unknown](photon_lib::photon_data_type::PhotonDataType::Unknown).
+    pub unknown_values: u64,
+    /// How many RPCs were dropped because they were muted, per actor.
+    pub muted_rpcs: IndexMap<i32, u64>,
+    /// The last few errors that occured while handling messages, oldest first.
+    pub recent_errors: VecDeque<String>,
+    /// The last message a handler failed on, within [DebugSettings::message_display].
+    pub last_failed_message: Option<String>,
+    /// How long each part of the websocket hook took.
+    pub handler_timings: HandlerTimer,
+}
+
+impl HaxStats {
+    /// Remembers an error for the status report, dropping the oldest one if there are too many.
+    pub fn record_error(&mut self, error: impl Display) {
+        if self.recent_errors.len() >= MAX_RECENT_ERRORS {
+            self.recent_errors.pop_front();
+        }
+        self.recent_errors.push_back(error.to_string());
+    }
+
+    /// Counts a muted RPC of an actor, forgetting the actor that was muted first if there are too many.
+    pub fn record_muted_rpc(&mut self, sender: i32) {
+        if !self.muted_rpcs.contains_key(&sender) && self.muted_rpcs.len() >= MAX_MUTED_RPC_ACTORS {
+            self.muted_rpcs.shift_remove_index(0);
+        }
+        *self.muted_rpcs.entry(sender).or_default() += 1;
+    }
+}
+
+/// The default for [DebugSettings::slow_handler_threshold]. A frame at 60 fps takes about 16ms.
+pub const DEFAULT_SLOW_HANDLER_THRESHOLD: Duration = Duration::from_millis(20);
+
+/// Settings that help with developing new features.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebugSettings {
+    /// Check that modified messages still parse before forwarding them, and forward the original message if they don't.
+    ///
+    /// Rewrites are expected to keep the number of top-level parameters the same.
+    pub validate_rewrites: bool,
+    /// Log a warning when a single handler takes longer than this, see [HaxStats::handler_timings].
+    pub slow_handler_threshold: Option<Duration>,
+    /// How much of a message is shown in the trace log and the status report, see [photon_lib::display].
+    pub message_display: DisplayLimits,
+}
+
+impl Default for DebugSettings {
+    fn default() -> Self {
+        Self {
+            validate_rewrites: cfg!(debug_assertions),
+            slow_handler_threshold: Some(DEFAULT_SLOW_HANDLER_THRESHOLD),
+            message_display: DisplayLimits::default(),
+        }
+    }
+}
+
+/// What to do when an upstream server stops answering pings.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogMode {
+    /// Don't monitor the connection.
+    Disabled,
+    /// Connect to the server again and replay the authentication request.
+    Reconnect,
+    /// Close the connection to the client, so the game shows its reconnect UI.
+    #[default]
+    CloseClient,
+}
+
+#[derive(Debug, Clone)]
+pub struct WatchdogSettings {
+    pub mode: WatchdogMode,
+    /// How long a ping can go unanswered before the server is considered dead.
+    pub timeout: Duration,
+}
+
+impl Default for WatchdogSettings {
+    fn default() -> Self {
+        Self {
+            mode: WatchdogMode::default(),
+            timeout: Duration::from_secs(10),
+        }
+    }
 }
 
 /// Game-related state that is kept over the lifetime of the program.
 #[derive(Default)]
 pub struct GlobalState {
     pub user_id: Option<String>,
+    /// The version of the client, which the rooms in the lobby are matched against.
     pub version: Option<VersionInfo>,
+    /// The version the lobby was told instead, if [HaxState::auth_overrides] changed it.
+    pub server_version: Option<VersionInfo>,
+
+    /// The regions returned by the name server, mapped to the address of their master server.
+    ///
+    /// Keyed by region code.
+    pub regions: IndexMap<String, String>,
+    /// The master server address the name server told us to connect to.
+    pub master_server_address: Option<String>,
+    /// The region the client last authenticated with, kept after the name server connection closed.
+    pub region: Option<String>,
+}
+
+/// State for a given name server connection
+#[derive(Default)]
+pub struct NameServerState {
+    /// The region the client authenticated with.
+    pub region: Option<String>,
 }
 
 /// State for a given lobby connection
 #[derive(Default)]
-pub struct LobbyState {}
+pub struct LobbyState {
+    /// The rooms the lobby listed.
+    pub rooms: RoomCache,
+    /// The rooms of old captures shown in this session, see [replayed_rooms].
+    pub replayed_rooms: ReplayedRooms,
+    /// The counts of the region the server sent last.
+    pub app_stats: Option<AppStats>,
+    /// Our lookups and joins of rooms that weren't answered yet, see [room_probe].
+    pub probes: PendingProbes,
+}
 
 /// State for a given game connection
 #[derive(Default)]
@@ -59,27 +1264,335 @@ pub struct GameplayState {
     /// the player id
     pub player_id: Option<i32>,
 
+    /// The name of the room we joined.
+    pub room_name: Option<String>,
+
+    /// The map of the room we joined, from its room properties.
+    pub map_name: Option<String>,
+
+    /// The name the lobby shows for the room we joined, from its room properties. Unlike [Self::room_name] the host
+    /// can change it, see [room_rename].
+    pub room_title: Option<String>,
+
+    /// The variant of the room we joined, which tells the keys of its room properties.
+    pub room_variant: GameVariant,
+
     /// our player's actor id
     pub actor_nr: Option<i32>,
 
+    /// The platform our client really reports, which the server is told differently while
+    /// [PlatformSpoof](platform_spoof::PlatformSpoof) is on.
+    pub own_platform: ClientPlatform,
+
     pub match_manager_view_id: Option<i32>,
 
     /// The player actors currently in the game.
     ///
     /// Keyed by actor id.
     pub players: IndexMap<i32, PlayerActor>,
+
+    /// Grenades, rockets and other projectiles that are in flight.
+    pub projectiles: ProjectileTracker,
+
+    /// Estimates the server time from the timestamps of serialized data.
+    pub server_clock: ServerClock,
+
+    /// The round trip time to the game server, measured from the client's pings.
+    pub latency: LatencyTracker,
+
+    /// The interest groups our client is subscribed to on the server.
+    pub interest_groups: InterestGroups,
+
+    /// The interest groups our client asked for, which differ from [Self::interest_groups] if we subscribed to all.
+    pub requested_groups: InterestGroups,
+
+    /// How suspicious the other players are.
+    pub detector: CheatDetector,
+
+    /// What goes into the summary of the current match.
+    pub match_tracker: MatchTracker,
+
+    /// Which of our player's instantiations were hidden from the other clients.
+    pub ghost: GhostJoin,
+
+    /// The state-changing events that were processed recently, so duplicates can be skipped.
+    pub event_dedup: EventDedup,
+
+    /// Whether our client is the room's master client, because it created the room or the previous master left. See
+    /// [host_migration].
+    pub hosting: bool,
+
+    /// The actor id of the room's master client, see [host_migration].
+    pub master_client: Option<i32>,
+
+    /// The actor id of the player that created the room, if they were still in it when we joined.
+    pub room_creator: Option<i32>,
+
+    /// How the rounds in the room went since we joined.
+    pub round: RoundTracker,
+
+    /// The recent deaths in the room.
+    pub kill_feed: KillFeed,
+
+    /// Whether the events we receive are still replayed from the room's cache, see [late_join].
+    pub late_join: ReplayWindow,
+
+    /// How the properties of the players in the room changed, see [actor_history].
+    pub actor_history: ActorHistory,
+
+    /// How the properties of the room changed, see [room_property_history].
+    pub room_properties: RoomPropertyHistory,
+
+    /// The game server address the server told the client to move to, see [server_migration].
+    pub announced_redirect: Option<String>,
+
+    /// The game we were moved away from to this connection, until the client joins a room on it. See
+    /// [server_migration].
+    pub migrated_from: Option<MigratedFrom>,
+
+    /// The SET_PROPERTIES requests waiting for a response, see [room_rename].
+    pub pending_properties: PendingProperties,
+
+    /// The GET_PROPERTIES requests waiting for a response, see [property_refresh].
+    pub pending_refreshes: PendingRefreshes,
+
+    /// When the client sent its own updates, see [client_health].
+    pub cadence: CadenceTracker,
+
+    /// How the room differs from its lobby listing, see [room_expectations].
+    pub room_mismatch: Option<RoomMismatch>,
 }
 
-#[derive(Default, Debug)]
+impl GameplayState {
+    /// Forgets everything about the room we were in, so a long-lived connection doesn't pile up state from every
+    /// room it joined. The match tracker is reset when joining the next room, as the summary may still be produced
+    /// after leaving.
+    pub fn clear_room(&mut self) {
+        self.room_name = None;
+        self.map_name = None;
+        self.room_title = None;
+        self.room_variant = GameVariant::default();
+        self.match_manager_view_id = None;
+        self.players.clear();
+        self.projectiles = ProjectileTracker::default();
+        self.interest_groups = InterestGroups::default();
+        self.requested_groups = InterestGroups::default();
+        self.detector = CheatDetector::default();
+        self.ghost = GhostJoin::default();
+        self.event_dedup = EventDedup::default();
+        self.hosting = false;
+        self.master_client = None;
+        self.room_creator = None;
+        self.round = RoundTracker::default();
+        self.kill_feed = KillFeed::default();
+        self.late_join = ReplayWindow::default();
+        self.actor_history = ActorHistory::default();
+        self.announced_redirect = None;
+        self.migrated_from = None;
+        self.pending_properties = PendingProperties::default();
+        self.pending_refreshes = PendingRefreshes::default();
+        self.cadence = CadenceTracker::default();
+        self.room_mismatch = None;
+    }
+
+    /// The name to show for a player: their nickname, or the last one they had if they left.
+    pub fn display_name(&self, actor_id: i32) -> String {
+        self.players
+            .get(&actor_id)
+            .and_then(|p| p.nickname.as_deref())
+            .filter(|n| !n.is_empty())
+            .or_else(|| self.actor_history.last_nickname(actor_id))
+            .map(String::from)
+            .unwrap_or_else(|| format!("actor {actor_id}"))
+    }
+
+    /// The [kill_feed](Self::kill_feed) described with the players' [display names](Self::display_name) as they are
+    /// now, oldest first.
+    pub fn kill_feed_lines(&self) -> impl DoubleEndedIterator<Item = String> + '_ {
+        self.kill_feed
+            .entries()
+            .map(|entry| entry.describe(|actor_id| self.display_name(actor_id)))
+    }
+
+    /// Puts our real platform back into our entry in [Self::players], after merging properties the server may have
+    /// echoed spoofed.
+    pub fn restore_own_platform(&mut self) {
+        let player = match self.player_id.and_then(|id| self.players.get_mut(&id)) {
+            Some(x) => x,
+            None => return,
+        };
+        if !self.own_platform.is_empty() {
+            player.platform = self.own_platform.clone();
+        }
+    }
+
+    /// Takes in the room properties the server sent when joining or when they changed.
+    pub fn observe_room_properties(&mut self, properties: &PhotonHashmap, now: Instant) {
+        let properties = WellKnownRoomProperties::new(properties);
+        if let Some(map_name) = properties.map_name() {
+            self.map_name = Some(map_name.to_string());
+        }
+        self.observe_room_title(&properties);
+        self.round.observe(&properties, now);
+    }
+
+    /// Takes in the name the lobby shows for the room. Changes only hold the properties that changed, so the variant
+    /// is only updated if they include the game version.
+    pub fn observe_room_title(&mut self, properties: &WellKnownRoomProperties<&PhotonHashmap>) {
+        if properties.game_version().is_some() {
+            self.room_variant = properties.variant();
+        }
+        if let Some(title) = properties.custom_str(self.room_variant.keys().room_name) {
+            self.room_title = Some(title.to_string());
+        }
+    }
+
+    /// Estimates how far along the round in the room is, see [match_phase].
+    pub fn match_phase(&self, now: Instant) -> PhaseEstimate {
+        self.round.estimate(
+            now,
+            self.server_clock.server_now(now),
+            match_phase::leading_score(self.players.values()),
+        )
+    }
+
+    /// Predicts where the player that owns the given view is at the given moment.
+    pub fn extrapolate(
+        &self,
+        view_id: ViewId,
+        now: Instant,
+        settings: &ExtrapolationSettings,
+    ) -> Option<Extrapolated> {
+        let server_now = self.server_clock.server_now(now)?;
+        self.players
+            .get(&view_id.get_owner_id())?
+            .positions
+            .extrapolate(server_now, settings)
+    }
+
+    /// Predicts where all players with a known position are at the given moment, keyed by actor id.
+    pub fn extrapolate_players(
+        &self,
+        now: Instant,
+        settings: &ExtrapolationSettings,
+    ) -> IndexMap<i32, Extrapolated> {
+        let server_now = match self.server_clock.server_now(now) {
+            Some(t) => t,
+            None => return IndexMap::new(),
+        };
+        self.players
+            .iter()
+            .filter_map(|(actor_id, player)| {
+                let extrapolated = player.positions.extrapolate(server_now, settings)?;
+                Some((*actor_id, extrapolated))
+            })
+            .collect()
+    }
+
+    /// The last time a serialized update from any player arrived.
+    fn latest_arrival(&self) -> Option<Instant> {
+        self.players
+            .values()
+            .filter_map(|p| p.link.last_arrival())
+            .max()
+    }
+
+    /// Whether the events of the given player reach us. Players in an interest group we're not subscribed to are
+    /// culled by the server, so missing updates from them are expected.
+    pub fn expects_updates_from(&self, actor_id: i32) -> bool {
+        match self.players.get(&actor_id).and_then(|p| p.interest_group) {
+            Some(group) => self.interest_groups.receives(group),
+            None => true,
+        }
+    }
+
+    /// How well each player that sent updates is connected at the given moment, keyed by actor id.
+    pub fn link_quality(
+        &self,
+        now: Instant,
+        settings: &LinkQualitySettings,
+    ) -> IndexMap<i32, LinkQualityStats> {
+        let latest_arrival = self.latest_arrival();
+        self.players
+            .iter()
+            .filter_map(|(actor_id, player)| {
+                Some((*actor_id, player.link.stats(now, latest_arrival, settings)?))
+            })
+            .collect()
+    }
+
+    /// How regularly the client sends its own updates at the given moment, compared to the updates of others.
+    pub fn client_health(
+        &self,
+        now: Instant,
+        settings: &ClientHealthSettings,
+    ) -> Option<ClientHealthStats> {
+        self.cadence.stats(now, self.latest_arrival(), settings)
+    }
+
+    /// Classifies the client's cadence and returns the previous and the new health if it changed.
+    pub fn update_client_health(
+        &mut self,
+        now: Instant,
+        settings: &ClientHealthSettings,
+    ) -> Option<(ClientHealth, ClientHealth)> {
+        let latest_arrival = self.latest_arrival();
+        self.cadence.update(now, latest_arrival, settings)
+    }
+
+    /// Classifies all players and returns the actor ids of the ones that just started timing out.
+    ///
+    /// Players whose interest group we don't receive are left out, see [Self::expects_updates_from].
+    pub fn update_link_quality(
+        &mut self,
+        now: Instant,
+        settings: &LinkQualitySettings,
+    ) -> Vec<i32> {
+        let latest_arrival = self.latest_arrival();
+        let interest_groups = &self.interest_groups;
+        self.players
+            .iter_mut()
+            .filter(|(_, player)| {
+                player
+                    .interest_group
+                    .is_none_or(|g| interest_groups.receives(g))
+            })
+            .filter_map(|(actor_id, player)| {
+                player
+                    .link
+                    .update(now, latest_arrival, settings)
+                    .then_some(*actor_id)
+            })
+            .collect()
+    }
+}
+
+#[derive(Default, Debug, Clone)]
 pub struct PlayerActor {
     pub view_id: Option<ViewId>,
     pub user_id: Option<String>,
     pub nickname: Option<String>,
     pub team_number: Option<u8>,
+    /// The weapons they spawned with.
+    pub loadout: Loadout,
+    /// The platform, device and store they reported when joining.
+    pub platform: ClientPlatform,
 
     pub health: Option<f32>,
     pub position: Option<Vector3>,
     pub facing_direction: Option<f32>,
+    /// The last positions, to extrapolate from.
+    pub positions: PositionHistory,
+    /// When their serialized updates arrived, to judge their connection by.
+    pub link: ArrivalTracker,
+    /// The interest group their events are raised in, if they were raised in one.
+    pub interest_group: Option<u8>,
+    /// How many kills they have in the current round.
+    pub kills: Option<i16>,
+    /// How many times they died in the current round.
+    pub deaths: Option<i16>,
+    /// When their properties were last refreshed from the server, see [property_refresh].
+    pub properties_refreshed: Option<Instant>,
 }
 
 impl PlayerActor {
@@ -95,10 +1608,11 @@ impl PlayerActor {
             self.nickname = Some(nickname.clone());
         }
 
-        if let Some(PhotonDataType::Byte(team_number)) = player.custom_properties.get("teamNumber")
-        {
-            self.team_number = Some(*team_number);
+        if let Some(team_number) = player.team_number() {
+            self.team_number = Some(team_number);
         }
+        self.loadout.merge(player.loadout());
+        self.platform.merge(player.client_platform());
     }
 
     pub fn merge_instantiation_data(&mut self, instantiation_data: &InstantiationEventData) {
@@ -115,17 +1629,39 @@ impl PlayerActor {
         );
 
         self.view_id = Some(instantiation_data.get_view_id());
+        if let Some(group) = instantiation_data.group {
+            self.interest_group = Some(group);
+        }
+        if let Some(data) = &instantiation_data.incoming_instantiation_data {
+            self.loadout.merge_instantiation_data(data);
+        }
     }
 
-    pub fn merge_player_script(&mut self, script: &PlayerScript) {
+    /// Takes in a serialized player script, and returns their death if it reports one. See [kill_feed].
+    pub fn merge_player_script(&mut self, script: &PlayerScript) -> Option<Death> {
         trace!(
             data = format!("{script:?}"),
             "Merging player with player script"
         );
+        let death = Death::from_script(self.deaths, self.position.take(), script);
 
         self.health = Some(script.health as f32 / 100.0);
         self.position = Some(script.position.clone());
         self.facing_direction = Some(script.move_angle as f32 / 10.0);
+        self.kills = Some(script.number_of_kills);
+        self.deaths = Some(script.number_of_deaths);
+        death
+    }
+
+    /// Remembers the current position as sent at the given server time.
+    pub fn record_position(&mut self, server_time: i32, settings: &ExtrapolationSettings) {
+        if let Some(position) = &self.position {
+            let sample = PositionSample {
+                position: position.clone(),
+                server_time,
+            };
+            self.positions.push(sample, settings);
+        }
     }
 }
 
@@ -136,3 +1672,14 @@ pub struct VersionInfo {
     /// The version of Photon Unity Networking. This is not the version of the Photon .Net Client Library.
     pub photon_version: String,
 }
+
+impl VersionInfo {
+    /// Reads an app version as sent when authenticating, such as `1.90.0_1.99`.
+    pub fn parse(app_version: &str) -> Option<Self> {
+        let (game, photon) = app_version.split_once('_')?;
+        Some(Self {
+            game_version: game.to_string(),
+            photon_version: photon.to_string(),
+        })
+    }
+}
@@ -0,0 +1,321 @@
+//! Stops handling the messages of a connection when most of them can't be parsed.
+//!
+//! After a game update breaks the protocol, every message that fails to parse logs a warning and features act on the
+//! few messages that still happen to parse. Once too many recent messages of a connection fail to parse, the
+//! connection goes into passthrough mode: messages are only counted and forwarded unmodified. Every
+//! [probe_interval](ParseBreakerSettings::probe_interval) a message is parsed as a probe, without being handled, and
+//! handling resumes once enough probes in a row succeed.
+//!
+//! The state built from that connection missed messages while in passthrough, so it stays marked as stale until the
+//! connection closes.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::proxy::WebSocketServer;
+
+#[derive(Debug, Clone)]
+pub struct ParseBreakerSettings {
+    pub enabled: bool,
+    /// The share of recent messages that have to fail to parse to go into passthrough, between 0 and 1.
+    pub threshold: f32,
+    /// How many of the most recent messages the failure rate is computed over.
+    pub window: usize,
+    /// How many messages have to be seen before the failure rate is trusted.
+    pub min_samples: usize,
+    /// How often a message is parsed to check whether parsing works again.
+    pub probe_interval: Duration,
+    /// How many probes in a row have to succeed to leave passthrough.
+    pub probe_successes: usize,
+}
+
+impl Default for ParseBreakerSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: 0.5,
+            window: 100,
+            min_samples: 20,
+            probe_interval: Duration::from_secs(2),
+            probe_successes: 3,
+        }
+    }
+}
+
+/// What to do with the next message of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseDecision {
+    /// Parse the message and run the handlers on it.
+    Handle,
+    /// Parse the message to see whether parsing works again, but don't handle it.
+    Probe,
+    /// Forward the message without parsing it.
+    Skip,
+}
+
+/// A change of mode, returned when recording the outcome of a parse.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerTransition {
+    /// Too many messages failed to parse, the connection is now in passthrough.
+    Tripped { failure_rate: f32 },
+    /// Enough probes succeeded, messages of the connection are handled again.
+    Recovered { skipped: u64 },
+}
+
+#[derive(Debug, Clone)]
+struct Passthrough {
+    last_probe: Option<Instant>,
+    successful_probes: usize,
+    /// How many messages were forwarded without being parsed.
+    skipped: u64,
+}
+
+/// The parse outcomes of a single connection.
+#[derive(Debug, Default, Clone)]
+pub struct ConnectionBreaker {
+    /// Whether each of the most recent messages failed to parse, oldest first.
+    outcomes: VecDeque<bool>,
+    failures: usize,
+    passthrough: Option<Passthrough>,
+    stale: bool,
+}
+
+impl ConnectionBreaker {
+    /// Decides what to do with the next message. Messages that are skipped are counted here.
+    pub fn decide(&mut self, now: Instant, settings: &ParseBreakerSettings) -> ParseDecision {
+        if !settings.enabled {
+            self.passthrough = None;
+            return ParseDecision::Handle;
+        }
+
+        let passthrough = match &mut self.passthrough {
+            Some(p) => p,
+            None => return ParseDecision::Handle,
+        };
+        match passthrough.last_probe {
+            Some(last) if now.duration_since(last) < settings.probe_interval => {
+                passthrough.skipped += 1;
+                ParseDecision::Skip
+            }
+            _ => {
+                passthrough.last_probe = Some(now);
+                ParseDecision::Probe
+            }
+        }
+    }
+
+    /// Records whether a message that wasn't skipped could be parsed.
+    pub fn record(
+        &mut self,
+        parsed: bool,
+        settings: &ParseBreakerSettings,
+    ) -> Option<BreakerTransition> {
+        if !settings.enabled {
+            return None;
+        }
+
+        if let Some(passthrough) = &mut self.passthrough {
+            passthrough.successful_probes = match parsed {
+                true => passthrough.successful_probes + 1,
+                false => 0,
+            };
+            if passthrough.successful_probes < settings.probe_successes {
+                return None;
+            }
+            let skipped = passthrough.skipped;
+            self.passthrough = None;
+            return Some(BreakerTransition::Recovered { skipped });
+        }
+
+        self.outcomes.push_back(!parsed);
+        if !parsed {
+            self.failures += 1;
+        }
+        while self.outcomes.len() > settings.window.max(1) {
+            if self.outcomes.pop_front() == Some(true) {
+                self.failures -= 1;
+            }
+        }
+
+        let failure_rate = self.failure_rate();
+        if self.outcomes.len() < settings.min_samples || failure_rate <= settings.threshold {
+            return None;
+        }
+        self.outcomes.clear();
+        self.failures = 0;
+        self.stale = true;
+        self.passthrough = Some(Passthrough {
+            last_probe: None,
+            successful_probes: 0,
+            skipped: 0,
+        });
+        Some(BreakerTransition::Tripped { failure_rate })
+    }
+
+    /// The share of recent messages that failed to parse. Not tracked while in passthrough.
+    pub fn failure_rate(&self) -> f32 {
+        match self.outcomes.len() {
+            0 => 0.0,
+            len => self.failures as f32 / len as f32,
+        }
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.passthrough.is_some()
+    }
+
+    /// How many messages were forwarded without being parsed since going into passthrough.
+    pub fn skipped(&self) -> u64 {
+        self.passthrough.as_ref().map_or(0, |p| p.skipped)
+    }
+
+    /// Whether this connection went into passthrough at some point, so the state built from it may be missing
+    /// changes.
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+}
+
+/// A [ConnectionBreaker] for each open connection.
+#[derive(Debug, Default, Clone)]
+pub struct ParseBreaker {
+    nameserver: ConnectionBreaker,
+    lobby: ConnectionBreaker,
+    gameplay: ConnectionBreaker,
+}
+
+impl ParseBreaker {
+    pub fn connection(&self, server: WebSocketServer) -> &ConnectionBreaker {
+        match server {
+            WebSocketServer::NameServer => &self.nameserver,
+            WebSocketServer::LobbyServer => &self.lobby,
+            WebSocketServer::GameServer => &self.gameplay,
+        }
+    }
+
+    pub fn connection_mut(&mut self, server: WebSocketServer) -> &mut ConnectionBreaker {
+        match server {
+            WebSocketServer::NameServer => &mut self.nameserver,
+            WebSocketServer::LobbyServer => &mut self.lobby,
+            WebSocketServer::GameServer => &mut self.gameplay,
+        }
+    }
+
+    /// Forgets the outcomes of a connection, the next connection starts out fresh.
+    pub fn connection_closed(&mut self, server: WebSocketServer) {
+        *self.connection_mut(server) = ConnectionBreaker::default();
+    }
+
+    /// The servers whose current connection is in passthrough.
+    pub fn passthrough_connections(&self) -> impl Iterator<Item = WebSocketServer> + '_ {
+        [
+            WebSocketServer::NameServer,
+            WebSocketServer::LobbyServer,
+            WebSocketServer::GameServer,
+        ]
+        .into_iter()
+        .filter(|server| self.connection(*server).is_passthrough())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{BreakerTransition, ConnectionBreaker, ParseBreakerSettings, ParseDecision};
+
+    fn settings() -> ParseBreakerSettings {
+        ParseBreakerSettings {
+            threshold: 0.5,
+            window: 10,
+            min_samples: 10,
+            probe_interval: Duration::from_secs(1),
+            probe_successes: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn trip_and_recover() {
+        let settings = settings();
+        let start = Instant::now();
+        let mut breaker = ConnectionBreaker::default();
+
+        // every other message is garbage, which stays at the threshold
+        for i in 0..19 {
+            assert_eq!(breaker.decide(start, &settings), ParseDecision::Handle);
+            assert_eq!(breaker.record(i % 2 == 0, &settings), None);
+        }
+        assert_eq!(breaker.failure_rate(), 0.5);
+        assert!(!breaker.is_stale());
+
+        // two garbage messages in a row push it over
+        assert_eq!(breaker.record(false, &settings), None);
+        assert_eq!(
+            breaker.record(false, &settings),
+            Some(BreakerTransition::Tripped { failure_rate: 0.6 })
+        );
+        assert!(breaker.is_passthrough() && breaker.is_stale());
+
+        // the first message is probed, the next ones are skipped until the probe interval passed
+        assert_eq!(breaker.decide(start, &settings), ParseDecision::Probe);
+        assert_eq!(breaker.record(true, &settings), None);
+        for _ in 0..5 {
+            assert_eq!(breaker.decide(start, &settings), ParseDecision::Skip);
+        }
+        // a failed probe starts the count over
+        let mut now = start + Duration::from_secs(1);
+        assert_eq!(breaker.decide(now, &settings), ParseDecision::Probe);
+        assert_eq!(breaker.record(false, &settings), None);
+        now += Duration::from_secs(1);
+        assert_eq!(breaker.decide(now, &settings), ParseDecision::Probe);
+        assert_eq!(breaker.record(true, &settings), None);
+        assert_eq!(breaker.decide(now, &settings), ParseDecision::Skip);
+        now += Duration::from_secs(1);
+        assert_eq!(breaker.decide(now, &settings), ParseDecision::Probe);
+        assert_eq!(
+            breaker.record(true, &settings),
+            Some(BreakerTransition::Recovered { skipped: 6 })
+        );
+
+        // handling resumes with a fresh window, but the state stays stale
+        assert!(!breaker.is_passthrough() && breaker.is_stale());
+        assert_eq!(breaker.decide(now, &settings), ParseDecision::Handle);
+        assert_eq!(breaker.failure_rate(), 0.0);
+    }
+
+    #[test]
+    fn needs_enough_samples() {
+        let settings = settings();
+        let mut breaker = ConnectionBreaker::default();
+        for _ in 0..9 {
+            assert_eq!(breaker.record(false, &settings), None);
+        }
+        assert_eq!(
+            breaker.record(false, &settings),
+            Some(BreakerTransition::Tripped { failure_rate: 1.0 })
+        );
+    }
+
+    #[test]
+    fn disabled() {
+        let mut settings = settings();
+        let mut breaker = ConnectionBreaker::default();
+        for _ in 0..10 {
+            breaker.record(false, &settings);
+        }
+        assert!(breaker.is_passthrough());
+
+        settings.enabled = false;
+        assert_eq!(
+            breaker.decide(Instant::now(), &settings),
+            ParseDecision::Handle
+        );
+        assert!(!breaker.is_passthrough());
+        for _ in 0..10 {
+            assert_eq!(breaker.record(false, &settings), None);
+        }
+    }
+}
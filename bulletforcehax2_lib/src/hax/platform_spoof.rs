@@ -0,0 +1,216 @@
+//! Reporting another platform to the game server than the one the client runs on.
+//!
+//! The client sends its platform, device model and store in its actor properties when joining a room, and the server
+//! uses them to keep mobile and web players apart. Only the values the client sends are replaced, so no properties
+//! are added that the game wouldn't send on this platform. The real values are still recorded, see
+//! [GameplayState::own_platform](super::GameplayState::own_platform), as the players map only holds what the room
+//! was told.
+
+use photon_lib::{photon_data_type::PhotonDataType, PhotonHashmap};
+
+use crate::protocol::properties::{
+    DEVICE_MODEL_PROPERTY, PLATFORM_PROPERTY, STORE_ID_PROPERTY, WEB_STORE_ID,
+};
+
+/// What to report instead of the client's platform. Values that aren't set are sent as they are.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PlatformSpoof {
+    pub platform: Option<String>,
+    pub device_model: Option<String>,
+    pub store_id: Option<String>,
+}
+
+impl PlatformSpoof {
+    /// Reports a browser client.
+    pub fn web() -> Self {
+        Self {
+            platform: Some("WebGLPlayer".into()),
+            device_model: None,
+            store_id: Some(WEB_STORE_ID.into()),
+        }
+    }
+
+    /// Reports an Android phone.
+    pub fn android() -> Self {
+        Self {
+            platform: Some("Android".into()),
+            device_model: Some("samsung SM-G991B".into()),
+            store_id: Some("BALYZE_MOBILE".into()),
+        }
+    }
+
+    /// Replaces the platform values in actor properties the client sends. Returns whether anything changed.
+    pub fn apply(&self, properties: &mut PhotonHashmap) -> bool {
+        let mut changed = false;
+        for (key, spoofed) in [
+            (PLATFORM_PROPERTY, &self.platform),
+            (DEVICE_MODEL_PROPERTY, &self.device_model),
+            (STORE_ID_PROPERTY, &self.store_id),
+        ] {
+            let spoofed = match spoofed {
+                Some(x) => x,
+                None => continue,
+            };
+            if let Some(PhotonDataType::String(value)) =
+                properties.get_mut(&PhotonDataType::String(key.into()))
+            {
+                if value != spoofed {
+                    *value = spoofed.clone();
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::{
+        highlevel::constants::{operation_code, parameter_code},
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{OperationResponse, PhotonMessage},
+        PhotonHashmap,
+    };
+
+    use super::PlatformSpoof;
+    use crate::{
+        hax::timeline::Replay,
+        protocol::properties::ClientPlatform,
+        proxy::Direction,
+        testsupport::{captured, request, string},
+    };
+
+    /// The actor properties a client of each platform joins with, as captured.
+    fn join_properties(mobile: bool) -> PhotonHashmap {
+        let (platform, device_model, store_id) = match mobile {
+            true => ("Android", "Xiaomi Redmi Note 8", "BALYZE_MOBILE"),
+            false => ("WebGLPlayer", "n/a", "BALYZE_WEB"),
+        };
+        indexmap! {
+            PhotonDataType::Byte(255) => string("me"),
+            string("teamNumber") => PhotonDataType::Byte(1),
+            string("platform") => string(platform),
+            string("deviceModel") => string(device_model),
+            string("storeID") => string(store_id),
+        }
+    }
+
+    /// Joins a room as actor 1, returning the join request and the properties update as they were forwarded.
+    fn join(replay: &mut Replay, mobile: bool) -> Vec<PhotonHashmap> {
+        let properties = join_properties(mobile);
+        let mut echoed = properties.clone();
+        if let Some(spoof) = &replay.state().settings().platform_spoof {
+            spoof.apply(&mut echoed);
+        }
+        let join_response = captured(
+            Direction::ServerToClient,
+            PhotonMessage::OperationResponse(OperationResponse {
+                operation_code: operation_code::JOIN_GAME,
+                return_code: 0,
+                debug_message: None,
+                parameters: indexmap! {
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                    // the server echoes what it was told
+                    parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Integer(1) => PhotonDataType::Hashtable(echoed),
+                    }),
+                    parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                },
+            }),
+        );
+        let messages = [
+            request(operation_code::AUTHENTICATE, indexmap! {}),
+            request(
+                operation_code::JOIN_GAME,
+                indexmap! {
+                    parameter_code::ROOM_NAME => string("room"),
+                    parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(properties.clone()),
+                },
+            ),
+            join_response,
+            request(
+                operation_code::SET_PROPERTIES,
+                indexmap! {
+                    parameter_code::PROPERTIES => PhotonDataType::Hashtable(properties),
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                    parameter_code::BROADCAST => PhotonDataType::Boolean(true),
+                },
+            ),
+        ];
+
+        let mut forwarded = vec![];
+        for message in &messages {
+            let raw = replay.forward(message).expect("nothing should be dropped");
+            if let PhotonMessage::OperationRequest(mut request) =
+                PhotonMessage::from_websocket_bytes(&mut raw.as_slice()).unwrap()
+            {
+                let key = match request.operation_code {
+                    operation_code::JOIN_GAME => parameter_code::PLAYER_PROPERTIES,
+                    operation_code::SET_PROPERTIES => parameter_code::PROPERTIES,
+                    _ => continue,
+                };
+                match request.parameters.swap_remove(&key) {
+                    Some(PhotonDataType::Hashtable(properties)) => forwarded.push(properties),
+                    other => panic!("expected actor properties, got {other:?}"),
+                }
+            }
+        }
+        forwarded
+    }
+
+    #[test]
+    fn records_the_platform_of_both_platforms() {
+        for mobile in [false, true] {
+            let mut replay = Replay::default();
+            let forwarded = join(&mut replay, mobile);
+            assert_eq!(
+                forwarded,
+                [join_properties(mobile), join_properties(mobile)]
+            );
+
+            let state = replay.state();
+            let (_, gameplay) = state.gameplay_state.as_ref().unwrap();
+            assert_eq!(gameplay.own_platform.is_mobile(), Some(mobile));
+            assert_eq!(gameplay.players[&1].platform, gameplay.own_platform);
+        }
+    }
+
+    #[test]
+    fn spoofs_outgoing_values_and_keeps_the_real_ones() {
+        let mut replay = Replay::default();
+        replay
+            .state()
+            .update_settings(|s| s.platform_spoof = Some(PlatformSpoof::web()));
+        let forwarded = join(&mut replay, true);
+
+        let mut expected = join_properties(true);
+        expected[&string("platform")] = string("WebGLPlayer");
+        expected[&string("storeID")] = string("BALYZE_WEB");
+        // the device model isn't spoofed, so it's sent as it is
+        assert_eq!(forwarded, [expected.clone(), expected]);
+
+        let state = replay.state();
+        assert!(state.stats.recent_errors.is_empty());
+        let (_, gameplay) = state.gameplay_state.as_ref().unwrap();
+        assert_eq!(gameplay.players[&1].platform, gameplay.own_platform);
+        assert_eq!(
+            gameplay.own_platform,
+            ClientPlatform {
+                platform: Some("Android".into()),
+                device_model: Some("Xiaomi Redmi Note 8".into()),
+                store_id: Some("BALYZE_MOBILE".into()),
+            }
+        );
+        assert_eq!(
+            gameplay.own_platform.to_string(),
+            "Android, Xiaomi Redmi Note 8, BALYZE_MOBILE"
+        );
+
+        // values the client doesn't send aren't added
+        let mut properties = indexmap! { PhotonDataType::Byte(255) => string("me") };
+        assert!(!PlatformSpoof::android().apply(&mut properties));
+        assert_eq!(properties.len(), 1);
+    }
+}
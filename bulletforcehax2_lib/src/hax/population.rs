@@ -0,0 +1,690 @@
+//! How busy the lobby was over time, per region and map.
+//!
+//! While a lobby connection is open, the task started by [BulletForceHax::start_population_sampler] samples the
+//! [room cache](super::lobby_cache) and the latest [APP_STATS](photon_lib::highlevel::constants::event_code::APP_STATS)
+//! event of the lobby. Samples are summed into buckets of [BUCKET_LENGTH] per region, so a bucket holds the averages
+//! over however many samples it got. Time without a lobby connection has no samples, and so no buckets.
+//!
+//! Buckets are saved to one file per UTC day, named like `2022-10-16.json`, in the directory given to
+//! [HaxState::load_population_history].
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::lock::Mutex;
+use photon_lib::{highlevel::parameters::Parameters, ParameterMap};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use super::{lobby_cache::RoomCache, BulletForceHax, HaxState};
+use crate::{
+    config::versioned::{self, Migration, VersionError, Versioned},
+    protocol::properties::BulletForceRoomProperties,
+};
+
+/// How much time a bucket covers.
+pub const BUCKET_LENGTH: Duration = Duration::from_secs(15 * 60);
+
+/// How often the lobby is sampled if not configured otherwise.
+pub const DEFAULT_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The file extension of the daily files.
+const EXTENSION: &str = "json";
+
+/// The counts the server sends in [APP_STATS](photon_lib::highlevel::constants::event_code::APP_STATS) events,
+/// which cover the whole region rather than the rooms the lobby listed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AppStats {
+    /// The players in rooms.
+    pub peer_count: Option<i32>,
+    pub game_count: Option<i32>,
+    /// The players in the lobby, looking for rooms.
+    pub master_peer_count: Option<i32>,
+}
+
+impl AppStats {
+    pub fn from_parameters(parameters: &ParameterMap) -> Self {
+        let parameters = Parameters(parameters);
+        Self {
+            peer_count: parameters.peer_count(),
+            game_count: parameters.game_count(),
+            master_peer_count: parameters.master_peer_count(),
+        }
+    }
+
+    /// All players of the region, in rooms or in the lobby.
+    pub fn players(&self) -> Option<i32> {
+        match (self.peer_count, self.master_peer_count) {
+            (None, None) => None,
+            (in_rooms, in_lobby) => Some(in_rooms.unwrap_or(0) + in_lobby.unwrap_or(0)),
+        }
+    }
+}
+
+/// Rooms and the players in them, summed over samples.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MapTotals {
+    pub rooms: u64,
+    pub players: u64,
+}
+
+/// The counts of the lobby at one point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PopulationSample {
+    pub timestamp: SystemTime,
+    /// The region the client authenticated with, if known.
+    pub region: Option<String>,
+    /// The players in the listed rooms.
+    pub players: u32,
+    pub rooms: u32,
+    pub passworded_rooms: u32,
+    /// By map name. Rooms that don't name their map are left out.
+    pub maps: BTreeMap<String, MapTotals>,
+    /// The players of the whole region, from the latest [AppStats].
+    pub server_players: Option<i32>,
+}
+
+impl PopulationSample {
+    /// Counts the rooms in the cache.
+    pub fn from_rooms(
+        rooms: &RoomCache,
+        stats: Option<&AppStats>,
+        region: Option<&str>,
+        timestamp: SystemTime,
+    ) -> Self {
+        let mut sample = Self {
+            timestamp,
+            region: region.map(str::to_string),
+            players: 0,
+            rooms: 0,
+            passworded_rooms: 0,
+            maps: BTreeMap::new(),
+            server_players: stats.and_then(AppStats::players),
+        };
+        for (_, room) in rooms.iter() {
+            let view = room.view();
+            let players = view.player_count().copied().unwrap_or(0) as u32;
+            sample.players += players;
+            sample.rooms += 1;
+            if view.has_password() {
+                sample.passworded_rooms += 1;
+            }
+            if let Some(map) = view.map_name() {
+                let totals = sample.maps.entry(map.to_string()).or_default();
+                totals.rooms += 1;
+                totals.players += players as u64;
+            }
+        }
+        sample
+    }
+}
+
+/// The samples of one region within [BUCKET_LENGTH], summed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PopulationBucket {
+    /// When the bucket starts, in seconds since the unix epoch.
+    pub start: u64,
+    pub region: Option<String>,
+    /// How many samples were summed into this bucket.
+    pub samples: u32,
+    /// The players in listed rooms, summed over the samples.
+    pub players: u64,
+    /// The most players in listed rooms of any sample.
+    pub peak_players: u32,
+    pub rooms: u64,
+    pub passworded_rooms: u64,
+    pub maps: BTreeMap<String, MapTotals>,
+    /// The players of the whole region, summed over the samples that had [AppStats].
+    pub server_players: u64,
+    pub server_samples: u32,
+}
+
+impl PopulationBucket {
+    fn new(start: u64, region: Option<String>) -> Self {
+        Self {
+            start,
+            region,
+            samples: 0,
+            players: 0,
+            peak_players: 0,
+            rooms: 0,
+            passworded_rooms: 0,
+            maps: BTreeMap::new(),
+            server_players: 0,
+            server_samples: 0,
+        }
+    }
+
+    fn add(&mut self, sample: &PopulationSample) {
+        self.samples += 1;
+        self.players += sample.players as u64;
+        self.peak_players = self.peak_players.max(sample.players);
+        self.rooms += sample.rooms as u64;
+        self.passworded_rooms += sample.passworded_rooms as u64;
+        for (map, totals) in &sample.maps {
+            let sum = self.maps.entry(map.clone()).or_default();
+            sum.rooms += totals.rooms;
+            sum.players += totals.players;
+        }
+        if let Some(players) = sample.server_players {
+            self.server_players += players.max(0) as u64;
+            self.server_samples += 1;
+        }
+    }
+
+    pub fn start_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.start)
+    }
+
+    fn average(&self, sum: u64) -> f32 {
+        match self.samples {
+            0 => 0.0,
+            n => sum as f32 / n as f32,
+        }
+    }
+
+    /// The average players in listed rooms.
+    pub fn average_players(&self) -> f32 {
+        self.average(self.players)
+    }
+
+    pub fn average_rooms(&self) -> f32 {
+        self.average(self.rooms)
+    }
+
+    /// The average rooms on the given map.
+    pub fn average_map_rooms(&self, map: &str) -> f32 {
+        self.average(self.maps.get(map).map_or(0, |m| m.rooms))
+    }
+
+    /// Which part of the listed rooms had a password, if any rooms were listed.
+    pub fn passworded_fraction(&self) -> Option<f32> {
+        (self.rooms > 0).then(|| self.passworded_rooms as f32 / self.rooms as f32)
+    }
+
+    /// The average players of the whole region, if the server sent its stats.
+    pub fn average_server_players(&self) -> Option<f32> {
+        (self.server_samples > 0).then(|| self.server_players as f32 / self.server_samples as f32)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct DayFile {
+    /// Oldest first.
+    buckets: Vec<PopulationBucket>,
+}
+
+impl Versioned for DayFile {
+    const NAME: &'static str = "population history";
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+/// The buckets of every day, saved to one file per day.
+#[derive(Debug, Default)]
+pub struct PopulationHistory {
+    /// Where the daily files are saved. If not set, the history is only kept in memory.
+    dir: Option<PathBuf>,
+    /// By days since the unix epoch.
+    days: BTreeMap<u64, DayFile>,
+    /// The days whose file a newer version wrote. Their samples are still recorded, but never saved over that file.
+    unsaved: HashSet<u64>,
+}
+
+impl PopulationHistory {
+    /// Loads the daily files in the given directory.
+    ///
+    /// This never fails. Files that are corrupt are replaced when their day gets a new sample, and files from a newer
+    /// version are left alone.
+    pub fn load(dir: &Path) -> Self {
+        let mut history = Self {
+            dir: Some(dir.to_owned()),
+            ..Default::default()
+        };
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!(
+                        dir = format!("{dir:?}"),
+                        "Could not read population history: {e}"
+                    );
+                }
+                return history;
+            }
+        };
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            let day = match path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .filter(|_| path.extension().is_some_and(|e| e == EXTENSION))
+                .and_then(parse_date)
+            {
+                Some(day) => day,
+                None => continue,
+            };
+            match versioned::read_optional(&path) {
+                Ok(Some(data)) => {
+                    history.days.insert(day, data);
+                }
+                // removed since the directory was listed
+                Ok(None) => {}
+                Err(e) if matches!(e.downcast_ref(), Some(VersionError::NewerVersion { .. })) => {
+                    warn!(
+                        path = format!("{path:?}"),
+                        "Could not load population history, this day won't be saved: {e:#}"
+                    );
+                    history.unsaved.insert(day);
+                }
+                Err(e) => warn!(
+                    path = format!("{path:?}"),
+                    "Could not read population history, starting the day over: {e:#}"
+                ),
+            }
+        }
+        history
+    }
+
+    fn save(&self, day: u64) -> anyhow::Result<()> {
+        let (dir, data) = match (&self.dir, self.days.get(&day)) {
+            (Some(dir), Some(data)) if !self.unsaved.contains(&day) => (dir, data),
+            _ => return Ok(()),
+        };
+
+        let path = dir.join(format!("{}.{EXTENSION}", format_date(day)));
+        versioned::write(&path, data)?;
+        debug!(day = format_date(day), "Saved population history");
+        Ok(())
+    }
+
+    /// Adds a sample to the bucket of its time and region, and saves its day.
+    pub fn record(&mut self, sample: &PopulationSample) -> anyhow::Result<()> {
+        let secs = sample
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let start = secs - secs % BUCKET_LENGTH.as_secs();
+        let day = start / SECS_PER_DAY;
+
+        let buckets = &mut self.days.entry(day).or_default().buckets;
+        let index = match buckets
+            .iter()
+            .position(|b| b.start == start && b.region == sample.region)
+        {
+            Some(index) => index,
+            None => {
+                buckets.push(PopulationBucket::new(start, sample.region.clone()));
+                buckets.len() - 1
+            }
+        };
+        buckets[index].add(sample);
+        self.save(day)
+    }
+
+    /// The buckets that start within the given time, oldest first.
+    pub fn range(&self, range: Range<SystemTime>) -> Vec<&PopulationBucket> {
+        let secs = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        };
+        let (start, end) = (secs(range.start), secs(range.end));
+        if start >= end {
+            return vec![];
+        }
+        let mut buckets = self
+            .days
+            .range(start / SECS_PER_DAY..=end / SECS_PER_DAY)
+            .flat_map(|(_, file)| &file.buckets)
+            .filter(|b| (start..end).contains(&b.start))
+            .collect::<Vec<_>>();
+        buckets.sort_by(|a, b| (a.start, &a.region).cmp(&(b.start, &b.region)));
+        buckets
+    }
+
+    /// How many buckets are loaded.
+    pub fn len(&self) -> usize {
+        self.days.values().map(|d| d.buckets.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Writes buckets as CSV, one row per bucket with the averages of its samples and a column for the average rooms on
+/// each map.
+pub fn to_csv(buckets: &[&PopulationBucket]) -> String {
+    let maps = buckets
+        .iter()
+        .flat_map(|b| b.maps.keys())
+        .collect::<BTreeSet<_>>();
+
+    let mut header = [
+        "start",
+        "region",
+        "samples",
+        "players",
+        "peak_players",
+        "rooms",
+        "passworded_fraction",
+        "server_players",
+    ]
+    .map(String::from)
+    .to_vec();
+    header.extend(maps.iter().map(|map| csv_field(&format!("rooms:{map}"))));
+    let mut out = header.join(",");
+    out.push('\n');
+
+    let optional = |value: Option<f32>| value.map(|v| format!("{v:.2}")).unwrap_or_default();
+    for bucket in buckets {
+        let mut row = vec![
+            format_timestamp(bucket.start),
+            csv_field(bucket.region.as_deref().unwrap_or_default()),
+            bucket.samples.to_string(),
+            format!("{:.2}", bucket.average_players()),
+            bucket.peak_players.to_string(),
+            format!("{:.2}", bucket.average_rooms()),
+            optional(bucket.passworded_fraction()),
+            optional(bucket.average_server_players()),
+        ];
+        row.extend(
+            maps.iter()
+                .map(|map| format!("{:.2}", bucket.average_map_rooms(map))),
+        );
+        out.push_str(&row.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+/// Quotes a field if it holds a separator or quote.
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+/// Formats seconds since the unix epoch as an ISO 8601 UTC time, like `2022-10-16T14:15:00Z`.
+fn format_timestamp(secs: u64) -> String {
+    let time = secs % SECS_PER_DAY;
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        format_date(secs / SECS_PER_DAY),
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Formats days since the unix epoch as a date, like `2022-10-16`.
+fn format_date(days: u64) -> String {
+    // Howard Hinnant's civil_from_days, for dates after the epoch
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Parses a date formatted by [format_date] back into days since the unix epoch.
+fn parse_date(date: &str) -> Option<u64> {
+    let mut parts = date.splitn(3, '-').map(|p| p.parse::<u64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // Howard Hinnant's days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year % 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = (era * 146_097 + doe).checked_sub(719_468)?;
+    // reject dates like the 31st of February
+    (format_date(days) == date).then_some(days)
+}
+
+impl HaxState {
+    /// Loads the population history from the given directory, replacing the one in memory. New samples are saved to
+    /// that directory.
+    pub fn load_population_history(&mut self, dir: &Path) {
+        self.population = PopulationHistory::load(dir);
+    }
+
+    /// The population buckets that start within the given time, oldest first. See [to_csv] to export them.
+    pub fn population_history(&self, range: Range<SystemTime>) -> Vec<&PopulationBucket> {
+        self.population.range(range)
+    }
+
+    /// Samples the rooms the lobby listed and adds them to the population history.
+    ///
+    /// Does nothing without a lobby connection, or before the lobby listed any rooms.
+    pub(crate) fn sample_population(&mut self, now: SystemTime) -> Option<PopulationSample> {
+        let (_, lobby) = self.lobby_state.as_ref()?;
+        if lobby.rooms.is_empty() {
+            return None;
+        }
+        let sample = PopulationSample::from_rooms(
+            &lobby.rooms,
+            lobby.app_stats.as_ref(),
+            self.global_state.region.as_deref(),
+            now,
+        );
+        if let Err(e) = self.population.record(&sample) {
+            warn!("Could not save population history: {e:#}");
+        }
+        Some(sample)
+    }
+}
+
+/// A running population sampler. Stopping it ends the task before its next sample.
+pub struct PopulationSamplerHandle {
+    stop: Arc<AtomicBool>,
+}
+
+impl PopulationSamplerHandle {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl BulletForceHax {
+    /// Samples the lobby into the population history at the given interval, until the returned handle is stopped.
+    pub fn start_population_sampler(&self, interval: Duration) -> PopulationSamplerHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        tokio::spawn(run_sampler(self.get_state(), interval, stop.clone()));
+        PopulationSamplerHandle { stop }
+    }
+}
+
+async fn run_sampler(state: Arc<Mutex<HaxState>>, interval: Duration, stop: Arc<AtomicBool>) {
+    let mut interval = tokio::time::interval(interval);
+    // after a stall, sample once instead of catching up with samples of the same moment
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        state.lock().await.sample_population(SystemTime::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use photon_lib::{
+        highlevel::constants::game_property_key, indexmap::indexmap,
+        photon_data_type::PhotonDataType, PhotonHashmap,
+    };
+
+    use super::{format_date, parse_date, to_csv, AppStats, PopulationHistory, PopulationSample};
+    use crate::{hax::lobby_cache::RoomCache, protocol::properties::GameVariant};
+
+    fn room(players: u8, map: &str, password: &str) -> PhotonDataType {
+        let keys = GameVariant::BulletForce.keys();
+        PhotonDataType::Hashtable(indexmap! {
+            PhotonDataType::Byte(game_property_key::PLAYER_COUNT) => PhotonDataType::Byte(players),
+            PhotonDataType::String(keys.map_name.into()) => PhotonDataType::String(map.into()),
+            PhotonDataType::String(keys.password.into()) => PhotonDataType::String(password.into()),
+        })
+    }
+
+    fn cache(rooms: &[(&str, PhotonDataType)]) -> RoomCache {
+        let games: PhotonHashmap = rooms
+            .iter()
+            .map(|(id, room)| (PhotonDataType::String(id.to_string()), room.clone()))
+            .collect();
+        let mut cache = RoomCache::default();
+        cache.replace(&games, Instant::now());
+        cache
+    }
+
+    #[test]
+    fn aggregates_samples_into_buckets() {
+        let stats = AppStats {
+            peer_count: Some(90),
+            game_count: Some(20),
+            master_peer_count: Some(10),
+        };
+        let start = UNIX_EPOCH + Duration::from_secs(1_665_929_700);
+        let busy = cache(&[
+            ("a", room(6, "Urban", "")),
+            ("b", room(4, "Urban", "secret")),
+            ("c", room(2, "Outskirts", "")),
+        ]);
+        let quiet = cache(&[("a", room(2, "Urban", ""))]);
+
+        let sample = PopulationSample::from_rooms(&busy, Some(&stats), Some("eu"), start);
+        assert_eq!(
+            (sample.players, sample.rooms, sample.passworded_rooms),
+            (12, 3, 1)
+        );
+        assert_eq!(sample.maps["Urban"].rooms, 2);
+        assert_eq!(sample.server_players, Some(100));
+
+        let mut history = PopulationHistory::default();
+        history.record(&sample).unwrap();
+        let later = start + Duration::from_secs(60);
+        history
+            .record(&PopulationSample::from_rooms(
+                &quiet,
+                None,
+                Some("eu"),
+                later,
+            ))
+            .unwrap();
+        // another region gets its own bucket
+        history
+            .record(&PopulationSample::from_rooms(
+                &quiet,
+                None,
+                Some("us"),
+                later,
+            ))
+            .unwrap();
+        // an hour without samples leaves a gap
+        let after_gap = start + Duration::from_secs(60 * 60);
+        history
+            .record(&PopulationSample::from_rooms(
+                &busy,
+                None,
+                Some("eu"),
+                after_gap,
+            ))
+            .unwrap();
+
+        let buckets = history.range(start..after_gap + Duration::from_secs(1));
+        assert_eq!(buckets.len(), 3);
+        let first = buckets[0];
+        assert_eq!(first.region.as_deref(), Some("eu"));
+        assert_eq!(first.samples, 2);
+        assert_eq!(first.average_players(), 7.0);
+        assert_eq!(first.peak_players, 12);
+        assert_eq!(first.average_rooms(), 2.0);
+        assert_eq!(first.average_map_rooms("Urban"), 1.5);
+        assert_eq!(first.average_map_rooms("Outskirts"), 0.5);
+        assert_eq!(first.passworded_fraction(), Some(0.25));
+        assert_eq!(first.average_server_players(), Some(100.0));
+        assert_eq!(buckets[1].region.as_deref(), Some("us"));
+        assert_eq!(buckets[2].start, first.start + 60 * 60);
+        assert_eq!(buckets[2].average_server_players(), None);
+
+        assert_eq!(history.range(start..start).len(), 0);
+        assert_eq!(
+            history
+                .range(start + Duration::from_secs(15 * 60)..after_gap)
+                .len(),
+            0
+        );
+
+        let csv = to_csv(&buckets[..2]);
+        assert_eq!(
+            csv,
+            "start,region,samples,players,peak_players,rooms,passworded_fraction,server_players,rooms:Outskirts,rooms:Urban\n\
+             2022-10-16T14:15:00Z,eu,2,7.00,12,2.00,0.25,100.00,0.50,1.50\n\
+             2022-10-16T14:15:00Z,us,1,2.00,2,1.00,0.00,,0.00,1.00\n"
+        );
+    }
+
+    #[test]
+    fn round_trips_daily_files() {
+        let dir = std::env::temp_dir().join(format!("bfhax-population-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let rooms = cache(&[("a", room(3, "Urban", "")), ("b", room(5, "Station", ""))]);
+        // just before and after midnight, so the samples go to two files
+        let before = UNIX_EPOCH + Duration::from_secs(1_665_964_799);
+        let after = before + Duration::from_secs(1);
+
+        let mut history = PopulationHistory::load(&dir);
+        for timestamp in [before, after, after] {
+            history
+                .record(&PopulationSample::from_rooms(
+                    &rooms,
+                    None,
+                    Some("eu"),
+                    timestamp,
+                ))
+                .unwrap();
+        }
+        assert!(dir.join("2022-10-16.json").exists());
+        assert!(dir.join("2022-10-17.json").exists());
+
+        let all = before - Duration::from_secs(60)..after + Duration::from_secs(60);
+        let loaded = PopulationHistory::load(&dir);
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.range(all.clone()), history.range(all));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(19_281), "2022-10-16");
+        assert_eq!(format_date(11_016), "2000-02-29");
+        assert_eq!(parse_date("2022-10-16"), Some(19_281));
+        assert_eq!(parse_date("2000-02-29"), Some(11_016));
+        assert_eq!(parse_date("2022-02-31"), None);
+        assert_eq!(parse_date("notes"), None);
+    }
+}
@@ -0,0 +1,776 @@
+//! All feature settings as a single document that can be shared, and named profiles of them saved to disk.
+//!
+//! A [Profile] holds the lobby and gameplay toggles, the property firewall, the non-secret authentication overrides,
+//! the room notes, the watchlist and the [schedules](super::scheduler). It leaves out anything that only makes sense
+//! for the current session, such as muted actors, and anything secret, such as the user id and custom authentication
+//! data of the overrides. Tuning settings like the [detection settings](super::DetectionSettings) come from the config
+//! file and aren't included.
+//!
+//! A profile saved while [paused](HaxState::set_global_pause) holds the settings from before the pause, and pauses
+//! again when applied.
+//!
+//! Profiles are [Versioned] documents. Fields a newer version of the program added are ignored with a warning when
+//! applying, so profiles can be shared between versions.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use super::{
+    auth_overrides::AuthOverrides,
+    lobby_sort::LobbySort,
+    property_firewall::{format_key, parse_key},
+    room_notes::{RoomKey, RoomNote},
+    scheduler::{check_action, check_schedule, Action, Schedule},
+    watchlist::{WatchEntry, WatchTarget, DEFAULT_COOLDOWN},
+    HaxState, VersionInfo,
+};
+use crate::config::versioned::{self, Migration, VersionError, Versioned};
+
+/// The file extension of saved profiles.
+const EXTENSION: &str = "json";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub show_mobile_games: bool,
+    pub show_other_versions: bool,
+    pub strip_passwords: bool,
+    /// The region to force, if forcing is enabled.
+    pub forced_region: Option<String>,
+    /// The [LobbySort] by its name, such as `player count`.
+    pub lobby_sort: Option<String>,
+    pub rewrite_newfps_rooms: bool,
+    pub lobby_phase_annotations: bool,
+    pub queue_jump: bool,
+    /// The name to spoof, if spoofing is enabled.
+    pub spoofed_name: Option<String>,
+    pub mute_all_cosmetic: bool,
+    pub ghost_join: bool,
+    pub receive_all_groups: bool,
+    pub stealth_host: bool,
+    /// The actor property keys the firewall blocks, numbers being byte keys as for
+    /// [parse_keys](super::property_firewall::parse_keys).
+    pub blocked_actor_properties: Vec<String>,
+    /// The room property keys the firewall blocks.
+    pub blocked_room_properties: Vec<String>,
+    /// The app version to authenticate with, see [AuthOverrides::app_version].
+    pub auth_app_version: Option<String>,
+    pub auth_region: Option<String>,
+    /// Room notes by room name.
+    pub room_notes: BTreeMap<String, RoomNote>,
+    /// Room notes by the user id of the host.
+    pub host_notes: BTreeMap<String, RoomNote>,
+    pub watchlist: Vec<WatchEntry>,
+    pub watch_cooldown_secs: u64,
+    pub schedules: Vec<Schedule>,
+    /// The macros the schedules can run, by name.
+    pub macros: BTreeMap<String, Vec<Action>>,
+    /// Whether all features were paused, the other fields being the settings to resume to.
+    pub paused: bool,
+    /// Fields this version doesn't know, likely from a newer version.
+    #[serde(flatten, skip_serializing)]
+    unknown: BTreeMap<String, Value>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            show_mobile_games: false,
+            show_other_versions: false,
+            strip_passwords: false,
+            forced_region: None,
+            lobby_sort: None,
+            rewrite_newfps_rooms: true,
+            lobby_phase_annotations: false,
+            queue_jump: false,
+            spoofed_name: None,
+            mute_all_cosmetic: false,
+            ghost_join: false,
+            receive_all_groups: false,
+            stealth_host: false,
+            blocked_actor_properties: vec![],
+            blocked_room_properties: vec![],
+            auth_app_version: None,
+            auth_region: None,
+            room_notes: BTreeMap::new(),
+            host_notes: BTreeMap::new(),
+            watchlist: vec![],
+            watch_cooldown_secs: DEFAULT_COOLDOWN.as_secs(),
+            schedules: vec![],
+            macros: BTreeMap::new(),
+            paused: false,
+            unknown: BTreeMap::new(),
+        }
+    }
+}
+
+impl Versioned for Profile {
+    const NAME: &'static str = "profile";
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+impl Profile {
+    pub fn to_json(&self) -> String {
+        let bytes = versioned::to_vec_pretty(self).expect("profiles always serialize");
+        String::from_utf8(bytes).expect("serde_json writes UTF-8")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, ProfileError> {
+        Ok(versioned::from_slice(json.as_bytes())?)
+    }
+
+    /// The names of the fields this version doesn't know.
+    pub fn unknown_fields(&self) -> impl Iterator<Item = &str> {
+        self.unknown.keys().map(String::as_str)
+    }
+
+    /// Checks every entry, so all problems can be reported at once.
+    fn validate(&self) -> Result<(), ProfileError> {
+        let mut invalid = vec![];
+        let mut check = |field: String, reason: Option<String>| {
+            if let Some(reason) = reason {
+                invalid.push(InvalidEntry { field, reason });
+            }
+        };
+        let not_empty = |value: &str| value.trim().is_empty().then(|| "is empty".to_string());
+
+        if let Some(region) = &self.forced_region {
+            check("forced_region".into(), not_empty(region));
+        }
+        if let Some(sort) = &self.lobby_sort {
+            check(
+                "lobby_sort".into(),
+                parse_sort(sort).is_none().then(|| {
+                    let known = LobbySort::ALL.map(|s| s.to_string()).join(", ");
+                    format!("unknown order {sort:?}, expected one of {known}")
+                }),
+            );
+        }
+        if let Some(name) = &self.spoofed_name {
+            check("spoofed_name".into(), not_empty(name));
+        }
+        for (field, keys) in [
+            ("blocked_actor_properties", &self.blocked_actor_properties),
+            ("blocked_room_properties", &self.blocked_room_properties),
+        ] {
+            for (i, key) in keys.iter().enumerate() {
+                check(format!("{field}[{i}]"), not_empty(key));
+            }
+        }
+        if let Some(version) = &self.auth_app_version {
+            check(
+                "auth_app_version".into(),
+                VersionInfo::parse(version).is_none().then(|| {
+                    format!("{version:?} is not in the <game version>_<photon version> format")
+                }),
+            );
+        }
+        if let Some(region) = &self.auth_region {
+            check("auth_region".into(), not_empty(region));
+        }
+        for (field, notes) in [
+            ("room_notes", &self.room_notes),
+            ("host_notes", &self.host_notes),
+        ] {
+            if notes.contains_key("") {
+                check(format!("{field}[\"\"]"), Some("has no room or host".into()));
+            }
+        }
+        for (i, entry) in self.watchlist.iter().enumerate() {
+            let target = match &entry.target {
+                WatchTarget::UserId(target) | WatchTarget::Nickname(target) => target,
+            };
+            check(format!("watchlist[{i}]"), not_empty(target));
+        }
+        for (name, actions) in &self.macros {
+            check(format!("macros[{name:?}]"), not_empty(name));
+            for (i, action) in actions.iter().enumerate() {
+                check(
+                    format!("macros[{name:?}][{i}]"),
+                    check_action(action, &self.macros, true),
+                );
+            }
+        }
+        for (i, schedule) in self.schedules.iter().enumerate() {
+            check(
+                format!("schedules[{i}]"),
+                check_schedule(schedule, &self.macros),
+            );
+        }
+
+        match invalid.is_empty() {
+            true => Ok(()),
+            false => Err(ProfileError::Invalid(invalid)),
+        }
+    }
+}
+
+fn parse_sort(name: &str) -> Option<LobbySort> {
+    LobbySort::ALL.into_iter().find(|s| s.to_string() == name)
+}
+
+/// An entry of a profile that can't be applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidEntry {
+    /// Where the entry is in the profile, such as `watchlist[2]`.
+    pub field: String,
+    pub reason: String,
+}
+
+impl Display for InvalidEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.field, self.reason)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ProfileError {
+    /// Some entries can't be applied, so nothing was.
+    #[error("invalid profile: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
+    Invalid(Vec<InvalidEntry>),
+    #[error(transparent)]
+    Version(#[from] VersionError),
+    #[error("there is no profile named {0:?}")]
+    NotFound(String),
+    /// Reading or writing a file failed.
+    #[error("{0:#}")]
+    Io(anyhow::Error),
+}
+
+/// Named profiles, saved as one file each in a directory.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileStore {
+    /// Where profiles are saved. If not set, there are no named profiles.
+    dir: Option<PathBuf>,
+}
+
+impl ProfileStore {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: Some(dir.to_owned()),
+        }
+    }
+
+    /// Where profiles are saved, if anywhere.
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, ProfileError> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => {
+                return Err(ProfileError::Io(anyhow::anyhow!(
+                    "no directory for profiles is set"
+                )))
+            }
+        };
+        let valid = !name.trim().is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'));
+        match valid {
+            true => Ok(dir.join(format!("{name}.{EXTENSION}"))),
+            false => Err(ProfileError::Invalid(vec![InvalidEntry {
+                field: "name".into(),
+                reason: format!(
+                    "{name:?} may only have letters, digits, spaces, dashes and underscores"
+                ),
+            }])),
+        }
+    }
+
+    /// The names of the saved profiles, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let entries = match self.dir.as_ref().map(std::fs::read_dir) {
+            Some(Ok(entries)) => entries,
+            _ => return vec![],
+        };
+        let mut names = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                match path.extension()?.to_str()? {
+                    EXTENSION => Some(path.file_stem()?.to_str()?.to_string()),
+                    _ => None,
+                }
+            })
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    pub fn save(&self, name: &str, profile: &Profile) -> Result<(), ProfileError> {
+        let path = self.path(name)?;
+        versioned::write(&path, profile).map_err(ProfileError::Io)?;
+        debug!(name, "Saved profile");
+        Ok(())
+    }
+
+    pub fn load(&self, name: &str) -> Result<Profile, ProfileError> {
+        let path = self.path(name)?;
+        match std::fs::read_to_string(&path) {
+            Ok(json) => Profile::from_json(&json),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(ProfileError::NotFound(name.into()))
+            }
+            Err(e) => Err(ProfileError::Io(
+                anyhow::Error::new(e).context(format!("read profile from {path:?}")),
+            )),
+        }
+    }
+
+    /// Deletes a saved profile. Returns whether there was one.
+    pub fn delete(&self, name: &str) -> Result<bool, ProfileError> {
+        let path = self.path(name)?;
+        match std::fs::remove_file(&path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(ProfileError::Io(
+                anyhow::Error::new(e).context(format!("delete profile {path:?}")),
+            )),
+        }
+    }
+}
+
+impl HaxState {
+    /// Captures the current feature settings, or the ones to resume to if paused.
+    pub fn export_profile(&self) -> Profile {
+        let current = self.settings();
+        let settings = current.paused.as_deref().unwrap_or(&current);
+        let overrides = settings.auth_overrides.clone().unwrap_or_default();
+        let (mut room_notes, mut host_notes) = (BTreeMap::new(), BTreeMap::new());
+        for (key, note) in self.room_notes.iter() {
+            match key {
+                RoomKey::RoomName(name) => room_notes.insert(name, note.clone()),
+                RoomKey::HostUserId(user_id) => host_notes.insert(user_id, note.clone()),
+            };
+        }
+
+        Profile {
+            show_mobile_games: settings.show_mobile_games,
+            show_other_versions: settings.show_other_versions,
+            strip_passwords: settings.strip_passwords,
+            forced_region: settings
+                .forced_region
+                .0
+                .then(|| settings.forced_region.1.clone()),
+            lobby_sort: settings.lobby_sort.map(|sort| sort.to_string()),
+            rewrite_newfps_rooms: settings.lobby_variants.newfps,
+            lobby_phase_annotations: settings.lobby_phase_annotations,
+            queue_jump: settings.queue_jump,
+            spoofed_name: settings
+                .spoofed_name
+                .0
+                .then(|| settings.spoofed_name.1.clone()),
+            mute_all_cosmetic: settings.mute_all_cosmetic,
+            ghost_join: settings.ghost_join,
+            receive_all_groups: self.receive_all_groups,
+            stealth_host: self.stealth_host,
+            blocked_actor_properties: self
+                .property_firewall
+                .actor_blocklist
+                .iter()
+                .map(format_key)
+                .collect(),
+            blocked_room_properties: self
+                .property_firewall
+                .game_blocklist
+                .iter()
+                .map(format_key)
+                .collect(),
+            auth_app_version: overrides.app_version,
+            auth_region: overrides.region,
+            room_notes,
+            host_notes,
+            watchlist: self.watchlist.entries().to_vec(),
+            watch_cooldown_secs: self.watchlist.cooldown().as_secs(),
+            schedules: self.scheduler.schedules().cloned().collect(),
+            macros: self.scheduler.macros().clone(),
+            paused: current.paused.is_some(),
+            unknown: BTreeMap::new(),
+        }
+    }
+
+    /// Applies a profile, replacing the current feature settings, room notes, watchlist and schedules. Returns warnings
+    /// about the parts of the profile that were ignored.
+    ///
+    /// The profile is validated first, and if any entry is invalid nothing is changed. The secret parts of the
+    /// authentication overrides are kept. While paused, the settings to resume to are replaced instead, and applying
+    /// doesn't resume.
+    pub fn apply_profile(&mut self, profile: Profile) -> Result<Vec<String>, ProfileError> {
+        profile.validate()?;
+        let warnings = profile
+            .unknown_fields()
+            .map(|field| {
+                format!("ignored unknown field {field:?}, the profile may be from a newer version")
+            })
+            .collect::<Vec<_>>();
+        for warning in &warnings {
+            warn!("{warning}");
+        }
+
+        self.room_notes
+            .replace(profile.room_notes, profile.host_notes)
+            .map_err(ProfileError::Io)?;
+        self.watchlist
+            .replace(
+                profile.watchlist,
+                Duration::from_secs(profile.watch_cooldown_secs),
+            )
+            .map_err(ProfileError::Io)?;
+        self.scheduler.replace(profile.schedules, profile.macros);
+
+        self.update_settings(|current| {
+            let settings = match &mut current.paused {
+                Some(previous) => previous,
+                None => current,
+            };
+            settings.show_mobile_games = profile.show_mobile_games;
+            settings.show_other_versions = profile.show_other_versions;
+            settings.strip_passwords = profile.strip_passwords;
+            settings.forced_region = match profile.forced_region {
+                Some(region) => (true, region),
+                None => (false, std::mem::take(&mut settings.forced_region.1)),
+            };
+            settings.lobby_sort = profile.lobby_sort.as_deref().and_then(parse_sort);
+            settings.lobby_variants.newfps = profile.rewrite_newfps_rooms;
+            settings.lobby_phase_annotations = profile.lobby_phase_annotations;
+            settings.queue_jump = profile.queue_jump;
+            settings.spoofed_name = match profile.spoofed_name {
+                Some(name) => (true, name),
+                None => (false, std::mem::take(&mut settings.spoofed_name.1)),
+            };
+            settings.mute_all_cosmetic = profile.mute_all_cosmetic;
+            settings.ghost_join = profile.ghost_join;
+
+            let secrets = settings.auth_overrides.take().unwrap_or_default();
+            let overrides = AuthOverrides {
+                app_version: profile.auth_app_version,
+                region: profile.auth_region,
+                ..secrets
+            };
+            if overrides != AuthOverrides::default() {
+                settings.auth_overrides = Some(overrides);
+            }
+        });
+        self.set_receive_all_groups(profile.receive_all_groups);
+        self.set_stealth_host(profile.stealth_host);
+        self.property_firewall.actor_blocklist = profile
+            .blocked_actor_properties
+            .iter()
+            .map(|key| parse_key(key.trim()))
+            .collect();
+        self.property_firewall.game_blocklist = profile
+            .blocked_room_properties
+            .iter()
+            .map(|key| parse_key(key.trim()))
+            .collect();
+        if profile.paused {
+            self.set_global_pause(true);
+        }
+
+        Ok(warnings)
+    }
+
+    /// Turns all feature settings back to their defaults. Room notes and the watchlist are kept, as they are data
+    /// rather than settings.
+    pub fn reset_to_defaults(&mut self) {
+        let current = self.export_profile();
+        let defaults = Profile {
+            room_notes: current.room_notes,
+            host_notes: current.host_notes,
+            watchlist: current.watchlist,
+            watch_cooldown_secs: current.watch_cooldown_secs,
+            ..Profile::default()
+        };
+        if let Err(e) = self.apply_profile(defaults) {
+            warn!("Could not reset to defaults: {e}");
+        }
+    }
+
+    /// Sets the directory named profiles are saved in.
+    pub fn load_profiles(&mut self, dir: &Path) {
+        self.profiles = ProfileStore::new(dir);
+    }
+
+    pub fn profiles(&self) -> &ProfileStore {
+        &self.profiles
+    }
+
+    /// Saves the current feature settings as a named profile.
+    pub fn save_profile(&self, name: &str) -> Result<(), ProfileError> {
+        self.profiles.save(name, &self.export_profile())
+    }
+
+    /// Applies a named profile, see [Self::apply_profile].
+    pub fn load_profile(&mut self, name: &str) -> Result<Vec<String>, ProfileError> {
+        let profile = self.profiles.load(name)?;
+        self.apply_profile(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::photon_data_type::PhotonDataType;
+
+    use super::{InvalidEntry, Profile, ProfileError, ProfileStore};
+    use crate::hax::{
+        auth_overrides::AuthOverrides,
+        game_variant::VariantSettings,
+        lobby_sort::LobbySort,
+        match_phase::MatchPhase,
+        room_notes::{RoomFlag, RoomKey, RoomNote},
+        scheduler::{Action, PrebuiltMessage, Schedule, Toggle, Trigger},
+        settings::{Settings, SharedSettings},
+        watchlist::{AlertLevel, WatchEntry, WatchTarget},
+        HaxState,
+    };
+
+    fn configured() -> HaxState {
+        let mut hax = HaxState {
+            settings: SharedSettings::new(Settings {
+                strip_passwords: true,
+                forced_region: (true, "eu".into()),
+                spoofed_name: (false, "not shared".into()),
+                lobby_sort: Some(LobbySort::FavoritesFirst),
+                lobby_variants: VariantSettings { newfps: false },
+                auth_overrides: Some(AuthOverrides {
+                    app_version: Some("1.90.0_1.99".into()),
+                    user_id: Some("secret".into()),
+                    ..Default::default()
+                }),
+                muted_actors: [3].into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        hax.set_stealth_host(true);
+        hax.property_firewall
+            .actor_blocklist
+            .insert(PhotonDataType::Byte(255));
+        hax.property_firewall
+            .game_blocklist
+            .insert(PhotonDataType::String("password".into()));
+        hax.set_room_note(
+            RoomKey::RoomName("Pros".into()),
+            RoomNote {
+                flag: Some(RoomFlag::Favorite),
+                note: "good".into(),
+            },
+        )
+        .unwrap();
+        hax.add_watch_entry(WatchEntry {
+            target: WatchTarget::Nickname("*hacker*".into()),
+            note: "aimbot".into(),
+            level: AlertLevel::Critical,
+        })
+        .unwrap();
+        hax.set_macro(
+            "hide",
+            vec![Action::SetToggle {
+                toggle: Toggle::StealthHost,
+                enabled: true,
+            }],
+        )
+        .unwrap();
+        hax.add_schedule(Schedule {
+            name: "hide at round start".into(),
+            trigger: Trigger::Phase(MatchPhase::Warmup),
+            action: Action::RunMacro("hide".into()),
+            repeating: true,
+        })
+        .unwrap();
+        hax
+    }
+
+    #[test]
+    fn round_trips() {
+        let hax = configured();
+        let profile = hax.export_profile();
+        let json = profile.to_json();
+        assert!(!json.contains("secret"));
+        assert!(!json.contains("not shared"));
+        assert_eq!(Profile::from_json(&json).unwrap(), profile);
+
+        let mut other = HaxState::default();
+        assert!(other.apply_profile(profile.clone()).unwrap().is_empty());
+        assert_eq!(other.export_profile(), profile);
+        assert_eq!(other.settings().forced_region, (true, "eu".into()));
+        assert!(other.stealth_host());
+        assert!(other
+            .property_firewall
+            .actor_blocklist
+            .contains(&PhotonDataType::Byte(255)));
+        // session state isn't part of a profile
+        assert!(other.settings().muted_actors.is_empty());
+        assert_eq!(
+            other.settings().auth_overrides.as_ref().unwrap().user_id,
+            None
+        );
+
+        assert_eq!(HaxState::default().export_profile(), Profile::default());
+    }
+
+    #[test]
+    fn pause_survives_save_and_load() {
+        let mut hax = configured();
+        let resumed = hax.export_profile();
+        hax.set_global_pause(true);
+
+        let profile = hax.export_profile();
+        assert!(profile.paused);
+        assert_eq!(
+            Profile {
+                paused: false,
+                ..profile.clone()
+            },
+            resumed
+        );
+
+        let mut other = HaxState::default();
+        other
+            .apply_profile(Profile::from_json(&profile.to_json()).unwrap())
+            .unwrap();
+        assert!(other.global_pause());
+        assert!(!other.settings().strip_passwords);
+        assert_eq!(other.export_profile(), profile);
+
+        // applied while paused, a profile replaces the settings to resume to
+        other
+            .apply_profile(Profile {
+                strip_passwords: false,
+                paused: false,
+                ..profile
+            })
+            .unwrap();
+        assert!(other.global_pause());
+        other.set_global_pause(false);
+        assert!(!other.settings().strip_passwords);
+        assert_eq!(other.settings().forced_region, (true, "eu".into()));
+    }
+
+    #[test]
+    fn keeps_secrets_and_data_on_reset() {
+        let mut hax = configured();
+        hax.reset_to_defaults();
+
+        let profile = hax.export_profile();
+        assert!(!profile.strip_passwords);
+        assert!(profile.rewrite_newfps_rooms);
+        assert_eq!(profile.lobby_sort, None);
+        assert!(!hax.stealth_host());
+        assert_eq!(hax.watchlist().entries().len(), 1);
+        assert_eq!(hax.room_notes().count(), 1);
+        // the user id isn't in profiles, so resetting leaves it alone
+        let settings = hax.settings();
+        let overrides = settings.auth_overrides.as_ref().unwrap();
+        assert_eq!(overrides.app_version, None);
+        assert_eq!(overrides.user_id.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        let mut hax = configured();
+        let before = hax.export_profile();
+        let profile = Profile {
+            strip_passwords: false,
+            lobby_sort: Some("ping".into()),
+            auth_app_version: Some("1.90.0".into()),
+            watchlist: vec![WatchEntry {
+                target: WatchTarget::UserId(" ".into()),
+                note: String::new(),
+                level: AlertLevel::Info,
+            }],
+            schedules: vec![Schedule {
+                name: "greet".into(),
+                trigger: Trigger::After { secs: 5 },
+                action: Action::Inject(PrebuiltMessage::RenameRoom("".into())),
+                repeating: false,
+            }],
+            ..Profile::default()
+        };
+
+        let invalid = match hax.apply_profile(profile) {
+            Err(ProfileError::Invalid(invalid)) => invalid,
+            other => panic!("expected invalid entries, got {other:?}"),
+        };
+        assert_eq!(
+            invalid.iter().map(|e| e.field.as_str()).collect::<Vec<_>>(),
+            [
+                "lobby_sort",
+                "auth_app_version",
+                "watchlist[0]",
+                "schedules[0]"
+            ]
+        );
+        assert_eq!(
+            invalid[0].to_string(),
+            "lobby_sort unknown order \"ping\", expected one of player count, name, map, favorites first"
+        );
+        // nothing was applied
+        assert_eq!(hax.export_profile(), before);
+    }
+
+    #[test]
+    fn ignores_unknown_fields() {
+        let json = r#"{
+            "schema_version": 1,
+            "strip_passwords": true,
+            "auto_aim": { "fov": 30 }
+        }"#;
+        let profile = Profile::from_json(json).unwrap();
+        assert_eq!(profile.unknown_fields().collect::<Vec<_>>(), ["auto_aim"]);
+
+        let mut hax = HaxState::default();
+        let warnings = hax.apply_profile(profile).unwrap();
+        assert_eq!(
+            warnings,
+            ["ignored unknown field \"auto_aim\", the profile may be from a newer version"]
+        );
+        assert!(hax.settings().strip_passwords);
+        // unknown fields aren't written back
+        assert!(!hax.export_profile().to_json().contains("auto_aim"));
+    }
+
+    #[test]
+    fn saves_named_profiles() {
+        let dir = std::env::temp_dir().join(format!("bfhax-profiles-{}", std::process::id()));
+        let mut hax = configured();
+        hax.load_profiles(&dir);
+        hax.save_profile("comp night").unwrap();
+        assert_eq!(hax.profiles().names(), ["comp night"]);
+
+        let mut other = HaxState::default();
+        other.load_profiles(&dir);
+        other.load_profile("comp night").unwrap();
+        assert_eq!(other.export_profile(), hax.export_profile());
+
+        assert!(matches!(
+            other.load_profile("missing"),
+            Err(ProfileError::NotFound(_))
+        ));
+        match hax.save_profile("../escape") {
+            Err(ProfileError::Invalid(invalid)) => assert_eq!(
+                invalid,
+                [InvalidEntry {
+                    field: "name".into(),
+                    reason: "\"../escape\" may only have letters, digits, spaces, dashes and underscores"
+                        .into()
+                }]
+            ),
+            other => panic!("expected an invalid name, got {other:?}"),
+        }
+        assert!(ProfileStore::new(&dir).delete("comp night").unwrap());
+        assert!(other.profiles().names().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+//! Tracking of short-lived projectiles such as grenades and rockets.
+
+use std::time::{Duration, Instant};
+
+use photon_lib::{
+    highlevel::structs::{InstantiationEventData, SerializedData, ViewId},
+    indexmap::IndexMap,
+    photon_data_type::{CustomData, PhotonDataType},
+    primitives::Vector3,
+};
+use tracing::{debug, trace};
+
+#[derive(Debug, Clone)]
+pub struct ProjectileSettings {
+    /// Prefabs whose name contains any of these are considered projectiles.
+    pub prefab_names: Vec<String>,
+    /// How long a projectile is kept around if we never see it get destroyed.
+    pub ttl: Duration,
+}
+
+impl Default for ProjectileSettings {
+    fn default() -> Self {
+        Self {
+            prefab_names: vec!["Grenade".into(), "Rocket".into(), "Projectile".into()],
+            ttl: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ProjectileSettings {
+    pub fn is_projectile(&self, prefab_name: &str) -> bool {
+        self.prefab_names
+            .iter()
+            .any(|name| !name.is_empty() && prefab_name.contains(name.as_str()))
+    }
+}
+
+/// A projectile that is currently in flight.
+#[derive(Debug, Clone)]
+pub struct Projectile {
+    pub view_id: ViewId,
+    /// The actor that threw or fired this projectile.
+    pub owner_actor: i32,
+    pub prefab: String,
+    /// The last known position, either from the instantiation or from the last serialize update.
+    pub position: Option<Vector3>,
+    pub spawned_at: Instant,
+    pub last_update: Instant,
+}
+
+impl Projectile {
+    pub fn age(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.spawned_at)
+    }
+}
+
+/// Keeps track of the projectiles in a game.
+#[derive(Debug, Default)]
+pub struct ProjectileTracker {
+    /// Keyed by view id.
+    projectiles: IndexMap<i32, Projectile>,
+}
+
+impl ProjectileTracker {
+    pub fn len(&self) -> usize {
+        self.projectiles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.projectiles.is_empty()
+    }
+
+    /// Starts tracking an instantiated object if it is a projectile. Returns whether it was.
+    pub fn on_instantiation(
+        &mut self,
+        settings: &ProjectileSettings,
+        event_data: &InstantiationEventData,
+        now: Instant,
+    ) -> bool {
+        if !settings.is_projectile(&event_data.prefab_name) {
+            return false;
+        }
+
+        self.expire(settings.ttl, now);
+
+        let view_id = event_data.get_view_id();
+        let position = match &event_data.position {
+            Some(CustomData::Vector3(v)) => Some(v.clone()),
+            _ => None,
+        };
+        debug!(
+            view_id = view_id.0,
+            prefab = event_data.prefab_name,
+            "Tracking projectile"
+        );
+
+        self.projectiles.insert(
+            view_id.0,
+            Projectile {
+                view_id,
+                owner_actor: view_id.get_owner_id(),
+                prefab: event_data.prefab_name.clone(),
+                position,
+                spawned_at: now,
+                last_update: now,
+            },
+        );
+        true
+    }
+
+    /// Updates the position of a projectile from its serialized data stream. Returns whether the view was a projectile.
+    ///
+    /// Projectiles sync their transform, so the first Vector3 in the stream is taken as the position.
+    pub fn on_serialize(&mut self, data: &SerializedData, now: Instant) -> bool {
+        let projectile = match self.projectiles.get_mut(&data.view_id) {
+            Some(p) => p,
+            None => return false,
+        };
+
+        let position = data.data_stream.iter().find_map(|d| match d {
+            PhotonDataType::Custom(CustomData::Vector3(v)) => Some(v.clone()),
+            _ => None,
+        });
+        if let Some(position) = position {
+            trace!(
+                view_id = data.view_id,
+                position = format!("{position:?}"),
+                "Projectile moved"
+            );
+            projectile.position = Some(position);
+        }
+        projectile.last_update = now;
+        true
+    }
+
+    /// Stops tracking a destroyed view. Returns whether it was a projectile.
+    pub fn on_destroy(&mut self, view_id: ViewId) -> bool {
+        let removed = self.projectiles.shift_remove(&view_id.0).is_some();
+        if removed {
+            debug!(view_id = view_id.0, "Projectile destroyed");
+        }
+        removed
+    }
+
+    /// Forgets projectiles that are older than the given time to live.
+    pub fn expire(&mut self, ttl: Duration, now: Instant) {
+        self.projectiles.retain(|_, p| p.age(now) <= ttl);
+    }
+
+    /// Iterates over the projectiles that are younger than the given time to live.
+    pub fn active(&self, ttl: Duration, now: Instant) -> impl Iterator<Item = &Projectile> {
+        self.projectiles.values().filter(move |p| p.age(now) <= ttl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use photon_lib::{
+        highlevel::{
+            constants::{parameter_code, pun_event_code},
+            structs::{
+                DestroyEvent, DestroyEventData, InstantiationEvent, InstantiationEventData,
+                SendSerializeEvent, ViewId,
+            },
+            PhotonMapConversion, PhotonParameterMapConversion,
+        },
+        indexmap::indexmap,
+        photon_data_type::{CustomData, PhotonDataType},
+        photon_message::{EventData, PhotonMessage},
+        primitives::Vector3,
+    };
+
+    use super::{ProjectileSettings, ProjectileTracker};
+
+    const GRENADE_VIEW_ID: i32 = 3007;
+
+    fn vector(x: f32, y: f32, z: f32) -> Vector3 {
+        Vector3(x.into(), y.into(), z.into())
+    }
+
+    fn event(code: u8, sender: i32, data: photon_lib::PhotonHashmap) -> Vec<u8> {
+        let mut buf = vec![];
+        PhotonMessage::EventData(EventData {
+            code,
+            parameters: indexmap! {
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(sender),
+                parameter_code::DATA => PhotonDataType::Hashtable(data),
+            },
+        })
+        .to_websocket_bytes(&mut buf)
+        .unwrap();
+        buf
+    }
+
+    /// A grenade thrown by actor 3: it gets instantiated, moves twice and then explodes.
+    fn grenade_throw() -> Vec<Vec<u8>> {
+        let serialize = |x: f32| {
+            event(
+                pun_event_code::SEND_SERIALIZE,
+                3,
+                indexmap! {
+                    PhotonDataType::Byte(0) => PhotonDataType::Integer(12345),
+                    PhotonDataType::Byte(1) => PhotonDataType::Short(0),
+                    PhotonDataType::Byte(10) => PhotonDataType::ObjectArray(vec![
+                        PhotonDataType::Integer(GRENADE_VIEW_ID),
+                        PhotonDataType::Boolean(false),
+                        PhotonDataType::Null,
+                        PhotonDataType::Custom(CustomData::Vector3(vector(x, 2.0, 0.0))),
+                    ]),
+                },
+            )
+        };
+
+        vec![
+            event(
+                pun_event_code::INSTANTIATION,
+                3,
+                indexmap! {
+                    PhotonDataType::Byte(0) => PhotonDataType::String("FragGrenade".into()),
+                    PhotonDataType::Byte(1) => PhotonDataType::Custom(CustomData::Vector3(vector(0.0, 2.0, 0.0))),
+                    PhotonDataType::Byte(6) => PhotonDataType::Integer(12000),
+                    PhotonDataType::Byte(7) => PhotonDataType::Integer(GRENADE_VIEW_ID),
+                },
+            ),
+            serialize(1.0),
+            serialize(2.0),
+            event(
+                pun_event_code::DESTROY,
+                3,
+                indexmap! {
+                    PhotonDataType::Byte(0) => PhotonDataType::Integer(GRENADE_VIEW_ID),
+                },
+            ),
+        ]
+    }
+
+    /// Feeds a message to the tracker, the same way the game server hook does.
+    fn replay(tracker: &mut ProjectileTracker, bytes: &[u8], now: Instant) {
+        let settings = ProjectileSettings::default();
+        let mut event = match PhotonMessage::from_websocket_bytes(&mut &bytes[..]).unwrap() {
+            PhotonMessage::EventData(e) => e,
+            m => panic!("unexpected message {m:?}"),
+        };
+
+        match event.code {
+            pun_event_code::INSTANTIATION => {
+                let mut event = InstantiationEvent::from_map(&mut event.parameters).unwrap();
+                let data = InstantiationEventData::from_map(&mut event.data).unwrap();
+                assert!(tracker.on_instantiation(&settings, &data, now));
+            }
+            pun_event_code::SEND_SERIALIZE => {
+                let event = SendSerializeEvent::from_map(&mut event.parameters).unwrap();
+                for obj in event.get_serialized_data().unwrap() {
+                    assert!(tracker.on_serialize(&obj, now));
+                }
+            }
+            pun_event_code::DESTROY => {
+                let mut event = DestroyEvent::from_map(&mut event.parameters).unwrap();
+                let data = DestroyEventData::from_map(&mut event.data).unwrap();
+                assert!(tracker.on_destroy(data.get_view_id()));
+            }
+            c => panic!("unexpected event code {c}"),
+        }
+    }
+
+    #[test]
+    fn grenade_lifecycle() {
+        let settings = ProjectileSettings::default();
+        let start = Instant::now();
+        let mut tracker = ProjectileTracker::default();
+        let messages = grenade_throw();
+
+        replay(&mut tracker, &messages[0], start);
+        let active = tracker.active(settings.ttl, start).collect::<Vec<_>>();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].view_id, ViewId(GRENADE_VIEW_ID));
+        assert_eq!(active[0].owner_actor, 3);
+        assert_eq!(active[0].prefab, "FragGrenade");
+        assert_eq!(active[0].position, Some(vector(0.0, 2.0, 0.0)));
+
+        replay(
+            &mut tracker,
+            &messages[1],
+            start + Duration::from_millis(100),
+        );
+        replay(
+            &mut tracker,
+            &messages[2],
+            start + Duration::from_millis(200),
+        );
+        let now = start + Duration::from_millis(250);
+        let active = tracker.active(settings.ttl, now).collect::<Vec<_>>();
+        assert_eq!(active[0].position, Some(vector(2.0, 2.0, 0.0)));
+        assert_eq!(active[0].age(now), Duration::from_millis(250));
+
+        replay(&mut tracker, &messages[3], start + Duration::from_secs(3));
+        assert_eq!(tracker.active(settings.ttl, start).count(), 0);
+    }
+
+    #[test]
+    fn projectile_expires_without_destroy() {
+        let settings = ProjectileSettings::default();
+        let start = Instant::now();
+        let mut tracker = ProjectileTracker::default();
+
+        replay(&mut tracker, &grenade_throw()[0], start);
+        assert_eq!(tracker.active(settings.ttl, start).count(), 1);
+        assert_eq!(
+            tracker
+                .active(settings.ttl, start + settings.ttl + Duration::from_secs(1))
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn ignores_other_prefabs() {
+        let settings = ProjectileSettings::default();
+        assert!(settings.is_projectile("FragGrenade"));
+        assert!(settings.is_projectile("RPGRocket"));
+        assert!(!settings.is_projectile("PlayerBody"));
+        assert!(!settings.is_projectile("Match Manager"));
+    }
+}
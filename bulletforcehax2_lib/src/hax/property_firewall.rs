@@ -0,0 +1,218 @@
+//! Stops the client from sending chosen actor and room properties.
+
+use std::{collections::VecDeque, fmt::Display, time::SystemTime};
+
+use photon_lib::{
+    highlevel::structs::SetPropertiesOperationRequest, indexmap::IndexSet,
+    photon_data_type::PhotonDataType,
+};
+use tracing::info;
+
+/// How many stripped properties to keep.
+const MAX_STRIPPED: usize = 100;
+
+/// What a SET_PROPERTIES request was updating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PropertyTarget {
+    /// The properties of the actor with this actor number.
+    Actor(i32),
+    /// The properties of the room.
+    Game,
+}
+
+impl Display for PropertyTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PropertyTarget::Actor(actor_nr) => write!(f, "actor {actor_nr}"),
+            PropertyTarget::Game => write!(f, "room"),
+        }
+    }
+}
+
+/// A property that the client tried to send, but was removed from the request.
+#[derive(Debug, Clone)]
+pub struct StrippedProperty {
+    pub timestamp: SystemTime,
+    pub target: PropertyTarget,
+    pub key: PhotonDataType,
+    pub value: PhotonDataType,
+}
+
+#[derive(Debug, Default)]
+pub struct PropertyFirewall {
+    /// Keys that are removed from outgoing actor properties.
+    pub actor_blocklist: IndexSet<PhotonDataType>,
+    /// Keys that are removed from outgoing room properties.
+    pub game_blocklist: IndexSet<PhotonDataType>,
+    stripped: VecDeque<StrippedProperty>,
+}
+
+impl PropertyFirewall {
+    /// Removes the blocked properties from an outgoing request and remembers them. Returns how many were removed.
+    pub fn filter(
+        &mut self,
+        request: &mut SetPropertiesOperationRequest,
+        now: SystemTime,
+    ) -> usize {
+        let (target, blocklist) = match request.actor_nr {
+            Some(actor_nr) => (PropertyTarget::Actor(actor_nr), &self.actor_blocklist),
+            None => (PropertyTarget::Game, &self.game_blocklist),
+        };
+        if blocklist.is_empty() {
+            return 0;
+        }
+
+        let blocked = request
+            .properties
+            .keys()
+            .filter(|key| blocklist.contains(*key))
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in &blocked {
+            let value = request
+                .properties
+                .shift_remove(key)
+                .expect("blocked key was just found");
+            info!(
+                target = target.to_string(),
+                key = format!("{key:?}"),
+                value = format!("{value:?}"),
+                "Stripped outgoing property"
+            );
+
+            if self.stripped.len() >= MAX_STRIPPED {
+                self.stripped.pop_front();
+            }
+            self.stripped.push_back(StrippedProperty {
+                timestamp: now,
+                target,
+                key: key.clone(),
+                value,
+            });
+        }
+
+        blocked.len()
+    }
+
+    /// The most recently stripped properties, oldest first.
+    pub fn stripped(
+        &self,
+    ) -> impl DoubleEndedIterator<Item = &StrippedProperty> + ExactSizeIterator {
+        self.stripped.iter()
+    }
+}
+
+/// Parses a comma-separated list of property keys. Numbers are byte keys, which Photon uses for its well-known
+/// properties, anything else is a string key.
+pub fn parse_keys(s: &str) -> IndexSet<PhotonDataType> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(parse_key)
+        .collect()
+}
+
+/// Parses a single property key, see [parse_keys].
+pub fn parse_key(key: &str) -> PhotonDataType {
+    match key.parse::<u8>() {
+        Ok(byte) => PhotonDataType::Byte(byte),
+        Err(_) => PhotonDataType::String(key.to_string()),
+    }
+}
+
+/// Formats property keys the way [parse_keys] reads them.
+pub fn format_keys(keys: &IndexSet<PhotonDataType>) -> String {
+    keys.iter().map(format_key).collect::<Vec<_>>().join(", ")
+}
+
+/// Formats a single property key the way [parse_key] reads it.
+pub fn format_key(key: &PhotonDataType) -> String {
+    match key {
+        PhotonDataType::Byte(byte) => byte.to_string(),
+        PhotonDataType::String(s) => s.clone(),
+        other => format!("{other:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use photon_lib::{
+        highlevel::structs::SetPropertiesOperationRequest, indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+    };
+
+    use super::{format_keys, parse_keys, PropertyFirewall, PropertyTarget};
+
+    fn request(actor_nr: Option<i32>) -> SetPropertiesOperationRequest {
+        SetPropertiesOperationRequest {
+            properties: indexmap! {
+                PhotonDataType::Byte(255) => PhotonDataType::String("name".into()),
+                PhotonDataType::String("platform".into()) => PhotonDataType::String("WebGLPlayer".into()),
+                PhotonDataType::String("teamNumber".into()) => PhotonDataType::Byte(1),
+            },
+            actor_nr,
+            broadcast: true,
+            expected_values: None,
+            event_forward: None,
+        }
+    }
+
+    #[test]
+    fn strips_blocked_keys() {
+        let mut firewall = PropertyFirewall {
+            actor_blocklist: parse_keys("platform, teamNumber"),
+            ..Default::default()
+        };
+
+        let mut req = request(Some(3));
+        assert_eq!(firewall.filter(&mut req, SystemTime::now()), 2);
+        assert_eq!(
+            req.properties.keys().collect::<Vec<_>>(),
+            [&PhotonDataType::Byte(255)]
+        );
+
+        let stripped = firewall.stripped().collect::<Vec<_>>();
+        assert_eq!(stripped.len(), 2);
+        assert_eq!(stripped[0].target, PropertyTarget::Actor(3));
+        assert_eq!(
+            stripped[0].value,
+            PhotonDataType::String("WebGLPlayer".into())
+        );
+
+        // room properties have their own blocklist
+        let mut req = request(None);
+        assert_eq!(firewall.filter(&mut req, SystemTime::now()), 0);
+        assert_eq!(req.properties.len(), 3);
+    }
+
+    #[test]
+    fn strips_all_keys() {
+        let mut firewall = PropertyFirewall {
+            game_blocklist: parse_keys("255,platform,teamNumber"),
+            ..Default::default()
+        };
+
+        let mut req = request(None);
+        assert_eq!(firewall.filter(&mut req, SystemTime::now()), 3);
+        assert!(req.properties.is_empty());
+        assert!(firewall
+            .stripped()
+            .all(|p| p.target == PropertyTarget::Game));
+    }
+
+    #[test]
+    fn key_list_round_trip() {
+        let keys = parse_keys(" 255, platform,,deviceModel ");
+        assert_eq!(
+            keys.iter().collect::<Vec<_>>(),
+            [
+                &PhotonDataType::Byte(255),
+                &PhotonDataType::String("platform".into()),
+                &PhotonDataType::String("deviceModel".into()),
+            ]
+        );
+        assert_eq!(format_keys(&keys), "255, platform, deviceModel");
+    }
+}
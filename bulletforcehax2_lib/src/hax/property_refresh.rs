@@ -0,0 +1,413 @@
+//! Asking the game server for the properties of every player, when the mirrored ones may be stale.
+//!
+//! The players' properties are mirrored from the join response and the PROPERTIES_CHANGED events after it. If one of
+//! those was missed, eg. while [parsing was skipped](super::parse_breaker), a player keeps the old values until they
+//! change the property again. [HaxState::refresh_actor_properties](super::HaxState::refresh_actor_properties) injects
+//! a GET_PROPERTIES request for all players and merges the server's answer into them, marking each with
+//! [PlayerActor::properties_refreshed](super::PlayerActor::properties_refreshed).
+//!
+//! Like SET_PROPERTIES responses (see [room_rename](super::room_rename)), GET_PROPERTIES responses don't say which
+//! request they answer but come in the order the requests were sent. [PendingRefreshes] keeps that order, so the
+//! answer to a refresh can be told apart from the answers to the client's own requests. The client never asked for the
+//! refresh, so its answer is dropped. If the server refuses a refresh, no more are sent until the next room.
+
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use photon_lib::{
+    highlevel::{
+        constants::{operation_code, property_type_flag},
+        structs::GetPropertiesRequest,
+        PhotonParameterMapConversion,
+    },
+    indexmap::IndexMap,
+    photon_message::OperationRequest,
+};
+use thiserror::Error;
+
+use crate::error::HaxError;
+
+/// How many requests can wait for a response. The server answers every request, more are only outstanding if
+/// responses got lost.
+const MAX_PENDING: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum RefreshError {
+    #[error("not in a room")]
+    NotInRoom,
+    #[error("the last refresh wasn't answered yet")]
+    InProgress,
+    #[error("the server refused to send the properties ({0})")]
+    Refused(Refusal),
+    #[error(transparent)]
+    Injection(#[from] HaxError),
+}
+
+/// How the server refused a refresh.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refusal {
+    pub return_code: i16,
+    pub debug_message: Option<String>,
+}
+
+impl Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "return code {}", self.return_code)?;
+        if let Some(debug_message) = &self.debug_message {
+            write!(f, ": {debug_message}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The GET_PROPERTIES request for the actor properties of the given actors.
+pub fn refresh_request(actors: Vec<i32>) -> OperationRequest {
+    let mut parameters = IndexMap::new();
+    GetPropertiesRequest {
+        properties_type: property_type_flag::ACTOR,
+        actor_list: Some(actors),
+    }
+    .into_map(&mut parameters);
+    OperationRequest {
+        operation_code: operation_code::GET_PROPERTIES,
+        parameters,
+    }
+}
+
+/// Who is waiting for the response to a GET_PROPERTIES request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefreshResponse {
+    /// The client.
+    Forward,
+    /// A refresh of the given actors, sent at the given time.
+    Refresh { actors: Vec<i32>, sent: Instant },
+}
+
+/// The GET_PROPERTIES requests sent to the game server that weren't answered yet, see [property_refresh](self).
+#[derive(Debug, Clone, Default)]
+pub struct PendingRefreshes {
+    pending: VecDeque<RefreshResponse>,
+    /// Set once the server refused a refresh.
+    refused: Option<Refusal>,
+}
+
+impl PendingRefreshes {
+    /// Notes a request of the client, whose response goes to it.
+    pub fn forwarded(&mut self) {
+        self.push(RefreshResponse::Forward);
+    }
+
+    /// Notes a refresh of the given actors.
+    pub fn refreshed(&mut self, actors: Vec<i32>, now: Instant) {
+        self.push(RefreshResponse::Refresh { actors, sent: now });
+    }
+
+    fn push(&mut self, response: RefreshResponse) {
+        if self.pending.len() == MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(response);
+    }
+
+    /// Takes the oldest request, when its response arrives. Responses nobody waits for are forwarded.
+    pub fn respond(&mut self) -> RefreshResponse {
+        self.pending.pop_front().unwrap_or(RefreshResponse::Forward)
+    }
+
+    /// Keeps the server from being asked again after it refused a refresh.
+    pub fn refused(&mut self, refusal: Refusal) {
+        self.refused = Some(refusal);
+    }
+
+    /// Fails if the server refused a refresh before, or if one is still waiting for its response.
+    pub fn check(&self) -> Result<(), RefreshError> {
+        if let Some(refusal) = &self.refused {
+            return Err(RefreshError::Refused(refusal.clone()));
+        }
+        let waiting = self
+            .pending
+            .iter()
+            .any(|r| matches!(r, RefreshResponse::Refresh { .. }));
+        match waiting {
+            true => Err(RefreshError::InProgress),
+            false => Ok(()),
+        }
+    }
+}
+
+/// What a refresh changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefreshOutcome {
+    /// The actors whose properties were merged.
+    pub updated: Vec<i32>,
+    /// The actors that were asked for but left out of the response, eg. because they left the room meanwhile.
+    pub missing: Vec<i32>,
+    /// How long the server took to answer.
+    pub round_trip: Duration,
+}
+
+impl Display for RefreshOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "refreshed {} players in {} ms",
+            self.updated.len(),
+            self.round_trip.as_millis()
+        )?;
+        if !self.missing.is_empty() {
+            write!(f, ", {} missing", self.missing.len())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::lock::Mutex;
+    use photon_lib::{
+        highlevel::constants::{actor_properties, error_code, operation_code, parameter_code},
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationRequest, OperationResponse, PhotonMessage},
+        PhotonHashmap,
+    };
+
+    use super::{refresh_request, RefreshError, RefreshOutcome};
+    use crate::{
+        hax::{bandwidth::feature, events::HaxEvent, HaxState},
+        proxy::WebSocketServer,
+        testsupport::{string, ProxiedConnection},
+    };
+
+    fn named(nickname: &str) -> PhotonDataType {
+        PhotonDataType::Hashtable(indexmap! {
+            PhotonDataType::Byte(actor_properties::PLAYER_NAME) => string(nickname),
+        })
+    }
+
+    /// An event the hooks don't handle, to check that everything sent before it was delivered or dropped.
+    fn marker() -> PhotonMessage {
+        PhotonMessage::EventData(EventData {
+            code: 123,
+            parameters: indexmap! {},
+        })
+    }
+
+    fn properties_response(return_code: i16, players: PhotonHashmap) -> PhotonMessage {
+        PhotonMessage::OperationResponse(OperationResponse {
+            operation_code: operation_code::GET_PROPERTIES,
+            return_code,
+            debug_message: (return_code != 0).then(|| "not allowed".into()),
+            parameters: match return_code {
+                0 => {
+                    indexmap! { parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(players) }
+                }
+                _ => indexmap! {},
+            },
+        })
+    }
+
+    /// The client's own request for the room's properties.
+    fn own_request() -> PhotonMessage {
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: operation_code::GET_PROPERTIES,
+            parameters: indexmap! { parameter_code::PROPERTIES => PhotonDataType::Byte(1) },
+        })
+    }
+
+    /// Connects to the game server and joins a room with the given players, as actor 1.
+    async fn join(state: &Arc<Mutex<HaxState>>, players: PhotonHashmap) -> ProxiedConnection {
+        let mut conn = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        conn.server
+            .send(PhotonMessage::OperationResponse(OperationResponse {
+                operation_code: operation_code::JOIN_GAME,
+                return_code: 0,
+                debug_message: None,
+                parameters: indexmap! {
+                    parameter_code::ROOM_NAME => string("room"),
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                    parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(players),
+                    parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                },
+            }));
+        conn.client_recv().await;
+        conn
+    }
+
+    #[tokio::test]
+    async fn refreshes_round_trip_in_order() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut events = state.lock().await.events.subscribe();
+        let mut conn = join(
+            &state,
+            indexmap! {
+                PhotonDataType::Integer(1) => named("me"),
+                PhotonDataType::Integer(2) => named("them"),
+            },
+        )
+        .await;
+
+        let actors = state.lock().await.refresh_actor_properties().unwrap();
+        assert_eq!(actors, [1, 2]);
+        assert_eq!(
+            conn.server.recv().await,
+            PhotonMessage::OperationRequest(refresh_request(vec![1, 2]))
+        );
+
+        // the client asks for properties of its own before the server answers
+        conn.client_send(own_request()).await;
+        assert_eq!(conn.server.recv().await, own_request());
+
+        // the first answer is to the refresh, which the client never asked for
+        conn.server.send_all([
+            properties_response(0, indexmap! { PhotonDataType::Integer(2) => named("them") }),
+            properties_response(0, indexmap! {}),
+            marker(),
+        ]);
+        assert_eq!(
+            conn.client_recv().await,
+            properties_response(0, indexmap! {})
+        );
+        assert_eq!(conn.client_recv().await, marker());
+        let outcome = loop {
+            if let HaxEvent::ActorPropertiesRefreshed(outcome) = events.recv().await.unwrap() {
+                break outcome;
+            }
+        };
+        assert_eq!(outcome.updated, [2]);
+
+        let mut hax = state.lock().await;
+        let dropped = hax
+            .drop_log
+            .entries()
+            .map(|d| d.reason.feature())
+            .collect::<Vec<_>>();
+        assert_eq!(dropped, [feature::PROPERTY_REFRESH]);
+        // answered, so the next one can go out
+        assert_eq!(hax.refresh_actor_properties().unwrap(), [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn refreshed_properties_are_merged() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut events = state.lock().await.events.subscribe();
+        let mut conn = join(
+            &state,
+            indexmap! {
+                PhotonDataType::Integer(1) => named("me"),
+                PhotonDataType::Integer(2) => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::Byte(actor_properties::PLAYER_NAME) => string("old name"),
+                    string("teamNumber") => PhotonDataType::Byte(1),
+                }),
+            },
+        )
+        .await;
+
+        state.lock().await.refresh_actor_properties().unwrap();
+        conn.server.recv().await;
+        // we left actor 1 out, which also counts as a partial answer
+        conn.server.send(properties_response(
+            0,
+            indexmap! {
+                PhotonDataType::Integer(2) => named("new name"),
+                PhotonDataType::Integer(3) => named("missed join"),
+            },
+        ));
+
+        // renames are announced before the refresh itself
+        let mut renamed = vec![];
+        let outcome = loop {
+            match events.recv().await.unwrap() {
+                HaxEvent::PlayerRenamed { actor_id, old, new } => {
+                    renamed.push((actor_id, old, new))
+                }
+                HaxEvent::ActorPropertiesRefreshed(outcome) => break outcome,
+                _ => (),
+            }
+        };
+        assert_eq!(renamed, [(2, "old name".into(), "new name".into())]);
+        assert!(matches!(
+            outcome,
+            RefreshOutcome { updated, missing, .. } if updated == [2, 3] && missing == [1]
+        ));
+        let hax = state.lock().await;
+        let players = &hax.gameplay_state.as_ref().unwrap().1.players;
+        assert_eq!(players[&2].nickname.as_deref(), Some("new name"));
+        // properties the answer doesn't hold are kept
+        assert_eq!(players[&2].team_number, Some(1));
+        assert_eq!(players[&3].nickname.as_deref(), Some("missed join"));
+        assert!(players[&2].properties_refreshed.is_some());
+        assert!(players[&3].properties_refreshed.is_some());
+        assert!(players[&1].properties_refreshed.is_none());
+    }
+
+    #[tokio::test]
+    async fn refusals_stop_refreshes_until_the_next_room() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        assert!(matches!(
+            state.lock().await.refresh_actor_properties(),
+            Err(RefreshError::NotInRoom)
+        ));
+        let mut conn = join(
+            &state,
+            indexmap! { PhotonDataType::Integer(1) => named("me") },
+        )
+        .await;
+
+        state.lock().await.refresh_actor_properties().unwrap();
+        conn.server.recv().await;
+        assert!(matches!(
+            state.lock().await.refresh_actor_properties(),
+            Err(RefreshError::InProgress)
+        ));
+
+        // a refusal is dropped too, and not tried again
+        conn.server.send_all([
+            properties_response(
+                error_code::OPERATION_NOT_ALLOWED_IN_CURRENT_STATE,
+                indexmap! {},
+            ),
+            marker(),
+        ]);
+        assert_eq!(conn.client_recv().await, marker());
+        {
+            let mut hax = state.lock().await;
+            assert!(matches!(
+                hax.refresh_actor_properties(),
+                Err(RefreshError::Refused(refusal))
+                    if refusal.return_code == error_code::OPERATION_NOT_ALLOWED_IN_CURRENT_STATE
+            ));
+            assert_eq!(hax.stats.recent_errors.len(), 1);
+            assert_eq!(hax.drop_log.entries().count(), 1);
+        }
+
+        // the refusal was for that room only
+        let leave = || {
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::LEAVE,
+                parameters: indexmap! {},
+            })
+        };
+        conn.client_send(leave()).await;
+        assert_eq!(conn.server.recv().await, leave());
+        assert!(matches!(
+            state.lock().await.refresh_actor_properties(),
+            Err(RefreshError::NotInRoom)
+        ));
+        let mut conn = join(
+            &state,
+            indexmap! { PhotonDataType::Integer(1) => named("me") },
+        )
+        .await;
+        state.lock().await.refresh_actor_properties().unwrap();
+        assert_eq!(
+            conn.server.recv().await,
+            PhotonMessage::OperationRequest(refresh_request(vec![1]))
+        );
+    }
+}
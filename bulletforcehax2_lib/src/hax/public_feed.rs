@@ -0,0 +1,555 @@
+//! A minimal, stable stream of what happens in the game, for external recorders such as tools that sync VODs.
+//!
+//! The [events](super::events) carry everything and change whenever a feature needs them to. This feed only carries
+//! what recorders need, in a format that doesn't change under them: one JSON object per line, written to a unix socket
+//! or, on Windows, a named pipe like `\\.\pipe\bfhax-feed`. The recorder listens on it and BulletForceHaxV2 connects to
+//! it. If the recorder goes away, the feed connects again on the next message, and messages in between are dropped.
+//!
+//! # Format
+//!
+//! Every line is a [FeedEntry]: the [schema version](SCHEMA_VERSION) as `v`, a sequence number `seq`, the
+//! milliseconds since the feed started `t_ms`, and the message's fields with its kind in `type`. `t_ms` comes from a
+//! monotonic clock, so it never goes backwards when the system clock is adjusted. Every connection starts with a
+//! [FeedMessage::Hello] telling when the feed started by the wall clock. `seq` goes up by one with every message,
+//! so a gap means messages were dropped while nobody was connected.
+//!
+//! # Stability
+//!
+//! Within a schema version, what is written for a message never changes. Any change to the written format bumps
+//! [SCHEMA_VERSION], including additions: a new message type, a new field, renaming or removing anything, or changing
+//! a field's type. Recorders should refuse versions they don't know.
+//!
+//! Every version has a snapshot of all its messages in `fixtures/public_feed/v{version}.jsonl`. A test compares the
+//! current format against the snapshot of the current version and fails on any difference, so changing the format
+//! means bumping the version and adding a snapshot for it. Older snapshots are kept as the documentation of older
+//! versions.
+
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use photon_lib::primitives::Vector3;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use super::match_summary::MatchEnd;
+
+/// The version of the format, see [Stability](self#stability).
+pub const SCHEMA_VERSION: u32 = 1;
+/// How often [FeedMessage::Position] is written at most.
+pub const KEYFRAME_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait before connecting again after a connection attempt failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// How long a write may block before the recorder is considered gone.
+#[cfg(unix)]
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A line of the feed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeedEntry {
+    /// The [SCHEMA_VERSION] it was written with.
+    pub v: u32,
+    pub seq: u64,
+    /// Milliseconds since the feed started, see [FeedMessage::Hello].
+    pub t_ms: u64,
+    #[serde(flatten)]
+    pub message: FeedMessage,
+}
+
+/// What happened, written as the `type` of an entry along with its fields.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FeedMessage {
+    /// The first message of every connection.
+    Hello {
+        /// When the feed started, which is where `t_ms` counts from, in milliseconds since the unix epoch.
+        started_at_unix_ms: u64,
+    },
+    /// We joined a room.
+    RoomJoined {
+        room_name: Option<String>,
+        map_name: Option<String>,
+        /// Our actor id in the room.
+        actor_id: i32,
+        /// Everyone in the room when we joined, including us.
+        players: Vec<FeedPlayer>,
+    },
+    /// We left the room we joined.
+    RoomLeft { reason: LeaveReason },
+    /// A player joined the room we're in.
+    PlayerJoined {
+        actor_id: i32,
+        nickname: Option<String>,
+    },
+    /// A player left the room we're in.
+    PlayerLeft { actor_id: i32 },
+    /// A player died, at the hands of the killer if known.
+    Kill { victim: i32, killer: Option<i32> },
+    /// Where our player is, at most every [KEYFRAME_INTERVAL].
+    Position { x: f32, y: f32, z: f32 },
+}
+
+/// A player in [FeedMessage::RoomJoined].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeedPlayer {
+    pub actor_id: i32,
+    pub nickname: Option<String>,
+}
+
+/// Why we left a room, in [FeedMessage::RoomLeft].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaveReason {
+    Left,
+    Disconnected,
+    /// The client was moved to another game server.
+    Migrated,
+}
+
+impl From<MatchEnd> for LeaveReason {
+    fn from(end: MatchEnd) -> Self {
+        match end {
+            MatchEnd::Left => LeaveReason::Left,
+            MatchEnd::Disconnected => LeaveReason::Disconnected,
+            MatchEnd::Migrated => LeaveReason::Migrated,
+        }
+    }
+}
+
+impl FeedMessage {
+    /// The position of our player, as a [FeedMessage::Position].
+    pub fn position(position: &Vector3) -> Self {
+        let (x, y, z) = position.floats();
+        FeedMessage::Position { x, y, z }
+    }
+}
+
+/// The feed, if one was [started](Self::start). Recording to a feed that wasn't started does nothing.
+#[derive(Default)]
+pub struct PublicFeed {
+    writer: Option<Writer>,
+    in_room: bool,
+    last_keyframe: Option<Instant>,
+}
+
+struct Writer {
+    path: PathBuf,
+    entries: mpsc::Sender<(Instant, FeedMessage)>,
+    thread: JoinHandle<()>,
+}
+
+impl PublicFeed {
+    /// Starts writing the feed to the unix socket or named pipe at the given path. Nothing is connected until the
+    /// first message is recorded.
+    pub fn start(path: impl Into<PathBuf>) -> Self {
+        Self::start_with(path.into(), RECONNECT_DELAY)
+    }
+
+    fn start_with(path: PathBuf, reconnect_delay: Duration) -> Self {
+        let (entries, receive) = mpsc::channel();
+        let thread_path = path.clone();
+        let thread = std::thread::Builder::new()
+            .name("public feed writer".into())
+            .spawn(move || run_writer(&thread_path, reconnect_delay, receive))
+            .expect("should be able to start the public feed writer thread");
+        Self {
+            writer: Some(Writer {
+                path,
+                entries,
+                thread,
+            }),
+            in_room: false,
+            last_keyframe: None,
+        }
+    }
+
+    /// Where the feed is written to, if it was started.
+    pub fn path(&self) -> Option<&Path> {
+        self.writer.as_ref().map(|w| w.path.as_path())
+    }
+
+    /// Writes a message to the feed. Positions closer together than [KEYFRAME_INTERVAL] and everything that happens
+    /// in a room before we joined it, or after we left it, are skipped.
+    pub fn record(&mut self, message: FeedMessage, now: Instant) {
+        let writer = match &self.writer {
+            Some(writer) => writer,
+            None => return,
+        };
+        match &message {
+            FeedMessage::Hello { .. } => return,
+            FeedMessage::RoomJoined { .. } => {
+                self.in_room = true;
+                self.last_keyframe = None;
+            }
+            _ if !self.in_room => return,
+            FeedMessage::RoomLeft { .. } => self.in_room = false,
+            FeedMessage::Position { .. } => {
+                let due = self
+                    .last_keyframe
+                    .is_none_or(|last| now.saturating_duration_since(last) >= KEYFRAME_INTERVAL);
+                if !due {
+                    return;
+                }
+                self.last_keyframe = Some(now);
+            }
+            _ => (),
+        }
+        if writer.entries.send((now, message)).is_err() {
+            warn!("Public feed writer stopped, nothing is written to the feed anymore");
+            self.writer = None;
+        }
+    }
+}
+
+impl Drop for PublicFeed {
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            // hanging up makes the writer stop
+            drop(writer.entries);
+            _ = writer.thread.join();
+        }
+    }
+}
+
+#[cfg(unix)]
+type Connection = std::os::unix::net::UnixStream;
+#[cfg(not(unix))]
+type Connection = std::fs::File;
+
+#[cfg(unix)]
+fn connect(path: &Path) -> io::Result<Connection> {
+    let stream = std::os::unix::net::UnixStream::connect(path)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Named pipes are opened like files, the timeout is up to the recorder reading them.
+#[cfg(not(unix))]
+fn connect(path: &Path) -> io::Result<Connection> {
+    std::fs::OpenOptions::new().write(true).open(path)
+}
+
+fn run_writer(
+    path: &Path,
+    reconnect_delay: Duration,
+    entries: mpsc::Receiver<(Instant, FeedMessage)>,
+) {
+    let started = Instant::now();
+    let started_at_unix_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut seq = 0;
+    let mut connection = None::<Connection>;
+    let mut last_attempt = None::<Instant>;
+
+    for (at, message) in entries {
+        let t_ms = at.saturating_duration_since(started).as_millis() as u64;
+        // a broken connection is replaced once per message, the recorder may just have restarted
+        for _ in 0..2 {
+            if connection.is_none() {
+                let due = last_attempt.is_none_or(|last| last.elapsed() >= reconnect_delay);
+                if !due {
+                    break;
+                }
+                last_attempt = Some(Instant::now());
+                connection = connect(path)
+                    .and_then(|mut c| {
+                        let hello = FeedMessage::Hello { started_at_unix_ms };
+                        write_entry(&mut c, seq, t_ms, &hello)?;
+                        Ok(c)
+                    })
+                    .map_err(|e| {
+                        debug!(
+                            path = format!("{}", path.display()),
+                            "Could not connect to the public feed: {e}"
+                        )
+                    })
+                    .ok();
+                match &connection {
+                    Some(_) => {
+                        debug!(
+                            path = format!("{}", path.display()),
+                            "Connected to the public feed"
+                        );
+                        seq += 1;
+                    }
+                    None => break,
+                }
+            }
+            if let Some(c) = &mut connection {
+                match write_entry(c, seq, t_ms, &message) {
+                    Ok(()) => break,
+                    Err(e) => {
+                        debug!(
+                            path = format!("{}", path.display()),
+                            "Public feed disconnected: {e}"
+                        );
+                        connection = None;
+                        last_attempt = None;
+                    }
+                }
+            }
+        }
+        // written or dropped, either way the next message gets the next number
+        seq += 1;
+    }
+}
+
+fn write_entry(
+    connection: &mut Connection,
+    seq: u64,
+    t_ms: u64,
+    message: &FeedMessage,
+) -> io::Result<()> {
+    let entry = FeedEntry {
+        v: SCHEMA_VERSION,
+        seq,
+        t_ms,
+        message: message.clone(),
+    };
+    let mut line = serde_json::to_vec(&entry)?;
+    line.push(b'\n');
+    connection.write_all(&line)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        FeedEntry, FeedMessage, FeedPlayer, LeaveReason, PublicFeed, Writer, SCHEMA_VERSION,
+    };
+
+    /// Every kind of message, so the snapshot covers all of them.
+    fn samples() -> Vec<FeedMessage> {
+        let samples = vec![
+            FeedMessage::Hello {
+                started_at_unix_ms: 1_665_000_000_000,
+            },
+            FeedMessage::RoomJoined {
+                room_name: Some("room".into()),
+                map_name: None,
+                actor_id: 2,
+                players: vec![
+                    FeedPlayer {
+                        actor_id: 1,
+                        nickname: Some("host".into()),
+                    },
+                    FeedPlayer {
+                        actor_id: 2,
+                        nickname: None,
+                    },
+                ],
+            },
+            FeedMessage::RoomLeft {
+                reason: LeaveReason::Left,
+            },
+            FeedMessage::RoomLeft {
+                reason: LeaveReason::Disconnected,
+            },
+            FeedMessage::RoomLeft {
+                reason: LeaveReason::Migrated,
+            },
+            FeedMessage::PlayerJoined {
+                actor_id: 3,
+                nickname: Some("joiner".into()),
+            },
+            FeedMessage::PlayerLeft { actor_id: 3 },
+            FeedMessage::Kill {
+                victim: 1,
+                killer: Some(2),
+            },
+            FeedMessage::Kill {
+                victim: 2,
+                killer: None,
+            },
+            FeedMessage::Position {
+                x: 1.5,
+                y: -2.0,
+                z: 100.25,
+            },
+        ];
+        // adding a message without a sample doesn't compile
+        for sample in &samples {
+            match sample {
+                FeedMessage::Hello { .. }
+                | FeedMessage::RoomJoined { .. }
+                | FeedMessage::RoomLeft { .. }
+                | FeedMessage::PlayerJoined { .. }
+                | FeedMessage::PlayerLeft { .. }
+                | FeedMessage::Kill { .. }
+                | FeedMessage::Position { .. } => (),
+            }
+        }
+        samples
+    }
+
+    fn snapshot_path(version: u32) -> std::path::PathBuf {
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join(format!("fixtures/public_feed/v{version}.jsonl"))
+    }
+
+    #[test]
+    fn format_matches_the_snapshot() {
+        let written = samples()
+            .into_iter()
+            .enumerate()
+            .map(|(i, message)| {
+                let entry = FeedEntry {
+                    v: SCHEMA_VERSION,
+                    seq: i as u64,
+                    t_ms: 1000 * i as u64,
+                    message,
+                };
+                serde_json::to_string(&entry).unwrap() + "\n"
+            })
+            .collect::<String>();
+        let snapshot =
+            std::fs::read_to_string(snapshot_path(SCHEMA_VERSION)).unwrap_or_else(|_| {
+                panic!(
+                    "no snapshot for schema version {SCHEMA_VERSION}, add {} with:\n{written}",
+                    snapshot_path(SCHEMA_VERSION).display()
+                )
+            });
+        assert_eq!(
+            snapshot, written,
+            "the format changed, which needs a new SCHEMA_VERSION with its own snapshot"
+        );
+
+        // readers get back what was written
+        for line in snapshot.lines() {
+            let entry: FeedEntry = serde_json::from_str(line).unwrap();
+            assert_eq!(serde_json::to_string(&entry).unwrap(), line);
+        }
+        // the snapshots of older versions stay around as their documentation
+        for version in 1..=SCHEMA_VERSION {
+            assert!(
+                snapshot_path(version).exists(),
+                "snapshot of v{version} is missing"
+            );
+        }
+        assert!(!snapshot_path(SCHEMA_VERSION + 1).exists());
+    }
+
+    #[test]
+    fn skips_what_recorders_dont_need() {
+        // a feed without a writer thread, to look at what it would write
+        let (entries, receive) = std::sync::mpsc::channel();
+        let mut feed = PublicFeed {
+            writer: Some(Writer {
+                path: "unused".into(),
+                entries,
+                thread: std::thread::spawn(|| ()),
+            }),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let position = || FeedMessage::Position {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let joined = FeedMessage::RoomJoined {
+            room_name: None,
+            map_name: None,
+            actor_id: 1,
+            players: vec![],
+        };
+        let left = FeedMessage::RoomLeft {
+            reason: LeaveReason::Left,
+        };
+
+        // nothing happens outside of a room
+        feed.record(position(), start);
+        feed.record(left.clone(), start);
+        feed.record(joined.clone(), start);
+        // positions are keyframes
+        for ms in [0, 500, 999, 1000, 1500, 2100] {
+            feed.record(position(), start + Duration::from_millis(ms));
+        }
+        feed.record(left.clone(), start + Duration::from_secs(3));
+        feed.record(left, start + Duration::from_secs(3));
+
+        let written = receive
+            .try_iter()
+            .map(|(at, message)| (at.duration_since(start).as_millis(), message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            written,
+            vec![
+                (0, joined),
+                (0, position()),
+                (1000, position()),
+                (2100, position()),
+                (
+                    3000,
+                    FeedMessage::RoomLeft {
+                        reason: LeaveReason::Left
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reconnects_when_the_recorder_goes_away() {
+        use std::{
+            io::{BufRead, BufReader},
+            os::unix::net::UnixListener,
+        };
+
+        let dir = std::env::temp_dir().join(format!("bfhax-public-feed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("feed.sock");
+        _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut feed = PublicFeed::start_with(path.clone(), Duration::ZERO);
+        let read_entries = |count: usize| {
+            let (stream, _) = listener.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut reader = BufReader::new(stream);
+            let entries = (0..count)
+                .map(|_| {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    serde_json::from_str::<FeedEntry>(&line).unwrap()
+                })
+                .collect::<Vec<_>>();
+            (entries, reader)
+        };
+
+        feed.record(
+            FeedMessage::RoomJoined {
+                room_name: Some("room".into()),
+                map_name: None,
+                actor_id: 2,
+                players: vec![],
+            },
+            Instant::now(),
+        );
+        let (entries, reader) = read_entries(2);
+        assert!(matches!(entries[0].message, FeedMessage::Hello { .. }));
+        assert!(matches!(entries[1].message, FeedMessage::RoomJoined { .. }));
+        assert_eq!((entries[0].seq, entries[1].seq), (0, 1));
+        assert!(entries.iter().all(|e| e.v == SCHEMA_VERSION));
+
+        // the recorder restarts
+        drop(reader);
+        feed.record(FeedMessage::PlayerLeft { actor_id: 3 }, Instant::now());
+        let (entries, _reader) = read_entries(2);
+        assert!(matches!(entries[0].message, FeedMessage::Hello { .. }));
+        assert_eq!(entries[1].message, FeedMessage::PlayerLeft { actor_id: 3 });
+        assert_eq!((entries[0].seq, entries[1].seq), (2, 3));
+
+        drop(feed);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
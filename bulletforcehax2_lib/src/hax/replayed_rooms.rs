@@ -0,0 +1,494 @@
+//! Showing the rooms of an old capture in the live lobby, for demos.
+//!
+//! The rooms are taken from the game lists of the capture and sent to the client as game list updates, as if the
+//! lobby server listed them. They go through the hook like real updates, so the lobby features apply to them too.
+//! The hook annotates their names with [ReplayedRoomSettings::prefix] so they can't be mistaken for live rooms, and
+//! rooms whose id is already taken get a new one. The server doesn't know about them, so joining one is answered with
+//! a failure instead of being forwarded.
+
+use std::{
+    collections::HashSet,
+    io::Read,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures_util::lock::Mutex;
+use photon_lib::{
+    highlevel::{
+        constants::{error_code, event_code, operation_code},
+        structs::{RoomInfoList, RoomInfoView},
+        PhotonParameterMapConversion,
+    },
+    indexmap::IndexMap,
+    photon_data_type::PhotonDataType,
+    photon_message::{EventData, OperationResponse, PhotonMessage},
+    PhotonHashmap,
+};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, warn};
+
+use super::{bandwidth::feature, BulletForceHax, HaxState};
+use crate::{
+    error::HaxError,
+    inspect::capture::Capture,
+    proxy::{Direction, WebSocketServer},
+};
+
+/// When the rooms of a capture show up in the lobby.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReplayPacing {
+    /// All at once.
+    #[default]
+    Immediate,
+    /// As long after the injection as they were listed after the start of the capture.
+    Captured,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayedRoomSettings {
+    /// Put in front of the names of replayed rooms, see [room_names](super::room_names).
+    pub prefix: String,
+    pub pacing: ReplayPacing,
+}
+
+impl Default for ReplayedRoomSettings {
+    fn default() -> Self {
+        Self {
+            prefix: "[replay]".into(),
+            pacing: ReplayPacing::Immediate,
+        }
+    }
+}
+
+/// A room listed in a capture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedRoom {
+    pub id: String,
+    /// The room's properties, as they were last listed.
+    pub properties: PhotonHashmap,
+    /// How long after the start of the capture the room was first listed.
+    pub offset: Duration,
+}
+
+/// Finds the rooms listed by the lobby in a capture, in the order they were first listed.
+///
+/// Rooms keep the last properties they were listed with, including rooms the lobby removed later on. Only rooms the
+/// filter accepts are returned.
+pub fn extract_rooms(
+    capture: &Capture,
+    filter: impl Fn(&RoomInfoView<&PhotonHashmap>) -> bool,
+) -> Vec<CapturedRoom> {
+    let start = match capture.start_time() {
+        Some(start) => start,
+        None => return vec![],
+    };
+    let mut rooms = IndexMap::<String, CapturedRoom>::new();
+    for message in &capture.messages {
+        if message.server != WebSocketServer::LobbyServer
+            || message.direction != Direction::ServerToClient
+        {
+            continue;
+        }
+        let mut event = match message.parse() {
+            Some(PhotonMessage::EventData(event))
+                if event.code == event_code::GAME_LIST
+                    || event.code == event_code::GAME_LIST_UPDATE =>
+            {
+                event
+            }
+            _ => continue,
+        };
+        let games = match RoomInfoList::from_map(&mut event.parameters) {
+            Ok(list) => list.games,
+            Err(_) => continue,
+        };
+        let offset = message.timestamp.duration_since(start).unwrap_or_default();
+        for (id, properties) in games {
+            let (id, properties) = match (id, properties) {
+                (PhotonDataType::String(id), PhotonDataType::Hashtable(properties)) => {
+                    (id, properties)
+                }
+                _ => continue,
+            };
+            let room = rooms.entry(id.clone()).or_insert_with(|| CapturedRoom {
+                id,
+                properties: PhotonHashmap::new(),
+                offset,
+            });
+            // a removed room is only sent with the flag, keep what it looked like before
+            if RoomInfoView(&properties).removed() == Some(&true) {
+                continue;
+            }
+            room.properties.extend(properties);
+        }
+    }
+    rooms
+        .into_values()
+        .filter(|room| !room.properties.is_empty() && filter(&RoomInfoView(&room.properties)))
+        .collect()
+}
+
+/// Gives rooms a new id if it's taken by a live room or another replayed room.
+pub fn remap_rooms(rooms: Vec<CapturedRoom>, is_taken: impl Fn(&str) -> bool) -> Vec<CapturedRoom> {
+    let mut ids = HashSet::new();
+    rooms
+        .into_iter()
+        .map(|mut room| {
+            let mut id = room.id.clone();
+            let mut n = 2;
+            while is_taken(&id) || ids.contains(&id) {
+                id = format!("{} ({n})", room.id);
+                n += 1;
+            }
+            if id != room.id {
+                debug!(from = room.id, to = id, "Renamed replayed room");
+            }
+            ids.insert(id.clone());
+            room.id = id;
+            room
+        })
+        .collect()
+}
+
+/// A game list update that lists the given rooms.
+pub fn room_list_update<'a>(rooms: impl IntoIterator<Item = &'a CapturedRoom>) -> PhotonMessage {
+    let games = rooms
+        .into_iter()
+        .map(|room| {
+            (
+                PhotonDataType::String(room.id.clone()),
+                PhotonDataType::Hashtable(room.properties.clone()),
+            )
+        })
+        .collect();
+    let mut parameters = IndexMap::new();
+    RoomInfoList { games }.into_map(&mut parameters);
+    PhotonMessage::EventData(EventData {
+        code: event_code::GAME_LIST_UPDATE,
+        parameters,
+    })
+}
+
+/// The answer to joining a replayed room, as the server would answer joining a room that doesn't exist.
+pub fn join_failure(room_id: &str) -> PhotonMessage {
+    PhotonMessage::OperationResponse(OperationResponse {
+        operation_code: operation_code::JOIN_GAME,
+        return_code: error_code::GAME_DOES_NOT_EXIST,
+        debug_message: Some(format!(
+            "{room_id} was replayed from a capture and can't be joined"
+        )),
+        parameters: IndexMap::new(),
+    })
+}
+
+/// The ids of the rooms replayed into the current lobby session.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayedRooms {
+    ids: HashSet<String>,
+}
+
+impl ReplayedRooms {
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+impl BulletForceHax {
+    /// Lists the rooms of a capture in the current lobby session, see [replayed_rooms](self). Returns how many rooms
+    /// will be shown.
+    ///
+    /// The rooms are sent in the background according to [ReplayedRoomSettings::pacing]. Only rooms the filter accepts
+    /// are shown.
+    pub fn inject_capture_rooms(
+        &self,
+        reader: impl Read,
+        filter: impl Fn(&RoomInfoView<&PhotonHashmap>) -> bool,
+    ) -> Result<usize, HaxError> {
+        let capture = Capture::read_from(reader)?;
+        let state = self.get_state();
+        let (rooms, pacing) = {
+            let mut hax = futures::executor::block_on(state.lock());
+            let settings = hax.replayed_rooms.clone();
+            let lobby = match &mut hax.lobby_state {
+                Some((_, lobby)) => lobby,
+                None => return Err(HaxError::InjectionUnavailable("not in the lobby".into())),
+            };
+            let rooms = remap_rooms(extract_rooms(&capture, filter), |id| {
+                lobby.rooms.get(id).is_some() || lobby.replayed_rooms.contains(id)
+            });
+            // known before any of them is listed, so no join slips through to the server
+            lobby
+                .replayed_rooms
+                .ids
+                .extend(rooms.iter().map(|room| room.id.clone()));
+            (rooms, settings.pacing)
+        };
+
+        let count = rooms.len();
+        info!(
+            rooms = count,
+            "Replaying rooms from a capture into the lobby"
+        );
+        tokio::spawn(send_rooms(state, rooms, pacing));
+        Ok(count)
+    }
+}
+
+async fn send_rooms(state: Arc<Mutex<HaxState>>, rooms: Vec<CapturedRoom>, pacing: ReplayPacing) {
+    let batches = match pacing {
+        ReplayPacing::Immediate => vec![(Duration::ZERO, rooms)],
+        ReplayPacing::Captured => {
+            let mut batches = Vec::<(Duration, Vec<CapturedRoom>)>::new();
+            for room in rooms {
+                match batches.last_mut() {
+                    Some((offset, batch)) if *offset == room.offset => batch.push(room),
+                    _ => batches.push((room.offset, vec![room])),
+                }
+            }
+            batches
+        }
+    };
+
+    let start = Instant::now();
+    for (offset, batch) in batches {
+        if batch.is_empty() {
+            continue;
+        }
+        tokio::time::sleep_until((start + offset).into()).await;
+        if let Err(e) = inject_lobby_message(&state, room_list_update(&batch)).await {
+            warn!("Failed to replay rooms into the lobby: {e}");
+            return;
+        }
+    }
+}
+
+/// Sends a message to the client as if the lobby server sent it, running the hook on it first.
+async fn inject_lobby_message(
+    state: &Arc<Mutex<HaxState>>,
+    message: PhotonMessage,
+) -> Result<(), HaxError> {
+    let mut data = vec![];
+    message.to_websocket_bytes(&mut data)?;
+
+    let forward = HaxState::websocket_hook(
+        state.clone(),
+        &mut data,
+        WebSocketServer::LobbyServer,
+        Direction::ServerToClient,
+    )?;
+    if !forward {
+        return Ok(());
+    }
+
+    match &state.lock().await.lobby_state {
+        Some((proxy, _)) => {
+            proxy
+                .send_client(Message::Binary(data), feature::REPLAYED_ROOMS)
+                .await
+        }
+        None => Err(HaxError::InjectionUnavailable("not in the lobby".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use futures_util::lock::Mutex;
+    use photon_lib::{
+        highlevel::{
+            constants::{
+                error_code, event_code, game_property_key, operation_code, parameter_code,
+            },
+            structs::{RoomInfoList, RoomInfoView},
+            PhotonParameterMapConversion,
+        },
+        indexmap::{indexmap, IndexMap},
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationRequest, PhotonMessage},
+        PhotonHashmap,
+    };
+
+    use super::{extract_rooms, join_failure, remap_rooms, CapturedRoom};
+    use crate::{
+        hax::{drop_log::DropReason, HaxState, LobbyState},
+        inspect::{capture::Capture, CapturedMessage},
+        protocol::properties::BulletForceRoomProperties,
+        proxy::{websocket_proxy::WebSocketProxy, Direction, WebSocketServer},
+    };
+
+    fn room(name: &str, players: u8) -> PhotonDataType {
+        PhotonDataType::Hashtable(indexmap! {
+            PhotonDataType::Byte(game_property_key::PLAYER_COUNT) => PhotonDataType::Byte(players),
+            PhotonDataType::String("roomName".into()) => PhotonDataType::String(name.into()),
+        })
+    }
+
+    fn game_list(millis: u64, code: u8, games: PhotonHashmap) -> CapturedMessage {
+        let mut parameters = IndexMap::new();
+        RoomInfoList { games }.into_map(&mut parameters);
+        let mut raw = vec![];
+        PhotonMessage::EventData(EventData { code, parameters })
+            .to_websocket_bytes(&mut raw)
+            .unwrap();
+        CapturedMessage {
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_665_000_000_000 + millis),
+            server: WebSocketServer::LobbyServer,
+            direction: Direction::ServerToClient,
+            raw,
+        }
+    }
+
+    #[test]
+    fn extracts_listed_rooms() {
+        let mut game_server = game_list(
+            5,
+            event_code::GAME_LIST,
+            indexmap! {
+                PhotonDataType::String("elsewhere".into()) => room("elsewhere", 1),
+            },
+        );
+        game_server.server = WebSocketServer::GameServer;
+        let capture = Capture {
+            messages: vec![
+                game_list(
+                    0,
+                    event_code::GAME_LIST,
+                    indexmap! {
+                        PhotonDataType::String("a".into()) => room("alpha", 1),
+                        PhotonDataType::String("b".into()) => room("bravo", 2),
+                    },
+                ),
+                game_server,
+                game_list(
+                    2500,
+                    event_code::GAME_LIST_UPDATE,
+                    indexmap! {
+                        PhotonDataType::String("c".into()) => room("charlie", 3),
+                        PhotonDataType::String("a".into()) => PhotonDataType::Hashtable(indexmap! {
+                            PhotonDataType::Byte(game_property_key::PLAYER_COUNT) => PhotonDataType::Byte(8),
+                        }),
+                        PhotonDataType::String("b".into()) => PhotonDataType::Hashtable(indexmap! {
+                            PhotonDataType::Byte(game_property_key::REMOVED) => PhotonDataType::Boolean(true),
+                        }),
+                    },
+                ),
+            ],
+        };
+
+        let rooms = extract_rooms(&capture, |_| true);
+        let summary = rooms
+            .iter()
+            .map(|r| {
+                let view = RoomInfoView(&r.properties);
+                (
+                    r.id.as_str(),
+                    view.room_name().unwrap().to_string(),
+                    *view.player_count().unwrap(),
+                    r.offset,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("a", "alpha".to_string(), 8, Duration::ZERO),
+                ("b", "bravo".to_string(), 2, Duration::ZERO),
+                ("c", "charlie".to_string(), 3, Duration::from_millis(2500)),
+            ]
+        );
+
+        let busy = extract_rooms(&capture, |room| room.player_count() >= Some(&3));
+        assert_eq!(
+            busy.iter().map(|r| r.id.as_str()).collect::<Vec<_>>(),
+            ["a", "c"]
+        );
+        assert!(extract_rooms(&Capture::default(), |_| true).is_empty());
+    }
+
+    #[test]
+    fn remaps_conflicting_rooms() {
+        let captured = |id: &str| CapturedRoom {
+            id: id.into(),
+            properties: match room(id, 1) {
+                PhotonDataType::Hashtable(properties) => properties,
+                _ => unreachable!(),
+            },
+            offset: Duration::ZERO,
+        };
+        let rooms = remap_rooms(
+            vec![captured("live"), captured("old"), captured("live (2)")],
+            |id| id == "live",
+        );
+        let ids = rooms.iter().map(|r| r.id.as_str()).collect::<Vec<_>>();
+        // the third room's own id was taken by the first one's new id
+        assert_eq!(ids, ["live (2)", "old", "live (2) (2)"]);
+        // the name is left to the hook
+        assert_eq!(RoomInfoView(&rooms[1].properties).room_name(), Some("old"));
+    }
+
+    #[test]
+    fn intercepts_joining_replayed_rooms() {
+        let mut lobby = LobbyState::default();
+        lobby.replayed_rooms.ids.insert("[old] room".into());
+        let state = Arc::new(Mutex::new(HaxState {
+            lobby_state: Some((
+                WebSocketProxy::detached(
+                    WebSocketServer::LobbyServer,
+                    Default::default(),
+                    Default::default(),
+                ),
+                lobby,
+            )),
+            ..Default::default()
+        }));
+        let join = |room: &str| {
+            let mut data = vec![];
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::JOIN_GAME,
+                parameters: indexmap! {
+                    parameter_code::ROOM_NAME => PhotonDataType::String(room.into()),
+                },
+            })
+            .to_websocket_bytes(&mut data)
+            .unwrap();
+            HaxState::websocket_hook(
+                state.clone(),
+                &mut data,
+                WebSocketServer::LobbyServer,
+                Direction::ClientToServer,
+            )
+            .unwrap()
+        };
+
+        assert!(join("live room"));
+        assert!(!join("[old] room"));
+        let hax = futures::executor::block_on(state.lock());
+        assert_eq!(
+            hax.drop_log.entries().last().map(|d| &d.reason),
+            Some(&DropReason::ReplayedRoomJoin {
+                room_id: "[old] room".into()
+            })
+        );
+
+        match join_failure("[old] room") {
+            PhotonMessage::OperationResponse(response) => {
+                assert_eq!(response.operation_code, operation_code::JOIN_GAME);
+                assert_eq!(response.return_code, error_code::GAME_DOES_NOT_EXIST);
+            }
+            other => panic!("expected a response, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,671 @@
+//! Noticing when the servers treat us like a flagged account.
+//!
+//! Restricted accounts aren't told about it. Instead, matchmaking quietly stops working: authentication or joins are
+//! refused with error codes that otherwise rarely show up, the lobby lists no rooms while the server says plenty of
+//! people are online, or the custom authentication response carries extra fields. None of these prove anything on
+//! their own, so each [RestrictionRule] adds its weight to a confidence score that decays over time, like the
+//! [cheat detection](super::detection). When the score reaches the threshold, a
+//! [HaxEvent::PossiblyRestricted](super::events::HaxEvent::PossiblyRestricted) is emitted with the evidence.
+//!
+//! The rules are data in [RestrictionSettings], so they can be tuned when the servers change their answers without
+//! touching the code that feeds them.
+
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant, SystemTime},
+};
+
+use photon_lib::{
+    highlevel::constants::{error_code, event_code, operation_code, parameter_code},
+    photon_data_type::PhotonDataType,
+    photon_message::PhotonMessage,
+    PhotonHashmap,
+};
+
+use super::population::AppStats;
+use crate::proxy::WebSocketServer;
+
+/// How much evidence to keep.
+const MAX_EVIDENCE: usize = 50;
+
+/// What a [RestrictionRule] looks for in the server's responses.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestrictionPattern {
+    /// At least `count` responses to one of the operations failed with one of the return codes within the window.
+    RepeatedReturnCode {
+        operation_codes: Vec<u8>,
+        return_codes: Vec<i16>,
+        count: usize,
+        window: Duration,
+    },
+    /// `count` game lists in a row had no rooms, while the server reported at least `min_players` players online.
+    EmptyLobby { min_players: i32, count: usize },
+    /// A successful authentication response had one of these keys set in its custom data. Keys are compared without
+    /// case, and only values that are set count, so `"banned": false` doesn't.
+    RestrictionField { keys: Vec<String> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestrictionRule {
+    pub name: String,
+    pub enabled: bool,
+    pub pattern: RestrictionPattern,
+    /// How much a match adds to the score.
+    pub weight: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct RestrictionSettings {
+    /// A warning is emitted when the score reaches this.
+    pub threshold: f32,
+    /// How long it takes for the score to halve.
+    pub half_life: Duration,
+    pub rules: Vec<RestrictionRule>,
+}
+
+impl Default for RestrictionSettings {
+    fn default() -> Self {
+        Self {
+            threshold: 10.0,
+            half_life: Duration::from_secs(30 * 60),
+            rules: vec![
+                RestrictionRule {
+                    name: "authentication refused".into(),
+                    enabled: true,
+                    pattern: RestrictionPattern::RepeatedReturnCode {
+                        operation_codes: vec![
+                            operation_code::AUTHENTICATE,
+                            operation_code::AUTHENTICATE_ONCE,
+                        ],
+                        return_codes: vec![
+                            error_code::INVALID_AUTHENTICATION,
+                            error_code::CUSTOM_AUTHENTICATION_FAILED,
+                            error_code::USER_BLOCKED,
+                        ],
+                        count: 2,
+                        window: Duration::from_secs(10 * 60),
+                    },
+                    weight: 6.0,
+                },
+                // full rooms and rooms that closed while joining are normal, so those codes aren't listed
+                RestrictionRule {
+                    name: "joins refused".into(),
+                    enabled: true,
+                    pattern: RestrictionPattern::RepeatedReturnCode {
+                        operation_codes: vec![
+                            operation_code::JOIN_GAME,
+                            operation_code::JOIN_RANDOM_GAME,
+                            operation_code::CREATE_GAME,
+                        ],
+                        return_codes: vec![
+                            error_code::USER_BLOCKED,
+                            error_code::OPERATION_NOT_ALLOWED_IN_CURRENT_STATE,
+                            error_code::SERVER_FULL,
+                        ],
+                        count: 3,
+                        window: Duration::from_secs(5 * 60),
+                    },
+                    weight: 4.0,
+                },
+                RestrictionRule {
+                    name: "empty lobby".into(),
+                    enabled: true,
+                    pattern: RestrictionPattern::EmptyLobby {
+                        min_players: 100,
+                        count: 2,
+                    },
+                    weight: 5.0,
+                },
+                RestrictionRule {
+                    name: "restriction field".into(),
+                    enabled: true,
+                    pattern: RestrictionPattern::RestrictionField {
+                        keys: ["banned", "ban", "restricted", "shadowban", "suspended"]
+                            .map(String::from)
+                            .to_vec(),
+                    },
+                    weight: 10.0,
+                },
+            ],
+        }
+    }
+}
+
+/// Why a rule matched.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Evidence {
+    pub timestamp: SystemTime,
+    pub rule: String,
+    pub server: WebSocketServer,
+    /// What was seen, eg. the return codes.
+    pub detail: String,
+    pub weight: f32,
+}
+
+impl Display for Evidence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} on {}: {}", self.rule, self.server, self.detail)
+    }
+}
+
+/// The score reached the threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestrictionWarning {
+    pub score: f32,
+    /// The evidence that is still kept, oldest first.
+    pub evidence: Vec<Evidence>,
+}
+
+/// Keeps the confidence that we're restricted, fed with the server's messages.
+#[derive(Debug, Default)]
+pub struct RestrictionDetector {
+    score: f32,
+    updated: Option<Instant>,
+    /// Whether the score is above the threshold, so crossing it is only reported once.
+    flagged: bool,
+    evidence: VecDeque<Evidence>,
+    /// When the failed responses arrived, per rule.
+    failures: Vec<VecDeque<(Instant, u8, i16)>>,
+    /// How many empty game lists arrived in a row.
+    empty_lists: usize,
+    players_online: Option<i32>,
+}
+
+impl RestrictionDetector {
+    /// Feeds a message the server sent.
+    pub fn observe(
+        &mut self,
+        server: WebSocketServer,
+        message: &PhotonMessage,
+        now: Instant,
+        timestamp: SystemTime,
+        settings: &RestrictionSettings,
+    ) -> Option<RestrictionWarning> {
+        self.failures
+            .resize_with(settings.rules.len(), VecDeque::new);
+        let mut matches = vec![];
+        for (index, rule) in settings.rules.iter().enumerate() {
+            if rule.enabled {
+                if let Some(detail) = self.check(index, &rule.pattern, message, now) {
+                    matches.push((rule, detail));
+                }
+            }
+        }
+
+        // every rule gets to update its counts before the lobby counts are taken over
+        if let PhotonMessage::EventData(event) = message {
+            if event.code == event_code::APP_STATS {
+                self.players_online = AppStats::from_parameters(&event.parameters).players();
+            }
+        }
+
+        let mut warning = None;
+        for (rule, detail) in matches {
+            let evidence = Evidence {
+                timestamp,
+                rule: rule.name.clone(),
+                server,
+                detail,
+                weight: rule.weight,
+            };
+            warning = warning.or(self.add(evidence, now, settings));
+        }
+        warning
+    }
+
+    /// Whether the message matches the rule at the index, and what matched if so.
+    fn check(
+        &mut self,
+        index: usize,
+        pattern: &RestrictionPattern,
+        message: &PhotonMessage,
+        now: Instant,
+    ) -> Option<String> {
+        match (pattern, message) {
+            (
+                RestrictionPattern::RepeatedReturnCode {
+                    operation_codes,
+                    return_codes,
+                    count,
+                    window,
+                },
+                PhotonMessage::OperationResponse(response),
+            ) => {
+                if !operation_codes.contains(&response.operation_code) {
+                    return None;
+                }
+                let failures = &mut self.failures[index];
+                if !return_codes.contains(&response.return_code) {
+                    // it worked after all
+                    if response.return_code == error_code::OK {
+                        failures.clear();
+                    }
+                    return None;
+                }
+                while matches!(failures.front(), Some((t, _, _)) if now.saturating_duration_since(*t) > *window)
+                {
+                    failures.pop_front();
+                }
+                failures.push_back((now, response.operation_code, response.return_code));
+                if failures.len() < *count {
+                    return None;
+                }
+                let detail = failures
+                    .iter()
+                    .map(|(_, operation_code, return_code)| {
+                        format!("operation {operation_code} returned {return_code}")
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                // the next match needs as many failures again
+                failures.clear();
+                Some(detail)
+            }
+            (
+                RestrictionPattern::EmptyLobby { min_players, count },
+                PhotonMessage::EventData(event),
+            ) if event.code == event_code::GAME_LIST => {
+                let rooms = match event.parameters.get(&parameter_code::GAME_LIST) {
+                    Some(PhotonDataType::Hashtable(rooms)) => rooms.len(),
+                    _ => return None,
+                };
+                match self.players_online {
+                    Some(players) if rooms == 0 && players >= *min_players => {
+                        self.empty_lists += 1;
+                    }
+                    _ => {
+                        self.empty_lists = 0;
+                        return None;
+                    }
+                }
+                if self.empty_lists < *count {
+                    return None;
+                }
+                self.empty_lists = 0;
+                Some(format!(
+                    "{count} empty game lists while {} players were online",
+                    self.players_online.unwrap_or_default()
+                ))
+            }
+            (
+                RestrictionPattern::RestrictionField { keys },
+                PhotonMessage::OperationResponse(response),
+            ) if matches!(
+                response.operation_code,
+                operation_code::AUTHENTICATE | operation_code::AUTHENTICATE_ONCE
+            ) && response.return_code == error_code::OK =>
+            {
+                let data = match response.parameters.get(&parameter_code::DATA) {
+                    Some(PhotonDataType::Dictionary(_, data) | PhotonDataType::Hashtable(data)) => {
+                        data
+                    }
+                    _ => return None,
+                };
+                let found = restriction_fields(data, keys);
+                (!found.is_empty()).then(|| format!("authentication data has {}", found.join(", ")))
+            }
+            _ => None,
+        }
+    }
+
+    fn decay(&mut self, now: Instant, half_life: Duration) {
+        if let Some(updated) = self.updated {
+            let elapsed = now.saturating_duration_since(updated).as_secs_f32();
+            self.score *= 0.5f32.powf(elapsed / half_life.as_secs_f32().max(f32::EPSILON));
+        }
+        self.updated = Some(now);
+    }
+
+    fn add(
+        &mut self,
+        evidence: Evidence,
+        now: Instant,
+        settings: &RestrictionSettings,
+    ) -> Option<RestrictionWarning> {
+        self.decay(now, settings.half_life);
+        if self.score < settings.threshold {
+            self.flagged = false;
+        }
+
+        self.score += evidence.weight;
+        if self.evidence.len() >= MAX_EVIDENCE {
+            self.evidence.pop_front();
+        }
+        self.evidence.push_back(evidence);
+
+        if self.score >= settings.threshold && !self.flagged {
+            self.flagged = true;
+            return Some(RestrictionWarning {
+                score: self.score,
+                evidence: self.evidence.iter().cloned().collect(),
+            });
+        }
+        None
+    }
+
+    /// The decayed confidence score.
+    pub fn score(&self, now: Instant, settings: &RestrictionSettings) -> f32 {
+        let mut decayed = Self {
+            score: self.score,
+            updated: self.updated,
+            ..Default::default()
+        };
+        decayed.decay(now, settings.half_life);
+        decayed.score
+    }
+
+    /// Why rules matched, oldest first.
+    pub fn evidence(&self) -> impl DoubleEndedIterator<Item = &Evidence> + ExactSizeIterator {
+        self.evidence.iter()
+    }
+
+    /// Forgets the score and the evidence, eg. after the account was checked.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// The keys of the custom authentication data that are among the given keys and set, as `key=value`.
+fn restriction_fields(data: &PhotonHashmap, keys: &[String]) -> Vec<String> {
+    data.iter()
+        .filter_map(|(key, value)| match key {
+            PhotonDataType::String(key) if keys.iter().any(|k| k.eq_ignore_ascii_case(key)) => {
+                is_set(value).then(|| format!("{key}={value:?}"))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether a value says the field applies, rather than being a default.
+fn is_set(value: &PhotonDataType) -> bool {
+    match value {
+        PhotonDataType::Null => false,
+        PhotonDataType::Boolean(b) => *b,
+        PhotonDataType::Byte(n) => *n != 0,
+        PhotonDataType::Short(n) => *n != 0,
+        PhotonDataType::Integer(n) => *n != 0,
+        PhotonDataType::Long(n) => *n != 0,
+        PhotonDataType::String(s) => !s.is_empty() && !s.eq_ignore_ascii_case("false"),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, SystemTime};
+
+    use photon_lib::{
+        highlevel::constants::{error_code, event_code, operation_code, parameter_code},
+        indexmap::{indexmap, IndexMap},
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationResponse, PhotonMessage},
+    };
+
+    use super::{RestrictionDetector, RestrictionSettings};
+    use crate::proxy::WebSocketServer;
+
+    fn response(operation_code: u8, return_code: i16) -> PhotonMessage {
+        PhotonMessage::OperationResponse(OperationResponse {
+            operation_code,
+            return_code,
+            debug_message: None,
+            parameters: indexmap! {},
+        })
+    }
+
+    fn app_stats(players: i32) -> PhotonMessage {
+        PhotonMessage::EventData(EventData {
+            code: event_code::APP_STATS,
+            parameters: indexmap! {
+                parameter_code::PEER_COUNT => PhotonDataType::Integer(players),
+                parameter_code::MASTER_PEER_COUNT => PhotonDataType::Integer(0),
+            },
+        })
+    }
+
+    fn game_list(rooms: usize) -> PhotonMessage {
+        let rooms = (0..rooms)
+            .map(|i| {
+                (
+                    PhotonDataType::String(format!("room {i}")),
+                    PhotonDataType::Hashtable(IndexMap::new()),
+                )
+            })
+            .collect();
+        PhotonMessage::EventData(EventData {
+            code: event_code::GAME_LIST,
+            parameters: indexmap! { parameter_code::GAME_LIST => PhotonDataType::Hashtable(rooms) },
+        })
+    }
+
+    fn auth_response(data: Vec<(&str, PhotonDataType)>) -> PhotonMessage {
+        let data = data
+            .into_iter()
+            .map(|(k, v)| (PhotonDataType::String(k.into()), v))
+            .collect();
+        PhotonMessage::OperationResponse(OperationResponse {
+            operation_code: operation_code::AUTHENTICATE,
+            return_code: error_code::OK,
+            debug_message: None,
+            parameters: indexmap! {
+                parameter_code::USER_ID => PhotonDataType::String("user".into()),
+                parameter_code::DATA => PhotonDataType::Dictionary((115, 42), data),
+            },
+        })
+    }
+
+    /// Feeds the messages a minute apart, and returns the rules that matched.
+    fn run(
+        detector: &mut RestrictionDetector,
+        server: WebSocketServer,
+        messages: &[PhotonMessage],
+        settings: &RestrictionSettings,
+    ) -> Vec<String> {
+        let start = Instant::now();
+        let evidence = detector.evidence().len();
+        for (i, message) in messages.iter().enumerate() {
+            let now = start + Duration::from_secs(60 * i as u64);
+            detector.observe(server, message, now, SystemTime::now(), settings);
+        }
+        detector
+            .evidence()
+            .skip(evidence)
+            .map(|e| e.rule.clone())
+            .collect()
+    }
+
+    #[test]
+    fn repeated_return_codes() {
+        let settings = RestrictionSettings::default();
+        let lobby = WebSocketServer::LobbyServer;
+
+        // full and vanished rooms are part of joining
+        let mut detector = RestrictionDetector::default();
+        let benign = [
+            response(operation_code::JOIN_GAME, error_code::GAME_FULL),
+            response(operation_code::JOIN_GAME, error_code::GAME_DOES_NOT_EXIST),
+            response(operation_code::JOIN_GAME, error_code::GAME_FULL),
+            response(
+                operation_code::JOIN_RANDOM_GAME,
+                error_code::NO_RANDOM_MATCH_FOUND,
+            ),
+        ];
+        assert!(run(&mut detector, lobby, &benign, &settings).is_empty());
+
+        // a success in between starts counting again
+        let interrupted = [
+            response(operation_code::JOIN_GAME, error_code::USER_BLOCKED),
+            response(operation_code::JOIN_GAME, error_code::USER_BLOCKED),
+            response(operation_code::JOIN_GAME, error_code::OK),
+            response(operation_code::JOIN_GAME, error_code::USER_BLOCKED),
+        ];
+        assert!(run(&mut detector, lobby, &interrupted, &settings).is_empty());
+
+        let refused = [
+            response(operation_code::JOIN_GAME, error_code::USER_BLOCKED),
+            response(operation_code::CREATE_GAME, error_code::SERVER_FULL),
+            response(operation_code::JOIN_GAME, error_code::USER_BLOCKED),
+        ];
+        let mut detector = RestrictionDetector::default();
+        assert_eq!(
+            run(&mut detector, lobby, &refused, &settings),
+            ["joins refused"]
+        );
+        let evidence = detector.evidence().next().unwrap();
+        assert_eq!(
+            evidence.detail,
+            "operation 226 returned 32761, operation 227 returned 32762, operation 226 returned 32761"
+        );
+
+        // too far apart
+        let mut detector = RestrictionDetector::default();
+        let start = Instant::now();
+        for (i, message) in refused.iter().enumerate() {
+            let now = start + Duration::from_secs(10 * 60 * i as u64);
+            detector.observe(lobby, message, now, SystemTime::now(), &settings);
+        }
+        assert_eq!(detector.evidence().len(), 0);
+
+        let mut detector = RestrictionDetector::default();
+        let auth = [
+            response(
+                operation_code::AUTHENTICATE,
+                error_code::CUSTOM_AUTHENTICATION_FAILED,
+            ),
+            response(
+                operation_code::AUTHENTICATE,
+                error_code::CUSTOM_AUTHENTICATION_FAILED,
+            ),
+        ];
+        assert_eq!(
+            run(&mut detector, WebSocketServer::NameServer, &auth, &settings),
+            ["authentication refused"]
+        );
+    }
+
+    #[test]
+    fn empty_lobby() {
+        let settings = RestrictionSettings::default();
+        let lobby = WebSocketServer::LobbyServer;
+
+        // a quiet region has empty lists
+        let mut detector = RestrictionDetector::default();
+        let quiet = [app_stats(20), game_list(0), game_list(0), game_list(0)];
+        assert!(run(&mut detector, lobby, &quiet, &settings).is_empty());
+
+        // without stats, an empty list says nothing
+        let mut detector = RestrictionDetector::default();
+        assert!(run(
+            &mut detector,
+            lobby,
+            &[game_list(0), game_list(0)],
+            &settings
+        )
+        .is_empty());
+
+        // rooms in between
+        let busy = [app_stats(2000), game_list(0), game_list(12), game_list(0)];
+        let mut detector = RestrictionDetector::default();
+        assert!(run(&mut detector, lobby, &busy, &settings).is_empty());
+
+        let hidden = [app_stats(2000), game_list(0), game_list(0)];
+        let mut detector = RestrictionDetector::default();
+        assert_eq!(
+            run(&mut detector, lobby, &hidden, &settings),
+            ["empty lobby"]
+        );
+        assert_eq!(
+            detector.evidence().next().unwrap().detail,
+            "2 empty game lists while 2000 players were online"
+        );
+    }
+
+    #[test]
+    fn restriction_field() {
+        let settings = RestrictionSettings::default();
+        let name_server = WebSocketServer::NameServer;
+
+        let mut detector = RestrictionDetector::default();
+        let benign = [
+            auth_response(vec![]),
+            auth_response(vec![
+                ("banned", PhotonDataType::Boolean(false)),
+                ("Restricted", PhotonDataType::Integer(0)),
+                ("level", PhotonDataType::Integer(30)),
+            ]),
+            // failed authentication isn't judged by its data
+            PhotonMessage::OperationResponse(OperationResponse {
+                operation_code: operation_code::AUTHENTICATE,
+                return_code: error_code::INVALID_AUTHENTICATION,
+                debug_message: None,
+                parameters: indexmap! {},
+            }),
+        ];
+        assert!(run(&mut detector, name_server, &benign, &settings).is_empty());
+
+        let flagged = [auth_response(vec![
+            ("ShadowBan", PhotonDataType::Boolean(true)),
+            ("level", PhotonDataType::Integer(30)),
+        ])];
+        let warning = detector
+            .observe(
+                name_server,
+                &flagged[0],
+                Instant::now(),
+                SystemTime::now(),
+                &settings,
+            )
+            .expect("the field alone reaches the threshold");
+        assert_eq!(warning.evidence.len(), 1);
+        assert_eq!(warning.evidence[0].rule, "restriction field");
+        assert_eq!(
+            warning.evidence[0].to_string(),
+            "restriction field on nameserver: authentication data has ShadowBan=Boolean(true)"
+        );
+    }
+
+    #[test]
+    fn warns_once_and_decays() {
+        let settings = RestrictionSettings {
+            threshold: 8.0,
+            half_life: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let lobby = WebSocketServer::LobbyServer;
+        let start = Instant::now();
+        let mut detector = RestrictionDetector::default();
+        let refuse = |detector: &mut RestrictionDetector, now| {
+            let mut warning = None;
+            for _ in 0..3 {
+                let message = response(operation_code::JOIN_GAME, error_code::USER_BLOCKED);
+                warning = warning.or(detector.observe(
+                    lobby,
+                    &message,
+                    now,
+                    SystemTime::now(),
+                    &settings,
+                ));
+            }
+            warning
+        };
+
+        assert_eq!(refuse(&mut detector, start), None);
+        let warning = refuse(&mut detector, start).expect("crossed the threshold");
+        assert_eq!(warning.score, 8.0);
+        assert_eq!(warning.evidence.len(), 2);
+        assert_eq!(refuse(&mut detector, start), None, "still above");
+
+        let later = start + Duration::from_secs(60);
+        assert!((detector.score(later, &settings) - 6.0).abs() < 0.01);
+        let much_later = start + Duration::from_secs(600);
+        assert!(detector.score(much_later, &settings) < 0.1);
+        assert_eq!(refuse(&mut detector, much_later), None);
+        assert!(refuse(&mut detector, much_later).is_some());
+
+        detector.clear();
+        assert_eq!(detector.score(much_later, &settings), 0.0);
+        assert_eq!(detector.evidence().len(), 0);
+    }
+}
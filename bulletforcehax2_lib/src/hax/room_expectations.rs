@@ -0,0 +1,304 @@
+//! What the lobby listed about a room, checked against what the game server says once we're in it.
+//!
+//! When the client asks the lobby to join a listed room, the listing is kept as a [RoomExpectation] on the
+//! [state](super::HaxState), as the game server connection comes later and knows nothing of the lobby. Once the game
+//! server's JOIN_GAME response for the same room arrives, its properties are compared to the listing. Differences,
+//! eg. because the host changed the map after the listing was sent, are kept as a [RoomMismatch] for the room and
+//! emitted as [HaxEvent::RoomMismatch](super::events::HaxEvent::RoomMismatch). Rooms joined by name without being
+//! listed have nothing to compare against.
+
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use photon_lib::{
+    highlevel::constants::game_property_key, photon_data_type::PhotonDataType, PhotonHashmap,
+};
+
+use super::{lobby_cache::CachedRoom, property_firewall::format_key};
+
+/// Properties that only mean something in the lobby listing, or change by us joining.
+const LOBBY_ONLY_KEYS: [u8; 3] = [
+    game_property_key::PLAYER_COUNT,
+    game_property_key::REMOVED,
+    game_property_key::PROPS_LISTED_IN_LOBBY,
+];
+
+/// A room as the lobby listed it when the client asked to join it.
+#[derive(Debug, Clone)]
+pub struct RoomExpectation {
+    pub room_id: String,
+    /// The listed properties, before any feature rewrote them.
+    pub properties: PhotonHashmap,
+    /// When the room was last listed or updated.
+    pub listed_at: Instant,
+}
+
+impl RoomExpectation {
+    pub fn from_listing(room_id: &str, room: &CachedRoom) -> Self {
+        Self {
+            room_id: room_id.into(),
+            properties: room.properties.clone(),
+            listed_at: room.last_seen,
+        }
+    }
+
+    /// Compares the listing to the properties the game server sent when joining. Only properties that both have are
+    /// compared, the lobby lists just a few and the game server may leave some out. Returns [None] if they agree.
+    pub fn check(&self, actual: &PhotonHashmap, now: Instant) -> Option<RoomMismatch> {
+        let differences = self
+            .properties
+            .iter()
+            .filter(|(key, _)| !is_lobby_only(key))
+            .filter_map(|(key, listed)| match actual.get(key) {
+                Some(actual) if actual != listed => Some(PropertyDifference {
+                    key: key.clone(),
+                    listed: listed.clone(),
+                    actual: actual.clone(),
+                }),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        (!differences.is_empty()).then(|| RoomMismatch {
+            room_id: self.room_id.clone(),
+            listing_age: now.saturating_duration_since(self.listed_at),
+            differences,
+        })
+    }
+}
+
+fn is_lobby_only(key: &PhotonDataType) -> bool {
+    matches!(key, PhotonDataType::Byte(key) if LOBBY_ONLY_KEYS.contains(key))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PropertyDifference {
+    pub key: PhotonDataType,
+    pub listed: PhotonDataType,
+    pub actual: PhotonDataType,
+}
+
+impl Display for PropertyDifference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {}",
+            format_key(&self.key),
+            self.listed.display_bounded(1, 8, 32),
+            self.actual.display_bounded(1, 8, 32)
+        )
+    }
+}
+
+/// How a joined room differs from its lobby listing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomMismatch {
+    pub room_id: String,
+    /// How old the listing was when the room was joined.
+    pub listing_age: Duration,
+    pub differences: Vec<PropertyDifference>,
+}
+
+impl Display for RoomMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} differs from its listing from {}s ago: ",
+            self.room_id,
+            self.listing_age.as_secs()
+        )?;
+        for (i, difference) in self.differences.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{difference}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use futures_util::lock::Mutex;
+    use photon_lib::{
+        highlevel::constants::{game_property_key, operation_code, parameter_code},
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{OperationRequest, OperationResponse, PhotonMessage},
+        PhotonHashmap,
+    };
+
+    use super::RoomExpectation;
+    use crate::{
+        hax::{
+            events::HaxEvent, game_variant::GameVariant, lobby_cache::CachedRoom, GameplayState,
+            HaxState, LobbyState,
+        },
+        proxy::{websocket_proxy::WebSocketProxy, Direction, WebSocketServer},
+    };
+
+    fn properties(max_players: u8, map: &str) -> PhotonHashmap {
+        indexmap! {
+            PhotonDataType::Byte(game_property_key::MAX_PLAYERS) => PhotonDataType::Byte(max_players),
+            PhotonDataType::Byte(game_property_key::PLAYER_COUNT) => PhotonDataType::Byte(3),
+            PhotonDataType::String("mapName".into()) => PhotonDataType::String(map.into()),
+        }
+    }
+
+    #[test]
+    fn compares_shared_properties() {
+        let listed_at = Instant::now();
+        let expectation = RoomExpectation::from_listing(
+            "room",
+            &CachedRoom {
+                properties: properties(12, "Urban"),
+                variant: GameVariant::BulletForce,
+                last_seen: listed_at,
+                probe: None,
+            },
+        );
+        let later = listed_at + Duration::from_secs(5);
+
+        // the player count went up by us joining, and the game server doesn't list it anyway
+        let mut actual = properties(12, "Urban");
+        actual.insert(
+            PhotonDataType::Byte(game_property_key::PLAYER_COUNT),
+            PhotonDataType::Byte(4),
+        );
+        actual.insert(
+            PhotonDataType::String("switchingmap".into()),
+            PhotonDataType::Boolean(false),
+        );
+        assert_eq!(expectation.check(&actual, later), None);
+
+        let mismatch = expectation
+            .check(&properties(10, "Outskirts"), later)
+            .unwrap();
+        assert_eq!(mismatch.differences.len(), 2);
+        assert_eq!(
+            mismatch.to_string(),
+            "room differs from its listing from 5s ago: \
+             255: Byte(12) -> Byte(10), mapName: String(\"Urban\") -> String(\"Outskirts\")"
+        );
+    }
+
+    fn join(
+        state: &Arc<Mutex<HaxState>>,
+        server: WebSocketServer,
+        direction: Direction,
+        message: PhotonMessage,
+    ) {
+        let mut data = vec![];
+        message.to_websocket_bytes(&mut data).unwrap();
+        HaxState::websocket_hook(state.clone(), &mut data, server, direction).unwrap();
+    }
+
+    fn join_request(room_name: &str) -> PhotonMessage {
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: operation_code::JOIN_GAME,
+            parameters: indexmap! {
+                parameter_code::ROOM_NAME => PhotonDataType::String(room_name.into()),
+            },
+        })
+    }
+
+    fn join_response(room_name: &str, game_properties: PhotonHashmap) -> PhotonMessage {
+        PhotonMessage::OperationResponse(OperationResponse {
+            operation_code: operation_code::JOIN_GAME,
+            return_code: 0,
+            debug_message: None,
+            parameters: indexmap! {
+                parameter_code::ROOM_NAME => PhotonDataType::String(room_name.into()),
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(4),
+                parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(game_properties),
+            },
+        })
+    }
+
+    /// Joins a room through the lobby and the game server, returning the mismatch recorded for it.
+    fn join_through_lobby(
+        requested: &str,
+        game_properties: PhotonHashmap,
+    ) -> (Option<String>, usize) {
+        let lobby = WebSocketProxy::detached(
+            WebSocketServer::LobbyServer,
+            Default::default(),
+            Default::default(),
+        );
+        let game = WebSocketProxy::detached(
+            WebSocketServer::GameServer,
+            Default::default(),
+            Default::default(),
+        );
+        let mut hax = HaxState {
+            lobby_state: Some((lobby, LobbyState::default())),
+            gameplay_state: Some((game, GameplayState::default())),
+            ..Default::default()
+        };
+        let games = indexmap! {
+            PhotonDataType::String("listed".into()) => PhotonDataType::Hashtable(properties(12, "Urban")),
+        };
+        let lobby_state = &mut hax.lobby_state.as_mut().unwrap().1;
+        lobby_state.rooms.update(&games, Instant::now());
+        let mut events = hax.events.subscribe();
+        let state = Arc::new(Mutex::new(hax));
+
+        join(
+            &state,
+            WebSocketServer::LobbyServer,
+            Direction::ClientToServer,
+            join_request(requested),
+        );
+        join(
+            &state,
+            WebSocketServer::GameServer,
+            Direction::ClientToServer,
+            join_request(requested),
+        );
+        join(
+            &state,
+            WebSocketServer::GameServer,
+            Direction::ServerToClient,
+            join_response(requested, game_properties),
+        );
+
+        let hax = futures::executor::block_on(state.lock());
+        let mismatch = hax.room_mismatch().map(ToString::to_string);
+        let mut emitted = 0;
+        while let Ok(event) = events.try_recv() {
+            if let HaxEvent::RoomMismatch(emitted_mismatch) = event {
+                assert_eq!(Some(emitted_mismatch.to_string()), mismatch);
+                emitted += 1;
+            }
+        }
+        (mismatch, emitted)
+    }
+
+    #[test]
+    fn joined_room_is_checked_against_the_lobby() {
+        assert_eq!(
+            join_through_lobby("listed", properties(12, "Urban")),
+            (None, 0)
+        );
+
+        let (mismatch, emitted) = join_through_lobby("listed", properties(12, "Outskirts"));
+        assert!(mismatch
+            .unwrap()
+            .ends_with(r#"mapName: String("Urban") -> String("Outskirts")"#));
+        assert_eq!(emitted, 1);
+
+        // joined by name without being listed, so there is nothing to expect
+        assert_eq!(
+            join_through_lobby("unlisted", properties(10, "Outskirts")),
+            (None, 0)
+        );
+    }
+}
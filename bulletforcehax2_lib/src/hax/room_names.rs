@@ -0,0 +1,313 @@
+//! Putting the lobby features' annotations in front of room names, within the width the client's room list shows.
+//!
+//! Several features mark the rooms they touch: stripped passwords with `[p]`, rooms of other stores and versions with
+//! their store and version, favorites with `[*]`, and replayed rooms and the match phase with their own prefixes.
+//! Each putting its prefix in front of the others' made for names the client cuts off at an unpredictable point, often
+//! before the room's actual name. The [lobby rewrites](super::transforms) collect [Annotation]s instead, and the
+//! [RoomNameComposer] puts them in front of the name once, in a fixed order and within a length budget. Annotations
+//! that don't fit are abbreviated, then left out, starting with the last ones.
+//!
+//! A name the composer returned may come back in a later listing, eg. when our own game list update is run through the
+//! hook again. [ComposedName] keeps the name a composition started from and its annotations, so the next one starts
+//! from there too instead of stacking the annotations.
+//!
+//! This module is also built without the `proxy` feature, like the rewrites using it.
+
+/// What an [Annotation] says about a room. Also the order annotations are shown in by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AnnotationKind {
+    /// The room is from an old capture, replayed into the live lobby.
+    Replay,
+    /// How far along the room's round is.
+    Phase,
+    /// The room or its host has a favorite note.
+    Favorite,
+    /// The room's password was stripped.
+    Password,
+    /// The room was forced to the client's version from the one shown.
+    Version,
+    /// The room was listed for another store.
+    Store,
+}
+
+/// A prefix for a room's name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Annotation {
+    pub kind: AnnotationKind,
+    pub full: String,
+    /// A shorter form to use when the full one doesn't fit.
+    pub short: Option<String>,
+}
+
+impl Annotation {
+    pub fn new(kind: AnnotationKind, full: impl Into<String>) -> Self {
+        Self {
+            kind,
+            full: full.into(),
+            short: None,
+        }
+    }
+
+    pub fn abbreviated(self, short: impl Into<String>) -> Self {
+        Self {
+            short: Some(short.into()),
+            ..self
+        }
+    }
+
+    pub fn password() -> Self {
+        Self::new(AnnotationKind::Password, "[p]")
+    }
+
+    pub fn favorite() -> Self {
+        Self::new(AnnotationKind::Favorite, "[*]")
+    }
+
+    /// The store a room was listed for, eg. `[M]` for mobile.
+    pub fn store(store_id: &str) -> Self {
+        match store_id {
+            "BALYZE_MOBILE" => Self::new(AnnotationKind::Store, "[M]"),
+            store_id => {
+                let short = store_id.strip_prefix("BALYZE_").unwrap_or(store_id);
+                let annotation = Self::new(AnnotationKind::Store, format!("[{store_id}]"));
+                match short.chars().next() {
+                    Some(initial) => annotation.abbreviated(format!("[{initial}]")),
+                    None => annotation,
+                }
+            }
+        }
+    }
+
+    /// The version a room was actually listed with.
+    pub fn version(version: &str) -> Self {
+        Self::new(AnnotationKind::Version, format!("[{version}]")).abbreviated("[v]")
+    }
+
+    fn len(&self, abbreviated: bool) -> usize {
+        match (&self.short, abbreviated) {
+            (Some(short), true) => short.chars().count(),
+            _ => self.full.chars().count(),
+        }
+    }
+}
+
+/// A room's name before and after composing, see [room_names](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComposedName {
+    pub original: String,
+    pub composed: String,
+    /// The annotations it was composed from, including ones that didn't fit.
+    pub annotations: Vec<Annotation>,
+}
+
+impl ComposedName {
+    /// The name to compose from for a room listed with the given name: the original if the listed name is what it was
+    /// composed into, or the listed name itself if the room was renamed since.
+    pub fn base<'a>(previous: Option<&'a ComposedName>, listed: &'a str) -> &'a str {
+        match previous {
+            Some(previous) if previous.composed == listed => &previous.original,
+            _ => listed,
+        }
+    }
+}
+
+/// The result of [RoomNameComposer::compose].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Composition {
+    pub name: String,
+    /// The kinds of the annotations that made it into the name, abbreviated or not.
+    pub shown: Vec<AnnotationKind>,
+}
+
+/// Puts annotations in front of room names, see [room_names](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomNameComposer {
+    /// The kinds of annotations to show, in the order they're put in front of the name. The last ones are abbreviated
+    /// and left out first. Annotations of other kinds are never shown.
+    pub order: Vec<AnnotationKind>,
+    /// How many characters of a name the client shows.
+    pub budget: usize,
+    /// How many characters of the room's own name always fit in the budget, if it's that long.
+    pub min_name: usize,
+}
+
+impl Default for RoomNameComposer {
+    fn default() -> Self {
+        Self {
+            order: vec![
+                AnnotationKind::Replay,
+                AnnotationKind::Phase,
+                AnnotationKind::Favorite,
+                AnnotationKind::Password,
+                AnnotationKind::Version,
+                AnnotationKind::Store,
+            ],
+            budget: 32,
+            min_name: 12,
+        }
+    }
+}
+
+impl RoomNameComposer {
+    /// Puts the annotations in front of the name. Only the first annotation of each kind is shown, and the name itself
+    /// is never shortened.
+    pub fn compose(&self, name: &str, annotations: &[Annotation]) -> Composition {
+        let mut shown = self
+            .order
+            .iter()
+            .filter_map(|kind| annotations.iter().find(|a| a.kind == *kind))
+            .map(|annotation| (annotation, false))
+            .collect::<Vec<_>>();
+
+        // each annotation takes a space after it
+        let room = self
+            .budget
+            .saturating_sub(name.chars().count().min(self.min_name));
+        let width = |shown: &[(&Annotation, bool)]| {
+            shown
+                .iter()
+                .map(|(annotation, abbreviated)| annotation.len(*abbreviated) + 1)
+                .sum::<usize>()
+        };
+        while width(&shown) > room {
+            let abbreviate = shown
+                .iter_mut()
+                .rev()
+                .find(|(annotation, abbreviated)| annotation.short.is_some() && !*abbreviated);
+            match abbreviate {
+                Some((_, abbreviated)) => *abbreviated = true,
+                None => {
+                    shown.pop();
+                }
+            }
+        }
+
+        let mut composed = String::new();
+        for (annotation, abbreviated) in &shown {
+            match (&annotation.short, abbreviated) {
+                (Some(short), true) => composed.push_str(short),
+                _ => composed.push_str(&annotation.full),
+            }
+            composed.push(' ');
+        }
+        composed.push_str(name);
+        Composition {
+            name: composed,
+            shown: shown
+                .iter()
+                .map(|(annotation, _)| annotation.kind)
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Annotation, AnnotationKind, ComposedName, RoomNameComposer};
+
+    fn phase() -> Annotation {
+        Annotation::new(AnnotationKind::Phase, "[~12m left]").abbreviated("[~12m]")
+    }
+
+    fn all() -> Vec<Annotation> {
+        vec![
+            Annotation::store("BALYZE_MOBILE"),
+            Annotation::version("1.89.0"),
+            Annotation::password(),
+            Annotation::favorite(),
+            phase(),
+        ]
+    }
+
+    #[test]
+    fn orders_by_priority() {
+        let composer = RoomNameComposer {
+            budget: 100,
+            ..Default::default()
+        };
+        let composition = composer.compose("Pros", &all());
+        assert_eq!(composition.name, "[~12m left] [*] [p] [1.89.0] [M] Pros");
+        assert_eq!(composer.compose("Pros", &[]).name, "Pros");
+
+        // kinds that aren't listed are left out
+        let composer = RoomNameComposer {
+            order: vec![AnnotationKind::Store, AnnotationKind::Password],
+            ..composer
+        };
+        let composition = composer.compose("Pros", &all());
+        assert_eq!(composition.name, "[M] [p] Pros");
+        assert_eq!(
+            composition.shown,
+            [AnnotationKind::Store, AnnotationKind::Password]
+        );
+    }
+
+    #[test]
+    fn stays_within_budget() {
+        let composer = RoomNameComposer {
+            budget: 32,
+            min_name: 12,
+            ..Default::default()
+        };
+        // 37 characters in full, the version is abbreviated first
+        assert_eq!(
+            composer.compose("Pros", &all()).name,
+            "[~12m left] [*] [p] [v] [M] Pros"
+        );
+        // then the phase, and then annotations are left out from the end
+        let composition = composer.compose("Only snipers allowed", &all());
+        assert_eq!(composition.name, "[~12m] [*] [p] [v] Only snipers allowed");
+        assert_eq!(
+            composition.shown,
+            [
+                AnnotationKind::Phase,
+                AnnotationKind::Favorite,
+                AnnotationKind::Password,
+                AnnotationKind::Version
+            ]
+        );
+        // only 12 characters of the name are guaranteed, longer names are never cut
+        let long = "a".repeat(40);
+        let composition = composer.compose(&long, &all());
+        assert_eq!(composition.name, format!("[~12m] [*] [p] [v] {long}"));
+
+        let stores = [
+            Annotation::store("BALYZE_STEAM"),
+            Annotation::new(AnnotationKind::Replay, "[replay]"),
+        ];
+        let tight = RoomNameComposer {
+            budget: 18,
+            ..composer
+        };
+        assert_eq!(tight.compose("Chill", &stores).name, "[replay] [S] Chill");
+        let tighter = RoomNameComposer {
+            budget: 16,
+            ..tight
+        };
+        assert_eq!(tighter.compose("Chill", &stores).name, "[replay] Chill");
+    }
+
+    #[test]
+    fn composes_from_the_original() {
+        let composer = RoomNameComposer::default();
+        let first = composer.compose("Chill", &all()).name;
+        let previous = ComposedName {
+            original: "Chill".into(),
+            composed: first.clone(),
+            annotations: all(),
+        };
+
+        // our own output listed again
+        let base = ComposedName::base(Some(&previous), &first);
+        assert_eq!(base, "Chill");
+        assert_eq!(composer.compose(base, &all()).name, first);
+        assert_eq!(
+            composer.compose(base, &[Annotation::password()]).name,
+            "[p] Chill"
+        );
+
+        // the host renamed the room
+        assert_eq!(ComposedName::base(Some(&previous), "Chill 2"), "Chill 2");
+        assert_eq!(ComposedName::base(None, "Chill"), "Chill");
+    }
+}
@@ -0,0 +1,307 @@
+//! Notes on lobby rooms, persisted across sessions.
+
+use std::{
+    borrow::Borrow,
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use photon_lib::{highlevel::structs::RoomInfoView, PhotonHashmap};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::{
+    config::versioned::{self, Migration, VersionError, Versioned},
+    protocol::properties::HOST_USER_ID_PROPERTIES,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomFlag {
+    /// Highlight the room in the lobby.
+    Favorite,
+    /// Hide the room from the lobby.
+    Blocked,
+}
+
+/// What a note applies to.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RoomKey {
+    RoomName(String),
+    HostUserId(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomNote {
+    pub flag: Option<RoomFlag>,
+    pub note: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct StoreFile {
+    rooms: BTreeMap<String, RoomNote>,
+    hosts: BTreeMap<String, RoomNote>,
+}
+
+impl Versioned for StoreFile {
+    const NAME: &'static str = "room notes";
+    const MIGRATIONS: &'static [Migration] = &[drop_legacy_version];
+}
+
+/// Version 1 had its own `version` field, which was always 1.
+fn drop_legacy_version(mut value: Value) -> anyhow::Result<Value> {
+    let object = value.as_object_mut().context("not an object")?;
+    match object.remove("version") {
+        Some(v) if v == 1 => Ok(value),
+        v => anyhow::bail!("unexpected legacy version {v:?}"),
+    }
+}
+
+/// Notes on rooms, keyed by room name or by the user id of the host.
+#[derive(Debug, Clone, Default)]
+pub struct RoomNoteStore {
+    /// Where the store is saved. If not set, the store is only kept in memory.
+    path: Option<PathBuf>,
+    data: StoreFile,
+}
+
+impl RoomNoteStore {
+    /// Loads the store from the given file.
+    ///
+    /// This never fails. If the file is missing or corrupt, an empty store is returned that will overwrite the file
+    /// when it is changed. Notes written by a newer version are left alone, and changes are then not saved.
+    pub fn load(path: &Path) -> Self {
+        let data = match versioned::read_optional(path) {
+            Ok(Some(data)) => data,
+            Ok(None) => StoreFile::default(),
+            Err(e) if matches!(e.downcast_ref(), Some(VersionError::NewerVersion { .. })) => {
+                warn!(
+                    path = format!("{path:?}"),
+                    "Could not load room notes, changes won't be saved: {e:#}"
+                );
+                return Self::default();
+            }
+            Err(e) => {
+                warn!(
+                    path = format!("{path:?}"),
+                    "Could not read room notes, starting with empty notes: {e:#}"
+                );
+                StoreFile::default()
+            }
+        };
+
+        Self {
+            path: Some(path.to_owned()),
+            data,
+        }
+    }
+
+    /// Where the store is saved, if anywhere.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub(super) fn save(&mut self) -> anyhow::Result<()> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        versioned::write(path, &self.data)?;
+        debug!("Saved room notes");
+        Ok(())
+    }
+
+    fn map_mut(&mut self, key: RoomKey) -> (&mut BTreeMap<String, RoomNote>, String) {
+        match key {
+            RoomKey::RoomName(n) => (&mut self.data.rooms, n),
+            RoomKey::HostUserId(u) => (&mut self.data.hosts, u),
+        }
+    }
+
+    pub fn get(&self, key: &RoomKey) -> Option<&RoomNote> {
+        match key {
+            RoomKey::RoomName(n) => self.data.rooms.get(n),
+            RoomKey::HostUserId(u) => self.data.hosts.get(u),
+        }
+    }
+
+    /// Adds or replaces a note and saves the store.
+    pub fn set(&mut self, key: RoomKey, note: RoomNote) -> anyhow::Result<()> {
+        let (map, key) = self.map_mut(key);
+        map.insert(key, note);
+        self.save()
+    }
+
+    /// Removes a note and saves the store. Returns the removed note.
+    pub fn remove(&mut self, key: &RoomKey) -> anyhow::Result<Option<RoomNote>> {
+        let (map, key) = self.map_mut(key.clone());
+        let removed = map.remove(&key);
+        if removed.is_some() {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// Replaces all notes and saves the store.
+    pub fn replace(
+        &mut self,
+        rooms: BTreeMap<String, RoomNote>,
+        hosts: BTreeMap<String, RoomNote>,
+    ) -> anyhow::Result<()> {
+        self.data.rooms = rooms;
+        self.data.hosts = hosts;
+        self.save()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (RoomKey, &RoomNote)> {
+        let rooms = self
+            .data
+            .rooms
+            .iter()
+            .map(|(k, v)| (RoomKey::RoomName(k.clone()), v));
+        let hosts = self
+            .data
+            .hosts
+            .iter()
+            .map(|(k, v)| (RoomKey::HostUserId(k.clone()), v));
+        rooms.chain(hosts)
+    }
+
+    /// Finds the note for a lobby room. Notes on the room's host take precedence over notes on the room's name.
+    pub fn find_for_room(
+        &self,
+        room_name: &str,
+        room: &RoomInfoView<impl Borrow<PhotonHashmap>>,
+    ) -> Option<&RoomNote> {
+        let host_note = HOST_USER_ID_PROPERTIES.iter().find_map(|property| {
+            room.custom_str(property)
+                .and_then(|user_id| self.data.hosts.get(user_id))
+        });
+
+        host_note.or_else(|| self.data.rooms.get(room_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::{
+        highlevel::structs::RoomInfoView, indexmap::indexmap, photon_data_type::PhotonDataType,
+        PhotonHashmap,
+    };
+
+    use super::{RoomFlag, RoomKey, RoomNote, RoomNoteStore, StoreFile};
+    use crate::config::versioned;
+
+    /// A file of every version that was ever written.
+    const FIXTURES: [&str; 2] = [
+        include_str!("../../fixtures/room_notes/v1.json"),
+        include_str!("../../fixtures/room_notes/v2.json"),
+    ];
+
+    fn room(host: Option<&str>) -> RoomInfoView {
+        RoomInfoView(match host {
+            Some(host) => indexmap! {
+                PhotonDataType::String("hostUserID".into()) => PhotonDataType::String(host.into())
+            },
+            None => PhotonHashmap::new(),
+        })
+    }
+
+    #[test]
+    fn host_note_takes_precedence() {
+        let mut store = RoomNoteStore::default();
+        let blocked = RoomNote {
+            flag: Some(RoomFlag::Blocked),
+            note: "cheater".into(),
+        };
+        let favorite = RoomNote {
+            flag: Some(RoomFlag::Favorite),
+            note: String::new(),
+        };
+        store
+            .set(RoomKey::HostUserId("abc".into()), blocked.clone())
+            .unwrap();
+        store
+            .set(RoomKey::RoomName("room".into()), favorite.clone())
+            .unwrap();
+
+        assert_eq!(
+            store.find_for_room("room", &room(Some("abc"))),
+            Some(&blocked)
+        );
+        assert_eq!(store.find_for_room("room", &room(None)), Some(&favorite));
+        assert_eq!(store.find_for_room("other", &room(Some("def"))), None);
+    }
+
+    #[test]
+    fn survives_corrupt_file() {
+        let path =
+            std::env::temp_dir().join(format!("bfhax_room_notes_{}.json", std::process::id()));
+        std::fs::write(&path, b"{ not json").unwrap();
+
+        let mut store = RoomNoteStore::load(&path);
+        assert_eq!(store.iter().count(), 0);
+
+        store
+            .set(RoomKey::RoomName("room".into()), RoomNote::default())
+            .unwrap();
+        let reloaded = RoomNoteStore::load(&path);
+        assert_eq!(
+            reloaded.get(&RoomKey::RoomName("room".into())),
+            Some(&RoomNote::default())
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn loads_every_version() {
+        let expected = StoreFile {
+            rooms: [(
+                "pro lobby".to_string(),
+                RoomNote {
+                    flag: Some(RoomFlag::Favorite),
+                    note: "good ping".into(),
+                },
+            )]
+            .into(),
+            hosts: [(
+                "8a2b4f".to_string(),
+                RoomNote {
+                    flag: Some(RoomFlag::Blocked),
+                    note: "aimbot".into(),
+                },
+            )]
+            .into(),
+        };
+        for fixture in FIXTURES {
+            let loaded: StoreFile = versioned::from_slice(fixture.as_bytes()).unwrap();
+            assert_eq!(loaded, expected);
+        }
+
+        // saving writes the latest version
+        let saved = String::from_utf8(versioned::to_vec_pretty(&expected).unwrap()).unwrap();
+        assert_eq!(saved, FIXTURES[FIXTURES.len() - 1].trim_end());
+    }
+
+    #[test]
+    fn keeps_newer_file() {
+        let path = std::env::temp_dir().join(format!(
+            "bfhax_room_notes_newer_{}.json",
+            std::process::id()
+        ));
+        let newer = r#"{ "schema_version": 999, "rooms": {}, "hosts": {}, "tags": [] }"#;
+        std::fs::write(&path, newer).unwrap();
+
+        let mut store = RoomNoteStore::load(&path);
+        store
+            .set(RoomKey::RoomName("room".into()), RoomNote::default())
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), newer);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
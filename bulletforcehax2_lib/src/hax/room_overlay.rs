@@ -0,0 +1,470 @@
+//! Data that features attach to lobby rooms, kept across game list refreshes.
+//!
+//! The [room cache](super::lobby_cache) only holds what the server listed, and every full game list replaces it.
+//! What features derive from the listings, like how far along a room's round is, which store id and version the room
+//! was first listed with or what its name was composed into, lives in the overlay instead, which outlives the listings
+//! and the lobby connection. [RoomView] puts the two together.
+//!
+//! Entries are keyed by room id, the name Photon lists the room under. A room listed under an id the overlay doesn't
+//! know is matched by its [RoomIdentity] instead, so a room that comes back under another id, eg. because its host
+//! recreated it, keeps its data. Only entries whose id isn't listed anymore are matched that way. Entries of rooms
+//! that weren't listed for [RoomOverlaySettings::ttl] are dropped.
+
+use std::time::{Duration, Instant};
+
+use photon_lib::{
+    highlevel::structs::RoomInfoView, indexmap::IndexMap, photon_data_type::PhotonDataType,
+    PhotonHashmap,
+};
+use tracing::debug;
+
+use super::{
+    lobby_cache::{CachedRoom, RoomCache},
+    match_phase::{PhaseEstimate, RoundTracker},
+    room_names::ComposedName,
+    HaxState,
+};
+use crate::protocol::properties::BulletForceRoomProperties;
+
+/// What else identifies a room when its id isn't known.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RoomIdentity {
+    /// Nothing, rooms under a new id start over.
+    IdOnly,
+    /// The room's name and map.
+    #[default]
+    NameAndMap,
+    /// The user id of the room's host.
+    Host,
+}
+
+impl RoomIdentity {
+    fn of(self, room: &RoomInfoView<&PhotonHashmap>) -> Option<String> {
+        match self {
+            RoomIdentity::IdOnly => None,
+            RoomIdentity::NameAndMap => {
+                Some(format!("{}\0{}", room.room_name()?, room.map_name()?))
+            }
+            RoomIdentity::Host => room.host_user_id().map(str::to_string),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomOverlaySettings {
+    pub identity: RoomIdentity,
+    /// How long an entry is kept after its room was last listed.
+    pub ttl: Duration,
+}
+
+impl Default for RoomOverlaySettings {
+    fn default() -> Self {
+        Self {
+            identity: RoomIdentity::default(),
+            ttl: Duration::from_secs(30 * 60),
+        }
+    }
+}
+
+/// What the features know about a room beyond its listing.
+#[derive(Debug, Clone)]
+pub struct OverlayEntry {
+    /// How the room's rounds went since it was first listed.
+    pub round: RoundTracker,
+    /// The store id the room was first listed with, before any feature rewrote it.
+    pub original_store_id: Option<String>,
+    /// The game version the room was first listed with, before any feature rewrote it.
+    pub original_version: Option<String>,
+    /// How the room's name was last [composed](super::room_names) from the name the server listed.
+    pub name: Option<ComposedName>,
+    /// Values other features attached to the room, by key.
+    values: IndexMap<String, PhotonDataType>,
+    identity: Option<String>,
+    last_seen: Instant,
+}
+
+impl OverlayEntry {
+    fn new(now: Instant) -> Self {
+        Self {
+            round: RoundTracker::default(),
+            original_store_id: None,
+            original_version: None,
+            name: None,
+            values: IndexMap::new(),
+            identity: None,
+            last_seen: now,
+        }
+    }
+
+    pub fn value(&self, key: &str) -> Option<&PhotonDataType> {
+        self.values.get(key)
+    }
+
+    /// When the room was last listed or updated.
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+}
+
+/// The overlay entries by room id, least recently listed first.
+#[derive(Debug, Default)]
+pub struct RoomOverlay {
+    entries: IndexMap<String, OverlayEntry>,
+    /// How many entries were dropped because their room wasn't listed for the TTL.
+    expired: u64,
+}
+
+impl RoomOverlay {
+    /// Updates the entries of the rooms in a game list. The cache must already hold the list, so rooms are identified
+    /// by everything known about them and not only by what an update changed.
+    pub fn observe(
+        &mut self,
+        games: &PhotonHashmap,
+        cache: &RoomCache,
+        is_update: bool,
+        now: Instant,
+        settings: &RoomOverlaySettings,
+    ) {
+        for (id, properties) in games {
+            let (id, properties) = match (id, properties) {
+                (PhotonDataType::String(id), PhotonDataType::Hashtable(properties)) => {
+                    (id, properties)
+                }
+                _ => continue,
+            };
+            // removed rooms keep their entry until it expires, in case they come back
+            let room = match cache.get(id) {
+                Some(room) => room.view(),
+                None => continue,
+            };
+            let identity = settings.identity.of(&room);
+            let mut entry = match self.entries.shift_remove(id) {
+                Some(entry) => entry,
+                None => match self.take_by_identity(identity.as_deref(), cache) {
+                    Some((previous_id, entry)) => {
+                        debug!(previous_id, id, "Room listed under a new id");
+                        entry
+                    }
+                    None => {
+                        let mut entry = OverlayEntry::new(now);
+                        // rooms that are new to an update were just created, so their first round starts
+                        if is_update {
+                            entry.round.start_round(now);
+                        }
+                        entry
+                    }
+                },
+            };
+            entry.round.observe(&RoomInfoView(properties), now);
+            entry.original_store_id = entry
+                .original_store_id
+                .or_else(|| room.store_id().map(str::to_string));
+            entry.original_version = entry
+                .original_version
+                .or_else(|| room.game_version().map(str::to_string));
+            entry.identity = identity;
+            entry.last_seen = now;
+            self.entries.insert(id.clone(), entry);
+        }
+        self.expire(now, settings.ttl);
+    }
+
+    /// Takes the entry of a room that isn't listed anymore but has the same identity.
+    fn take_by_identity(
+        &mut self,
+        identity: Option<&str>,
+        cache: &RoomCache,
+    ) -> Option<(String, OverlayEntry)> {
+        let identity = identity?;
+        let index = self.entries.iter().position(|(id, entry)| {
+            entry.identity.as_deref() == Some(identity) && cache.get(id).is_none()
+        })?;
+        self.entries.shift_remove_index(index)
+    }
+
+    fn expire(&mut self, now: Instant, ttl: Duration) {
+        let expired = self
+            .entries
+            .values()
+            .take_while(|e| now.saturating_duration_since(e.last_seen) > ttl)
+            .count();
+        self.expired += expired as u64;
+        self.entries.drain(..expired);
+    }
+
+    pub fn get(&self, id: &str) -> Option<&OverlayEntry> {
+        self.entries.get(id)
+    }
+
+    /// Notes how a room's name was composed. Returns whether it had an entry.
+    pub fn set_name(&mut self, id: &str, name: ComposedName) -> bool {
+        match self.entries.get_mut(id) {
+            Some(entry) => {
+                entry.name = Some(name);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Attaches a value to a room that has an entry. Returns whether it had one.
+    pub fn set_value(&mut self, id: &str, key: impl Into<String>, value: PhotonDataType) -> bool {
+        match self.entries.get_mut(id) {
+            Some(entry) => {
+                entry.values.insert(key.into(), value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn expired(&self) -> u64 {
+        self.expired
+    }
+}
+
+/// A cached lobby room together with its overlay entry.
+#[derive(Debug, Clone, Copy)]
+pub struct RoomView<'a> {
+    pub id: &'a str,
+    /// The room as the server listed it.
+    pub room: &'a CachedRoom,
+    pub overlay: Option<&'a OverlayEntry>,
+}
+
+impl<'a> RoomView<'a> {
+    pub fn new(id: &'a str, room: &'a CachedRoom, overlay: &'a RoomOverlay) -> Self {
+        Self {
+            id,
+            room,
+            overlay: overlay.get(id),
+        }
+    }
+
+    /// The properties the server listed the room with.
+    pub fn properties(&self) -> RoomInfoView<&'a PhotonHashmap> {
+        self.room.view()
+    }
+
+    /// Estimates how far along the room's current round is.
+    pub fn phase(&self, now: Instant) -> PhaseEstimate {
+        match self.overlay {
+            Some(overlay) => overlay.round.estimate(now, None, None),
+            None => PhaseEstimate::UNKNOWN,
+        }
+    }
+
+    pub fn original_store_id(&self) -> Option<&'a str> {
+        self.overlay?.original_store_id.as_deref()
+    }
+
+    pub fn original_version(&self) -> Option<&'a str> {
+        self.overlay?.original_version.as_deref()
+    }
+
+    pub fn value(&self, key: &str) -> Option<&'a PhotonDataType> {
+        self.overlay?.value(key)
+    }
+}
+
+impl HaxState {
+    /// A room of the current lobby, with what the features know about it.
+    pub fn lobby_room<'a>(&'a self, id: &str) -> Option<RoomView<'a>> {
+        let (_, lobby) = self.lobby_state.as_ref()?;
+        let (id, room) = lobby.rooms.get_key_value(id)?;
+        Some(RoomView::new(id, room, &self.room_overlay))
+    }
+
+    /// The rooms of the current lobby, least recently updated first.
+    pub fn lobby_rooms(&self) -> impl Iterator<Item = RoomView<'_>> {
+        self.lobby_state
+            .iter()
+            .flat_map(|(_, lobby)| lobby.rooms.iter())
+            .map(|(id, room)| RoomView::new(id, room, &self.room_overlay))
+    }
+
+    /// Attaches a value to a lobby room, which stays with it until the room is gone for
+    /// [RoomOverlaySettings::ttl]. Returns whether the room is known.
+    pub fn set_room_value(
+        &mut self,
+        id: &str,
+        key: impl Into<String>,
+        value: PhotonDataType,
+    ) -> bool {
+        self.room_overlay.set_value(id, key, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use photon_lib::{indexmap::indexmap, photon_data_type::PhotonDataType, PhotonHashmap};
+
+    use super::{RoomIdentity, RoomOverlay, RoomOverlaySettings, RoomView};
+    use crate::{
+        hax::{lobby_cache::RoomCache, match_phase::MatchPhase},
+        testsupport::string,
+    };
+
+    fn room(name: &str, map: &str, store_id: &str) -> PhotonDataType {
+        PhotonDataType::Hashtable(indexmap! {
+            string("roomName") => string(name),
+            string("mapName") => string(map),
+            string("storeID") => string(store_id),
+            string("roundTime") => PhotonDataType::Integer(600),
+        })
+    }
+
+    /// Feeds a game list to the cache and the overlay, like the lobby hook does.
+    struct Lobby {
+        cache: RoomCache,
+        overlay: RoomOverlay,
+        settings: RoomOverlaySettings,
+    }
+
+    impl Lobby {
+        fn new(identity: RoomIdentity) -> Self {
+            Self {
+                cache: RoomCache::default(),
+                overlay: RoomOverlay::default(),
+                settings: RoomOverlaySettings {
+                    identity,
+                    ..Default::default()
+                },
+            }
+        }
+
+        fn list(&mut self, games: PhotonHashmap, is_update: bool, now: Instant) {
+            match is_update {
+                true => self.cache.update(&games, now),
+                false => self.cache.replace(&games, now),
+            };
+            self.overlay
+                .observe(&games, &self.cache, is_update, now, &self.settings);
+        }
+
+        fn view(&self, id: &str) -> RoomView<'_> {
+            let (id, room) = self.cache.get_key_value(id).unwrap();
+            RoomView::new(id, room, &self.overlay)
+        }
+    }
+
+    #[test]
+    fn survives_refreshes() {
+        let mut lobby = Lobby::new(RoomIdentity::default());
+        let now = Instant::now();
+        lobby.list(
+            indexmap! { string("a") => string("placeholder") },
+            false,
+            now,
+        );
+        lobby.list(
+            indexmap! { string("a") => room("Friends", "Urban", "STEAM") },
+            true,
+            now,
+        );
+        assert!(lobby
+            .overlay
+            .set_value("a", "tag", PhotonDataType::Integer(7)));
+        assert!(!lobby
+            .overlay
+            .set_value("b", "tag", PhotonDataType::Integer(7)));
+
+        // a full list replaces the cached room, the overlay stays
+        let later = now + Duration::from_secs(60);
+        lobby.list(
+            indexmap! { string("a") => room("Friends", "Urban", "BALYZE_WEB") },
+            false,
+            later,
+        );
+        let view = lobby.view("a");
+        assert_eq!(view.properties().custom_str("storeID"), Some("BALYZE_WEB"));
+        assert_eq!(view.original_store_id(), Some("STEAM"));
+        assert_eq!(view.value("tag"), Some(&PhotonDataType::Integer(7)));
+        // the round started with the update, not with the full list
+        assert_eq!(view.phase(later).phase, MatchPhase::Active);
+    }
+
+    #[test]
+    fn matches_rooms_under_new_ids_by_identity() {
+        for (identity, kept) in [
+            (RoomIdentity::NameAndMap, true),
+            (RoomIdentity::IdOnly, false),
+            // the rooms don't list a host
+            (RoomIdentity::Host, false),
+        ] {
+            let mut lobby = Lobby::new(identity);
+            let now = Instant::now();
+            lobby.list(
+                indexmap! {
+                    string("a") => room("Friends", "Urban", "STEAM"),
+                    string("b") => room("Friends", "Outpost", "STEAM"),
+                },
+                false,
+                now,
+            );
+            lobby.overlay.set_value("a", "tag", string("a"));
+            lobby.overlay.set_value("b", "tag", string("b"));
+
+            // a room with the same name and map under a new id takes over the entry of the room that is gone
+            lobby.list(
+                indexmap! {
+                    string("b") => room("Friends", "Outpost", "STEAM"),
+                    string("c") => room("Friends", "Urban", "STEAM"),
+                    string("d") => room("Friends", "Outpost", "STEAM"),
+                },
+                false,
+                now,
+            );
+            let expected = kept.then(|| string("a"));
+            assert_eq!(
+                lobby.view("c").value("tag"),
+                expected.as_ref(),
+                "{identity:?}"
+            );
+            assert_eq!(lobby.overlay.get("a").is_some(), !kept, "{identity:?}");
+            // the entry of a room that is still listed isn't taken
+            assert_eq!(lobby.view("b").value("tag"), Some(&string("b")));
+            assert_eq!(lobby.view("d").value("tag"), None);
+        }
+    }
+
+    #[test]
+    fn forgets_rooms_that_are_gone() {
+        let mut lobby = Lobby::new(RoomIdentity::IdOnly);
+        let now = Instant::now();
+        lobby.list(
+            indexmap! {
+                string("a") => room("A", "Urban", "STEAM"),
+                string("b") => room("B", "Urban", "STEAM"),
+            },
+            false,
+            now,
+        );
+
+        let ttl = lobby.settings.ttl;
+        lobby.list(
+            indexmap! { string("b") => room("B", "Urban", "STEAM") },
+            false,
+            now + ttl / 2,
+        );
+        assert_eq!(lobby.overlay.len(), 2);
+        lobby.list(
+            indexmap! { string("b") => room("B", "Urban", "STEAM") },
+            false,
+            now + ttl + Duration::from_secs(1),
+        );
+        assert!(lobby.overlay.get("a").is_none());
+        assert!(lobby.overlay.get("b").is_some());
+        assert_eq!(lobby.overlay.expired(), 1);
+    }
+}
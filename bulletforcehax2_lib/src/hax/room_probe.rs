@@ -0,0 +1,1028 @@
+//! Finding out who is in a lobby room without joining it through the client.
+//!
+//! The lobby only lists a room's properties, which say how many players it has but not who they are. Photon has no
+//! operation that lists a room's players from the lobby, so [HaxState::probe_room] makes do with two others, and each
+//! [RoomProbe] says which [ProbeMethod] it came from:
+//!
+//! - [FIND_FRIENDS](operation_code::FIND_FRIENDS) asks the lobby which room each of a list of user ids is in. It only
+//!   finds players we know the user id of: the room's host, the [watchlist](super::watchlist) and the players of
+//!   earlier matches, unless the ids are given. Nobody notices it, but it can't tell how many players it missed.
+//! - Joining the room on a connection of our own and leaving right away gets the full player list and exact count.
+//!   Everyone in the room sees a nameless player join and leave, so it's only done when asked for and confirmed with
+//!   [ProbeOptions::confirm_visible_join]. When both are allowed, the room is joined only if looking up the user ids
+//!   found nobody.
+//!
+//! Lobby responses don't say which request they answer, but come in the order the requests were sent, like the
+//! GET_PROPERTIES responses of a [property refresh](super::property_refresh). [PendingProbes] keeps that order so the
+//! client never sees the answers to our requests. The join gets the game server's address from the lobby, and
+//! authenticates there with the token of the lobby's authentication, which is why the lobby has to be connected
+//! through the proxy from the start.
+//!
+//! Probes are paced by [ProbeSettings], as every probe is a request to the servers that the client didn't make.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::Display,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context;
+use futures_util::{lock::Mutex, SinkExt, StreamExt};
+use hyper::header::{HeaderName, HeaderValue};
+use photon_lib::{
+    highlevel::{
+        constants::{actor_properties, operation_code, parameter_code},
+        parameters::Parameters,
+    },
+    indexmap::{indexmap, IndexMap},
+    photon_data_type::PhotonDataType,
+    photon_message::{OperationRequest, OperationResponse, PhotonMessage},
+    protocol_version::ProtocolVersion,
+    ParameterMap,
+};
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, warn};
+
+use super::{
+    bandwidth::feature,
+    drop_log::DropReason,
+    events::HaxEvent,
+    journal::{ChangedKey, Section},
+    HaxState,
+};
+use crate::{
+    error::HaxError,
+    protocol::properties::BulletForceRoomProperties,
+    proxy::{
+        protocol_pin::SUBPROTOCOL_HEADER,
+        watchdog::{reconnect_upstream, UpstreamTarget},
+    },
+};
+
+/// How many user ids are looked up at most in one probe.
+pub const MAX_CANDIDATES: usize = 64;
+
+/// How many requests can wait for a response, see [property_refresh](super::property_refresh).
+const MAX_PENDING: usize = 32;
+
+/// How a [RoomProbe] found the players.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProbeMethod {
+    /// Looking up known user ids with FIND_FRIENDS. Only finds players we knew of.
+    FindFriends,
+    /// Joining the room and leaving right away, which everyone in the room sees. Finds every player.
+    JoinLeave,
+}
+
+impl ProbeMethod {
+    /// Whether the method finds every player in the room, and so the exact count.
+    pub fn is_exhaustive(self) -> bool {
+        self == ProbeMethod::JoinLeave
+    }
+}
+
+impl Display for ProbeMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeMethod::FindFriends => write!(f, "find friends"),
+            ProbeMethod::JoinLeave => write!(f, "join and leave"),
+        }
+    }
+}
+
+/// A player a probe found in the room.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProbedPlayer {
+    pub user_id: Option<String>,
+    /// The nickname they had in the room, or when we last saw them if the probe only found their user id.
+    pub nickname: Option<String>,
+}
+
+/// Who a probe found in a lobby room, kept with the room in the [lobby cache](super::lobby_cache).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomProbe {
+    pub room_id: String,
+    pub method: ProbeMethod,
+    pub players: Vec<ProbedPlayer>,
+    /// How many players were in the room, not counting us. Only known if the method is
+    /// [exhaustive](ProbeMethod::is_exhaustive).
+    pub player_count: Option<usize>,
+    /// How many user ids were looked up, for [ProbeMethod::FindFriends].
+    pub looked_up: usize,
+    pub probed_at: SystemTime,
+}
+
+impl Display for RoomProbe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.player_count {
+            Some(count) => write!(f, "{count} players by {}", self.method),
+            None => write!(
+                f,
+                "{} of {} looked up players by {}",
+                self.players.len(),
+                self.looked_up,
+                self.method
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeSettings {
+    /// How long to wait between starting two probes.
+    pub min_interval: Duration,
+    /// How many probes can run at the same time.
+    pub max_concurrent: usize,
+    /// How long a probe can take before it's given up on. A join that takes longer is left unfinished.
+    pub timeout: Duration,
+}
+
+impl Default for ProbeSettings {
+    fn default() -> Self {
+        Self {
+            min_interval: Duration::from_secs(5),
+            max_concurrent: 2,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// How to probe a room, see [HaxState::probe_room].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProbeOptions {
+    /// The user ids to look for. If empty, the user ids we know of are used.
+    pub user_ids: Vec<String>,
+    /// Whether to join the room if looking up user ids finds nobody, or if there are none to look up.
+    pub join: bool,
+    /// Confirms that joining may be seen by everyone in the room. Joining isn't done without it.
+    pub confirm_visible_join: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum ProbeError {
+    #[error("not connected to the lobby")]
+    NotInLobby,
+    #[error("the lobby doesn't list room {0}")]
+    UnknownRoom(String),
+    #[error("probed too recently, wait another {} ms", .0.as_millis())]
+    TooSoon(Duration),
+    #[error("{0} probes are running already")]
+    TooMany(usize),
+    #[error("joining is seen by everyone in the room, it has to be confirmed")]
+    JoinNotConfirmed,
+    #[error("there are no user ids to look for, and joining wasn't allowed")]
+    NoCandidates,
+    #[error("the lobby authenticated before the proxy saw it, so there's no token to join with")]
+    NoToken,
+    #[error(transparent)]
+    Injection(#[from] HaxError),
+}
+
+/// Who is waiting for the response to a FIND_FRIENDS or JOIN_GAME request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ProbeResponse {
+    /// The client.
+    Forward,
+    /// Our lookup of the given players in a room.
+    FindFriends {
+        room_id: String,
+        candidates: Vec<ProbedPlayer>,
+        /// Whether to join the room if nobody was found.
+        then_join: bool,
+        sent: Instant,
+    },
+    /// Our join of a room.
+    Join { room_id: String, sent: Instant },
+}
+
+impl ProbeResponse {
+    fn sent(&self) -> Option<Instant> {
+        match self {
+            ProbeResponse::Forward => None,
+            ProbeResponse::FindFriends { sent, .. } | ProbeResponse::Join { sent, .. } => {
+                Some(*sent)
+            }
+        }
+    }
+}
+
+/// The lobby requests whose responses are intercepted, and what is needed to join rooms, see [room_probe](self).
+#[derive(Debug, Clone, Default)]
+pub struct PendingProbes {
+    find_friends: VecDeque<ProbeResponse>,
+    joins: VecDeque<ProbeResponse>,
+    /// The parameters of the client's lobby authentication, with the token the lobby answered with.
+    authentication: Option<ParameterMap>,
+}
+
+impl PendingProbes {
+    fn queue(&mut self, operation_code: u8) -> Option<&mut VecDeque<ProbeResponse>> {
+        match operation_code {
+            operation_code::FIND_FRIENDS => Some(&mut self.find_friends),
+            operation_code::JOIN_GAME => Some(&mut self.joins),
+            _ => None,
+        }
+    }
+
+    fn push(&mut self, operation_code: u8, response: ProbeResponse) {
+        if let Some(queue) = self.queue(operation_code) {
+            if queue.len() == MAX_PENDING {
+                queue.pop_front();
+            }
+            queue.push_back(response);
+        }
+    }
+
+    /// Notes a request of the client, whose response goes to it.
+    pub fn forwarded(&mut self, operation_code: u8) {
+        self.push(operation_code, ProbeResponse::Forward);
+    }
+
+    /// Takes the oldest request, when its response arrives. Responses nobody waits for are forwarded.
+    fn respond(&mut self, operation_code: u8) -> ProbeResponse {
+        self.queue(operation_code)
+            .and_then(VecDeque::pop_front)
+            .unwrap_or(ProbeResponse::Forward)
+    }
+
+    /// How many of our requests were sent in the last `timeout` and weren't answered yet.
+    fn running(&self, now: Instant, timeout: Duration) -> usize {
+        self.find_friends
+            .iter()
+            .chain(&self.joins)
+            .filter_map(ProbeResponse::sent)
+            .filter(|sent| now.saturating_duration_since(*sent) < timeout)
+            .count()
+    }
+
+    /// Keeps the parameters the client authenticated with.
+    pub fn authenticating(&mut self, parameters: &ParameterMap) {
+        self.authentication = Some(parameters.clone());
+    }
+
+    /// Replaces the token the client authenticated with by the one the lobby answered with, which the game servers
+    /// expect.
+    pub fn authenticated(&mut self, token: Option<&PhotonDataType>) {
+        if let (Some(authentication), Some(token)) = (&mut self.authentication, token) {
+            authentication.insert(parameter_code::TOKEN, token.clone());
+        }
+    }
+}
+
+/// When probes were started, across lobby connections.
+#[derive(Debug, Default)]
+pub(super) struct ProbePacing {
+    last_started: Option<Instant>,
+    /// How many joins are running on connections of their own.
+    joining: usize,
+}
+
+/// What the hook does with a lobby response, see [HaxState::answer_room_probe].
+pub(super) enum ProbeAnswer {
+    Forward,
+    /// The response was to our request. If it told us where to join, the join has to be started.
+    Drop(DropReason, Option<Box<JoinProbe>>),
+}
+
+/// A join of a room on a connection of our own, see [join_and_leave].
+pub(super) struct JoinProbe {
+    room_id: String,
+    target: UpstreamTarget,
+    authentication: Vec<u8>,
+    timeout: Duration,
+}
+
+/// The FIND_FRIENDS request for the given user ids.
+pub fn find_friends_request(user_ids: Vec<String>) -> OperationRequest {
+    OperationRequest {
+        operation_code: operation_code::FIND_FRIENDS,
+        parameters: indexmap! {
+            parameter_code::FIND_FRIENDS_REQUEST_LIST => PhotonDataType::StringArray(user_ids),
+        },
+    }
+}
+
+/// The JOIN_GAME request for a room, sent to the lobby and then to the game server.
+pub fn join_request(room_id: &str) -> OperationRequest {
+    OperationRequest {
+        operation_code: operation_code::JOIN_GAME,
+        parameters: indexmap! {
+            parameter_code::ROOM_NAME => PhotonDataType::String(room_id.to_string()),
+            parameter_code::BROADCAST => PhotonDataType::Boolean(true),
+        },
+    }
+}
+
+impl HaxState {
+    /// Finds out who is in a lobby room, see [room_probe](self). Returns how the room is probed first, the result is
+    /// kept with the room in the lobby cache when it arrives and announced as [HaxEvent::RoomProbed].
+    ///
+    /// Fails if the last probe started less than [ProbeSettings::min_interval] ago, or if too many are running.
+    pub fn probe_room(
+        &mut self,
+        room_id: &str,
+        options: ProbeOptions,
+    ) -> Result<ProbeMethod, ProbeError> {
+        let now = Instant::now();
+        if options.join && !options.confirm_visible_join {
+            return Err(ProbeError::JoinNotConfirmed);
+        }
+        let settings = self.room_probe_settings;
+        let candidates = match &self.lobby_state {
+            Some((_, lobby)) => match lobby.rooms.get(room_id) {
+                Some(room) => self.probe_candidates(room.view().host_user_id(), options.user_ids),
+                None => return Err(ProbeError::UnknownRoom(room_id.to_string())),
+            },
+            None => return Err(ProbeError::NotInLobby),
+        };
+        if let Some(last_started) = self.room_probes.last_started {
+            let wait = settings
+                .min_interval
+                .saturating_sub(now.saturating_duration_since(last_started));
+            if !wait.is_zero() {
+                return Err(ProbeError::TooSoon(wait));
+            }
+        }
+        let (proxy, lobby) = match &mut self.lobby_state {
+            Some(x) => x,
+            None => return Err(ProbeError::NotInLobby),
+        };
+        let running = lobby.probes.running(now, settings.timeout) + self.room_probes.joining;
+        if running >= settings.max_concurrent {
+            return Err(ProbeError::TooMany(running));
+        }
+        if options.join && lobby.probes.authentication.is_none() {
+            return Err(ProbeError::NoToken);
+        }
+
+        let room_id = room_id.to_string();
+        let method = match (candidates.is_empty(), options.join) {
+            (false, then_join) => {
+                let user_ids = candidates
+                    .iter()
+                    .filter_map(|c| c.user_id.clone())
+                    .collect();
+                proxy.queue_server(
+                    PhotonMessage::OperationRequest(find_friends_request(user_ids)),
+                    feature::ROOM_PROBE,
+                )?;
+                debug!(
+                    room_id,
+                    candidates = candidates.len(),
+                    "Looking up players of a room"
+                );
+                let response = ProbeResponse::FindFriends {
+                    room_id,
+                    candidates,
+                    then_join,
+                    sent: now,
+                };
+                lobby.probes.push(operation_code::FIND_FRIENDS, response);
+                ProbeMethod::FindFriends
+            }
+            (true, true) => {
+                proxy.queue_server(
+                    PhotonMessage::OperationRequest(join_request(&room_id)),
+                    feature::ROOM_PROBE,
+                )?;
+                debug!(room_id, "Joining a room to probe it");
+                let response = ProbeResponse::Join { room_id, sent: now };
+                lobby.probes.push(operation_code::JOIN_GAME, response);
+                ProbeMethod::JoinLeave
+            }
+            (true, false) => return Err(ProbeError::NoCandidates),
+        };
+        self.room_probes.last_started = Some(now);
+        Ok(method)
+    }
+
+    /// The players to look for in a room: the given user ids, or else the room's host, the watchlist and the players
+    /// of earlier matches, most recent first. Nicknames are taken from the match history.
+    fn probe_candidates(&self, host: Option<&str>, user_ids: Vec<String>) -> Vec<ProbedPlayer> {
+        let encountered = self
+            .match_history
+            .iter()
+            .rev()
+            .flat_map(|summary| &summary.players)
+            .filter_map(|p| Some((p.user_id.as_deref()?, p.nickname.as_deref())))
+            .collect::<Vec<_>>();
+        let nickname = |user_id: &str| {
+            encountered
+                .iter()
+                .find(|(id, _)| id.eq_ignore_ascii_case(user_id))
+                .and_then(|(_, nickname)| nickname.map(str::to_string))
+        };
+        let user_ids = match user_ids.is_empty() {
+            false => user_ids,
+            true => {
+                let watched = self
+                    .watchlist
+                    .entries()
+                    .iter()
+                    .filter_map(|e| match &e.target {
+                        super::watchlist::WatchTarget::UserId(id) => Some(id.as_str()),
+                        _ => None,
+                    });
+                let known = encountered.iter().map(|(id, _)| *id);
+                host.into_iter()
+                    .chain(watched)
+                    .chain(known)
+                    .map(str::to_string)
+                    .collect()
+            }
+        };
+
+        let mut seen = HashSet::new();
+        user_ids
+            .into_iter()
+            .filter(|id| !id.is_empty() && seen.insert(id.to_lowercase()))
+            .take(MAX_CANDIDATES)
+            .map(|id| ProbedPlayer {
+                nickname: nickname(&id),
+                user_id: Some(id),
+            })
+            .collect()
+    }
+
+    /// Notes a FIND_FRIENDS or JOIN_GAME request the client sent to the lobby, so its response isn't taken for ours.
+    pub(super) fn forwarded_to_lobby(&mut self, operation_code: u8) {
+        if let Some((_, lobby)) = &mut self.lobby_state {
+            lobby.probes.forwarded(operation_code);
+        }
+    }
+
+    /// Handles the lobby's response to a FIND_FRIENDS or JOIN_GAME request, if it was ours.
+    pub(super) fn answer_room_probe(&mut self, response: &OperationResponse) -> ProbeAnswer {
+        let now = Instant::now();
+        let settings = self.room_probe_settings;
+        let identity_randomizer = self.settings().identity_randomizer;
+        let (proxy, lobby) = match &mut self.lobby_state {
+            Some(x) => x,
+            None => return ProbeAnswer::Forward,
+        };
+        let pending = lobby.probes.respond(response.operation_code);
+        let method = match &pending {
+            ProbeResponse::Forward => return ProbeAnswer::Forward,
+            ProbeResponse::FindFriends { .. } => ProbeMethod::FindFriends,
+            ProbeResponse::Join { .. } => ProbeMethod::JoinLeave,
+        };
+        let accepted = response.return_code == 0;
+        let reason = DropReason::RoomProbe { method, accepted };
+
+        let refused = || {
+            let mut refusal = format!("return code {}", response.return_code);
+            if let Some(debug_message) = &response.debug_message {
+                refusal += &format!(": {debug_message}");
+            }
+            format!("the lobby refused the request ({refusal})")
+        };
+        match pending {
+            ProbeResponse::Forward => ProbeAnswer::Forward,
+            ProbeResponse::FindFriends { room_id, .. } if !accepted => {
+                self.room_probe_failed(room_id, method, refused());
+                ProbeAnswer::Drop(reason, None)
+            }
+            ProbeResponse::FindFriends {
+                room_id,
+                candidates,
+                then_join,
+                ..
+            } => {
+                let parameters = Parameters(&response.parameters);
+                let rooms = parameters
+                    .get_as::<&[String]>(parameter_code::FIND_FRIENDS_RESPONSE_ROOM_ID_LIST)
+                    .unwrap_or_default();
+                let looked_up = candidates.len();
+                let players = candidates
+                    .into_iter()
+                    .zip(rooms)
+                    .filter(|(_, room)| **room == room_id)
+                    .map(|(player, _)| player)
+                    .collect::<Vec<_>>();
+                if players.is_empty() && then_join {
+                    let joined = proxy.queue_server(
+                        PhotonMessage::OperationRequest(join_request(&room_id)),
+                        feature::ROOM_PROBE,
+                    );
+                    match joined {
+                        Ok(()) => {
+                            debug!(room_id, "Found nobody in the room, joining it instead");
+                            lobby.probes.push(
+                                operation_code::JOIN_GAME,
+                                ProbeResponse::Join { room_id, sent: now },
+                            );
+                        }
+                        Err(e) => {
+                            self.room_probe_failed(room_id, ProbeMethod::JoinLeave, e.to_string())
+                        }
+                    }
+                } else {
+                    self.room_probed(RoomProbe {
+                        room_id,
+                        method,
+                        players,
+                        player_count: None,
+                        looked_up,
+                        probed_at: SystemTime::now(),
+                    });
+                }
+                ProbeAnswer::Drop(reason, None)
+            }
+            ProbeResponse::Join { room_id, .. } => {
+                let address = Parameters(&response.parameters)
+                    .address()
+                    .map(str::to_string);
+                let (address, authentication) =
+                    match (accepted, address, lobby.probes.authentication.clone()) {
+                        (false, ..) => {
+                            self.room_probe_failed(room_id, method, refused());
+                            return ProbeAnswer::Drop(reason, None);
+                        }
+                        (true, Some(address), Some(authentication)) => (address, authentication),
+                        (true, None, _) => {
+                            let reason_text =
+                                "the lobby didn't say which game server the room is on";
+                            self.room_probe_failed(room_id, method, reason_text.into());
+                            return ProbeAnswer::Drop(reason, None);
+                        }
+                        (true, Some(_), None) => {
+                            self.room_probe_failed(
+                                room_id,
+                                method,
+                                ProbeError::NoToken.to_string(),
+                            );
+                            return ProbeAnswer::Drop(reason, None);
+                        }
+                    };
+                let uri = match address.parse() {
+                    Ok(uri) => self.proxy.config().route_upstream(&uri),
+                    Err(e) => {
+                        self.room_probe_failed(
+                            room_id,
+                            method,
+                            format!("bad game server address {address}: {e}"),
+                        );
+                        return ProbeAnswer::Drop(reason, None);
+                    }
+                };
+
+                let mut authentication = PhotonMessage::OperationRequest(OperationRequest {
+                    operation_code: operation_code::AUTHENTICATE,
+                    parameters: authentication,
+                });
+                // the token was given to the identity the servers know us by
+                if identity_randomizer {
+                    self.identity.randomize(&mut authentication, None);
+                }
+                let mut bytes = vec![];
+                if let Err(e) = authentication.to_websocket_bytes(&mut bytes) {
+                    self.room_probe_failed(
+                        room_id,
+                        method,
+                        format!("could not write the authentication: {e}"),
+                    );
+                    return ProbeAnswer::Drop(reason, None);
+                }
+
+                self.room_probes.joining += 1;
+                let target = UpstreamTarget {
+                    uri,
+                    headers: vec![
+                        (
+                            HeaderName::from_static("sec-websocket-version"),
+                            HeaderValue::from_static("13"),
+                        ),
+                        (
+                            HeaderName::from_static(SUBPROTOCOL_HEADER),
+                            HeaderValue::from_static(ProtocolVersion::GpBinaryV16.subprotocol()),
+                        ),
+                    ],
+                };
+                let join = JoinProbe {
+                    room_id,
+                    target,
+                    authentication: bytes,
+                    timeout: settings.timeout,
+                };
+                ProbeAnswer::Drop(reason, Some(Box::new(join)))
+            }
+        }
+    }
+
+    /// Keeps a probe's result with its room, and announces it.
+    fn room_probed(&mut self, probe: RoomProbe) {
+        info!(room_id = probe.room_id, "Probed room: {probe}");
+        if let Some((_, lobby)) = &mut self.lobby_state {
+            if let Some(room) = lobby.rooms.get_mut(&probe.room_id) {
+                room.probe = Some(probe.clone());
+                self.journal
+                    .record(Section::Lobby, ChangedKey::LobbyRoom(probe.room_id.clone()));
+            }
+        }
+        self.events.emit(HaxEvent::RoomProbed(probe));
+    }
+
+    fn room_probe_failed(&mut self, room_id: String, method: ProbeMethod, reason: String) {
+        warn!(
+            room_id,
+            method = format!("{method}"),
+            "Could not probe the room: {reason}"
+        );
+        self.stats
+            .record_error(format!("Could not probe room {room_id}: {reason}"));
+        self.events.emit(HaxEvent::RoomProbeFailed {
+            room_id,
+            method,
+            reason,
+        });
+    }
+}
+
+/// Joins a room on a connection of our own, reads who is in it, and leaves again. The result is kept with the room.
+pub(super) async fn join_and_leave(hax: Arc<Mutex<HaxState>>, probe: Box<JoinProbe>) {
+    let result = tokio::time::timeout(probe.timeout, join(&probe))
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out after {:?}", probe.timeout)));
+
+    let mut hax = hax.lock().await;
+    hax.room_probes.joining = hax.room_probes.joining.saturating_sub(1);
+    match result {
+        Ok((players, player_count)) => hax.room_probed(RoomProbe {
+            room_id: probe.room_id,
+            method: ProbeMethod::JoinLeave,
+            players,
+            player_count: Some(player_count),
+            looked_up: 0,
+            probed_at: SystemTime::now(),
+        }),
+        Err(e) => hax.room_probe_failed(probe.room_id, ProbeMethod::JoinLeave, format!("{e:#}")),
+    }
+}
+
+/// Returns the players in the room and how many there are, not counting us.
+async fn join(probe: &JoinProbe) -> anyhow::Result<(Vec<ProbedPlayer>, usize)> {
+    let (mut sink, mut stream) = reconnect_upstream(
+        &probe.target,
+        Some(probe.authentication.clone()),
+        probe.timeout,
+    )
+    .await?;
+
+    let send = |message: PhotonMessage| {
+        let mut bytes = vec![];
+        message
+            .to_websocket_bytes(&mut bytes)
+            .map(|_| Message::Binary(bytes))
+    };
+    sink.send(send(PhotonMessage::OperationRequest(join_request(
+        &probe.room_id,
+    )))?)
+    .await?;
+    let response = loop {
+        let message = stream
+            .next()
+            .await
+            .context("The game server closed the connection while joining")??;
+        let bytes = match message {
+            Message::Binary(bytes) => bytes,
+            _ => continue,
+        };
+        match PhotonMessage::from_websocket_bytes(&mut bytes.as_slice()) {
+            Ok(PhotonMessage::OperationResponse(response))
+                if response.operation_code == operation_code::JOIN_GAME =>
+            {
+                break response
+            }
+            _ => continue,
+        }
+    };
+    if response.return_code != 0 {
+        anyhow::bail!(
+            "The game server refused the join with code {}: {:?}",
+            response.return_code,
+            response.debug_message
+        );
+    }
+
+    // leave before reading, so we're in the room no longer than needed
+    let leave = OperationRequest {
+        operation_code: operation_code::LEAVE,
+        parameters: IndexMap::new(),
+    };
+    sink.send(send(PhotonMessage::OperationRequest(leave))?)
+        .await?;
+    _ = sink.close().await;
+
+    Ok(players_of(&response.parameters))
+}
+
+/// The players in a JOIN_GAME response and how many there are, leaving out the actor that joined.
+fn players_of(parameters: &ParameterMap) -> (Vec<ProbedPlayer>, usize) {
+    let parameters = Parameters(parameters);
+    let own_actor = parameters.actor_nr();
+    let mut players = parameters
+        .player_properties()
+        .into_iter()
+        .flatten()
+        .filter_map(|(actor, properties)| match (actor, properties) {
+            (&PhotonDataType::Integer(actor), PhotonDataType::Hashtable(properties))
+                if Some(actor) != own_actor =>
+            {
+                let string = |key| match properties.get(&PhotonDataType::Byte(key)) {
+                    Some(PhotonDataType::String(s)) if !s.is_empty() => Some(s.clone()),
+                    _ => None,
+                };
+                let player = ProbedPlayer {
+                    user_id: string(actor_properties::USER_ID),
+                    nickname: string(actor_properties::PLAYER_NAME),
+                };
+                Some((actor, player))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    players.sort_by_key(|(actor, _)| *actor);
+
+    let listed = match parameters.get(&parameter_code::ACTOR_LIST) {
+        Some(PhotonDataType::IntArray(actors)) => {
+            Some(actors.iter().filter(|a| Some(**a) != own_actor).count())
+        }
+        Some(PhotonDataType::Array(actors)) => Some(
+            actors
+                .iter()
+                .filter(|a| !matches!(a, PhotonDataType::Integer(a) if Some(*a) == own_actor))
+                .count(),
+        ),
+        _ => None,
+    };
+    let count = listed.unwrap_or(players.len());
+    (
+        players.into_iter().map(|(_, player)| player).collect(),
+        count,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use futures_util::lock::Mutex;
+    use photon_lib::{
+        highlevel::constants::{actor_properties, event_code, operation_code, parameter_code},
+        indexmap::{indexmap, IndexMap},
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationRequest, OperationResponse, PhotonMessage},
+        protocol_version::ProtocolVersion,
+        ParameterMap,
+    };
+
+    use super::{
+        find_friends_request, join_request, ProbeError, ProbeMethod, ProbeOptions, ProbedPlayer,
+        RoomProbe,
+    };
+    use crate::{
+        hax::{bandwidth::feature, events::HaxEvent, HaxState},
+        proxy::WebSocketServer,
+        testsupport::{string, MockPhotonServer, ProxiedConnection},
+    };
+
+    fn game_list(room_id: &str) -> PhotonMessage {
+        PhotonMessage::EventData(EventData {
+            code: event_code::GAME_LIST,
+            parameters: indexmap! {
+                parameter_code::GAME_LIST => PhotonDataType::Hashtable(indexmap! {
+                    string(room_id) => PhotonDataType::Hashtable(IndexMap::new()),
+                }),
+            },
+        })
+    }
+
+    fn response(operation_code: u8, parameters: ParameterMap) -> PhotonMessage {
+        PhotonMessage::OperationResponse(OperationResponse {
+            operation_code,
+            return_code: 0,
+            debug_message: None,
+            parameters,
+        })
+    }
+
+    /// An event the hooks don't handle, to check that everything sent before it was delivered or dropped.
+    fn marker() -> PhotonMessage {
+        PhotonMessage::EventData(EventData {
+            code: 123,
+            parameters: indexmap! {},
+        })
+    }
+
+    async fn next_probe(events: &mut tokio::sync::broadcast::Receiver<HaxEvent>) -> RoomProbe {
+        loop {
+            match events.recv().await.unwrap() {
+                HaxEvent::RoomProbed(probe) => return probe,
+                HaxEvent::RoomProbeFailed { reason, .. } => panic!("probe failed: {reason}"),
+                _ => (),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_known_players() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut events = state.lock().await.events.subscribe();
+        let mut conn =
+            ProxiedConnection::connect(state.clone(), WebSocketServer::LobbyServer).await;
+        conn.server.send(game_list("Chill"));
+        conn.client_recv().await;
+
+        let options = ProbeOptions {
+            user_ids: vec!["u1".into(), "u2".into(), "U1".into()],
+            ..Default::default()
+        };
+        {
+            let mut hax = state.lock().await;
+            assert!(matches!(
+                hax.probe_room("Elsewhere", options.clone()),
+                Err(ProbeError::UnknownRoom(_))
+            ));
+            let join = ProbeOptions {
+                join: true,
+                ..options.clone()
+            };
+            assert!(matches!(
+                hax.probe_room("Chill", join),
+                Err(ProbeError::JoinNotConfirmed)
+            ));
+            assert_eq!(
+                hax.probe_room("Chill", options.clone()).unwrap(),
+                ProbeMethod::FindFriends
+            );
+            assert!(matches!(
+                hax.probe_room("Chill", options.clone()),
+                Err(ProbeError::TooSoon(_))
+            ));
+            hax.room_probe_settings.min_interval = Duration::ZERO;
+            hax.room_probe_settings.max_concurrent = 1;
+            assert!(matches!(
+                hax.probe_room("Chill", options.clone()),
+                Err(ProbeError::TooMany(1))
+            ));
+        }
+        assert_eq!(
+            conn.server.recv().await,
+            PhotonMessage::OperationRequest(find_friends_request(vec!["u1".into(), "u2".into()]))
+        );
+
+        // the client looks up its friends before the lobby answers
+        let own_request =
+            || PhotonMessage::OperationRequest(find_friends_request(vec!["f".into()]));
+        conn.client_send(own_request()).await;
+        assert_eq!(conn.server.recv().await, own_request());
+
+        let rooms = |rooms: &[&str]| {
+            indexmap! {
+                parameter_code::FIND_FRIENDS_RESPONSE_ROOM_ID_LIST => PhotonDataType::StringArray(
+                    rooms.iter().map(|r| r.to_string()).collect(),
+                ),
+            }
+        };
+        conn.server.send_all([
+            response(operation_code::FIND_FRIENDS, rooms(&["Chill", ""])),
+            response(operation_code::FIND_FRIENDS, rooms(&["Other"])),
+            marker(),
+        ]);
+        assert_eq!(
+            conn.client_recv().await,
+            response(operation_code::FIND_FRIENDS, rooms(&["Other"]))
+        );
+        assert_eq!(conn.client_recv().await, marker());
+
+        let probe = next_probe(&mut events).await;
+        assert_eq!(probe.method, ProbeMethod::FindFriends);
+        assert_eq!(
+            probe.players,
+            [ProbedPlayer {
+                user_id: Some("u1".into()),
+                nickname: None,
+            }]
+        );
+        assert_eq!(probe.player_count, None);
+        assert_eq!(probe.looked_up, 2);
+
+        let hax = state.lock().await;
+        let (_, lobby) = hax.lobby_state.as_ref().unwrap();
+        assert_eq!(lobby.rooms.get("Chill").unwrap().probe, Some(probe));
+        let dropped = hax.drop_log.entries().collect::<Vec<_>>();
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].reason.feature(), feature::ROOM_PROBE);
+    }
+
+    #[tokio::test]
+    async fn joins_and_leaves_when_confirmed() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut events = state.lock().await.events.subscribe();
+        let mut conn =
+            ProxiedConnection::connect(state.clone(), WebSocketServer::LobbyServer).await;
+
+        let authenticate = PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: operation_code::AUTHENTICATE,
+            parameters: indexmap! {
+                parameter_code::USER_ID => string("me"),
+                parameter_code::TOKEN => string("name server token"),
+            },
+        });
+        conn.client_send(authenticate).await;
+        conn.server.recv().await;
+        conn.server.send_all([
+            response(
+                operation_code::AUTHENTICATE,
+                indexmap! { parameter_code::TOKEN => string("lobby token") },
+            ),
+            game_list("Full"),
+        ]);
+        conn.client_recv().await;
+        conn.client_recv().await;
+
+        // nobody is known to look for, so the room is joined right away
+        let options = ProbeOptions {
+            join: true,
+            confirm_visible_join: true,
+            ..Default::default()
+        };
+        let method = state.lock().await.probe_room("Full", options).unwrap();
+        assert_eq!(method, ProbeMethod::JoinLeave);
+        assert_eq!(
+            conn.server.recv().await,
+            PhotonMessage::OperationRequest(join_request("Full"))
+        );
+
+        let mut game_server =
+            MockPhotonServer::start_speaking(&[ProtocolVersion::GpBinaryV16]).await;
+        conn.server.send_all([
+            response(
+                operation_code::JOIN_GAME,
+                indexmap! { parameter_code::ADDRESS => string(&game_server.url()) },
+            ),
+            marker(),
+        ]);
+        assert_eq!(conn.client_recv().await, marker());
+
+        let authentication = match game_server.recv().await {
+            PhotonMessage::OperationRequest(request) => request,
+            other => panic!("expected the authentication, got {other:?}"),
+        };
+        assert_eq!(authentication.operation_code, operation_code::AUTHENTICATE);
+        assert_eq!(
+            authentication.parameters.get(&parameter_code::TOKEN),
+            Some(&string("lobby token"))
+        );
+        game_server.send(response(operation_code::AUTHENTICATE, indexmap! {}));
+
+        assert_eq!(
+            game_server.recv().await,
+            PhotonMessage::OperationRequest(join_request("Full"))
+        );
+        let player = |name: &str| {
+            PhotonDataType::Hashtable(indexmap! {
+                PhotonDataType::Byte(actor_properties::PLAYER_NAME) => string(name),
+                PhotonDataType::Byte(actor_properties::USER_ID) => string(&format!("{name}-id")),
+            })
+        };
+        game_server.send(response(
+            operation_code::JOIN_GAME,
+            indexmap! {
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(4),
+                parameter_code::ACTOR_LIST => PhotonDataType::IntArray(vec![1, 2, 3, 4]),
+                parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::Integer(2) => player("bob"),
+                    PhotonDataType::Integer(1) => player("alice"),
+                    PhotonDataType::Integer(4) => PhotonDataType::Hashtable(IndexMap::new()),
+                }),
+            },
+        ));
+        assert_eq!(
+            game_server.recv().await,
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::LEAVE,
+                parameters: indexmap! {},
+            })
+        );
+
+        let probe = next_probe(&mut events).await;
+        assert_eq!(probe.method, ProbeMethod::JoinLeave);
+        assert_eq!(probe.player_count, Some(3));
+        let names = probe
+            .players
+            .iter()
+            .map(|p| p.nickname.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["alice", "bob"]);
+        assert_eq!(probe.players[0].user_id.as_deref(), Some("alice-id"));
+
+        let hax = state.lock().await;
+        let (_, lobby) = hax.lobby_state.as_ref().unwrap();
+        assert_eq!(lobby.rooms.get("Full").unwrap().probe, Some(probe));
+        // the client never learned about the game server
+        assert!(hax.game_server_routes.iter().next().is_none());
+    }
+}
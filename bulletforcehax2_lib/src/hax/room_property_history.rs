@@ -0,0 +1,357 @@
+//! How the properties of the room we're in changed, so a host changing the map or the rules mid-match doesn't go
+//! unnoticed.
+//!
+//! The join response holds all of the room's properties, which is what the room started with and isn't recorded.
+//! After that, the properties in PROPERTIES_CHANGED events and in the server's GET_PROPERTIES responses are compared
+//! to the values before, and every value that differs is recorded with who changed it, if the event says. Properties
+//! are removed by setting them to null, which is recorded as having no new value.
+//!
+//! Changes of the [watched](RoomPropertySettings::watched) properties are also announced as
+//! [HaxEvent::RoomPropertyChanged], for the UI to show them.
+
+use std::{collections::VecDeque, fmt::Display, time::SystemTime};
+
+use photon_lib::{indexmap::IndexMap, photon_data_type::PhotonDataType, PhotonHashmap};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::{
+    events::{EventBus, HaxEvent},
+    property_firewall::format_key,
+};
+use crate::protocol::properties::ROUND_TIME_PROPERTY;
+
+/// How many changes to keep. The oldest ones are forgotten first.
+const MAX_CHANGES: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomPropertyChange {
+    pub timestamp: SystemTime,
+    /// The actor that changed the property, if the server said.
+    pub actor: Option<i32>,
+    /// The property's key, as the [property firewall](super::property_firewall) writes it.
+    pub key: String,
+    /// The value before, [None] if the property wasn't set.
+    pub old: Option<String>,
+    /// The value after, [None] if the property was removed.
+    pub new: Option<String>,
+}
+
+impl Display for RoomPropertyChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => write!(f, "{} changed from {old} to {new}", self.key)?,
+            (None, Some(new)) => write!(f, "{} was set to {new}", self.key)?,
+            (_, None) => write!(f, "{} was removed", self.key)?,
+        }
+        if let Some(actor) = self.actor {
+            write!(f, " by actor {actor}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomPropertySettings {
+    /// The keys of the properties whose changes are announced. Matching ignores case, so `mapName` also matches the
+    /// `mapname` of newfps rooms.
+    pub watched: Vec<String>,
+}
+
+impl RoomPropertySettings {
+    pub fn is_watched(&self, key: &str) -> bool {
+        self.watched.iter().any(|w| w.eq_ignore_ascii_case(key))
+    }
+}
+
+impl Default for RoomPropertySettings {
+    fn default() -> Self {
+        Self {
+            watched: vec!["mapName".into(), ROUND_TIME_PROPERTY.into()],
+        }
+    }
+}
+
+/// The current values of the room's properties and how they changed, see the [module docs](self).
+#[derive(Debug, Default)]
+pub struct RoomPropertyHistory {
+    values: IndexMap<PhotonDataType, PhotonDataType>,
+    /// Oldest first.
+    changes: VecDeque<RoomPropertyChange>,
+    joined: bool,
+}
+
+impl RoomPropertyHistory {
+    /// Takes in the properties of the join response. The first ones are what the room started with, if the room is
+    /// joined again the differences are recorded.
+    pub fn joined(
+        &mut self,
+        properties: &PhotonHashmap,
+        timestamp: SystemTime,
+    ) -> Vec<RoomPropertyChange> {
+        if self.joined {
+            return self.observe(properties, None, timestamp);
+        }
+        self.joined = true;
+        for (key, value) in properties {
+            self.values.insert(key.clone(), value.clone());
+        }
+        vec![]
+    }
+
+    /// Takes in properties the server sent after the join, and records those that changed. Returns the changes.
+    pub fn observe(
+        &mut self,
+        properties: &PhotonHashmap,
+        actor: Option<i32>,
+        timestamp: SystemTime,
+    ) -> Vec<RoomPropertyChange> {
+        let mut changes = vec![];
+        for (key, value) in properties {
+            let old = match value {
+                PhotonDataType::Null => self.values.shift_remove(key),
+                value => self.values.insert(key.clone(), value.clone()),
+            };
+            if old.as_ref() == Some(value) || (old.is_none() && *value == PhotonDataType::Null) {
+                continue;
+            }
+            changes.push(RoomPropertyChange {
+                timestamp,
+                actor,
+                key: format_key(key),
+                old: old.as_ref().map(format_value),
+                new: match value {
+                    PhotonDataType::Null => None,
+                    value => Some(format_value(value)),
+                },
+            });
+        }
+
+        for change in &changes {
+            if self.changes.len() >= MAX_CHANGES {
+                self.changes.pop_front();
+            }
+            self.changes.push_back(change.clone());
+        }
+        changes
+    }
+
+    /// The recorded changes, oldest first.
+    pub fn changes(&self) -> impl DoubleEndedIterator<Item = &RoomPropertyChange> {
+        self.changes.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+/// Writes a value the way a player would read it, without the type around it.
+fn format_value(value: &PhotonDataType) -> String {
+    match value {
+        PhotonDataType::String(s) => s.clone(),
+        PhotonDataType::Boolean(b) => b.to_string(),
+        PhotonDataType::Byte(n) => n.to_string(),
+        PhotonDataType::Short(n) => n.to_string(),
+        PhotonDataType::Integer(n) => n.to_string(),
+        PhotonDataType::Long(n) => n.to_string(),
+        PhotonDataType::Float(n) => n.to_string(),
+        PhotonDataType::Double(n) => n.to_string(),
+        other => other.display_bounded(1, 8, 32).compact().to_string(),
+    }
+}
+
+/// Logs the changes and announces those of watched properties.
+pub(super) fn emit_room_property_changes(
+    events: &EventBus,
+    settings: &RoomPropertySettings,
+    changes: Vec<RoomPropertyChange>,
+) {
+    for change in changes {
+        if settings.is_watched(&change.key) {
+            info!("Room property {change}");
+            events.emit(HaxEvent::RoomPropertyChanged(change));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::SystemTime};
+
+    use futures_util::lock::Mutex;
+    use photon_lib::{
+        highlevel::constants::{event_code, game_property_key, operation_code, parameter_code},
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationResponse, PhotonMessage},
+        PhotonHashmap,
+    };
+
+    use super::{RoomPropertyChange, RoomPropertyHistory};
+    use crate::{
+        hax::{events::HaxEvent, HaxState},
+        proxy::WebSocketServer,
+        testsupport::{string, ProxiedConnection},
+    };
+
+    fn properties_changed(sender: Option<i32>, properties: PhotonHashmap) -> PhotonMessage {
+        let mut parameters = indexmap! {
+            parameter_code::TARGET_ACTOR_NR => PhotonDataType::Integer(0),
+            parameter_code::PROPERTIES => PhotonDataType::Hashtable(properties),
+        };
+        if let Some(sender) = sender {
+            parameters.insert(parameter_code::ACTOR_NR, PhotonDataType::Integer(sender));
+        }
+        PhotonMessage::EventData(EventData {
+            code: event_code::PROPERTIES_CHANGED,
+            parameters,
+        })
+    }
+
+    fn change(
+        actor: Option<i32>,
+        key: &str,
+        old: Option<&str>,
+        new: Option<&str>,
+    ) -> (Option<i32>, String, Option<String>, Option<String>) {
+        (actor, key.into(), old.map(Into::into), new.map(Into::into))
+    }
+
+    fn fields(
+        change: &RoomPropertyChange,
+    ) -> (Option<i32>, String, Option<String>, Option<String>) {
+        (
+            change.actor,
+            change.key.clone(),
+            change.old.clone(),
+            change.new.clone(),
+        )
+    }
+
+    #[test]
+    fn records_differences_after_the_join() {
+        let mut history = RoomPropertyHistory::default();
+        let now = SystemTime::now();
+        let joined = history.joined(
+            &indexmap! {
+                string("mapName") => string("Urban"),
+                string("roundTime") => PhotonDataType::Integer(600),
+            },
+            now,
+        );
+        assert!(joined.is_empty());
+
+        // setting a property to what it was already isn't a change
+        let changes = history.observe(
+            &indexmap! {
+                string("mapName") => string("Urban"),
+                string("friendlyFire") => PhotonDataType::Boolean(true),
+                string("roundTime") => PhotonDataType::Null,
+                string("never set") => PhotonDataType::Null,
+            },
+            Some(1),
+            now,
+        );
+        assert_eq!(
+            changes.iter().map(fields).collect::<Vec<_>>(),
+            [
+                change(Some(1), "friendlyFire", None, Some("true")),
+                change(Some(1), "roundTime", Some("600"), None),
+            ]
+        );
+        assert_eq!(
+            changes.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "friendlyFire was set to true by actor 1",
+                "roundTime was removed by actor 1"
+            ]
+        );
+        assert_eq!(history.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn replays_changes_of_the_room() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut events = state.lock().await.events.subscribe();
+        let mut conn = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+
+        conn.server.send_all([
+            PhotonMessage::OperationResponse(OperationResponse {
+                operation_code: operation_code::JOIN_GAME,
+                return_code: 0,
+                debug_message: None,
+                parameters: indexmap! {
+                    parameter_code::ROOM_NAME => string("room"),
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(2),
+                    parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                    parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                        string("mapName") => string("Urban"),
+                        string("roundTime") => PhotonDataType::Integer(600),
+                        string("friendlyFire") => PhotonDataType::Boolean(false),
+                    }),
+                },
+            }),
+            properties_changed(
+                Some(1),
+                indexmap! {
+                    string("friendlyFire") => PhotonDataType::Boolean(true),
+                    string("roundTime") => PhotonDataType::Integer(300),
+                },
+            ),
+            properties_changed(
+                None,
+                indexmap! {
+                    PhotonDataType::Byte(game_property_key::MASTER_CLIENT_ID) => PhotonDataType::Integer(2),
+                },
+            ),
+            properties_changed(Some(1), indexmap! { string("mapName") => string("Outskirts") }),
+            PhotonMessage::OperationResponse(OperationResponse {
+                operation_code: operation_code::GET_PROPERTIES,
+                return_code: 0,
+                debug_message: None,
+                parameters: indexmap! {
+                    parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                        string("mapName") => string("Outskirts"),
+                        string("roundTime") => PhotonDataType::Integer(900),
+                    }),
+                },
+            }),
+        ]);
+        for _ in 0..5 {
+            conn.client_recv().await;
+        }
+
+        let hax = state.lock().await;
+        assert_eq!(
+            hax.room_property_history().map(fields).collect::<Vec<_>>(),
+            [
+                change(Some(1), "friendlyFire", Some("false"), Some("true")),
+                change(Some(1), "roundTime", Some("600"), Some("300")),
+                change(None, "248", None, Some("2")),
+                change(Some(1), "mapName", Some("Urban"), Some("Outskirts")),
+                change(None, "roundTime", Some("300"), Some("900")),
+            ]
+        );
+
+        // only the watched properties are announced
+        let mut announced = vec![];
+        while let Ok(event) = events.try_recv() {
+            if let HaxEvent::RoomPropertyChanged(change) = event {
+                announced.push(change.to_string());
+            }
+        }
+        assert_eq!(
+            announced,
+            [
+                "roundTime changed from 600 to 300 by actor 1",
+                "mapName changed from Urban to Outskirts by actor 1",
+                "roundTime changed from 300 to 900",
+            ]
+        );
+    }
+}
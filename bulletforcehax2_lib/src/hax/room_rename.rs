@@ -0,0 +1,347 @@
+//! Renaming the room we host, as it is listed in the lobby.
+//!
+//! Photon's own name of a room is what players join it by and can't change. The name the lobby shows is the custom
+//! `roomName` property, or `roomname` in rooms of the newfps [variant](super::game_variant), which the host can
+//! change with a SET_PROPERTIES request like any other room property. Photon has no well-known property for it.
+//!
+//! The server passes the change on to the lobby, which shows it with its next game list update. The game itself
+//! reads the name once when joining, so neither our client nor the other players in the room see the new one.
+//!
+//! SET_PROPERTIES responses don't say which request they answer, but they come in the order the requests were sent.
+//! [PendingProperties] keeps that order, so the response to a rename can be told apart from the responses to the
+//! client's own requests. The client never asked for the rename, so its response is dropped.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use photon_lib::{
+    highlevel::{
+        constants::operation_code, structs::SetPropertiesOperationRequest,
+        PhotonParameterMapConversion,
+    },
+    indexmap::{indexmap, IndexMap},
+    photon_data_type::PhotonDataType,
+    photon_message::OperationRequest,
+};
+use thiserror::Error;
+
+use super::game_variant::GameVariant;
+use crate::error::HaxError;
+
+/// The longest name the lobby shows without cutting it off, in characters.
+pub const MAX_NAME_LENGTH: usize = 30;
+
+/// How long to wait between renames, so the lobby isn't flooded with updates of the room.
+pub const COOLDOWN: Duration = Duration::from_secs(5);
+
+/// How many requests can wait for a response. The server answers every request, more are only outstanding if
+/// responses got lost.
+const MAX_PENDING: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum RenameError {
+    #[error("not in a room")]
+    NotInRoom,
+    #[error("only the host of the room can rename it")]
+    NotHost,
+    #[error("the new name is empty")]
+    Empty,
+    #[error("the new name has {len} characters, at most {max} fit")]
+    TooLong { len: usize, max: usize },
+    #[error("the room was renamed recently, try again in {:.1}s", .remaining.as_secs_f32())]
+    CoolingDown { remaining: Duration },
+    #[error(transparent)]
+    Injection(#[from] HaxError),
+}
+
+/// Checks a new name for the room, returning it without surrounding whitespace.
+pub fn validate_name(name: &str) -> Result<&str, RenameError> {
+    let name = name.trim();
+    let len = name.chars().count();
+    match len {
+        0 => Err(RenameError::Empty),
+        len if len > MAX_NAME_LENGTH => Err(RenameError::TooLong {
+            len,
+            max: MAX_NAME_LENGTH,
+        }),
+        _ => Ok(name),
+    }
+}
+
+/// The SET_PROPERTIES request that renames the room we're in.
+pub fn rename_request(variant: GameVariant, name: &str) -> OperationRequest {
+    let mut parameters = IndexMap::new();
+    SetPropertiesOperationRequest {
+        properties: indexmap! {
+            PhotonDataType::String(variant.keys().room_name.into()) => PhotonDataType::String(name.into()),
+        },
+        actor_nr: None,
+        broadcast: true,
+        expected_values: None,
+        event_forward: None,
+    }
+    .into_map(&mut parameters);
+    OperationRequest {
+        operation_code: operation_code::SET_PROPERTIES,
+        parameters,
+    }
+}
+
+/// Who is waiting for the response to a SET_PROPERTIES request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PropertiesResponse {
+    /// The client, or a feature that leaves the response to it.
+    Forward,
+    /// A rename, which goes back to the previous name if it failed.
+    Rename { previous: Option<String> },
+}
+
+/// The SET_PROPERTIES requests sent to the game server that weren't answered yet, see [room_rename](self).
+#[derive(Debug, Clone, Default)]
+pub struct PendingProperties {
+    pending: VecDeque<PropertiesResponse>,
+    last_rename: Option<Instant>,
+}
+
+impl PendingProperties {
+    /// Notes a request whose response goes to the client.
+    pub fn forwarded(&mut self) {
+        self.push(PropertiesResponse::Forward);
+    }
+
+    /// Notes a rename away from the given name.
+    pub fn renamed(&mut self, previous: Option<String>, now: Instant) {
+        self.push(PropertiesResponse::Rename { previous });
+        self.last_rename = Some(now);
+    }
+
+    fn push(&mut self, response: PropertiesResponse) {
+        if self.pending.len() == MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(response);
+    }
+
+    /// Takes the oldest request, when its response arrives. Responses nobody waits for are forwarded.
+    pub fn respond(&mut self) -> PropertiesResponse {
+        self.pending
+            .pop_front()
+            .unwrap_or(PropertiesResponse::Forward)
+    }
+
+    /// Fails if the last rename was too recent.
+    pub fn check_cooldown(&self, now: Instant) -> Result<(), RenameError> {
+        let elapsed = match self.last_rename {
+            Some(last) => now.saturating_duration_since(last),
+            None => return Ok(()),
+        };
+        match COOLDOWN.checked_sub(elapsed) {
+            Some(remaining) if !remaining.is_zero() => Err(RenameError::CoolingDown { remaining }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Instant};
+
+    use futures_util::lock::Mutex;
+    use photon_lib::{
+        highlevel::constants::{game_property_key, operation_code, parameter_code},
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationRequest, OperationResponse, PhotonMessage},
+        PhotonHashmap,
+    };
+
+    use super::{validate_name, RenameError, COOLDOWN};
+    use crate::{
+        hax::{bandwidth::feature, HaxState},
+        proxy::WebSocketServer,
+        testsupport::{string, ProxiedConnection},
+    };
+
+    /// An event the hooks don't handle, to check that everything sent before it was delivered or dropped.
+    fn marker() -> PhotonMessage {
+        PhotonMessage::EventData(EventData {
+            code: 123,
+            parameters: indexmap! {},
+        })
+    }
+
+    /// Joins a room as actor 1, which is the master client if `host` is set.
+    async fn join(conn: &mut ProxiedConnection, properties: PhotonHashmap, host: bool) {
+        let mut properties = properties;
+        properties.insert(
+            PhotonDataType::Byte(game_property_key::MASTER_CLIENT_ID),
+            PhotonDataType::Integer(if host { 1 } else { 2 }),
+        );
+        conn.server
+            .send(PhotonMessage::OperationResponse(OperationResponse {
+                operation_code: operation_code::JOIN_GAME,
+                return_code: 0,
+                debug_message: None,
+                parameters: indexmap! {
+                    parameter_code::ROOM_NAME => string("room"),
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                    parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                    parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(properties),
+                },
+            }));
+        conn.client_recv().await;
+    }
+
+    fn properties_response(return_code: i16) -> PhotonMessage {
+        PhotonMessage::OperationResponse(OperationResponse {
+            operation_code: operation_code::SET_PROPERTIES,
+            return_code,
+            debug_message: None,
+            parameters: indexmap! {},
+        })
+    }
+
+    async fn room_title(state: &Arc<Mutex<HaxState>>) -> Option<String> {
+        let hax = state.lock().await;
+        hax.gameplay_state.as_ref().unwrap().1.room_title.clone()
+    }
+
+    #[test]
+    fn names_are_validated() {
+        assert_eq!(validate_name("  my room ").unwrap(), "my room");
+        assert!(matches!(validate_name(" \t"), Err(RenameError::Empty)));
+        assert_eq!(validate_name(&"ü".repeat(30)).unwrap().len(), 60);
+        assert!(matches!(
+            validate_name(&"a".repeat(31)),
+            Err(RenameError::TooLong { len: 31, max: 30 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn rename_is_injected_and_rolled_back() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut conn = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        join(
+            &mut conn,
+            indexmap! {
+                string("roomName") => string("old name"),
+                string("gameVersion") => string("1.99.0"),
+            },
+            true,
+        )
+        .await;
+        assert_eq!(room_title(&state).await.as_deref(), Some("old name"));
+
+        state.lock().await.rename_my_room(" new name ").unwrap();
+        assert_eq!(room_title(&state).await.as_deref(), Some("new name"));
+        assert_eq!(
+            conn.server.recv().await,
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::SET_PROPERTIES,
+                parameters: indexmap! {
+                    parameter_code::PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                        string("roomName") => string("new name"),
+                    }),
+                    parameter_code::BROADCAST => PhotonDataType::Boolean(true),
+                },
+            })
+        );
+        assert!(matches!(
+            state.lock().await.rename_my_room("newer name"),
+            Err(RenameError::CoolingDown { .. })
+        ));
+
+        // the client sends a change of its own before the server answers the rename
+        let map_change = || {
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::SET_PROPERTIES,
+                parameters: indexmap! {
+                    parameter_code::PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                        string("mapName") => string("Urban"),
+                    }),
+                    parameter_code::BROADCAST => PhotonDataType::Boolean(true),
+                },
+            })
+        };
+        conn.client_send(map_change()).await;
+        assert_eq!(conn.server.recv().await, map_change());
+
+        // the rename failed, the client's change didn't
+        conn.server
+            .send_all([properties_response(-2), properties_response(0), marker()]);
+        assert_eq!(conn.client_recv().await, properties_response(0));
+        assert_eq!(conn.client_recv().await, marker());
+        assert_eq!(room_title(&state).await.as_deref(), Some("old name"));
+
+        let hax = state.lock().await;
+        let dropped = hax.drop_log.entries().collect::<Vec<_>>();
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].reason.feature(), feature::ROOM_RENAME);
+        assert!(hax.stats.recent_errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn newfps_rooms_use_their_key() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut conn = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        join(
+            &mut conn,
+            indexmap! {
+                string("roomname") => string("old name"),
+                string("gameversion") => string("newfps-1.0"),
+            },
+            true,
+        )
+        .await;
+
+        state.lock().await.rename_my_room("new name").unwrap();
+        let parameters = match conn.server.recv().await {
+            PhotonMessage::OperationRequest(request) => request.parameters,
+            other => panic!("expected a request, got {other:?}"),
+        };
+        assert_eq!(
+            parameters[&parameter_code::PROPERTIES],
+            PhotonDataType::Hashtable(indexmap! { string("roomname") => string("new name") })
+        );
+
+        conn.server.send_all([properties_response(0), marker()]);
+        assert_eq!(conn.client_recv().await, marker());
+        assert_eq!(room_title(&state).await.as_deref(), Some("new name"));
+    }
+
+    #[tokio::test]
+    async fn only_the_host_renames() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        assert!(matches!(
+            state.lock().await.rename_my_room("new name"),
+            Err(RenameError::NotInRoom)
+        ));
+
+        let mut conn = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        join(&mut conn, indexmap! {}, false).await;
+        let mut hax = state.lock().await;
+        assert!(matches!(
+            hax.rename_my_room("new name"),
+            Err(RenameError::NotHost)
+        ));
+        assert!(matches!(
+            hax.rename_my_room(&"a".repeat(40)),
+            Err(RenameError::NotHost)
+        ));
+    }
+
+    #[test]
+    fn cooldown_runs_out() {
+        let start = Instant::now();
+        let mut pending = super::PendingProperties::default();
+        assert!(pending.check_cooldown(start).is_ok());
+        pending.renamed(None, start);
+        assert!(matches!(
+            pending.check_cooldown(start + COOLDOWN / 2),
+            Err(RenameError::CoolingDown { remaining }) if remaining == COOLDOWN / 2
+        ));
+        assert!(pending.check_cooldown(start + COOLDOWN).is_ok());
+    }
+}
@@ -0,0 +1,359 @@
+//! Counts which RPC methods are called, by whom and in which direction, to find out what the game actually uses.
+//!
+//! The table can be exported as json and compared against an export from another game version, which shows the
+//! methods that were added or stopped being used. The [DriftDetector](super::drift::DriftDetector) only knows
+//! whether a method was seen at all, this also keeps how often and with which parameters.
+
+use std::{collections::BTreeMap, fmt::Display, time::SystemTime};
+
+use photon_lib::{highlevel::structs::RpcCall, photon_data_type::PhotonDataType};
+use serde::{Deserialize, Serialize};
+
+use crate::{protocol::rpc::METHOD_NAMES, proxy::Direction};
+
+/// How many distinct methods are remembered, so garbage data can't grow the table forever.
+const MAX_METHODS: usize = 512;
+/// How many distinct callers are counted per method.
+const MAX_ACTORS_PER_METHOD: usize = 64;
+/// How many parameters of a sample are kept.
+const MAX_SAMPLE_PARAMETERS: usize = 16;
+/// How long a single formatted sample parameter can get, in characters.
+const MAX_SAMPLE_PARAMETER_LEN: usize = 128;
+
+/// An RPC method, as it was called.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RpcMethod {
+    /// Called by name, or by an index that is in [METHOD_NAMES].
+    Name(String),
+    /// Called by an index that is not in [METHOD_NAMES], likely because the game updated.
+    Index(u8),
+}
+
+impl RpcMethod {
+    /// The method of a call, or [None] if it has neither a name nor an index.
+    pub fn of(call: &RpcCall) -> Option<Self> {
+        match (call.rpc_index, &call.method_name) {
+            (Some(index), _) => Some(match METHOD_NAMES.get(index as usize) {
+                Some(name) => Self::Name(name.to_string()),
+                None => Self::Index(index),
+            }),
+            (None, Some(name)) => Some(Self::Name(name.clone())),
+            (None, None) => None,
+        }
+    }
+}
+
+impl Display for RpcMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcMethod::Name(name) => write!(f, "{name}"),
+            RpcMethod::Index(index) => write!(f, "unknown #{index}"),
+        }
+    }
+}
+
+/// How many calls were made in each direction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcCallCounts {
+    /// Calls sent by us.
+    pub client_to_server: u64,
+    /// Calls received from other players.
+    pub server_to_client: u64,
+}
+
+impl RpcCallCounts {
+    fn add(&mut self, direction: Direction) {
+        match direction {
+            Direction::ClientToServer => self.client_to_server += 1,
+            Direction::ServerToClient => self.server_to_client += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.client_to_server + self.server_to_client
+    }
+}
+
+/// How a single method was called.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcMethodUsage {
+    pub method: RpcMethod,
+    pub calls: RpcCallCounts,
+    /// The calls of each actor. Only the first few callers are counted, see [MAX_ACTORS_PER_METHOD].
+    pub by_actor: BTreeMap<i32, RpcCallCounts>,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+    /// The parameters of one call, formatted and shortened. A call with non-empty parameters is preferred.
+    pub sample_parameters: Option<Vec<String>>,
+    /// Whether the sample has any non-empty parameters, in which case it is kept.
+    #[serde(default)]
+    sample_has_values: bool,
+}
+
+impl RpcMethodUsage {
+    fn new(method: RpcMethod, now: SystemTime) -> Self {
+        Self {
+            method,
+            calls: RpcCallCounts::default(),
+            by_actor: BTreeMap::new(),
+            first_seen: now,
+            last_seen: now,
+            sample_parameters: None,
+            sample_has_values: false,
+        }
+    }
+
+    fn record(
+        &mut self,
+        direction: Direction,
+        actor: i32,
+        parameters: Option<&[PhotonDataType]>,
+        now: SystemTime,
+    ) {
+        self.calls.add(direction);
+        if self.by_actor.len() < MAX_ACTORS_PER_METHOD || self.by_actor.contains_key(&actor) {
+            self.by_actor.entry(actor).or_default().add(direction);
+        }
+        self.last_seen = now;
+
+        let has_values = parameters.is_some_and(|p| p.iter().any(|p| !is_empty_value(p)));
+        let replace_sample = match &self.sample_parameters {
+            None => true,
+            Some(_) => has_values && !self.sample_has_values,
+        };
+        if replace_sample {
+            self.sample_parameters = Some(sample(parameters.unwrap_or_default()));
+            self.sample_has_values = has_values;
+        }
+    }
+}
+
+/// The RPC calls seen over a session, per method.
+#[derive(Debug, Default)]
+pub struct RpcUsageTable {
+    methods: BTreeMap<RpcMethod, RpcMethodUsage>,
+}
+
+impl RpcUsageTable {
+    /// Counts a call made by the given actor. Calls without a method are ignored.
+    pub fn record(&mut self, direction: Direction, actor: i32, call: &RpcCall, now: SystemTime) {
+        let method = match RpcMethod::of(call) {
+            Some(method) => method,
+            None => return,
+        };
+        if self.methods.len() >= MAX_METHODS && !self.methods.contains_key(&method) {
+            return;
+        }
+        self.methods
+            .entry(method.clone())
+            .or_insert_with(|| RpcMethodUsage::new(method, now))
+            .record(direction, actor, call.in_method_parameters.as_deref(), now);
+    }
+
+    pub fn get(&self, method: &RpcMethod) -> Option<&RpcMethodUsage> {
+        self.methods.get(method)
+    }
+
+    /// The methods in order, named ones first.
+    pub fn iter(&self) -> impl Iterator<Item = &RpcMethodUsage> {
+        self.methods.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.methods.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.methods.is_empty()
+    }
+
+    /// The table as pretty-printed json, to compare across game versions.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&self.iter().collect::<Vec<_>>())
+            .expect("rpc usage should serialize")
+    }
+
+    /// Reads a table that was exported with [Self::to_json].
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        let methods = serde_json::from_str::<Vec<RpcMethodUsage>>(json)?;
+        Ok(Self {
+            methods: methods
+                .into_iter()
+                .map(|usage| (usage.method.clone(), usage))
+                .collect(),
+        })
+    }
+
+    /// The methods that were called here but not in the other table, for example one exported from an older version.
+    pub fn new_methods<'a>(&'a self, other: &'a Self) -> impl Iterator<Item = &'a RpcMethod> {
+        self.methods
+            .keys()
+            .filter(|method| !other.methods.contains_key(*method))
+    }
+}
+
+/// Whether a parameter carries no information, as is the case for a lot of calls.
+fn is_empty_value(value: &PhotonDataType) -> bool {
+    match value {
+        PhotonDataType::Null => true,
+        PhotonDataType::String(s) => s.is_empty(),
+        PhotonDataType::ByteArray(a) => a.is_empty(),
+        PhotonDataType::StringArray(a) => a.is_empty(),
+        PhotonDataType::IntArray(a) => a.is_empty(),
+        PhotonDataType::Array(a) | PhotonDataType::ObjectArray(a) => a.is_empty(),
+        PhotonDataType::Hashtable(h) | PhotonDataType::Dictionary(_, h) => h.is_empty(),
+        _ => false,
+    }
+}
+
+fn sample(parameters: &[PhotonDataType]) -> Vec<String> {
+    parameters
+        .iter()
+        .take(MAX_SAMPLE_PARAMETERS)
+        .map(|p| {
+            let formatted = format!("{p:?}");
+            match formatted.char_indices().nth(MAX_SAMPLE_PARAMETER_LEN) {
+                Some((end, _)) => format!("{}...", &formatted[..end]),
+                None => formatted,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use photon_lib::{highlevel::structs::RpcCall, photon_data_type::PhotonDataType};
+
+    use super::{RpcMethod, RpcUsageTable, MAX_SAMPLE_PARAMETER_LEN};
+    use crate::proxy::Direction;
+
+    fn call(
+        rpc_index: Option<u8>,
+        method_name: Option<&str>,
+        parameters: Option<Vec<PhotonDataType>>,
+    ) -> RpcCall {
+        RpcCall {
+            net_view_id: 1001,
+            other_side_prefix: None,
+            server_timestamp: None,
+            method_name: method_name.map(String::from),
+            in_method_parameters: parameters,
+            rpc_index,
+            custom_properties: Default::default(),
+        }
+    }
+
+    #[test]
+    fn counts_known_and_unknown_methods() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let later = start + Duration::from_secs(30);
+        let mut table = RpcUsageTable::default();
+
+        // index 4 is "Chat", index 200 is past the end of the method list
+        let chat = call(
+            Some(4),
+            None,
+            Some(vec![PhotonDataType::String("hi".into())]),
+        );
+        table.record(Direction::ClientToServer, 1, &chat, start);
+        table.record(Direction::ServerToClient, 2, &chat, start);
+        table.record(Direction::ServerToClient, 2, &chat, later);
+        table.record(
+            Direction::ServerToClient,
+            3,
+            &call(Some(200), None, None),
+            start,
+        );
+        table.record(
+            Direction::ServerToClient,
+            3,
+            &call(None, Some("NewRpc"), None),
+            later,
+        );
+        table.record(Direction::ServerToClient, 3, &call(None, None, None), later);
+
+        assert_eq!(table.len(), 3);
+        let chat = table.get(&RpcMethod::Name("Chat".into())).unwrap();
+        assert_eq!(chat.calls.client_to_server, 1);
+        assert_eq!(chat.calls.server_to_client, 2);
+        assert_eq!(chat.calls.total(), 3);
+        assert_eq!(chat.by_actor[&1].client_to_server, 1);
+        assert_eq!(chat.by_actor[&2].server_to_client, 2);
+        assert_eq!(chat.first_seen, start);
+        assert_eq!(chat.last_seen, later);
+        assert_eq!(
+            chat.sample_parameters.as_deref(),
+            Some(&[r#"String("hi")"#.to_string()][..])
+        );
+
+        let unknown = table.get(&RpcMethod::Index(200)).unwrap();
+        assert_eq!(unknown.calls.server_to_client, 1);
+        assert_eq!(unknown.method.to_string(), "unknown #200");
+        assert!(table.get(&RpcMethod::Name("NewRpc".into())).is_some());
+
+        // the export can be read back and diffed against an older one
+        let exported = RpcUsageTable::from_json(&table.to_json()).unwrap();
+        assert_eq!(
+            exported.iter().collect::<Vec<_>>(),
+            table.iter().collect::<Vec<_>>()
+        );
+        let mut older = RpcUsageTable::default();
+        older.record(
+            Direction::ServerToClient,
+            2,
+            &call(Some(4), None, None),
+            start,
+        );
+        assert_eq!(
+            table.new_methods(&older).collect::<Vec<_>>(),
+            [&RpcMethod::Name("NewRpc".into()), &RpcMethod::Index(200)]
+        );
+    }
+
+    #[test]
+    fn prefers_samples_with_values() {
+        let now = UNIX_EPOCH;
+        let mut table = RpcUsageTable::default();
+        let method = RpcMethod::Name("RpcShoot".into());
+        let sample = |table: &RpcUsageTable| table.get(&method).unwrap().sample_parameters.clone();
+
+        let empty = call(
+            None,
+            Some("RpcShoot"),
+            Some(vec![
+                PhotonDataType::Null,
+                PhotonDataType::String("".into()),
+            ]),
+        );
+        table.record(Direction::ServerToClient, 2, &empty, now);
+        assert_eq!(sample(&table).unwrap(), ["Null", r#"String("")"#]);
+
+        let long = "x".repeat(MAX_SAMPLE_PARAMETER_LEN * 2);
+        let full = call(
+            None,
+            Some("RpcShoot"),
+            Some(vec![
+                PhotonDataType::String(long),
+                PhotonDataType::Integer(7),
+            ]),
+        );
+        table.record(Direction::ServerToClient, 2, &full, now);
+        let kept = sample(&table).unwrap();
+        assert!(kept[0].ends_with("..."));
+        assert_eq!(kept[0].chars().count(), MAX_SAMPLE_PARAMETER_LEN + 3);
+        assert_eq!(kept[1], "Integer(7)");
+
+        // once a sample has values, it isn't replaced anymore
+        table.record(Direction::ServerToClient, 2, &empty, now);
+        let other = call(
+            None,
+            Some("RpcShoot"),
+            Some(vec![PhotonDataType::Integer(8)]),
+        );
+        table.record(Direction::ServerToClient, 2, &other, now);
+        assert_eq!(sample(&table).unwrap(), kept);
+    }
+}
@@ -0,0 +1,778 @@
+//! Toggles and injections that run by themselves: on a timer, when the round in the room enters a phase, or when an
+//! event comes up, eg. "send a message at the start of the round" or "stop hosting in stealth once a friend shows up".
+//!
+//! A [Schedule] pairs a [Trigger] with an [Action]. Schedules run once unless they're
+//! [repeating](Schedule::repeating), and are saved with the other settings in [profiles](super::profiles). An action
+//! can also run a [macro](Scheduler::macros), a named list of actions that several schedules can share.
+//!
+//! The triggers are checked by the task [BulletForceHax::start_scheduler] starts: on every tick for timers and phase
+//! changes, and for every event on the [event bus](super::events::EventBus). Nothing runs while
+//! [paused](HaxState::set_global_pause). Timers that ran out meanwhile fire once resumed, events and phase changes
+//! are missed.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::lock::Mutex;
+use photon_lib::{
+    highlevel::constants::operation_code,
+    indexmap::indexmap,
+    photon_message::{OperationRequest, PhotonMessage},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
+
+use super::{
+    bandwidth::feature, events::HaxEvent, match_phase::MatchPhase, room_rename, settings::Settings,
+    watchlist::WatchTarget, BulletForceHax, HaxState,
+};
+use crate::error::HaxError;
+
+/// When a schedule runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    /// This many seconds after the schedule was added or loaded. Repeating schedules run again every as many seconds.
+    After {
+        secs: u64,
+    },
+    /// The round in the room entered the phase, eg. [MatchPhase::Warmup] when a round starts. See
+    /// [match_phase](super::match_phase).
+    Phase(MatchPhase),
+    Event(EventTrigger),
+}
+
+/// The events a schedule can run on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventTrigger {
+    /// A player on the [watchlist](super::watchlist) showed up. With a nickname pattern, only a player whose nickname
+    /// matches it, as for [WatchTarget::Nickname].
+    WatchedPlayerSeen {
+        nickname: Option<String>,
+    },
+    SuspectedCheater,
+    MasterClientChanged,
+    MatchFinished,
+}
+
+impl EventTrigger {
+    pub fn matches(&self, event: &HaxEvent) -> bool {
+        match (self, event) {
+            (
+                EventTrigger::WatchedPlayerSeen { nickname: None },
+                HaxEvent::WatchedPlayerSeen { .. },
+            ) => true,
+            (
+                EventTrigger::WatchedPlayerSeen {
+                    nickname: Some(pattern),
+                },
+                HaxEvent::WatchedPlayerSeen { nickname, .. },
+            ) => WatchTarget::Nickname(pattern.clone()).matches(None, nickname.as_deref()),
+            (EventTrigger::SuspectedCheater, HaxEvent::SuspectedCheater { .. }) => true,
+            (EventTrigger::MasterClientChanged, HaxEvent::MasterClientChanged(_)) => true,
+            (EventTrigger::MatchFinished, HaxEvent::MatchFinished(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// The toggles a schedule can switch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Toggle {
+    ShowMobileGames,
+    ShowOtherVersions,
+    StripPasswords,
+    LobbyPhaseAnnotations,
+    QueueJump,
+    MuteAllCosmetic,
+    GhostJoin,
+    ObserveOnly,
+    /// See [HaxState::set_receive_all_groups].
+    ReceiveAllGroups,
+    /// See [HaxState::set_stealth_host].
+    StealthHost,
+}
+
+impl Toggle {
+    /// The setting the toggle stands for, if it is one of the [Settings].
+    fn setting(self, settings: &mut Settings) -> Option<&mut bool> {
+        match self {
+            Toggle::ShowMobileGames => Some(&mut settings.show_mobile_games),
+            Toggle::ShowOtherVersions => Some(&mut settings.show_other_versions),
+            Toggle::StripPasswords => Some(&mut settings.strip_passwords),
+            Toggle::LobbyPhaseAnnotations => Some(&mut settings.lobby_phase_annotations),
+            Toggle::QueueJump => Some(&mut settings.queue_jump),
+            Toggle::MuteAllCosmetic => Some(&mut settings.mute_all_cosmetic),
+            Toggle::GhostJoin => Some(&mut settings.ghost_join),
+            Toggle::ObserveOnly => Some(&mut settings.observe_only),
+            Toggle::ReceiveAllGroups | Toggle::StealthHost => None,
+        }
+    }
+}
+
+/// The messages a schedule can inject.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrebuiltMessage {
+    /// Renames the room we host, see [HaxState::rename_my_room].
+    RenameRoom(String),
+    /// Leaves the room. The client gets the server's response as if it had asked to leave itself.
+    LeaveRoom,
+}
+
+/// What a schedule does.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    SetToggle {
+        toggle: Toggle,
+        enabled: bool,
+    },
+    Inject(PrebuiltMessage),
+    /// Runs the actions of a [macro](Scheduler::macros) in order.
+    RunMacro(String),
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::SetToggle { toggle, enabled } => {
+                let state = if *enabled { "on" } else { "off" };
+                write!(f, "turn {toggle:?} {state}")
+            }
+            Action::Inject(PrebuiltMessage::RenameRoom(name)) => {
+                write!(f, "rename room to {name:?}")
+            }
+            Action::Inject(PrebuiltMessage::LeaveRoom) => write!(f, "leave room"),
+            Action::RunMacro(name) => write!(f, "run macro {name:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    /// What the schedule is shown as, it doesn't have to be unique.
+    pub name: String,
+    pub trigger: Trigger,
+    pub action: Action,
+    /// Run every time the trigger comes up, instead of only the first time.
+    #[serde(default)]
+    pub repeating: bool,
+}
+
+/// Why an action can't run, or [None] if it can. Macros can't run other macros, so `in_macro` rejects those.
+pub fn check_action(
+    action: &Action,
+    macros: &BTreeMap<String, Vec<Action>>,
+    in_macro: bool,
+) -> Option<String> {
+    match action {
+        Action::Inject(PrebuiltMessage::RenameRoom(name)) => room_rename::validate_name(name)
+            .err()
+            .map(|e| e.to_string()),
+        Action::RunMacro(_) if in_macro => Some("macros can't run other macros".into()),
+        Action::RunMacro(name) if !macros.contains_key(name) => {
+            Some(format!("there is no macro named {name:?}"))
+        }
+        _ => None,
+    }
+}
+
+/// Why a schedule can't run, or [None] if it can.
+pub fn check_schedule(
+    schedule: &Schedule,
+    macros: &BTreeMap<String, Vec<Action>>,
+) -> Option<String> {
+    match &schedule.trigger {
+        Trigger::After { secs: 0 } if schedule.repeating => {
+            return Some("repeating timers need at least a second".into())
+        }
+        Trigger::Event(EventTrigger::WatchedPlayerSeen {
+            nickname: Some(pattern),
+        }) if pattern.trim().is_empty() => return Some("nickname pattern is empty".into()),
+        _ => {}
+    }
+    check_action(&schedule.action, macros, false)
+}
+
+/// How far along a schedule is.
+#[derive(Debug, Clone, Default)]
+struct Progress {
+    /// When the timer started, set on the first tick after the schedule was added.
+    armed_at: Option<Instant>,
+    /// Whether the schedule ran, schedules that don't repeat only run once.
+    ran: bool,
+}
+
+/// A schedule that came up, with the actions to run, macros already expanded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DueSchedule {
+    pub name: String,
+    pub actions: Vec<Action>,
+}
+
+/// The schedules and macros, and how far along each schedule is.
+#[derive(Debug, Clone, Default)]
+pub struct Scheduler {
+    schedules: Vec<(Schedule, Progress)>,
+    macros: BTreeMap<String, Vec<Action>>,
+    /// The phase of the round when last checked.
+    phase: Option<MatchPhase>,
+}
+
+impl Scheduler {
+    pub fn schedules(&self) -> impl ExactSizeIterator<Item = &Schedule> {
+        self.schedules.iter().map(|(schedule, _)| schedule)
+    }
+
+    /// How many schedules can still run, the repeating ones and those that didn't run yet.
+    pub fn pending(&self) -> usize {
+        self.schedules
+            .iter()
+            .filter(|(schedule, progress)| schedule.repeating || !progress.ran)
+            .count()
+    }
+
+    /// The named lists of actions schedules can run with [Action::RunMacro].
+    pub fn macros(&self) -> &BTreeMap<String, Vec<Action>> {
+        &self.macros
+    }
+
+    /// Adds a schedule without checking it, see [check_schedule]. Returns its index.
+    pub fn add(&mut self, schedule: Schedule) -> usize {
+        self.schedules.push((schedule, Progress::default()));
+        self.schedules.len() - 1
+    }
+
+    /// Replaces the schedule at the given index, starting it over. Returns the old one.
+    pub fn update(&mut self, index: usize, schedule: Schedule) -> Option<Schedule> {
+        let entry = self.schedules.get_mut(index)?;
+        let (old, _) = std::mem::replace(entry, (schedule, Progress::default()));
+        Some(old)
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Schedule> {
+        if index >= self.schedules.len() {
+            return None;
+        }
+        Some(self.schedules.remove(index).0)
+    }
+
+    /// Adds or replaces a macro. Returns the actions it had before.
+    pub fn set_macro(&mut self, name: &str, actions: Vec<Action>) -> Option<Vec<Action>> {
+        self.macros.insert(name.into(), actions)
+    }
+
+    pub fn remove_macro(&mut self, name: &str) -> Option<Vec<Action>> {
+        self.macros.remove(name)
+    }
+
+    /// Replaces all schedules and macros, starting every schedule over.
+    pub fn replace(&mut self, schedules: Vec<Schedule>, macros: BTreeMap<String, Vec<Action>>) {
+        self.schedules = schedules
+            .into_iter()
+            .map(|schedule| (schedule, Progress::default()))
+            .collect();
+        self.macros = macros;
+    }
+
+    /// The schedules whose timer ran out, or whose phase the round just entered.
+    pub fn tick(&mut self, now: Instant, phase: MatchPhase) -> Vec<DueSchedule> {
+        let previous = self.phase.replace(phase).unwrap_or(MatchPhase::Unknown);
+        let entered = (phase != previous).then_some(phase);
+
+        let mut due = vec![];
+        for (schedule, progress) in &mut self.schedules {
+            if progress.ran && !schedule.repeating {
+                continue;
+            }
+            let runs = match &schedule.trigger {
+                Trigger::After { secs } => {
+                    let armed_at = *progress.armed_at.get_or_insert(now);
+                    let runs =
+                        now.saturating_duration_since(armed_at) >= Duration::from_secs(*secs);
+                    if runs {
+                        progress.armed_at = Some(now);
+                    }
+                    runs
+                }
+                Trigger::Phase(target) => entered == Some(*target),
+                Trigger::Event(_) => false,
+            };
+            if runs {
+                progress.ran = true;
+                due.push(schedule.clone());
+            }
+        }
+        due.into_iter().map(|s| self.expand(s)).collect()
+    }
+
+    /// The schedules that run on the event.
+    pub fn on_event(&mut self, event: &HaxEvent) -> Vec<DueSchedule> {
+        let mut due = vec![];
+        for (schedule, progress) in &mut self.schedules {
+            if progress.ran && !schedule.repeating {
+                continue;
+            }
+            if let Trigger::Event(trigger) = &schedule.trigger {
+                if trigger.matches(event) {
+                    progress.ran = true;
+                    due.push(schedule.clone());
+                }
+            }
+        }
+        due.into_iter().map(|s| self.expand(s)).collect()
+    }
+
+    fn expand(&self, schedule: Schedule) -> DueSchedule {
+        let actions = match schedule.action {
+            Action::RunMacro(name) => match self.macros.get(&name) {
+                Some(actions) => actions.clone(),
+                None => {
+                    warn!(schedule = schedule.name, "There is no macro named {name:?}");
+                    vec![]
+                }
+            },
+            action => vec![action],
+        };
+        DueSchedule {
+            name: schedule.name,
+            actions,
+        }
+    }
+}
+
+impl HaxState {
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    /// Adds a schedule, see [scheduler]. Returns its index.
+    pub fn add_schedule(&mut self, schedule: Schedule) -> Result<usize, HaxError> {
+        if let Some(reason) = check_schedule(&schedule, self.scheduler.macros()) {
+            return Err(HaxError::Config(reason));
+        }
+        debug!(name = schedule.name, "Added schedule");
+        Ok(self.scheduler.add(schedule))
+    }
+
+    /// Replaces the schedule at the given index, starting it over. Returns the old one, or [None] if there is no
+    /// schedule at that index.
+    pub fn update_schedule(
+        &mut self,
+        index: usize,
+        schedule: Schedule,
+    ) -> Result<Option<Schedule>, HaxError> {
+        if let Some(reason) = check_schedule(&schedule, self.scheduler.macros()) {
+            return Err(HaxError::Config(reason));
+        }
+        Ok(self.scheduler.update(index, schedule))
+    }
+
+    /// Removes the schedule at the given index. Returns the removed schedule.
+    pub fn remove_schedule(&mut self, index: usize) -> Option<Schedule> {
+        self.scheduler.remove(index)
+    }
+
+    /// Adds or replaces a macro.
+    pub fn set_macro(&mut self, name: &str, actions: Vec<Action>) -> Result<(), HaxError> {
+        if name.trim().is_empty() {
+            return Err(HaxError::Config("macro name is empty".into()));
+        }
+        let macros = self.scheduler.macros();
+        if let Some(reason) = actions
+            .iter()
+            .find_map(|action| check_action(action, macros, true))
+        {
+            return Err(HaxError::Config(reason));
+        }
+        self.scheduler.set_macro(name, actions);
+        Ok(())
+    }
+
+    /// Removes a macro, unless a schedule still runs it. Returns its actions.
+    pub fn remove_macro(&mut self, name: &str) -> Result<Option<Vec<Action>>, HaxError> {
+        let used_by = self
+            .scheduler
+            .schedules()
+            .find(|schedule| matches!(&schedule.action, Action::RunMacro(m) if m == name));
+        if let Some(schedule) = used_by {
+            return Err(HaxError::Config(format!(
+                "macro {name:?} is run by schedule {:?}",
+                schedule.name
+            )));
+        }
+        Ok(self.scheduler.remove_macro(name))
+    }
+
+    /// Runs the schedules whose timer ran out or whose phase the round just entered.
+    pub fn run_due_schedules(&mut self, now: Instant) {
+        if self.global_pause() {
+            return;
+        }
+        let phase = match &self.gameplay_state {
+            Some((_, state)) => state.match_phase(now).phase,
+            None => MatchPhase::Unknown,
+        };
+        let due = self.scheduler.tick(now, phase);
+        self.run_schedules(due);
+    }
+
+    /// Runs the schedules that run on the event.
+    pub fn run_event_schedules(&mut self, event: &HaxEvent) {
+        if self.global_pause() {
+            return;
+        }
+        let due = self.scheduler.on_event(event);
+        self.run_schedules(due);
+    }
+
+    fn run_schedules(&mut self, due: Vec<DueSchedule>) {
+        for schedule in due {
+            for action in &schedule.actions {
+                match self.run_action(action) {
+                    Ok(()) => info!(schedule = schedule.name, "Scheduled action: {action}"),
+                    Err(e) => warn!(
+                        schedule = schedule.name,
+                        "Scheduled action failed: {action}: {e}"
+                    ),
+                }
+            }
+            self.events.emit(HaxEvent::ScheduleRan {
+                name: schedule.name,
+            });
+        }
+    }
+
+    fn run_action(&mut self, action: &Action) -> Result<(), HaxError> {
+        match action {
+            Action::SetToggle {
+                toggle: Toggle::ReceiveAllGroups,
+                enabled,
+            } => self.set_receive_all_groups(*enabled),
+            Action::SetToggle {
+                toggle: Toggle::StealthHost,
+                enabled,
+            } => self.set_stealth_host(*enabled),
+            Action::SetToggle { toggle, enabled } => self.update_settings(|settings| {
+                if let Some(setting) = toggle.setting(settings) {
+                    *setting = *enabled;
+                }
+            }),
+            Action::Inject(PrebuiltMessage::RenameRoom(name)) => self
+                .rename_my_room(name)
+                .map_err(|e| HaxError::InjectionUnavailable(e.to_string()))?,
+            Action::Inject(PrebuiltMessage::LeaveRoom) => {
+                let proxy = match &self.gameplay_state {
+                    Some((proxy, _)) => proxy,
+                    None => return Err(HaxError::InjectionUnavailable("not in a room".into())),
+                };
+                proxy.queue_server(
+                    PhotonMessage::OperationRequest(OperationRequest {
+                        operation_code: operation_code::LEAVE,
+                        parameters: indexmap! {},
+                    }),
+                    feature::INJECTED_MESSAGES,
+                )?;
+            }
+            // expanded before running, see Scheduler::expand
+            Action::RunMacro(name) => {
+                return Err(HaxError::Config(format!(
+                    "macros can't run other macros, not running {name:?}"
+                )))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A running scheduler task. Stopping it ends the task on its next tick.
+pub struct SchedulerHandle {
+    stop: Arc<AtomicBool>,
+}
+
+impl SchedulerHandle {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl BulletForceHax {
+    /// Runs the [schedules](scheduler) until the returned handle is stopped, checking the timers and the phase of the
+    /// round at the given interval.
+    pub fn start_scheduler(&self, tick: Duration) -> SchedulerHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        tokio::spawn(run_scheduler(self.get_state(), tick, stop.clone()));
+        SchedulerHandle { stop }
+    }
+}
+
+async fn run_scheduler(state: Arc<Mutex<HaxState>>, tick: Duration, stop: Arc<AtomicBool>) {
+    let mut events = state.lock().await.events.subscribe();
+    let mut interval = tokio::time::interval(tick);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                state.lock().await.run_due_schedules(Instant::now());
+            }
+            event = events.recv() => match event {
+                Ok(event) => state.lock().await.run_event_schedules(&event),
+                Err(RecvError::Lagged(missed)) => warn!(missed, "Scheduler missed events"),
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+
+    debug!("Scheduler stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        time::{Duration, Instant},
+    };
+
+    use super::{
+        check_schedule, Action, DueSchedule, EventTrigger, PrebuiltMessage, Schedule, Scheduler,
+        Toggle, Trigger,
+    };
+    use crate::hax::{
+        events::HaxEvent,
+        match_phase::MatchPhase,
+        watchlist::{AlertLevel, WatchEntry, WatchTarget},
+        HaxState,
+    };
+    use crate::proxy::WebSocketServer;
+
+    fn toggle(toggle: Toggle, enabled: bool) -> Action {
+        Action::SetToggle { toggle, enabled }
+    }
+
+    fn schedule(name: &str, trigger: Trigger, action: Action, repeating: bool) -> Schedule {
+        Schedule {
+            name: name.into(),
+            trigger,
+            action,
+            repeating,
+        }
+    }
+
+    fn names(due: &[DueSchedule]) -> Vec<&str> {
+        due.iter().map(|d| d.name.as_str()).collect()
+    }
+
+    fn watched(nickname: &str) -> HaxEvent {
+        HaxEvent::WatchedPlayerSeen {
+            entry: WatchEntry {
+                target: WatchTarget::Nickname("*".into()),
+                note: String::new(),
+                level: AlertLevel::Info,
+            },
+            user_id: None,
+            nickname: Some(nickname.into()),
+            room: None,
+            server: WebSocketServer::GameServer,
+        }
+    }
+
+    #[test]
+    fn timers_and_phases() {
+        let mut scheduler = Scheduler::default();
+        let after = |secs| Trigger::After { secs };
+        scheduler.add(schedule(
+            "once",
+            after(120),
+            toggle(Toggle::QueueJump, true),
+            false,
+        ));
+        scheduler.add(schedule(
+            "every",
+            after(60),
+            toggle(Toggle::GhostJoin, false),
+            true,
+        ));
+        scheduler.add(schedule(
+            "round start",
+            Trigger::Phase(MatchPhase::Warmup),
+            Action::Inject(PrebuiltMessage::RenameRoom("GG".into())),
+            true,
+        ));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // the first tick starts the timers
+        assert!(scheduler.tick(at(0), MatchPhase::Unknown).is_empty());
+        assert!(scheduler.tick(at(59), MatchPhase::Unknown).is_empty());
+        assert_eq!(
+            names(&scheduler.tick(at(60), MatchPhase::Warmup)),
+            ["every", "round start"]
+        );
+        // staying in the phase isn't entering it again
+        assert!(scheduler.tick(at(61), MatchPhase::Warmup).is_empty());
+        assert_eq!(
+            names(&scheduler.tick(at(120), MatchPhase::Active)),
+            ["once", "every"]
+        );
+        assert_eq!(
+            names(&scheduler.tick(at(180), MatchPhase::Warmup)),
+            ["every", "round start"]
+        );
+        assert_eq!(
+            names(&scheduler.tick(at(240), MatchPhase::Warmup)),
+            ["every"]
+        );
+        assert_eq!(scheduler.pending(), 2);
+
+        // updating a schedule starts it over
+        scheduler.update(
+            0,
+            schedule("again", after(10), toggle(Toggle::QueueJump, false), false),
+        );
+        assert!(scheduler.tick(at(241), MatchPhase::Warmup).is_empty());
+        let due = scheduler.tick(at(251), MatchPhase::Warmup);
+        assert_eq!(due[0].actions, [toggle(Toggle::QueueJump, false)]);
+    }
+
+    #[test]
+    fn events_and_macros() {
+        let mut scheduler = Scheduler::default();
+        scheduler.set_macro(
+            "hide",
+            vec![
+                toggle(Toggle::StealthHost, true),
+                Action::Inject(PrebuiltMessage::LeaveRoom),
+            ],
+        );
+        scheduler.add(schedule(
+            "friend",
+            Trigger::Event(EventTrigger::WatchedPlayerSeen {
+                nickname: Some("friend*".into()),
+            }),
+            Action::RunMacro("hide".into()),
+            false,
+        ));
+        scheduler.add(schedule(
+            "anyone",
+            Trigger::Event(EventTrigger::WatchedPlayerSeen { nickname: None }),
+            toggle(Toggle::MuteAllCosmetic, true),
+            true,
+        ));
+
+        assert_eq!(names(&scheduler.on_event(&watched("stranger"))), ["anyone"]);
+        let due = scheduler.on_event(&watched("Friend123"));
+        assert_eq!(names(&due), ["friend", "anyone"]);
+        assert_eq!(due[0].actions.len(), 2);
+        assert_eq!(names(&scheduler.on_event(&watched("friend"))), ["anyone"]);
+        let other = HaxEvent::UpstreamReconnected { server: None };
+        assert!(scheduler.on_event(&other).is_empty());
+        // events don't start timers or phases
+        assert!(scheduler
+            .tick(Instant::now(), MatchPhase::Active)
+            .is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        let macros = BTreeMap::from([("m".to_string(), vec![])]);
+        let check = |trigger, action, repeating| {
+            check_schedule(&schedule("s", trigger, action, repeating), &macros)
+        };
+        let after = Trigger::After { secs: 0 };
+        assert_eq!(
+            check(after.clone(), Action::RunMacro("m".into()), false),
+            None
+        );
+        assert!(check(after.clone(), Action::RunMacro("missing".into()), false).is_some());
+        assert!(check(after.clone(), toggle(Toggle::QueueJump, true), true).is_some());
+        let rename = Action::Inject(PrebuiltMessage::RenameRoom(" ".into()));
+        assert!(check(after, rename, false).is_some());
+
+        let mut hax = HaxState::default();
+        assert!(hax
+            .set_macro("nested", vec![Action::RunMacro("m".into())])
+            .is_err());
+        hax.set_macro("m", vec![]).unwrap();
+        hax.add_schedule(schedule(
+            "s",
+            Trigger::After { secs: 1 },
+            Action::RunMacro("m".into()),
+            false,
+        ))
+        .unwrap();
+        assert!(hax.remove_macro("m").is_err());
+        assert!(hax.remove_schedule(0).is_some());
+        assert_eq!(hax.remove_macro("m").unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn runs_actions_on_the_state() {
+        let mut hax = HaxState::default();
+        let mut events = hax.events.subscribe();
+        hax.add_schedule(schedule(
+            "mobile",
+            Trigger::After { secs: 120 },
+            toggle(Toggle::ShowMobileGames, true),
+            false,
+        ))
+        .unwrap();
+        hax.add_schedule(schedule(
+            "groups",
+            Trigger::Event(EventTrigger::WatchedPlayerSeen { nickname: None }),
+            toggle(Toggle::ReceiveAllGroups, true),
+            false,
+        ))
+        .unwrap();
+        let start = Instant::now();
+
+        hax.run_due_schedules(start);
+        hax.run_event_schedules(&watched("anyone"));
+        assert!(hax.receive_all_groups);
+        assert!(
+            matches!(events.try_recv(), Ok(HaxEvent::ScheduleRan { name }) if name == "groups")
+        );
+
+        // nothing runs while paused, the timer fires once resumed
+        hax.set_global_pause(true);
+        hax.run_due_schedules(start + Duration::from_secs(120));
+        hax.set_global_pause(false);
+        assert!(!hax.settings().show_mobile_games);
+        hax.run_due_schedules(start + Duration::from_secs(121));
+        assert!(hax.settings().show_mobile_games);
+        assert_eq!(hax.scheduler().pending(), 0);
+
+        // injecting outside of a room fails without stopping the other actions
+        hax.set_macro(
+            "leave",
+            vec![
+                Action::Inject(PrebuiltMessage::LeaveRoom),
+                toggle(Toggle::GhostJoin, true),
+            ],
+        )
+        .unwrap();
+        hax.add_schedule(schedule(
+            "leave",
+            Trigger::After { secs: 0 },
+            Action::RunMacro("leave".into()),
+            false,
+        ))
+        .unwrap();
+        hax.run_due_schedules(start + Duration::from_secs(200));
+        assert!(hax.settings().ghost_join);
+    }
+}
@@ -0,0 +1,458 @@
+//! A self-test that checks our assumptions about the protocol against live traffic, so game updates that break
+//! parsing are noticed.
+
+use std::time::{Duration, Instant};
+
+use photon_lib::{
+    highlevel::{
+        constants::{event_code, operation_code, parameter_code, pun_event_code},
+        structs::{
+            JoinGameResponseSuccess, Player, RaiseEvent, RoomInfo, RoomInfoList, RpcCall, RpcEvent,
+        },
+        PhotonMapConversion, PhotonParameterMapConversion,
+    },
+    photon_data_type::PhotonDataType,
+    photon_message::PhotonMessage,
+};
+
+use crate::protocol::rpc::get_rpc_method_name;
+
+/// The custom properties we rely on being present in lobby rooms.
+const EXPECTED_ROOM_PROPERTIES: [&str; 3] = ["roomName", "storeID", "gameVersion"];
+/// The fraction of RPCs whose method name needs to be resolvable.
+const MIN_RPC_RESOLVE_RATIO: f32 = 0.8;
+/// How many failures to keep as samples for each check.
+const MAX_SAMPLES: usize = 5;
+
+#[derive(Debug, Clone)]
+pub struct SelfTestSettings {
+    /// Start a self-test after authenticating with the lobby for the first time.
+    pub run_on_connect: bool,
+    /// How long to gather traffic for.
+    pub duration: Duration,
+}
+
+impl Default for SelfTestSettings {
+    fn default() -> Self {
+        Self {
+            run_on_connect: true,
+            duration: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Passed,
+    Failed,
+    /// Not enough traffic was observed to decide.
+    Inconclusive,
+}
+
+#[derive(Debug, Clone)]
+pub struct SelfTestCheck {
+    pub name: &'static str,
+    /// The feature that will likely be broken if this check fails.
+    pub affects: &'static str,
+    pub status: CheckStatus,
+    pub summary: String,
+    /// Some examples of what went wrong.
+    pub failure_samples: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    pub checks: Vec<SelfTestCheck>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.status != CheckStatus::Failed)
+    }
+
+    pub fn failed_checks(&self) -> impl Iterator<Item = &SelfTestCheck> {
+        self.checks
+            .iter()
+            .filter(|c| c.status == CheckStatus::Failed)
+    }
+}
+
+/// A counter of successes and failures, with samples of the failures.
+#[derive(Debug, Default)]
+struct Tally {
+    passed: usize,
+    failed: usize,
+    samples: Vec<String>,
+}
+
+impl Tally {
+    fn pass(&mut self) {
+        self.passed += 1;
+    }
+
+    fn fail(&mut self, sample: impl FnOnce() -> String) {
+        self.failed += 1;
+        if self.samples.len() < MAX_SAMPLES {
+            self.samples.push(sample());
+        }
+    }
+
+    fn total(&self) -> usize {
+        self.passed + self.failed
+    }
+}
+
+/// Gathers observations from live traffic until its deadline has passed.
+#[derive(Debug)]
+pub struct SelfTest {
+    deadline: Instant,
+    game_lists: Tally,
+    rooms_with_properties: Tally,
+    rpc_names: Tally,
+    player_nicknames: Tally,
+}
+
+impl SelfTest {
+    pub fn new(duration: Duration, now: Instant) -> Self {
+        Self {
+            deadline: now + duration,
+            game_lists: Tally::default(),
+            rooms_with_properties: Tally::default(),
+            rpc_names: Tally::default(),
+            player_nicknames: Tally::default(),
+        }
+    }
+
+    pub fn is_finished(&self, now: Instant) -> bool {
+        now >= self.deadline
+    }
+
+    pub fn observe(&mut self, message: &PhotonMessage) {
+        match message {
+            PhotonMessage::EventData(event) => match event.code {
+                event_code::GAME_LIST | event_code::GAME_LIST_UPDATE => {
+                    self.observe_game_list(&mut event.parameters.clone())
+                }
+                pun_event_code::RPC => {
+                    match RpcEvent::from_map(&mut event.parameters.clone())
+                        .and_then(|mut e| e.extract_rpc_call())
+                    {
+                        Ok(call) => self.observe_rpc(&call),
+                        Err(e) => self
+                            .rpc_names
+                            .fail(|| format!("could not parse RPC event: {e}")),
+                    }
+                }
+                _ => (),
+            },
+            PhotonMessage::OperationRequest(request)
+                if request.operation_code == operation_code::RAISE_EVENT =>
+            {
+                if let Ok(RaiseEvent {
+                    event_code: pun_event_code::RPC,
+                    data: Some(PhotonDataType::Hashtable(mut data)),
+                    ..
+                }) = RaiseEvent::from_map(&mut request.parameters.clone())
+                {
+                    match RpcCall::from_map(&mut data) {
+                        Ok(call) => self.observe_rpc(&call),
+                        Err(e) => self
+                            .rpc_names
+                            .fail(|| format!("could not parse RPC call: {e}")),
+                    }
+                }
+            }
+            // the lobby answers joins too, with only the game server's address
+            PhotonMessage::OperationResponse(response)
+                if response.operation_code == operation_code::JOIN_GAME
+                    && response.return_code == 0
+                    && response
+                        .parameters
+                        .contains_key(&parameter_code::PLAYER_PROPERTIES) =>
+            {
+                if let Ok(resp) =
+                    JoinGameResponseSuccess::from_map(&mut response.parameters.clone())
+                {
+                    for (actor, props) in resp.player_properties {
+                        if let PhotonDataType::Hashtable(mut props) = props {
+                            match Player::from_map(&mut props) {
+                                Ok(Player {
+                                    nickname: Some(_), ..
+                                }) => self.player_nicknames.pass(),
+                                Ok(_) => self
+                                    .player_nicknames
+                                    .fail(|| format!("actor {actor:?} has no nickname")),
+                                Err(e) => self
+                                    .player_nicknames
+                                    .fail(|| format!("actor {actor:?} did not parse: {e}")),
+                            }
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn observe_game_list(&mut self, parameters: &mut photon_lib::ParameterMap) {
+        let game_list = match RoomInfoList::from_map(parameters) {
+            Ok(l) => l,
+            Err(e) => {
+                self.game_lists
+                    .fail(|| format!("could not parse game list: {e}"));
+                return;
+            }
+        };
+
+        let mut room_errors = 0;
+        for (name, room) in game_list.games {
+            let mut room = match room {
+                PhotonDataType::Hashtable(room) => room,
+                _ => {
+                    room_errors += 1;
+                    self.game_lists
+                        .fail(|| format!("room {name:?} is not a hashtable"));
+                    continue;
+                }
+            };
+
+            let room_info = match RoomInfo::from_map(&mut room) {
+                Ok(r) => r,
+                Err(e) => {
+                    room_errors += 1;
+                    self.game_lists
+                        .fail(|| format!("room {name:?} did not parse: {e}"));
+                    continue;
+                }
+            };
+
+            // removed rooms only contain the removed flag
+            if room_info.removed == Some(true) {
+                continue;
+            }
+
+            let missing = EXPECTED_ROOM_PROPERTIES
+                .iter()
+                .filter(|key| !room_info.custom_properties.contains_key(**key))
+                .collect::<Vec<_>>();
+            match missing.is_empty() {
+                true => self.rooms_with_properties.pass(),
+                false => self
+                    .rooms_with_properties
+                    .fail(|| format!("room {name:?} is missing {missing:?}")),
+            }
+        }
+
+        if room_errors == 0 {
+            self.game_lists.pass();
+        }
+    }
+
+    fn observe_rpc(&mut self, call: &RpcCall) {
+        match get_rpc_method_name(call) {
+            Ok(_) => self.rpc_names.pass(),
+            Err(e) => self.rpc_names.fail(|| e.to_string()),
+        }
+    }
+
+    pub fn into_report(self) -> SelfTestReport {
+        let game_list = match (self.game_lists.total(), self.game_lists.failed) {
+            (0, _) => inconclusive("game list", "Lobby", "no game lists were received"),
+            (total, 0) => passed("game list", "Lobby", format!("{total} game lists parsed")),
+            (_, failed) => failed_check(
+                "game list",
+                "Lobby",
+                format!("{failed} rooms or game lists failed to parse"),
+                self.game_lists.samples,
+            ),
+        };
+
+        // a single room with all properties is enough, some rooms are created by other games or old versions
+        let room_properties = match (
+            self.rooms_with_properties.total(),
+            self.rooms_with_properties.passed,
+        ) {
+            (0, _) => inconclusive("room properties", "Lobby", "no rooms were received"),
+            (total, 0) => failed_check(
+                "room properties",
+                "Lobby",
+                format!("none of {total} rooms had the expected properties"),
+                self.rooms_with_properties.samples,
+            ),
+            (total, passed_count) => passed(
+                "room properties",
+                "Lobby",
+                format!("{passed_count} of {total} rooms had the expected properties"),
+            ),
+        };
+
+        let rpc_names = match self.rpc_names.total() {
+            0 => inconclusive(
+                "RPC names",
+                "RPC logging and muting",
+                "no RPCs were observed",
+            ),
+            total => {
+                let ratio = self.rpc_names.passed as f32 / total as f32;
+                let summary = format!("{} of {total} RPC names resolved", self.rpc_names.passed);
+                match ratio >= MIN_RPC_RESOLVE_RATIO {
+                    true => passed("RPC names", "RPC logging and muting", summary),
+                    false => failed_check(
+                        "RPC names",
+                        "RPC logging and muting",
+                        summary,
+                        self.rpc_names.samples,
+                    ),
+                }
+            }
+        };
+
+        let player_nicknames = match (self.player_nicknames.total(), self.player_nicknames.failed) {
+            (0, _) => inconclusive(
+                "player nicknames",
+                "Player list",
+                "no players were observed",
+            ),
+            (total, 0) => passed(
+                "player nicknames",
+                "Player list",
+                format!("all {total} players had a nickname"),
+            ),
+            (total, failed) => failed_check(
+                "player nicknames",
+                "Player list",
+                format!("{failed} of {total} players had no nickname"),
+                self.player_nicknames.samples,
+            ),
+        };
+
+        SelfTestReport {
+            checks: vec![game_list, room_properties, rpc_names, player_nicknames],
+        }
+    }
+}
+
+fn passed(name: &'static str, affects: &'static str, summary: String) -> SelfTestCheck {
+    SelfTestCheck {
+        name,
+        affects,
+        status: CheckStatus::Passed,
+        summary,
+        failure_samples: vec![],
+    }
+}
+
+fn failed_check(
+    name: &'static str,
+    affects: &'static str,
+    summary: String,
+    failure_samples: Vec<String>,
+) -> SelfTestCheck {
+    SelfTestCheck {
+        name,
+        affects,
+        status: CheckStatus::Failed,
+        summary,
+        failure_samples,
+    }
+}
+
+fn inconclusive(name: &'static str, affects: &'static str, summary: &str) -> SelfTestCheck {
+    SelfTestCheck {
+        name,
+        affects,
+        status: CheckStatus::Inconclusive,
+        summary: summary.to_string(),
+        failure_samples: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use photon_lib::{
+        highlevel::constants::{actor_properties, event_code, operation_code, parameter_code},
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationResponse, PhotonMessage},
+    };
+
+    use super::{CheckStatus, SelfTest};
+
+    fn game_list(rooms: photon_lib::PhotonHashmap) -> PhotonMessage {
+        PhotonMessage::EventData(EventData {
+            code: event_code::GAME_LIST,
+            parameters: indexmap! {
+                parameter_code::GAME_LIST => PhotonDataType::Hashtable(rooms),
+            },
+        })
+    }
+
+    #[test]
+    fn passes_on_expected_rooms() {
+        let mut test = SelfTest::new(Duration::from_secs(10), Instant::now());
+        test.observe(&game_list(indexmap! {
+            PhotonDataType::String("room".into()) => PhotonDataType::Hashtable(indexmap! {
+                PhotonDataType::String("roomName".into()) => PhotonDataType::String("room".into()),
+                PhotonDataType::String("storeID".into()) => PhotonDataType::String("BALYZE_WEB".into()),
+                PhotonDataType::String("gameVersion".into()) => PhotonDataType::String("1.89.0".into()),
+            }),
+        }));
+
+        let report = test.into_report();
+        assert!(report.passed());
+        assert_eq!(report.checks[0].status, CheckStatus::Passed);
+        assert_eq!(report.checks[1].status, CheckStatus::Passed);
+        assert_eq!(report.checks[2].status, CheckStatus::Inconclusive);
+    }
+
+    #[test]
+    fn fails_on_missing_properties() {
+        let mut test = SelfTest::new(Duration::from_secs(10), Instant::now());
+        test.observe(&game_list(indexmap! {
+            PhotonDataType::String("room".into()) => PhotonDataType::Hashtable(indexmap! {
+                PhotonDataType::String("roomName".into()) => PhotonDataType::String("room".into()),
+            }),
+        }));
+
+        let report = test.into_report();
+        assert!(!report.passed());
+        let failed = report.failed_checks().collect::<Vec<_>>();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].name, "room properties");
+        assert_eq!(failed[0].failure_samples.len(), 1);
+    }
+
+    fn join_response(parameters: photon_lib::ParameterMap) -> PhotonMessage {
+        PhotonMessage::OperationResponse(OperationResponse {
+            operation_code: operation_code::JOIN_GAME,
+            return_code: 0,
+            debug_message: None,
+            parameters,
+        })
+    }
+
+    #[test]
+    fn skips_join_responses_of_the_lobby() {
+        let mut test = SelfTest::new(Duration::from_secs(10), Instant::now());
+        test.observe(&join_response(indexmap! {
+            parameter_code::ADDRESS => PhotonDataType::String("wss://game.example:19091".into()),
+            parameter_code::ROOM_NAME => PhotonDataType::String("room".into()),
+        }));
+        assert_eq!(test.player_nicknames.total(), 0);
+
+        test.observe(&join_response(indexmap! {
+            parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+            parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                PhotonDataType::Integer(1) => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::Byte(actor_properties::PLAYER_NAME) => PhotonDataType::String("player".into()),
+                }),
+            }),
+            parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+        }));
+        let report = test.into_report();
+        assert_eq!(report.checks[3].name, "player nicknames");
+        assert_eq!(report.checks[3].status, CheckStatus::Passed);
+    }
+}
@@ -0,0 +1,229 @@
+//! Noticing when the client is moved to another game server while it's still in a game.
+//!
+//! Photon can move clients between game servers, eg. to rebalance a region. The client then connects to the new game
+//! server before its connection to the old one closed, sometimes after the old server announced the move with an
+//! address in a response. Without noticing, the state of the old game would apply to the new connection, and the old
+//! connection closing would clear the state of the new one.
+//!
+//! When a game server connection comes in while another is still open, the game on the old one is finished with
+//! [MatchEnd::Migrated](super::match_summary::MatchEnd::Migrated) and the new connection starts with fresh state. The
+//! session and settings aren't tied to a connection, so they carry over as they are. If the client joins the same
+//! room on the new server, the match continues: its summary is linked to the earlier one through
+//! [MatchSummary::continues](super::match_summary::MatchSummary::continues).
+
+use std::{fmt::Display, time::SystemTime};
+
+/// A move of the client from one game server connection to another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    /// The port of the game server we were moved away from.
+    pub from_port: u16,
+    /// The port of the game server we were moved to.
+    pub to_port: u16,
+    /// The room we were in on the old game server, if any.
+    pub room_name: Option<String>,
+    /// The address the old game server told the client to move to, if it announced the move.
+    pub announced: Option<String>,
+    pub timestamp: SystemTime,
+}
+
+impl Display for Migration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "moved from game server port {} to {}",
+            self.from_port, self.to_port
+        )?;
+        if let Some(room_name) = &self.room_name {
+            write!(f, " while in {room_name}")?;
+        }
+        if self.announced.is_some() {
+            write!(f, " (announced)")?;
+        }
+        Ok(())
+    }
+}
+
+/// What is kept of the game we were moved away from, until the client joins a room on the new game server.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigratedFrom {
+    pub room_name: Option<String>,
+    /// When the match in that room started, including the games before earlier migrations.
+    pub match_started: Option<SystemTime>,
+}
+
+impl MigratedFrom {
+    /// Whether joining the given room continues the match we were moved away from.
+    pub fn continued_by(&self, room_name: Option<&str>) -> Option<SystemTime> {
+        match (self.room_name.as_deref(), room_name) {
+            (Some(from), Some(to)) if from == to => self.match_started,
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use futures_util::lock::Mutex;
+    use photon_lib::{
+        highlevel::constants::{operation_code, parameter_code},
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{OperationResponse, PhotonMessage},
+    };
+    use tokio::sync::broadcast;
+
+    use super::{MigratedFrom, Migration};
+    use crate::{
+        hax::{events::HaxEvent, match_summary::MatchEnd, HaxState},
+        proxy::WebSocketServer,
+        testsupport::{next_event, string, ProxiedConnection},
+    };
+
+    fn join_room(room_name: &str) -> PhotonMessage {
+        PhotonMessage::OperationResponse(OperationResponse {
+            operation_code: operation_code::JOIN_GAME,
+            return_code: 0,
+            debug_message: None,
+            parameters: indexmap! {
+                parameter_code::ROOM_NAME => string(room_name),
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+            },
+        })
+    }
+
+    /// The old game server telling the client where to go.
+    fn announce_move() -> PhotonMessage {
+        PhotonMessage::OperationResponse(OperationResponse {
+            operation_code: operation_code::AUTHENTICATE,
+            return_code: 0,
+            debug_message: None,
+            parameters: indexmap! {
+                parameter_code::ADDRESS => string("wss://gs2.example:2083"),
+            },
+        })
+    }
+
+    async fn wait_for_no_game_server(state: &Arc<Mutex<HaxState>>) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.lock().await.gameplay_state.is_some() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("game server connection was not cleared");
+    }
+
+    /// The events about migrations that were emitted so far.
+    fn migration_events(events: &mut broadcast::Receiver<HaxEvent>) -> Vec<HaxEvent> {
+        let mut found = vec![];
+        while let Ok(event) = events.try_recv() {
+            if let HaxEvent::GameServerMigrated(_) | HaxEvent::MigratedMatchResumed { .. } = event {
+                found.push(event);
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn continues_only_the_same_room() {
+        let started = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let from = MigratedFrom {
+            room_name: Some("room".into()),
+            match_started: Some(started),
+        };
+        assert_eq!(from.continued_by(Some("room")), Some(started));
+        assert_eq!(from.continued_by(Some("other")), None);
+        assert_eq!(from.continued_by(None), None);
+        assert_eq!(MigratedFrom::default().continued_by(None), None);
+
+        let migration = Migration {
+            from_port: 2083,
+            to_port: 2084,
+            room_name: Some("room".into()),
+            announced: Some("wss://gs2.example:2084".into()),
+            timestamp: started,
+        };
+        assert_eq!(
+            migration.to_string(),
+            "moved from game server port 2083 to 2084 while in room (announced)"
+        );
+    }
+    #[tokio::test]
+    async fn closing_before_the_new_connection_is_a_disconnect() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut events = state.lock().await.events.subscribe();
+        let mut old = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        old.server.send_all([join_room("room"), announce_move()]);
+        old.client_recv().await;
+        old.client_recv().await;
+
+        // announcing a move doesn't count until the client is on the new server
+        old.close().await;
+        let first = next_event(&mut events, |e| match e {
+            HaxEvent::MatchFinished(summary) => Some(summary),
+            _ => None,
+        })
+        .await;
+        assert_eq!(first.ended_by, MatchEnd::Disconnected);
+        wait_for_no_game_server(&state).await;
+
+        let mut new = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        assert_eq!(
+            state
+                .lock()
+                .await
+                .gameplay_state
+                .as_ref()
+                .unwrap()
+                .1
+                .migrated_from,
+            None
+        );
+        new.server.send(join_room("room"));
+        new.client_recv().await;
+        assert!(migration_events(&mut events).is_empty());
+        let second = state.lock().await.finish_match(MatchEnd::Left).unwrap();
+        assert_eq!(second.continues, None);
+    }
+
+    #[tokio::test]
+    async fn unfinished_migrations_are_forgotten() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut events = state.lock().await.events.subscribe();
+        let mut old = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        old.server.send(join_room("room"));
+        old.client_recv().await;
+
+        let new = ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        let migration = next_event(&mut events, |e| match e {
+            HaxEvent::GameServerMigrated(migration) => Some(migration),
+            _ => None,
+        })
+        .await;
+        assert_eq!(migration.room_name.as_deref(), Some("room"));
+        assert_eq!(migration.announced, None);
+        old.close().await;
+
+        // the client never joins a room on the new server before it's gone too
+        new.close().await;
+        wait_for_no_game_server(&state).await;
+        assert!(state.lock().await.finish_match(MatchEnd::Left).is_none());
+
+        // so joining the room again later starts a new match
+        let mut later =
+            ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        later.server.send(join_room("room"));
+        later.client_recv().await;
+        assert!(migration_events(&mut events).is_empty());
+        let summary = state.lock().await.finish_match(MatchEnd::Left).unwrap();
+        assert_eq!(summary.continues, None);
+    }
+}
@@ -0,0 +1,541 @@
+//! A report of what happened in a [Capture], made by replaying it through the handlers.
+//!
+//! Everything but the message counts and the listed rooms is read from the state the replay leaves behind, so the
+//! report sees the session the same way the proxy did.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    time::{Duration, SystemTime},
+};
+
+use photon_lib::{
+    highlevel::{
+        constants::{event_code, parameter_code},
+        parameters::Parameters,
+        structs::RoomInfoView,
+    },
+    photon_data_type::PhotonDataType,
+    photon_message::PhotonMessage,
+    PhotonHashmap,
+};
+use serde::Serialize;
+
+use super::{rpc_usage::RpcMethodUsage, timeline::Replay, GameplayState};
+use crate::{
+    error::HaxError,
+    inspect::{capture::Capture, message_code, message_type_name, CapturedMessage},
+    protocol::properties::BulletForceRoomProperties,
+    proxy::WebSocketServer,
+};
+
+/// How many messages of one kind were in the capture.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MessageCount {
+    pub server: String,
+    pub direction: String,
+    /// The type of the message, `encrypted` or `unparsed` for messages that couldn't be read.
+    pub message_type: &'static str,
+    /// The operation or event code.
+    pub code: Option<u8>,
+    pub count: u64,
+}
+
+/// A player that was in one of the rooms we joined.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SeenPlayer {
+    pub room_name: Option<String>,
+    pub actor_id: i32,
+    pub nickname: Option<String>,
+    pub user_id: Option<String>,
+}
+
+/// A room that showed up in the lobby.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ListedRoom {
+    /// The id the lobby knows the room by.
+    pub id: String,
+    /// The name shown in the room list.
+    pub room_name: Option<String>,
+    pub map_name: Option<String>,
+    /// The most players that were in it at once.
+    pub max_player_count: u8,
+    /// How many players fit in the room, if it's limited.
+    pub max_players: Option<u8>,
+    /// Whether the room could be joined when it was last listed.
+    pub is_open: Option<bool>,
+}
+
+/// A message that the handlers failed on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReplayFailure {
+    /// The position of the message in the capture.
+    pub index: usize,
+    /// How far into the capture the message was, in milliseconds.
+    pub at_ms: u64,
+    pub server: String,
+    pub direction: String,
+    pub len: usize,
+    /// Where in the message parsing failed, for parse failures.
+    pub parse_offset: Option<usize>,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SessionReport {
+    pub messages: usize,
+    pub duration_ms: u64,
+    /// The message counts, ordered by server, direction, type and code.
+    pub message_counts: Vec<MessageCount>,
+    pub players: Vec<SeenPlayer>,
+    pub rooms: Vec<ListedRoom>,
+    pub rpc_usage: Vec<RpcMethodUsage>,
+    pub parse_failures: Vec<ReplayFailure>,
+    /// Messages that parsed, but that a handler returned an error for.
+    pub handler_failures: Vec<ReplayFailure>,
+}
+
+impl SessionReport {
+    /// Replays the capture through the handlers of a fresh state and reports on it.
+    ///
+    /// The parse breaker is disabled for the replay, so every message is parsed and every failure is reported.
+    pub fn analyze(capture: &Capture) -> Self {
+        let start = capture.start_time().unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut replay = Replay::default();
+        replay.state().parse_breaker_settings.enabled = false;
+
+        let mut report = Self {
+            messages: capture.messages.len(),
+            duration_ms: capture.duration().as_millis() as u64,
+            ..Default::default()
+        };
+        let mut counts = BTreeMap::new();
+        let mut players = BTreeMap::new();
+        let mut rooms = BTreeMap::<String, ListedRoom>::new();
+
+        for (index, message) in capture.messages.iter().enumerate() {
+            let parsed = message.parse();
+            let kind = match &parsed {
+                Some(parsed) => (message_type_name(parsed), message_code(parsed)),
+                None if PhotonMessage::is_encrypted_websocket_bytes(&message.raw) => {
+                    ("encrypted", None)
+                }
+                None => ("unparsed", None),
+            };
+            let key = (
+                message.server.to_string(),
+                message.direction.to_string(),
+                kind,
+            );
+            *counts.entry(key).or_insert(0u64) += 1;
+
+            if let Some(PhotonMessage::EventData(event)) = &parsed {
+                if event.code == event_code::GAME_LIST || event.code == event_code::GAME_LIST_UPDATE
+                {
+                    let parameters = Parameters(&event.parameters);
+                    let games = parameters.get_as::<&PhotonHashmap>(parameter_code::GAME_LIST);
+                    for (id, room) in games.into_iter().flatten() {
+                        if let (PhotonDataType::String(id), PhotonDataType::Hashtable(room)) =
+                            (id, room)
+                        {
+                            observe_room(&mut rooms, id, room);
+                        }
+                    }
+                }
+            }
+
+            if let Err(e) = replay.hook(message) {
+                let failure = ReplayFailure::new(index, message, start, &e);
+                match e {
+                    HaxError::ProtocolParse { .. } => report.parse_failures.push(failure),
+                    _ => report.handler_failures.push(failure),
+                }
+            }
+
+            if message.server == WebSocketServer::GameServer {
+                if let Some((_, state)) = &replay.state().gameplay_state {
+                    observe_players(&mut players, state);
+                }
+            }
+        }
+
+        report.message_counts = counts
+            .into_iter()
+            .map(
+                |((server, direction, (message_type, code)), count)| MessageCount {
+                    server,
+                    direction,
+                    message_type,
+                    code,
+                    count,
+                },
+            )
+            .collect();
+        report.players = players.into_values().collect();
+        report.rooms = rooms.into_values().collect();
+        report.rpc_usage = replay.into_state().rpc_usage().iter().cloned().collect();
+        report
+    }
+
+    /// The report as pretty-printed json.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("session report should serialize")
+    }
+}
+
+impl ReplayFailure {
+    fn new(index: usize, message: &CapturedMessage, start: SystemTime, error: &HaxError) -> Self {
+        Self {
+            index,
+            at_ms: message
+                .timestamp
+                .duration_since(start)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            server: message.server.to_string(),
+            direction: message.direction.to_string(),
+            len: message.raw.len(),
+            parse_offset: match error {
+                HaxError::ProtocolParse { offset, .. } => Some(*offset),
+                _ => None,
+            },
+            error: error.to_string(),
+        }
+    }
+}
+
+fn observe_room(rooms: &mut BTreeMap<String, ListedRoom>, id: &str, properties: &PhotonHashmap) {
+    let view = RoomInfoView(properties);
+    if view.removed() == Some(&true) {
+        return;
+    }
+    let room = rooms.entry(id.to_string()).or_insert_with(|| ListedRoom {
+        id: id.to_string(),
+        room_name: None,
+        map_name: None,
+        max_player_count: 0,
+        max_players: None,
+        is_open: None,
+    });
+    // updates only hold the properties that changed
+    if let Some(name) = view.room_name() {
+        room.room_name = Some(name.to_string());
+    }
+    if let Some(map) = view.map_name() {
+        room.map_name = Some(map.to_string());
+    }
+    if let Some(count) = view.player_count() {
+        room.max_player_count = room.max_player_count.max(*count);
+    }
+    if let Some(max_players) = view.max_players() {
+        room.max_players = (*max_players != 0).then_some(*max_players);
+    }
+    if let Some(is_open) = view.is_open() {
+        room.is_open = Some(*is_open);
+    }
+}
+
+fn observe_players(
+    players: &mut BTreeMap<(Option<String>, i32), SeenPlayer>,
+    state: &GameplayState,
+) {
+    for (actor_id, player) in &state.players {
+        let seen = players
+            .entry((state.room_name.clone(), *actor_id))
+            .or_insert_with(|| SeenPlayer {
+                room_name: state.room_name.clone(),
+                actor_id: *actor_id,
+                nickname: None,
+                user_id: None,
+            });
+        if player.nickname.is_some() {
+            seen.nickname = player.nickname.clone();
+        }
+        if player.user_id.is_some() {
+            seen.user_id = player.user_id.clone();
+        }
+    }
+}
+
+impl Display for SessionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} messages over {:.1}s",
+            self.messages,
+            Duration::from_millis(self.duration_ms).as_secs_f64()
+        )?;
+
+        writeln!(f, "\nmessages:")?;
+        for count in &self.message_counts {
+            let code = count.code.map(|c| c.to_string()).unwrap_or_default();
+            writeln!(
+                f,
+                "  {:<10} {} {:<26} {code:>3}: {}",
+                count.server, count.direction, count.message_type, count.count
+            )?;
+        }
+
+        writeln!(f, "\nrooms listed: {}", self.rooms.len())?;
+        for room in &self.rooms {
+            write!(
+                f,
+                "  {} on {}, up to {}",
+                room.room_name.as_deref().unwrap_or("<unnamed>"),
+                room.map_name.as_deref().unwrap_or("?"),
+                room.max_player_count,
+            )?;
+            if let Some(max_players) = room.max_players {
+                write!(f, "/{max_players}")?;
+            }
+            write!(f, " players")?;
+            if room.is_open == Some(false) {
+                write!(f, ", closed")?;
+            }
+            writeln!(f, " ({})", room.id)?;
+        }
+
+        writeln!(f, "\nplayers seen: {}", self.players.len())?;
+        for player in &self.players {
+            writeln!(
+                f,
+                "  {} #{}: {}",
+                player.room_name.as_deref().unwrap_or("?"),
+                player.actor_id,
+                player.nickname.as_deref().unwrap_or("<unknown>")
+            )?;
+        }
+
+        writeln!(f, "\nRPC methods: {}", self.rpc_usage.len())?;
+        for usage in &self.rpc_usage {
+            writeln!(
+                f,
+                "  {}: {} sent, {} received, {} callers",
+                usage.method,
+                usage.calls.client_to_server,
+                usage.calls.server_to_client,
+                usage.by_actor.len()
+            )?;
+        }
+
+        for (name, failures) in [
+            ("parse failures", &self.parse_failures),
+            ("handler failures", &self.handler_failures),
+        ] {
+            writeln!(f, "\n{name}: {}", failures.len())?;
+            for failure in failures {
+                writeln!(
+                    f,
+                    "  #{} at {:.3}s ({} {}): {}",
+                    failure.index,
+                    Duration::from_millis(failure.at_ms).as_secs_f64(),
+                    failure.server,
+                    failure.direction,
+                    failure.error
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use photon_lib::{
+        highlevel::{
+            constants::{event_code, game_property_key, operation_code, parameter_code},
+            structs::RoomInfoView,
+        },
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationRequest, OperationResponse, PhotonMessage},
+    };
+
+    use super::SessionReport;
+    use crate::{
+        inspect::{capture::Capture, CapturedMessage},
+        proxy::{Direction, WebSocketServer},
+        testgen::GameListBuilder,
+    };
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_665_000_000) + Duration::from_millis(millis)
+    }
+
+    fn captured(
+        millis: u64,
+        server: WebSocketServer,
+        direction: Direction,
+        message: PhotonMessage,
+    ) -> CapturedMessage {
+        let mut raw = vec![];
+        message.to_websocket_bytes(&mut raw).unwrap();
+        CapturedMessage {
+            timestamp: at(millis),
+            server,
+            direction,
+            raw,
+        }
+    }
+
+    fn authenticate(millis: u64, server: WebSocketServer) -> CapturedMessage {
+        captured(
+            millis,
+            server,
+            Direction::ClientToServer,
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::AUTHENTICATE,
+                parameters: indexmap! {},
+            }),
+        )
+    }
+
+    fn capture() -> Capture {
+        let string = |s: &str| PhotonDataType::String(s.into());
+        let game_list = PhotonMessage::EventData(EventData {
+            code: event_code::GAME_LIST,
+            parameters: indexmap! {
+                parameter_code::GAME_LIST => PhotonDataType::Hashtable(indexmap! {
+                    string("abc") => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Byte(game_property_key::PLAYER_COUNT) => PhotonDataType::Byte(3),
+                        string("roomName") => string("Sniper only"),
+                        string("mapName") => string("Urban"),
+                    }),
+                }),
+            },
+        });
+        let join_response = PhotonMessage::OperationResponse(OperationResponse {
+            operation_code: operation_code::JOIN_GAME,
+            return_code: 0,
+            debug_message: None,
+            parameters: indexmap! {
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(2),
+                parameter_code::ACTOR_LIST => PhotonDataType::IntArray(vec![1, 2]),
+                parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::Integer(1) => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Byte(255) => string("someone"),
+                    }),
+                }),
+                parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+            },
+        });
+
+        let mut truncated = vec![];
+        join_response.to_websocket_bytes(&mut truncated).unwrap();
+        truncated.truncate(truncated.len() / 2);
+
+        Capture {
+            messages: vec![
+                authenticate(0, WebSocketServer::LobbyServer),
+                captured(
+                    100,
+                    WebSocketServer::LobbyServer,
+                    Direction::ServerToClient,
+                    game_list,
+                ),
+                authenticate(1000, WebSocketServer::GameServer),
+                captured(
+                    1200,
+                    WebSocketServer::GameServer,
+                    Direction::ServerToClient,
+                    join_response,
+                ),
+                CapturedMessage {
+                    timestamp: at(1500),
+                    server: WebSocketServer::GameServer,
+                    direction: Direction::ServerToClient,
+                    raw: truncated,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn reports_on_replayed_capture() {
+        let report = SessionReport::analyze(&capture());
+        assert_eq!(report.messages, 5);
+        assert_eq!(report.duration_ms, 1500);
+
+        let count = |server: &str, message_type: &str| {
+            report
+                .message_counts
+                .iter()
+                .filter(|c| c.server == server && c.message_type == message_type)
+                .map(|c| c.count)
+                .sum::<u64>()
+        };
+        assert_eq!(count("lobby", "OperationRequest"), 1);
+        assert_eq!(count("game", "OperationResponse"), 1);
+        assert_eq!(count("game", "unparsed"), 1);
+
+        assert_eq!(report.rooms.len(), 1);
+        assert_eq!(report.rooms[0].room_name.as_deref(), Some("Sniper only"));
+        assert_eq!(report.rooms[0].max_player_count, 3);
+
+        assert_eq!(report.players.len(), 1);
+        assert_eq!(report.players[0].nickname.as_deref(), Some("someone"));
+
+        assert_eq!(report.parse_failures.len(), 1);
+        let failure = &report.parse_failures[0];
+        assert_eq!((failure.index, failure.at_ms), (4, 1500));
+        assert!(failure.parse_offset.is_some());
+        assert!(report.handler_failures.is_empty());
+
+        let text = report.to_string();
+        assert!(text.starts_with("5 messages over 1.5s\n"), "{text}");
+        assert!(text.contains("parse failures: 1"), "{text}");
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["parse_failures"][0]["index"], 4);
+    }
+
+    #[test]
+    fn lists_generated_rooms() {
+        let builder = GameListBuilder::new(40).with_seed(11);
+        let report = SessionReport::analyze(&Capture {
+            messages: vec![
+                authenticate(0, WebSocketServer::LobbyServer),
+                captured(
+                    100,
+                    WebSocketServer::LobbyServer,
+                    Direction::ServerToClient,
+                    builder.build(),
+                ),
+            ],
+        });
+        assert!(report.parse_failures.is_empty() && report.handler_failures.is_empty());
+
+        let mut expected = builder
+            .games()
+            .iter()
+            .map(|(id, room)| match (id, room) {
+                (PhotonDataType::String(id), PhotonDataType::Hashtable(room)) => {
+                    let view = RoomInfoView(room);
+                    (
+                        id.clone(),
+                        view.custom_str("roomName").map(str::to_string),
+                        *view.player_count().unwrap(),
+                        view.max_players().copied(),
+                    )
+                }
+                other => panic!("expected a room, got {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        expected.sort();
+        let listed = report
+            .rooms
+            .iter()
+            .map(|r| {
+                (
+                    r.id.clone(),
+                    r.room_name.clone(),
+                    r.max_player_count,
+                    r.max_players,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(listed, expected);
+        assert!(report.rooms.iter().all(|r| r.is_open == Some(true)));
+    }
+}
@@ -0,0 +1,377 @@
+//! One timeline of a session's websocket messages and [web requests](super::web_requests), ordered by time, so it
+//! shows eg. an item being bought right before joining a room.
+
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use super::{
+    web_requests::{WebEndpoint, WebExchange, WebPhase},
+    HaxState,
+};
+use crate::inspect::{message_code, message_type_name, CapturedMessage};
+
+/// What happened at a point of the timeline.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TimelineItem {
+    /// A websocket message, `unparsed` if it couldn't be read.
+    Photon {
+        server: String,
+        direction: String,
+        message_type: &'static str,
+        code: Option<u8>,
+    },
+    Web {
+        endpoint: WebEndpoint,
+        phase: WebPhase,
+        url: String,
+        summary: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TimelineEntry {
+    /// Milliseconds since the first entry.
+    pub at_ms: u64,
+    #[serde(flatten)]
+    pub item: TimelineItem,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SessionTimeline {
+    pub session_id: String,
+    pub entries: Vec<TimelineEntry>,
+}
+
+impl SessionTimeline {
+    /// Interleaves the messages with the session's web exchanges. Exchanges of other sessions are left out, entries
+    /// with the same timestamp keep the websocket messages first.
+    pub fn merge<'a>(
+        session_id: &str,
+        messages: impl IntoIterator<Item = &'a CapturedMessage>,
+        web: impl IntoIterator<Item = &'a WebExchange>,
+    ) -> Self {
+        let mut timed = messages
+            .into_iter()
+            .map(|message| (message.timestamp, photon_item(message)))
+            .chain(
+                web.into_iter()
+                    .filter(|exchange| exchange.session_id == session_id)
+                    .map(|exchange| (exchange.timestamp, web_item(exchange))),
+            )
+            .collect::<Vec<_>>();
+        // stable, so simultaneous entries stay in the order they were given
+        timed.sort_by_key(|(timestamp, _)| *timestamp);
+
+        let start = timed.first().map_or(SystemTime::UNIX_EPOCH, |(t, _)| *t);
+        Self {
+            session_id: session_id.into(),
+            entries: timed
+                .into_iter()
+                .map(|(timestamp, item)| TimelineEntry {
+                    at_ms: timestamp
+                        .duration_since(start)
+                        .unwrap_or_default()
+                        .as_millis() as u64,
+                    item,
+                })
+                .collect(),
+        }
+    }
+
+    /// The timeline as pretty-printed json.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("session timeline should serialize")
+    }
+}
+
+fn photon_item(message: &CapturedMessage) -> TimelineItem {
+    let parsed = message.parse();
+    TimelineItem::Photon {
+        server: message.server.to_string(),
+        direction: message.direction.to_string(),
+        message_type: parsed.as_ref().map_or("unparsed", message_type_name),
+        code: parsed.as_ref().and_then(message_code),
+    }
+}
+
+fn web_item(exchange: &WebExchange) -> TimelineItem {
+    TimelineItem::Web {
+        endpoint: exchange.endpoint,
+        phase: exchange.phase,
+        url: exchange.url.clone(),
+        summary: exchange.summary.clone(),
+    }
+}
+
+impl HaxState {
+    /// The current session's [recent messages](Self::recent_messages) and web requests on one timeline.
+    pub fn session_timeline(&self) -> SessionTimeline {
+        let messages = self.recent_messages.iter().collect::<Vec<_>>();
+        SessionTimeline::merge(
+            self.join_trace.session_id(),
+            messages.iter().map(|m| m.as_ref()),
+            self.web_requests.entries(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use futures_util::lock::Mutex;
+    use photon_lib::{
+        highlevel::constants::operation_code,
+        indexmap::indexmap,
+        photon_message::{OperationRequest, PhotonMessage},
+    };
+
+    use super::{SessionTimeline, TimelineItem};
+    use crate::{
+        hax::{
+            events::HaxEvent,
+            web_requests::{SessionContext, WebEndpoint, WebExchange, WebPhase},
+            HaxState,
+        },
+        inspect::{capture::Capture, CapturedMessage, MessageBuffer},
+        proxy::{Direction, WebSocketServer},
+        testsupport::ProxiedConnection,
+    };
+
+    fn captured(timestamp: SystemTime, operation_code: u8) -> CapturedMessage {
+        let mut raw = vec![];
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code,
+            parameters: indexmap! {},
+        })
+        .to_websocket_bytes(&mut raw)
+        .unwrap();
+        CapturedMessage {
+            timestamp,
+            server: WebSocketServer::GameServer,
+            direction: Direction::ClientToServer,
+            raw,
+        }
+    }
+
+    /// The entries as `<endpoint> <phase>` for web exchanges and the code for the client's requests, leaving out
+    /// the rest.
+    fn sequence(timeline: &SessionTimeline) -> Vec<String> {
+        timeline
+            .entries
+            .iter()
+            .filter_map(|entry| match &entry.item {
+                TimelineItem::Photon {
+                    message_type: "OperationRequest",
+                    code: Some(code),
+                    ..
+                } => Some(code.to_string()),
+                TimelineItem::Photon { .. } => None,
+                TimelineItem::Web {
+                    endpoint, phase, ..
+                } => Some(format!("{endpoint} {phase:?}")),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn web_requests_are_interleaved() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let (session_id, mut events) = {
+            let hax = futures::executor::block_on(state.lock());
+            (
+                hax.join_trace.session_id().to_string(),
+                hax.events.subscribe(),
+            )
+        };
+        let start = SystemTime::now();
+        let at = |ms: u64| SessionContext {
+            session_id: session_id.clone(),
+            timestamp: start + Duration::from_millis(ms),
+        };
+
+        // bought an item, then joined a room and changed the loadout while in it
+        let shop = "https://server.example.com/shop/purchase_item.php?key=secret"
+            .parse()
+            .unwrap();
+        let loadout = "https://server.example.com/update_loadout.php"
+            .parse()
+            .unwrap();
+        let unrelated = "https://server.example.com/news.php".parse().unwrap();
+        let mut body = b"item=M4A1&currency=credits".to_vec();
+        HaxState::webrequest_hook_onrequest(state.clone(), &at(0), &shop, &mut body).unwrap();
+        let mut body = br#"{"success":true}"#.to_vec();
+        HaxState::webrequest_hook_onresponse(state.clone(), &at(150), &shop, &mut body).unwrap();
+        HaxState::webrequest_hook_onrequest(state.clone(), &at(200), &unrelated, &mut vec![])
+            .unwrap();
+        let mut body = b"primary=M4A1".to_vec();
+        HaxState::webrequest_hook_onrequest(state.clone(), &at(3000), &loadout, &mut body).unwrap();
+        // another session's request doesn't belong on this timeline
+        let other = SessionContext {
+            session_id: "other".into(),
+            timestamp: start + Duration::from_millis(2500),
+        };
+        HaxState::webrequest_hook_onrequest(state.clone(), &other, &loadout, &mut vec![]).unwrap();
+
+        let capture = Capture {
+            messages: vec![
+                captured(
+                    start + Duration::from_millis(1000),
+                    operation_code::JOIN_GAME,
+                ),
+                captured(
+                    start + Duration::from_millis(3000),
+                    operation_code::RAISE_EVENT,
+                ),
+                captured(start + Duration::from_millis(5000), operation_code::LEAVE),
+            ],
+        };
+        let hax = futures::executor::block_on(state.lock());
+        let timeline =
+            SessionTimeline::merge(&session_id, &capture.messages, hax.web_requests.entries());
+
+        let order = timeline
+            .entries
+            .iter()
+            .map(|entry| match &entry.item {
+                TimelineItem::Photon { code, .. } => format!("{} photon {code:?}", entry.at_ms),
+                TimelineItem::Web {
+                    endpoint, phase, ..
+                } => format!("{} {endpoint} {phase:?}", entry.at_ms),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            [
+                "0 shop Request",
+                "150 shop Response",
+                "1000 photon Some(226)",
+                "3000 photon Some(253)",
+                "3000 loadout Request",
+                "5000 photon Some(254)",
+            ]
+        );
+        assert_eq!(
+            timeline.entries[0].item,
+            TimelineItem::Web {
+                endpoint: WebEndpoint::Shop,
+                phase: WebPhase::Request,
+                url: "server.example.com/shop/purchase_item.php".into(),
+                summary: "item=M4A1, currency=credits".into(),
+            }
+        );
+        let json = timeline.to_json();
+        assert!(json.contains(r#""kind": "web""#) && json.contains(r#""endpoint": "shop""#));
+
+        // every tracked exchange was announced, including the other session's
+        let mut announced = 0;
+        while let Ok(HaxEvent::WebRequest(_)) = events.try_recv() {
+            announced += 1;
+        }
+        assert_eq!(announced, 4);
+    }
+    #[tokio::test]
+    async fn reconnects_stay_in_order() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let session_id = state.lock().await.join_trace.session_id().to_string();
+        let loadout = "https://server.example.com/update_loadout.php"
+            .parse()
+            .unwrap();
+        let request = |operation_code| {
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code,
+                parameters: indexmap! {},
+            })
+        };
+        // entries with the same timestamp would keep the websocket messages first
+        let tick = || tokio::time::sleep(Duration::from_millis(5));
+
+        let mut first =
+            ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        first.client_send(request(operation_code::JOIN_GAME)).await;
+        first.server.recv().await;
+        tick().await;
+        let session = SessionContext::now(&session_id);
+        HaxState::webrequest_hook_onrequest(state.clone(), &session, &loadout, &mut vec![])
+            .unwrap();
+        tick().await;
+        first.close().await;
+
+        // the session outlives its connections, so the new one continues the same timeline
+        let mut second =
+            ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        second
+            .client_send(request(operation_code::GET_PROPERTIES))
+            .await;
+        second.server.recv().await;
+        tick().await;
+        let session = SessionContext::now(&session_id);
+        HaxState::webrequest_hook_onresponse(state.clone(), &session, &loadout, &mut vec![])
+            .unwrap();
+        tick().await;
+        second.client_send(request(operation_code::LEAVE)).await;
+        second.server.recv().await;
+
+        let timeline = state.lock().await.session_timeline();
+        assert_eq!(
+            sequence(&timeline),
+            [
+                operation_code::JOIN_GAME.to_string(),
+                "loadout Request".into(),
+                operation_code::GET_PROPERTIES.to_string(),
+                "loadout Response".into(),
+                operation_code::LEAVE.to_string(),
+            ]
+        );
+        assert!(timeline
+            .entries
+            .windows(2)
+            .all(|pair| pair[0].at_ms <= pair[1].at_ms));
+    }
+
+    #[test]
+    fn timeline_is_bounded_by_the_buffers() {
+        let mut hax = HaxState {
+            recent_messages: MessageBuffer::with_capacity(8),
+            ..Default::default()
+        };
+        let session = SessionContext {
+            session_id: hax.join_trace.session_id().to_string(),
+            timestamp: SystemTime::now(),
+        };
+        let loadout = "https://server.example.com/update_loadout.php"
+            .parse()
+            .unwrap();
+        let recorded = 300;
+        for i in 0..recorded {
+            let timestamp = session.timestamp + Duration::from_millis(i);
+            hax.recent_messages
+                .push(captured(timestamp, operation_code::LEAVE));
+            let at = SessionContext {
+                timestamp,
+                ..session.clone()
+            };
+            hax.web_requests.record(WebExchange::new(
+                &at,
+                WebEndpoint::Loadout,
+                WebPhase::Request,
+                &loadout,
+                b"",
+            ));
+        }
+
+        let kept = hax.web_requests.entries().len() as u64;
+        assert!(kept < recorded);
+        let timeline = hax.session_timeline();
+        assert_eq!(timeline.entries.len() as u64, 8 + kept);
+        // the oldest were forgotten first, so the timeline runs from the oldest kept web exchange to the newest one
+        let last = timeline.entries.last().unwrap();
+        assert_eq!(last.at_ms, (recorded - 1) - (recorded - kept));
+        assert!(matches!(last.item, TimelineItem::Web { .. }));
+    }
+}
@@ -0,0 +1,166 @@
+//! The feature toggles, kept apart from the rest of [HaxState](super::HaxState) so reading them doesn't wait on its
+//! lock.
+//!
+//! The hook takes a [Settings] snapshot once per message and every handler reads from that, while the UI and the
+//! profiles swap in a new snapshot when something changes. Snapshots are immutable, so holding on to one for a long
+//! time only means missing later changes, it never blocks anyone. What the handlers update as traffic goes by, such as
+//! the trackers and the settings tuning them, stays behind the state's mutex.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
+
+use photon_lib::protocol_version::ProtocolVersion;
+
+use super::{
+    auth_overrides::AuthOverrides, dry_run::DryRunSettings, game_variant::VariantSettings,
+    lobby_sort::LobbySort, platform_spoof::PlatformSpoof, DebugSettings,
+};
+
+/// The toggles the handlers read on every message, but that only change when the user changes them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settings {
+    pub show_mobile_games: bool,
+    pub show_other_versions: bool,
+    pub strip_passwords: bool,
+    pub spoofed_name: (bool, String),
+    pub forced_region: (bool, String),
+    /// The order to show rooms in the lobby in. If not set, rooms are shown in the order the server sends them.
+    pub lobby_sort: Option<LobbySort>,
+    /// Which game variants the lobby features apply to, see [game_variant](super::game_variant).
+    pub lobby_variants: VariantSettings,
+    /// Prefix lobby room names with how far along their round is, see [match_phase](super::match_phase).
+    pub lobby_phase_annotations: bool,
+    /// Hold joins of full rooms until a slot frees, see [hold](super::hold).
+    pub queue_jump: bool,
+    /// Drop cosmetic RPCs from all players.
+    pub mute_all_cosmetic: bool,
+    /// Drop cosmetic RPCs from these actors.
+    pub muted_actors: HashSet<i32>,
+    /// Join rooms without spawning a player for the other clients, see [ghost_join](super::ghost_join).
+    pub ghost_join: bool,
+    /// What to send instead of the client's authentication parameters, see [auth_overrides](super::auth_overrides).
+    pub auth_overrides: Option<AuthOverrides>,
+    /// What to report instead of our platform when joining rooms, see [platform_spoof](super::platform_spoof).
+    pub platform_spoof: Option<PlatformSpoof>,
+    /// Present a generated user id and nickname to the servers each session, see
+    /// [identity_randomizer](super::identity_randomizer).
+    pub identity_randomizer: bool,
+    /// The serialization protocol to pin new connections to, instead of letting the server pick one of those the
+    /// client offers. Connections whose client doesn't offer it are left alone, those whose server doesn't accept it
+    /// are refused.
+    pub forced_protocol: Option<ProtocolVersion>,
+    pub debug: DebugSettings,
+    /// Forward every message unchanged and refuse to inject any, whatever the other settings say. What features would
+    /// have changed is logged as in a [dry run](super::dry_run).
+    pub observe_only: bool,
+    /// Features whose changes are only logged instead of applied, see [dry_run](super::dry_run).
+    pub dry_run: DryRunSettings,
+    /// The settings from before the [global pause](super::HaxState::set_global_pause), while paused.
+    pub paused: Option<Box<Settings>>,
+}
+
+impl Settings {
+    /// Whether messages must be forwarded unchanged and nothing injected, because of [Self::observe_only] or a
+    /// global pause.
+    pub fn is_inert(&self) -> bool {
+        self.observe_only || self.paused.is_some()
+    }
+}
+
+/// A handle to the current [Settings]. Clones share the same settings.
+#[derive(Debug, Clone, Default)]
+pub struct SharedSettings(Arc<RwLock<Arc<Settings>>>);
+
+impl SharedSettings {
+    pub fn new(settings: Settings) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(settings))))
+    }
+
+    /// The current settings. The lock is only held long enough to clone the [Arc].
+    pub fn load(&self) -> Arc<Settings> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn store(&self, settings: Settings) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(settings);
+    }
+
+    /// Changes a copy of the current settings and makes it the current one. Readers holding the old snapshot keep
+    /// seeing it unchanged.
+    pub fn update<R>(&self, f: impl FnOnce(&mut Settings) -> R) -> R {
+        let mut current = self.0.write().unwrap_or_else(|e| e.into_inner());
+        let mut settings = Settings::clone(&current);
+        let result = f(&mut settings);
+        *current = Arc::new(settings);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Settings, SharedSettings};
+    use crate::hax::{
+        bandwidth::feature, dry_run::DryRunSettings, events::HaxEvent, lobby_sort::LobbySort,
+        HaxState,
+    };
+
+    #[test]
+    fn snapshots_are_unaffected_by_updates() {
+        let shared = SharedSettings::default();
+        let before = shared.load();
+        shared.clone().update(|s| s.muted_actors.insert(3));
+
+        assert!(before.muted_actors.is_empty());
+        assert!(shared.load().muted_actors.contains(&3));
+    }
+
+    #[test]
+    fn resuming_restores_the_settings_from_before_the_pause() {
+        let mut hax = HaxState::default();
+        hax.shared_settings().store(Settings {
+            strip_passwords: true,
+            spoofed_name: (true, "streamer".into()),
+            lobby_sort: Some(LobbySort::PlayerCount),
+            queue_jump: true,
+            muted_actors: [3, 5].into(),
+            dry_run: DryRunSettings {
+                enabled: false,
+                features: [(feature::GHOST_JOIN, true)].into_iter().collect(),
+            },
+            ..Default::default()
+        });
+        let before = hax.settings();
+        let mut events = hax.events.subscribe();
+
+        hax.set_global_pause(true);
+        let paused = hax.settings();
+        assert!(paused.is_inert());
+        assert!(!paused.observe_only);
+        assert!(!paused.strip_passwords && !paused.queue_jump && paused.muted_actors.is_empty());
+        assert!(hax.global_pause());
+
+        // pausing twice doesn't lose the settings from before
+        hax.set_global_pause(true);
+        // nor do changes made while paused stick
+        hax.update_settings(|s| s.ghost_join = true);
+
+        hax.set_global_pause(false);
+        assert_eq!(*hax.settings(), *before);
+        assert!(!hax.global_pause());
+
+        assert!(matches!(
+            events.try_recv(),
+            Ok(HaxEvent::GlobalPauseChanged {
+                paused: true,
+                flushed: 0
+            })
+        ));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(HaxEvent::GlobalPauseChanged { paused: false, .. })
+        ));
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,521 @@
+//! Exports the players of the current game to a shared memory region, for external overlays that need the data at a
+//! higher rate than a socket can comfortably provide.
+//!
+//! The region is a file mapped into memory. On Linux it is placed in `/dev/shm` so it never touches the disk, other
+//! platforms use the temp directory. Readers map the same file read-only.
+//!
+//! # Layout
+//!
+//! The file holds a single [SharedRegion]: a [SharedHeader] followed by two [SharedSnapshot] buffers, laid out as
+//! declared with `#[repr(C)]` in the native byte order. [SharedHeader::magic] and [SharedHeader::version] identify
+//! the layout, the version is bumped whenever it changes.
+//!
+//! # Reading
+//!
+//! The writer never waits for readers. It writes each snapshot to the buffer that `active` does not point at, bumping
+//! that buffer's `sequence` to an odd number before writing and to the next even number after, and then points
+//! `active` at it. To read a consistent snapshot:
+//!
+//! 1. read `active` to pick a buffer
+//! 2. read that buffer's `sequence`, and start over if it is odd because a write is in progress
+//! 3. copy the buffer's `data`
+//! 4. read `sequence` again, and start over if it changed because the copy is torn
+//!
+//! [read_snapshot] implements this, `examples/shared_state_reader.rs` shows how to use it.
+
+use std::{
+    fs::OpenOptions,
+    io,
+    mem::{align_of, size_of},
+    path::{Path, PathBuf},
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{fence, AtomicU32, AtomicU64, Ordering},
+};
+
+use memmap2::MmapMut;
+
+use super::{map_annotations::MapAnnotations, GameplayState, HaxState};
+
+/// `BFHX` in little-endian, the first bytes of a region.
+pub const MAGIC: u32 = u32::from_le_bytes(*b"BFHX");
+/// The version of the layout, bumped whenever it changes.
+pub const LAYOUT_VERSION: u32 = 3;
+/// How many players a snapshot holds. Players past this are left out.
+pub const MAX_PLAYERS: usize = 32;
+/// How many bytes of a callout name fit in [SharedPlayer::callout].
+pub const MAX_CALLOUT_LEN: usize = 24;
+/// How often [read_snapshot] retries a torn read before giving up.
+const MAX_READ_ATTEMPTS: usize = 64;
+
+/// Bits of [SharedPlayer::flags], telling which fields are known.
+pub mod player_flags {
+    pub const HAS_POSITION: u32 = 1 << 0;
+    pub const HAS_HEALTH: u32 = 1 << 1;
+    pub const HAS_TEAM: u32 = 1 << 2;
+    pub const HAS_NAME: u32 = 1 << 3;
+    /// This is the player using the proxy.
+    pub const LOCAL_PLAYER: u32 = 1 << 4;
+    /// Both weapons and the camo are known, and fit their fields.
+    pub const HAS_LOADOUT: u32 = 1 << 5;
+    /// The player is in one of the [map callouts](crate::hax::map_annotations).
+    pub const HAS_CALLOUT: u32 = 1 << 6;
+}
+
+/// A single player, 64 bytes.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SharedPlayer {
+    pub actor_id: i32,
+    /// See [player_flags]. Fields whose flag is not set are zero.
+    pub flags: u32,
+    pub position: [f32; 3],
+    pub health: f32,
+    pub team: u32,
+    /// The ids of the [loadout](crate::protocol::loadout), names can be looked up in
+    /// [WEAPON_NAMES](crate::protocol::loadout::WEAPON_NAMES) and [CAMO_NAMES](crate::protocol::loadout::CAMO_NAMES).
+    pub primary_weapon: u8,
+    pub secondary_weapon: u8,
+    pub camo: u16,
+    /// The 64-bit FNV-1a hash of the UTF-8 nickname, see [name_hash].
+    pub name_hash: u64,
+    /// The UTF-8 name of the callout the player is in, padded with zeroes. Longer names are cut off at a character
+    /// boundary.
+    pub callout: [u8; MAX_CALLOUT_LEN],
+}
+
+impl SharedPlayer {
+    pub const EMPTY: SharedPlayer = SharedPlayer {
+        actor_id: 0,
+        flags: 0,
+        position: [0.0; 3],
+        health: 0.0,
+        team: 0,
+        primary_weapon: 0,
+        secondary_weapon: 0,
+        camo: 0,
+        name_hash: 0,
+        callout: [0; MAX_CALLOUT_LEN],
+    };
+
+    /// The name of the callout the player is in, if [player_flags::HAS_CALLOUT] is set.
+    pub fn callout(&self) -> Option<&str> {
+        if self.flags & player_flags::HAS_CALLOUT == 0 {
+            return None;
+        }
+        let len = self
+            .callout
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(MAX_CALLOUT_LEN);
+        std::str::from_utf8(&self.callout[..len]).ok()
+    }
+
+    fn set_callout(&mut self, name: &str) {
+        let mut len = name.len().min(MAX_CALLOUT_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.callout = [0; MAX_CALLOUT_LEN];
+        self.callout[..len].copy_from_slice(&name.as_bytes()[..len]);
+        self.flags |= player_flags::HAS_CALLOUT;
+    }
+}
+
+/// The players of the current game, empty when not in a game.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SnapshotData {
+    /// How many entries of [Self::players] are used.
+    pub player_count: u32,
+    pub _reserved: u32,
+    pub players: [SharedPlayer; MAX_PLAYERS],
+}
+
+impl SnapshotData {
+    pub const EMPTY: SnapshotData = SnapshotData {
+        player_count: 0,
+        _reserved: 0,
+        players: [SharedPlayer::EMPTY; MAX_PLAYERS],
+    };
+
+    pub fn players(&self) -> &[SharedPlayer] {
+        &self.players[..(self.player_count as usize).min(MAX_PLAYERS)]
+    }
+
+    fn from_gameplay(state: Option<&GameplayState>, annotations: &MapAnnotations) -> Self {
+        let mut data = Self::EMPTY;
+        let state = match state {
+            Some(s) => s,
+            None => return data,
+        };
+
+        for (slot, (actor_id, actor)) in data.players.iter_mut().zip(&state.players) {
+            let mut player = SharedPlayer {
+                actor_id: *actor_id,
+                ..SharedPlayer::EMPTY
+            };
+            if let Some(position) = &actor.position {
+                let (x, y, z) = position.floats();
+                player.position = [x, y, z];
+                player.flags |= player_flags::HAS_POSITION;
+
+                let map = state.map_name.as_deref();
+                if let Some(callout) = map.and_then(|map| annotations.callout_for(map, position)) {
+                    player.set_callout(callout);
+                }
+            }
+            if let Some(health) = actor.health {
+                player.health = health;
+                player.flags |= player_flags::HAS_HEALTH;
+            }
+            if let Some(team) = actor.team_number {
+                player.team = team as u32;
+                player.flags |= player_flags::HAS_TEAM;
+            }
+            let loadout = &actor.loadout;
+            if let (Some(primary), Some(secondary), Some(camo)) = (
+                loadout.primary.and_then(|w| u8::try_from(w.id).ok()),
+                loadout.secondary.and_then(|w| u8::try_from(w.id).ok()),
+                loadout.camo.and_then(|c| u16::try_from(c.id).ok()),
+            ) {
+                player.primary_weapon = primary;
+                player.secondary_weapon = secondary;
+                player.camo = camo;
+                player.flags |= player_flags::HAS_LOADOUT;
+            }
+            if let Some(nickname) = &actor.nickname {
+                player.name_hash = name_hash(nickname);
+                player.flags |= player_flags::HAS_NAME;
+            }
+            if state.actor_nr == Some(*actor_id) {
+                player.flags |= player_flags::LOCAL_PLAYER;
+            }
+            *slot = player;
+        }
+        data.player_count = state.players.len().min(MAX_PLAYERS) as u32;
+        data
+    }
+}
+
+/// One of the two buffers of the region.
+#[repr(C)]
+pub struct SharedSnapshot {
+    /// Odd while [Self::data] is being written, incremented twice for every write.
+    pub sequence: AtomicU64,
+    pub data: SnapshotData,
+}
+
+#[repr(C)]
+pub struct SharedHeader {
+    /// Always [MAGIC].
+    pub magic: u32,
+    /// Always [LAYOUT_VERSION].
+    pub version: u32,
+    /// The index of the buffer holding the latest snapshot, 0 or 1.
+    pub active: AtomicU32,
+    pub _reserved: u32,
+    /// How many snapshots were published, to cheaply check for changes.
+    pub generation: AtomicU64,
+}
+
+/// The whole shared memory region.
+#[repr(C)]
+pub struct SharedRegion {
+    pub header: SharedHeader,
+    pub buffers: [SharedSnapshot; 2],
+}
+
+impl SharedRegion {
+    /// Interprets a mapped region, checking that it's large enough and has the expected layout.
+    pub fn from_bytes(bytes: &[u8]) -> Option<&SharedRegion> {
+        if bytes.len() < size_of::<SharedRegion>()
+            || !(bytes.as_ptr() as usize).is_multiple_of(align_of::<SharedRegion>())
+        {
+            return None;
+        }
+        // SAFETY: the size and alignment were checked, and every bit pattern is a valid SharedRegion
+        let region = unsafe { &*(bytes.as_ptr() as *const SharedRegion) };
+        match (region.header.magic, region.header.version) {
+            (MAGIC, LAYOUT_VERSION) => Some(region),
+            _ => None,
+        }
+    }
+}
+
+/// The hash used for [SharedPlayer::name_hash]: 64-bit FNV-1a over the UTF-8 bytes of the nickname.
+pub fn name_hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Reads the latest consistent snapshot, retrying torn reads. Returns [None] if the writer kept interfering.
+pub fn read_snapshot(region: &SharedRegion) -> Option<SnapshotData> {
+    for _ in 0..MAX_READ_ATTEMPTS {
+        let active = region.header.active.load(Ordering::Acquire) as usize & 1;
+        let buffer = &region.buffers[active];
+
+        let before = buffer.sequence.load(Ordering::Acquire);
+        if before % 2 == 1 {
+            std::hint::spin_loop();
+            continue;
+        }
+        // SAFETY: the data is plain old data, a torn copy is detected through the sequence number below
+        let data = unsafe { std::ptr::read_volatile(addr_of!(buffer.data)) };
+        fence(Ordering::Acquire);
+        if buffer.sequence.load(Ordering::Relaxed) == before {
+            return Some(data);
+        }
+    }
+    None
+}
+
+/// Writes snapshots to a shared memory region.
+pub struct SharedStateExporter {
+    mmap: MmapMut,
+    path: PathBuf,
+    /// The last published snapshot, to skip publishing when nothing changed.
+    last: SnapshotData,
+}
+
+impl SharedStateExporter {
+    /// The path of the region with the given name for this platform.
+    pub fn default_path(name: &str) -> PathBuf {
+        let shm = Path::new("/dev/shm");
+        match cfg!(target_os = "linux") && shm.is_dir() {
+            true => shm.join(name),
+            false => std::env::temp_dir().join(name),
+        }
+    }
+
+    /// Creates or takes over the region at the given path, and publishes an empty snapshot to it.
+    pub fn create(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        file.set_len(size_of::<SharedRegion>() as u64)?;
+        // SAFETY: the file is only written through this mapping, readers only read it
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+        mmap.fill(0);
+
+        let mut exporter = Self {
+            mmap,
+            path,
+            last: SnapshotData::EMPTY,
+        };
+        let region = exporter.region_ptr();
+        // SAFETY: the mapping is page-aligned and large enough to hold a SharedRegion
+        unsafe {
+            (*region).header.magic = MAGIC;
+            (*region).header.version = LAYOUT_VERSION;
+        }
+        exporter.write(&SnapshotData::EMPTY);
+        Ok(exporter)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Publishes a snapshot, unless it's the same as the last one. This never waits for readers.
+    pub fn publish(&mut self, data: &SnapshotData) {
+        if *data != self.last {
+            self.write(data);
+            self.last = *data;
+        }
+    }
+
+    fn region_ptr(&mut self) -> *mut SharedRegion {
+        self.mmap.as_mut_ptr() as *mut SharedRegion
+    }
+
+    fn write(&mut self, data: &SnapshotData) {
+        let region = self.region_ptr();
+        // SAFETY: the mapping holds a SharedRegion, and we're the only writer
+        unsafe {
+            let header = &(*region).header;
+            let next = (header.active.load(Ordering::Relaxed) as usize + 1) & 1;
+            let buffer = addr_of_mut!((*region).buffers[next]);
+
+            let sequence = (*buffer).sequence.load(Ordering::Relaxed);
+            (*buffer).sequence.store(sequence + 1, Ordering::Relaxed);
+            fence(Ordering::Release);
+            std::ptr::write_volatile(addr_of_mut!((*buffer).data), *data);
+            (*buffer).sequence.store(sequence + 2, Ordering::Release);
+
+            header.active.store(next as u32, Ordering::Release);
+            header.generation.fetch_add(1, Ordering::Release);
+        }
+    }
+}
+
+impl HaxState {
+    /// Publishes the players of the current game to the [shared state exporter](Self::shared_state), if any.
+    pub fn publish_shared_state(&mut self) {
+        if let Some(exporter) = &mut self.shared_state {
+            let gameplay = self.gameplay_state.as_ref().map(|(_, state)| state);
+            exporter.publish(&SnapshotData::from_gameplay(
+                gameplay,
+                &self.map_annotations,
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{mem::size_of, sync::atomic::Ordering};
+
+    use photon_lib::{ordered_float::OrderedFloat, primitives::Vector3};
+
+    use super::{
+        name_hash, player_flags, read_snapshot, SharedPlayer, SharedRegion, SharedStateExporter,
+        SnapshotData, MAX_CALLOUT_LEN,
+    };
+    use crate::{
+        hax::{map_annotations::MapAnnotations, GameplayState, PlayerActor},
+        protocol::loadout::{Loadout, LoadoutItem},
+    };
+
+    fn exporter(name: &str) -> SharedStateExporter {
+        let path =
+            std::env::temp_dir().join(format!("bfhax-shared-state-{name}-{}", std::process::id()));
+        SharedStateExporter::create(path).unwrap()
+    }
+
+    fn gameplay() -> GameplayState {
+        let mut state = GameplayState {
+            actor_nr: Some(2),
+            map_name: Some("Urban".into()),
+            ..Default::default()
+        };
+        state.players.insert(
+            1,
+            PlayerActor {
+                nickname: Some("host".into()),
+                team_number: Some(1),
+                health: Some(0.5),
+                loadout: Loadout {
+                    primary: Some(LoadoutItem::weapon(1)),
+                    secondary: Some(LoadoutItem::weapon(9)),
+                    camo: Some(LoadoutItem::camo(4)),
+                },
+                position: Some(Vector3(
+                    OrderedFloat(24.0),
+                    OrderedFloat(0.5),
+                    OrderedFloat(30.0),
+                )),
+                ..Default::default()
+            },
+        );
+        state.players.insert(2, PlayerActor::default());
+        state
+    }
+
+    #[test]
+    fn layout_is_stable() {
+        assert_eq!(size_of::<SharedPlayer>(), 64);
+        assert_eq!(size_of::<SnapshotData>(), 8 + 64 * 32);
+        assert_eq!(size_of::<SharedRegion>(), 24 + 2 * (8 + 8 + 64 * 32));
+        // the reference values of FNV-1a
+        assert_eq!(name_hash(""), 0xcbf29ce484222325);
+        assert_eq!(name_hash("a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn published_snapshot_can_be_read() {
+        let mut exporter = exporter("publish");
+        let file = std::fs::read(exporter.path()).unwrap();
+        assert_eq!(file.len(), size_of::<SharedRegion>());
+
+        let state = gameplay();
+        let annotations = MapAnnotations::builtin();
+        exporter.publish(&SnapshotData::from_gameplay(Some(&state), &annotations));
+        // unchanged snapshots are not written again
+        exporter.publish(&SnapshotData::from_gameplay(Some(&state), &annotations));
+
+        let region = SharedRegion::from_bytes(&exporter.mmap).unwrap();
+        assert_eq!(region.header.generation.load(Ordering::Acquire), 2);
+        let snapshot = read_snapshot(region).unwrap();
+        let players = snapshot.players();
+        assert_eq!(players.len(), 2);
+        let mut callout = [0; MAX_CALLOUT_LEN];
+        callout[..6].copy_from_slice(b"A site");
+        assert_eq!(
+            players[0],
+            SharedPlayer {
+                actor_id: 1,
+                flags: player_flags::HAS_POSITION
+                    | player_flags::HAS_HEALTH
+                    | player_flags::HAS_TEAM
+                    | player_flags::HAS_NAME
+                    | player_flags::HAS_LOADOUT
+                    | player_flags::HAS_CALLOUT,
+                position: [24.0, 0.5, 30.0],
+                health: 0.5,
+                team: 1,
+                primary_weapon: 1,
+                secondary_weapon: 9,
+                camo: 4,
+                name_hash: name_hash("host"),
+                callout,
+            }
+        );
+        assert_eq!(players[0].callout(), Some("A site"));
+        assert_eq!(players[1].callout(), None);
+        assert_eq!(players[1].flags, player_flags::LOCAL_PLAYER);
+
+        // leaving the game clears the players
+        exporter.publish(&SnapshotData::from_gameplay(None, &annotations));
+        let region = SharedRegion::from_bytes(&exporter.mmap).unwrap();
+        assert_eq!(read_snapshot(region).unwrap().players(), []);
+
+        std::fs::remove_file(exporter.path()).unwrap();
+    }
+
+    #[test]
+    fn long_callouts_are_cut_at_a_character() {
+        let mut player = SharedPlayer::EMPTY;
+        assert_eq!(player.callout(), None);
+        player.set_callout("north east staircase landing");
+        assert_eq!(player.callout(), Some("north east staircase lan"));
+        // the 24th byte is in the middle of the last character
+        player.set_callout("stairs by the café baré");
+        assert_eq!(player.callout(), Some("stairs by the café bar"));
+    }
+
+    #[test]
+    fn torn_reads_are_retried() {
+        let mut exporter = exporter("torn");
+        exporter.publish(&SnapshotData::from_gameplay(
+            Some(&gameplay()),
+            &MapAnnotations::builtin(),
+        ));
+
+        let region = exporter.region_ptr();
+        // SAFETY: the exporter's mapping holds a region
+        let region = unsafe { &*region };
+        let active = region.header.active.load(Ordering::Acquire) as usize;
+
+        // a write in progress on the active buffer
+        region.buffers[active]
+            .sequence
+            .fetch_add(1, Ordering::Release);
+        assert_eq!(read_snapshot(region), None);
+
+        region.buffers[active]
+            .sequence
+            .fetch_add(1, Ordering::Release);
+        assert_eq!(read_snapshot(region).unwrap().player_count, 2);
+
+        // other layouts are rejected
+        exporter.mmap[0] ^= 1;
+        assert!(SharedRegion::from_bytes(&exporter.mmap).is_none());
+
+        std::fs::remove_file(exporter.path()).unwrap();
+    }
+}
@@ -0,0 +1,315 @@
+//! Stopping the proxy without losing anything, see [BulletForceHax::shutdown].
+//!
+//! Killing the process mid-session leaves compressed capture files without their index, loses the settings, and leaves
+//! the game waiting on a connection nobody answers anymore. A shutdown goes through these steps instead:
+//!
+//! 1. The listeners are stopped, so no new connections come in.
+//! 2. Every websocket connection is closed with a close frame to both the client and the server, so the game shows
+//!    that it was disconnected instead of hanging.
+//! 3. The [capture sinks](crate::inspect::sink) are finished, which writes the index of compressed files.
+//! 4. The settings are saved as the [SESSION_PROFILE]. The room notes, watchlist and match history are saved whenever
+//!    they change, they're saved once more in case that failed. The map annotations are only ever read, so there's
+//!    nothing to save for them.
+//! 5. [HaxEvent::ShutDown] is emitted.
+//!
+//! Only waiting for the connections to close can take long. When the timeout runs out, the other steps are still done,
+//! so the files are always finished. A shutdown runs once, later calls wait for it and get the same report.
+
+use std::time::Duration;
+
+use futures_util::future::join_all;
+use tokio::{sync::watch, time::Instant};
+use tracing::{info, warn};
+
+use super::{events::HaxEvent, BulletForceHax, HaxState};
+use crate::proxy::{listeners::ProxyConfig, websocket_proxy::WebSocketProxy};
+
+/// The profile the settings are saved as when shutting down, see [profiles](super::profiles).
+pub const SESSION_PROFILE: &str = "last session";
+
+/// The reason given in the close frames.
+const CLOSE_REASON: &str = "proxy is shutting down";
+
+/// What a shutdown did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// How many websocket connections were closed.
+    pub closed_connections: usize,
+    /// How many capture sinks were finished.
+    pub closed_sinks: usize,
+    /// What was saved, eg. `room notes`.
+    pub saved: Vec<&'static str>,
+    /// What couldn't be saved, and why.
+    pub failed: Vec<(&'static str, String)>,
+    /// Whether the timeout ran out before every connection was closed.
+    pub timed_out: bool,
+}
+
+/// Whether a shutdown started, and its report once it's done.
+#[derive(Default)]
+pub(super) struct ShutdownProgress(Option<watch::Receiver<Option<ShutdownReport>>>);
+
+impl BulletForceHax {
+    /// Shuts the proxy down, see [shutdown](self). Resolves once everything is done, or once the timeout ran out while
+    /// waiting for the connections to close.
+    pub async fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().await;
+        if let Some(progress) = state.shutdown.0.clone() {
+            drop(state);
+            return wait_for_report(progress, deadline).await;
+        }
+        let (done, progress) = watch::channel(None);
+        state.shutdown.0 = Some(progress);
+        info!("Shutting down");
+
+        let config = ProxyConfig {
+            listen_addresses: vec![],
+            ..state.proxy.config().clone()
+        };
+        if let Err(e) = state.apply_proxy_config(config, true).await {
+            warn!("Could not stop the listeners: {e}");
+        }
+
+        let closes = state
+            .connections()
+            .map(|connection| connection.close(CLOSE_REASON))
+            .collect::<Vec<_>>();
+        let mut report = ShutdownReport {
+            closed_connections: closes.len(),
+            ..Default::default()
+        };
+        drop(state);
+        let closed = tokio::time::timeout_at(deadline, async {
+            join_all(closes).await;
+            // connections are taken out of the state once both sides are gone
+            while self.state.lock().await.connections().next().is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        if closed.is_err() {
+            warn!("Timed out waiting for the connections to close");
+            report.timed_out = true;
+        }
+
+        let mut state = self.state.lock().await;
+        report.closed_sinks = state.capture_sinks.close_all();
+        state.persist(&mut report);
+        info!(
+            connections = report.closed_connections,
+            sinks = report.closed_sinks,
+            saved = report.saved.join(", "),
+            "Shut down"
+        );
+        state.events.emit(HaxEvent::ShutDown(report.clone()));
+        _ = done.send(Some(report.clone()));
+        report
+    }
+}
+
+/// Waits for the report of a shutdown that another call started. If it doesn't finish in time, the report is empty
+/// and says it timed out.
+async fn wait_for_report(
+    mut progress: watch::Receiver<Option<ShutdownReport>>,
+    deadline: Instant,
+) -> ShutdownReport {
+    let finished = tokio::time::timeout_at(deadline, async {
+        loop {
+            if let Some(report) = progress.borrow().clone() {
+                return Some(report);
+            }
+            if progress.changed().await.is_err() {
+                return None;
+            }
+        }
+    })
+    .await;
+    match finished {
+        Ok(Some(report)) => report,
+        _ => ShutdownReport {
+            timed_out: true,
+            ..Default::default()
+        },
+    }
+}
+
+impl HaxState {
+    /// The websocket connections to each server.
+    fn connections(&self) -> impl Iterator<Item = &WebSocketProxy> {
+        let nameserver = self.nameserver_state.as_ref().map(|(c, _)| c);
+        let lobby = self.lobby_state.as_ref().map(|(c, _)| c);
+        let gameplay = self.gameplay_state.as_ref().map(|(c, _)| c);
+        nameserver.into_iter().chain(lobby).chain(gameplay)
+    }
+
+    /// Saves what is kept across sessions, skipping the files that weren't loaded from anywhere.
+    fn persist(&mut self, report: &mut ShutdownReport) {
+        let mut results = vec![];
+        if self.profiles.dir().is_some() {
+            let result = self.save_profile(SESSION_PROFILE);
+            results.push(("settings", result.map_err(|e| e.to_string())));
+        }
+        if self.room_notes.path().is_some() {
+            let result = self.room_notes.save();
+            results.push(("room notes", result.map_err(|e| format!("{e:#}"))));
+        }
+        if self.watchlist.path().is_some() {
+            let result = self.watchlist.save();
+            results.push(("watchlist", result.map_err(|e| format!("{e:#}"))));
+        }
+        if self.match_history.path().is_some() {
+            let result = self.match_history.save();
+            results.push(("match history", result.map_err(|e| format!("{e:#}"))));
+        }
+
+        for (name, result) in results {
+            match result {
+                Ok(()) => report.saved.push(name),
+                Err(e) => {
+                    warn!("Could not save the {name}: {e}");
+                    report.failed.push((name, e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use photon_lib::{
+        indexmap::indexmap,
+        photon_message::{OperationRequest, PhotonMessage},
+    };
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+    use super::{ShutdownReport, SESSION_PROFILE};
+    use crate::{
+        hax::{
+            events::HaxEvent,
+            room_notes::{RoomKey, RoomNote},
+            BulletForceHax,
+        },
+        inspect::capture::Capture,
+        proxy::WebSocketServer,
+        testsupport::ProxiedConnection,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bfhax-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn request(i: i32) -> PhotonMessage {
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: 42,
+            parameters: indexmap! { 1 => photon_lib::photon_data_type::PhotonDataType::Integer(i) },
+        })
+    }
+
+    #[tokio::test]
+    async fn closes_connections_and_finishes_captures() {
+        let dir = temp_dir("shutdown-capture");
+        let path = dir.join("session.bfhc");
+        let hax = BulletForceHax::default();
+        let state = hax.get_state();
+        let mut events = state.lock().await.events.subscribe();
+        state
+            .lock()
+            .await
+            .add_capture_sink("session", None, &path)
+            .unwrap();
+
+        let mut connection =
+            ProxiedConnection::connect(state.clone(), WebSocketServer::GameServer).await;
+        for i in 0..20 {
+            connection.client_send(request(i)).await;
+            connection.server.recv().await;
+        }
+
+        let report = hax.shutdown(TIMEOUT).await;
+        assert_eq!(report.closed_connections, 1);
+        assert_eq!(report.closed_sinks, 1);
+        assert!(!report.timed_out);
+        let frame = connection.client_close_frame().await.unwrap();
+        assert_eq!(frame.code, CloseCode::Away);
+        assert!(state.lock().await.gameplay_state.is_none());
+        assert!(state.lock().await.capture_sinks.is_empty());
+
+        // the file ends with its index, and has every message
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(bytes.ends_with(b"BFHI"));
+        let requests = Capture::load(&path)
+            .unwrap()
+            .messages
+            .iter()
+            .filter(|m| {
+                matches!(
+                    m.parse(),
+                    Some(PhotonMessage::OperationRequest(OperationRequest {
+                        operation_code: 42,
+                        ..
+                    }))
+                )
+            })
+            .count();
+        assert_eq!(requests, 20);
+
+        let mut last = None;
+        while let Ok(event) = events.try_recv() {
+            last = Some(event);
+        }
+        assert!(matches!(last, Some(HaxEvent::ShutDown(r)) if r == report));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn saves_each_file_once() {
+        let dir = temp_dir("shutdown-persist");
+        let notes = dir.join("room_notes.json");
+        let profiles = dir.join("profiles");
+        let hax = BulletForceHax::default();
+        let state = hax.get_state();
+        {
+            let mut state = state.lock().await;
+            state.load_room_notes(&notes);
+            state.load_profiles(&profiles);
+            let note = RoomNote {
+                note: "good host".into(),
+                ..Default::default()
+            };
+            state
+                .set_room_note(RoomKey::RoomName("Chill".into()), note)
+                .unwrap();
+        }
+        // saved when it was changed, the shutdown writes it again
+        std::fs::remove_file(&notes).unwrap();
+
+        let report = hax.shutdown(TIMEOUT).await;
+        assert_eq!(
+            report,
+            ShutdownReport {
+                saved: vec!["settings", "room notes"],
+                ..Default::default()
+            }
+        );
+        assert!(notes.exists());
+        let profile = state.lock().await.profiles().load(SESSION_PROFILE);
+        assert!(profile.is_ok());
+
+        // a second shutdown doesn't write anything
+        std::fs::remove_file(&notes).unwrap();
+        std::fs::remove_dir_all(&profiles).unwrap();
+        assert_eq!(hax.shutdown(TIMEOUT).await, report);
+        assert!(!notes.exists());
+        assert!(!profiles.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,616 @@
+//! Fake remote players, to develop features without other people online.
+//!
+//! The simulation fabricates the events a game server would send when other players join, spawn, move around and
+//! shoot. These go through [HaxState::websocket_hook] like real server messages before being sent to the client, so
+//! both the hax state and the game see the fake players.
+
+use std::{
+    f32::consts::TAU,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures_util::lock::Mutex;
+use photon_lib::{
+    highlevel::{
+        constants::{actor_properties, event_code, parameter_code, pun_event_code},
+        structs::{InstantiationEventData, RpcCall},
+        PhotonMapConversion,
+    },
+    indexmap::IndexMap,
+    ordered_float::OrderedFloat,
+    photon_data_type::{CustomData, PhotonDataType},
+    photon_message::{EventData, PhotonMessage},
+    primitives::{Quaternion, Vector3},
+    ParameterMap, PhotonHashmap,
+};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, warn};
+
+use super::{bandwidth::feature, BulletForceHax, HaxState};
+use crate::{
+    error::HaxError,
+    protocol::{player_script::PlayerScript, rpc::METHOD_NAMES},
+    proxy::{Direction, WebSocketServer},
+    testgen::SplitMix64,
+};
+
+/// The actor number of the first fake player. High enough to not collide with real players in the same room.
+const FIRST_ACTOR_NR: i32 = 900;
+
+/// PUN reserves this many view ids per actor.
+const MAX_VIEW_IDS: i32 = 1000;
+
+/// How many ticks a player stays dead before respawning.
+const DEAD_TICKS: u32 = 10;
+
+/// Full health in [PlayerScript::health].
+const FULL_HEALTH: i16 = 10000;
+
+#[derive(Debug, Clone)]
+pub struct SimulationSettings {
+    /// How many fake players to create.
+    pub players: u8,
+    /// Makes the simulation produce the same players and movement every time.
+    pub seed: u64,
+    /// How often the fake players send their position.
+    pub tick_interval: Duration,
+    /// The chance that a player shoots during a tick, between 0 and 1.
+    pub shoot_chance: f32,
+    /// The chance that a player dies during a tick, between 0 and 1.
+    pub death_chance: f32,
+}
+
+impl Default for SimulationSettings {
+    fn default() -> Self {
+        Self {
+            players: 4,
+            seed: 0,
+            tick_interval: Duration::from_millis(100),
+            shoot_chance: 0.05,
+            death_chance: 0.005,
+        }
+    }
+}
+
+/// A fake player walking in circles.
+struct FakePlayer {
+    actor_nr: i32,
+    team_number: u8,
+    center: (f32, f32, f32),
+    radius: f32,
+    /// Radians per second. Negative to walk clockwise.
+    angular_speed: f32,
+    start_angle: f32,
+    kills: i16,
+    deaths: i16,
+    /// Ticks left until the player respawns, if it is dead.
+    dead_for: Option<u32>,
+}
+
+impl FakePlayer {
+    fn view_id(&self) -> i32 {
+        self.actor_nr * MAX_VIEW_IDS + 1
+    }
+
+    fn nickname(&self) -> String {
+        format!("SimBot{}", self.actor_nr - FIRST_ACTOR_NR + 1)
+    }
+
+    fn angle(&self, elapsed: Duration) -> f32 {
+        self.start_angle + self.angular_speed * elapsed.as_secs_f32()
+    }
+
+    fn position(&self, elapsed: Duration) -> Vector3 {
+        let angle = self.angle(elapsed);
+        let (x, y, z) = self.center;
+        Vector3(
+            OrderedFloat(x + self.radius * angle.cos()),
+            OrderedFloat(y),
+            OrderedFloat(z + self.radius * angle.sin()),
+        )
+    }
+
+    /// The direction the player walks in, in radians around the y axis.
+    fn heading(&self, elapsed: Duration) -> f32 {
+        let tangent = self.angle(elapsed) + self.angular_speed.signum() * TAU / 4.0;
+        tangent.rem_euclid(TAU)
+    }
+
+    fn player_script(&self, elapsed: Duration) -> PlayerScript {
+        let heading = self.heading(elapsed);
+        // yaw is in tenths of a degree
+        let yaw = (heading.to_degrees() * 10.0) as i16;
+        let speed = self.radius * self.angular_speed.abs();
+        let health = match self.dead_for {
+            Some(_) => 0,
+            None => FULL_HEALTH,
+        };
+
+        PlayerScript {
+            pitch: 0,
+            yaw,
+            move_angle: yaw,
+            number_of_kills: self.kills,
+            number_of_deaths: self.deaths,
+            number_of_rounds: 0,
+            ping: 50,
+            last_local_hit_y: 0,
+            gun_game_score: 0,
+            velocity_x: (heading.sin() * speed * 100.0) as i16,
+            velocity_y: 0,
+            velocity_z: (heading.cos() * speed * 100.0) as i16,
+            health,
+            accessory_type: 0,
+            barrel_type: 0,
+            sight_type: 0,
+            weapon_last_damaged_from: 0,
+            // CanShoot and IsGrounded
+            bitflags: 0b0001_0010,
+            last_damager_id: -1,
+            position: self.position(elapsed),
+            rotation: Quaternion(
+                OrderedFloat(0.0),
+                OrderedFloat((heading / 2.0).sin()),
+                OrderedFloat(0.0),
+                OrderedFloat((heading / 2.0).cos()),
+            ),
+        }
+    }
+}
+
+/// Generates the messages for a set of fake players. Does not send anything by itself, see
+/// [BulletForceHax::start_simulation] for that.
+pub struct Simulation {
+    settings: SimulationSettings,
+    rng: SplitMix64,
+    players: Vec<FakePlayer>,
+}
+
+impl Simulation {
+    pub fn new(settings: SimulationSettings) -> Self {
+        let mut rng = SplitMix64(settings.seed);
+        let players = (0..settings.players)
+            .map(|i| FakePlayer {
+                actor_nr: FIRST_ACTOR_NR + i as i32,
+                team_number: i % 2,
+                center: (rng.range(-40.0, 40.0), 0.0, rng.range(-40.0, 40.0)),
+                radius: rng.range(3.0, 15.0),
+                angular_speed: rng.range(0.2, 0.8) * if rng.next_f32() < 0.5 { -1.0 } else { 1.0 },
+                start_angle: rng.range(0.0, TAU),
+                kills: 0,
+                deaths: 0,
+                dead_for: None,
+            })
+            .collect();
+
+        Self {
+            settings,
+            rng,
+            players,
+        }
+    }
+
+    /// The actor numbers of the fake players.
+    pub fn actor_numbers(&self) -> impl Iterator<Item = i32> + '_ {
+        self.players.iter().map(|p| p.actor_nr)
+    }
+
+    /// The messages that make the fake players join the room and spawn.
+    pub fn spawn(&self, server_time: i32) -> Vec<PhotonMessage> {
+        let mut messages = vec![];
+        let mut actor_list: Vec<i32> = vec![];
+
+        for player in &self.players {
+            actor_list.push(player.actor_nr);
+
+            let mut player_properties = PhotonHashmap::new();
+            player_properties.insert(
+                PhotonDataType::Byte(actor_properties::PLAYER_NAME),
+                PhotonDataType::String(String::new()),
+            );
+            let mut parameters = IndexMap::new();
+            parameters.insert(
+                parameter_code::ACTOR_NR,
+                PhotonDataType::Integer(player.actor_nr),
+            );
+            parameters.insert(
+                parameter_code::ACTOR_LIST,
+                PhotonDataType::IntArray(actor_list.clone()),
+            );
+            // like in real join events, the nickname is only set later through a property change
+            parameters.insert(
+                parameter_code::PLAYER_PROPERTIES,
+                PhotonDataType::Hashtable(player_properties),
+            );
+            messages.push(event(event_code::JOIN, parameters));
+
+            let mut data = PhotonHashmap::new();
+            InstantiationEventData {
+                prefab_name: "PlayerBody".into(),
+                position: Some(CustomData::Vector3(player.position(Duration::ZERO))),
+                rotation: None,
+                group: None,
+                views_ids: None,
+                incoming_instantiation_data: None,
+                server_time,
+                instantiation_id: player.view_id(),
+                obj_level_prefix: None,
+                custom_properties: IndexMap::new(),
+            }
+            .into_map(&mut data);
+            messages.push(event(
+                pun_event_code::INSTANTIATION,
+                sender_and_data(player.actor_nr, parameter_code::DATA, data),
+            ));
+
+            let mut properties = PhotonHashmap::new();
+            properties.insert(
+                PhotonDataType::Byte(actor_properties::PLAYER_NAME),
+                PhotonDataType::String(player.nickname()),
+            );
+            properties.insert(
+                PhotonDataType::String("teamNumber".into()),
+                PhotonDataType::Byte(player.team_number),
+            );
+            let mut parameters =
+                sender_and_data(player.actor_nr, parameter_code::PROPERTIES, properties);
+            parameters.insert(
+                parameter_code::TARGET_ACTOR_NR,
+                PhotonDataType::Integer(player.actor_nr),
+            );
+            messages.push(event(event_code::PROPERTIES_CHANGED, parameters));
+        }
+
+        messages
+    }
+
+    /// Advances the simulation, and returns the position updates and RPCs of the fake players.
+    ///
+    /// `elapsed` is the time since [Self::spawn].
+    pub fn tick(&mut self, elapsed: Duration, server_time: i32) -> Vec<PhotonMessage> {
+        let mut messages = vec![];
+
+        for player in &mut self.players {
+            let rpc = match player.dead_for {
+                Some(0) => {
+                    player.dead_for = None;
+                    Some("PunRespawn")
+                }
+                Some(ref mut ticks) => {
+                    *ticks -= 1;
+                    None
+                }
+                None if self.rng.next_f32() < self.settings.death_chance => {
+                    player.deaths += 1;
+                    player.dead_for = Some(DEAD_TICKS);
+                    Some("RpcDie")
+                }
+                None if self.rng.next_f32() < self.settings.shoot_chance => {
+                    player.kills += (self.rng.next_f32() < 0.1) as i16;
+                    Some("RpcShoot")
+                }
+                None => None,
+            };
+
+            if let Some(method_name) = rpc {
+                let mut data = PhotonHashmap::new();
+                RpcCall {
+                    net_view_id: player.view_id(),
+                    other_side_prefix: None,
+                    server_timestamp: Some(server_time),
+                    method_name: None,
+                    in_method_parameters: None,
+                    rpc_index: METHOD_NAMES
+                        .iter()
+                        .position(|m| *m == method_name)
+                        .map(|i| i as u8),
+                    custom_properties: IndexMap::new(),
+                }
+                .into_map(&mut data);
+                messages.push(event(
+                    pun_event_code::RPC,
+                    sender_and_data(player.actor_nr, parameter_code::CUSTOM_EVENT_CONTENT, data),
+                ));
+            }
+
+            // a single object per serialize event, like PUN does for objects on their own
+            let mut object = vec![
+                PhotonDataType::Integer(player.view_id()),
+                PhotonDataType::Boolean(false),
+                PhotonDataType::Null,
+            ];
+            object.extend(player.player_script(elapsed).to_object_array());
+            let mut data = PhotonHashmap::new();
+            data.insert(
+                PhotonDataType::Byte(0),
+                PhotonDataType::Integer(server_time),
+            );
+            data.insert(PhotonDataType::Byte(1), PhotonDataType::Short(0));
+            data.insert(
+                PhotonDataType::Byte(10),
+                PhotonDataType::ObjectArray(object),
+            );
+            messages.push(event(
+                pun_event_code::SEND_SERIALIZE,
+                sender_and_data(player.actor_nr, parameter_code::DATA, data),
+            ));
+        }
+
+        messages
+    }
+
+    /// The messages that make the fake players leave the room.
+    pub fn leave(&self) -> Vec<PhotonMessage> {
+        self.players
+            .iter()
+            .map(|player| {
+                let mut parameters = IndexMap::new();
+                parameters.insert(
+                    parameter_code::ACTOR_NR,
+                    PhotonDataType::Integer(player.actor_nr),
+                );
+                event(event_code::LEAVE, parameters)
+            })
+            .collect()
+    }
+}
+
+fn event(code: u8, parameters: ParameterMap) -> PhotonMessage {
+    PhotonMessage::EventData(EventData { code, parameters })
+}
+
+fn sender_and_data(sender: i32, data_parameter: u8, data: PhotonHashmap) -> ParameterMap {
+    let mut parameters = IndexMap::new();
+    parameters.insert(parameter_code::ACTOR_NR, PhotonDataType::Integer(sender));
+    parameters.insert(data_parameter, PhotonDataType::Hashtable(data));
+    parameters
+}
+
+/// A running simulation. Stopping it makes the fake players leave.
+pub struct SimulationHandle {
+    stop: Arc<AtomicBool>,
+}
+
+impl SimulationHandle {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl BulletForceHax {
+    /// Starts adding fake players to every game the client joins, until the returned handle is stopped.
+    pub fn start_simulation(&self, settings: SimulationSettings) -> SimulationHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        tokio::spawn(run_simulation(self.get_state(), settings, stop.clone()));
+        SimulationHandle { stop }
+    }
+}
+
+async fn run_simulation(
+    state: Arc<Mutex<HaxState>>,
+    settings: SimulationSettings,
+    stop: Arc<AtomicBool>,
+) {
+    let mut interval = tokio::time::interval(settings.tick_interval);
+    // the simulation of the current game, and the ticks since it started
+    let mut running: Option<(Simulation, u32)> = None;
+
+    loop {
+        interval.tick().await;
+
+        // only start once the join response told us who we are
+        let in_game = match &state.lock().await.gameplay_state {
+            Some((_, gameplay)) => gameplay.player_id.is_some(),
+            None => false,
+        };
+        let stopping = stop.load(Ordering::Relaxed);
+
+        let messages = match (&mut running, in_game) {
+            (Some((simulation, _)), true) if stopping => {
+                info!("Stopping simulation");
+                simulation.leave()
+            }
+            (_, _) if stopping => break,
+            (Some((simulation, ticks)), true) => {
+                *ticks += 1;
+                let elapsed = settings.tick_interval * *ticks;
+                simulation.tick(elapsed, elapsed.as_millis() as i32)
+            }
+            (None, true) => {
+                info!(players = settings.players, "Starting simulation");
+                let simulation = Simulation::new(settings.clone());
+                let messages = simulation.spawn(0);
+                running = Some((simulation, 0));
+                messages
+            }
+            (Some(_), false) => {
+                debug!("Left game, resetting simulation");
+                running = None;
+                continue;
+            }
+            (None, false) => continue,
+        };
+
+        for message in messages {
+            if let Err(e) = inject_server_message(&state, message).await {
+                warn!("Failed to inject simulated message: {e}");
+            }
+        }
+
+        if stopping {
+            break;
+        }
+    }
+
+    debug!("Simulation stopped");
+}
+
+/// Sends a message to the client as if the game server sent it, running the hook on it first.
+async fn inject_server_message(
+    state: &Arc<Mutex<HaxState>>,
+    message: PhotonMessage,
+) -> Result<(), HaxError> {
+    let mut data = vec![];
+    message.to_websocket_bytes(&mut data)?;
+
+    let forward = HaxState::websocket_hook(
+        state.clone(),
+        &mut data,
+        WebSocketServer::GameServer,
+        Direction::ServerToClient,
+    )?;
+    if !forward {
+        return Ok(());
+    }
+
+    match &state.lock().await.gameplay_state {
+        Some((proxy, _)) => {
+            proxy
+                .send_client(Message::Binary(data), feature::SIMULATION)
+                .await
+        }
+        None => Err(HaxError::InjectionUnavailable("not in a game".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use photon_lib::{
+        highlevel::{
+            constants::{event_code, pun_event_code},
+            structs::{InstantiationEvent, InstantiationEventData, RpcEvent, SendSerializeEvent},
+            PhotonMapConversion, PhotonParameterMapConversion,
+        },
+        photon_message::PhotonMessage,
+    };
+
+    use super::{Simulation, SimulationSettings};
+    use crate::protocol::{
+        player_script::PlayerScript, rpc::get_rpc_method_name, stream_layout::StreamLayout,
+    };
+
+    fn settings() -> SimulationSettings {
+        SimulationSettings {
+            players: 3,
+            seed: 1234,
+            shoot_chance: 0.5,
+            death_chance: 0.1,
+            ..Default::default()
+        }
+    }
+
+    /// Serializes and parses the messages again, like the client would.
+    fn round_trip(messages: Vec<PhotonMessage>) -> Vec<PhotonMessage> {
+        messages
+            .into_iter()
+            .map(|message| {
+                let mut buf = vec![];
+                message.to_websocket_bytes(&mut buf).unwrap();
+                PhotonMessage::from_websocket_bytes(&mut buf.as_slice()).unwrap()
+            })
+            .collect()
+    }
+
+    fn run(ticks: u32) -> Vec<PhotonMessage> {
+        let mut simulation = Simulation::new(settings());
+        let mut messages = simulation.spawn(0);
+        for tick in 1..=ticks {
+            let elapsed = Duration::from_millis(100) * tick;
+            messages.extend(simulation.tick(elapsed, elapsed.as_millis() as i32));
+        }
+        messages
+    }
+
+    #[test]
+    fn deterministic() {
+        assert_eq!(run(50), run(50));
+
+        let mut other = Simulation::new(SimulationSettings {
+            seed: 4321,
+            ..settings()
+        });
+        other.spawn(0);
+        let mut simulation = Simulation::new(settings());
+        assert_ne!(
+            simulation.tick(Duration::from_millis(100), 100),
+            other.tick(Duration::from_millis(100), 100)
+        );
+    }
+
+    #[test]
+    fn spawns_players() {
+        let simulation = Simulation::new(settings());
+        let messages = round_trip(simulation.spawn(0));
+        assert_eq!(messages.len(), 3 * 3);
+
+        let mut instantiated = vec![];
+        for message in messages {
+            match message {
+                PhotonMessage::EventData(mut event)
+                    if event.code == pun_event_code::INSTANTIATION =>
+                {
+                    let mut event = InstantiationEvent::from_map(&mut event.parameters).unwrap();
+                    let data = InstantiationEventData::from_map(&mut event.data).unwrap();
+                    assert_eq!(data.prefab_name, "PlayerBody");
+                    assert_eq!(
+                        data.get_view_id().get_owner_id(),
+                        event.sender_actor.unwrap()
+                    );
+                    instantiated.push(event.sender_actor.unwrap());
+                }
+                PhotonMessage::EventData(event) => assert!(matches!(
+                    event.code,
+                    event_code::JOIN | event_code::PROPERTIES_CHANGED
+                )),
+                m => panic!("unexpected message {m:?}"),
+            }
+        }
+        assert_eq!(instantiated, simulation.actor_numbers().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn players_move_and_shoot() {
+        let mut serialized = 0;
+        let mut rpcs = vec![];
+        let mut positions = vec![];
+
+        for message in round_trip(run(50)) {
+            let mut event = match message {
+                PhotonMessage::EventData(e) => e,
+                m => panic!("unexpected message {m:?}"),
+            };
+            match event.code {
+                pun_event_code::SEND_SERIALIZE => {
+                    let event = SendSerializeEvent::from_map(&mut event.parameters).unwrap();
+                    for object in event.get_serialized_data().unwrap() {
+                        let script =
+                            PlayerScript::decode(&object.data_stream, &StreamLayout::builtin())
+                                .unwrap();
+                        if object.get_view_id().get_owner_id() == 900 {
+                            positions.push(script.position);
+                        }
+                        serialized += 1;
+                    }
+                }
+                pun_event_code::RPC => {
+                    let mut event = RpcEvent::from_map(&mut event.parameters).unwrap();
+                    let call = event.extract_rpc_call().unwrap();
+                    rpcs.push(get_rpc_method_name(&call).unwrap().to_string());
+                }
+                _ => (),
+            }
+        }
+
+        assert_eq!(serialized, 3 * 50);
+        positions.dedup();
+        assert_eq!(positions.len(), 50);
+        assert!(rpcs.iter().any(|m| m == "RpcShoot"));
+        assert!(rpcs.iter().any(|m| m == "RpcDie"));
+    }
+}
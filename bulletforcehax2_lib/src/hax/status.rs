@@ -0,0 +1,482 @@
+//! A human-readable summary of the current state, for quick diagnostics.
+
+use std::fmt::Write;
+
+use super::{disconnects::Disconnect, interest_groups::InterestGroups, GameplayState, HaxState};
+use crate::proxy::{websocket_proxy::WebSocketProxy, Direction, WebSocketServer};
+
+const REDACTED: &str = "<redacted>";
+
+/// How many of the most recent dropped messages to list.
+const MAX_LISTED_DROPS: usize = 5;
+
+impl HaxState {
+    /// Describes the current state as a multi-line text report.
+    ///
+    /// If `redact` is set, user ids are left out so the report can be shared with others. This works at any point
+    /// of the connection, missing information is shown as unknown.
+    pub fn status_report(&self, redact: bool) -> String {
+        let mut out = String::with_capacity(1024);
+        // writing to a String never fails
+        _ = self.write_status_report(&mut out, redact);
+        out
+    }
+
+    fn write_status_report(&self, out: &mut String, redact: bool) -> std::fmt::Result {
+        writeln!(out, "== BulletForceHaxV2 status ==")?;
+        let settings = self.settings();
+        if settings.paused.is_some() {
+            writeln!(
+                out,
+                "PAUSED: all features are off until resumed, messages are forwarded unchanged"
+            )?;
+        } else if settings.observe_only {
+            writeln!(
+                out,
+                "OBSERVE ONLY: messages are forwarded unchanged and nothing is injected"
+            )?;
+        }
+
+        write!(out, "name server: ")?;
+        match &self.nameserver_state {
+            Some((proxy, state)) => {
+                write_connected(out, proxy)?;
+                if let Some(region) = &state.region {
+                    write!(out, ", region {region}")?;
+                }
+                writeln!(out)?;
+            }
+            None => write_disconnected(out, self.last_disconnect(WebSocketServer::NameServer))?,
+        }
+        write!(out, "lobby: ")?;
+        match &self.lobby_state {
+            Some((proxy, _)) => {
+                write_connected(out, proxy)?;
+                writeln!(out)?;
+            }
+            None => write_disconnected(out, self.last_disconnect(WebSocketServer::LobbyServer))?,
+        }
+        write!(out, "game server: ")?;
+        match &self.gameplay_state {
+            Some((proxy, _)) => {
+                write_connected(out, proxy)?;
+                writeln!(out)?;
+            }
+            None => write_disconnected(out, self.last_disconnect(WebSocketServer::GameServer))?,
+        }
+
+        match &self.global_state.version {
+            Some(v) => writeln!(
+                out,
+                "game version: {} (PUN {})",
+                v.game_version, v.photon_version
+            )?,
+            None => writeln!(out, "game version: unknown")?,
+        }
+        if let Some(v) = &self.global_state.server_version {
+            writeln!(out, "sent to server as: {}", v.game_version)?;
+        }
+        match (&self.global_state.user_id, redact) {
+            (Some(_), true) => writeln!(out, "user id: {REDACTED}")?,
+            (Some(user_id), false) => writeln!(out, "user id: {user_id}")?,
+            (None, _) => writeln!(out, "user id: unknown")?,
+        }
+        // the generated identity says nothing about us, so it's shown even when redacting
+        if let Some(identity) = self.identity.identity() {
+            write!(
+                out,
+                "presented as: {} with user id {}",
+                identity.nickname, identity.user_id
+            )?;
+            match (&identity.real_nickname, redact) {
+                (Some(_), true) => write!(out, ", instead of {REDACTED}")?,
+                (Some(nickname), false) => write!(out, ", instead of {nickname}")?,
+                (None, _) => (),
+            }
+            writeln!(out)?;
+        }
+
+        match &self.gameplay_state {
+            Some((_, state)) => write_gameplay(out, state, redact)?,
+            None => writeln!(out, "not in a game")?,
+        }
+        if let Some(health) = self.client_health() {
+            writeln!(out, "client cadence: {health}")?;
+        }
+
+        write!(out, "active features:")?;
+        let features = [
+            (settings.show_mobile_games, "show mobile games"),
+            (settings.show_other_versions, "show other versions"),
+            (settings.strip_passwords, "strip passwords"),
+            (settings.spoofed_name.0, "spoof name"),
+            (settings.platform_spoof.is_some(), "spoof platform"),
+            (settings.identity_randomizer, "randomize identity"),
+            (settings.forced_region.0, "force region"),
+            (settings.lobby_sort.is_some(), "sort lobby"),
+            (settings.auth_overrides.is_some(), "auth overrides"),
+            (settings.mute_all_cosmetic, "mute all cosmetic RPCs"),
+            (settings.ghost_join, "ghost join"),
+            (self.stealth_host, "stealth host"),
+            (self.receive_all_groups, "receive all interest groups"),
+            (!settings.muted_actors.is_empty(), "mute actors"),
+            (settings.debug.validate_rewrites, "validate rewrites"),
+            (settings.dry_run.enabled, "dry run"),
+        ];
+        let mut any_feature = false;
+        for (_, name) in features.iter().filter(|(enabled, _)| *enabled) {
+            write!(out, " [{name}]")?;
+            any_feature = true;
+        }
+        if !any_feature {
+            write!(out, " none")?;
+        }
+        writeln!(out)?;
+
+        let stats = &self.stats;
+        writeln!(
+            out,
+            "messages: {} c->s, {} s->c, {} rejected rewrites, {} muted RPCs",
+            stats.messages_client_to_server,
+            stats.messages_server_to_client,
+            stats.rejected_rewrites,
+            stats.muted_rpcs.values().sum::<u64>(),
+        )?;
+        if stats.handler_panics > 0 {
+            writeln!(out, "handler panics: {}", stats.handler_panics)?;
+        }
+        if stats.unknown_values > 0 {
+            writeln!(out, "values of unknown types: {}", stats.unknown_values)?;
+        }
+
+        let relay = self.relay.report();
+        for direction in [Direction::ClientToServer, Direction::ServerToClient] {
+            let report = relay.get(direction);
+            if report.transit.count() == 0 {
+                continue;
+            }
+            writeln!(
+                out,
+                "proxy transit {direction}: {} messages, p99 <= {:?}, max {:?}, {} blocked writes, at most {} waiting to be written ({} bytes)",
+                report.transit.count(),
+                report.transit.percentile(0.99).unwrap_or_default(),
+                report.transit.max(),
+                report.backpressure,
+                report.write.peak_messages,
+                report.write.peak_bytes,
+            )?;
+        }
+
+        let encrypted = self.encryption.total();
+        if encrypted.total() > 0 {
+            write!(
+                out,
+                "encrypted messages: {} c->s, {} s->c, active on:",
+                encrypted.client_to_server, encrypted.server_to_client
+            )?;
+            let mut any_active = false;
+            for server in self.encryption.active_connections() {
+                write!(out, " {server}")?;
+                any_active = true;
+            }
+            if !any_active {
+                write!(out, " none")?;
+            }
+            writeln!(out)?;
+        }
+
+        for server in [
+            WebSocketServer::NameServer,
+            WebSocketServer::LobbyServer,
+            WebSocketServer::GameServer,
+        ] {
+            let breaker = self.parse_breaker.connection(server);
+            match (breaker.is_passthrough(), breaker.is_stale()) {
+                (true, _) => writeln!(
+                    out,
+                    "{server} passthrough: messages don't parse, {} forwarded without handling",
+                    breaker.skipped()
+                )?,
+                (false, true) => writeln!(
+                    out,
+                    "{server} state is stale: messages were forwarded without handling earlier"
+                )?,
+                (false, false) => (),
+            }
+        }
+
+        match self.drop_log.total() {
+            0 => writeln!(out, "dropped messages: none")?,
+            total => {
+                write!(out, "dropped messages: {total} (")?;
+                for (i, (feature, count)) in self.drop_log.counts().iter().enumerate() {
+                    if i > 0 {
+                        write!(out, ", ")?;
+                    }
+                    write!(out, "{feature}: {count}")?;
+                }
+                writeln!(out, ")")?;
+
+                let skip = self
+                    .drop_log
+                    .entries()
+                    .len()
+                    .saturating_sub(MAX_LISTED_DROPS);
+                for dropped in self.drop_log.entries().skip(skip) {
+                    write!(out, "  {} {} ", dropped.server, dropped.direction)?;
+                    if let Some(code) = dropped.code {
+                        write!(out, "{code} ")?;
+                    }
+                    writeln!(out, "{}", dropped.reason)?;
+                }
+            }
+        }
+
+        if self.dry_run_log.total() > 0 {
+            write!(out, "dry run, would have changed or dropped: ")?;
+            for (i, (feature, count)) in self.dry_run_log.counts().iter().enumerate() {
+                if i > 0 {
+                    write!(out, ", ")?;
+                }
+                write!(out, "{feature}: {count}")?;
+            }
+            writeln!(out)?;
+        }
+
+        if !self.capture_sinks.is_empty() {
+            let sinks = self
+                .capture_sinks
+                .iter()
+                .map(|sink| format!("{} ({})", sink.name, sink.path.display()))
+                .collect::<Vec<_>>();
+            writeln!(out, "capturing to: {}", sinks.join(", "))?;
+        }
+
+        let sizes = self
+            .collection_sizes()
+            .into_iter()
+            .filter(|(_, size)| *size > 0)
+            .map(|(name, size)| format!("{name} {size}"))
+            .collect::<Vec<_>>();
+        if !sizes.is_empty() {
+            writeln!(out, "collection sizes: {}", sizes.join(", "))?;
+        }
+
+        match stats.recent_errors.is_empty() {
+            true => writeln!(out, "recent errors: none")?,
+            false => {
+                writeln!(out, "recent errors:")?;
+                for error in &stats.recent_errors {
+                    writeln!(out, "  {error}")?;
+                }
+            }
+        }
+        if let Some(message) = &stats.last_failed_message {
+            writeln!(out, "last failed message: {message}")?;
+        }
+
+        Ok(())
+    }
+}
+
+fn write_disconnected(out: &mut String, last: Option<&Disconnect>) -> std::fmt::Result {
+    match last {
+        Some(last) => writeln!(out, "disconnected ({})", last.reason),
+        None => writeln!(out, "disconnected"),
+    }
+}
+
+fn write_connected(out: &mut String, proxy: &WebSocketProxy) -> std::fmt::Result {
+    write!(out, "connected (port {})", proxy.get_port())
+}
+
+fn write_gameplay(out: &mut String, state: &GameplayState, redact: bool) -> std::fmt::Result {
+    write!(out, "room: ")?;
+    match &state.room_name {
+        Some(name) => write!(out, "{name}")?,
+        None => write!(out, "unknown")?,
+    }
+    write!(out, " ({} players", state.players.len())?;
+    if state.hosting {
+        write!(out, ", hosted by us")?;
+    }
+    writeln!(out, ")")?;
+
+    match state.player_id {
+        Some(id) => writeln!(out, "player id: {id}")?,
+        None => writeln!(out, "player id: unknown")?,
+    }
+    if let Some(master) = state.master_client {
+        write!(
+            out,
+            "master client: {} ({master})",
+            state.display_name(master)
+        )?;
+        match state.room_creator {
+            Some(creator) if creator == master => write!(out, ", created the room")?,
+            Some(creator) => write!(out, ", room created by {}", state.display_name(creator))?,
+            None => (),
+        }
+        writeln!(out)?;
+    }
+    if !state.own_platform.is_empty() {
+        writeln!(out, "platform: {}", state.own_platform)?;
+    }
+    if state.interest_groups != InterestGroups::default() {
+        write!(out, "interest groups: {}", state.interest_groups)?;
+        if state.requested_groups != state.interest_groups {
+            write!(out, " (client asked for {})", state.requested_groups)?;
+        }
+        writeln!(out)?;
+    }
+
+    for (actor_id, player) in &state.players {
+        write!(
+            out,
+            "  {actor_id}: {}",
+            player.nickname.as_deref().unwrap_or("<unknown>")
+        )?;
+        if let Some(team) = player.team_number {
+            write!(out, ", team {team}")?;
+        }
+        if let Some(health) = player.health {
+            write!(out, ", health {health:.2}")?;
+        }
+        if !player.loadout.is_empty() {
+            write!(out, ", loadout {}", player.loadout)?;
+        }
+        if player.platform.is_mobile() == Some(true) {
+            write!(out, ", mobile")?;
+        }
+        match (&player.user_id, redact) {
+            (Some(_), true) => write!(out, ", user id {REDACTED}")?,
+            (Some(user_id), false) => write!(out, ", user id {user_id}")?,
+            (None, _) => (),
+        }
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use crate::{
+        hax::{
+            drop_log::{DropReason, DroppedMessage},
+            GameplayState, HaxState, PlayerActor, VersionInfo,
+        },
+        proxy::{Direction, WebSocketServer},
+    };
+
+    use super::write_gameplay;
+
+    #[test]
+    fn empty_state() {
+        let hax = HaxState::default();
+        hax.update_settings(|s| s.debug.validate_rewrites = false);
+
+        let expected = "\
+== BulletForceHaxV2 status ==
+name server: disconnected
+lobby: disconnected
+game server: disconnected
+game version: unknown
+user id: unknown
+not in a game
+active features: none
+messages: 0 c->s, 0 s->c, 0 rejected rewrites, 0 muted RPCs
+dropped messages: none
+recent errors: none
+";
+        assert_eq!(hax.status_report(false), expected);
+
+        hax.update_settings(|s| s.observe_only = true);
+        let report = hax.status_report(false);
+        assert_eq!(
+            report.lines().nth(1),
+            Some("OBSERVE ONLY: messages are forwarded unchanged and nothing is injected")
+        );
+    }
+
+    #[test]
+    fn redacts_user_ids() {
+        let mut hax = HaxState::default();
+        hax.global_state.user_id = Some("secret-user-id".into());
+        hax.global_state.version = Some(VersionInfo {
+            game_version: "1.89.0".into(),
+            photon_version: "1.99".into(),
+        });
+        hax.update_settings(|s| {
+            s.debug.validate_rewrites = false;
+            s.strip_passwords = true;
+            s.forced_region = (true, "eu".into());
+        });
+        hax.stats.messages_client_to_server = 12;
+        hax.stats.messages_server_to_client = 34;
+        hax.stats.record_error("handler failed");
+        hax.drop_log.record(DroppedMessage {
+            timestamp: SystemTime::now(),
+            server: WebSocketServer::GameServer,
+            direction: Direction::ServerToClient,
+            code: Some(200),
+            reason: DropReason::MutedRpc {
+                sender: 3,
+                method_name: "PlayTaunt".into(),
+            },
+        });
+
+        let expected = "\
+== BulletForceHaxV2 status ==
+name server: disconnected
+lobby: disconnected
+game server: disconnected
+game version: 1.89.0 (PUN 1.99)
+user id: <redacted>
+not in a game
+active features: [strip passwords] [force region]
+messages: 12 c->s, 34 s->c, 0 rejected rewrites, 0 muted RPCs
+dropped messages: 1 (RPC muting: 1)
+  game s->c 200 RPC muting: PlayTaunt from actor 3
+collection sizes: recent errors 1, dropped messages 1
+recent errors:
+  handler failed
+";
+        assert_eq!(hax.status_report(true), expected);
+        assert!(hax.status_report(false).contains("user id: secret-user-id"));
+    }
+
+    #[test]
+    fn gameplay_section() {
+        let mut state = GameplayState {
+            room_name: Some("my room".into()),
+            player_id: Some(2),
+            ..Default::default()
+        };
+        state.players.insert(
+            1,
+            PlayerActor {
+                nickname: Some("host".into()),
+                user_id: Some("host-user-id".into()),
+                team_number: Some(0),
+                health: Some(1.0),
+                ..Default::default()
+            },
+        );
+        state.players.insert(2, PlayerActor::default());
+
+        let mut out = String::new();
+        write_gameplay(&mut out, &state, true).unwrap();
+        assert_eq!(
+            out,
+            "\
+room: my room (2 players)
+player id: 2
+  1: host, team 0, health 1.00, user id <redacted>
+  2: <unknown>
+"
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! Hosts rooms that stay out of the lobby's room list, while friends can still join them by name.
+//!
+//! Photon only lists rooms that are visible, and only lets players join rooms that are open. The client sends its
+//! CREATE_GAME request to the lobby and then again to the game server, both are rewritten to create the room
+//! invisible. Room property changes that would make it visible again are rewritten too. The open flag is never
+//! touched, so joining by name keeps working.
+
+use photon_lib::{
+    highlevel::{
+        constants::{game_property_key, operation_code, parameter_code},
+        structs::{RoomInfo, SetPropertiesOperationRequest},
+        PhotonParameterMapConversion,
+    },
+    indexmap::{indexmap, IndexMap},
+    photon_data_type::PhotonDataType,
+    photon_message::OperationRequest,
+    ParameterMap, PhotonHashmap,
+};
+
+/// Makes the room of a CREATE_GAME request invisible. Returns whether anything changed.
+pub fn hide_created_room(parameters: &mut ParameterMap) -> bool {
+    match parameters.get_mut(&parameter_code::GAME_PROPERTIES) {
+        Some(PhotonDataType::Hashtable(properties)) => RoomInfo::edit_map(properties, |room| {
+            if room.is_visible() == Some(&false) {
+                return false;
+            }
+            room.set_is_visible(false);
+            true
+        }),
+        _ => false,
+    }
+}
+
+/// Keeps room properties from making the room visible. Returns whether anything changed.
+///
+/// Unlike [hide_created_room] the flag is only replaced if the client sets it, as other property changes don't
+/// affect the visibility.
+pub fn hide_room_properties(properties: &mut PhotonHashmap) -> bool {
+    RoomInfo::edit_map(properties, |room| match room.is_visible() {
+        Some(true) => {
+            room.set_is_visible(false);
+            true
+        }
+        _ => false,
+    })
+}
+
+/// The SET_PROPERTIES request that shows or hides the room we're in.
+pub fn visibility_request(visible: bool) -> OperationRequest {
+    let mut parameters = IndexMap::new();
+    SetPropertiesOperationRequest {
+        properties: indexmap! {
+            PhotonDataType::Byte(game_property_key::IS_VISIBLE) => PhotonDataType::Boolean(visible),
+        },
+        actor_nr: None,
+        broadcast: true,
+        expected_values: None,
+        event_forward: None,
+    }
+    .into_map(&mut parameters);
+    OperationRequest {
+        operation_code: operation_code::SET_PROPERTIES,
+        parameters,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use photon_lib::{
+        highlevel::{
+            constants::{game_property_key, operation_code, parameter_code},
+            structs::RoomInfoView,
+        },
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{OperationRequest, PhotonMessage},
+        ParameterMap, PhotonHashmap,
+    };
+
+    use super::visibility_request;
+    use crate::{
+        hax::{bandwidth::feature, timeline::Replay},
+        inspect::CapturedMessage,
+        proxy::{Direction, WebSocketServer},
+        testsupport::string,
+    };
+
+    /// Room properties as the client creates them, with a custom property whose key looks like a well-known one.
+    fn room_properties() -> PhotonHashmap {
+        indexmap! {
+            PhotonDataType::Byte(game_property_key::MAX_PLAYERS) => PhotonDataType::Byte(12),
+            PhotonDataType::Byte(game_property_key::IS_OPEN) => PhotonDataType::Boolean(true),
+            PhotonDataType::Byte(game_property_key::IS_VISIBLE) => PhotonDataType::Boolean(true),
+            string("254") => PhotonDataType::Boolean(true),
+            string("roomName") => string("friends only"),
+        }
+    }
+
+    fn request(
+        server: WebSocketServer,
+        operation_code: u8,
+        parameters: ParameterMap,
+    ) -> CapturedMessage {
+        let mut raw = vec![];
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code,
+            parameters,
+        })
+        .to_websocket_bytes(&mut raw)
+        .unwrap();
+        CapturedMessage {
+            timestamp: SystemTime::now(),
+            server,
+            direction: Direction::ClientToServer,
+            raw,
+        }
+    }
+
+    fn create_game(server: WebSocketServer) -> CapturedMessage {
+        request(
+            server,
+            operation_code::CREATE_GAME,
+            indexmap! {
+                parameter_code::ROOM_NAME => string("friends only"),
+                parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(room_properties()),
+            },
+        )
+    }
+
+    fn set_room_properties(properties: PhotonHashmap) -> CapturedMessage {
+        request(
+            WebSocketServer::GameServer,
+            operation_code::SET_PROPERTIES,
+            indexmap! {
+                parameter_code::PROPERTIES => PhotonDataType::Hashtable(properties),
+                parameter_code::BROADCAST => PhotonDataType::Boolean(true),
+            },
+        )
+    }
+
+    /// Forwards a request and returns the room properties that would reach the server.
+    fn forwarded_properties(replay: &mut Replay, message: &CapturedMessage) -> PhotonHashmap {
+        let raw = replay.forward(message).unwrap();
+        let mut request = match PhotonMessage::from_websocket_bytes(&mut raw.as_slice()).unwrap() {
+            PhotonMessage::OperationRequest(request) => request,
+            other => panic!("expected a request, got {other:?}"),
+        };
+        let key = match request.operation_code {
+            operation_code::CREATE_GAME => parameter_code::GAME_PROPERTIES,
+            _ => parameter_code::PROPERTIES,
+        };
+        match request.parameters.swap_remove(&key) {
+            Some(PhotonDataType::Hashtable(properties)) => properties,
+            other => panic!("expected room properties, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn well_known_and_custom_properties() {
+        let properties = room_properties();
+        let room = RoomInfoView(&properties);
+        assert_eq!(room.max_players(), Some(&12));
+        assert_eq!(room.is_open(), Some(&true));
+        assert_eq!(room.is_visible(), Some(&true));
+        assert_eq!(room.custom_str("roomName"), Some("friends only"));
+        assert_eq!(
+            room.custom_property("254"),
+            Some(&PhotonDataType::Boolean(true))
+        );
+
+        let mut properties = properties;
+        assert!(super::hide_room_properties(&mut properties));
+        let room = RoomInfoView(&properties);
+        assert_eq!(room.is_visible(), Some(&false));
+        // the custom property with the same number is a different key
+        assert_eq!(
+            room.custom_property("254"),
+            Some(&PhotonDataType::Boolean(true))
+        );
+        assert_eq!(properties.len(), room_properties().len());
+    }
+
+    #[test]
+    fn hides_hosted_room() {
+        let mut replay = Replay::default();
+        replay.state().set_stealth_host(true);
+
+        // the room is created on the lobby first, then on the game server
+        for server in [WebSocketServer::LobbyServer, WebSocketServer::GameServer] {
+            if server == WebSocketServer::GameServer {
+                replay.feed(&request(server, operation_code::AUTHENTICATE, indexmap! {}));
+            }
+            let mut expected = room_properties();
+            expected[&PhotonDataType::Byte(game_property_key::IS_VISIBLE)] =
+                PhotonDataType::Boolean(false);
+            assert_eq!(
+                forwarded_properties(&mut replay, &create_game(server)),
+                expected
+            );
+        }
+
+        // properties that don't change the visibility are left alone
+        let properties = indexmap! { string("mapName") => string("Urban") };
+        let forwarded = set_room_properties(properties.clone());
+        assert_eq!(forwarded_properties(&mut replay, &forwarded), properties);
+        let forwarded = set_room_properties(indexmap! {
+            PhotonDataType::Byte(game_property_key::IS_VISIBLE) => PhotonDataType::Boolean(true),
+            PhotonDataType::Byte(game_property_key::IS_OPEN) => PhotonDataType::Boolean(true),
+        });
+        assert_eq!(
+            forwarded_properties(&mut replay, &forwarded),
+            indexmap! {
+                PhotonDataType::Byte(game_property_key::IS_VISIBLE) => PhotonDataType::Boolean(false),
+                PhotonDataType::Byte(game_property_key::IS_OPEN) => PhotonDataType::Boolean(true),
+            }
+        );
+
+        let mut state = replay.state();
+        assert!(state.stats.recent_errors.is_empty());
+        assert!(state.gameplay_state.as_ref().unwrap().1.hosting);
+        assert!(state
+            .feature_availability()
+            .get(feature::STEALTH_HOST)
+            .is_available());
+        // there's no live connection to inject the request on, but toggling still goes through
+        state.set_stealth_host(false);
+        assert!(!state.stealth_host());
+    }
+
+    #[test]
+    fn visibility_request_round_trips() {
+        let mut request = visibility_request(true);
+        assert_eq!(request.operation_code, operation_code::SET_PROPERTIES);
+        let properties = match request.parameters.swap_remove(&parameter_code::PROPERTIES) {
+            Some(PhotonDataType::Hashtable(properties)) => properties,
+            other => panic!("expected properties, got {other:?}"),
+        };
+        assert_eq!(RoomInfoView(&properties).is_visible(), Some(&true));
+        assert_eq!(RoomInfoView(&properties).is_open(), None);
+        assert!(!request.parameters.contains_key(&parameter_code::ACTOR_NR));
+    }
+}
@@ -0,0 +1,305 @@
+//! Reconstructs the mirrored state at any point of a [Capture] by replaying it.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use futures_util::lock::Mutex;
+use photon_lib::{
+    highlevel::constants::operation_code, indexmap::IndexMap, photon_message::PhotonMessage,
+};
+
+use super::{GameplayState, HaxState, LobbyState, NameServerState, PlayerActor};
+use crate::{
+    error::HaxError,
+    inspect::{capture::Capture, CapturedMessage},
+    proxy::{websocket_proxy::WebSocketProxy, Direction, WebSocketServer},
+};
+
+/// Replays the messages of the capture up to and including time `t` into a fresh state.
+pub fn reconstruct_state_at(capture: &Capture, t: SystemTime) -> HaxState {
+    let mut replay = Replay::default();
+    for message in capture.messages.iter().take_while(|m| m.timestamp <= t) {
+        replay.feed(message);
+    }
+    replay.into_state()
+}
+
+/// The parts of the state that are kept in a [StateTimeline].
+#[derive(Debug, Clone)]
+pub struct StateSnapshot {
+    pub timestamp: SystemTime,
+    /// How many messages of the capture were replayed to get to this state.
+    pub messages_replayed: usize,
+    pub user_id: Option<String>,
+    pub region: Option<String>,
+    pub room_name: Option<String>,
+    pub actor_nr: Option<i32>,
+    /// The player actors in the game, keyed by actor id. Empty when not in a game.
+    pub players: IndexMap<i32, PlayerActor>,
+}
+
+impl StateSnapshot {
+    fn take(state: &HaxState, timestamp: SystemTime, messages_replayed: usize) -> Self {
+        let gameplay = state.gameplay_state.as_ref().map(|(_, s)| s);
+        Self {
+            timestamp,
+            messages_replayed,
+            user_id: state.global_state.user_id.clone(),
+            region: state
+                .nameserver_state
+                .as_ref()
+                .and_then(|(_, s)| s.region.clone()),
+            room_name: gameplay.and_then(|s| s.room_name.clone()),
+            actor_nr: gameplay.and_then(|s| s.actor_nr),
+            players: gameplay.map(|s| s.players.clone()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Snapshots of the state taken at regular intervals while replaying a capture.
+#[derive(Debug, Clone, Default)]
+pub struct StateTimeline {
+    snapshots: Vec<StateSnapshot>,
+}
+
+impl StateTimeline {
+    /// Replays the whole capture, taking a snapshot every `interval` of capture time and one after the last message.
+    pub fn record(capture: &Capture, interval: Duration) -> Self {
+        let start = match capture.start_time() {
+            Some(t) => t,
+            None => return Self::default(),
+        };
+
+        let mut replay = Replay::default();
+        let mut snapshots = vec![];
+        let mut next_snapshot = start;
+        for (i, message) in capture.messages.iter().enumerate() {
+            while message.timestamp > next_snapshot {
+                snapshots.push(StateSnapshot::take(&replay.state(), next_snapshot, i));
+                next_snapshot += interval.max(Duration::from_millis(1));
+            }
+            replay.feed(message);
+        }
+
+        let end = capture
+            .messages
+            .last()
+            .map(|m| m.timestamp)
+            .unwrap_or(start);
+        snapshots.push(StateSnapshot::take(
+            &replay.state(),
+            end,
+            capture.messages.len(),
+        ));
+
+        Self { snapshots }
+    }
+
+    /// All snapshots, oldest first.
+    pub fn snapshots(&self) -> &[StateSnapshot] {
+        &self.snapshots
+    }
+
+    /// The last snapshot taken at or before `t`.
+    pub fn at(&self, t: SystemTime) -> Option<&StateSnapshot> {
+        match self.snapshots.partition_point(|s| s.timestamp <= t) {
+            0 => None,
+            i => Some(&self.snapshots[i - 1]),
+        }
+    }
+
+    /// Finds the first snapshot for which `predicate` holds, assuming it keeps holding for all snapshots after it.
+    ///
+    /// Use [reconstruct_state_at] between this snapshot and the one before it to find the exact message.
+    pub fn bisect(
+        &self,
+        mut predicate: impl FnMut(&StateSnapshot) -> bool,
+    ) -> Option<&StateSnapshot> {
+        let i = self.snapshots.partition_point(|s| !predicate(s));
+        self.snapshots.get(i)
+    }
+}
+
+/// Feeds captured messages through the websocket hook, keeping track of connections the way the proxy would.
+#[derive(Default)]
+pub(super) struct Replay {
+    state: Arc<Mutex<HaxState>>,
+}
+
+impl Replay {
+    pub(super) fn feed(&mut self, message: &CapturedMessage) {
+        self.forward(message);
+    }
+
+    /// Feeds a message, returning it as the proxy would forward it, or [None] if it would be dropped.
+    ///
+    /// Errors are recorded in the stats, and the message is forwarded unchanged like the proxy does.
+    pub(super) fn forward(&mut self, message: &CapturedMessage) -> Option<Vec<u8>> {
+        match self.hook(message) {
+            Ok(forwarded) => forwarded,
+            Err(e) => {
+                self.state().stats.record_error(e);
+                Some(message.raw.clone())
+            }
+        }
+    }
+
+    /// Like [Self::forward], but returns errors instead of recording them.
+    pub(super) fn hook(&mut self, message: &CapturedMessage) -> Result<Option<Vec<u8>>, HaxError> {
+        {
+            let mut state = self.state();
+            // every connection starts with the client authenticating, so that's where the previous one ended
+            if message.direction == Direction::ClientToServer && is_authentication(message) {
+                let proxy = WebSocketProxy::detached(
+                    message.server,
+                    state.bandwidth.clone(),
+                    state.shared_settings(),
+                );
+                state.encryption.connection_closed(message.server);
+                match message.server {
+                    WebSocketServer::NameServer => {
+                        state.nameserver_state = Some((proxy, NameServerState::default()))
+                    }
+                    WebSocketServer::LobbyServer => {
+                        state.lobby_state = Some((proxy, LobbyState::default()))
+                    }
+                    WebSocketServer::GameServer => {
+                        state.gameplay_state = Some((proxy, GameplayState::default()))
+                    }
+                }
+            }
+        }
+
+        let mut raw = message.raw.clone();
+        let forward = HaxState::websocket_hook(
+            self.state.clone(),
+            &mut raw,
+            message.server,
+            message.direction,
+        )?;
+        Ok(forward.then_some(raw))
+    }
+
+    pub(super) fn state(&self) -> futures_util::lock::MutexGuard<'_, HaxState> {
+        futures::executor::block_on(self.state.lock())
+    }
+
+    pub(super) fn into_state(self) -> HaxState {
+        match Arc::try_unwrap(self.state) {
+            Ok(state) => state.into_inner(),
+            Err(_) => unreachable!("replayed state should not be shared"),
+        }
+    }
+}
+
+fn is_authentication(message: &CapturedMessage) -> bool {
+    matches!(
+        message.parse(),
+        Some(PhotonMessage::OperationRequest(r))
+            if r.operation_code == operation_code::AUTHENTICATE
+                || r.operation_code == operation_code::AUTHENTICATE_ONCE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use photon_lib::{
+        highlevel::constants::{event_code, operation_code, parameter_code},
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationRequest, PhotonMessage},
+    };
+
+    use super::{reconstruct_state_at, StateTimeline};
+    use crate::{
+        inspect::{capture::Capture, CapturedMessage},
+        proxy::{Direction, WebSocketServer},
+    };
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_665_000_000 + seconds)
+    }
+
+    fn captured(seconds: u64, direction: Direction, message: PhotonMessage) -> CapturedMessage {
+        let mut raw = vec![];
+        message.to_websocket_bytes(&mut raw).unwrap();
+        CapturedMessage {
+            timestamp: at(seconds),
+            server: WebSocketServer::GameServer,
+            direction,
+            raw,
+        }
+    }
+
+    fn capture() -> Capture {
+        let join = |actor_nr: i32| {
+            PhotonMessage::EventData(EventData {
+                code: event_code::JOIN,
+                parameters: indexmap! {
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(actor_nr),
+                    parameter_code::ACTOR_LIST => PhotonDataType::Array(
+                        (1..=actor_nr).map(PhotonDataType::Integer).collect()
+                    ),
+                },
+            })
+        };
+        let leave = PhotonMessage::EventData(EventData {
+            code: event_code::LEAVE,
+            parameters: indexmap! {
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(2),
+            },
+        });
+
+        Capture {
+            messages: vec![
+                captured(
+                    0,
+                    Direction::ClientToServer,
+                    PhotonMessage::OperationRequest(OperationRequest {
+                        operation_code: operation_code::AUTHENTICATE,
+                        parameters: indexmap! {},
+                    }),
+                ),
+                captured(1, Direction::ServerToClient, join(1)),
+                captured(5, Direction::ServerToClient, join(2)),
+                captured(10, Direction::ServerToClient, leave),
+            ],
+        }
+    }
+
+    #[test]
+    fn reconstructs_players_at_time() {
+        let capture = capture();
+
+        let state = reconstruct_state_at(&capture, at(0));
+        let players = &state.gameplay_state.as_ref().unwrap().1.players;
+        assert!(players.is_empty());
+
+        let state = reconstruct_state_at(&capture, at(7));
+        let (_, gameplay) = state.gameplay_state.as_ref().unwrap();
+        assert_eq!(gameplay.players.keys().copied().collect::<Vec<_>>(), [1, 2]);
+
+        let state = reconstruct_state_at(&capture, at(10));
+        let (_, gameplay) = state.gameplay_state.as_ref().unwrap();
+        assert_eq!(gameplay.players.keys().copied().collect::<Vec<_>>(), [1]);
+    }
+
+    #[test]
+    fn timeline_bisects() {
+        let timeline = StateTimeline::record(&capture(), Duration::from_secs(2));
+
+        // at 0, 2, 4, 6, 8 and after the last message
+        assert_eq!(timeline.snapshots().len(), 6);
+        assert_eq!(timeline.at(at(3)).unwrap().players.len(), 1);
+        assert_eq!(timeline.at(at(7)).unwrap().players.len(), 2);
+        assert!(timeline.at(at(0) - Duration::from_secs(1)).is_none());
+
+        let two_players = timeline.bisect(|s| s.players.len() >= 2).unwrap();
+        assert_eq!(two_players.timestamp, at(6));
+        assert_eq!(two_players.messages_replayed, 3);
+    }
+}
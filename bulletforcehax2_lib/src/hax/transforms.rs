@@ -0,0 +1,669 @@
+//! The lobby rewrites as pure functions of a room's properties and a snapshot of the settings.
+//!
+//! Each transformation takes the room's properties and returns them rewritten, along with whether anything changed.
+//! They don't touch [HaxState](super::HaxState), the lobby hook copies the settings out of it and only calls these.
+//! Applying a transformation to its own output changes nothing. The rewrites don't touch the room's name either, what
+//! they mark it with is [composed](super::room_names) into it once all of them ran.
+//!
+//! This module is also built without the `proxy` feature, so the rewrites can be used without tokio, eg. from WASM.
+//! [process_lobby_message] applies them to a message the way the lobby hook does.
+
+use photon_lib::{
+    highlevel::{
+        constants::event_code,
+        structs::{RoomInfoList, RoomInfoView},
+        PhotonParameterMapConversion,
+    },
+    photon_data_type::PhotonDataType,
+    photon_message::PhotonMessage,
+    PhotonHashmap,
+};
+use tracing::debug;
+
+use super::{
+    bandwidth::feature,
+    game_variant::{GameVariant, VariantSettings},
+    room_names::{Annotation, AnnotationKind, ComposedName, RoomNameComposer},
+    room_notes::RoomFlag,
+};
+use crate::protocol::properties::{BulletForceRoomProperties, BulletForceRoomPropertiesMut};
+
+/// The settings the lobby rewrites depend on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LobbySettings {
+    pub show_mobile_games: bool,
+    pub strip_passwords: bool,
+    /// The game version to force rooms to, if version forcing is enabled and the client's version is known.
+    pub forced_version: Option<String>,
+    pub variants: VariantSettings,
+    pub names: RoomNameComposer,
+}
+
+/// What is known about a room besides its properties.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomContext {
+    /// The flag of the note on the room or its host, if there is one.
+    pub flag: Option<RoomFlag>,
+    /// Annotations for the room's name that don't come from its properties, like the match phase.
+    pub annotations: Vec<Annotation>,
+    /// How the room's name was last composed, see [ComposedName::base].
+    pub name: Option<ComposedName>,
+}
+
+/// A room after the lobby rewrites.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transformed {
+    pub room: PhotonHashmap,
+    /// The [features](feature) that changed the room, in the order they were applied.
+    pub features: Vec<&'static str>,
+    /// How the room's name was composed, if it has one.
+    pub name: Option<ComposedName>,
+}
+
+impl Transformed {
+    pub fn changed(&self) -> bool {
+        !self.features.is_empty()
+    }
+
+    fn apply(
+        &mut self,
+        feature: &'static str,
+        transform: impl FnOnce(PhotonHashmap) -> (PhotonHashmap, bool),
+    ) -> bool {
+        let (room, changed) = transform(std::mem::take(&mut self.room));
+        self.room = room;
+        if changed {
+            self.features.push(feature);
+        }
+        changed
+    }
+
+    /// Composes the room's name from the annotations. Features that only annotate are attributed if their annotation
+    /// made it into the name.
+    fn compose_name(
+        &mut self,
+        composer: &RoomNameComposer,
+        context: &RoomContext,
+        mut annotations: Vec<Annotation>,
+    ) {
+        let mut room = RoomInfoView(&mut self.room);
+        let name = match room.room_name_mut() {
+            Some(name) => name,
+            None => return,
+        };
+        let previous = context.name.as_ref().filter(|p| p.composed == *name);
+        if let Some(previous) = previous {
+            // our own output, the rewrites changed its properties already so they didn't mark it again
+            let rewritten = previous
+                .annotations
+                .iter()
+                .filter(|a| {
+                    matches!(
+                        a.kind,
+                        AnnotationKind::Password | AnnotationKind::Version | AnnotationKind::Store
+                    ) && !annotations.iter().any(|b| b.kind == a.kind)
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            annotations.extend(rewritten);
+        }
+        let original = ComposedName::base(previous, name).to_string();
+        let composition = composer.compose(&original, &annotations);
+        if composition.name != *name {
+            *name = composition.name.clone();
+            for kind in composition.shown {
+                let feature = annotation_feature(kind);
+                if !self.features.contains(&feature) {
+                    self.features.push(feature);
+                }
+            }
+            // only the annotations of an earlier composition were taken off
+            if self.features.is_empty() {
+                self.features.push(feature::LOBBY_REWRITES);
+            }
+        }
+        self.name = Some(ComposedName {
+            original,
+            composed: composition.name,
+            annotations,
+        });
+    }
+}
+
+/// The feature that annotates rooms with the given kind of annotation.
+fn annotation_feature(kind: AnnotationKind) -> &'static str {
+    match kind {
+        AnnotationKind::Replay => feature::REPLAYED_ROOMS,
+        AnnotationKind::Phase => feature::MATCH_PHASE,
+        AnnotationKind::Favorite => feature::ROOM_NOTES,
+        AnnotationKind::Password => feature::PASSWORD_STRIPPING,
+        AnnotationKind::Version => feature::VERSION_FORCING,
+        AnnotationKind::Store => feature::MOBILE_GAMES,
+    }
+}
+
+/// Applies all enabled lobby rewrites to a room. Rooms of variants the rewrites are disabled for are left alone.
+pub fn transform_room(
+    room: PhotonHashmap,
+    settings: &LobbySettings,
+    context: &RoomContext,
+) -> Transformed {
+    let mut transformed = Transformed {
+        room,
+        features: vec![],
+        name: None,
+    };
+    let variant = RoomInfoView(&transformed.room).variant();
+    if !settings.variants.is_enabled(variant) {
+        return transformed;
+    }
+
+    let mut annotations = context.annotations.clone();
+    if settings.show_mobile_games {
+        let store_id = RoomInfoView(&transformed.room)
+            .store_id()
+            .map(str::to_string);
+        if transformed.apply(feature::MOBILE_GAMES, force_games_web) {
+            annotations.extend(store_id.as_deref().map(Annotation::store));
+        }
+    }
+    if let Some(version) = &settings.forced_version {
+        // the client can't play rooms of another variant, even on the same version
+        if GameVariant::of_version(version) == variant {
+            let actual = RoomInfoView(&transformed.room)
+                .game_version()
+                .map(str::to_string);
+            if transformed.apply(feature::VERSION_FORCING, |room| {
+                force_games_current_ver(room, version)
+            }) {
+                annotations.extend(actual.as_deref().map(Annotation::version));
+            }
+        }
+    }
+    if settings.strip_passwords && transformed.apply(feature::PASSWORD_STRIPPING, strip_password) {
+        annotations.push(Annotation::password());
+    }
+    match context.flag {
+        Some(RoomFlag::Favorite) => annotations.push(Annotation::favorite()),
+        Some(RoomFlag::Blocked) => {
+            transformed.apply(feature::ROOM_NOTES, hide_room);
+        }
+        None => (),
+    }
+    transformed.compose_name(&settings.names, context, annotations);
+    transformed
+}
+
+/// What [transform_game_list] did to a game list.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransformedList {
+    /// The features that changed a room, in the order they were applied.
+    pub features: Vec<&'static str>,
+    /// How the names of the rooms were composed, by room id.
+    pub names: Vec<(String, ComposedName)>,
+}
+
+/// Applies [transform_room] to every room of a game list, keyed by room id. The context of a room is looked up by its
+/// id and properties before it is rewritten.
+pub fn transform_game_list(
+    games: &mut PhotonHashmap,
+    settings: &LobbySettings,
+    mut context: impl FnMut(&str, &RoomInfoView<&PhotonHashmap>) -> RoomContext,
+) -> TransformedList {
+    let mut list = TransformedList::default();
+    for (id, room) in games.iter_mut() {
+        if let (PhotonDataType::String(id), PhotonDataType::Hashtable(props)) = (id, room) {
+            let context = context(id, &RoomInfoView(&*props));
+            let transformed = transform_room(std::mem::take(props), settings, &context);
+            *props = transformed.room;
+            list.features.extend(transformed.features);
+            list.names
+                .extend(transformed.name.map(|name| (id.clone(), name)));
+        }
+    }
+    list
+}
+
+/// The feature to attribute a rewritten message to: the one that changed it, or [feature::LOBBY_REWRITES] if several
+/// did. [None] if nothing changed it.
+pub fn message_feature(mut features: Vec<&'static str>) -> Option<&'static str> {
+    features.dedup();
+    match features.as_slice() {
+        [] => None,
+        [feature] => Some(*feature),
+        _ => Some(feature::LOBBY_REWRITES),
+    }
+}
+
+/// Applies the lobby rewrites to a message from the lobby server, without any of the state the lobby hook keeps. The
+/// rooms of game lists and their updates are rewritten with [transform_game_list], other messages are left alone.
+///
+/// Returns the feature that changed the message, see [message_feature].
+pub fn process_lobby_message(
+    message: &mut PhotonMessage,
+    settings: &LobbySettings,
+    context: impl FnMut(&str, &RoomInfoView<&PhotonHashmap>) -> RoomContext,
+) -> anyhow::Result<Option<&'static str>> {
+    let event = match message {
+        PhotonMessage::EventData(event)
+            if matches!(
+                event.code,
+                event_code::GAME_LIST | event_code::GAME_LIST_UPDATE
+            ) =>
+        {
+            event
+        }
+        _ => return Ok(None),
+    };
+    let mut game_list = RoomInfoList::from_map(&mut event.parameters.clone())?;
+    let feature =
+        message_feature(transform_game_list(&mut game_list.games, settings, context).features);
+    if feature.is_some() {
+        game_list.into_map(&mut event.parameters);
+    }
+    Ok(feature)
+}
+
+/// Runs an edit on a room's properties, returning them with whether the edit changed anything.
+fn edit(
+    mut room: PhotonHashmap,
+    edit: impl FnOnce(&mut RoomInfoView<&mut PhotonHashmap>) -> bool,
+) -> (PhotonHashmap, bool) {
+    let changed = edit(&mut RoomInfoView(&mut room));
+    (room, changed)
+}
+
+/// Clears the password of passworded rooms so they can be joined.
+pub fn strip_password(room: PhotonHashmap) -> (PhotonHashmap, bool) {
+    edit(room, |room| {
+        if !room.has_password() {
+            return false;
+        }
+        if let Some(password) = room.password_mut() {
+            password.clear();
+        }
+        true
+    })
+}
+
+/// Removes a room from the client's list.
+pub fn hide_room(room: PhotonHashmap) -> (PhotonHashmap, bool) {
+    edit(room, |room| {
+        if room.removed() == Some(&true) {
+            return false;
+        }
+        debug!(room_name = room.room_name(), "Hiding blocked room");
+        room.set_removed(true);
+        true
+    })
+}
+
+/// Lists rooms of other stores as web rooms so the client shows them.
+pub fn force_games_web(room: PhotonHashmap) -> (PhotonHashmap, bool) {
+    edit(room, |room| {
+        if matches!(room.store_id(), Some("BALYZE_WEB") | None) {
+            return false;
+        }
+        // force game to web so it shows up in the list
+        if let Some(store_id) = room.store_id_mut() {
+            *store_id = "BALYZE_WEB".into();
+        }
+        true
+    })
+}
+
+/// Forces a room to the given version so it appears in the lobby list.
+///
+/// Only rooms of the variant the client plays should be forced, see [GameVariant::of_version].
+pub fn force_games_current_ver(room: PhotonHashmap, target_version: &str) -> (PhotonHashmap, bool) {
+    edit(room, |room| {
+        match room.game_version() {
+            Some(version) if version != target_version => (),
+            _ => return false,
+        }
+        if let Some(new_version) = room.game_version_mut() {
+            *new_version = target_version.to_string();
+        }
+        true
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::{
+        highlevel::{
+            constants::{event_code, game_property_key, parameter_code},
+            structs::RoomInfoView,
+        },
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, PhotonMessage},
+        PhotonHashmap,
+    };
+    use proptest::{collection::vec, option, prelude::*};
+
+    use super::{process_lobby_message, transform_room, LobbySettings, RoomContext};
+    use crate::{
+        hax::{
+            bandwidth::feature,
+            game_variant::{GameVariant, VariantSettings},
+            room_names::{Annotation, AnnotationKind, ComposedName, RoomNameComposer},
+            room_notes::RoomFlag,
+        },
+        protocol::properties::BulletForceRoomProperties,
+        testgen::GameListBuilder,
+    };
+
+    fn string(s: &str) -> PhotonDataType {
+        PhotonDataType::String(s.into())
+    }
+
+    fn variant() -> impl Strategy<Value = GameVariant> {
+        prop_oneof![Just(GameVariant::BulletForce), Just(GameVariant::NewFps)]
+    }
+
+    /// Rooms of either variant, with any of the rewritten properties missing, and some unrelated ones.
+    fn room() -> impl Strategy<Value = PhotonHashmap> {
+        (
+            variant(),
+            option::of("[a-zA-Z0-9 \\[\\]]{0,12}"),
+            option::of(prop_oneof![Just(String::new()), "[0-9]{1,4}"]),
+            option::of(prop_oneof![
+                Just("BALYZE_WEB".to_string()),
+                Just("BALYZE_MOBILE".to_string()),
+                "[A-Z_]{1,12}",
+            ]),
+            option::of(prop_oneof![
+                Just("1.90.0".to_string()),
+                "1\\.[0-9]{2}\\.[0-9]",
+                "newfps-0\\.[0-9]\\.[0-9]",
+            ]),
+            option::of(any::<u8>()),
+            vec(("[a-z]{1,8}", any::<i32>()), 0..4),
+        )
+            .prop_map(
+                |(variant, name, password, store_id, version, player_count, extra)| {
+                    let keys = variant.keys();
+                    let mut room = PhotonHashmap::new();
+                    for (key, value) in [
+                        (keys.room_name, name),
+                        (keys.password, password),
+                        (keys.store_id, store_id),
+                        (keys.game_version, version),
+                    ] {
+                        if let Some(value) = value {
+                            room.insert(string(key), PhotonDataType::String(value));
+                        }
+                    }
+                    if let Some(count) = player_count {
+                        room.insert(
+                            PhotonDataType::Byte(game_property_key::PLAYER_COUNT),
+                            PhotonDataType::Byte(count),
+                        );
+                    }
+                    for (key, value) in extra {
+                        // prefixed so they never collide with the properties the rewrites know of
+                        room.insert(string(&format!("x_{key}")), PhotonDataType::Integer(value));
+                    }
+                    room
+                },
+            )
+    }
+
+    fn settings() -> impl Strategy<Value = LobbySettings> {
+        (
+            any::<bool>(),
+            any::<bool>(),
+            option::of(prop_oneof![
+                Just("1.90.0".to_string()),
+                Just("newfps-0.4.1".to_string())
+            ]),
+            any::<bool>(),
+            // down to no room for annotations at all
+            prop_oneof![Just(32usize), 0usize..24],
+        )
+            .prop_map(
+                |(show_mobile_games, strip_passwords, forced_version, newfps, budget)| {
+                    LobbySettings {
+                        show_mobile_games,
+                        strip_passwords,
+                        forced_version,
+                        variants: VariantSettings { newfps },
+                        names: RoomNameComposer {
+                            budget,
+                            ..Default::default()
+                        },
+                    }
+                },
+            )
+    }
+
+    fn context() -> impl Strategy<Value = RoomContext> {
+        (
+            option::of(prop_oneof![
+                Just(RoomFlag::Favorite),
+                Just(RoomFlag::Blocked)
+            ]),
+            option::of(Just(
+                Annotation::new(AnnotationKind::Phase, "[~3m left]").abbreviated("[~3m]"),
+            )),
+        )
+            .prop_map(|(flag, phase)| RoomContext {
+                flag,
+                annotations: phase.into_iter().collect(),
+                name: None,
+            })
+    }
+
+    /// The properties the rewrites may change.
+    fn rewritten_keys(room: &PhotonHashmap) -> Vec<PhotonDataType> {
+        let mut keys = [GameVariant::BulletForce, GameVariant::NewFps]
+            .iter()
+            .flat_map(|variant| {
+                let keys = variant.keys();
+                [
+                    keys.room_name,
+                    keys.password,
+                    keys.store_id,
+                    keys.game_version,
+                ]
+            })
+            .map(string)
+            .collect::<Vec<_>>();
+        keys.push(PhotonDataType::Byte(game_property_key::REMOVED));
+        keys.retain(|key| room.contains_key(key));
+        keys
+    }
+
+    #[test]
+    fn processes_game_lists() {
+        let builder = GameListBuilder::new(20).with_mobile_fraction(0.5);
+        let settings = LobbySettings {
+            show_mobile_games: true,
+            ..Default::default()
+        };
+        let mut message = builder.build();
+        let feature = process_lobby_message(&mut message, &settings, |_, _| Default::default());
+        assert_eq!(feature.unwrap(), Some(feature::MOBILE_GAMES));
+        let games = match &message {
+            PhotonMessage::EventData(event) => {
+                match &event.parameters[&parameter_code::GAME_LIST] {
+                    PhotonDataType::Hashtable(games) => games.clone(),
+                    _ => panic!("game list should be a hashtable"),
+                }
+            }
+            _ => panic!("message should stay an event"),
+        };
+        assert_eq!(games.len(), 20);
+        for room in games.values() {
+            match room {
+                PhotonDataType::Hashtable(room) => {
+                    assert_eq!(RoomInfoView(room).store_id(), Some("BALYZE_WEB"))
+                }
+                _ => panic!("room should be a hashtable"),
+            }
+        }
+
+        // nothing enabled, or not a game list
+        let mut message = builder.build();
+        let feature = process_lobby_message(&mut message, &LobbySettings::default(), |_, _| {
+            Default::default()
+        });
+        assert_eq!(feature.unwrap(), None);
+        assert_eq!(message, builder.build());
+        let mut message = PhotonMessage::EventData(EventData {
+            code: event_code::JOIN,
+            parameters: Default::default(),
+        });
+        let feature = process_lobby_message(&mut message, &settings, |_, _| Default::default());
+        assert_eq!(feature.unwrap(), None);
+    }
+
+    proptest! {
+        #[test]
+        fn idempotent(room in room(), settings in settings()) {
+            let once = transform_room(room, &settings, &RoomContext::default());
+            let twice = transform_room(once.room.clone(), &settings, &RoomContext::default());
+            prop_assert_eq!(&twice.room, &once.room);
+            prop_assert!(!twice.changed());
+        }
+
+        #[test]
+        fn noop_when_disabled(room in room(), newfps in any::<bool>()) {
+            let settings = LobbySettings {
+                variants: VariantSettings { newfps },
+                ..Default::default()
+            };
+            let transformed = transform_room(room.clone(), &settings, &RoomContext::default());
+            prop_assert_eq!(&transformed.room, &room);
+            prop_assert!(!transformed.changed());
+        }
+
+        #[test]
+        fn preserves_unrelated_keys(
+            room in room(),
+            settings in settings(),
+            context in context(),
+        ) {
+            let transformed = transform_room(room.clone(), &settings, &context);
+            let rewritable = rewritten_keys(&transformed.room);
+            for (key, value) in &room {
+                if !rewritable.contains(key) {
+                    prop_assert_eq!(transformed.room.get(key), Some(value));
+                }
+            }
+            // no keys are added, except for hiding the room
+            for key in transformed.room.keys() {
+                prop_assert!(
+                    room.contains_key(key) || *key == PhotonDataType::Byte(game_property_key::REMOVED)
+                );
+            }
+            prop_assert_eq!(transformed.changed(), transformed.room != room);
+        }
+    }
+
+    #[test]
+    fn skips_disabled_variants() {
+        let mut room = PhotonHashmap::new();
+        room.insert(string("roomname"), string("Chill"));
+        room.insert(string("password"), string("1234"));
+        room.insert(string("gameversion"), string("newfps-0.4.1"));
+        let settings = LobbySettings {
+            strip_passwords: true,
+            variants: VariantSettings { newfps: false },
+            ..Default::default()
+        };
+        let context = RoomContext {
+            flag: Some(RoomFlag::Favorite),
+            ..Default::default()
+        };
+        assert!(!transform_room(room.clone(), &settings, &context).changed());
+
+        let settings = LobbySettings {
+            variants: VariantSettings { newfps: true },
+            ..settings
+        };
+        let transformed = transform_room(room, &settings, &context);
+        assert_eq!(transformed.features, ["password stripping", "room notes"]);
+        assert_eq!(
+            transformed.room[&string("roomname")],
+            string("[*] [p] Chill")
+        );
+    }
+
+    #[test]
+    fn composes_names_once() {
+        let mut room = PhotonHashmap::new();
+        room.insert(string("roomName"), string("Only snipers allowed"));
+        room.insert(string("password"), string("1234"));
+        room.insert(string("storeID"), string("BALYZE_MOBILE"));
+        room.insert(string("gameVersion"), string("1.89.0"));
+        let settings = LobbySettings {
+            show_mobile_games: true,
+            strip_passwords: true,
+            forced_version: Some("1.90.0".into()),
+            ..Default::default()
+        };
+        let context = RoomContext {
+            flag: Some(RoomFlag::Favorite),
+            annotations: vec![
+                Annotation::new(AnnotationKind::Phase, "[~12m left]").abbreviated("[~12m]")
+            ],
+            name: None,
+        };
+        let transformed = transform_room(room, &settings, &context);
+        let name = "[~12m] [*] [p] [v] Only snipers allowed";
+        assert_eq!(transformed.room[&string("roomName")], string(name));
+        assert_eq!(
+            transformed.features,
+            [
+                "mobile games",
+                "version forcing",
+                "password stripping",
+                "match phase",
+                "room notes"
+            ]
+        );
+        let composed = transformed.name.as_ref().unwrap();
+        assert_eq!(
+            (composed.original.as_str(), composed.composed.as_str()),
+            ("Only snipers allowed", name)
+        );
+        assert_eq!(composed.annotations.len(), 5);
+
+        // the composed name listed again, eg. by an update run through the hook twice
+        let context = RoomContext {
+            name: transformed.name,
+            ..context
+        };
+        let again = transform_room(transformed.room.clone(), &settings, &context);
+        assert_eq!(again.room, transformed.room);
+        assert!(!again.changed());
+
+        // the annotations are composed from the original name, not stacked
+        let unannotated = RoomContext {
+            flag: None,
+            annotations: vec![],
+            ..context
+        };
+        let again = transform_room(transformed.room, &settings, &unannotated);
+        assert_eq!(
+            again.room[&string("roomName")],
+            string("[p] [1.89.0] [M] Only snipers allowed")
+        );
+
+        // nothing marks the room anymore
+        let mut room = PhotonHashmap::new();
+        room.insert(string("roomName"), string("[*] Chill"));
+        let context = RoomContext {
+            name: Some(ComposedName {
+                original: "Chill".into(),
+                composed: "[*] Chill".into(),
+                annotations: vec![Annotation::favorite()],
+            }),
+            ..Default::default()
+        };
+        let again = transform_room(room, &LobbySettings::default(), &context);
+        assert_eq!(again.room[&string("roomName")], string("Chill"));
+        assert_eq!(again.features, ["lobby rewrites"]);
+    }
+}
@@ -0,0 +1,531 @@
+//! A copy of what the overlay shows, readable without locking the state.
+//!
+//! The overlay draws every frame, and locking [HaxState] for that makes a frame wait whenever a handler takes a while.
+//! Instead, the part of the state the overlay shows is copied into a [UiSnapshot] that is swapped in atomically, and
+//! readers get the current one from a [UiSnapshots] handle without taking any lock. Everything in a snapshot was copied
+//! at once, its [generation](UiSnapshot::generation) tells which copy it is.
+//!
+//! Snapshots are published after messages are handled, but at most once per
+//! [publish interval](UiSnapshotSettings::publish_interval), and by a background task while no messages come in, so a
+//! snapshot is never older than the interval plus however long the state's lock is held.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use futures_util::lock::Mutex;
+use photon_lib::primitives::Vector3;
+
+use super::{
+    availability::FeatureAvailability, detection::SuspicionScore, link_quality::LinkQualityStats,
+    match_phase::PhaseEstimate, projectiles::Projectile, selftest::SelfTestReport,
+    settings::Settings, BulletForceHax, HaxState, VersionInfo, WatchdogMode,
+};
+use crate::{
+    protocol::{loadout::Loadout, properties::ClientPlatform},
+    proxy::WebSocketServer,
+};
+
+/// How many lines of the kill feed a snapshot holds, the newest ones.
+const KILL_FEED_LINES: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UiSnapshotSettings {
+    /// How often snapshots are published at most, which is also how stale they can get.
+    pub publish_interval: Duration,
+}
+
+impl Default for UiSnapshotSettings {
+    fn default() -> Self {
+        Self {
+            publish_interval: Duration::from_millis(50),
+        }
+    }
+}
+
+/// What the overlay shows, copied from the state at once, see [ui_snapshot](self).
+#[derive(Debug, Clone)]
+pub struct UiSnapshot {
+    /// Counts up with every published snapshot. The empty snapshot from before the first one is generation 0.
+    pub generation: u64,
+    pub published: Instant,
+    /// The room we're in, if any.
+    pub room: Option<UiRoom>,
+    pub players: Vec<UiPlayer>,
+    /// The scores of each team, in the order the teams first show up in [Self::players].
+    pub scoreboard: Vec<TeamScore>,
+    /// The round trip time to the game server.
+    pub ping: Option<Duration>,
+    /// How far along the round in the room is.
+    pub round: PhaseEstimate,
+    /// The newest lines of the kill feed, oldest first.
+    pub kill_feed: Vec<String>,
+    /// The projectiles in flight.
+    pub projectiles: Vec<Projectile>,
+    /// The feature toggles.
+    pub settings: Arc<Settings>,
+    /// The toggles that are kept in the state rather than in the [settings](Self::settings).
+    pub stealth_host: bool,
+    pub receive_all_groups: bool,
+    pub watchdog_mode: WatchdogMode,
+    /// Whether each feature can currently work.
+    pub availability: FeatureAvailability,
+    pub status: UiStatus,
+    pub feeds: FeedLengths,
+}
+
+impl Default for UiSnapshot {
+    fn default() -> Self {
+        Self {
+            generation: 0,
+            published: Instant::now(),
+            room: None,
+            players: vec![],
+            scoreboard: vec![],
+            ping: None,
+            round: PhaseEstimate::UNKNOWN,
+            kill_feed: vec![],
+            projectiles: vec![],
+            settings: Arc::default(),
+            stealth_host: false,
+            receive_all_groups: false,
+            watchdog_mode: WatchdogMode::default(),
+            availability: FeatureAvailability::default(),
+            status: UiStatus::default(),
+            feeds: FeedLengths::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UiRoom {
+    pub name: Option<String>,
+    /// The name the lobby shows, see [GameplayState::room_title](super::GameplayState::room_title).
+    pub title: Option<String>,
+    pub map_name: Option<String>,
+    pub hosting: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UiPlayer {
+    pub actor_id: i32,
+    pub nickname: Option<String>,
+    pub team_number: Option<u8>,
+    pub health: Option<f32>,
+    pub kills: Option<i16>,
+    pub deaths: Option<i16>,
+    pub position: Option<Vector3>,
+    /// The callout of the map the position is in, see [map_annotations](super::map_annotations).
+    pub callout: Option<String>,
+    pub loadout: Loadout,
+    pub platform: ClientPlatform,
+    /// [None] until enough of their updates arrived.
+    pub link: Option<LinkQualityStats>,
+    pub suspicion: Option<SuspicionScore>,
+    /// How many of their cosmetic RPCs were muted.
+    pub muted_rpcs: u64,
+    /// This is the player using the proxy.
+    pub is_local: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TeamScore {
+    /// [None] for players whose team isn't known.
+    pub team_number: Option<u8>,
+    pub players: usize,
+    pub kills: i32,
+    pub deaths: i32,
+}
+
+/// What the overlay shows about the session and the connections.
+#[derive(Debug, Clone, Default)]
+pub struct UiStatus {
+    pub user_id: Option<String>,
+    pub version: Option<VersionInfo>,
+    /// The names of the regions the name server listed.
+    pub regions: Vec<String>,
+    pub selftest_running: bool,
+    pub selftest_report: Option<SelfTestReport>,
+    /// The connections with encrypted traffic, which can't be read.
+    pub encrypted: Vec<WebSocketServer>,
+    /// The connections whose messages are forwarded without being parsed, see [parse_breaker](super::parse_breaker).
+    pub passthrough: Vec<WebSocketServer>,
+}
+
+/// How many entries the logs the overlay lists hold.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeedLengths {
+    pub kill_feed: usize,
+    pub recent_messages: usize,
+    pub dropped_messages: usize,
+    pub web_requests: usize,
+}
+
+/// A handle to the current [UiSnapshot]. Clones share the same snapshot.
+#[derive(Debug, Clone, Default)]
+pub struct UiSnapshots(Arc<ArcSwap<UiSnapshot>>);
+
+impl UiSnapshots {
+    /// The current snapshot, without locking anything.
+    pub fn load(&self) -> Arc<UiSnapshot> {
+        self.0.load_full()
+    }
+}
+
+/// Publishes the snapshots of a state.
+#[derive(Debug, Default)]
+pub(super) struct UiPublisher {
+    snapshots: UiSnapshots,
+    last_published: Option<Instant>,
+}
+
+impl UiSnapshot {
+    fn capture(hax: &HaxState, generation: u64, now: Instant) -> Self {
+        let gameplay = hax.gameplay_state.as_ref().map(|(_, state)| state);
+        let mut link_quality = hax.player_link_quality();
+        let mut suspicion = hax.suspicion_scores();
+        let players = match gameplay {
+            Some(state) => state
+                .players
+                .iter()
+                .map(|(actor_id, player)| UiPlayer {
+                    actor_id: *actor_id,
+                    nickname: player.nickname.clone(),
+                    team_number: player.team_number,
+                    health: player.health,
+                    kills: player.kills,
+                    deaths: player.deaths,
+                    position: player.position.clone(),
+                    callout: player
+                        .position
+                        .as_ref()
+                        .and_then(|position| hax.callout_for(position))
+                        .map(Into::into),
+                    loadout: player.loadout.clone(),
+                    platform: player.platform.clone(),
+                    link: link_quality.shift_remove(actor_id),
+                    suspicion: suspicion.shift_remove(actor_id),
+                    muted_rpcs: hax.stats.muted_rpcs.get(actor_id).copied().unwrap_or(0),
+                    is_local: state.player_id == Some(*actor_id),
+                })
+                .collect(),
+            None => vec![],
+        };
+
+        let mut scoreboard: Vec<TeamScore> = vec![];
+        for player in &players {
+            let index = match scoreboard
+                .iter()
+                .position(|team| team.team_number == player.team_number)
+            {
+                Some(index) => index,
+                None => {
+                    scoreboard.push(TeamScore {
+                        team_number: player.team_number,
+                        ..Default::default()
+                    });
+                    scoreboard.len() - 1
+                }
+            };
+            let team = &mut scoreboard[index];
+            team.players += 1;
+            team.kills += player.kills.unwrap_or_default() as i32;
+            team.deaths += player.deaths.unwrap_or_default() as i32;
+        }
+
+        Self {
+            generation,
+            published: now,
+            room: gameplay.map(|state| UiRoom {
+                name: state.room_name.clone(),
+                title: state.room_title.clone(),
+                map_name: state.map_name.clone(),
+                hosting: state.hosting,
+            }),
+            players,
+            scoreboard,
+            ping: gameplay.and_then(|state| state.latency.round_trip()),
+            round: hax.match_phase(),
+            kill_feed: gameplay.map_or(vec![], |state| {
+                let mut lines = state
+                    .kill_feed_lines()
+                    .rev()
+                    .take(KILL_FEED_LINES)
+                    .collect::<Vec<_>>();
+                lines.reverse();
+                lines
+            }),
+            projectiles: hax.active_projectiles().cloned().collect(),
+            settings: hax.settings(),
+            stealth_host: hax.stealth_host(),
+            receive_all_groups: hax.receives_all_groups(),
+            watchdog_mode: hax.watchdog.mode,
+            availability: hax.feature_availability(),
+            status: UiStatus {
+                user_id: hax.global_state.user_id.clone(),
+                version: hax.global_state.version.clone(),
+                regions: hax.global_state.regions.keys().cloned().collect(),
+                selftest_running: hax.is_selftest_running(),
+                selftest_report: hax.selftest_report().cloned(),
+                encrypted: hax.encryption.active_connections().collect(),
+                passthrough: hax.parse_breaker.passthrough_connections().collect(),
+            },
+            feeds: FeedLengths {
+                kill_feed: gameplay.map_or(0, |state| state.kill_feed.len()),
+                recent_messages: hax.recent_messages.len(),
+                dropped_messages: hax.drop_log.entries().len(),
+                web_requests: hax.web_requests.entries().len(),
+            },
+        }
+    }
+}
+
+impl HaxState {
+    /// The current [UiSnapshot]. This only reads the published snapshot, to read it without locking the state keep
+    /// the handle from [Self::ui_snapshots].
+    pub fn ui_snapshot(&self) -> Arc<UiSnapshot> {
+        self.ui_publisher.snapshots.load()
+    }
+
+    /// A handle to the published snapshots that can be kept to read them without locking this state.
+    pub fn ui_snapshots(&self) -> UiSnapshots {
+        self.ui_publisher.snapshots.clone()
+    }
+
+    /// Copies the state into a new [UiSnapshot] and swaps it in.
+    pub fn publish_ui_snapshot(&mut self, now: Instant) {
+        let generation = self.ui_publisher.snapshots.load().generation + 1;
+        let snapshot = UiSnapshot::capture(self, generation, now);
+        self.ui_publisher.snapshots.0.store(Arc::new(snapshot));
+        self.ui_publisher.last_published = Some(now);
+    }
+
+    /// Publishes a snapshot if the last one is at least a [publish interval](UiSnapshotSettings::publish_interval)
+    /// old. Returns whether it did.
+    pub fn publish_ui_snapshot_if_due(&mut self, now: Instant) -> bool {
+        let due = match self.ui_publisher.last_published {
+            Some(last) => {
+                now.saturating_duration_since(last) >= self.ui_snapshot_settings.publish_interval
+            }
+            None => true,
+        };
+        if due {
+            self.publish_ui_snapshot(now);
+        }
+        due
+    }
+}
+
+impl BulletForceHax {
+    /// A handle to the published [UiSnapshot]s, see [ui_snapshot](self).
+    pub fn ui_snapshots(&self) -> UiSnapshots {
+        futures::executor::block_on(self.state.lock()).ui_snapshots()
+    }
+}
+
+/// Publishes snapshots while no messages come in, which would publish them otherwise.
+pub(super) async fn run_publisher(state: Arc<Mutex<HaxState>>) {
+    loop {
+        let interval = {
+            let mut hax = state.lock().await;
+            hax.publish_ui_snapshot_if_due(Instant::now());
+            hax.ui_snapshot_settings.publish_interval
+        };
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use futures_util::lock::Mutex;
+
+    use super::{TeamScore, UiSnapshotSettings};
+    use crate::{
+        hax::{GameplayState, HaxState, PlayerActor},
+        proxy::{websocket_proxy::WebSocketProxy, WebSocketServer},
+    };
+
+    fn in_game(hax: &mut HaxState, gameplay: GameplayState) {
+        let proxy = WebSocketProxy::detached(
+            WebSocketServer::GameServer,
+            hax.bandwidth.clone(),
+            hax.shared_settings(),
+        );
+        hax.gameplay_state = Some((proxy, gameplay));
+    }
+
+    #[test]
+    fn publishes_at_most_once_per_interval() {
+        let mut hax = HaxState {
+            ui_snapshot_settings: UiSnapshotSettings {
+                publish_interval: Duration::from_millis(100),
+            },
+            ..Default::default()
+        };
+        let snapshots = hax.ui_snapshots();
+        assert_eq!(snapshots.load().generation, 0);
+
+        let start = Instant::now();
+        assert!(hax.publish_ui_snapshot_if_due(start));
+        assert!(!hax.publish_ui_snapshot_if_due(start + Duration::from_millis(50)));
+        assert_eq!(snapshots.load().generation, 1);
+        assert!(hax.publish_ui_snapshot_if_due(start + Duration::from_millis(100)));
+        assert_eq!(hax.ui_snapshot().generation, 2);
+        assert_eq!(
+            snapshots.load().published,
+            start + Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn copies_the_game() {
+        let mut hax = HaxState::default();
+        let mut gameplay = GameplayState {
+            player_id: Some(2),
+            room_name: Some("room".into()),
+            hosting: true,
+            ..Default::default()
+        };
+        for (actor_id, team_number, kills) in [
+            (1, Some(1), 3),
+            (2, Some(2), 1),
+            (3, Some(1), 4),
+            (4, None, 0),
+        ] {
+            gameplay.players.insert(
+                actor_id,
+                PlayerActor {
+                    team_number,
+                    kills: Some(kills),
+                    deaths: Some(1),
+                    ..Default::default()
+                },
+            );
+        }
+        in_game(&mut hax, gameplay);
+        hax.publish_ui_snapshot(Instant::now());
+
+        let snapshot = hax.ui_snapshot();
+        let room = snapshot.room.as_ref().unwrap();
+        assert_eq!(room.name.as_deref(), Some("room"));
+        assert!(room.hosting);
+        assert_eq!(snapshot.players.len(), 4);
+        assert!(snapshot.players[1].is_local && !snapshot.players[0].is_local);
+        assert!(snapshot.players.iter().all(|player| player.link.is_none()));
+        assert!(snapshot.kill_feed.is_empty() && snapshot.projectiles.is_empty());
+        assert_eq!(snapshot.settings, hax.settings());
+        assert_eq!(snapshot.availability, hax.feature_availability());
+        assert_eq!(
+            snapshot.scoreboard,
+            [
+                TeamScore {
+                    team_number: Some(1),
+                    players: 2,
+                    kills: 7,
+                    deaths: 2
+                },
+                TeamScore {
+                    team_number: Some(2),
+                    players: 1,
+                    kills: 1,
+                    deaths: 1
+                },
+                TeamScore {
+                    team_number: None,
+                    players: 1,
+                    kills: 0,
+                    deaths: 1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn readers_never_see_torn_snapshots() {
+        const PLAYERS: i32 = 16;
+        const WRITES: u64 = 2000;
+
+        let mut hax = HaxState::default();
+        let mut gameplay = GameplayState::default();
+        for actor_id in 1..=PLAYERS {
+            gameplay.players.insert(actor_id, PlayerActor::default());
+        }
+        in_game(&mut hax, gameplay);
+        let snapshots = hax.ui_snapshots();
+        let hax = Arc::new(Mutex::new(hax));
+        let done = Arc::new(AtomicBool::new(false));
+
+        // every field of the state is set to the number of the write, and published right after
+        let writer = {
+            let hax = hax.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                for i in 1..=WRITES {
+                    let mut hax = futures::executor::block_on(hax.lock());
+                    let (_, state) = hax.gameplay_state.as_mut().unwrap();
+                    state.room_name = Some(i.to_string());
+                    for player in state.players.values_mut() {
+                        player.kills = Some(i as i16);
+                        player.nickname = Some(format!("player {i}"));
+                    }
+                    hax.update_settings(|s| s.muted_actors = [i as i32].into());
+                    hax.publish_ui_snapshot(Instant::now());
+                }
+                done.store(true, Ordering::Relaxed);
+            })
+        };
+
+        let readers = (0..4)
+            .map(|_| {
+                let snapshots = snapshots.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let mut reads = 0;
+                    let mut last_generation = 0;
+                    // the last read is after the writer is done, so every reader reads at least once
+                    loop {
+                        let finished = done.load(Ordering::Relaxed);
+                        let snapshot = snapshots.load();
+                        let generation = snapshot.generation;
+                        assert!(generation >= last_generation);
+                        last_generation = generation;
+                        reads += 1;
+                        if generation == 0 {
+                            continue;
+                        }
+
+                        let room = snapshot.room.as_ref().unwrap();
+                        assert_eq!(room.name, Some(generation.to_string()));
+                        assert_eq!(snapshot.players.len(), PLAYERS as usize);
+                        for player in &snapshot.players {
+                            assert_eq!(player.kills, Some(generation as i16));
+                            assert_eq!(player.nickname, Some(format!("player {generation}")));
+                        }
+                        assert_eq!(snapshot.scoreboard[0].kills, PLAYERS * generation as i32);
+                        assert_eq!(snapshot.settings.muted_actors, [generation as i32].into());
+                        if finished {
+                            break reads;
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        writer.join().unwrap();
+        for reader in readers {
+            assert!(reader.join().unwrap() > 0);
+        }
+        assert_eq!(snapshots.load().generation, WRITES);
+    }
+}
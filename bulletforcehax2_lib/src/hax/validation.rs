@@ -0,0 +1,197 @@
+//! Sanity checks for messages that were modified by the hook, so broken rewrites don't get us disconnected.
+
+use photon_lib::{
+    highlevel::{
+        constants::{event_code, operation_code},
+        structs::{
+            JoinGameRequest, Player, RaiseEvent, RoomInfo, RoomInfoList,
+            SetPropertiesOperationRequest,
+        },
+        PhotonMapConversion, PhotonParameterMapConversion,
+    },
+    photon_data_type::PhotonDataType,
+    photon_message::PhotonMessage,
+    ParameterMap,
+};
+
+use crate::inspect::{message_code, message_parameters, message_type_name};
+
+/// Checks whether a rewritten message can be safely forwarded in place of the original.
+///
+/// On failure, returns a list of human-readable problems and differences.
+pub(super) fn validate_rewrite(
+    original_bytes: &[u8],
+    rewritten_bytes: &[u8],
+    rewritten: &PhotonMessage,
+) -> Result<(), Vec<String>> {
+    let reparsed = match PhotonMessage::from_websocket_bytes(&mut &rewritten_bytes[..]) {
+        Ok(m) => m,
+        Err(e) => return Err(vec![format!("rewritten message does not parse: {e}")]),
+    };
+
+    let mut problems = vec![];
+
+    if reparsed != *rewritten {
+        problems.push("rewritten message does not round-trip".to_string());
+    }
+
+    // the original message parsed before, so this should never fail
+    if let Ok(original) = PhotonMessage::from_websocket_bytes(&mut &original_bytes[..]) {
+        if message_type_name(&original) != message_type_name(&reparsed)
+            || message_code(&original) != message_code(&reparsed)
+        {
+            problems.push(format!(
+                "message kind changed from {} {:?} to {} {:?}",
+                message_type_name(&original),
+                message_code(&original),
+                message_type_name(&reparsed),
+                message_code(&reparsed),
+            ));
+        }
+
+        if let (Some(original_params), Some(reparsed_params)) =
+            (message_parameters(&original), message_parameters(&reparsed))
+        {
+            if original_params.len() != reparsed_params.len() {
+                problems.push(format!(
+                    "parameter count changed from {} to {}",
+                    original_params.len(),
+                    reparsed_params.len()
+                ));
+            }
+
+            if !problems.is_empty() {
+                problems.extend(parameter_diff(original_params, reparsed_params));
+            }
+        }
+    }
+
+    if let Err(e) = validate_highlevel(&reparsed) {
+        problems.push(format!(
+            "rewritten message does not convert to its high-level type: {e}"
+        ));
+    }
+
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(problems),
+    }
+}
+
+/// Checks whether the high-level structs that our rewrites touch can still be created from the message.
+fn validate_highlevel(message: &PhotonMessage) -> anyhow::Result<()> {
+    match message {
+        PhotonMessage::EventData(event) => match event.code {
+            event_code::GAME_LIST | event_code::GAME_LIST_UPDATE => {
+                let game_list = RoomInfoList::from_map(&mut event.parameters.clone())?;
+                for (_, v) in game_list.games {
+                    if let PhotonDataType::Hashtable(mut props) = v {
+                        RoomInfo::from_map(&mut props)?;
+                    }
+                }
+            }
+            _ => (),
+        },
+        PhotonMessage::OperationRequest(request) => match request.operation_code {
+            operation_code::SET_PROPERTIES => {
+                let mut req =
+                    SetPropertiesOperationRequest::from_map(&mut request.parameters.clone())?;
+                if req.actor_nr.is_some() {
+                    Player::from_map(&mut req.properties)?;
+                }
+            }
+            operation_code::JOIN_GAME => {
+                JoinGameRequest::from_map(&mut request.parameters.clone())?;
+            }
+            operation_code::RAISE_EVENT => {
+                RaiseEvent::from_map(&mut request.parameters.clone())?;
+            }
+            _ => (),
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// Describes which parameters were added, removed or changed.
+fn parameter_diff(original: &ParameterMap, rewritten: &ParameterMap) -> Vec<String> {
+    let mut diff = vec![];
+
+    for (key, original_value) in original {
+        match rewritten.get(key) {
+            None => diff.push(format!("- [{key}] {original_value:?}")),
+            Some(value) if value != original_value => {
+                diff.push(format!("~ [{key}] {original_value:?} => {value:?}"))
+            }
+            _ => (),
+        }
+    }
+    for (key, value) in rewritten {
+        if !original.contains_key(key) {
+            diff.push(format!("+ [{key}] {value:?}"));
+        }
+    }
+
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::{
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{OperationRequest, PhotonMessage},
+    };
+
+    use super::validate_rewrite;
+
+    fn to_bytes(message: &PhotonMessage) -> Vec<u8> {
+        let mut buf = vec![];
+        message.to_websocket_bytes(&mut buf).unwrap();
+        buf
+    }
+
+    fn auth_request(parameters: photon_lib::ParameterMap) -> PhotonMessage {
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: 230,
+            parameters,
+        })
+    }
+
+    #[test]
+    fn accepts_changed_value() {
+        let original = auth_request(indexmap! { 210 => PhotonDataType::String("eu".into()) });
+        let rewritten = auth_request(indexmap! { 210 => PhotonDataType::String("us".into()) });
+
+        let result = validate_rewrite(&to_bytes(&original), &to_bytes(&rewritten), &rewritten);
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn rejects_changed_parameter_count() {
+        let original = auth_request(indexmap! { 210 => PhotonDataType::String("eu".into()) });
+        let rewritten = auth_request(indexmap! {
+            210 => PhotonDataType::String("eu".into()),
+            225 => PhotonDataType::String("user".into()),
+        });
+
+        let problems =
+            validate_rewrite(&to_bytes(&original), &to_bytes(&rewritten), &rewritten).unwrap_err();
+        assert_eq!(
+            problems,
+            vec![
+                "parameter count changed from 1 to 2".to_string(),
+                r#"+ [225] String("user")"#.to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unparsable_bytes() {
+        let original = auth_request(indexmap! {});
+        let problems =
+            validate_rewrite(&to_bytes(&original), &[0xF3, 0x02], &original).unwrap_err();
+        assert_eq!(problems.len(), 1);
+    }
+}
@@ -0,0 +1,360 @@
+//! Players to look out for, such as friends or known cheaters, persisted across sessions.
+//!
+//! Players are checked against the watchlist whenever they show up in a room we're in, and whenever a lobby room
+//! lists its host. A hit raises a [HaxEvent::WatchedPlayerSeen](super::events::HaxEvent::WatchedPlayerSeen). The same
+//! player is only reported again once the [cooldown](Watchlist::cooldown) has passed, as they keep showing up in
+//! every game list update while hosting.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::config::versioned::{self, Migration, VersionError, Versioned};
+
+/// How long to wait before reporting the same player again, unless the watchlist sets another cooldown.
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(10 * 60);
+
+/// Who an entry matches. Matching ignores case.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchTarget {
+    UserId(String),
+    /// A nickname pattern, where `*` matches any text and `?` matches a single character.
+    Nickname(String),
+}
+
+impl WatchTarget {
+    pub fn matches(&self, user_id: Option<&str>, nickname: Option<&str>) -> bool {
+        match (self, user_id, nickname) {
+            (WatchTarget::UserId(id), Some(user_id), _) => id.eq_ignore_ascii_case(user_id),
+            (WatchTarget::Nickname(pattern), _, Some(nickname)) if !nickname.is_empty() => {
+                glob_match(&pattern.to_lowercase(), &nickname.to_lowercase())
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertLevel {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchEntry {
+    pub target: WatchTarget,
+    #[serde(default)]
+    pub note: String,
+    #[serde(default)]
+    pub level: AlertLevel,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct WatchlistFile {
+    entries: Vec<WatchEntry>,
+    cooldown_secs: u64,
+}
+
+impl Default for WatchlistFile {
+    fn default() -> Self {
+        Self {
+            entries: vec![],
+            cooldown_secs: DEFAULT_COOLDOWN.as_secs(),
+        }
+    }
+}
+
+impl Versioned for WatchlistFile {
+    const NAME: &'static str = "watchlist";
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Watchlist {
+    /// Where the watchlist is saved. If not set, it is only kept in memory.
+    path: Option<PathBuf>,
+    data: WatchlistFile,
+    /// When each player was last reported, by lowercase user id or nickname.
+    last_alerts: HashMap<String, Instant>,
+}
+
+impl Watchlist {
+    /// Loads the watchlist from the given file.
+    ///
+    /// This never fails, see [RoomNoteStore::load](super::room_notes::RoomNoteStore::load).
+    pub fn load(path: &Path) -> Self {
+        let data = match versioned::read_optional(path) {
+            Ok(Some(data)) => data,
+            Ok(None) => WatchlistFile::default(),
+            Err(e) if matches!(e.downcast_ref(), Some(VersionError::NewerVersion { .. })) => {
+                warn!(
+                    path = format!("{path:?}"),
+                    "Could not load watchlist, changes won't be saved: {e:#}"
+                );
+                return Self::default();
+            }
+            Err(e) => {
+                warn!(
+                    path = format!("{path:?}"),
+                    "Could not read watchlist, starting with an empty one: {e:#}"
+                );
+                WatchlistFile::default()
+            }
+        };
+
+        Self {
+            path: Some(path.to_owned()),
+            data,
+            last_alerts: HashMap::new(),
+        }
+    }
+
+    /// Where the watchlist is saved, if anywhere.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub(super) fn save(&mut self) -> anyhow::Result<()> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        versioned::write(path, &self.data)?;
+        debug!("Saved watchlist");
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[WatchEntry] {
+        &self.data.entries
+    }
+
+    /// Adds an entry and saves the watchlist.
+    pub fn add(&mut self, entry: WatchEntry) -> anyhow::Result<()> {
+        self.data.entries.push(entry);
+        self.save()
+    }
+
+    /// Removes the entry at the given index and saves the watchlist. Returns the removed entry.
+    pub fn remove(&mut self, index: usize) -> anyhow::Result<Option<WatchEntry>> {
+        if index >= self.data.entries.len() {
+            return Ok(None);
+        }
+        let removed = self.data.entries.remove(index);
+        self.save()?;
+        Ok(Some(removed))
+    }
+
+    /// Replaces all entries and the cooldown, and saves the watchlist.
+    pub fn replace(&mut self, entries: Vec<WatchEntry>, cooldown: Duration) -> anyhow::Result<()> {
+        self.data.entries = entries;
+        self.data.cooldown_secs = cooldown.as_secs();
+        self.save()
+    }
+
+    /// How long to wait before reporting the same player again.
+    pub fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.data.cooldown_secs)
+    }
+
+    pub fn set_cooldown(&mut self, cooldown: Duration) -> anyhow::Result<()> {
+        self.data.cooldown_secs = cooldown.as_secs();
+        self.save()
+    }
+
+    /// Returns the first entry matching the player, unless they were already reported within the cooldown.
+    pub fn check(
+        &mut self,
+        user_id: Option<&str>,
+        nickname: Option<&str>,
+        now: Instant,
+    ) -> Option<&WatchEntry> {
+        let entry = self
+            .data
+            .entries
+            .iter()
+            .find(|e| e.target.matches(user_id, nickname))?;
+
+        let cooldown = Duration::from_secs(self.data.cooldown_secs);
+        self.last_alerts
+            .retain(|_, alerted| now.saturating_duration_since(*alerted) < cooldown);
+        let player = user_id
+            .or(nickname)
+            .map(str::to_lowercase)
+            .unwrap_or_default();
+        if self.last_alerts.contains_key(&player) {
+            return None;
+        }
+        self.last_alerts.insert(player, now);
+        Some(entry)
+    }
+}
+
+/// Matches text against a pattern where `*` matches any text and `?` matches a single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // where the last `*` was, and the text position it's currently matched up to
+    let mut star = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    // let the `*` match one more character
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use photon_lib::{
+        highlevel::constants::{actor_properties, operation_code, parameter_code},
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{OperationRequest, OperationResponse, PhotonMessage},
+    };
+
+    use super::{glob_match, AlertLevel, WatchEntry, WatchTarget, Watchlist};
+    use crate::{
+        hax::{events::HaxEvent, timeline::Replay},
+        proxy::{Direction, WebSocketServer},
+        testsupport::captured,
+    };
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("xx*sniper*", "xx_pro_sniper_99"));
+        assert!(glob_match("b?b", "bob"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("b?b", "boob"));
+        assert!(!glob_match("*sniper", "sniper99"));
+    }
+
+    #[test]
+    fn alerts_once_per_cooldown() {
+        let mut watchlist = Watchlist::default();
+        watchlist
+            .add(WatchEntry {
+                target: WatchTarget::Nickname("*Hacker*".into()),
+                note: "aimbot".into(),
+                level: AlertLevel::Critical,
+            })
+            .unwrap();
+        watchlist
+            .add(WatchEntry {
+                target: WatchTarget::UserId("8A2B4F".into()),
+                note: "friend".into(),
+                level: AlertLevel::Info,
+            })
+            .unwrap();
+        let now = Instant::now();
+
+        let entry = watchlist.check(None, Some("xXhackerXx"), now).unwrap();
+        assert_eq!(entry.level, AlertLevel::Critical);
+        assert!(watchlist
+            .check(None, Some("XXHACKERXX"), now + Duration::from_secs(60))
+            .is_none());
+        // a different player matching the same entry is still reported
+        assert!(watchlist.check(None, Some("hacker2"), now).is_some());
+        assert_eq!(
+            watchlist.check(Some("8a2b4f"), Some(""), now).unwrap().note,
+            "friend"
+        );
+        assert!(watchlist
+            .check(Some("other"), Some("friend"), now)
+            .is_none());
+
+        let later = now + watchlist.cooldown();
+        assert!(watchlist.check(None, Some("xXhackerXx"), later).is_some());
+    }
+
+    #[test]
+    fn alerts_on_join() {
+        let mut replay = Replay::default();
+        replay
+            .state()
+            .add_watch_entry(WatchEntry {
+                target: WatchTarget::Nickname("*hacker*".into()),
+                note: "aimbot".into(),
+                level: AlertLevel::Warning,
+            })
+            .unwrap();
+        let mut events = replay.state().events.subscribe();
+
+        replay.feed(&captured(
+            Direction::ClientToServer,
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: operation_code::AUTHENTICATE,
+                parameters: indexmap! {},
+            }),
+        ));
+        let player = |name: &str| {
+            PhotonDataType::Hashtable(indexmap! {
+                PhotonDataType::Byte(actor_properties::PLAYER_NAME) => PhotonDataType::String(name.into()),
+            })
+        };
+        replay.feed(&captured(
+            Direction::ServerToClient,
+            PhotonMessage::OperationResponse(OperationResponse {
+                operation_code: operation_code::JOIN_GAME,
+                return_code: 0,
+                debug_message: None,
+                parameters: indexmap! {
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(1),
+                    parameter_code::ROOM_NAME => PhotonDataType::String("pro lobby".into()),
+                    parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Integer(1) => player("me"),
+                        PhotonDataType::Integer(2) => player("xXHackerXx"),
+                    }),
+                    parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable(indexmap! {}),
+                },
+            }),
+        ));
+
+        assert!(replay.state().stats.recent_errors.is_empty());
+        match events.try_recv() {
+            Ok(HaxEvent::WatchedPlayerSeen {
+                entry,
+                nickname,
+                room,
+                server,
+                ..
+            }) => {
+                assert_eq!(entry.note, "aimbot");
+                assert_eq!(nickname.as_deref(), Some("xXHackerXx"));
+                assert_eq!(room.as_deref(), Some("pro lobby"));
+                assert_eq!(server, WebSocketServer::GameServer);
+            }
+            other => panic!("expected a watchlist alert, got {other:?}"),
+        }
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! The game's build files, hashed while they stream through the web request proxy.
+//!
+//! The WebGL build is tens of megabytes, so the proxy doesn't buffer it: the asset hook is a
+//! [streaming](super::web_requests::BodyMode::Streaming) one, fed a chunk at a time. Each asset is hashed as it passes
+//! and kept in the [AssetLog] by its url, so a build that changed under the same url, ie. a game update the version
+//! doesn't tell about, shows up as a new digest.
+
+use std::time::SystemTime;
+
+use photon_lib::indexmap::IndexMap;
+use sha2::{Digest, Sha256};
+
+use super::web_requests::SessionContext;
+
+/// How many assets the log keeps.
+const MAX_ENTRIES: usize = 64;
+/// The extensions of the files of a Unity WebGL build, after compression extensions are taken off.
+const ASSET_EXTENSIONS: [&str; 4] = ["data", "wasm", "unityweb", "bundle"];
+
+/// Whether a url points to a file of the game's build.
+pub fn is_asset(url: &hyper::Uri) -> bool {
+    let path = url.path().to_ascii_lowercase();
+    let path = path
+        .strip_suffix(".br")
+        .or_else(|| path.strip_suffix(".gz"))
+        .unwrap_or(&path);
+    match path.rsplit_once('.') {
+        Some((_, extension)) => ASSET_EXTENSIONS.contains(&extension),
+        None => false,
+    }
+}
+
+/// Hashes an asset a chunk at a time.
+#[derive(Debug, Clone, Default)]
+pub struct AssetHasher {
+    hasher: Sha256,
+    size: u64,
+}
+
+impl AssetHasher {
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.hasher.update(chunk);
+        self.size += chunk.len() as u64;
+    }
+
+    pub fn finish(self, session: &SessionContext, url: &hyper::Uri) -> AssetDigest {
+        AssetDigest {
+            session_id: session.session_id.clone(),
+            timestamp: session.timestamp,
+            url: format!("{}{}", url.host().unwrap_or_default(), url.path()),
+            size: self.size,
+            sha256: format!("{:x}", self.hasher.finalize()),
+        }
+    }
+}
+
+/// An asset as it was downloaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssetDigest {
+    pub session_id: String,
+    pub timestamp: SystemTime,
+    /// The host and path, the query is left out like for [web exchanges](super::web_requests::WebExchange::url).
+    pub url: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// The last digest of each asset, least recently downloaded first.
+#[derive(Debug, Clone, Default)]
+pub struct AssetLog {
+    entries: IndexMap<String, AssetDigest>,
+}
+
+impl AssetLog {
+    /// Keeps the digest of a download. Returns the previous digest of the asset if its contents changed.
+    pub fn record(&mut self, digest: AssetDigest) -> Option<AssetDigest> {
+        let previous = self.entries.shift_remove(&digest.url);
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries.shift_remove_index(0);
+        }
+        self.entries.insert(digest.url.clone(), digest.clone());
+        previous.filter(|previous| previous.sha256 != digest.sha256)
+    }
+
+    pub fn get(&self, url: &str) -> Option<&AssetDigest> {
+        self.entries.get(url)
+    }
+
+    /// The digests, least recently downloaded first.
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &AssetDigest> + ExactSizeIterator {
+        self.entries.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_asset, AssetHasher, AssetLog};
+    use crate::hax::web_requests::SessionContext;
+
+    #[test]
+    fn recognizes_build_files() {
+        let asset = |url: &str| is_asset(&url.parse().unwrap());
+        assert!(asset(
+            "https://games.example.com/bulletforce/Build/WebGL.data.br"
+        ));
+        assert!(asset(
+            "https://games.example.com/bulletforce/Build/WebGL.wasm"
+        ));
+        assert!(asset("https://example.com/Build/game.unityweb?v=3"));
+        assert!(!asset("https://example.com/Build/WebGL.loader.js"));
+        assert!(!asset(
+            "https://example.com/OnlineAccountSystem/get_loadout.php"
+        ));
+        assert!(!asset("https://example.com/br"));
+    }
+
+    #[test]
+    fn notices_changed_assets() {
+        let session = SessionContext::now("session");
+        let url = "https://example.com/Build/WebGL.data".parse().unwrap();
+        let digest = |chunks: &[&[u8]]| {
+            let mut hasher = AssetHasher::default();
+            for chunk in chunks {
+                hasher.update(chunk);
+            }
+            hasher.finish(&session, &url)
+        };
+
+        let first = digest(&[b"hello ", b"world"]);
+        assert_eq!(first.size, 11);
+        assert_eq!(
+            first.sha256,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+        // chunked differently, it's still the same file
+        assert_eq!(digest(&[b"hello world"]).sha256, first.sha256);
+
+        let mut log = AssetLog::default();
+        assert_eq!(log.record(first.clone()), None);
+        assert_eq!(log.record(first.clone()), None);
+        assert_eq!(log.record(digest(&[b"hello there"])), Some(first));
+        assert_eq!(log.entries().len(), 1);
+        assert_eq!(log.get("example.com/Build/WebGL.data").unwrap().size, 11);
+    }
+}
@@ -0,0 +1,341 @@
+//! The game's web requests that matter next to its Photon traffic, eg. loadout changes and shop purchases.
+//!
+//! The web request proxy hands every request and response to the hooks together with a [SessionContext], the same
+//! session id the [join_trace](super::join_trace) spans carry. Requests to a known [WebEndpoint] are kept in a
+//! [WebRequestLog] and emitted as [HaxEvent::WebRequest](super::events::HaxEvent::WebRequest), so they can be put on
+//! one [session_timeline](super::session_timeline) with the websocket messages.
+//!
+//! Payloads are only summarized. Fields that look like credentials are redacted, as the summaries end up in exports.
+//!
+//! Not every hook needs a whole body, and buffering one holds back its first byte until the last one arrived, which
+//! for the game's build is seconds. Each hook in [WEB_HOOKS] declares which bodies it looks at and in which
+//! [BodyMode], and the proxy only buffers a body if a hook that matches it needs it whole. Streaming hooks see the body
+//! a chunk at a time through a [StreamedBody] while it's forwarded.
+
+use std::{collections::VecDeque, fmt::Display, time::SystemTime};
+
+use bytes::Bytes;
+use serde::Serialize;
+
+use super::web_assets::{self, AssetHasher};
+
+/// How many exchanges the log keeps.
+const MAX_ENTRIES: usize = 256;
+/// Longer field values are cut off in summaries.
+const MAX_VALUE_LENGTH: usize = 32;
+/// Fields whose values are left out of summaries, matched as substrings of the lowercased field name.
+const REDACTED_FIELDS: [&str; 5] = ["password", "token", "ticket", "session", "hash"];
+
+/// The session a web request belongs to, and when the proxy saw it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionContext {
+    pub session_id: String,
+    pub timestamp: SystemTime,
+}
+
+impl SessionContext {
+    pub fn now(session_id: &str) -> Self {
+        Self {
+            session_id: session_id.into(),
+            timestamp: SystemTime::now(),
+        }
+    }
+}
+
+/// The kinds of web requests that are tracked, recognized by their path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebEndpoint {
+    Account,
+    Loadout,
+    Shop,
+}
+
+/// Path fragments of the tracked endpoints, the first one found in the lowercased path wins.
+const ENDPOINTS: [(&str, WebEndpoint); 8] = [
+    ("loadout", WebEndpoint::Loadout),
+    ("equip", WebEndpoint::Loadout),
+    ("purchase", WebEndpoint::Shop),
+    ("buy", WebEndpoint::Shop),
+    ("shop", WebEndpoint::Shop),
+    ("account", WebEndpoint::Account),
+    ("login", WebEndpoint::Account),
+    ("profile", WebEndpoint::Account),
+];
+
+impl WebEndpoint {
+    /// The endpoint a request goes to, if it is tracked.
+    pub fn matching(url: &hyper::Uri) -> Option<Self> {
+        let path = url.path().to_ascii_lowercase();
+        ENDPOINTS
+            .iter()
+            .find(|(fragment, _)| path.contains(fragment))
+            .map(|(_, endpoint)| *endpoint)
+    }
+}
+
+impl Display for WebEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebEndpoint::Account => write!(f, "account"),
+            WebEndpoint::Loadout => write!(f, "loadout"),
+            WebEndpoint::Shop => write!(f, "shop"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebPhase {
+    Request,
+    Response,
+}
+
+/// How the proxy hands a body to the hooks, ordered by how much of it has to be held back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BodyMode {
+    /// No hook looks at the body, it's forwarded as it arrives.
+    Forward,
+    /// Hooks look at the body a chunk at a time while it's forwarded.
+    Streaming,
+    /// A hook needs the whole body, eg. to rewrite it, so it's forwarded once it arrived completely.
+    Buffered,
+}
+
+/// A web request hook and the bodies it looks at.
+#[derive(Debug, Clone, Copy)]
+pub struct WebHook {
+    pub name: &'static str,
+    pub mode: BodyMode,
+    pub matches: fn(&hyper::Uri, WebPhase) -> bool,
+}
+
+/// The web request hooks, see [web_requests](self).
+pub const WEB_HOOKS: [WebHook; 2] = [
+    WebHook {
+        name: "web exchanges",
+        mode: BodyMode::Buffered,
+        matches: |url, _| WebEndpoint::matching(url).is_some(),
+    },
+    WebHook {
+        name: "asset digests",
+        mode: BodyMode::Streaming,
+        matches: |url, phase| phase == WebPhase::Response && web_assets::is_asset(url),
+    },
+];
+
+/// The mode the proxy handles a body in, the one of the matching hooks that holds back the most.
+pub fn body_mode(url: &hyper::Uri, phase: WebPhase) -> BodyMode {
+    WEB_HOOKS
+        .iter()
+        .filter(|hook| (hook.matches)(url, phase))
+        .map(|hook| hook.mode)
+        .max()
+        .unwrap_or(BodyMode::Forward)
+}
+
+/// A body the streaming hooks look at, from its first chunk to its end.
+///
+/// The chunks are seen without locking [HaxState](super::HaxState), so a large download doesn't hold up the websocket
+/// hooks. What the hooks found is handed to the state once the body ended, see
+/// [HaxState::webrequest_hook_onstreamed](super::HaxState::webrequest_hook_onstreamed).
+#[derive(Debug, Clone)]
+pub struct StreamedBody {
+    pub session: SessionContext,
+    pub url: hyper::Uri,
+    pub phase: WebPhase,
+    /// How many bytes went through so far.
+    pub size: u64,
+    pub(super) asset: Option<AssetHasher>,
+}
+
+impl StreamedBody {
+    pub fn new(session: SessionContext, url: hyper::Uri, phase: WebPhase) -> Self {
+        let asset =
+            (phase == WebPhase::Response && web_assets::is_asset(&url)).then(AssetHasher::default);
+        Self {
+            session,
+            url,
+            phase,
+            size: 0,
+            asset,
+        }
+    }
+
+    /// Runs the streaming hooks on the next chunk of the body, before it's forwarded.
+    pub fn chunk(&mut self, chunk: &mut Bytes) {
+        self.size += chunk.len() as u64;
+        if let Some(asset) = &mut self.asset {
+            asset.update(chunk);
+        }
+    }
+}
+
+/// A request to or response from a tracked endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebExchange {
+    pub session_id: String,
+    pub timestamp: SystemTime,
+    pub endpoint: WebEndpoint,
+    pub phase: WebPhase,
+    /// The host and path, the query is left out as it may hold credentials.
+    pub url: String,
+    /// What the body held, see [summarize].
+    pub summary: String,
+}
+
+impl WebExchange {
+    pub fn new(
+        session: &SessionContext,
+        endpoint: WebEndpoint,
+        phase: WebPhase,
+        url: &hyper::Uri,
+        body: &[u8],
+    ) -> Self {
+        Self {
+            session_id: session.session_id.clone(),
+            timestamp: session.timestamp,
+            endpoint,
+            phase,
+            url: format!("{}{}", url.host().unwrap_or_default(), url.path()),
+            summary: summarize(body),
+        }
+    }
+}
+
+impl Display for WebExchange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let arrow = match self.phase {
+            WebPhase::Request => "->",
+            WebPhase::Response => "<-",
+        };
+        write!(
+            f,
+            "{} {arrow} {}: {}",
+            self.endpoint, self.url, self.summary
+        )
+    }
+}
+
+/// Describes a body in one line: the fields of a form or a json object, or its size if it's neither.
+pub fn summarize(body: &[u8]) -> String {
+    let text = match std::str::from_utf8(body) {
+        Ok(text) if !text.trim().is_empty() => text.trim(),
+        Ok(_) => return "empty".into(),
+        Err(_) => return format!("{} bytes", body.len()),
+    };
+
+    if let Ok(serde_json::Value::Object(object)) = serde_json::from_str(text) {
+        let fields = object
+            .iter()
+            .map(|(key, value)| match value {
+                serde_json::Value::String(s) => field(key, s),
+                other => field(key, &other.to_string()),
+            })
+            .collect::<Vec<_>>();
+        return format!("{{{}}}", fields.join(", "));
+    }
+    let is_form = text
+        .split('&')
+        .all(|pair| pair.split_once('=').is_some_and(|(key, _)| !key.is_empty()));
+    if is_form {
+        let fields = text
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| field(key, value))
+            .collect::<Vec<_>>();
+        return fields.join(", ");
+    }
+    format!("{} bytes", body.len())
+}
+
+fn field(key: &str, value: &str) -> String {
+    let lowercase = key.to_ascii_lowercase();
+    if REDACTED_FIELDS.iter().any(|r| lowercase.contains(r)) {
+        return format!("{key}=<redacted>");
+    }
+    match value.char_indices().nth(MAX_VALUE_LENGTH) {
+        Some((end, _)) => format!("{key}={}...", &value[..end]),
+        None => format!("{key}={value}"),
+    }
+}
+
+/// The most recent exchanges with tracked endpoints.
+#[derive(Debug, Default)]
+pub struct WebRequestLog {
+    entries: VecDeque<WebExchange>,
+}
+
+impl WebRequestLog {
+    pub fn record(&mut self, exchange: WebExchange) {
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(exchange);
+    }
+
+    /// The exchanges, oldest first.
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &WebExchange> + ExactSizeIterator {
+        self.entries.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{body_mode, summarize, BodyMode, WebEndpoint, WebPhase};
+
+    #[test]
+    fn endpoints_are_matched_by_path() {
+        let endpoint = |url: &str| WebEndpoint::matching(&url.parse().unwrap());
+        assert_eq!(
+            endpoint("https://server.blayzegames.com/OnlineAccountSystem/update_loadout.php"),
+            Some(WebEndpoint::Loadout)
+        );
+        assert_eq!(
+            endpoint("https://example.com/shop/BuyItem?account=1"),
+            Some(WebEndpoint::Shop)
+        );
+        assert_eq!(
+            endpoint("https://example.com/Login"),
+            Some(WebEndpoint::Account)
+        );
+        assert_eq!(endpoint("https://example.com/?loadout=1"), None);
+    }
+
+    #[test]
+    fn bodies_are_only_buffered_for_buffered_hooks() {
+        let mode = |url: &str, phase| body_mode(&url.parse().unwrap(), phase);
+        let loadout = "https://server.blayzegames.com/OnlineAccountSystem/update_loadout.php";
+        assert_eq!(mode(loadout, WebPhase::Request), BodyMode::Buffered);
+        assert_eq!(mode(loadout, WebPhase::Response), BodyMode::Buffered);
+        let build = "https://example.com/Build/WebGL.data.br";
+        assert_eq!(mode(build, WebPhase::Response), BodyMode::Streaming);
+        assert_eq!(mode(build, WebPhase::Request), BodyMode::Forward);
+        // a buffered hook wins over a streaming one
+        let bought = "https://example.com/shop/skins.bundle";
+        assert_eq!(mode(bought, WebPhase::Response), BodyMode::Buffered);
+        assert_eq!(
+            mode("https://example.com/index.html", WebPhase::Response),
+            BodyMode::Forward
+        );
+    }
+
+    #[test]
+    fn payloads_are_summarized() {
+        assert_eq!(
+            summarize(b"item=AK47&price=500&sessionToken=abc"),
+            "item=AK47, price=500, sessionToken=<redacted>"
+        );
+        assert_eq!(
+            summarize(br#"{"ok":true,"balance":1200,"password":"hunter2"}"#),
+            "{balance=1200, ok=true, password=<redacted>}"
+        );
+        assert_eq!(
+            summarize(format!("note={}", "x".repeat(40)).as_bytes()),
+            format!("note={}...", "x".repeat(32))
+        );
+        assert_eq!(summarize(b"OK"), "2 bytes");
+        assert_eq!(summarize(b""), "empty");
+        assert_eq!(summarize(&[0xff, 0xfe]), "2 bytes");
+    }
+}
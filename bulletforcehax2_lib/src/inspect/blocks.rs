@@ -0,0 +1,362 @@
+//! The blocks of compressed capture files, see [CaptureFormat::Compressed](super::capture::CaptureFormat).
+//!
+//! After the header, a compressed file holds blocks. Each starts with [BLOCK_TAG] and the block's [BlockInfo],
+//! followed by its records compressed with zstd. The records are the same as in plain files, except that in files
+//! with [deltas](super::delta) a record can be a diff against an earlier record of the same block. Every block starts
+//! the streams over, so it can be read on its own.
+//!
+//! When the file is finished, the index follows the last block: [INDEX_TAG], the offset and info of every block, and
+//! a trailer with the number of blocks, the offset of the index and [INDEX_MAGIC], so readers can find the index from
+//! the end of the file. A file whose writer never finished it, eg. because the proxy crashed, has no index. Its blocks
+//! are found by reading them one after another instead.
+
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+
+use super::{
+    capture::{invalid_data, read_raw_record, to_millis, write_delta_record, write_record},
+    delta::{self, DeltaEncoder, Encoded},
+    message_code, CapturedMessage,
+};
+
+pub const BLOCK_TAG: u8 = b'B';
+pub const INDEX_TAG: u8 = b'I';
+pub const INDEX_MAGIC: &[u8; 4] = b"BFHI";
+
+/// Blocks are compressed once their records take up this many bytes.
+pub const DEFAULT_BLOCK_SIZE: usize = 256 * 1024;
+
+const COMPRESSION_LEVEL: i32 = 3;
+/// The length of a [BlockInfo] in the file.
+const INFO_LEN: u64 = 4 + 4 + 8 + 8 + 8;
+/// The length of the trailer at the end of a finished file.
+const TRAILER_LEN: u64 = 4 + 8 + 4;
+
+/// A bloom filter over the [message codes](message_code) in a block. It can tell for sure that a block has no message
+/// with a code, but not that it has one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CodeBloom(u64);
+
+impl CodeBloom {
+    pub fn insert(&mut self, code: u8) {
+        for bit in Self::bits(code) {
+            self.0 |= 1 << bit;
+        }
+    }
+
+    pub fn may_contain(&self, code: u8) -> bool {
+        Self::bits(code).iter().all(|bit| self.0 & (1 << bit) != 0)
+    }
+
+    /// Two bits out of 64 for each code, from two multiplicative hashes.
+    fn bits(code: u8) -> [u32; 2] {
+        let code = code as u32;
+        [
+            code.wrapping_mul(0x9E37_79B1) >> 26,
+            (code ^ 0xA5).wrapping_mul(0x85EB_CA6B) >> 26,
+        ]
+    }
+}
+
+/// What a block of a compressed file holds, so blocks can be skipped without decompressing them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockInfo {
+    /// Where the block starts in the file, at its tag.
+    pub offset: u64,
+    pub compressed_len: u32,
+    pub messages: u32,
+    /// The earliest and latest timestamps in the block, in milliseconds since the unix epoch.
+    pub min_millis: u64,
+    pub max_millis: u64,
+    pub codes: CodeBloom,
+}
+
+impl BlockInfo {
+    /// Whether the block has messages at or after the given time.
+    pub fn reaches(&self, millis: u64) -> bool {
+        self.max_millis >= millis
+    }
+
+    fn observe(&mut self, message: &CapturedMessage) {
+        let millis = to_millis(message.timestamp);
+        if self.messages == 0 {
+            self.min_millis = millis;
+            self.max_millis = millis;
+        } else {
+            self.min_millis = self.min_millis.min(millis);
+            self.max_millis = self.max_millis.max(millis);
+        }
+        self.messages += 1;
+        if let Some(code) = message.parse().as_ref().and_then(message_code) {
+            self.codes.insert(code);
+        }
+    }
+
+    fn write(&self, mut writer: impl Write) -> std::io::Result<()> {
+        writer.write_all(&self.compressed_len.to_le_bytes())?;
+        writer.write_all(&self.messages.to_le_bytes())?;
+        writer.write_all(&self.min_millis.to_le_bytes())?;
+        writer.write_all(&self.max_millis.to_le_bytes())?;
+        writer.write_all(&self.codes.0.to_le_bytes())
+    }
+
+    fn read(mut reader: impl Read, offset: u64) -> std::io::Result<Self> {
+        let mut buf = [0u8; INFO_LEN as usize];
+        reader.read_exact(&mut buf)?;
+        Ok(Self {
+            offset,
+            compressed_len: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+            messages: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            min_millis: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+            max_millis: u64::from_le_bytes(buf[16..24].try_into().unwrap()),
+            codes: CodeBloom(u64::from_le_bytes(buf[24..32].try_into().unwrap())),
+        })
+    }
+}
+
+/// Writes messages as compressed blocks, after the header was written.
+pub struct BlockWriter<W: Write> {
+    writer: W,
+    block_size: usize,
+    /// The records of the block that isn't written yet.
+    pending: Vec<u8>,
+    pending_info: BlockInfo,
+    blocks: Vec<BlockInfo>,
+    /// Where the next block starts in the file.
+    position: u64,
+    /// Decides which messages are stored as diffs, if any are.
+    deltas: Option<DeltaEncoder>,
+}
+
+impl<W: Write> BlockWriter<W> {
+    /// Starts writing blocks to a writer that is at the given position in the file.
+    pub fn new(writer: W, position: u64, block_size: usize) -> Self {
+        Self {
+            writer,
+            block_size,
+            pending: Vec::with_capacity(block_size),
+            pending_info: BlockInfo::default(),
+            blocks: vec![],
+            position,
+            deltas: None,
+        }
+    }
+
+    /// Stores serialize messages as diffs, with a keyframe every `keyframe_interval` messages of a view.
+    pub fn with_deltas(mut self, keyframe_interval: usize) -> Self {
+        self.deltas = Some(DeltaEncoder::new(keyframe_interval));
+        self
+    }
+
+    pub fn push(&mut self, message: &CapturedMessage) -> std::io::Result<()> {
+        // diffs refer to their keyframe by its index in the block
+        let index = self.pending_info.messages;
+        let encoded = self
+            .deltas
+            .as_mut()
+            .map(|deltas| deltas.encode(message, index as u64));
+        match encoded {
+            Some(Encoded::Delta { base, diff }) => {
+                write_delta_record(&mut self.pending, message, base as u32, &diff)?
+            }
+            _ => write_record(&mut self.pending, message)?,
+        };
+        self.pending_info.observe(message);
+        if self.pending.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// How big the file is so far, counting the messages that aren't compressed yet at their full size.
+    pub fn written(&self) -> u64 {
+        self.position + self.pending.len() as u64
+    }
+
+    /// Compresses and writes the messages pushed since the last block, so they can be read even if the file is never
+    /// finished. Blocks are best left to fill up, as small blocks compress worse.
+    pub fn finish_block(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let compressed = zstd::bulk::compress(&self.pending, COMPRESSION_LEVEL)?;
+        let mut info = std::mem::take(&mut self.pending_info);
+        info.offset = self.position;
+        info.compressed_len =
+            u32::try_from(compressed.len()).map_err(|_| invalid_data("block is too large"))?;
+
+        self.writer.write_all(&[BLOCK_TAG])?;
+        info.write(&mut self.writer)?;
+        self.writer.write_all(&compressed)?;
+        self.position += 1 + INFO_LEN + compressed.len() as u64;
+        self.blocks.push(info);
+        self.pending.clear();
+        if let Some(deltas) = &mut self.deltas {
+            deltas.reset();
+        }
+        Ok(())
+    }
+
+    /// Writes the remaining messages and the index. Nothing may be pushed afterwards.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        self.finish_block()?;
+        let index_offset = self.position;
+        self.writer.write_all(&[INDEX_TAG])?;
+        for info in &self.blocks {
+            self.writer.write_all(&info.offset.to_le_bytes())?;
+            info.write(&mut self.writer)?;
+        }
+        let count =
+            u32::try_from(self.blocks.len()).map_err(|_| invalid_data("too many blocks"))?;
+        self.writer.write_all(&count.to_le_bytes())?;
+        self.writer.write_all(&index_offset.to_le_bytes())?;
+        self.writer.write_all(INDEX_MAGIC)?;
+        self.position += 1 + (8 + INFO_LEN) * self.blocks.len() as u64 + TRAILER_LEN;
+        self.writer.flush()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+}
+
+/// Finds the blocks of a compressed file whose header ends at `start`, from the index if the file has one.
+pub fn find_blocks(mut reader: impl Read + Seek, start: u64) -> std::io::Result<Vec<BlockInfo>> {
+    match read_index(&mut reader, start)? {
+        Some(blocks) => Ok(blocks),
+        None => scan_blocks(reader, start),
+    }
+}
+
+fn read_index(mut reader: impl Read + Seek, start: u64) -> std::io::Result<Option<Vec<BlockInfo>>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    if len < start + 1 + TRAILER_LEN {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+    let mut trailer = [0u8; TRAILER_LEN as usize];
+    reader.read_exact(&mut trailer)?;
+    if &trailer[12..] != INDEX_MAGIC {
+        return Ok(None);
+    }
+    let count = u32::from_le_bytes(trailer[..4].try_into().unwrap()) as u64;
+    let index_offset = u64::from_le_bytes(trailer[4..12].try_into().unwrap());
+    if index_offset < start || index_offset + 1 + (8 + INFO_LEN) * count + TRAILER_LEN != len {
+        return Ok(None);
+    }
+
+    reader.seek(SeekFrom::Start(index_offset))?;
+    let mut tag = [0u8];
+    reader.read_exact(&mut tag)?;
+    if tag[0] != INDEX_TAG {
+        return Ok(None);
+    }
+    let mut blocks = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut offset = [0u8; 8];
+        reader.read_exact(&mut offset)?;
+        blocks.push(BlockInfo::read(&mut reader, u64::from_le_bytes(offset))?);
+    }
+    Ok(Some(blocks))
+}
+
+/// Finds the blocks by reading their infos one after another. A block cut off at the end of the file is left out.
+fn scan_blocks(mut reader: impl Read + Seek, start: u64) -> std::io::Result<Vec<BlockInfo>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let mut offset = reader.seek(SeekFrom::Start(start))?;
+    let mut blocks = vec![];
+    while offset + 1 + INFO_LEN <= len {
+        let mut tag = [0u8];
+        reader.read_exact(&mut tag)?;
+        match tag[0] {
+            BLOCK_TAG => (),
+            INDEX_TAG => break,
+            x => return Err(invalid_data(format!("unknown block tag {x}"))),
+        }
+        let info = BlockInfo::read(&mut reader, offset)?;
+        let next = offset + 1 + INFO_LEN + info.compressed_len as u64;
+        if next > len {
+            break;
+        }
+        blocks.push(info);
+        offset = reader.seek(SeekFrom::Start(next))?;
+    }
+    Ok(blocks)
+}
+
+/// Reads and decompresses the messages of a block.
+pub fn read_block(
+    mut reader: impl Read + Seek,
+    info: &BlockInfo,
+) -> std::io::Result<Vec<CapturedMessage>> {
+    reader.seek(SeekFrom::Start(info.offset + 1 + INFO_LEN))?;
+    let mut compressed = vec![0u8; info.compressed_len as usize];
+    reader.read_exact(&mut compressed)?;
+    decode_block(&compressed, info)
+}
+
+/// Reads the blocks that follow the header one after another, for readers that can't seek. Stops at the index or
+/// at the end of the file.
+pub fn read_blocks_in_order(
+    mut reader: impl Read,
+    messages: &mut Vec<CapturedMessage>,
+) -> std::io::Result<()> {
+    loop {
+        let mut tag = [0u8];
+        match reader.read_exact(&mut tag) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        match tag[0] {
+            BLOCK_TAG => (),
+            INDEX_TAG => return Ok(()),
+            x => return Err(invalid_data(format!("unknown block tag {x}"))),
+        }
+        let info = BlockInfo::read(&mut reader, 0)?;
+        let mut compressed = vec![0u8; info.compressed_len as usize];
+        reader.read_exact(&mut compressed)?;
+        messages.extend(decode_block(&compressed, &info)?);
+    }
+}
+
+fn decode_block(compressed: &[u8], info: &BlockInfo) -> std::io::Result<Vec<CapturedMessage>> {
+    let records = zstd::stream::decode_all(compressed)?;
+    let mut records = records.as_slice();
+    let mut messages = Vec::<CapturedMessage>::with_capacity(info.messages as usize);
+    while let Some((mut message, base)) = read_raw_record(&mut records)? {
+        if let Some(base) = base {
+            let keyframe = messages
+                .get(base as usize)
+                .ok_or_else(|| invalid_data(format!("diff against missing record {base}")))?;
+            message.raw = delta::patch(&keyframe.raw, &message.raw)?;
+        }
+        messages.push(message);
+    }
+    if messages.len() != info.messages as usize {
+        return Err(invalid_data(format!(
+            "block at {} has {} messages instead of {}",
+            info.offset,
+            messages.len(),
+            info.messages
+        )));
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CodeBloom;
+
+    #[test]
+    fn bloom_has_no_false_negatives() {
+        let mut bloom = CodeBloom::default();
+        for code in [200, 201, 253, 0] {
+            bloom.insert(code);
+        }
+        for code in [200, 201, 253, 0] {
+            assert!(bloom.may_contain(code));
+        }
+        let false_positives = (0..=255u8).filter(|c| bloom.may_contain(*c)).count() - 4;
+        assert!(false_positives < 8, "{false_positives} false positives");
+    }
+}
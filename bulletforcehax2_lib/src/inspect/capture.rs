@@ -0,0 +1,615 @@
+//! A file format to store captured messages in, so they can be replayed later.
+//!
+//! The file starts with [MAGIC] and a version byte. Every message is stored as a record:
+//! - the timestamp in milliseconds since the unix epoch, as a little-endian u64
+//! - the server type as a byte (0: name server, 1: lobby, 2: game server)
+//! - the direction as a byte (0: client to server, 1: server to client)
+//! - the length of the raw message as a little-endian u32, followed by the raw message
+//!
+//! In version 1 files, see [CaptureFormat::Plain], the records follow the header one after another. Version 2 files,
+//! see [CaptureFormat::Compressed], hold the records in compressed blocks with an index at the end. [CaptureReader]
+//! uses the index to jump to a point in time or to the messages with a code without decompressing the whole file.
+//! Version 3 files, see [CaptureFormat::Delta], are compressed files that store most serialize messages as diffs: the
+//! highest bit of the direction byte is set on those records, and their raw message is the index of the record within
+//! the block that the diff is against, as a little-endian u32, followed by the diff. [Capture::load] reads files of
+//! any version.
+//!
+//! Files can also be written while the proxy runs, see [sink](super::sink).
+
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Seek, Write},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    blocks::{self, BlockInfo, BlockWriter, DEFAULT_BLOCK_SIZE},
+    delta::DEFAULT_KEYFRAME_INTERVAL,
+    message_code, CapturedMessage, MessageBuffer,
+};
+use crate::proxy::{Direction, WebSocketServer};
+
+pub const MAGIC: &[u8; 4] = b"BFHC";
+
+/// Set in the direction byte of records that are stored as a diff.
+const DELTA_FLAG: u8 = 0x80;
+
+/// How the messages of a capture file are stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CaptureFormat {
+    /// Records one after another, version 1. Older versions of the tools only read this format.
+    Plain,
+    /// Records in zstd-compressed blocks with an index, version 2.
+    #[default]
+    Compressed,
+    /// Like [Self::Compressed], but serialize messages are stored as diffs against an earlier message of the same
+    /// view, version 3. These take up less space before compression and make blocks cover more time.
+    Delta,
+}
+
+impl CaptureFormat {
+    fn version(self) -> u8 {
+        match self {
+            CaptureFormat::Plain => 1,
+            CaptureFormat::Compressed => 2,
+            CaptureFormat::Delta => 3,
+        }
+    }
+
+    fn from_version(version: u8) -> std::io::Result<Self> {
+        match version {
+            1 => Ok(CaptureFormat::Plain),
+            2 => Ok(CaptureFormat::Compressed),
+            3 => Ok(CaptureFormat::Delta),
+            x => Err(invalid_data(format!("unsupported capture version {x}"))),
+        }
+    }
+}
+
+/// A list of captured messages, ordered by timestamp.
+#[derive(Debug, Clone, Default)]
+pub struct Capture {
+    pub messages: Vec<CapturedMessage>,
+}
+
+impl Capture {
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+
+    /// Saves the capture in the plain format, see [Self::save_as].
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        self.save_as(path, CaptureFormat::Plain)
+    }
+
+    pub fn save_as(&self, path: &Path, format: CaptureFormat) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_as(&mut writer, format)?;
+        writer.flush()
+    }
+
+    pub fn read_from(mut reader: impl Read) -> std::io::Result<Self> {
+        let mut messages = vec![];
+        match read_header(&mut reader)? {
+            CaptureFormat::Plain => {
+                while let Some(message) = read_record(&mut reader)? {
+                    messages.push(message);
+                }
+            }
+            CaptureFormat::Compressed | CaptureFormat::Delta => {
+                blocks::read_blocks_in_order(reader, &mut messages)?
+            }
+        }
+        Ok(Self { messages })
+    }
+
+    /// Writes the capture in the plain format, see [Self::write_as].
+    pub fn write_to(&self, writer: impl Write) -> std::io::Result<()> {
+        self.write_as(writer, CaptureFormat::Plain)
+    }
+
+    pub fn write_as(&self, mut writer: impl Write, format: CaptureFormat) -> std::io::Result<()> {
+        write_header(&mut writer, format)?;
+        match format {
+            CaptureFormat::Plain => {
+                for message in &self.messages {
+                    write_record(&mut writer, message)?;
+                }
+            }
+            CaptureFormat::Compressed | CaptureFormat::Delta => {
+                let mut blocks = block_writer(writer, format);
+                for message in &self.messages {
+                    blocks.push(message)?;
+                }
+                blocks.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The timestamp of the first message.
+    pub fn start_time(&self) -> Option<SystemTime> {
+        self.messages.first().map(|m| m.timestamp)
+    }
+
+    /// The time between the first and the last message.
+    pub fn duration(&self) -> Duration {
+        match (self.messages.first(), self.messages.last()) {
+            (Some(first), Some(last)) => last
+                .timestamp
+                .duration_since(first.timestamp)
+                .unwrap_or_default(),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+impl From<&MessageBuffer> for Capture {
+    fn from(buffer: &MessageBuffer) -> Self {
+        Self {
+            messages: buffer.iter().map(Cow::into_owned).collect(),
+        }
+    }
+}
+
+/// Reads the messages of a capture file as they're needed, instead of all at once like [Capture::load].
+///
+/// The reader is an iterator over the messages from its current position, which starts at the first message. Plain
+/// files are read into memory when opened, as they have no index to find messages by.
+pub struct CaptureReader<R> {
+    reader: R,
+    format: CaptureFormat,
+    source: Source,
+    /// The messages of the current block that weren't returned yet.
+    pending: VecDeque<CapturedMessage>,
+    decompressed_blocks: usize,
+}
+
+enum Source {
+    Plain {
+        messages: Vec<CapturedMessage>,
+        /// The message after the last one returned.
+        next: usize,
+    },
+    Compressed {
+        blocks: Vec<BlockInfo>,
+        /// The block after the current one.
+        next: usize,
+    },
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> CaptureReader<R> {
+    pub fn new(mut reader: R) -> std::io::Result<Self> {
+        let format = read_header(&mut reader)?;
+        let source = match format {
+            CaptureFormat::Plain => {
+                let mut messages = vec![];
+                while let Some(message) = read_record(&mut reader)? {
+                    messages.push(message);
+                }
+                Source::Plain { messages, next: 0 }
+            }
+            CaptureFormat::Compressed | CaptureFormat::Delta => Source::Compressed {
+                blocks: blocks::find_blocks(&mut reader, HEADER_LEN)?,
+                next: 0,
+            },
+        };
+        Ok(Self {
+            reader,
+            format,
+            source,
+            pending: VecDeque::new(),
+            decompressed_blocks: 0,
+        })
+    }
+
+    pub fn format(&self) -> CaptureFormat {
+        self.format
+    }
+
+    /// How many blocks were decompressed so far, to see how much [Self::seek_to_time] and [Self::iter_code] skipped.
+    pub fn decompressed_blocks(&self) -> usize {
+        self.decompressed_blocks
+    }
+
+    /// Moves to the first message at or after the given time, assuming the messages are ordered by time like the
+    /// proxy writes them. Seeking backwards works too. If all messages are earlier, the reader ends.
+    pub fn seek_to_time(&mut self, time: SystemTime) -> std::io::Result<()> {
+        let millis = to_millis(time);
+        self.pending.clear();
+        match &mut self.source {
+            Source::Plain { messages, next } => {
+                *next = messages.partition_point(|m| to_millis(m.timestamp) < millis);
+            }
+            Source::Compressed { blocks, next } => {
+                *next = blocks.partition_point(|b| !b.reaches(millis));
+                if let Some(info) = blocks.get(*next) {
+                    *next += 1;
+                    self.decompressed_blocks += 1;
+                    self.pending = blocks::read_block(&mut self.reader, info)?
+                        .into_iter()
+                        .skip_while(|m| to_millis(m.timestamp) < millis)
+                        .collect();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Iterates over the messages with the given [operation or event code](message_code) from the current position.
+    /// Blocks that can't have such messages are skipped without decompressing them.
+    pub fn iter_code(
+        &mut self,
+        code: u8,
+    ) -> impl Iterator<Item = std::io::Result<CapturedMessage>> + '_ {
+        std::iter::from_fn(move || self.next_matching(Some(code)))
+    }
+
+    fn next_matching(&mut self, code: Option<u8>) -> Option<std::io::Result<CapturedMessage>> {
+        let matches = |message: &CapturedMessage| match code {
+            Some(code) => message.parse().as_ref().and_then(message_code) == Some(code),
+            None => true,
+        };
+        loop {
+            while let Some(message) = self.pending.pop_front() {
+                if matches(&message) {
+                    return Some(Ok(message));
+                }
+            }
+            match &mut self.source {
+                Source::Plain { messages, next } => {
+                    let message = messages.get(*next)?;
+                    *next += 1;
+                    if matches(message) {
+                        return Some(Ok(message.clone()));
+                    }
+                }
+                Source::Compressed { blocks, next } => {
+                    let info = loop {
+                        let info = blocks.get(*next)?;
+                        *next += 1;
+                        match code {
+                            Some(code) if !info.codes.may_contain(code) => continue,
+                            _ => break info,
+                        }
+                    };
+                    self.decompressed_blocks += 1;
+                    match blocks::read_block(&mut self.reader, info) {
+                        Ok(messages) => self.pending = messages.into(),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> Iterator for CaptureReader<R> {
+    type Item = std::io::Result<CapturedMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_matching(None)
+    }
+}
+
+/// The length of the header at the start of every capture file.
+pub(super) const HEADER_LEN: u64 = 5;
+
+/// Writes the start of a capture file, see [write_record] for the messages that follow in plain files.
+pub(super) fn write_header(mut writer: impl Write, format: CaptureFormat) -> std::io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[format.version()])
+}
+
+/// Starts writing the blocks of a compressed file, after its header.
+pub(super) fn block_writer<W: Write>(writer: W, format: CaptureFormat) -> BlockWriter<W> {
+    let blocks = BlockWriter::new(writer, HEADER_LEN, DEFAULT_BLOCK_SIZE);
+    match format {
+        CaptureFormat::Delta => blocks.with_deltas(DEFAULT_KEYFRAME_INTERVAL),
+        _ => blocks,
+    }
+}
+
+fn read_header(mut reader: impl Read) -> std::io::Result<CaptureFormat> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(invalid_data("not a capture file"));
+    }
+    CaptureFormat::from_version(header[4])
+}
+
+/// Appends a single message to a capture file. Returns how many bytes were written.
+pub(super) fn write_record(writer: impl Write, message: &CapturedMessage) -> std::io::Result<u64> {
+    write_record_parts(writer, message, 0, &[], &message.raw)
+}
+
+/// Appends a message that is stored as a diff against the record at index `base` of the same block.
+pub(super) fn write_delta_record(
+    writer: impl Write,
+    message: &CapturedMessage,
+    base: u32,
+    diff: &[u8],
+) -> std::io::Result<u64> {
+    write_record_parts(writer, message, DELTA_FLAG, &base.to_le_bytes(), diff)
+}
+
+fn write_record_parts(
+    mut writer: impl Write,
+    message: &CapturedMessage,
+    flags: u8,
+    prefix: &[u8],
+    raw: &[u8],
+) -> std::io::Result<u64> {
+    let server = match message.server {
+        WebSocketServer::NameServer => 0u8,
+        WebSocketServer::LobbyServer => 1,
+        WebSocketServer::GameServer => 2,
+    };
+    let direction = match message.direction {
+        Direction::ClientToServer => 0u8,
+        Direction::ServerToClient => 1,
+    };
+    let len = prefix.len() + raw.len();
+    let len = u32::try_from(len).map_err(|_| invalid_data("message is too large"))?;
+
+    writer.write_all(&to_millis(message.timestamp).to_le_bytes())?;
+    writer.write_all(&[server, direction | flags])?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(prefix)?;
+    writer.write_all(raw)?;
+    Ok(8 + 2 + 4 + len as u64)
+}
+
+/// Reads the next record, or [None] at the end of the file.
+pub(super) fn read_record(reader: impl Read) -> std::io::Result<Option<CapturedMessage>> {
+    match read_raw_record(reader)? {
+        Some((message, None)) => Ok(Some(message)),
+        Some((_, Some(_))) => Err(invalid_data("diff record outside of a block")),
+        None => Ok(None),
+    }
+}
+
+/// Reads the next record like [read_record], along with the index of the record it is a diff against if it is
+/// stored as one. The raw message of such a record is the diff.
+pub(super) fn read_raw_record(
+    mut reader: impl Read,
+) -> std::io::Result<Option<(CapturedMessage, Option<u32>)>> {
+    let mut timestamp = [0u8; 8];
+    match reader.read_exact(&mut timestamp) {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut record_header = [0u8; 6];
+    reader.read_exact(&mut record_header)?;
+    let server = match record_header[0] {
+        0 => WebSocketServer::NameServer,
+        1 => WebSocketServer::LobbyServer,
+        2 => WebSocketServer::GameServer,
+        x => return Err(invalid_data(format!("unknown server type {x}"))),
+    };
+    let is_delta = record_header[1] & DELTA_FLAG != 0;
+    let direction = match record_header[1] & !DELTA_FLAG {
+        0 => Direction::ClientToServer,
+        1 => Direction::ServerToClient,
+        x => return Err(invalid_data(format!("unknown direction {x}"))),
+    };
+    let len = u32::from_le_bytes(record_header[2..].try_into().unwrap());
+
+    let mut raw = vec![0u8; len as usize];
+    reader.read_exact(&mut raw)?;
+    let base = match is_delta {
+        true if raw.len() >= 4 => Some(u32::from_le_bytes(
+            raw.drain(..4).as_slice().try_into().unwrap(),
+        )),
+        true => return Err(invalid_data("diff record is too short")),
+        false => None,
+    };
+
+    let message = CapturedMessage {
+        timestamp: UNIX_EPOCH + Duration::from_millis(u64::from_le_bytes(timestamp)),
+        server,
+        direction,
+        raw,
+    };
+    Ok(Some((message, base)))
+}
+
+/// A time as stored in capture files, in milliseconds since the unix epoch.
+pub(super) fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+pub(super) fn invalid_data(message: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use super::{Capture, CaptureFormat, CaptureReader};
+    use crate::{
+        inspect::{message_code, CapturedMessage},
+        proxy::{Direction, WebSocketServer},
+    };
+
+    /// The same 60 messages, 500ms apart, in every version. Lobby messages come first, then game server ones.
+    const FIXTURES: [(CaptureFormat, &[u8]); 2] = [
+        (
+            CaptureFormat::Plain,
+            include_bytes!("../../fixtures/captures/v1.bfhc"),
+        ),
+        (
+            CaptureFormat::Compressed,
+            include_bytes!("../../fixtures/captures/v2.bfhc"),
+        ),
+    ];
+    const FIXTURE_START: u64 = 1_665_000_000_000;
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    /// What tells the messages apart, as they can't be compared themselves.
+    fn keys<'a>(
+        messages: impl IntoIterator<Item = &'a CapturedMessage>,
+    ) -> Vec<(SystemTime, &'a [u8])> {
+        messages
+            .into_iter()
+            .map(|m| (m.timestamp, m.raw.as_slice()))
+            .collect()
+    }
+
+    fn read_all(
+        reader: impl Iterator<Item = std::io::Result<CapturedMessage>>,
+    ) -> Vec<CapturedMessage> {
+        reader.collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let capture = Capture {
+            messages: vec![
+                CapturedMessage {
+                    timestamp: UNIX_EPOCH + Duration::from_millis(1_665_000_000_123),
+                    server: WebSocketServer::LobbyServer,
+                    direction: Direction::ClientToServer,
+                    raw: vec![0xF3, 2, 230, 0, 0],
+                },
+                CapturedMessage {
+                    timestamp: UNIX_EPOCH + Duration::from_millis(1_665_000_002_000),
+                    server: WebSocketServer::GameServer,
+                    direction: Direction::ServerToClient,
+                    raw: vec![],
+                },
+            ],
+        };
+
+        let mut buf = vec![];
+        capture.write_to(&mut buf).unwrap();
+        let read = Capture::read_from(buf.as_slice()).unwrap();
+
+        assert_eq!(read.messages.len(), 2);
+        for (a, b) in capture.messages.iter().zip(&read.messages) {
+            assert_eq!(a.timestamp, b.timestamp);
+            assert_eq!(a.server, b.server);
+            assert_eq!(a.direction, b.direction);
+            assert_eq!(a.raw, b.raw);
+        }
+        assert_eq!(read.duration(), Duration::from_millis(1877));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(Capture::read_from(&b"PK\x03\x04\x14"[..]).is_err());
+        assert!(Capture::read_from(&b"BFHC\x09"[..]).is_err());
+    }
+
+    #[test]
+    fn reads_every_version() {
+        let expected = Capture::read_from(FIXTURES[0].1).unwrap();
+        assert_eq!(expected.messages.len(), 60);
+
+        for (format, bytes) in FIXTURES {
+            let capture = Capture::read_from(bytes).unwrap();
+            assert_eq!(keys(&capture.messages), keys(&expected.messages));
+
+            let reader = CaptureReader::new(Cursor::new(bytes)).unwrap();
+            assert_eq!(reader.format(), format);
+            assert_eq!(keys(&read_all(reader)), keys(&expected.messages));
+
+            // and the other way around
+            let mut written = vec![];
+            expected.write_as(&mut written, format).unwrap();
+            let reader = CaptureReader::new(Cursor::new(&written)).unwrap();
+            assert_eq!(keys(&read_all(reader)), keys(&expected.messages));
+        }
+    }
+
+    #[test]
+    fn reads_unfinished_compressed_files() {
+        let bytes = FIXTURES[1].1;
+        let index_offset = u64::from_le_bytes(bytes[bytes.len() - 12..][..8].try_into().unwrap());
+        let unfinished = &bytes[..index_offset as usize];
+        let expected = Capture::read_from(bytes).unwrap();
+
+        let reader = CaptureReader::new(Cursor::new(unfinished)).unwrap();
+        assert_eq!(keys(&read_all(reader)), keys(&expected.messages));
+
+        // a block that was cut off is left out, the ones before it are still read
+        let cut = &unfinished[..unfinished.len() - 3];
+        let read = read_all(CaptureReader::new(Cursor::new(cut)).unwrap());
+        assert!(!read.is_empty() && read.len() < expected.messages.len());
+        assert_eq!(keys(&read), keys(&expected.messages[..read.len()]));
+    }
+
+    #[test]
+    fn seeks_to_time() {
+        for (format, bytes) in FIXTURES {
+            let mut reader = CaptureReader::new(Cursor::new(bytes)).unwrap();
+            for i in [33, 0, 59, 7] {
+                reader.seek_to_time(at(FIXTURE_START + i * 500)).unwrap();
+                let next = reader.next().unwrap().unwrap();
+                assert_eq!(next.timestamp, at(FIXTURE_START + i * 500), "{format:?}");
+
+                // between two messages, the later one is next
+                reader
+                    .seek_to_time(at(FIXTURE_START + i * 500 - 1))
+                    .unwrap();
+                let next = reader.next().unwrap().unwrap();
+                assert_eq!(next.timestamp, at(FIXTURE_START + i * 500), "{format:?}");
+            }
+            reader.seek_to_time(at(FIXTURE_START + 60 * 500)).unwrap();
+            assert!(reader.next().is_none());
+        }
+
+        // only the block with the time is decompressed
+        let mut reader = CaptureReader::new(Cursor::new(FIXTURES[1].1)).unwrap();
+        reader.seek_to_time(at(FIXTURE_START + 40 * 500)).unwrap();
+        assert_eq!(reader.decompressed_blocks(), 1);
+    }
+
+    #[test]
+    fn code_filter_returns_exactly_the_matching_messages() {
+        let all = Capture::read_from(FIXTURES[0].1).unwrap().messages;
+        let block_count = {
+            let mut reader = CaptureReader::new(Cursor::new(FIXTURES[1].1)).unwrap();
+            read_all(&mut reader);
+            reader.decompressed_blocks()
+        };
+        assert!(block_count > 3);
+
+        // lobby messages, the single leave event, every other game server event and a code that's never sent
+        for code in [229, 254, 200, 99] {
+            let expected = all
+                .iter()
+                .filter(|m| m.parse().as_ref().and_then(message_code) == Some(code))
+                .collect::<Vec<_>>();
+            for (format, bytes) in FIXTURES {
+                let mut reader = CaptureReader::new(Cursor::new(bytes)).unwrap();
+                let found = read_all(reader.iter_code(code));
+                assert_eq!(keys(&found), keys(expected.clone()), "{format:?} {code}");
+                if format == CaptureFormat::Compressed && code != 200 {
+                    assert!(reader.decompressed_blocks() < block_count, "{code}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,500 @@
+//! Delta encoding for serialize messages, which make up most of the traffic and change little from one to the next.
+//!
+//! The serialize messages of each view form a stream. Every [keyframe interval](DeltaEncoder::new) messages, a
+//! stream's message is stored in full as its keyframe, the messages in between are stored as a [diff] against it. A
+//! message whose diff wouldn't be smaller than the message itself is stored in full and becomes the new keyframe, as
+//! the stream's layout changed. As diffs are always against a keyframe and never against another diff, each message
+//! can be restored from a single full message.
+//!
+//! A diff holds the length of the message as a varint, then pairs of varints that alternate between the number of
+//! bytes to copy from the keyframe at the same offset and the number of bytes that follow as they are, until the
+//! message is complete. Serialize messages of the same view mostly keep their layout, so only the changed values end
+//! up in the diff.
+
+use std::collections::HashMap;
+
+use photon_lib::{
+    highlevel::constants::{operation_code, parameter_code, pun_event_code},
+    photon_data_type::PhotonDataType,
+    photon_message::PhotonMessage,
+};
+
+use super::{capture::invalid_data, CapturedMessage};
+use crate::proxy::{Direction, WebSocketServer};
+
+/// How many messages of a stream share a keyframe, including the keyframe itself.
+pub const DEFAULT_KEYFRAME_INTERVAL: usize = 16;
+
+/// The key of the first serialized object in serialize messages, which holds the view id.
+const FIRST_OBJECT_KEY: u8 = 10;
+
+/// The message type bytes of operation requests and events, which follow the magic number in websocket messages.
+const OPERATION_REQUEST: u8 = 2;
+const EVENT_DATA: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct StreamKey {
+    server: WebSocketServer,
+    direction: Direction,
+    view_id: i32,
+}
+
+impl StreamKey {
+    /// The stream of a serialize message, keyed by its first object's view id. Other messages aren't delta encoded.
+    fn of(message: &CapturedMessage) -> Option<Self> {
+        // every buffered message passes through here, so only those that may be serialize messages are parsed
+        if !may_be_serialize(&message.raw) {
+            return None;
+        }
+        let (code, parameters) = match message.parse()? {
+            PhotonMessage::EventData(event) => (event.code, event.parameters),
+            PhotonMessage::OperationRequest(request)
+                if request.operation_code == operation_code::RAISE_EVENT =>
+            {
+                match request.parameters.get(&parameter_code::CODE) {
+                    Some(PhotonDataType::Byte(code)) => (*code, request.parameters),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        if code != pun_event_code::SEND_SERIALIZE && code != pun_event_code::SEND_SERIALIZE_RELIABLE
+        {
+            return None;
+        }
+        let data = match parameters.get(&parameter_code::DATA) {
+            Some(PhotonDataType::Hashtable(data)) => data,
+            _ => return None,
+        };
+        let view_id = match data.get(&PhotonDataType::Byte(FIRST_OBJECT_KEY)) {
+            Some(PhotonDataType::ObjectArray(object)) => match object.first() {
+                Some(PhotonDataType::Integer(view_id)) => *view_id,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(Self {
+            server: message.server,
+            direction: message.direction,
+            view_id,
+        })
+    }
+}
+
+/// Whether raw bytes may hold a serialize message, going by their message type and code without parsing them. Events
+/// carry their code right after the type, the client raises serialize events with an operation that holds the code
+/// among its parameters.
+fn may_be_serialize(raw: &[u8]) -> bool {
+    match raw {
+        [0xF3, EVENT_DATA, code, ..] => {
+            *code == pun_event_code::SEND_SERIALIZE
+                || *code == pun_event_code::SEND_SERIALIZE_RELIABLE
+        }
+        [0xF3, OPERATION_REQUEST, code, ..] => *code == operation_code::RAISE_EVENT,
+        _ => false,
+    }
+}
+
+/// How to store a message, see [DeltaEncoder::encode].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Encoded {
+    Full {
+        /// Whether later messages may be stored as diffs against this one.
+        keyframe: bool,
+    },
+    Delta {
+        /// The id of the keyframe the diff is against.
+        base: u64,
+        diff: Vec<u8>,
+    },
+}
+
+#[derive(Clone)]
+struct Keyframe {
+    id: u64,
+    raw: Vec<u8>,
+    /// How many messages were stored as diffs against it.
+    deltas: usize,
+}
+
+/// Decides which messages are stored as diffs, and against which keyframe.
+#[derive(Clone)]
+pub struct DeltaEncoder {
+    keyframe_interval: usize,
+    keyframes: HashMap<StreamKey, Keyframe>,
+}
+
+impl DeltaEncoder {
+    pub fn new(keyframe_interval: usize) -> Self {
+        Self {
+            keyframe_interval,
+            keyframes: HashMap::new(),
+        }
+    }
+
+    /// Encodes a message, given an id that is larger than those of the messages encoded before it. The id of a
+    /// [Encoded::Delta] is that of an earlier message.
+    pub fn encode(&mut self, message: &CapturedMessage, id: u64) -> Encoded {
+        let key = match StreamKey::of(message) {
+            Some(key) => key,
+            None => return Encoded::Full { keyframe: false },
+        };
+        if let Some(keyframe) = self.keyframes.get_mut(&key) {
+            if keyframe.deltas + 1 < self.keyframe_interval {
+                let diff = diff(&keyframe.raw, &message.raw);
+                if diff.len() < message.raw.len() {
+                    keyframe.deltas += 1;
+                    return Encoded::Delta {
+                        base: keyframe.id,
+                        diff,
+                    };
+                }
+            }
+        }
+        self.keyframes.insert(
+            key,
+            Keyframe {
+                id,
+                raw: message.raw.clone(),
+                deltas: 0,
+            },
+        );
+        Encoded::Full { keyframe: true }
+    }
+
+    /// Stops encoding against the message with the given id, eg. because it was dropped.
+    pub fn forget(&mut self, id: u64) {
+        self.keyframes.retain(|_, keyframe| keyframe.id != id);
+    }
+
+    /// Stops encoding against any earlier message, so the next message of each stream is a keyframe.
+    pub fn reset(&mut self) {
+        self.keyframes.clear();
+    }
+}
+
+/// Encodes `target` as a diff against `base`, see the [module documentation](self).
+pub fn diff(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut diff = vec![];
+    write_varint(&mut diff, target.len() as u64);
+    let mut offset = 0;
+    while offset < target.len() {
+        let copy = target[offset..]
+            .iter()
+            .zip(base.get(offset..).unwrap_or_default())
+            .take_while(|(t, b)| t == b)
+            .count();
+        offset += copy;
+        let literal = target[offset..]
+            .iter()
+            .zip(
+                base.get(offset..)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(Some)
+                    .chain(std::iter::repeat(None)),
+            )
+            .take_while(|(t, b)| Some(*t) != *b)
+            .count();
+        write_varint(&mut diff, copy as u64);
+        write_varint(&mut diff, literal as u64);
+        diff.extend_from_slice(&target[offset..offset + literal]);
+        offset += literal;
+    }
+    diff
+}
+
+/// Restores a message from its [diff] against `base`.
+pub fn patch(base: &[u8], mut diff: &[u8]) -> std::io::Result<Vec<u8>> {
+    let len = read_varint(&mut diff)? as usize;
+    let mut target = Vec::with_capacity(len);
+    while target.len() < len {
+        let copy = read_varint(&mut diff)? as usize;
+        let literal = read_varint(&mut diff)? as usize;
+        let offset = target.len();
+        let copied = base
+            .get(offset..offset + copy)
+            .ok_or_else(|| invalid_data("diff copies past the end of its base"))?;
+        target.extend_from_slice(copied);
+        if literal > diff.len() {
+            return Err(invalid_data("diff is cut off"));
+        }
+        let (literal, rest) = diff.split_at(literal);
+        target.extend_from_slice(literal);
+        diff = rest;
+    }
+    if target.len() != len || !diff.is_empty() {
+        return Err(invalid_data("diff doesn't match its length"));
+    }
+    Ok(target)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint(buf: &mut &[u8]) -> std::io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = buf
+            .split_first()
+            .ok_or_else(|| invalid_data("diff is cut off"))?;
+        *buf = rest;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("varint is too long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use photon_lib::{
+        highlevel::constants::{operation_code, parameter_code, pun_event_code},
+        indexmap::{indexmap, IndexMap},
+        ordered_float::OrderedFloat,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationRequest, PhotonMessage},
+    };
+
+    use super::{diff, may_be_serialize, patch, DeltaEncoder, Encoded};
+    use crate::{
+        inspect::{
+            blocks::BlockWriter,
+            capture::{write_header, Capture, CaptureFormat, CaptureReader, HEADER_LEN},
+            CapturedMessage, MessageBuffer,
+        },
+        proxy::{Direction, WebSocketServer},
+    };
+
+    fn serialize_data(server_time: i32, prefix: bool, objects: &[(i32, f32)]) -> PhotonDataType {
+        let mut data = indexmap! {
+            PhotonDataType::Byte(0) => PhotonDataType::Integer(server_time),
+        };
+        if prefix {
+            data.insert(PhotonDataType::Byte(1), PhotonDataType::Short(0));
+        }
+        for (i, (view_id, x)) in objects.iter().enumerate() {
+            data.insert(
+                PhotonDataType::Byte(10 + i as u8),
+                PhotonDataType::ObjectArray(vec![
+                    PhotonDataType::Integer(*view_id),
+                    PhotonDataType::Boolean(false),
+                    PhotonDataType::Null,
+                    PhotonDataType::Float(OrderedFloat(*x)),
+                    PhotonDataType::Float(OrderedFloat(1.5)),
+                    PhotonDataType::Float(OrderedFloat(-*x / 2.0)),
+                    PhotonDataType::Short(10000),
+                ]),
+            );
+        }
+        PhotonDataType::Hashtable(data)
+    }
+
+    fn captured(tick: u64, direction: Direction, message: PhotonMessage) -> CapturedMessage {
+        let mut raw = vec![];
+        message.to_websocket_bytes(&mut raw).unwrap();
+        CapturedMessage {
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_665_000_000_000 + tick * 100),
+            server: WebSocketServer::GameServer,
+            direction,
+            raw,
+        }
+    }
+
+    /// A game with three other players and us moving around, with the occasional RPC in between. Every now and then
+    /// one of the players leaves out the level prefix, which moves everything after it.
+    fn session(ticks: u64) -> Vec<CapturedMessage> {
+        let mut messages = vec![];
+        for tick in 0..ticks {
+            let server_time = tick as i32 * 100;
+            let x = |speed: f32| (tick as f32 * speed).sin() * 20.0;
+            for actor in 2..=4 {
+                let view_id = actor * 1000 + 1;
+                let prefix = !(actor == 2 && tick % 50 == 25);
+                let objects = [(view_id, x(actor as f32 / 10.0))];
+                let parameters = indexmap! {
+                    parameter_code::ACTOR_NR => PhotonDataType::Integer(actor),
+                    parameter_code::DATA => serialize_data(server_time, prefix, &objects),
+                };
+                messages.push(captured(
+                    tick,
+                    Direction::ServerToClient,
+                    PhotonMessage::EventData(EventData {
+                        code: pun_event_code::SEND_SERIALIZE,
+                        parameters,
+                    }),
+                ));
+            }
+            messages.push(captured(
+                tick,
+                Direction::ClientToServer,
+                PhotonMessage::OperationRequest(OperationRequest {
+                    operation_code: operation_code::RAISE_EVENT,
+                    parameters: indexmap! {
+                        parameter_code::CODE => PhotonDataType::Byte(pun_event_code::SEND_SERIALIZE),
+                        parameter_code::DATA => serialize_data(server_time, true, &[(1001, x(0.05))]),
+                    },
+                }),
+            ));
+            if tick % 7 == 0 {
+                messages.push(captured(
+                    tick,
+                    Direction::ServerToClient,
+                    PhotonMessage::EventData(EventData {
+                        code: pun_event_code::RPC,
+                        parameters: IndexMap::new(),
+                    }),
+                ));
+            }
+        }
+        messages
+    }
+
+    fn assert_identical(actual: &[CapturedMessage], expected: &[CapturedMessage]) {
+        assert_eq!(actual.len(), expected.len());
+        for (i, (actual, expected)) in actual.iter().zip(expected).enumerate() {
+            assert_eq!(actual.raw, expected.raw, "message {i} differs");
+            assert_eq!(actual.timestamp, expected.timestamp);
+            assert_eq!(actual.direction, expected.direction);
+        }
+    }
+
+    #[test]
+    fn diffs_restore_the_original() {
+        let cases: [(&[u8], &[u8]); 6] = [
+            (b"abcdef", b"abcdef"),
+            (b"abcdef", b"abXdeY"),
+            (b"abcdef", b"abc"),
+            (b"abc", b"abcdefgh"),
+            (b"", b"abc"),
+            (b"abc", b""),
+        ];
+        for (base, target) in cases {
+            let diff = diff(base, target);
+            assert_eq!(
+                patch(base, &diff).unwrap(),
+                target,
+                "{base:?} -> {target:?}"
+            );
+        }
+        // unchanged messages take a few bytes
+        assert_eq!(diff(&[7; 300], &[7; 300]).len(), 5);
+        assert!(patch(b"abc", &diff(b"abcdef", b"abcdeX")).is_err());
+    }
+
+    #[test]
+    fn falls_back_to_full_messages() {
+        // a serialize message of view 2001 with some bytes in its object
+        let message = |bytes: Vec<u8>| {
+            let mut data = serialize_data(0, true, &[(2001, 0.0)]);
+            if let PhotonDataType::Hashtable(data) = &mut data {
+                if let Some(PhotonDataType::ObjectArray(object)) =
+                    data.get_mut(&PhotonDataType::Byte(10))
+                {
+                    object.push(PhotonDataType::ByteArray(bytes));
+                }
+            }
+            let parameters = indexmap! {
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(2),
+                parameter_code::DATA => data,
+            };
+            let event = EventData {
+                code: pun_event_code::SEND_SERIALIZE,
+                parameters,
+            };
+            captured(
+                0,
+                Direction::ServerToClient,
+                PhotonMessage::EventData(event),
+            )
+        };
+        let zeros = message(vec![0; 1000]);
+        let one_zero = message(vec![0; 999].into_iter().chain([1]).collect());
+        // every other byte changed makes for a diff that is larger than the message
+        let alternating = message((0..1000).map(|i| (i % 2) as u8).collect());
+
+        let mut encoder = DeltaEncoder::new(4);
+        let mut encode = |message: &CapturedMessage, id| match encoder.encode(message, id) {
+            Encoded::Full { keyframe } => (keyframe, None),
+            Encoded::Delta { base, diff } => {
+                assert!(diff.len() < message.raw.len());
+                (false, Some(base))
+            }
+        };
+        assert_eq!(encode(&zeros, 0), (true, None));
+        assert_eq!(encode(&one_zero, 1), (false, Some(0)));
+        assert_eq!(encode(&alternating, 2), (true, None));
+        for id in 3..=5 {
+            assert_eq!(encode(&alternating, id), (false, Some(2)));
+        }
+        // a new keyframe every 4 messages
+        assert_eq!(encode(&alternating, 6), (true, None));
+
+        let rpc = captured(
+            0,
+            Direction::ServerToClient,
+            PhotonMessage::EventData(EventData {
+                code: pun_event_code::RPC,
+                parameters: IndexMap::new(),
+            }),
+        );
+        assert_eq!(encode(&rpc, 7), (false, None));
+        assert!(may_be_serialize(&zeros.raw));
+        assert!(!may_be_serialize(&rpc.raw));
+    }
+
+    #[test]
+    fn buffer_restores_a_session() {
+        let messages = session(600);
+        let mut buffer = MessageBuffer::default();
+        for message in &messages {
+            buffer.push(message.clone());
+        }
+        let restored = buffer.iter().map(|m| m.into_owned()).collect::<Vec<_>>();
+        // older messages were dropped, including the keyframes of the first diffs
+        assert_identical(&restored, &messages[messages.len() - buffer.len()..]);
+
+        let (stored, full) = buffer.raw_sizes();
+        assert!(stored * 2 < full, "{stored} of {full} bytes");
+    }
+
+    #[test]
+    fn capture_files_restore_a_session() {
+        let messages = session(600);
+        let mut file = vec![];
+        write_header(&mut file, CaptureFormat::Delta).unwrap();
+        let mut blocks = BlockWriter::new(&mut file, HEADER_LEN, 4096).with_deltas(16);
+        for message in &messages {
+            blocks.push(message).unwrap();
+        }
+        blocks.finish().unwrap();
+
+        let capture = Capture::read_from(file.as_slice()).unwrap();
+        assert_identical(&capture.messages, &messages);
+
+        let mut reader = CaptureReader::new(Cursor::new(&file)).unwrap();
+        assert_eq!(reader.format(), CaptureFormat::Delta);
+        let at = messages[1000].timestamp;
+        reader.seek_to_time(at).unwrap();
+        let rest = reader.collect::<std::io::Result<Vec<_>>>().unwrap();
+        let expected = messages.iter().position(|m| m.timestamp >= at).unwrap();
+        assert_identical(&rest, &messages[expected..]);
+
+        let mut plain = vec![];
+        Capture { messages }
+            .write_as(&mut plain, CaptureFormat::Plain)
+            .unwrap();
+        assert!(file.len() < plain.len());
+    }
+}
@@ -0,0 +1,239 @@
+//! Checks a guessed [StreamLayout] against the serialized streams in a capture, to build layouts for new game versions.
+//!
+//! Every object of every SEND_SERIALIZE event, sent by the client or the server, is a sample. Views that aren't
+//! players, such as thrown grenades, serialize streams of their own and never fit a player layout, so the failures
+//! are counted by their error: a layout that is off by one field fails the same way for most samples.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use photon_lib::{
+    highlevel::{
+        constants::{operation_code, pun_event_code},
+        structs::{RaiseEvent, SendSerializeEvent, SerializedData},
+        PhotonParameterMapConversion,
+    },
+    photon_data_type::PhotonDataType,
+    photon_message::PhotonMessage,
+};
+
+use super::{capture::Capture, CapturedMessage};
+use crate::protocol::stream_layout::StreamLayout;
+
+/// How many samples of a capture a layout decoded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LayoutFit {
+    pub samples: usize,
+    /// The samples that matched the layout from the first to the last value.
+    pub clean: usize,
+    /// How many samples failed with each error.
+    pub errors: BTreeMap<String, usize>,
+}
+
+impl LayoutFit {
+    /// The share of samples that decoded cleanly, between 0 and 1.
+    pub fn ratio(&self) -> f32 {
+        match self.samples {
+            0 => 0.0,
+            samples => self.clean as f32 / samples as f32,
+        }
+    }
+}
+
+impl Display for LayoutFit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} samples parse cleanly ({:.0}%)",
+            self.clean,
+            self.samples,
+            self.ratio() * 100.0
+        )?;
+        let mut errors = self.errors.iter().collect::<Vec<_>>();
+        errors.sort_by(|(_, a), (_, b)| b.cmp(a));
+        for (error, count) in errors {
+            write!(f, "\n{count}x {error}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes every serialized stream in the capture with the layout.
+pub fn fit(capture: &Capture, layout: &StreamLayout) -> LayoutFit {
+    let mut fit = LayoutFit::default();
+    for object in capture.messages.iter().flat_map(serialized_objects) {
+        fit.samples += 1;
+        match layout.decode(&object.data_stream) {
+            Ok(_) => fit.clean += 1,
+            Err(e) => *fit.errors.entry(e.to_string()).or_default() += 1,
+        }
+    }
+    fit
+}
+
+/// The objects of a SEND_SERIALIZE event, either raised by the client or sent by the server.
+fn serialized_objects(message: &CapturedMessage) -> Vec<SerializedData> {
+    let objects = match message.parse() {
+        Some(PhotonMessage::EventData(mut event))
+            if matches!(
+                event.code,
+                pun_event_code::SEND_SERIALIZE | pun_event_code::SEND_SERIALIZE_RELIABLE
+            ) =>
+        {
+            SendSerializeEvent::from_map(&mut event.parameters)
+                .ok()
+                .and_then(|event| event.get_serialized_data())
+        }
+        Some(PhotonMessage::OperationRequest(mut request))
+            if request.operation_code == operation_code::RAISE_EVENT =>
+        {
+            match RaiseEvent::from_map(&mut request.parameters) {
+                Ok(RaiseEvent {
+                    event_code:
+                        pun_event_code::SEND_SERIALIZE | pun_event_code::SEND_SERIALIZE_RELIABLE,
+                    data: Some(PhotonDataType::Hashtable(data)),
+                    ..
+                }) => SendSerializeEvent::parse_serialized_data(&data),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    objects.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use photon_lib::{
+        highlevel::{
+            constants::{operation_code, parameter_code, pun_event_code},
+            structs::RaiseEvent,
+            PhotonParameterMapConversion,
+        },
+        indexmap::indexmap,
+        ordered_float::OrderedFloat,
+        photon_data_type::{CustomData, PhotonDataType},
+        photon_message::{EventData, OperationRequest, PhotonMessage},
+        primitives::{Quaternion, Vector3},
+        PhotonHashmap,
+    };
+
+    use super::fit;
+    use crate::{
+        inspect::{capture::Capture, CapturedMessage},
+        protocol::stream_layout::StreamLayout,
+        proxy::{Direction, WebSocketServer},
+    };
+
+    fn captured(message: PhotonMessage, direction: Direction) -> CapturedMessage {
+        let mut raw = vec![];
+        message.to_websocket_bytes(&mut raw).unwrap();
+        CapturedMessage {
+            timestamp: SystemTime::UNIX_EPOCH,
+            server: WebSocketServer::GameServer,
+            direction,
+            raw,
+        }
+    }
+
+    /// A stream in the built-in layout.
+    fn player_stream(x: f32) -> Vec<PhotonDataType> {
+        let mut stream = vec![PhotonDataType::Short(0); 13];
+        stream.extend(vec![PhotonDataType::Byte(0); 5]);
+        stream.extend([
+            PhotonDataType::Integer(-1),
+            PhotonDataType::Custom(CustomData::Vector3(Vector3(
+                OrderedFloat(x),
+                OrderedFloat(0.0),
+                OrderedFloat(0.0),
+            ))),
+            PhotonDataType::Custom(CustomData::Quaternion(Quaternion(
+                OrderedFloat(0.0),
+                OrderedFloat(0.0),
+                OrderedFloat(0.0),
+                OrderedFloat(1.0),
+            ))),
+        ]);
+        stream
+    }
+
+    /// The data of a SEND_SERIALIZE event with a single object.
+    fn serialized(view_id: i32, stream: Vec<PhotonDataType>) -> PhotonHashmap {
+        let mut object = vec![
+            PhotonDataType::Integer(view_id),
+            PhotonDataType::Boolean(false),
+            PhotonDataType::Null,
+        ];
+        object.extend(stream);
+        indexmap! {
+            PhotonDataType::Byte(0) => PhotonDataType::Integer(1000),
+            PhotonDataType::Byte(1) => PhotonDataType::Short(0),
+            PhotonDataType::Byte(10) => PhotonDataType::ObjectArray(object),
+        }
+    }
+
+    fn server_event(data: PhotonHashmap) -> CapturedMessage {
+        let message = PhotonMessage::EventData(EventData {
+            code: pun_event_code::SEND_SERIALIZE,
+            parameters: indexmap! {
+                parameter_code::DATA => PhotonDataType::Hashtable(data),
+                parameter_code::ACTOR_NR => PhotonDataType::Integer(2),
+            },
+        });
+        captured(message, Direction::ServerToClient)
+    }
+
+    fn raised_event(code: u8, data: PhotonHashmap) -> CapturedMessage {
+        let mut parameters = indexmap! {};
+        RaiseEvent::new(code, PhotonDataType::Hashtable(data)).into_map(&mut parameters);
+        let message = PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: operation_code::RAISE_EVENT,
+            parameters,
+        });
+        captured(message, Direction::ClientToServer)
+    }
+
+    #[test]
+    fn counts_clean_samples() {
+        let mut capture = Capture::default();
+        for i in 0..4 {
+            capture
+                .messages
+                .push(server_event(serialized(2001, player_stream(i as f32))));
+        }
+        capture.messages.extend([
+            raised_event(
+                pun_event_code::SEND_SERIALIZE,
+                serialized(1001, player_stream(0.0)),
+            ),
+            // a grenade
+            raised_event(
+                pun_event_code::SEND_SERIALIZE,
+                serialized(1002, vec![PhotonDataType::Float(OrderedFloat(1.0))]),
+            ),
+            // not a serialize event
+            raised_event(pun_event_code::RPC, serialized(1001, player_stream(0.0))),
+        ]);
+
+        let builtin = fit(&capture, &StreamLayout::builtin());
+        assert_eq!((builtin.samples, builtin.clean), (6, 5));
+        assert_eq!(
+            builtin.to_string(),
+            "5 of 6 samples parse cleanly (83%)\n\
+             1x expected short for pitch at position 0, found float"
+        );
+
+        // a guess that is off by one field
+        let mut guess = StreamLayout::builtin();
+        guess.fields.remove(3);
+        let guessed = fit(&capture, &guess);
+        assert_eq!(guessed.clean, 0);
+        assert_eq!(
+            guessed.to_string(),
+            "0 of 6 samples parse cleanly (0%)\n\
+             5x expected byte for accessory_type at position 12, found short\n\
+             1x expected short for pitch at position 0, found float"
+        );
+    }
+}
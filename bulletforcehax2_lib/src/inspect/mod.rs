@@ -0,0 +1,309 @@
+//! Tools to inspect the messages that flow through the proxy.
+
+mod blocks;
+pub mod capture;
+mod delta;
+pub mod layout_fit;
+pub mod query;
+pub mod sink;
+
+use std::{borrow::Cow, collections::VecDeque, time::SystemTime};
+
+use photon_lib::{
+    annotate::Annotation,
+    highlevel::{
+        constants::operation_code,
+        parameters::Parameters,
+        structs::{ChangeGroupsRequest, RaiseEvent},
+        PhotonParameterMapConversion,
+    },
+    photon_message::PhotonMessage,
+    ParameterMap,
+};
+
+use self::delta::{DeltaEncoder, Encoded};
+use crate::proxy::{Direction, WebSocketServer};
+pub use delta::DEFAULT_KEYFRAME_INTERVAL;
+pub use query::{Query, QueryError};
+
+const DEFAULT_CAPACITY: usize = 1000;
+
+/// A websocket message as it was seen by the proxy.
+#[derive(Debug, Clone)]
+pub struct CapturedMessage {
+    pub timestamp: SystemTime,
+    pub server: WebSocketServer,
+    pub direction: Direction,
+    /// The raw message, before any modifications were made to it.
+    pub raw: Vec<u8>,
+}
+
+impl CapturedMessage {
+    pub fn parse(&self) -> Option<PhotonMessage> {
+        PhotonMessage::from_websocket_bytes(&mut self.raw.as_slice()).ok()
+    }
+
+    /// Breaks the raw message down into the byte ranges of its fields, for a hexdump. Also works on messages that
+    /// fail to parse.
+    pub fn annotate(&self) -> Annotation {
+        PhotonMessage::annotate(&self.raw)
+    }
+}
+
+/// A ring buffer holding the most recent messages.
+///
+/// By default, serialize messages are stored as diffs against an earlier message of the same view, and restored when
+/// they're read.
+#[derive(Clone)]
+pub struct MessageBuffer {
+    messages: VecDeque<Stored>,
+    capacity: usize,
+    /// The id of the oldest message, the ids of the others count up from it.
+    first_id: u64,
+    deltas: Option<DeltaEncoder>,
+}
+
+/// A message as the buffer holds it.
+#[derive(Clone)]
+enum Stored {
+    Full {
+        message: CapturedMessage,
+        /// Whether later messages may be stored as diffs against this one.
+        keyframe: bool,
+    },
+    /// A message whose raw bytes are a diff against the keyframe with the given id.
+    Delta { message: CapturedMessage, base: u64 },
+}
+
+impl Default for MessageBuffer {
+    fn default() -> Self {
+        Self::with_delta_encoding(DEFAULT_CAPACITY, DEFAULT_KEYFRAME_INTERVAL)
+    }
+}
+
+impl MessageBuffer {
+    /// Creates a buffer that stores every message as it is.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+            first_id: 0,
+            deltas: None,
+        }
+    }
+
+    /// Creates a buffer that stores serialize messages as diffs, with a keyframe every `keyframe_interval` messages
+    /// of a view.
+    pub fn with_delta_encoding(capacity: usize, keyframe_interval: usize) -> Self {
+        Self {
+            deltas: Some(DeltaEncoder::new(keyframe_interval)),
+            ..Self::with_capacity(capacity)
+        }
+    }
+
+    pub fn push(&mut self, message: CapturedMessage) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.messages.len() >= self.capacity {
+            self.pop_front();
+        }
+        let id = self.first_id + self.messages.len() as u64;
+        let encoded = self
+            .deltas
+            .as_mut()
+            .map(|deltas| deltas.encode(&message, id));
+        self.messages.push_back(match encoded {
+            Some(Encoded::Delta { base, diff }) => Stored::Delta {
+                message: CapturedMessage {
+                    raw: diff,
+                    ..message
+                },
+                base,
+            },
+            Some(Encoded::Full { keyframe }) => Stored::Full { message, keyframe },
+            None => Stored::Full {
+                message,
+                keyframe: false,
+            },
+        });
+    }
+
+    fn pop_front(&mut self) {
+        let id = self.first_id;
+        self.first_id += 1;
+        let keyframe = match self.messages.pop_front() {
+            Some(Stored::Full {
+                message,
+                keyframe: true,
+            }) => message,
+            _ => return,
+        };
+        if let Some(deltas) = &mut self.deltas {
+            deltas.forget(id);
+        }
+        // the messages stored as diffs against it are restored before it's gone
+        for stored in &mut self.messages {
+            if let Stored::Delta { message, base } = stored {
+                if *base == id {
+                    let message = restore(&keyframe, message);
+                    *stored = Stored::Full {
+                        message,
+                        keyframe: false,
+                    };
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.first_id += self.messages.len() as u64;
+        self.messages.clear();
+        if let Some(deltas) = &mut self.deltas {
+            deltas.reset();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Iterates over the messages in this buffer, from oldest to newest. Messages stored as diffs are restored.
+    pub fn iter(&self) -> impl Iterator<Item = Cow<'_, CapturedMessage>> {
+        self.messages.iter().map(|stored| match stored {
+            Stored::Full { message, .. } => Cow::Borrowed(message),
+            Stored::Delta { message, base } => {
+                let keyframe = match &self.messages[(base - self.first_id) as usize] {
+                    Stored::Full { message, .. } => message,
+                    Stored::Delta { .. } => unreachable!("diffs are against full messages"),
+                };
+                Cow::Owned(restore(keyframe, message))
+            }
+        })
+    }
+
+    /// Finds all messages in this buffer that match the given query, from oldest to newest.
+    pub fn search<'a>(
+        &'a self,
+        query: &'a Query,
+    ) -> impl Iterator<Item = Cow<'a, CapturedMessage>> + 'a {
+        self.iter().filter(|message| query.matches(message))
+    }
+
+    /// How many bytes the raw messages take up in the buffer, and how many they would take up if none were stored as
+    /// diffs.
+    pub fn raw_sizes(&self) -> (usize, usize) {
+        let stored = self.messages.iter().map(|stored| match stored {
+            Stored::Full { message, .. } | Stored::Delta { message, .. } => message.raw.len(),
+        });
+        let restored = self.iter().map(|message| message.raw.len());
+        (stored.sum(), restored.sum())
+    }
+}
+
+/// Restores a message that is stored as a diff against `keyframe`.
+fn restore(keyframe: &CapturedMessage, delta: &CapturedMessage) -> CapturedMessage {
+    CapturedMessage {
+        timestamp: delta.timestamp,
+        server: delta.server,
+        direction: delta.direction,
+        raw: delta::patch(&keyframe.raw, &delta.raw).expect("diffs made by the buffer apply"),
+    }
+}
+
+/// Gets the operation or event code of a message, if it has one.
+pub fn message_code(message: &PhotonMessage) -> Option<u8> {
+    match message {
+        PhotonMessage::OperationRequest(r) | PhotonMessage::InternalOperationRequest(r) => {
+            Some(r.operation_code)
+        }
+        PhotonMessage::OperationResponse(r) | PhotonMessage::InternalOperationResponse(r) => {
+            Some(r.operation_code)
+        }
+        PhotonMessage::EventData(e) => Some(e.code),
+        _ => None,
+    }
+}
+
+/// Gets the name of the type of a message.
+pub fn message_type_name(message: &PhotonMessage) -> &'static str {
+    match message {
+        PhotonMessage::Init => "Init",
+        PhotonMessage::InitResponse => "InitResponse",
+        PhotonMessage::OperationRequest(_) => "OperationRequest",
+        PhotonMessage::OperationResponse(_) => "OperationResponse",
+        PhotonMessage::EventData(_) => "EventData",
+        PhotonMessage::DisconnectMessage(_) => "DisconnectMessage",
+        PhotonMessage::InternalOperationRequest(_) => "InternalOperationRequest",
+        PhotonMessage::InternalOperationResponse(_) => "InternalOperationResponse",
+        PhotonMessage::Message(_) => "Message",
+        PhotonMessage::RawMessage(_) => "RawMessage",
+        PhotonMessage::PingResult(_) => "PingResult",
+        PhotonMessage::Encrypted(_) => "Encrypted",
+    }
+}
+
+/// Gets the parameter map of a message, if it has one.
+pub fn message_parameters(message: &PhotonMessage) -> Option<&ParameterMap> {
+    match message {
+        PhotonMessage::OperationRequest(r) | PhotonMessage::InternalOperationRequest(r) => {
+            Some(&r.parameters)
+        }
+        PhotonMessage::OperationResponse(r) | PhotonMessage::InternalOperationResponse(r) => {
+            Some(&r.parameters)
+        }
+        PhotonMessage::EventData(e) => Some(&e.parameters),
+        PhotonMessage::DisconnectMessage(d) => Some(&d.parameters),
+        _ => None,
+    }
+}
+
+/// Describes the delivery options of a message, for messages that have them.
+///
+/// For RAISE_EVENT requests, these are the caching, receiver and interest group options and who the event reaches. For
+/// CHANGE_GROUPS requests, these are the interest groups that are left and joined. For events, this is the interest
+/// group they were raised in, if the server sent it.
+pub fn message_options(message: &PhotonMessage) -> Option<String> {
+    match message {
+        PhotonMessage::OperationRequest(r) if r.operation_code == operation_code::RAISE_EVENT => {
+            let event = RaiseEvent::from_map(&mut r.parameters.clone()).ok()?;
+            let mut options = vec![format!("event {}", event.event_code)];
+            if let Some(cache) = event.cache {
+                options.push(format!("cache {cache}"));
+            }
+            if let Some(index) = event.cache_slice_index {
+                options.push(format!("slice {index}"));
+            }
+            if let Some(receivers) = event.receiver_group {
+                options.push(format!("receivers {receivers}"));
+            }
+            if let Some(group) = event.interest_group {
+                options.push(format!("group {group}"));
+            }
+            if let Some(actors) = &event.actor_list {
+                options.push(format!("actors {actors:?}"));
+            }
+            options.push(format!("to {}", event.target()));
+            Some(options.join(", "))
+        }
+        PhotonMessage::OperationRequest(r) if r.operation_code == operation_code::CHANGE_GROUPS => {
+            let change = ChangeGroupsRequest::from_map(&mut r.parameters.clone()).ok()?;
+            let mut options = vec![];
+            if let Some(remove) = change.remove {
+                options.push(format!("leave groups {remove:?}"));
+            }
+            if let Some(add) = change.add {
+                options.push(format!("join groups {add:?}"));
+            }
+            Some(options.join(", "))
+        }
+        PhotonMessage::EventData(e) => Parameters(&e.parameters)
+            .group()
+            .map(|group| format!("group {group}")),
+        _ => None,
+    }
+}
@@ -0,0 +1,870 @@
+//! A small query language to search through captured messages.
+//!
+//! A query consists of one or more filters, which can be combined using `and`, `or`, `not` and parentheses. Filters
+//! next to each other are implicitly combined using `and`, and `and` binds stronger than `or`.
+//!
+//! The following filters are supported:
+//! - `direction:client` or `direction:server`: the side that sent the message.
+//! - `server:name`, `server:lobby` or `server:game`: the server the connection goes to.
+//! - `type:request`, `type:response`, `type:event`, ...: the type of the message.
+//! - `code:253` or `code>=200`: the operation or event code.
+//! - `param[245]`: the parameter with the given code exists. Hashtables and dictionaries can be descended into using
+//!   further brackets, such as `param[251]["roomName"]` or `param[245][0]`.
+//! - `param[245].contains("Rifle")`: the parameter is a string (or array of strings) that contains the given text.
+//! - `param[252] == 3`, `param[252] > 3`, ...: the parameter equals or compares to a number, string or boolean.
+//!
+//! For example: `direction:server code:253 param[245].contains("Rifle")`.
+
+use photon_lib::{photon_data_type::PhotonDataType, photon_message::PhotonMessage};
+use thiserror::Error;
+
+use super::{message_code, message_parameters, CapturedMessage};
+use crate::proxy::{Direction, WebSocketServer};
+
+/// An error that occured while parsing a query.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("error at position {position}: {message}")]
+pub struct QueryError {
+    /// The byte offset in the query where the error occured.
+    pub position: usize,
+    pub message: String,
+}
+
+impl QueryError {
+    fn new(position: usize, message: impl Into<String>) -> Self {
+        Self {
+            position,
+            message: message.into(),
+        }
+    }
+}
+
+/// A compiled query.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+    Direction(Direction),
+    Server(ServerKind),
+    Type(MessageKind),
+    Code(Comparison, f64),
+    Exists(Accessor),
+    Contains(Accessor, String),
+    Compare(Accessor, Comparison, Literal),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerKind {
+    Name,
+    Lobby,
+    Game,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Request,
+    Response,
+    Event,
+    InternalRequest,
+    InternalResponse,
+    Disconnect,
+    Ping,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Number(f64),
+    String(String),
+    Boolean(bool),
+}
+
+/// Addresses a value inside a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accessor {
+    /// The parameter code.
+    pub parameter: u8,
+    /// The keys to descend into, in order.
+    pub path: Vec<PathKey>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathKey {
+    /// Matches any numeric key with this value, regardless of its type.
+    Number(i64),
+    String(String),
+}
+
+impl Query {
+    pub fn parse(input: &str) -> Result<Self, QueryError> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser {
+            tokens,
+            index: 0,
+            input_len: input.len(),
+        };
+        let query = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            return Err(QueryError::new(
+                token.position,
+                format!("unexpected {}", token.kind.describe()),
+            ));
+        }
+        Ok(query)
+    }
+
+    pub fn and(self, other: Query) -> Query {
+        Query::And(Box::new(self), Box::new(other))
+    }
+
+    pub fn or(self, other: Query) -> Query {
+        Query::Or(Box::new(self), Box::new(other))
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Query {
+        Query::Not(Box::new(self))
+    }
+
+    /// Checks whether a captured message matches this query. Messages that cannot be parsed only match filters that
+    /// don't look at the message contents.
+    pub fn matches(&self, captured: &CapturedMessage) -> bool {
+        let message = captured.parse();
+        self.matches_parsed(captured.direction, captured.server, message.as_ref())
+    }
+
+    pub fn matches_parsed(
+        &self,
+        direction: Direction,
+        server: WebSocketServer,
+        message: Option<&PhotonMessage>,
+    ) -> bool {
+        match self {
+            Query::And(a, b) => {
+                a.matches_parsed(direction, server, message)
+                    && b.matches_parsed(direction, server, message)
+            }
+            Query::Or(a, b) => {
+                a.matches_parsed(direction, server, message)
+                    || b.matches_parsed(direction, server, message)
+            }
+            Query::Not(q) => !q.matches_parsed(direction, server, message),
+            Query::Direction(d) => matches!(
+                (d, direction),
+                (Direction::ClientToServer, Direction::ClientToServer)
+                    | (Direction::ServerToClient, Direction::ServerToClient)
+            ),
+            Query::Server(kind) => matches!(
+                (kind, server),
+                (ServerKind::Name, WebSocketServer::NameServer)
+                    | (ServerKind::Lobby, WebSocketServer::LobbyServer)
+                    | (ServerKind::Game, WebSocketServer::GameServer)
+            ),
+            Query::Type(kind) => message.map(|m| kind.matches(m)).unwrap_or(false),
+            Query::Code(cmp, value) => message
+                .and_then(message_code)
+                .map(|code| cmp.compare(code as f64, *value))
+                .unwrap_or(false),
+            Query::Exists(accessor) => message.and_then(|m| accessor.resolve(m)).is_some(),
+            Query::Contains(accessor, needle) => message
+                .and_then(|m| accessor.resolve(m))
+                .map(|value| value_contains(value, needle))
+                .unwrap_or(false),
+            Query::Compare(accessor, cmp, literal) => message
+                .and_then(|m| accessor.resolve(m))
+                .map(|value| value_compare(value, *cmp, literal))
+                .unwrap_or(false),
+        }
+    }
+
+    /// Filters an iterator of captured messages, eg. from a [Capture](super::capture::Capture).
+    pub fn filter<'a, I>(&'a self, messages: I) -> impl Iterator<Item = &'a CapturedMessage> + 'a
+    where
+        I: Iterator<Item = &'a CapturedMessage> + 'a,
+    {
+        messages.filter(move |m| self.matches(m))
+    }
+}
+
+impl MessageKind {
+    fn matches(&self, message: &PhotonMessage) -> bool {
+        matches!(
+            (self, message),
+            (MessageKind::Request, PhotonMessage::OperationRequest(_))
+                | (MessageKind::Response, PhotonMessage::OperationResponse(_))
+                | (MessageKind::Event, PhotonMessage::EventData(_))
+                | (
+                    MessageKind::InternalRequest,
+                    PhotonMessage::InternalOperationRequest(_)
+                )
+                | (
+                    MessageKind::InternalResponse,
+                    PhotonMessage::InternalOperationResponse(_)
+                )
+                | (MessageKind::Disconnect, PhotonMessage::DisconnectMessage(_))
+                | (MessageKind::Ping, PhotonMessage::PingResult(_))
+        )
+    }
+}
+
+impl Comparison {
+    fn compare(&self, a: f64, b: f64) -> bool {
+        match self {
+            Comparison::Equal => a == b,
+            Comparison::NotEqual => a != b,
+            Comparison::Less => a < b,
+            Comparison::LessOrEqual => a <= b,
+            Comparison::Greater => a > b,
+            Comparison::GreaterOrEqual => a >= b,
+        }
+    }
+}
+
+impl Accessor {
+    /// Finds the value this accessor points to in the given message.
+    pub fn resolve<'a>(&self, message: &'a PhotonMessage) -> Option<&'a PhotonDataType> {
+        let mut value = message_parameters(message)?.get(&self.parameter)?;
+
+        for key in &self.path {
+            value = match value {
+                PhotonDataType::Hashtable(map) | PhotonDataType::Dictionary(_, map) => {
+                    map.iter().find(|(k, _)| key.matches(k)).map(|(_, v)| v)?
+                }
+                PhotonDataType::Array(items) | PhotonDataType::ObjectArray(items) => match key {
+                    PathKey::Number(i) => items.get(usize::try_from(*i).ok()?)?,
+                    PathKey::String(_) => return None,
+                },
+                _ => return None,
+            };
+        }
+
+        Some(value)
+    }
+}
+
+impl PathKey {
+    fn matches(&self, key: &PhotonDataType) -> bool {
+        match (self, key) {
+            (PathKey::String(s), PhotonDataType::String(k)) => s == k,
+            (PathKey::Number(n), k) => as_number(k) == Some(*n as f64),
+            _ => false,
+        }
+    }
+}
+
+fn as_number(value: &PhotonDataType) -> Option<f64> {
+    match value {
+        PhotonDataType::Byte(x) => Some(*x as f64),
+        PhotonDataType::Short(x) => Some(*x as f64),
+        PhotonDataType::Integer(x) => Some(*x as f64),
+        PhotonDataType::Long(x) => Some(*x as f64),
+        PhotonDataType::Float(x) => Some(x.0 as f64),
+        PhotonDataType::Double(x) => Some(x.0),
+        _ => None,
+    }
+}
+
+fn value_contains(value: &PhotonDataType, needle: &str) -> bool {
+    match value {
+        PhotonDataType::String(s) => s.contains(needle),
+        PhotonDataType::StringArray(items) => items.iter().any(|s| s.contains(needle)),
+        PhotonDataType::Array(items) | PhotonDataType::ObjectArray(items) => {
+            items.iter().any(|v| value_contains(v, needle))
+        }
+        _ => false,
+    }
+}
+
+fn value_compare(value: &PhotonDataType, cmp: Comparison, literal: &Literal) -> bool {
+    match (literal, value) {
+        (Literal::Number(n), v) => as_number(v).map(|v| cmp.compare(v, *n)).unwrap_or(false),
+        (Literal::String(s), PhotonDataType::String(v)) => match cmp {
+            Comparison::Equal => v == s,
+            Comparison::NotEqual => v != s,
+            Comparison::Less => v < s,
+            Comparison::LessOrEqual => v <= s,
+            Comparison::Greater => v > s,
+            Comparison::GreaterOrEqual => v >= s,
+        },
+        (Literal::Boolean(b), PhotonDataType::Boolean(v)) => match cmp {
+            Comparison::Equal => v == b,
+            Comparison::NotEqual => v != b,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Ident(String),
+    Number(f64),
+    String(String),
+    Colon,
+    Dot,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    Comparison(Comparison),
+}
+
+impl TokenKind {
+    fn describe(&self) -> String {
+        match self {
+            TokenKind::Ident(i) => format!("'{i}'"),
+            TokenKind::Number(n) => format!("number {n}"),
+            TokenKind::String(s) => format!("string {s:?}"),
+            TokenKind::Colon => "':'".into(),
+            TokenKind::Dot => "'.'".into(),
+            TokenKind::LBracket => "'['".into(),
+            TokenKind::RBracket => "']'".into(),
+            TokenKind::LParen => "'('".into(),
+            TokenKind::RParen => "')'".into(),
+            TokenKind::Comparison(_) => "comparison operator".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    position: usize,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, QueryError> {
+    let mut tokens = vec![];
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(position, c)) = chars.peek() {
+        let kind = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            ':' | '.' | '[' | ']' | '(' | ')' => {
+                chars.next();
+                match c {
+                    ':' => TokenKind::Colon,
+                    '.' => TokenKind::Dot,
+                    '[' => TokenKind::LBracket,
+                    ']' => TokenKind::RBracket,
+                    '(' => TokenKind::LParen,
+                    _ => TokenKind::RParen,
+                }
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let followed_by_eq = matches!(chars.peek(), Some((_, '=')));
+                if followed_by_eq {
+                    chars.next();
+                }
+                TokenKind::Comparison(match (c, followed_by_eq) {
+                    ('=', true) => Comparison::Equal,
+                    ('!', true) => Comparison::NotEqual,
+                    ('<', false) => Comparison::Less,
+                    ('<', true) => Comparison::LessOrEqual,
+                    ('>', false) => Comparison::Greater,
+                    ('>', true) => Comparison::GreaterOrEqual,
+                    _ => {
+                        return Err(QueryError::new(
+                            position,
+                            format!("expected '=' after '{c}'"),
+                        ))
+                    }
+                })
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, escaped)) => s.push(escaped),
+                            None => return Err(QueryError::new(position, "unterminated string")),
+                        },
+                        Some((_, c)) => s.push(c),
+                        None => return Err(QueryError::new(position, "unterminated string")),
+                    }
+                }
+                TokenKind::String(s)
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut s = String::new();
+                s.push(c);
+                chars.next();
+                while let Some(&(_, c)) = chars.peek() {
+                    // allow a single decimal point, but only when followed by a digit so `.contains` still works
+                    let is_decimal_point = c == '.'
+                        && !s.contains('.')
+                        && input[position + s.len() + 1..]
+                            .starts_with(|c: char| c.is_ascii_digit());
+                    if !c.is_ascii_digit() && !is_decimal_point {
+                        break;
+                    }
+                    s.push(c);
+                    chars.next();
+                }
+                let number = s
+                    .parse()
+                    .map_err(|_| QueryError::new(position, format!("invalid number '{s}'")))?;
+                TokenKind::Number(number)
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut s = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if !c.is_alphanumeric() && c != '_' {
+                        break;
+                    }
+                    s.push(c);
+                    chars.next();
+                }
+                TokenKind::Ident(s)
+            }
+            c => {
+                return Err(QueryError::new(
+                    position,
+                    format!("unexpected character '{c}'"),
+                ))
+            }
+        };
+
+        tokens.push(Token { kind, position });
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    index: usize,
+    input_len: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index)
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+
+    fn peek_ident(&self, ident: &str) -> bool {
+        matches!(self.peek(), Some(Token { kind: TokenKind::Ident(i), .. }) if i.eq_ignore_ascii_case(ident))
+    }
+
+    fn expect(&mut self, expected: TokenKind) -> Result<Token, QueryError> {
+        match self.advance() {
+            Some(token) if token.kind == expected => Ok(token),
+            Some(token) => Err(QueryError::new(
+                token.position,
+                format!(
+                    "expected {} but found {}",
+                    expected.describe(),
+                    token.kind.describe()
+                ),
+            )),
+            None => Err(QueryError::new(
+                self.input_len,
+                format!("expected {} but query ended", expected.describe()),
+            )),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Query, QueryError> {
+        let mut query = self.parse_and()?;
+        while self.peek_ident("or") {
+            self.advance();
+            query = query.or(self.parse_and()?);
+        }
+        Ok(query)
+    }
+
+    fn parse_and(&mut self) -> Result<Query, QueryError> {
+        let mut query = self.parse_unary()?;
+        loop {
+            if self.peek_ident("and") {
+                self.advance();
+            } else if self.peek_ident("or")
+                || matches!(
+                    self.peek(),
+                    None | Some(Token {
+                        kind: TokenKind::RParen,
+                        ..
+                    })
+                )
+            {
+                break;
+            }
+            query = query.and(self.parse_unary()?);
+        }
+        Ok(query)
+    }
+
+    fn parse_unary(&mut self) -> Result<Query, QueryError> {
+        if self.peek_ident("not") {
+            self.advance();
+            return Ok(self.parse_unary()?.not());
+        }
+
+        match self.advance() {
+            Some(Token {
+                kind: TokenKind::LParen,
+                ..
+            }) => {
+                let query = self.parse_or()?;
+                self.expect(TokenKind::RParen)?;
+                Ok(query)
+            }
+            Some(Token {
+                kind: TokenKind::Ident(ident),
+                position,
+            }) => self.parse_filter(&ident, position),
+            Some(token) => Err(QueryError::new(
+                token.position,
+                format!("expected a filter but found {}", token.kind.describe()),
+            )),
+            None => Err(QueryError::new(
+                self.input_len,
+                "expected a filter but query ended",
+            )),
+        }
+    }
+
+    fn parse_filter(&mut self, ident: &str, position: usize) -> Result<Query, QueryError> {
+        match ident.to_ascii_lowercase().as_str() {
+            "direction" => {
+                let (value, position) = self.parse_keyword_value()?;
+                match value.as_str() {
+                    "client" | "c2s" => Ok(Query::Direction(Direction::ClientToServer)),
+                    "server" | "s2c" => Ok(Query::Direction(Direction::ServerToClient)),
+                    v => Err(QueryError::new(
+                        position,
+                        format!("unknown direction '{v}'"),
+                    )),
+                }
+            }
+            "server" => {
+                let (value, position) = self.parse_keyword_value()?;
+                match value.as_str() {
+                    "name" | "nameserver" => Ok(Query::Server(ServerKind::Name)),
+                    "lobby" | "master" => Ok(Query::Server(ServerKind::Lobby)),
+                    "game" => Ok(Query::Server(ServerKind::Game)),
+                    v => Err(QueryError::new(position, format!("unknown server '{v}'"))),
+                }
+            }
+            "type" => {
+                let (value, position) = self.parse_keyword_value()?;
+                let kind = match value.as_str() {
+                    "request" => MessageKind::Request,
+                    "response" => MessageKind::Response,
+                    "event" => MessageKind::Event,
+                    "internalrequest" => MessageKind::InternalRequest,
+                    "internalresponse" => MessageKind::InternalResponse,
+                    "disconnect" => MessageKind::Disconnect,
+                    "ping" => MessageKind::Ping,
+                    v => {
+                        return Err(QueryError::new(
+                            position,
+                            format!("unknown message type '{v}'"),
+                        ))
+                    }
+                };
+                Ok(Query::Type(kind))
+            }
+            "code" => {
+                let cmp = match self.advance() {
+                    Some(Token {
+                        kind: TokenKind::Colon,
+                        ..
+                    }) => Comparison::Equal,
+                    Some(Token {
+                        kind: TokenKind::Comparison(cmp),
+                        ..
+                    }) => cmp,
+                    other => {
+                        return Err(self.unexpected(other, "':' or a comparison after 'code'"));
+                    }
+                };
+                match self.advance() {
+                    Some(Token {
+                        kind: TokenKind::Number(n),
+                        ..
+                    }) => Ok(Query::Code(cmp, n)),
+                    other => Err(self.unexpected(other, "a number")),
+                }
+            }
+            "param" => {
+                let accessor = self.parse_accessor()?;
+                match self.peek().map(|t| t.kind.clone()) {
+                    Some(TokenKind::Dot) => {
+                        self.advance();
+                        match self.advance() {
+                            Some(Token {
+                                kind: TokenKind::Ident(method),
+                                ..
+                            }) if method == "contains" => (),
+                            other => return Err(self.unexpected(other, "'contains'")),
+                        }
+                        self.expect(TokenKind::LParen)?;
+                        let needle = match self.advance() {
+                            Some(Token {
+                                kind: TokenKind::String(s),
+                                ..
+                            }) => s,
+                            other => return Err(self.unexpected(other, "a string")),
+                        };
+                        self.expect(TokenKind::RParen)?;
+                        Ok(Query::Contains(accessor, needle))
+                    }
+                    Some(TokenKind::Comparison(cmp)) => {
+                        self.advance();
+                        let literal = match self.advance() {
+                            Some(Token {
+                                kind: TokenKind::Number(n),
+                                ..
+                            }) => Literal::Number(n),
+                            Some(Token {
+                                kind: TokenKind::String(s),
+                                ..
+                            }) => Literal::String(s),
+                            Some(Token {
+                                kind: TokenKind::Ident(i),
+                                ..
+                            }) if i == "true" || i == "false" => Literal::Boolean(i == "true"),
+                            other => return Err(self.unexpected(other, "a literal")),
+                        };
+                        Ok(Query::Compare(accessor, cmp, literal))
+                    }
+                    _ => Ok(Query::Exists(accessor)),
+                }
+            }
+            _ => Err(QueryError::new(
+                position,
+                format!("unknown filter '{ident}'"),
+            )),
+        }
+    }
+
+    /// Parses the `:value` part of a filter such as `direction:server`.
+    fn parse_keyword_value(&mut self) -> Result<(String, usize), QueryError> {
+        self.expect(TokenKind::Colon)?;
+        match self.advance() {
+            Some(Token {
+                kind: TokenKind::Ident(value),
+                position,
+            }) => Ok((value.to_ascii_lowercase(), position)),
+            other => Err(self.unexpected(other, "a value")),
+        }
+    }
+
+    /// Parses the `[245]["key"]` part of a parameter accessor.
+    fn parse_accessor(&mut self) -> Result<Accessor, QueryError> {
+        self.expect(TokenKind::LBracket)?;
+        let parameter = match self.advance() {
+            Some(Token {
+                kind: TokenKind::Number(n),
+                ..
+            }) if n.fract() == 0.0 && (0.0..=255.0).contains(&n) => n as u8,
+            other => return Err(self.unexpected(other, "a parameter code between 0 and 255")),
+        };
+        self.expect(TokenKind::RBracket)?;
+
+        let mut path = vec![];
+        while matches!(
+            self.peek(),
+            Some(Token {
+                kind: TokenKind::LBracket,
+                ..
+            })
+        ) {
+            self.advance();
+            let key = match self.advance() {
+                Some(Token {
+                    kind: TokenKind::Number(n),
+                    ..
+                }) if n.fract() == 0.0 => PathKey::Number(n as i64),
+                Some(Token {
+                    kind: TokenKind::String(s),
+                    ..
+                }) => PathKey::String(s),
+                other => return Err(self.unexpected(other, "an integer or string key")),
+            };
+            self.expect(TokenKind::RBracket)?;
+            path.push(key);
+        }
+
+        Ok(Accessor { parameter, path })
+    }
+
+    fn unexpected(&self, token: Option<Token>, expected: &str) -> QueryError {
+        match token {
+            Some(token) => QueryError::new(
+                token.position,
+                format!("expected {expected} but found {}", token.kind.describe()),
+            ),
+            None => QueryError::new(
+                self.input_len,
+                format!("expected {expected} but query ended"),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use photon_lib::{
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, OperationRequest, PhotonMessage},
+    };
+
+    use super::{Query, QueryError};
+    use crate::{
+        inspect::CapturedMessage,
+        proxy::{Direction, WebSocketServer},
+    };
+
+    fn capture(
+        direction: Direction,
+        server: WebSocketServer,
+        message: PhotonMessage,
+    ) -> CapturedMessage {
+        let mut raw = vec![];
+        message.to_websocket_bytes(&mut raw).unwrap();
+        CapturedMessage {
+            timestamp: SystemTime::now(),
+            server,
+            direction,
+            raw,
+        }
+    }
+
+    /// A small set of messages: a raised RPC event from the client, a properties changed event from the server and a
+    /// game list from the lobby.
+    fn fixtures() -> Vec<CapturedMessage> {
+        vec![
+            capture(
+                Direction::ClientToServer,
+                WebSocketServer::GameServer,
+                PhotonMessage::OperationRequest(OperationRequest {
+                    operation_code: 253,
+                    parameters: indexmap! {
+                        244 => PhotonDataType::Byte(200),
+                        245 => PhotonDataType::Hashtable(indexmap! {
+                            PhotonDataType::Byte(3) => PhotonDataType::String("WeaponTypeChanged".into()),
+                            PhotonDataType::Byte(4) => PhotonDataType::ObjectArray(vec![
+                                PhotonDataType::String("Rifle_AK".into()),
+                            ]),
+                        }),
+                    },
+                }),
+            ),
+            capture(
+                Direction::ServerToClient,
+                WebSocketServer::GameServer,
+                PhotonMessage::EventData(EventData {
+                    code: 253,
+                    parameters: indexmap! {
+                        253 => PhotonDataType::Integer(3),
+                        251 => PhotonDataType::Hashtable(indexmap! {
+                            PhotonDataType::String("teamNumber".into()) => PhotonDataType::Byte(1),
+                        }),
+                        245 => PhotonDataType::String("Rifle".into()),
+                    },
+                }),
+            ),
+            capture(
+                Direction::ServerToClient,
+                WebSocketServer::LobbyServer,
+                PhotonMessage::EventData(EventData {
+                    code: 230,
+                    parameters: indexmap! {
+                        222 => PhotonDataType::Hashtable(indexmap! {}),
+                    },
+                }),
+            ),
+        ]
+    }
+
+    fn search(query: &str) -> Vec<usize> {
+        let query = Query::parse(query).unwrap();
+        fixtures()
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| query.matches(m))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    #[test]
+    fn simple_filters() {
+        assert_eq!(search("direction:client"), vec![0]);
+        assert_eq!(search("direction:server"), vec![1, 2]);
+        assert_eq!(search("server:lobby"), vec![2]);
+        assert_eq!(search("type:event"), vec![1, 2]);
+        assert_eq!(search("code:253"), vec![0, 1]);
+        assert_eq!(search("code<250"), vec![2]);
+    }
+
+    #[test]
+    fn parameter_access() {
+        assert_eq!(search("param[222]"), vec![2]);
+        assert_eq!(search(r#"param[245].contains("Rifle")"#), vec![1]);
+        assert_eq!(search(r#"param[245][4].contains("Rifle")"#), vec![0]);
+        assert_eq!(search(r#"param[245][3] == "WeaponTypeChanged""#), vec![0]);
+        assert_eq!(search(r#"param[251]["teamNumber"] == 1"#), vec![1]);
+        assert_eq!(search("param[253] >= 3"), vec![1]);
+        assert_eq!(search("param[253] > 3"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn composition() {
+        assert_eq!(
+            search(r#"direction:server code:253 param[245].contains("Rifle")"#),
+            vec![1]
+        );
+        assert_eq!(search("server:lobby or direction:client"), vec![0, 2]);
+        assert_eq!(search("not server:lobby and code:253"), vec![0, 1]);
+        assert_eq!(
+            search("(direction:client or server:lobby) and type:event"),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn parse_errors_report_position() {
+        assert_eq!(
+            Query::parse("direction:sideways"),
+            Err(QueryError {
+                position: 10,
+                message: "unknown direction 'sideways'".into()
+            })
+        );
+        assert_eq!(Query::parse("code:253 foo:1").unwrap_err().position, 9);
+        assert_eq!(Query::parse("param[245").unwrap_err().position, 9);
+        assert_eq!(
+            Query::parse(r#"param[245].contains("x"#)
+                .unwrap_err()
+                .position,
+            20
+        );
+        assert_eq!(Query::parse("(code:1").unwrap_err().position, 7);
+    }
+}
@@ -0,0 +1,524 @@
+//! Writing messages to capture files as they come through the proxy.
+//!
+//! Any number of named sinks can be active at once, each with its own file, [filter](Query) and [RotationPolicy]. A
+//! sink could take only the RPCs of the game server while another takes everything. The files are in the usual
+//! [capture](super::capture) format, compressed unless the sink asks for plain files, so they're read with
+//! [Capture::load](super::capture::Capture::load) like any other capture.
+//!
+//! Files are written and compressed on a background thread, so a slow disk doesn't hold up the proxy.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread::JoinHandle,
+};
+
+use photon_lib::{indexmap::IndexMap, photon_message::PhotonMessage};
+use tracing::{debug, warn};
+
+use super::{
+    blocks::BlockWriter,
+    capture::{block_writer, write_header, write_record, CaptureFormat, HEADER_LEN},
+    CapturedMessage, Query,
+};
+
+/// The default for [RotationPolicy::max_bytes].
+pub const DEFAULT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// When a sink starts a new file, and how many old ones it keeps around.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RotationPolicy {
+    /// Start a new file once the current one would grow past this size. If not set, the file grows forever.
+    pub max_bytes: Option<u64>,
+    /// How many full files to keep next to the current one. They're named after the sink's path with `.1`, `.2`,
+    /// ... appended, `.1` being the most recent one.
+    pub keep: usize,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        Self {
+            max_bytes: Some(DEFAULT_MAX_BYTES),
+            keep: 3,
+        }
+    }
+}
+
+/// A file that receives the messages matching a filter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureSink {
+    pub name: String,
+    /// The messages to write. If not set, all messages are written.
+    pub filter: Option<Query>,
+    pub path: PathBuf,
+    pub rotation: RotationPolicy,
+    pub format: CaptureFormat,
+}
+
+impl CaptureSink {
+    fn matches(&self, captured: &CapturedMessage, message: Option<&PhotonMessage>) -> bool {
+        match &self.filter {
+            Some(filter) => filter.matches_parsed(captured.direction, captured.server, message),
+            None => true,
+        }
+    }
+}
+
+enum Command {
+    Open(u64, SinkFile),
+    Close(u64),
+    /// Writes a message to all of the given sinks.
+    Write(Vec<u64>, CapturedMessage),
+    /// Flushes all files, and answers once everything before it was written.
+    Flush(mpsc::Sender<()>),
+}
+
+/// The active capture sinks.
+#[derive(Default)]
+pub struct CaptureSinks {
+    /// The sinks by name, with the id the writer knows them by.
+    sinks: IndexMap<String, (u64, CaptureSink)>,
+    next_id: u64,
+    writer: Option<Writer>,
+}
+
+struct Writer {
+    commands: mpsc::Sender<Command>,
+    thread: JoinHandle<()>,
+}
+
+impl CaptureSinks {
+    /// Starts writing the messages that match the sink's filter to its file, replacing any sink with the same name.
+    ///
+    /// The file is created right away, so this fails if it can't be. An existing file is overwritten. If the file
+    /// can't be created, the replaced sink is still removed.
+    pub fn add(&mut self, sink: CaptureSink) -> std::io::Result<()> {
+        // the replaced sink may write to the same file, so it has to be done before the file is created again
+        if self.remove(&sink.name) {
+            self.flush();
+        }
+        let file = SinkFile::create(sink.path.clone(), sink.rotation.clone(), sink.format)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(Command::Open(id, file));
+        debug!(
+            name = sink.name,
+            path = format!("{}", sink.path.display()),
+            "Added capture sink"
+        );
+        self.sinks.insert(sink.name.clone(), (id, sink));
+        Ok(())
+    }
+
+    /// Stops writing to the sink with the given name. Returns whether there was one.
+    pub fn remove(&mut self, name: &str) -> bool {
+        match self.sinks.shift_remove(name) {
+            Some((id, _)) => {
+                self.send(Command::Close(id));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops writing to every sink and waits until their files are finished, with the index written for compressed
+    /// files. Returns how many sinks there were.
+    pub fn close_all(&mut self) -> usize {
+        let ids = self
+            .sinks
+            .drain(..)
+            .map(|(_, (id, _))| id)
+            .collect::<Vec<_>>();
+        for id in &ids {
+            self.send(Command::Close(*id));
+        }
+        self.flush();
+        ids.len()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&CaptureSink> {
+        self.sinks.get(name).map(|(_, sink)| sink)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &CaptureSink> {
+        self.sinks.values().map(|(_, sink)| sink)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sinks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    /// Writes a message to every sink whose filter it matches. The message is parsed at most once, however many sinks
+    /// there are.
+    pub fn record(&mut self, captured: &CapturedMessage) {
+        if self.sinks.is_empty() {
+            return;
+        }
+        let needs_parse = self.sinks.values().any(|(_, sink)| sink.filter.is_some());
+        let message = match needs_parse {
+            true => captured.parse(),
+            false => None,
+        };
+        let ids = self
+            .sinks
+            .values()
+            .filter(|(_, sink)| sink.matches(captured, message.as_ref()))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        if !ids.is_empty() {
+            self.send(Command::Write(ids, captured.clone()));
+        }
+    }
+
+    /// Waits until all messages recorded so far are written to their files.
+    pub fn flush(&self) {
+        let writer = match &self.writer {
+            Some(writer) => writer,
+            None => return,
+        };
+        let (done, wait) = mpsc::channel();
+        if writer.commands.send(Command::Flush(done)).is_ok() {
+            _ = wait.recv();
+        }
+    }
+
+    fn send(&mut self, command: Command) {
+        let writer = self.writer.get_or_insert_with(Writer::start);
+        if writer.commands.send(command).is_err() {
+            warn!("Capture writer stopped, messages are no longer written to capture sinks");
+        }
+    }
+}
+
+impl Drop for CaptureSinks {
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            // hanging up makes the writer flush its files and stop
+            drop(writer.commands);
+            _ = writer.thread.join();
+        }
+    }
+}
+
+impl Writer {
+    fn start() -> Self {
+        let (commands, receive) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("capture writer".into())
+            .spawn(move || run_writer(receive))
+            .expect("should be able to start the capture writer thread");
+        Self { commands, thread }
+    }
+}
+
+fn run_writer(commands: mpsc::Receiver<Command>) {
+    let mut files = IndexMap::<u64, SinkFile>::new();
+    for command in commands {
+        match command {
+            Command::Open(id, file) => {
+                files.insert(id, file);
+            }
+            Command::Close(id) => {
+                if let Some(mut file) = files.shift_remove(&id) {
+                    file.close();
+                }
+            }
+            Command::Write(ids, message) => {
+                for id in ids {
+                    let failed = match files.get_mut(&id) {
+                        Some(file) => match file.write(&message) {
+                            Ok(()) => false,
+                            Err(e) => {
+                                warn!(
+                                    path = format!("{}", file.path.display()),
+                                    "Could not write to capture sink, closing it: {e}"
+                                );
+                                true
+                            }
+                        },
+                        None => false,
+                    };
+                    if failed {
+                        files.shift_remove(&id);
+                    }
+                }
+            }
+            Command::Flush(done) => {
+                for file in files.values_mut() {
+                    file.flush();
+                }
+                _ = done.send(());
+            }
+        }
+    }
+    for file in files.values_mut() {
+        file.close();
+    }
+}
+
+/// The file a sink currently writes to.
+struct SinkFile {
+    path: PathBuf,
+    rotation: RotationPolicy,
+    format: CaptureFormat,
+    writer: SinkWriter,
+    written: u64,
+}
+
+enum SinkWriter {
+    Plain(BufWriter<File>),
+    Compressed(BlockWriter<BufWriter<File>>),
+}
+
+impl SinkFile {
+    fn create(
+        path: PathBuf,
+        rotation: RotationPolicy,
+        format: CaptureFormat,
+    ) -> std::io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(&path)?);
+        write_header(&mut writer, format)?;
+        let writer = match format {
+            CaptureFormat::Plain => SinkWriter::Plain(writer),
+            CaptureFormat::Compressed | CaptureFormat::Delta => {
+                SinkWriter::Compressed(block_writer(writer, format))
+            }
+        };
+        Ok(Self {
+            path,
+            rotation,
+            format,
+            writer,
+            written: HEADER_LEN,
+        })
+    }
+
+    fn write(&mut self, message: &CapturedMessage) -> std::io::Result<()> {
+        // compressed files count the messages that aren't compressed yet at their full size
+        let record_len = 14 + message.raw.len() as u64;
+        if let Some(max_bytes) = self.rotation.max_bytes {
+            // a message that doesn't fit in an empty file still goes in one on its own
+            if self.written > HEADER_LEN && self.written + record_len > max_bytes {
+                self.rotate()?;
+            }
+        }
+        match &mut self.writer {
+            SinkWriter::Plain(writer) => self.written += write_record(writer, message)?,
+            SinkWriter::Compressed(blocks) => {
+                blocks.push(message)?;
+                self.written = blocks.written();
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the current file out of the way and starts a new one.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.finish()?;
+        match self.rotation.keep {
+            0 => std::fs::remove_file(&self.path)?,
+            keep => {
+                for i in (1..keep).rev() {
+                    let from = rotated_path(&self.path, i);
+                    if from.exists() {
+                        std::fs::rename(&from, rotated_path(&self.path, i + 1))?;
+                    }
+                }
+                std::fs::rename(&self.path, rotated_path(&self.path, 1))?;
+            }
+        }
+        *self = Self::create(self.path.clone(), self.rotation.clone(), self.format)?;
+        Ok(())
+    }
+
+    /// Writes everything so far, so the file can be read while the sink keeps writing to it. Compressed files end the
+    /// current block early for that.
+    fn flush(&mut self) {
+        let flushed = match &mut self.writer {
+            SinkWriter::Plain(writer) => writer.flush(),
+            SinkWriter::Compressed(blocks) => blocks
+                .finish_block()
+                .and_then(|()| blocks.get_mut().flush()),
+        };
+        if let Err(e) = flushed {
+            warn!(
+                path = format!("{}", self.path.display()),
+                "Could not flush capture sink: {e}"
+            );
+        }
+    }
+
+    /// Writes the rest of the file, which is the index for compressed files. Nothing may be written afterwards.
+    fn finish(&mut self) -> std::io::Result<()> {
+        match &mut self.writer {
+            SinkWriter::Plain(writer) => writer.flush(),
+            SinkWriter::Compressed(blocks) => blocks.finish(),
+        }
+    }
+
+    fn close(&mut self) {
+        if let Err(e) = self.finish() {
+            warn!(
+                path = format!("{}", self.path.display()),
+                "Could not finish capture sink: {e}"
+            );
+        }
+    }
+}
+
+/// The path of an older file of a sink, see [RotationPolicy::keep].
+pub fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{index}"));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use photon_lib::{
+        indexmap::indexmap,
+        photon_message::{EventData, PhotonMessage},
+    };
+
+    use super::{rotated_path, CaptureSink, CaptureSinks, RotationPolicy};
+    use crate::{
+        inspect::{
+            capture::{Capture, CaptureFormat},
+            CapturedMessage, Query,
+        },
+        proxy::{Direction, WebSocketServer},
+    };
+
+    fn event(i: u64, server: WebSocketServer, code: u8) -> CapturedMessage {
+        let mut raw = vec![];
+        PhotonMessage::EventData(EventData {
+            code,
+            parameters: indexmap! {},
+        })
+        .to_websocket_bytes(&mut raw)
+        .unwrap();
+        CapturedMessage {
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_665_000_000_000 + i),
+            server,
+            direction: Direction::ServerToClient,
+            raw,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bfhax-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sink(name: &str, filter: Option<&str>, path: PathBuf) -> CaptureSink {
+        CaptureSink {
+            name: name.into(),
+            filter: filter.map(|f| Query::parse(f).unwrap()),
+            path,
+            rotation: RotationPolicy::default(),
+            format: CaptureFormat::default(),
+        }
+    }
+
+    #[test]
+    fn filtered_sink_gets_a_subset() {
+        let dir = temp_dir("capture-sinks");
+        let mut sinks = CaptureSinks::default();
+        sinks.add(sink("all", None, dir.join("all.bfhc"))).unwrap();
+        sinks
+            .add(sink(
+                "rpcs",
+                Some("server:game code:200"),
+                dir.join("rpcs.bfhc"),
+            ))
+            .unwrap();
+
+        let messages = (0..30)
+            .map(|i| match i % 3 {
+                0 => event(i, WebSocketServer::GameServer, 200),
+                1 => event(i, WebSocketServer::GameServer, 201),
+                _ => event(i, WebSocketServer::LobbyServer, 200),
+            })
+            .collect::<Vec<_>>();
+        for message in &messages {
+            sinks.record(message);
+        }
+        sinks.flush();
+
+        let all = Capture::load(&dir.join("all.bfhc")).unwrap();
+        let rpcs = Capture::load(&dir.join("rpcs.bfhc")).unwrap();
+        assert_eq!(all.messages.len(), 30);
+        assert_eq!(rpcs.messages.len(), 10);
+        let timestamps = all.messages.iter().map(|m| m.timestamp).collect::<Vec<_>>();
+        for message in &rpcs.messages {
+            assert!(timestamps.contains(&message.timestamp));
+            assert_eq!(message.server, WebSocketServer::GameServer);
+            assert_eq!(message.raw, messages[0].raw);
+        }
+
+        // removed sinks stop receiving messages, the others carry on
+        assert!(sinks.remove("rpcs"));
+        assert!(!sinks.remove("rpcs"));
+        sinks.record(&messages[0]);
+        drop(sinks);
+        assert_eq!(
+            Capture::load(&dir.join("all.bfhc")).unwrap().messages.len(),
+            31
+        );
+        assert_eq!(
+            Capture::load(&dir.join("rpcs.bfhc"))
+                .unwrap()
+                .messages
+                .len(),
+            10
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_files() {
+        let dir = temp_dir("capture-rotation");
+        let path = dir.join("rotating.bfhc");
+        let message = event(0, WebSocketServer::GameServer, 200);
+        // the header and three messages fit in a file
+        let record_len = 14 + message.raw.len() as u64;
+        let mut sinks = CaptureSinks::default();
+        sinks
+            .add(CaptureSink {
+                rotation: RotationPolicy {
+                    max_bytes: Some(5 + 3 * record_len),
+                    keep: 2,
+                },
+                format: CaptureFormat::Plain,
+                ..sink("rotating", None, path.clone())
+            })
+            .unwrap();
+        for _ in 0..11 {
+            sinks.record(&message);
+        }
+        sinks.flush();
+
+        // 11 messages make 4 files of which the oldest was deleted
+        let count = |path: PathBuf| Capture::load(&path).unwrap().messages.len();
+        assert_eq!(count(path.clone()), 2);
+        assert_eq!(count(rotated_path(&path, 1)), 3);
+        assert_eq!(count(rotated_path(&path, 2)), 3);
+        assert!(!rotated_path(&path, 3).exists());
+
+        drop(sinks);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
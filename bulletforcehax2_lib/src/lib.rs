@@ -1,10 +1,38 @@
 // allow match over single value, as it is used frequently for matching on photon messages
 #![allow(clippy::single_match)]
 
+pub mod config;
+#[cfg(feature = "proxy")]
+pub mod diagnostics;
+pub mod error;
+#[cfg(feature = "proxy")]
 pub mod hax;
+/// Without the `proxy` feature, only the lobby rewrites are available, as pure functions of the messages.
+#[cfg(not(feature = "proxy"))]
+pub mod hax {
+    pub mod bandwidth;
+    pub mod game_variant;
+    pub mod lobby_sort;
+    pub mod room_names;
+    pub mod room_notes;
+    pub mod transforms;
+}
+#[cfg(feature = "proxy")]
+pub mod inspect;
 pub mod protocol;
 pub(crate) mod proxy;
+#[cfg(feature = "otel")]
+pub mod telemetry;
+pub mod testgen;
+#[cfg(all(test, feature = "proxy"))]
+pub(crate) mod testsupport;
+#[cfg(feature = "proxy")]
 pub mod version_scraper;
 
+pub use error::HaxError;
 pub use photon_lib::indexmap;
+#[cfg(feature = "proxy")]
+pub use proxy::listeners::{ProxyConfig, ProxyConfigChange};
+pub use proxy::{Direction, WebSocketServer};
+#[cfg(feature = "proxy")]
 pub use tokio_tungstenite::tungstenite;
@@ -0,0 +1,220 @@
+//! Decoding of the weapons and camos players spawn with.
+//!
+//! The loadout is sent as actor properties when it changes, and as part of the instantiation data of the player's
+//! body when spawning. Both only hold numeric ids, which are looked up in [WEAPON_NAMES] and [CAMO_NAMES]. Ids that
+//! aren't listed are kept as-is, so drift reports can show them after a game update.
+
+use std::fmt::Display;
+
+use photon_lib::photon_data_type::PhotonDataType;
+
+/// The actor property holding the id of the primary weapon.
+pub const PRIMARY_WEAPON_PROPERTY: &str = "primaryWeapon";
+/// The actor property holding the id of the secondary weapon.
+pub const SECONDARY_WEAPON_PROPERTY: &str = "secondaryWeapon";
+/// The actor property holding the id of the camo of the primary weapon.
+pub const CAMO_PROPERTY: &str = "weaponCamo";
+
+/// The index of the loadout in the instantiation data of a `PlayerBody`. It is a byte array of the primary weapon,
+/// secondary weapon and camo ids.
+pub const INSTANTIATION_LOADOUT_INDEX: usize = 0;
+
+/// The weapons seen in traffic so far, by id. Extend this when drift reports show unknown weapon ids.
+pub const WEAPON_NAMES: &[(i32, &str)] = &[
+    (0, "M4A1"),
+    (1, "AK-12"),
+    (2, "MP5"),
+    (3, "Kriss Vector"),
+    (4, "M14 EBR"),
+    (5, "Remington 870"),
+    (6, "Barrett M98B"),
+    (7, "SCAR-H"),
+    (8, "M249"),
+    (9, "Glock 18"),
+    (10, "Desert Eagle"),
+    (11, "M9"),
+    (12, "Knife"),
+];
+
+/// The weapon camos seen in traffic so far, by id. Extend this when drift reports show unknown camo ids.
+pub const CAMO_NAMES: &[(i32, &str)] = &[
+    (0, "None"),
+    (1, "Woodland"),
+    (2, "Desert"),
+    (3, "Urban"),
+    (4, "Gold"),
+];
+
+fn lookup(table: &[(i32, &'static str)], id: i32) -> Option<&'static str> {
+    table.iter().find(|(i, _)| *i == id).map(|(_, name)| *name)
+}
+
+/// A weapon or camo id, with its name if it is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadoutItem {
+    pub id: i32,
+    /// [None] if the id is not in the table, likely because the game added it in an update.
+    pub name: Option<&'static str>,
+}
+
+impl LoadoutItem {
+    pub fn weapon(id: i32) -> Self {
+        Self {
+            id,
+            name: lookup(WEAPON_NAMES, id),
+        }
+    }
+
+    pub fn camo(id: i32) -> Self {
+        Self {
+            id,
+            name: lookup(CAMO_NAMES, id),
+        }
+    }
+
+    pub fn is_known(&self) -> bool {
+        self.name.is_some()
+    }
+}
+
+impl Display for LoadoutItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "{name}"),
+            None => write!(f, "unknown #{}", self.id),
+        }
+    }
+}
+
+/// The weapons a player spawned with. Parts that weren't sent yet are [None].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Loadout {
+    pub primary: Option<LoadoutItem>,
+    pub secondary: Option<LoadoutItem>,
+    pub camo: Option<LoadoutItem>,
+}
+
+impl Loadout {
+    /// Takes the parts of another loadout that are known.
+    pub fn merge(&mut self, other: Loadout) {
+        self.primary = other.primary.or(self.primary.take());
+        self.secondary = other.secondary.or(self.secondary.take());
+        self.camo = other.camo.or(self.camo.take());
+    }
+
+    /// Takes the loadout properties from a set of actor properties, keeping the parts that aren't in it.
+    pub fn merge_properties<'a>(
+        &mut self,
+        properties: impl IntoIterator<Item = (&'a str, &'a PhotonDataType)>,
+    ) {
+        for (key, value) in properties {
+            let id = match as_id(value) {
+                Some(id) => id,
+                None => continue,
+            };
+            match key {
+                PRIMARY_WEAPON_PROPERTY => self.primary = Some(LoadoutItem::weapon(id)),
+                SECONDARY_WEAPON_PROPERTY => self.secondary = Some(LoadoutItem::weapon(id)),
+                CAMO_PROPERTY => self.camo = Some(LoadoutItem::camo(id)),
+                _ => (),
+            }
+        }
+    }
+
+    /// Takes the loadout from the instantiation data of a `PlayerBody`, see [INSTANTIATION_LOADOUT_INDEX].
+    pub fn merge_instantiation_data(&mut self, data: &[PhotonDataType]) {
+        let ids = match data.get(INSTANTIATION_LOADOUT_INDEX) {
+            Some(PhotonDataType::ByteArray(ids)) => ids,
+            _ => return,
+        };
+        if let Some(id) = ids.first() {
+            self.primary = Some(LoadoutItem::weapon(*id as i32));
+        }
+        if let Some(id) = ids.get(1) {
+            self.secondary = Some(LoadoutItem::weapon(*id as i32));
+        }
+        if let Some(id) = ids.get(2) {
+            self.camo = Some(LoadoutItem::camo(*id as i32));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// The weapon ids that are not in [WEAPON_NAMES].
+    pub fn unknown_weapon_ids(&self) -> impl Iterator<Item = i32> + '_ {
+        [self.primary, self.secondary]
+            .into_iter()
+            .flatten()
+            .filter(|item| !item.is_known())
+            .map(|item| item.id)
+    }
+
+    /// The camo id, if it is not in [CAMO_NAMES].
+    pub fn unknown_camo_id(&self) -> Option<i32> {
+        self.camo
+            .filter(|item| !item.is_known())
+            .map(|item| item.id)
+    }
+}
+
+impl Display for Loadout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let unknown = || "?".to_string();
+        write!(
+            f,
+            "{} / {}",
+            self.primary.map_or_else(unknown, |w| w.to_string()),
+            self.secondary.map_or_else(unknown, |w| w.to_string()),
+        )?;
+        if let Some(camo) = self.camo {
+            write!(f, " ({camo} camo)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Ids are sent as whatever integer type fits them.
+fn as_id(value: &PhotonDataType) -> Option<i32> {
+    match value {
+        PhotonDataType::Byte(x) => Some(*x as i32),
+        PhotonDataType::Short(x) => Some(*x as i32),
+        PhotonDataType::Integer(x) => Some(*x),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::photon_data_type::PhotonDataType;
+
+    use super::{Loadout, LoadoutItem, CAMO_PROPERTY, PRIMARY_WEAPON_PROPERTY};
+
+    #[test]
+    fn decodes_properties_and_instantiation() {
+        let mut loadout = Loadout::default();
+        assert!(loadout.is_empty());
+
+        loadout.merge_instantiation_data(&[PhotonDataType::ByteArray(vec![1, 9, 200])]);
+        assert_eq!(loadout.primary, Some(LoadoutItem::weapon(1)));
+        assert_eq!(loadout.to_string(), "AK-12 / Glock 18 (unknown #200 camo)");
+        assert_eq!(loadout.unknown_camo_id(), Some(200));
+
+        let primary = PhotonDataType::Short(99);
+        let camo = PhotonDataType::Integer(4);
+        let other = PhotonDataType::Byte(1);
+        loadout.merge_properties([
+            (PRIMARY_WEAPON_PROPERTY, &primary),
+            (CAMO_PROPERTY, &camo),
+            ("teamNumber", &other),
+        ]);
+        assert_eq!(loadout.to_string(), "unknown #99 / Glock 18 (Gold camo)");
+        assert_eq!(loadout.unknown_weapon_ids().collect::<Vec<_>>(), [99]);
+        assert_eq!(loadout.unknown_camo_id(), None);
+
+        // anything else is ignored
+        loadout.merge_instantiation_data(&[PhotonDataType::Integer(3)]);
+        assert_eq!(loadout.primary.unwrap().id, 99);
+    }
+}
@@ -3,5 +3,9 @@
 #[allow(unused)]
 use photon_lib;
 
+pub mod loadout;
 pub mod player_script;
+pub mod profile;
+pub mod properties;
 pub mod rpc;
+pub mod stream_layout;
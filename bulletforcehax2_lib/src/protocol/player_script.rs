@@ -1,8 +1,11 @@
 use photon_lib::{
+    ordered_float::OrderedFloat,
     photon_data_type::{CustomData, PhotonDataType},
     primitives::{Quaternion, Vector3},
 };
 
+use super::stream_layout::{FieldType, LayoutError, StreamLayout};
+
 #[derive(Debug)]
 pub struct PlayerScript {
     /// Value between 0 and 3600 where 0 is straight ahead.
@@ -39,93 +42,124 @@ pub struct PlayerScript {
     pub rotation: Quaternion,
 }
 
+/// The fields [PlayerScript] reads, with the types they must have in a [StreamLayout].
+const FIELDS: [(&str, FieldType); 21] = [
+    ("pitch", FieldType::Short),
+    ("yaw", FieldType::Short),
+    ("move_angle", FieldType::Short),
+    ("number_of_kills", FieldType::Short),
+    ("number_of_deaths", FieldType::Short),
+    ("number_of_rounds", FieldType::Short),
+    ("ping", FieldType::Short),
+    ("last_local_hit_y", FieldType::Short),
+    ("gun_game_score", FieldType::Short),
+    ("velocity_x", FieldType::Short),
+    ("velocity_y", FieldType::Short),
+    ("velocity_z", FieldType::Short),
+    ("health", FieldType::Short),
+    ("accessory_type", FieldType::Byte),
+    ("barrel_type", FieldType::Byte),
+    ("sight_type", FieldType::Byte),
+    ("weapon_last_damaged_from", FieldType::Byte),
+    ("bitflags", FieldType::Byte),
+    ("last_damager_id", FieldType::Int),
+    ("position", FieldType::Vector3),
+    ("rotation", FieldType::Quaternion),
+];
+
+/// The fields every layout must have. The others are zero if a layout leaves them out.
+const REQUIRED_FIELDS: [&str; 2] = ["health", "position"];
+
 impl PlayerScript {
-    pub fn from_object_array(objects: &[PhotonDataType]) -> anyhow::Result<Self> {
+    /// Reads a player's stream as described by the layout, see [stream_layout](super::stream_layout).
+    pub fn decode(objects: &[PhotonDataType], layout: &StreamLayout) -> Result<Self, LayoutError> {
+        let stream = layout.decode(objects)?;
+        let short = |name| stream.short(name).unwrap_or_default();
+        let byte = |name| stream.byte(name).unwrap_or_default();
         Ok(Self {
-            pitch: match objects.get(0) {
-                Some(PhotonDataType::Short(x)) => *x,
-                _ => anyhow::bail!("Expected type Short in PlayerScript position 0"),
-            },
-            yaw: match objects.get(1) {
-                Some(PhotonDataType::Short(x)) => *x,
-                _ => anyhow::bail!("Expected type Short in PlayerScript position 1"),
-            },
-            move_angle: match objects.get(2) {
-                Some(PhotonDataType::Short(x)) => *x,
-                _ => anyhow::bail!("Expected type Short in PlayerScript position 2"),
-            },
-            number_of_kills: match objects.get(3) {
-                Some(PhotonDataType::Short(x)) => *x,
-                _ => anyhow::bail!("Expected type Short in PlayerScript position 3"),
-            },
-            number_of_deaths: match objects.get(4) {
-                Some(PhotonDataType::Short(x)) => *x,
-                _ => anyhow::bail!("Expected type Short in PlayerScript position 4"),
-            },
-            number_of_rounds: match objects.get(5) {
-                Some(PhotonDataType::Short(x)) => *x,
-                _ => anyhow::bail!("Expected type Short in PlayerScript position 5"),
-            },
-            ping: match objects.get(6) {
-                Some(PhotonDataType::Short(x)) => *x,
-                _ => anyhow::bail!("Expected type Short in PlayerScript position 6"),
-            },
-            last_local_hit_y: match objects.get(7) {
-                Some(PhotonDataType::Short(x)) => *x,
-                _ => anyhow::bail!("Expected type Short in PlayerScript position 7"),
-            },
-            gun_game_score: match objects.get(8) {
-                Some(PhotonDataType::Short(x)) => *x,
-                _ => anyhow::bail!("Expected type Short in PlayerScript position 8"),
-            },
-            velocity_x: match objects.get(9) {
-                Some(PhotonDataType::Short(x)) => *x,
-                _ => anyhow::bail!("Expected type Short in PlayerScript position 9"),
-            },
-            velocity_y: match objects.get(10) {
-                Some(PhotonDataType::Short(x)) => *x,
-                _ => anyhow::bail!("Expected type Short in PlayerScript position 10"),
-            },
-            velocity_z: match objects.get(11) {
-                Some(PhotonDataType::Short(x)) => *x,
-                _ => anyhow::bail!("Expected type Short in PlayerScript position 11"),
-            },
-            health: match objects.get(12) {
-                Some(PhotonDataType::Short(x)) => *x,
-                _ => anyhow::bail!("Expected type Short in PlayerScript position 12"),
-            },
-            accessory_type: match objects.get(13) {
-                Some(PhotonDataType::Byte(x)) => *x,
-                _ => anyhow::bail!("Expected type Byte in PlayerScript position 13"),
-            },
-            barrel_type: match objects.get(14) {
-                Some(PhotonDataType::Byte(x)) => *x,
-                _ => anyhow::bail!("Expected type Byte in PlayerScript position 14"),
-            },
-            sight_type: match objects.get(15) {
-                Some(PhotonDataType::Byte(x)) => *x,
-                _ => anyhow::bail!("Expected type Byte in PlayerScript position 15"),
-            },
-            weapon_last_damaged_from: match objects.get(16) {
-                Some(PhotonDataType::Byte(x)) => *x,
-                _ => anyhow::bail!("Expected type Byte in PlayerScript position 16"),
-            },
-            bitflags: match objects.get(17) {
-                Some(PhotonDataType::Byte(x)) => *x,
-                _ => anyhow::bail!("Expected type Byte in PlayerScript position 17"),
-            },
-            last_damager_id: match objects.get(18) {
-                Some(PhotonDataType::Integer(x)) => *x,
-                _ => anyhow::bail!("Expected type Integer in PlayerScript position 18"),
-            },
-            position: match objects.get(19) {
-                Some(PhotonDataType::Custom(CustomData::Vector3(x))) => x.clone(),
-                _ => anyhow::bail!("Expected type Vector3 in PlayerScript position 19"),
-            },
-            rotation: match objects.get(20) {
-                Some(PhotonDataType::Custom(CustomData::Quaternion(x))) => x.clone(),
-                _ => anyhow::bail!("Expected type Quaternion in PlayerScript position 20"),
-            },
+            pitch: short("pitch"),
+            yaw: short("yaw"),
+            move_angle: short("move_angle"),
+            number_of_kills: short("number_of_kills"),
+            number_of_deaths: short("number_of_deaths"),
+            number_of_rounds: short("number_of_rounds"),
+            ping: short("ping"),
+            last_local_hit_y: short("last_local_hit_y"),
+            gun_game_score: short("gun_game_score"),
+            velocity_x: short("velocity_x"),
+            velocity_y: short("velocity_y"),
+            velocity_z: short("velocity_z"),
+            health: stream
+                .short("health")
+                .ok_or(LayoutError::MissingField("health"))?,
+            accessory_type: byte("accessory_type"),
+            barrel_type: byte("barrel_type"),
+            sight_type: byte("sight_type"),
+            weapon_last_damaged_from: byte("weapon_last_damaged_from"),
+            bitflags: byte("bitflags"),
+            last_damager_id: stream.int("last_damager_id").unwrap_or_default(),
+            position: stream
+                .vector3("position")
+                .cloned()
+                .ok_or(LayoutError::MissingField("position"))?,
+            rotation: stream.quaternion("rotation").cloned().unwrap_or(Quaternion(
+                OrderedFloat(0.0),
+                OrderedFloat(0.0),
+                OrderedFloat(0.0),
+                OrderedFloat(1.0),
+            )),
         })
     }
+
+    /// Checks that the layout is [valid](StreamLayout::validate), has the required fields and gives the fields
+    /// [PlayerScript] reads the right types.
+    pub fn check_layout(layout: &StreamLayout) -> Result<(), LayoutError> {
+        layout.validate()?;
+        if let Some(missing) = REQUIRED_FIELDS
+            .into_iter()
+            .find(|name| layout.field(name).is_none())
+        {
+            return Err(LayoutError::MissingField(missing));
+        }
+        for (name, expected) in FIELDS {
+            match layout.field(name) {
+                Some(field) if field.kind != expected => {
+                    return Err(LayoutError::FieldType {
+                        field: name,
+                        expected,
+                        found: field.kind.clone(),
+                    })
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// The inverse of [Self::decode] with the [built-in](StreamLayout::builtin) layout.
+    pub fn to_object_array(&self) -> Vec<PhotonDataType> {
+        vec![
+            PhotonDataType::Short(self.pitch),
+            PhotonDataType::Short(self.yaw),
+            PhotonDataType::Short(self.move_angle),
+            PhotonDataType::Short(self.number_of_kills),
+            PhotonDataType::Short(self.number_of_deaths),
+            PhotonDataType::Short(self.number_of_rounds),
+            PhotonDataType::Short(self.ping),
+            PhotonDataType::Short(self.last_local_hit_y),
+            PhotonDataType::Short(self.gun_game_score),
+            PhotonDataType::Short(self.velocity_x),
+            PhotonDataType::Short(self.velocity_y),
+            PhotonDataType::Short(self.velocity_z),
+            PhotonDataType::Short(self.health),
+            PhotonDataType::Byte(self.accessory_type),
+            PhotonDataType::Byte(self.barrel_type),
+            PhotonDataType::Byte(self.sight_type),
+            PhotonDataType::Byte(self.weapon_last_damaged_from),
+            PhotonDataType::Byte(self.bitflags),
+            PhotonDataType::Integer(self.last_damager_id),
+            PhotonDataType::Custom(CustomData::Vector3(self.position.clone())),
+            PhotonDataType::Custom(CustomData::Quaternion(self.rotation.clone())),
+        ]
+    }
 }
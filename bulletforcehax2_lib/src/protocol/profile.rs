@@ -0,0 +1,60 @@
+//! What we know about the properties and RPCs used by a version of Bullet Force.
+
+use serde::{Deserialize, Serialize};
+
+use super::{loadout, rpc::METHOD_NAMES};
+
+/// The custom room properties that lobby rooms are known to have.
+///
+/// This list is not complete, drift reports can be used to extend it.
+const ROOM_PROPERTY_KEYS: [&str; 10] = [
+    "roomName",
+    "password",
+    "storeID",
+    "gameVersion",
+    "hostUserID",
+    "ownerID",
+    "switchingmap",
+    "meanKD",
+    "seasonID",
+    "eventcode",
+];
+
+/// The custom actor properties that players are known to have.
+const ACTOR_PROPERTY_KEYS: [&str; 4] = [
+    "teamNumber",
+    loadout::PRIMARY_WEAPON_PROPERTY,
+    loadout::SECONDARY_WEAPON_PROPERTY,
+    loadout::CAMO_PROPERTY,
+];
+
+/// The custom property keys and RPC methods a game version is expected to use.
+///
+/// Only string keys are listed, byte keys are defined by Photon itself and don't change with game updates.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GameProtocolProfile {
+    /// A name to recognize the profile by, usually the game version it was made for.
+    pub name: String,
+    pub room_property_keys: Vec<String>,
+    pub actor_property_keys: Vec<String>,
+    /// The RPC methods, in the order of their index.
+    pub rpc_method_names: Vec<String>,
+}
+
+impl GameProtocolProfile {
+    /// The profile matching the hardcoded protocol knowledge in this crate.
+    pub fn builtin() -> Self {
+        Self {
+            name: "builtin".into(),
+            room_property_keys: ROOM_PROPERTY_KEYS.iter().map(|k| k.to_string()).collect(),
+            actor_property_keys: ACTOR_PROPERTY_KEYS.iter().map(|k| k.to_string()).collect(),
+            rpc_method_names: METHOD_NAMES.iter().map(|k| k.to_string()).collect(),
+        }
+    }
+}
+
+impl Default for GameProtocolProfile {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
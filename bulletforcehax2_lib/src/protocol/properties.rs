@@ -0,0 +1,432 @@
+//! Bullet Force's custom room and actor properties.
+//!
+//! [photon_lib] only interprets the properties Photon itself defines, see
+//! [WellKnownRoomProperties] and [WellKnownActorProperties]. The traits here add accessors for the string-keyed
+//! properties Bullet Force puts next to them. Rooms of the "newfps" variant are listed in the same lobby but use other
+//! keys, the accessors look up the keys of the room's [GameVariant].
+
+use std::{
+    borrow::{Borrow, BorrowMut},
+    fmt::Display,
+    time::Duration,
+};
+
+use photon_lib::{
+    highlevel::structs::{Player, WellKnownActorProperties, WellKnownRoomProperties},
+    photon_data_type::PhotonDataType,
+    PhotonHashmap,
+};
+
+use super::loadout::Loadout;
+
+/// The prefix of the game versions of "newfps" rooms.
+const NEWFPS_VERSION_PREFIX: &str = "newfps-";
+
+/// Custom room properties that may hold the user id of the room's host.
+///
+/// Not every room lists its host, so anything relying on the host only works for rooms that have one of these.
+pub const HOST_USER_ID_PROPERTIES: [&str; 2] = ["hostUserID", "ownerID"];
+
+/// The actor property holding the team a player is on.
+pub const TEAM_NUMBER_PROPERTY: &str = "teamNumber";
+
+/// The room property that is true while the room loads the next map between rounds.
+pub const SWITCHING_MAP_PROPERTY: &str = "switchingmap";
+
+/// The room property holding how long a round lasts, in seconds. Only rooms with a time limit have it.
+pub const ROUND_TIME_PROPERTY: &str = "roundTime";
+
+/// The room property holding the score that ends a round. Only rooms with a score limit have it.
+pub const SCORE_LIMIT_PROPERTY: &str = "scoreLimit";
+
+/// The room property holding the server time the current round started at, in milliseconds. Not every game build
+/// sets it.
+pub const ROUND_START_PROPERTY: &str = "roundStartTime";
+
+/// The actor property holding the Unity platform the client runs on, such as `WebGLPlayer` or `Android`.
+pub const PLATFORM_PROPERTY: &str = "platform";
+
+/// The actor property holding the device model the client runs on. Browsers don't report one.
+pub const DEVICE_MODEL_PROPERTY: &str = "deviceModel";
+
+/// The actor property holding the store the client came from. The server matches players of the web store separately
+/// from the mobile ones.
+pub const STORE_ID_PROPERTY: &str = "storeID";
+
+/// The store id of browser clients.
+pub const WEB_STORE_ID: &str = "BALYZE_WEB";
+
+/// The games whose rooms are listed in the Bullet Force lobby.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GameVariant {
+    #[default]
+    BulletForce,
+    NewFps,
+}
+
+/// The custom room property keys a variant uses.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PropertyKeys {
+    pub room_name: &'static str,
+    pub password: &'static str,
+    pub store_id: &'static str,
+    pub game_version: &'static str,
+    pub map_name: &'static str,
+}
+
+const BULLETFORCE_KEYS: PropertyKeys = PropertyKeys {
+    room_name: "roomName",
+    password: "password",
+    store_id: "storeID",
+    game_version: "gameVersion",
+    map_name: "mapName",
+};
+
+const NEWFPS_KEYS: PropertyKeys = PropertyKeys {
+    room_name: "roomname",
+    password: "password",
+    store_id: "storeid",
+    game_version: "gameversion",
+    map_name: "mapname",
+};
+
+impl GameVariant {
+    /// Tells which variant a client on the given game version plays.
+    pub fn of_version(game_version: &str) -> Self {
+        match game_version.starts_with(NEWFPS_VERSION_PREFIX) {
+            true => GameVariant::NewFps,
+            false => GameVariant::BulletForce,
+        }
+    }
+
+    pub fn keys(self) -> &'static PropertyKeys {
+        match self {
+            GameVariant::BulletForce => &BULLETFORCE_KEYS,
+            GameVariant::NewFps => &NEWFPS_KEYS,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GameVariant::BulletForce => "BulletForce",
+            GameVariant::NewFps => "newfps",
+        }
+    }
+}
+
+/// Reads Bullet Force's custom room properties.
+pub trait BulletForceRoomProperties {
+    /// Tells which variant the room belongs to. Rooms without a known version are assumed to be Bullet Force rooms.
+    fn variant(&self) -> GameVariant;
+    fn room_name(&self) -> Option<&str>;
+    fn password(&self) -> Option<&str>;
+    /// The store the host's client came from, such as `BALYZE_WEB`.
+    fn store_id(&self) -> Option<&str>;
+    fn game_version(&self) -> Option<&str>;
+    fn map_name(&self) -> Option<&str>;
+    /// The user id of the host, if the room lists it.
+    fn host_user_id(&self) -> Option<&str>;
+    /// Whether the room is loading the next map between rounds.
+    fn switching_map(&self) -> Option<bool>;
+    /// How long a round lasts, if the room has a time limit.
+    fn round_time(&self) -> Option<Duration>;
+    /// The score that ends a round, if the room has a score limit.
+    fn score_limit(&self) -> Option<i32>;
+    /// The server time the current round started at, in milliseconds.
+    fn round_start_time(&self) -> Option<i32>;
+
+    fn has_password(&self) -> bool {
+        self.password().is_some_and(|p| !p.is_empty())
+    }
+}
+
+impl<M: Borrow<PhotonHashmap>> BulletForceRoomProperties for WellKnownRoomProperties<M> {
+    fn variant(&self) -> GameVariant {
+        match self.custom_str(NEWFPS_KEYS.game_version) {
+            Some(version) if version.starts_with(NEWFPS_VERSION_PREFIX) => GameVariant::NewFps,
+            _ => GameVariant::BulletForce,
+        }
+    }
+
+    fn room_name(&self) -> Option<&str> {
+        self.custom_str(self.variant().keys().room_name)
+    }
+
+    fn password(&self) -> Option<&str> {
+        self.custom_str(self.variant().keys().password)
+    }
+
+    fn store_id(&self) -> Option<&str> {
+        self.custom_str(self.variant().keys().store_id)
+    }
+
+    fn game_version(&self) -> Option<&str> {
+        self.custom_str(self.variant().keys().game_version)
+    }
+
+    fn map_name(&self) -> Option<&str> {
+        self.custom_str(self.variant().keys().map_name)
+    }
+
+    fn host_user_id(&self) -> Option<&str> {
+        HOST_USER_ID_PROPERTIES
+            .iter()
+            .find_map(|property| self.custom_str(property))
+    }
+
+    fn switching_map(&self) -> Option<bool> {
+        match self.custom_property(SWITCHING_MAP_PROPERTY) {
+            Some(PhotonDataType::Boolean(switching)) => Some(*switching),
+            _ => None,
+        }
+    }
+
+    fn round_time(&self) -> Option<Duration> {
+        integer(self.custom_property(ROUND_TIME_PROPERTY)?)
+            .filter(|&secs| secs > 0)
+            .map(|secs| Duration::from_secs(secs as u64))
+    }
+
+    fn score_limit(&self) -> Option<i32> {
+        integer(self.custom_property(SCORE_LIMIT_PROPERTY)?).filter(|&limit| limit > 0)
+    }
+
+    fn round_start_time(&self) -> Option<i32> {
+        integer(self.custom_property(ROUND_START_PROPERTY)?)
+    }
+}
+
+/// Reads a number property, whichever integer type the client picked for it.
+fn integer(value: &PhotonDataType) -> Option<i32> {
+    match value {
+        PhotonDataType::Byte(v) => Some(*v as i32),
+        PhotonDataType::Short(v) => Some(*v as i32),
+        PhotonDataType::Integer(v) => Some(*v),
+        _ => None,
+    }
+}
+
+/// Edits Bullet Force's custom room properties in place. Properties the room doesn't have are [None].
+pub trait BulletForceRoomPropertiesMut: BulletForceRoomProperties {
+    fn room_name_mut(&mut self) -> Option<&mut String>;
+    fn password_mut(&mut self) -> Option<&mut String>;
+    fn store_id_mut(&mut self) -> Option<&mut String>;
+    fn game_version_mut(&mut self) -> Option<&mut String>;
+}
+
+impl<M: BorrowMut<PhotonHashmap>> BulletForceRoomPropertiesMut for WellKnownRoomProperties<M> {
+    fn room_name_mut(&mut self) -> Option<&mut String> {
+        self.custom_str_mut(self.variant().keys().room_name)
+    }
+
+    fn password_mut(&mut self) -> Option<&mut String> {
+        self.custom_str_mut(self.variant().keys().password)
+    }
+
+    fn store_id_mut(&mut self) -> Option<&mut String> {
+        self.custom_str_mut(self.variant().keys().store_id)
+    }
+
+    fn game_version_mut(&mut self) -> Option<&mut String> {
+        self.custom_str_mut(self.variant().keys().game_version)
+    }
+}
+
+/// What a client reports about the device it runs on, from its actor properties.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ClientPlatform {
+    pub platform: Option<String>,
+    pub device_model: Option<String>,
+    pub store_id: Option<String>,
+}
+
+impl ClientPlatform {
+    fn from_properties<'a>(mut get: impl FnMut(&str) -> Option<&'a PhotonDataType>) -> Self {
+        let mut string = |key| match get(key) {
+            Some(PhotonDataType::String(value)) => Some(value.clone()),
+            _ => None,
+        };
+        Self {
+            platform: string(PLATFORM_PROPERTY),
+            device_model: string(DEVICE_MODEL_PROPERTY),
+            store_id: string(STORE_ID_PROPERTY),
+        }
+    }
+
+    /// Whether the client is a mobile one, going by its store and otherwise its platform. None if it reported neither.
+    pub fn is_mobile(&self) -> Option<bool> {
+        match (self.store_id.as_deref(), self.platform.as_deref()) {
+            (Some(store_id), _) => Some(store_id != WEB_STORE_ID),
+            (None, Some(platform)) => Some(matches!(platform, "Android" | "IPhonePlayer")),
+            (None, None) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Takes over the values the other one has, as properties are only sent when they change.
+    pub fn merge(&mut self, other: ClientPlatform) {
+        if other.platform.is_some() {
+            self.platform = other.platform;
+        }
+        if other.device_model.is_some() {
+            self.device_model = other.device_model;
+        }
+        if other.store_id.is_some() {
+            self.store_id = other.store_id;
+        }
+    }
+}
+
+impl Display for ClientPlatform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts = [&self.platform, &self.device_model, &self.store_id]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        match parts.is_empty() {
+            true => write!(f, "unknown"),
+            false => write!(f, "{}", parts.join(", ")),
+        }
+    }
+}
+
+/// Reads Bullet Force's custom actor properties.
+pub trait BulletForceActorProperties {
+    fn team_number(&self) -> Option<u8>;
+    /// The platform, device and store the client reported.
+    fn client_platform(&self) -> ClientPlatform;
+    /// The parts of the loadout the properties hold.
+    fn loadout(&self) -> Loadout;
+}
+
+impl BulletForceActorProperties for Player {
+    fn team_number(&self) -> Option<u8> {
+        match self.custom_properties.get(TEAM_NUMBER_PROPERTY) {
+            Some(PhotonDataType::Byte(team_number)) => Some(*team_number),
+            _ => None,
+        }
+    }
+
+    fn client_platform(&self) -> ClientPlatform {
+        ClientPlatform::from_properties(|key| self.custom_properties.get(key))
+    }
+
+    fn loadout(&self) -> Loadout {
+        let mut loadout = Loadout::default();
+        loadout.merge_properties(
+            self.custom_properties
+                .iter()
+                .map(|(key, value)| (key.as_str(), value)),
+        );
+        loadout
+    }
+}
+
+impl<M: Borrow<PhotonHashmap>> BulletForceActorProperties for WellKnownActorProperties<M> {
+    fn team_number(&self) -> Option<u8> {
+        match self.custom_property(TEAM_NUMBER_PROPERTY) {
+            Some(PhotonDataType::Byte(team_number)) => Some(*team_number),
+            _ => None,
+        }
+    }
+
+    fn client_platform(&self) -> ClientPlatform {
+        ClientPlatform::from_properties(|key| self.custom_property(key))
+    }
+
+    fn loadout(&self) -> Loadout {
+        let mut loadout = Loadout::default();
+        loadout.merge_properties(self.0.borrow().iter().filter_map(|(key, value)| match key {
+            PhotonDataType::String(key) => Some((key.as_str(), value)),
+            _ => None,
+        }));
+        loadout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::{
+        highlevel::{
+            constants::actor_properties,
+            structs::{Player, WellKnownActorProperties, WellKnownRoomProperties},
+            PhotonMapConversion,
+        },
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+    };
+
+    use super::{
+        BulletForceActorProperties, BulletForceRoomProperties, BulletForceRoomPropertiesMut,
+        GameVariant,
+    };
+    use crate::protocol::loadout::PRIMARY_WEAPON_PROPERTY;
+
+    fn string(s: &str) -> PhotonDataType {
+        PhotonDataType::String(s.into())
+    }
+
+    #[test]
+    fn room_properties_of_both_variants() {
+        let mut bulletforce = indexmap! {
+            string("roomName") => string("Pros"),
+            string("password") => string(""),
+            string("gameVersion") => string("1.90.0"),
+            string("hostUserID") => string("8a2b4f"),
+        };
+        let mut newfps = indexmap! {
+            string("roomname") => string("Chill"),
+            string("password") => string("1234"),
+            string("gameversion") => string("newfps-0.4.1"),
+        };
+
+        let room = WellKnownRoomProperties::new(&bulletforce);
+        assert_eq!(room.variant(), GameVariant::BulletForce);
+        assert_eq!(room.room_name(), Some("Pros"));
+        assert!(!room.has_password());
+        assert_eq!(room.host_user_id(), Some("8a2b4f"));
+        let room = WellKnownRoomProperties::new(&newfps);
+        assert_eq!(room.variant(), GameVariant::NewFps);
+        assert_eq!(room.room_name(), Some("Chill"));
+        assert_eq!(room.game_version(), Some("newfps-0.4.1"));
+        assert!(room.has_password());
+        assert_eq!(room.store_id(), None);
+
+        // the prefix alone doesn't make a Bullet Force room a newfps one
+        bulletforce[&string("gameVersion")] = string("newfps-0.4.1");
+        let mut room = WellKnownRoomProperties::new(&mut bulletforce);
+        assert_eq!(room.variant(), GameVariant::BulletForce);
+        room.room_name_mut().unwrap().insert_str(0, "[*] ");
+        assert_eq!(bulletforce[&string("roomName")], string("[*] Pros"));
+        WellKnownRoomProperties::new(&mut newfps)
+            .password_mut()
+            .unwrap()
+            .clear();
+        assert_eq!(newfps[&string("password")], string(""));
+        assert_eq!(GameVariant::of_version("newfps-0.4.1"), GameVariant::NewFps);
+    }
+
+    #[test]
+    fn actor_properties() {
+        let properties = indexmap! {
+            PhotonDataType::Byte(actor_properties::PLAYER_NAME) => string("guest"),
+            string("teamNumber") => PhotonDataType::Byte(1),
+            string(PRIMARY_WEAPON_PROPERTY) => PhotonDataType::Integer(6),
+            string("platform") => string("IPhonePlayer"),
+        };
+        let view = WellKnownActorProperties::new(&properties);
+        assert_eq!(view.team_number(), Some(1));
+        assert_eq!(view.client_platform().is_mobile(), Some(true));
+        assert_eq!(view.client_platform().to_string(), "IPhonePlayer");
+        assert_eq!(view.loadout().primary.unwrap().to_string(), "Barrett M98B");
+
+        let player = Player::from_map(&mut properties.clone()).unwrap();
+        assert_eq!(player.team_number(), Some(1));
+        assert_eq!(player.loadout(), view.loadout());
+        assert_eq!(player.client_platform(), view.client_platform());
+    }
+}
@@ -87,16 +87,49 @@ pub const METHOD_NAMES: [&str; 80] = [
     "RpcForceKillstreak",
 ];
 
+/// RPC methods that only have a visual effect on other clients, and can be safely ignored.
+///
+/// Bullet Force has no dedicated emote or voice line RPCs, so these are the closest thing to them.
+pub const COSMETIC_METHOD_NAMES: [&str; 4] = [
+    "ColorRpc",
+    "RpcShowPerkMessage",
+    "ShowDebugCapsule",
+    "WeaponCamoChanged",
+];
+
+/// Whether the given RPC method is purely cosmetic. See [COSMETIC_METHOD_NAMES].
+pub fn is_cosmetic_method(method_name: &str) -> bool {
+    COSMETIC_METHOD_NAMES.contains(&method_name)
+}
+
 /// Get the method name of an RPC call.
 ///
 /// This function gets the string method name if it is present, or otherwise resolves the method index using the
 /// hardcoded [METHOD_NAMES] list.
 pub fn get_rpc_method_name(data: &RpcCall) -> anyhow::Result<Cow<str>> {
     if let Some(idx) = data.rpc_index {
-        Ok(Cow::Borrowed(METHOD_NAMES[idx as usize]))
+        match METHOD_NAMES.get(idx as usize) {
+            Some(name) => Ok(Cow::Borrowed(name)),
+            None => anyhow::bail!("unknown method index {idx}"),
+        }
     } else if let Some(method_name) = &data.method_name {
         Ok(Cow::Owned(method_name.clone()))
     } else {
         anyhow::bail!("malformatted call, neither method name nor index was present")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{is_cosmetic_method, COSMETIC_METHOD_NAMES, METHOD_NAMES};
+
+    #[test]
+    fn cosmetic_methods_are_known_methods() {
+        for name in COSMETIC_METHOD_NAMES {
+            assert!(METHOD_NAMES.contains(&name), "{name}");
+            assert!(is_cosmetic_method(name));
+        }
+        assert!(!is_cosmetic_method("RpcShoot"));
+        assert!(!is_cosmetic_method("colorrpc"));
+    }
+}
@@ -0,0 +1,616 @@
+//! Declarative descriptions of the data stream a player's photon view serializes.
+//!
+//! The values a player sends in SEND_SERIALIZE events change between game updates. Instead of hardcoding them, a
+//! [StreamLayout] lists them in order, each with a name and a [FieldType], and [PlayerScript] picks its values out of
+//! the decoded stream by name. Layouts are kept by the name of the [protocol profile](super::profile) they belong to.
+//! The [built-in](StreamLayouts::builtin) ones ship with the program, and a user file in the same format adds layouts
+//! or replaces built-in ones:
+//!
+//! ```json
+//! {
+//!   "schema_version": 1,
+//!   "layouts": {
+//!     "1.99": [
+//!       { "name": "position", "type": "vector3" },
+//!       { "name": "health", "type": "short" },
+//!       { "name": "skin_length", "type": "byte" },
+//!       { "name": "skin", "type": "byte_array", "length": { "field": "skin_length" } }
+//!     ]
+//!   }
+//! }
+//! ```
+//!
+//! To build a layout for a new version, [layout_fit](crate::inspect::layout_fit) checks a guess against a capture.
+
+use std::{collections::BTreeMap, fmt::Display, path::Path};
+
+use photon_lib::{
+    photon_data_type::{CustomData, PhotonDataType},
+    primitives::{Quaternion, Vector3},
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use super::player_script::PlayerScript;
+use crate::config::versioned::{self, Migration, Versioned};
+
+/// The layouts that ship with the program.
+const BUILTIN: &str = include_str!("../../data/stream_layouts.json");
+
+/// The layout used for profiles without one of their own.
+pub const BUILTIN_LAYOUT: &str = "builtin";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FieldType {
+    Bool,
+    Byte,
+    Short,
+    Int,
+    Float,
+    Vector3,
+    Quaternion,
+    ByteArray {
+        #[serde(default)]
+        length: LengthSource,
+    },
+}
+
+impl FieldType {
+    fn matches(&self, value: &PhotonDataType) -> bool {
+        matches!(
+            (self, value),
+            (FieldType::Bool, PhotonDataType::Boolean(_))
+                | (FieldType::Byte, PhotonDataType::Byte(_))
+                | (FieldType::Short, PhotonDataType::Short(_))
+                | (FieldType::Int, PhotonDataType::Integer(_))
+                | (FieldType::Float, PhotonDataType::Float(_))
+                | (
+                    FieldType::Vector3,
+                    PhotonDataType::Custom(CustomData::Vector3(_))
+                )
+                | (
+                    FieldType::Quaternion,
+                    PhotonDataType::Custom(CustomData::Quaternion(_))
+                )
+                | (FieldType::ByteArray { .. }, PhotonDataType::ByteArray(_))
+        )
+    }
+
+    fn is_integer(&self) -> bool {
+        matches!(self, FieldType::Byte | FieldType::Short | FieldType::Int)
+    }
+}
+
+impl Display for FieldType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FieldType::Bool => "bool",
+            FieldType::Byte => "byte",
+            FieldType::Short => "short",
+            FieldType::Int => "int",
+            FieldType::Float => "float",
+            FieldType::Vector3 => "vector3",
+            FieldType::Quaternion => "quaternion",
+            FieldType::ByteArray { .. } => "byte array",
+        })
+    }
+}
+
+/// The type of a value, named like the [FieldType] that matches it.
+fn type_name(value: &PhotonDataType) -> String {
+    match value {
+        PhotonDataType::Boolean(_) => "bool".into(),
+        PhotonDataType::Byte(_) => "byte".into(),
+        PhotonDataType::Short(_) => "short".into(),
+        PhotonDataType::Integer(_) => "int".into(),
+        PhotonDataType::Float(_) => "float".into(),
+        PhotonDataType::Custom(CustomData::Vector3(_)) => "vector3".into(),
+        PhotonDataType::Custom(CustomData::Quaternion(_)) => "quaternion".into(),
+        PhotonDataType::ByteArray(_) => "byte array".into(),
+        PhotonDataType::Null => "null".into(),
+        value => format!("type 0x{:02X}", value.get_type_byte()),
+    }
+}
+
+/// How many bytes a [FieldType::ByteArray] holds.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LengthSource {
+    #[default]
+    Any,
+    Fixed(usize),
+    /// The value of an integer field before the array.
+    Field(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamField {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: FieldType,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum LayoutError {
+    #[error("the stream ends before {field} at position {index}")]
+    Missing { index: usize, field: String },
+    #[error("expected {expected} for {field} at position {index}, found {found}")]
+    WrongType {
+        index: usize,
+        field: String,
+        expected: FieldType,
+        found: String,
+    },
+    #[error("expected {expected} bytes for {field}, found {found}")]
+    WrongLength {
+        field: String,
+        expected: i64,
+        found: usize,
+    },
+    #[error("the stream has {found} values, but the layout only {expected}")]
+    TooLong { expected: usize, found: usize },
+    #[error("{0} is in the layout more than once")]
+    DuplicateField(String),
+    #[error(
+        "the length of {field} comes from {length_field}, which isn't an integer field before it"
+    )]
+    InvalidLengthField { field: String, length_field: String },
+    #[error("the layout has no {0} field")]
+    MissingField(&'static str),
+    #[error("{field} must be a {expected}, not a {found}")]
+    FieldType {
+        field: &'static str,
+        expected: FieldType,
+        found: FieldType,
+    },
+}
+
+/// The values of a stream, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct StreamLayout {
+    pub fields: Vec<StreamField>,
+}
+
+impl StreamLayout {
+    /// The layout of the game version this crate was written for.
+    pub fn builtin() -> Self {
+        StreamLayouts::builtin()
+            .layouts
+            .remove(BUILTIN_LAYOUT)
+            .expect("built-in stream layouts should have the built-in layout")
+    }
+
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|f| f.name == name)
+    }
+
+    pub fn field(&self, name: &str) -> Option<&StreamField> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+
+    /// Checks that field names are unique, and that byte arrays take their length from an integer field before them.
+    pub fn validate(&self) -> Result<(), LayoutError> {
+        for (index, field) in self.fields.iter().enumerate() {
+            if self.fields[..index].iter().any(|f| f.name == field.name) {
+                return Err(LayoutError::DuplicateField(field.name.clone()));
+            }
+            if let FieldType::ByteArray {
+                length: LengthSource::Field(length_field),
+            } = &field.kind
+            {
+                let valid = self.fields[..index]
+                    .iter()
+                    .any(|f| &f.name == length_field && f.kind.is_integer());
+                if !valid {
+                    return Err(LayoutError::InvalidLengthField {
+                        field: field.name.clone(),
+                        length_field: length_field.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the values match the layout, from the first to the last.
+    pub fn decode<'a>(
+        &'a self,
+        values: &'a [PhotonDataType],
+    ) -> Result<DecodedStream<'a>, LayoutError> {
+        let stream = DecodedStream {
+            layout: self,
+            values,
+        };
+        for (index, field) in self.fields.iter().enumerate() {
+            let value = match values.get(index) {
+                Some(value) => value,
+                None => {
+                    return Err(LayoutError::Missing {
+                        index,
+                        field: field.name.clone(),
+                    })
+                }
+            };
+            if !field.kind.matches(value) {
+                return Err(LayoutError::WrongType {
+                    index,
+                    field: field.name.clone(),
+                    expected: field.kind.clone(),
+                    found: type_name(value),
+                });
+            }
+            if let (FieldType::ByteArray { length }, PhotonDataType::ByteArray(bytes)) =
+                (&field.kind, value)
+            {
+                let expected = match length {
+                    LengthSource::Any => None,
+                    LengthSource::Fixed(n) => Some(*n as i64),
+                    LengthSource::Field(name) => stream.integer(name),
+                };
+                match expected {
+                    Some(expected) if expected != bytes.len() as i64 => {
+                        return Err(LayoutError::WrongLength {
+                            field: field.name.clone(),
+                            expected,
+                            found: bytes.len(),
+                        })
+                    }
+                    _ => (),
+                }
+            }
+        }
+        if values.len() > self.fields.len() {
+            return Err(LayoutError::TooLong {
+                expected: self.fields.len(),
+                found: values.len(),
+            });
+        }
+        Ok(stream)
+    }
+}
+
+/// A stream that matched a [StreamLayout], to read its values by name. Fields the layout doesn't have are [None].
+#[derive(Debug, Clone, Copy)]
+pub struct DecodedStream<'a> {
+    layout: &'a StreamLayout,
+    values: &'a [PhotonDataType],
+}
+
+impl<'a> DecodedStream<'a> {
+    pub fn get(&self, name: &str) -> Option<&'a PhotonDataType> {
+        self.values.get(self.layout.position(name)?)
+    }
+
+    pub fn bool(&self, name: &str) -> Option<bool> {
+        match self.get(name)? {
+            PhotonDataType::Boolean(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn byte(&self, name: &str) -> Option<u8> {
+        match self.get(name)? {
+            PhotonDataType::Byte(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn short(&self, name: &str) -> Option<i16> {
+        match self.get(name)? {
+            PhotonDataType::Short(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn int(&self, name: &str) -> Option<i32> {
+        match self.get(name)? {
+            PhotonDataType::Integer(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    /// The value of a byte, short or int field.
+    pub fn integer(&self, name: &str) -> Option<i64> {
+        match self.get(name)? {
+            PhotonDataType::Byte(x) => Some(*x as i64),
+            PhotonDataType::Short(x) => Some(*x as i64),
+            PhotonDataType::Integer(x) => Some(*x as i64),
+            _ => None,
+        }
+    }
+
+    pub fn float(&self, name: &str) -> Option<f32> {
+        match self.get(name)? {
+            PhotonDataType::Float(x) => Some(x.0),
+            _ => None,
+        }
+    }
+
+    pub fn vector3(&self, name: &str) -> Option<&'a Vector3> {
+        match self.get(name)? {
+            PhotonDataType::Custom(CustomData::Vector3(x)) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn quaternion(&self, name: &str) -> Option<&'a Quaternion> {
+        match self.get(name)? {
+            PhotonDataType::Custom(CustomData::Quaternion(x)) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn bytes(&self, name: &str) -> Option<&'a [u8]> {
+        match self.get(name)? {
+            PhotonDataType::ByteArray(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct StreamLayoutsFile {
+    #[serde(default)]
+    layouts: BTreeMap<String, StreamLayout>,
+}
+
+impl Versioned for StreamLayoutsFile {
+    const NAME: &'static str = "stream layouts";
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+/// The layouts of the players' streams, by the name of the protocol profile they belong to.
+#[derive(Debug, Clone)]
+pub struct StreamLayouts {
+    layouts: BTreeMap<String, StreamLayout>,
+}
+
+impl Default for StreamLayouts {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl StreamLayouts {
+    /// The layouts that ship with the program.
+    pub fn builtin() -> Self {
+        let mut layouts = Self {
+            layouts: BTreeMap::new(),
+        };
+        let file = versioned::from_slice(BUILTIN.as_bytes())
+            .expect("built-in stream layouts should be valid");
+        layouts.merge(file);
+        layouts
+    }
+
+    /// Loads the built-in layouts and the ones in the given file on top of them.
+    ///
+    /// This never fails. A missing file only leaves the built-in layouts, and a file that can't be read is logged and
+    /// ignored.
+    pub fn load(path: &Path) -> Self {
+        let mut layouts = Self::builtin();
+        match versioned::read_optional(path) {
+            Ok(Some(file)) => layouts.merge(file),
+            Ok(None) => debug!(
+                path = format!("{path:?}"),
+                "No stream layouts file, using the built-in ones"
+            ),
+            Err(e) => warn!(
+                path = format!("{path:?}"),
+                "Could not read stream layouts, using the built-in ones: {e:#}"
+            ),
+        }
+        layouts
+    }
+
+    /// Replaces the layouts in the file. Layouts [PlayerScript] can't be read with are left out.
+    fn merge(&mut self, file: StreamLayoutsFile) {
+        for (profile, layout) in file.layouts {
+            match PlayerScript::check_layout(&layout) {
+                Ok(()) => {
+                    self.layouts.insert(profile, layout);
+                }
+                Err(e) => warn!(profile, "Ignoring invalid stream layout: {e}"),
+            }
+        }
+    }
+
+    /// The profiles that have a layout.
+    pub fn profiles(&self) -> impl Iterator<Item = &str> {
+        self.layouts.keys().map(String::as_str)
+    }
+
+    pub fn get(&self, profile: &str) -> Option<&StreamLayout> {
+        self.layouts.get(profile)
+    }
+
+    /// The layout of a profile, or the [built-in](BUILTIN_LAYOUT) one if it has none.
+    pub fn for_profile(&self, profile: &str) -> &StreamLayout {
+        self.get(profile)
+            .or_else(|| self.get(BUILTIN_LAYOUT))
+            .expect("the built-in stream layout can't be replaced by an invalid one")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::{
+        ordered_float::OrderedFloat,
+        photon_data_type::{CustomData, PhotonDataType},
+        primitives::{Quaternion, Vector3},
+    };
+    use serde_json::json;
+
+    use super::{FieldType, LayoutError, StreamLayout, StreamLayouts, BUILTIN_LAYOUT};
+    use crate::protocol::player_script::PlayerScript;
+
+    fn vector(x: f32, y: f32, z: f32) -> Vector3 {
+        Vector3(OrderedFloat(x), OrderedFloat(y), OrderedFloat(z))
+    }
+
+    /// A made-up newer layout, with fields moved around, a float and a byte array of a given length.
+    fn newer_layout() -> StreamLayout {
+        serde_json::from_value(json!([
+            { "name": "position", "type": "vector3" },
+            { "name": "health", "type": "short" },
+            { "name": "sprinting", "type": "bool" },
+            { "name": "stamina", "type": "float" },
+            { "name": "skin_length", "type": "byte" },
+            { "name": "skin", "type": "byte_array", "length": { "field": "skin_length" } },
+            { "name": "last_damager_id", "type": "int" },
+        ]))
+        .unwrap()
+    }
+
+    fn builtin_stream() -> Vec<PhotonDataType> {
+        let mut stream = vec![PhotonDataType::Short(0); 13];
+        stream[3] = PhotonDataType::Short(7);
+        stream[12] = PhotonDataType::Short(8000);
+        stream.extend(vec![PhotonDataType::Byte(0); 5]);
+        stream.extend([
+            PhotonDataType::Integer(3),
+            PhotonDataType::Custom(CustomData::Vector3(vector(1.0, 2.0, 3.0))),
+            PhotonDataType::Custom(CustomData::Quaternion(Quaternion(
+                OrderedFloat(0.0),
+                OrderedFloat(0.0),
+                OrderedFloat(0.0),
+                OrderedFloat(1.0),
+            ))),
+        ]);
+        stream
+    }
+
+    fn newer_stream(skin: Vec<u8>) -> Vec<PhotonDataType> {
+        vec![
+            PhotonDataType::Custom(CustomData::Vector3(vector(4.0, 5.0, 6.0))),
+            PhotonDataType::Short(2500),
+            PhotonDataType::Boolean(true),
+            PhotonDataType::Float(OrderedFloat(0.5)),
+            PhotonDataType::Byte(3),
+            PhotonDataType::ByteArray(skin),
+            PhotonDataType::Integer(9),
+        ]
+    }
+
+    #[test]
+    fn layouts_decode_their_streams() {
+        let builtin = StreamLayout::builtin();
+        let script = PlayerScript::decode(&builtin_stream(), &builtin).unwrap();
+        assert_eq!(script.number_of_kills, 7);
+        assert_eq!(script.health, 8000);
+        assert_eq!(script.last_damager_id, 3);
+        assert_eq!(script.position, vector(1.0, 2.0, 3.0));
+        assert_eq!(script.to_object_array(), builtin_stream());
+
+        let newer = newer_layout();
+        PlayerScript::check_layout(&newer).unwrap();
+        let stream = newer_stream(vec![1, 2, 3]);
+        let decoded = newer.decode(&stream).unwrap();
+        assert_eq!(decoded.bool("sprinting"), Some(true));
+        assert_eq!(decoded.float("stamina"), Some(0.5));
+        assert_eq!(decoded.bytes("skin"), Some([1, 2, 3].as_slice()));
+        let script = PlayerScript::decode(&stream, &newer).unwrap();
+        assert_eq!(script.health, 2500);
+        assert_eq!(script.last_damager_id, 9);
+        assert_eq!(script.position, vector(4.0, 5.0, 6.0));
+        // not in this layout
+        assert_eq!(script.number_of_kills, 0);
+
+        assert_eq!(
+            newer.decode(&builtin_stream()).unwrap_err().to_string(),
+            "expected vector3 for position at position 0, found short"
+        );
+        assert_eq!(
+            builtin.decode(&stream).unwrap_err().to_string(),
+            "expected short for pitch at position 0, found vector3"
+        );
+        assert_eq!(
+            newer.decode(&newer_stream(vec![1, 2])).unwrap_err(),
+            LayoutError::WrongLength {
+                field: "skin".into(),
+                expected: 3,
+                found: 2
+            }
+        );
+        assert!(matches!(
+            newer.decode(&stream[..4]),
+            Err(LayoutError::Missing { index: 4, .. })
+        ));
+        let mut longer = stream.clone();
+        longer.push(PhotonDataType::Null);
+        assert!(matches!(
+            newer.decode(&longer),
+            Err(LayoutError::TooLong {
+                expected: 7,
+                found: 8
+            })
+        ));
+    }
+
+    #[test]
+    fn layouts_are_checked() {
+        let mut layout = newer_layout();
+        layout.fields.swap(4, 5);
+        assert!(matches!(
+            layout.validate(),
+            Err(LayoutError::InvalidLengthField { .. })
+        ));
+
+        let mut layout = newer_layout();
+        layout.fields[3].name = "health".into();
+        assert_eq!(
+            layout.validate(),
+            Err(LayoutError::DuplicateField("health".into()))
+        );
+
+        let mut layout = newer_layout();
+        layout.fields.remove(0);
+        assert_eq!(
+            PlayerScript::check_layout(&layout),
+            Err(LayoutError::MissingField("position"))
+        );
+
+        let mut layout = newer_layout();
+        layout.fields[1].kind = FieldType::Float;
+        assert_eq!(
+            PlayerScript::check_layout(&layout).unwrap_err().to_string(),
+            "health must be a short, not a float"
+        );
+    }
+
+    #[test]
+    fn user_layouts_replace_builtin_ones() {
+        let dir = std::env::temp_dir().join(format!("bfhax-stream-layouts-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("stream_layouts.json");
+
+        assert_eq!(
+            StreamLayouts::load(&path).for_profile("1.99"),
+            &StreamLayout::builtin()
+        );
+
+        let mut invalid = serde_json::to_value(newer_layout()).unwrap();
+        invalid[0]["name"] = "somewhere".into();
+        let file = json!({
+            "schema_version": 1,
+            "layouts": {
+                "1.99": newer_layout(),
+                BUILTIN_LAYOUT: invalid,
+            },
+        });
+        std::fs::write(&path, serde_json::to_vec(&file).unwrap()).unwrap();
+        let layouts = StreamLayouts::load(&path);
+        assert_eq!(
+            layouts.profiles().collect::<Vec<_>>(),
+            ["1.99", BUILTIN_LAYOUT]
+        );
+        assert_eq!(layouts.for_profile("1.99"), &newer_layout());
+        // the invalid replacement was ignored
+        assert_eq!(layouts.for_profile("1.98"), &StreamLayout::builtin());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
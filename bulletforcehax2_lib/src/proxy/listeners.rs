@@ -0,0 +1,514 @@
+//! The addresses the proxy accepts connections on, and the upstream servers it connects to.
+//!
+//! Both can be changed while the proxy is running with [HaxState::apply_proxy_config](crate::hax::HaxState). New
+//! listeners are bound before anything else changes, so a config that can't be applied leaves the old one in place.
+//! Removed listeners stop accepting connections, but connections they already accepted are left to finish. The
+//! upstream hosts are swapped as a whole, so every new connection sees either the old or the new table.
+
+use std::{convert::Infallible, fmt::Display, net::SocketAddr};
+
+use hyper::{
+    http::uri::Authority, server::conn::Http, service::service_fn, Body, Request, Response, Uri,
+};
+use photon_lib::indexmap::IndexMap;
+use tokio::{net::TcpListener, sync::watch};
+use tower::{util::BoxCloneService, ServiceExt};
+use tracing::{debug, info, warn};
+
+use crate::error::HaxError;
+
+/// The service that answers the requests of every listener.
+pub type ProxyService = BoxCloneService<Request<Body>, Response<Body>, Infallible>;
+
+/// Added to the extensions of every request, holds the configured address of the listener that accepted it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenerAddr(pub SocketAddr);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxyConfig {
+    /// The addresses to accept connections on.
+    pub listen_addresses: Vec<SocketAddr>,
+    /// Upstream hosts to connect to instead of the ones the client asked for, such as when the game moves to a new
+    /// host. Keyed by the requested host, the value is a host with an optional port. The requested port is kept if
+    /// no port is given.
+    pub upstream_hosts: IndexMap<String, String>,
+}
+
+impl ProxyConfig {
+    /// Replaces the host of an upstream address according to [Self::upstream_hosts].
+    pub fn route_upstream(&self, uri: &Uri) -> Uri {
+        let replacement = match uri.host().and_then(|h| self.upstream_hosts.get(h)) {
+            Some(r) => r,
+            None => return uri.clone(),
+        };
+        let authority = match (replacement.contains(':'), uri.port_u16()) {
+            (false, Some(port)) => format!("{replacement}:{port}"),
+            _ => replacement.clone(),
+        };
+
+        let mut parts = uri.clone().into_parts();
+        parts.authority = match authority.parse() {
+            Ok(a) => Some(a),
+            Err(e) => {
+                warn!(authority, "Not routing to invalid upstream host: {e}");
+                return uri.clone();
+            }
+        };
+        Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
+    }
+}
+
+/// A change made by [ProxyListeners::apply].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyConfigChange {
+    ListenerAdded(SocketAddr),
+    /// The listener stopped accepting connections. The connections it accepted before are left to finish.
+    ListenerRemoved(SocketAddr),
+    UpstreamAdded {
+        host: String,
+        upstream: String,
+    },
+    UpstreamChanged {
+        host: String,
+        upstream: String,
+    },
+    UpstreamRemoved {
+        host: String,
+    },
+}
+
+impl Display for ProxyConfigChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyConfigChange::ListenerAdded(addr) => write!(f, "listening on {addr}"),
+            ProxyConfigChange::ListenerRemoved(addr) => write!(f, "stopped listening on {addr}"),
+            ProxyConfigChange::UpstreamAdded { host, upstream } => {
+                write!(f, "connecting to {upstream} instead of {host}")
+            }
+            ProxyConfigChange::UpstreamChanged { host, upstream } => {
+                write!(f, "connecting to {upstream} instead of {host} now")
+            }
+            ProxyConfigChange::UpstreamRemoved { host } => {
+                write!(f, "connecting to {host} directly again")
+            }
+        }
+    }
+}
+
+/// Stops a listener when dropped. Its accepted connections are shut down gracefully.
+struct ListenerHandle {
+    _shutdown: watch::Sender<()>,
+}
+
+/// The listeners of the proxy and the [ProxyConfig] they were started from.
+#[derive(Default)]
+pub struct ProxyListeners {
+    config: ProxyConfig,
+    service: Option<ProxyService>,
+    /// Keyed by the configured address.
+    listeners: IndexMap<SocketAddr, ListenerHandle>,
+}
+
+impl ProxyListeners {
+    /// The config that was applied last.
+    pub fn config(&self) -> &ProxyConfig {
+        &self.config
+    }
+
+    /// Sets the service that answers the requests of listeners started from now on.
+    pub fn set_service(&mut self, service: ProxyService) {
+        self.service = Some(service);
+    }
+
+    /// Starts and stops listeners and swaps the upstream hosts to match the given config, and returns what changed.
+    ///
+    /// `active_game_listener` is the listener the current game connection came in on. Removing it is refused unless
+    /// `force` is set, since the client couldn't reach the game server through it anymore.
+    pub async fn apply(
+        &mut self,
+        config: ProxyConfig,
+        force: bool,
+        active_game_listener: Option<SocketAddr>,
+    ) -> Result<Vec<ProxyConfigChange>, HaxError> {
+        let removed = self
+            .listeners
+            .keys()
+            .filter(|addr| !config.listen_addresses.contains(addr))
+            .copied()
+            .collect::<Vec<_>>();
+        if let Some(addr) = active_game_listener.filter(|addr| removed.contains(addr)) {
+            if !force {
+                return Err(HaxError::Config(format!(
+                    "removing the listener on {addr} would orphan the active game connection"
+                )));
+            }
+            warn!("Removing the listener of the active game connection on {addr}");
+        }
+        for (host, upstream) in &config.upstream_hosts {
+            if let Err(e) = upstream.parse::<Authority>() {
+                return Err(HaxError::Config(format!(
+                    "invalid upstream host {upstream:?} for {host}: {e}"
+                )));
+            }
+        }
+
+        let mut added = vec![];
+        for addr in &config.listen_addresses {
+            if self.listeners.contains_key(addr) || added.iter().any(|(a, _)| a == addr) {
+                continue;
+            }
+            if self.service.is_none() {
+                return Err(HaxError::Config(
+                    "no service to answer requests on new listeners".into(),
+                ));
+            }
+            // nothing is changed until every new listener is bound, dropping them stops listening again
+            let listener = TcpListener::bind(addr)
+                .await
+                .map_err(|e| HaxError::Config(format!("could not listen on {addr}: {e}")))?;
+            added.push((*addr, listener));
+        }
+
+        let mut changes = vec![];
+        for addr in removed {
+            self.listeners.shift_remove(&addr);
+            changes.push(ProxyConfigChange::ListenerRemoved(addr));
+        }
+        for (addr, listener) in added {
+            let service = self.service.clone().expect("service was checked above");
+            let (shutdown, shutdown_recv) = watch::channel(());
+            tokio::spawn(serve(listener, addr, service, shutdown_recv));
+            self.listeners.insert(
+                addr,
+                ListenerHandle {
+                    _shutdown: shutdown,
+                },
+            );
+            changes.push(ProxyConfigChange::ListenerAdded(addr));
+        }
+
+        for (host, upstream) in &config.upstream_hosts {
+            match self.config.upstream_hosts.get(host) {
+                None => changes.push(ProxyConfigChange::UpstreamAdded {
+                    host: host.clone(),
+                    upstream: upstream.clone(),
+                }),
+                Some(old) if old != upstream => changes.push(ProxyConfigChange::UpstreamChanged {
+                    host: host.clone(),
+                    upstream: upstream.clone(),
+                }),
+                Some(_) => (),
+            }
+        }
+        for host in self.config.upstream_hosts.keys() {
+            if !config.upstream_hosts.contains_key(host) {
+                changes.push(ProxyConfigChange::UpstreamRemoved { host: host.clone() });
+            }
+        }
+
+        self.config = config;
+        Ok(changes)
+    }
+}
+
+/// Accepts connections until the [ListenerHandle] is dropped.
+async fn serve(
+    listener: TcpListener,
+    addr: SocketAddr,
+    service: ProxyService,
+    mut shutdown: watch::Receiver<()>,
+) {
+    info!("Listening on {addr}");
+    loop {
+        let stream = tokio::select! {
+            _ = shutdown.changed() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept connection on {addr}: {e}");
+                    continue;
+                }
+            },
+        };
+
+        let service = service.clone();
+        let mut shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |mut req: Request<Body>| {
+                req.extensions_mut().insert(ListenerAddr(addr));
+                service.clone().oneshot(req)
+            });
+            // upgraded websocket connections are handed off, so they survive the shutdown
+            let connection = Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades();
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = shutdown.changed() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                debug!("Connection on {addr} failed: {e}");
+            }
+        });
+    }
+    info!("Stopped listening on {addr}");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use futures_util::{lock::Mutex, SinkExt};
+    use photon_lib::{
+        indexmap::indexmap,
+        photon_message::{OperationRequest, PhotonMessage},
+    };
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::{ProxyConfig, ProxyConfigChange};
+    use crate::{
+        error::HaxError,
+        hax::{events::HaxEvent, BulletForceHax, HaxState},
+        testsupport::MockPhotonServer,
+    };
+
+    fn free_addr() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    fn config(listen_addresses: &[SocketAddr]) -> ProxyConfig {
+        ProxyConfig {
+            listen_addresses: listen_addresses.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    /// A proxy that isn't listening anywhere yet.
+    fn proxy() -> Arc<Mutex<HaxState>> {
+        let mut hax = BulletForceHax::default();
+        let service = hax.get_websocket_proxy();
+        let state = hax.get_state();
+        futures::executor::block_on(state.lock())
+            .proxy
+            .set_service(service);
+        state
+    }
+
+    fn request() -> PhotonMessage {
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: 42,
+            parameters: indexmap! {},
+        })
+    }
+
+    fn binary(message: &PhotonMessage) -> Message {
+        let mut bytes = vec![];
+        message.to_websocket_bytes(&mut bytes).unwrap();
+        Message::Binary(bytes)
+    }
+
+    /// Waits until nothing accepts connections on the address anymore.
+    async fn wait_until_closed(addr: SocketAddr) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while TcpStream::connect(addr).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("listener was not closed");
+    }
+
+    #[tokio::test]
+    async fn add_modify_remove_listeners() {
+        let state = proxy();
+        let mut events = state.lock().await.events.subscribe();
+        let (a, b) = (free_addr(), free_addr());
+        state
+            .lock()
+            .await
+            .game_server_routes
+            .set_endpoint(format!("ws://{a}/socket").parse().unwrap());
+
+        let changes = state
+            .lock()
+            .await
+            .apply_proxy_config(config(&[a]), false)
+            .await
+            .unwrap();
+        assert_eq!(changes, [ProxyConfigChange::ListenerAdded(a)]);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(HaxEvent::ProxyConfigChanged(ProxyConfigChange::ListenerAdded(addr))) if addr == a
+        ));
+
+        // join a game through the new listener
+        let mut server = MockPhotonServer::start().await;
+        let local = state
+            .lock()
+            .await
+            .game_server_routes
+            .register(&server.url(), Instant::now())
+            .unwrap();
+        let (mut game, _) = tokio_tungstenite::connect_async(local).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.lock().await.gameplay_state.is_none() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("game connection was not stored");
+
+        // moving to another listener would orphan the game connection
+        let result = state
+            .lock()
+            .await
+            .apply_proxy_config(config(&[b]), false)
+            .await;
+        assert!(matches!(result, Err(HaxError::Config(_))));
+        assert_eq!(state.lock().await.proxy.config(), &config(&[a]));
+        assert!(TcpStream::connect(b).await.is_err());
+        assert!(events.try_recv().is_err());
+
+        // adding a listener and an upstream host
+        let mut both = config(&[a, b]);
+        both.upstream_hosts
+            .insert("game.invalid".into(), "127.0.0.1".into());
+        let changes = state
+            .lock()
+            .await
+            .apply_proxy_config(both, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            changes,
+            [
+                ProxyConfigChange::ListenerAdded(b),
+                ProxyConfigChange::UpstreamAdded {
+                    host: "game.invalid".into(),
+                    upstream: "127.0.0.1".into(),
+                },
+            ]
+        );
+        assert!(TcpStream::connect(b).await.is_ok());
+
+        // forcing the old listener away leaves the game connection running
+        let changes = state
+            .lock()
+            .await
+            .apply_proxy_config(config(&[b]), true)
+            .await
+            .unwrap();
+        assert_eq!(
+            changes,
+            [
+                ProxyConfigChange::ListenerRemoved(a),
+                ProxyConfigChange::UpstreamRemoved {
+                    host: "game.invalid".into()
+                },
+            ]
+        );
+        wait_until_closed(a).await;
+        game.send(binary(&request())).await.unwrap();
+        assert_eq!(server.recv().await, request());
+        // new game servers are routed through the remaining listener
+        assert_eq!(
+            state
+                .lock()
+                .await
+                .game_server_routes
+                .endpoint()
+                .and_then(|e| e.authority())
+                .map(|a| a.to_string()),
+            Some(b.to_string())
+        );
+
+        let changes = state
+            .lock()
+            .await
+            .apply_proxy_config(config(&[]), true)
+            .await
+            .unwrap();
+        assert_eq!(changes, [ProxyConfigChange::ListenerRemoved(b)]);
+        wait_until_closed(b).await;
+    }
+
+    #[tokio::test]
+    async fn upstream_hosts_apply_to_new_connections() {
+        let state = proxy();
+        let addr = free_addr();
+        let mut server = MockPhotonServer::start().await;
+        let port = server.url().rsplit_once(':').unwrap().1.to_string();
+
+        let mut routed = config(&[addr]);
+        routed
+            .upstream_hosts
+            .insert("game.invalid".into(), "127.0.0.1".into());
+        state
+            .lock()
+            .await
+            .apply_proxy_config(routed, false)
+            .await
+            .unwrap();
+
+        // the requested port is kept
+        let url = format!("ws://{addr}/socket?ws://game.invalid:{port}");
+        let (mut client, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        client.send(binary(&request())).await.unwrap();
+        assert_eq!(server.recv().await, request());
+
+        let changes = state
+            .lock()
+            .await
+            .apply_proxy_config(config(&[addr]), false)
+            .await
+            .unwrap();
+        assert_eq!(
+            changes,
+            [ProxyConfigChange::UpstreamRemoved {
+                host: "game.invalid".into()
+            }]
+        );
+        assert!(tokio_tungstenite::connect_async(&url).await.is_err());
+
+        // upstream hosts are checked before anything is applied
+        let mut invalid = config(&[addr, free_addr()]);
+        invalid
+            .upstream_hosts
+            .insert("game.invalid".into(), "not a host".into());
+        let result = state.lock().await.apply_proxy_config(invalid, false).await;
+        assert!(matches!(result, Err(HaxError::Config(_))));
+        assert_eq!(state.lock().await.proxy.config(), &config(&[addr]));
+    }
+
+    #[test]
+    fn routes_upstream_hosts() {
+        let config = ProxyConfig {
+            upstream_hosts: indexmap! {
+                "old.example.com".into() => "new.example.com".into(),
+                "moved.example.com".into() => "10.0.0.1:9000".into(),
+            },
+            ..Default::default()
+        };
+        let route = |uri: &str| config.route_upstream(&uri.parse().unwrap()).to_string();
+
+        assert_eq!(
+            route("wss://old.example.com:2053/path?x=1"),
+            "wss://new.example.com:2053/path?x=1"
+        );
+        assert_eq!(route("ws://moved.example.com:2083/"), "ws://10.0.0.1:9000/");
+        assert_eq!(
+            route("ws://other.example.com:2083/"),
+            "ws://other.example.com:2083/"
+        );
+    }
+}
@@ -1,9 +1,19 @@
 use std::fmt::Display;
 
+#[cfg(feature = "proxy")]
+pub mod listeners;
+#[cfg(feature = "proxy")]
+pub mod protocol_pin;
+#[cfg(feature = "proxy")]
+pub mod relay_metrics;
+#[cfg(feature = "proxy")]
+pub mod watchdog;
+#[cfg(feature = "proxy")]
 pub mod webrequest_proxy;
+#[cfg(feature = "proxy")]
 pub mod websocket_proxy;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Direction {
     ClientToServer,
     ServerToClient,
@@ -18,9 +28,11 @@ impl Display for Direction {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 /// Indicates what kind of server a websocket is connected to.
 pub enum WebSocketServer {
+    /// The server where clients first connect to to get the list of regions and the master server address.
+    NameServer,
     /// The server where clients connect to to find a game. Called the master server by Photon.
     LobbyServer,
     /// The game server where actual matches happen.
@@ -32,16 +44,77 @@ impl WebSocketServer {
         match port {
             2053 => Some(Self::LobbyServer),
             2083 => Some(Self::GameServer),
+            // default photon ports for the name server over ws and wss
+            9093 | 19093 => Some(Self::NameServer),
             _ => None,
         }
     }
+
+    /// Classifies the server based on the target uri of the connection.
+    ///
+    /// The name server is recognized by its hostname (`ns.exitgames.com`, `ns.blayzegames.com`, ...) or by its
+    /// default port. Master and game servers are recognized by their port.
+    #[cfg(feature = "proxy")]
+    pub fn from_uri(uri: &hyper::Uri) -> Option<Self> {
+        if let Some(host) = uri.host() {
+            if host.starts_with("ns.") || host.starts_with("ns-") {
+                return Some(Self::NameServer);
+            }
+        }
+
+        Self::from_port(uri.port_u16().unwrap_or(0))
+    }
 }
 
 impl Display for WebSocketServer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            WebSocketServer::NameServer => write!(f, "nameserver"),
             WebSocketServer::LobbyServer => write!(f, "lobby"),
             WebSocketServer::GameServer => write!(f, "game"),
         }
     }
 }
+
+#[cfg(all(test, feature = "proxy"))]
+mod tests {
+    use super::WebSocketServer;
+
+    fn classify(uri: &str) -> Option<WebSocketServer> {
+        WebSocketServer::from_uri(&uri.parse().unwrap())
+    }
+
+    #[test]
+    fn classifies_servers_by_host_and_port() {
+        // the name server by its hostname, whatever the port
+        assert_eq!(
+            classify("wss://ns.exitgames.com/"),
+            Some(WebSocketServer::NameServer)
+        );
+        assert_eq!(
+            classify("wss://ns-eu.blayzegames.com:443/"),
+            Some(WebSocketServer::NameServer)
+        );
+        // or by photon's default ports
+        assert_eq!(
+            classify("ws://10.0.0.1:9093/"),
+            Some(WebSocketServer::NameServer)
+        );
+        assert_eq!(
+            classify("wss://photon.example.com:19093/"),
+            Some(WebSocketServer::NameServer)
+        );
+
+        assert_eq!(
+            classify("wss://eu.example.com:2053/"),
+            Some(WebSocketServer::LobbyServer)
+        );
+        assert_eq!(
+            classify("wss://eu.example.com:2083/"),
+            Some(WebSocketServer::GameServer)
+        );
+        // a host that only contains "ns" isn't the name server
+        assert_eq!(classify("wss://dns.example.com:443/"), None);
+        assert_eq!(classify("wss://example.com/"), None);
+    }
+}
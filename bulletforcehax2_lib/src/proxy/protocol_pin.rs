@@ -0,0 +1,225 @@
+//! Pinning connections to one serialization protocol, see
+//! [Settings::forced_protocol](crate::hax::settings::Settings::forced_protocol).
+//!
+//! The client's upgrade request lists the protocols it speaks and the server picks one, see
+//! [protocol_version](photon_lib::protocol_version). To pin a connection, its upgrade request is forwarded with only
+//! the forced protocol, and the server's pick is checked before the client gets to see it. Client and server then
+//! both serialize with the forced protocol, in both directions.
+//!
+//! Pinning is refused if the client doesn't offer the forced protocol, as it couldn't read what the server sends.
+//! Such connections are proxied as they are. If the server answers with another protocol, the connection is refused
+//! instead, the server already agreed to something the client never offered.
+
+use photon_lib::protocol_version::{subprotocols, ProtocolVersion};
+use thiserror::Error;
+
+/// The header that holds the offered and accepted subprotocols.
+pub const SUBPROTOCOL_HEADER: &str = "sec-websocket-protocol";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PinError {
+    #[error("not pinning the connection to protocol {forced}, the client only offers {offered}")]
+    ClientUnsupported {
+        forced: ProtocolVersion,
+        offered: String,
+    },
+    #[error("the server doesn't accept the forced protocol {forced}, it answered with {accepted}")]
+    ServerUnsupported {
+        forced: ProtocolVersion,
+        accepted: String,
+    },
+}
+
+fn describe(header: Option<&str>) -> String {
+    match header.map(|h| subprotocols(h).collect::<Vec<_>>().join(", ")) {
+        Some(list) if !list.is_empty() => list,
+        _ => "no protocol".into(),
+    }
+}
+
+/// The subprotocol header to forward to the server instead of the one the client offered.
+pub fn pin_offer(forced: ProtocolVersion, offered: Option<&str>) -> Result<&'static str, PinError> {
+    let supported = offered
+        .into_iter()
+        .flat_map(subprotocols)
+        .any(|s| ProtocolVersion::from_subprotocol(s) == Some(forced));
+    match supported {
+        true => Ok(forced.subprotocol()),
+        false => Err(PinError::ClientUnsupported {
+            forced,
+            offered: describe(offered),
+        }),
+    }
+}
+
+/// Checks that the server accepted the forced protocol.
+pub fn check_accepted(forced: ProtocolVersion, accepted: Option<&str>) -> Result<(), PinError> {
+    match accepted.and_then(ProtocolVersion::from_subprotocol) {
+        Some(accepted) if accepted == forced => Ok(()),
+        _ => Err(PinError::ServerUnsupported {
+            forced,
+            accepted: describe(accepted),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use futures_util::{lock::Mutex, SinkExt};
+    use photon_lib::{
+        indexmap::indexmap,
+        photon_message::{OperationRequest, PhotonMessage},
+        protocol_version::ProtocolVersion::{self, GpBinaryV16, GpBinaryV18},
+    };
+    use tokio_tungstenite::tungstenite::{client::IntoClientRequest, http::HeaderValue, Message};
+
+    use super::{check_accepted, pin_offer, PinError, SUBPROTOCOL_HEADER};
+    use crate::{
+        hax::{BulletForceHax, HaxState},
+        testsupport::MockPhotonServer,
+        ProxyConfig,
+    };
+
+    #[test]
+    fn pins_offered_protocols() {
+        assert_eq!(
+            pin_offer(GpBinaryV16, Some("GpBinaryV18, GpBinaryV16")),
+            Ok("GpBinaryV16")
+        );
+        assert_eq!(
+            pin_offer(GpBinaryV18, Some("GpBinaryV16")),
+            Err(PinError::ClientUnsupported {
+                forced: GpBinaryV18,
+                offered: "GpBinaryV16".into(),
+            })
+        );
+        assert_eq!(
+            pin_offer(GpBinaryV16, None).unwrap_err().to_string(),
+            "not pinning the connection to protocol 1.6, the client only offers no protocol"
+        );
+
+        assert_eq!(check_accepted(GpBinaryV16, Some("GpBinaryV16")), Ok(()));
+        assert_eq!(
+            check_accepted(GpBinaryV16, Some("GpBinaryV18"))
+                .unwrap_err()
+                .to_string(),
+            "the server doesn't accept the forced protocol 1.6, it answered with GpBinaryV18"
+        );
+        assert!(check_accepted(GpBinaryV16, None).is_err());
+    }
+
+    /// A proxy listening on a free port, with the given protocol forced.
+    async fn proxy(forced: Option<ProtocolVersion>) -> (Arc<Mutex<HaxState>>, SocketAddr) {
+        let mut hax = BulletForceHax::default();
+        let service = hax.get_websocket_proxy();
+        let state = hax.get_state();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut locked = state.lock().await;
+        locked.proxy.set_service(service);
+        locked
+            .apply_proxy_config(
+                ProxyConfig {
+                    listen_addresses: vec![addr],
+                    ..Default::default()
+                },
+                false,
+            )
+            .await
+            .unwrap();
+        locked.update_settings(|s| s.forced_protocol = forced);
+        drop(locked);
+        (state, addr)
+    }
+
+    fn request() -> PhotonMessage {
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: 42,
+            parameters: indexmap! {},
+        })
+    }
+
+    /// Connects through the proxy with the given offer, returning the protocol the client was answered with once a
+    /// message went through, or [None] if the connection was refused.
+    async fn negotiate(
+        addr: SocketAddr,
+        offered: &'static str,
+        server_speaks: &'static [ProtocolVersion],
+    ) -> Option<String> {
+        let mut server = MockPhotonServer::start_speaking(server_speaks).await;
+        let mut upgrade = format!("ws://{addr}/socket?{}", server.url())
+            .into_client_request()
+            .unwrap();
+        upgrade
+            .headers_mut()
+            .insert(SUBPROTOCOL_HEADER, HeaderValue::from_static(offered));
+        let (mut client, response) = tokio_tungstenite::connect_async(upgrade).await.ok()?;
+
+        let mut bytes = vec![];
+        request().to_websocket_bytes(&mut bytes).unwrap();
+        client.send(Message::Binary(bytes)).await.unwrap();
+        assert_eq!(server.recv().await, request());
+        Some(
+            response
+                .headers()
+                .get(SUBPROTOCOL_HEADER)?
+                .to_str()
+                .unwrap()
+                .to_string(),
+        )
+    }
+
+    /// What a client that speaks both protocols offers.
+    const BOTH: &str = "GpBinaryV18, GpBinaryV16";
+    const SERVER_SPEAKS_BOTH: &[ProtocolVersion] = &[GpBinaryV16, GpBinaryV18];
+
+    #[tokio::test]
+    async fn server_picks_without_a_pin() {
+        let (_state, addr) = proxy(None).await;
+        let accepted = negotiate(addr, BOTH, SERVER_SPEAKS_BOTH).await;
+        assert_eq!(accepted.as_deref(), Some("GpBinaryV18"));
+    }
+
+    #[tokio::test]
+    async fn pinned_in_both_directions() {
+        for forced in [GpBinaryV16, GpBinaryV18] {
+            let (state, addr) = proxy(Some(forced)).await;
+            let accepted = negotiate(addr, BOTH, SERVER_SPEAKS_BOTH).await;
+            assert_eq!(accepted.as_deref(), Some(forced.subprotocol()));
+            assert!(state.lock().await.stats.recent_errors.is_empty());
+        }
+
+        // observing only, nothing is changed
+        let (state, addr) = proxy(Some(GpBinaryV16)).await;
+        state
+            .lock()
+            .await
+            .update_settings(|s| s.observe_only = true);
+        let accepted = negotiate(addr, BOTH, SERVER_SPEAKS_BOTH).await;
+        assert_eq!(accepted.as_deref(), Some("GpBinaryV18"));
+    }
+
+    #[tokio::test]
+    async fn unsupported_pins_are_refused() {
+        // the client can't speak it, so the connection is left alone
+        let (state, addr) = proxy(Some(GpBinaryV18)).await;
+        let accepted = negotiate(addr, "GpBinaryV16", SERVER_SPEAKS_BOTH).await;
+        assert_eq!(accepted.as_deref(), Some("GpBinaryV16"));
+        assert_eq!(
+            state.lock().await.stats.recent_errors,
+            ["not pinning the connection to protocol 1.8, the client only offers GpBinaryV16"]
+        );
+
+        // the server can't, and the client must not see what it picked instead
+        let (state, addr) = proxy(Some(GpBinaryV16)).await;
+        assert_eq!(negotiate(addr, BOTH, &[GpBinaryV18]).await, None);
+        assert_eq!(
+            state.lock().await.stats.recent_errors,
+            ["the server doesn't accept the forced protocol 1.6, it answered with no protocol"]
+        );
+    }
+}
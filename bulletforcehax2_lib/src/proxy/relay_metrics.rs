@@ -0,0 +1,437 @@
+//! How long messages spend inside the proxy, to tell whether the proxy itself adds latency.
+//!
+//! Each direction of a connection relays one message at a time: it is read, handed to the hooks, and written to the
+//! other side. The gauges count the messages that are between those stages over all connections, the transit time is
+//! measured from reading a message to having written it, and writes that could not complete right away because the
+//! socket was full are counted as backpressure. Recording a message only reads the monotonic clock and updates
+//! counters, it doesn't allocate.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+use super::Direction;
+use crate::hax::handler_timing::TimingHistogram;
+
+/// The default for [RelayMetrics::set_warn_threshold].
+pub const DEFAULT_TRANSIT_WARN_THRESHOLD: Duration = Duration::from_millis(50);
+
+/// How often the transit time is checked against the threshold. There is at most one warning per direction in each
+/// interval.
+const WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The stages a message goes through while it is relayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayStage {
+    /// Read from the socket, waiting for or inside the hooks.
+    Hook,
+    /// Waiting for the other socket to accept it.
+    Write,
+}
+
+#[derive(Debug, Default)]
+struct StageGauge {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+    peak_messages: AtomicU64,
+    peak_bytes: AtomicU64,
+}
+
+impl StageGauge {
+    fn enter(&self, len: u64) {
+        let messages = self.messages.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes = self.bytes.fetch_add(len, Ordering::Relaxed) + len;
+        self.peak_messages.fetch_max(messages, Ordering::Relaxed);
+        self.peak_bytes.fetch_max(bytes, Ordering::Relaxed);
+    }
+
+    fn leave(&self, len: u64) {
+        self.messages.fetch_sub(1, Ordering::Relaxed);
+        self.bytes.fetch_sub(len, Ordering::Relaxed);
+    }
+
+    fn depth(&self) -> StageDepth {
+        StageDepth {
+            messages: self.messages.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            peak_messages: self.peak_messages.load(Ordering::Relaxed),
+            peak_bytes: self.peak_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Transit {
+    total: TimingHistogram,
+    /// The transit times since the last check against the threshold.
+    window: TimingHistogram,
+    window_start: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct DirectionMetrics {
+    hook: StageGauge,
+    write: StageGauge,
+    transit: Mutex<Transit>,
+    backpressure: AtomicU64,
+    slow_windows: AtomicU64,
+}
+
+impl DirectionMetrics {
+    fn stage(&self, stage: RelayStage) -> &StageGauge {
+        match stage {
+            RelayStage::Hook => &self.hook,
+            RelayStage::Write => &self.write,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Metrics {
+    client_to_server: DirectionMetrics,
+    server_to_client: DirectionMetrics,
+    /// In microseconds, 0 if disabled.
+    warn_threshold: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            client_to_server: DirectionMetrics::default(),
+            server_to_client: DirectionMetrics::default(),
+            warn_threshold: AtomicU64::new(DEFAULT_TRANSIT_WARN_THRESHOLD.as_micros() as u64),
+        }
+    }
+}
+
+impl Metrics {
+    fn direction(&self, direction: Direction) -> &DirectionMetrics {
+        match direction {
+            Direction::ClientToServer => &self.client_to_server,
+            Direction::ServerToClient => &self.server_to_client,
+        }
+    }
+}
+
+/// Measures the relay path of all proxied connections. Cheap to clone, all clones share the same data.
+#[derive(Debug, Clone, Default)]
+pub struct RelayMetrics {
+    metrics: Arc<Metrics>,
+}
+
+impl RelayMetrics {
+    /// Starts tracking a message that was just read. It counts towards the [RelayStage::Hook] gauge until
+    /// [InFlight::writing] is called, and stops counting once the returned guard is dropped.
+    pub fn read(&self, direction: Direction, len: usize, now: Instant) -> InFlight<'_> {
+        let len = len as u64;
+        self.metrics.direction(direction).hook.enter(len);
+        InFlight {
+            metrics: self,
+            direction,
+            stage: RelayStage::Hook,
+            len,
+            read_at: now,
+        }
+    }
+
+    /// Log a warning when the 99th percentile of the transit time in one direction is above this, [None] to disable.
+    pub fn set_warn_threshold(&self, threshold: Option<Duration>) {
+        let micros = threshold.map_or(0, |t| (t.as_micros() as u64).max(1));
+        self.metrics.warn_threshold.store(micros, Ordering::Relaxed);
+    }
+
+    pub fn warn_threshold(&self) -> Option<Duration> {
+        match self.metrics.warn_threshold.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    /// Records how long a message took from being read to being written. Returns whether this ended a check interval
+    /// with a transit time above the threshold, which is logged.
+    fn record_transit(&self, direction: Direction, elapsed: Duration, now: Instant) -> bool {
+        let metrics = self.metrics.direction(direction);
+        let (p99, messages) = {
+            // the histograms are only updated in single statements, so they stay consistent even if poisoned
+            let mut transit = metrics.transit.lock().unwrap_or_else(|e| e.into_inner());
+            transit.total.record(elapsed);
+            transit.window.record(elapsed);
+            let window_start = *transit.window_start.get_or_insert(now);
+            if now.saturating_duration_since(window_start) < WARN_INTERVAL {
+                return false;
+            }
+            let window = std::mem::take(&mut transit.window);
+            transit.window_start = Some(now);
+            (window.percentile(0.99), window.count())
+        };
+
+        match (p99, self.warn_threshold()) {
+            (Some(p99), Some(threshold)) if p99 > threshold => {
+                metrics.slow_windows.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    direction = format!("{direction}"),
+                    messages,
+                    p99_us = p99.as_micros() as u64,
+                    threshold_us = threshold.as_micros() as u64,
+                    "Messages are spending a long time in the proxy"
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn report(&self) -> RelayReport {
+        let direction = |direction| {
+            let metrics = self.metrics.direction(direction);
+            let transit = metrics.transit.lock().unwrap_or_else(|e| e.into_inner());
+            DirectionReport {
+                hook: metrics.hook.depth(),
+                write: metrics.write.depth(),
+                transit: transit.total.clone(),
+                backpressure: metrics.backpressure.load(Ordering::Relaxed),
+                slow_windows: metrics.slow_windows.load(Ordering::Relaxed),
+            }
+        };
+        RelayReport {
+            client_to_server: direction(Direction::ClientToServer),
+            server_to_client: direction(Direction::ServerToClient),
+        }
+    }
+}
+
+/// A message that is being relayed, see [RelayMetrics::read].
+#[must_use]
+pub struct InFlight<'a> {
+    metrics: &'a RelayMetrics,
+    direction: Direction,
+    stage: RelayStage,
+    len: u64,
+    read_at: Instant,
+}
+
+impl InFlight<'_> {
+    fn gauge(&self) -> &StageGauge {
+        self.metrics
+            .metrics
+            .direction(self.direction)
+            .stage(self.stage)
+    }
+
+    /// The hooks are done with the message, which now has the given length.
+    pub fn writing(&mut self, len: usize) {
+        self.gauge().leave(self.len);
+        self.stage = RelayStage::Write;
+        self.len = len as u64;
+        self.gauge().enter(self.len);
+    }
+
+    /// The socket could not take the message right away.
+    pub fn blocked(&self) {
+        self.metrics
+            .metrics
+            .direction(self.direction)
+            .backpressure
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The message was written, so its transit time is recorded.
+    pub fn written(self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.read_at);
+        self.metrics.record_transit(self.direction, elapsed, now);
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.gauge().leave(self.len);
+    }
+}
+
+/// How many messages are in a stage of the relay.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StageDepth {
+    pub messages: u64,
+    pub bytes: u64,
+    /// The most messages that were in the stage at once.
+    pub peak_messages: u64,
+    /// The most bytes that were in the stage at once.
+    pub peak_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DirectionReport {
+    pub hook: StageDepth,
+    pub write: StageDepth,
+    /// How long messages took from being read to being written.
+    pub transit: TimingHistogram,
+    /// How many writes could not complete right away because the socket was full.
+    pub backpressure: u64,
+    /// How many check intervals had a transit time above the warning threshold.
+    pub slow_windows: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RelayReport {
+    pub client_to_server: DirectionReport,
+    pub server_to_client: DirectionReport,
+}
+
+impl RelayReport {
+    pub fn get(&self, direction: Direction) -> &DirectionReport {
+        match direction {
+            Direction::ClientToServer => &self.client_to_server,
+            Direction::ServerToClient => &self.server_to_client,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use futures_util::{lock::Mutex, SinkExt};
+    use photon_lib::{
+        indexmap::indexmap,
+        photon_message::{OperationRequest, PhotonMessage},
+    };
+    use tokio_tungstenite::tungstenite::{Error, Message};
+
+    use super::{RelayMetrics, StageDepth, WARN_INTERVAL};
+    use crate::{
+        hax::HaxState,
+        proxy::{Direction, WebSocketServer},
+        testsupport::ProxiedConnection,
+    };
+
+    #[test]
+    fn gauges_and_warnings() {
+        let metrics = RelayMetrics::default();
+        metrics.set_warn_threshold(Some(Duration::from_millis(5)));
+        let start = Instant::now();
+
+        let mut first = metrics.read(Direction::ClientToServer, 100, start);
+        let second = metrics.read(Direction::ClientToServer, 50, start);
+        first.writing(120);
+        first.blocked();
+        let report = metrics.report();
+        let expected_hook = StageDepth {
+            messages: 1,
+            bytes: 50,
+            peak_messages: 2,
+            peak_bytes: 150,
+        };
+        assert_eq!(report.client_to_server.hook, expected_hook);
+        assert_eq!(report.client_to_server.write.bytes, 120);
+        assert_eq!(report.client_to_server.backpressure, 1);
+        assert_eq!(report.server_to_client.hook.peak_messages, 0);
+
+        // messages that were dropped by a hook don't count towards the transit time
+        drop(second);
+        first.written(start + Duration::from_millis(20));
+        let report = metrics.report().client_to_server;
+        assert_eq!(report.hook.messages + report.write.messages, 0);
+        assert_eq!(report.write.bytes, 0);
+        assert_eq!(report.transit.count(), 1);
+
+        // the transit time is only checked once per interval
+        let at = |offset| start + Duration::from_millis(20) + offset;
+        assert!(!metrics.record_transit(
+            Direction::ClientToServer,
+            Duration::from_millis(30),
+            at(Duration::ZERO)
+        ));
+        assert!(metrics.record_transit(
+            Direction::ClientToServer,
+            Duration::from_millis(30),
+            at(WARN_INTERVAL)
+        ));
+        assert!(!metrics.record_transit(
+            Direction::ClientToServer,
+            Duration::from_millis(1),
+            at(WARN_INTERVAL)
+        ));
+        assert!(!metrics.record_transit(
+            Direction::ClientToServer,
+            Duration::from_millis(1),
+            at(WARN_INTERVAL * 3)
+        ));
+        assert_eq!(metrics.report().client_to_server.slow_windows, 1);
+
+        metrics.set_warn_threshold(None);
+        assert_eq!(metrics.warn_threshold(), None);
+        metrics.record_transit(
+            Direction::ClientToServer,
+            Duration::from_millis(1),
+            at(WARN_INTERVAL * 3),
+        );
+        assert!(!metrics.record_transit(
+            Direction::ClientToServer,
+            Duration::from_millis(30),
+            at(WARN_INTERVAL * 5)
+        ));
+    }
+
+    #[tokio::test]
+    async fn slow_upstream_writer() {
+        const DELAY: Duration = Duration::from_millis(20);
+
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut conn = ProxiedConnection::connect_with_upstream(
+            state.clone(),
+            WebSocketServer::NameServer,
+            |sink| {
+                Box::new(sink.with(|message: Message| {
+                    Box::pin(async move {
+                        tokio::time::sleep(DELAY).await;
+                        Ok::<_, Error>(message)
+                    })
+                }))
+            },
+        )
+        .await;
+
+        let request = || {
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: 42,
+                parameters: indexmap! {},
+            })
+        };
+        for _ in 0..3 {
+            conn.client_send(request()).await;
+        }
+        for _ in 0..3 {
+            assert_eq!(conn.server.recv().await, request());
+        }
+
+        // the relay records the message right after the write completes, which may be after the server got it
+        let relay = state.lock().await.relay.clone();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while relay.report().client_to_server.transit.count() < 3 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("transit times were not recorded");
+
+        let report = relay.report().client_to_server;
+        assert!(report.transit.percentile(0.99).unwrap() >= DELAY);
+        assert!(report.backpressure >= 3);
+        assert_eq!(report.write.messages, 0);
+        assert!(report.write.peak_messages >= 1);
+        assert!(state
+            .lock()
+            .await
+            .status_report(false)
+            .contains("proxy transit c->s: 3 messages"));
+    }
+}
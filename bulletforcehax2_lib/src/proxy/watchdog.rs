@@ -0,0 +1,310 @@
+//! Detection of upstream servers that stopped responding, and recovery of their connections.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use futures_util::{SinkExt, StreamExt};
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    http::Request,
+    Uri,
+};
+use photon_lib::{
+    highlevel::constants::operation_code, indexmap::IndexMap, photon_data_type::PhotonDataType,
+    photon_message::PhotonMessage,
+};
+use tokio_tungstenite::tungstenite::{handshake::client::generate_key, Message};
+use tracing::{debug, trace};
+
+use super::websocket_proxy::{SocketSink, SocketStream};
+
+/// The operation code Photon uses for pings over websockets.
+pub(crate) const PING_OPERATION_CODE: u8 = 1;
+/// The parameter that holds the client's timestamp in ping requests and responses.
+pub(crate) const PING_CLIENT_TIME_PARAMETER: u8 = 1;
+
+/// How many unanswered pings to remember. Older ones get forgotten.
+const MAX_PENDING_PINGS: usize = 32;
+
+/// Tracks ping requests and responses on a single proxied connection.
+#[derive(Default)]
+pub(crate) struct ConnectionWatchdog {
+    /// Pings sent by the client that have not been answered yet, keyed by the client's timestamp.
+    pending_pings: IndexMap<i32, Instant>,
+    /// The last time the server sent anything at all.
+    last_server_activity: Option<Instant>,
+    /// The last authentication request sent by the client, so it can be replayed after reconnecting.
+    auth_request: Option<Vec<u8>>,
+    /// Whether the proxied connection has closed.
+    pub closed: bool,
+}
+
+impl ConnectionWatchdog {
+    pub fn observe_client_message(&mut self, bytes: &[u8], now: Instant) {
+        match message_header(bytes) {
+            Some((2, operation_code::AUTHENTICATE | operation_code::AUTHENTICATE_ONCE)) => {
+                trace!("Captured authentication request");
+                self.auth_request = Some(bytes.to_vec());
+            }
+            Some((6, PING_OPERATION_CODE)) => {
+                if let Ok(PhotonMessage::InternalOperationRequest(req)) =
+                    PhotonMessage::from_websocket_bytes(&mut &bytes[..])
+                {
+                    if let Some(PhotonDataType::Integer(time)) =
+                        req.parameters.get(&PING_CLIENT_TIME_PARAMETER)
+                    {
+                        if self.pending_pings.len() >= MAX_PENDING_PINGS {
+                            self.pending_pings.shift_remove_index(0);
+                        }
+                        self.pending_pings.insert(*time, now);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub fn observe_server_message(&mut self, bytes: &[u8], now: Instant) {
+        self.last_server_activity = Some(now);
+
+        let answered_time = match bytes.first() {
+            Some(0xF0) => match PhotonMessage::from_websocket_bytes(&mut &bytes[..]) {
+                Ok(PhotonMessage::PingResult(res)) => Some(res.client_sent_time()),
+                _ => None,
+            },
+            Some(0xF3) if message_header(bytes) == Some((7, PING_OPERATION_CODE)) => {
+                match PhotonMessage::from_websocket_bytes(&mut &bytes[..]) {
+                    Ok(PhotonMessage::InternalOperationResponse(res)) => {
+                        match res.parameters.get(&PING_CLIENT_TIME_PARAMETER) {
+                            Some(PhotonDataType::Integer(time)) => Some(*time),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        if let Some(time) = answered_time {
+            // everything sent before this ping is implicitly answered too
+            if let Some(idx) = self.pending_pings.get_index_of(&time) {
+                self.pending_pings.drain(..=idx);
+            }
+        }
+    }
+
+    /// Returns how long the server has been silent, if it has not answered a ping.
+    ///
+    /// The server is only considered silent if it has neither answered the oldest pending ping, nor sent any other
+    /// message since then.
+    pub fn silent_for(&self, now: Instant) -> Option<Duration> {
+        let (_, oldest_ping) = self.pending_pings.get_index(0)?;
+
+        match self.last_server_activity {
+            Some(activity) if activity > *oldest_ping => None,
+            _ => Some(now.saturating_duration_since(*oldest_ping)),
+        }
+    }
+
+    pub fn auth_request(&self) -> Option<&[u8]> {
+        self.auth_request.as_deref()
+    }
+
+    /// Forget about pending pings, eg. after the upstream was replaced.
+    pub fn reset(&mut self) {
+        self.pending_pings.clear();
+        self.last_server_activity = None;
+    }
+}
+
+/// Gets the message type and operation code of a message with magic number 0xF3, without parsing the whole message.
+fn message_header(bytes: &[u8]) -> Option<(u8, u8)> {
+    match bytes {
+        [0xF3, msg_type, code, ..] => Some((msg_type & 0x7F, *code)),
+        _ => None,
+    }
+}
+
+/// The information needed to open a connection to an upstream server.
+#[derive(Clone)]
+pub(crate) struct UpstreamTarget {
+    pub uri: Uri,
+    /// Headers forwarded from the client. Should not contain `sec-websocket-key`.
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl UpstreamTarget {
+    /// Creates a handshake request. If no key is given, a new one is generated.
+    pub fn to_request(&self, key: Option<HeaderValue>) -> Request<()> {
+        let key = key.unwrap_or_else(|| {
+            HeaderValue::from_str(&generate_key()).expect("generated key should be valid header")
+        });
+
+        let mut request = Request::builder()
+            .method("GET")
+            .header("host", self.uri.host().unwrap_or_default())
+            .header("connection", "Upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-key", key)
+            .uri(&self.uri);
+        for (header_name, header_value) in &self.headers {
+            request = request.header(header_name, header_value);
+        }
+
+        request
+            .body(())
+            .expect("should be able to create handshake request")
+    }
+}
+
+/// Opens a new connection to the upstream server and replays the given authentication request.
+///
+/// The server's response to the authentication request is consumed, as the client already received one over the
+/// original connection.
+pub(crate) async fn reconnect_upstream(
+    target: &UpstreamTarget,
+    auth_request: Option<Vec<u8>>,
+    timeout: Duration,
+) -> anyhow::Result<(SocketSink, SocketStream)> {
+    let (ws_stream, _) = tokio::time::timeout(
+        timeout,
+        tokio_tungstenite::connect_async(target.to_request(None)),
+    )
+    .await
+    .with_context(|| "Timed out while reconnecting to server")?
+    .with_context(|| "Failed to reconnect to server")?;
+    debug!("Reconnected to upstream server");
+
+    let (mut sink, mut stream) = ws_stream.split();
+
+    if let Some(auth_request) = auth_request {
+        sink.send(Message::Binary(auth_request)).await?;
+
+        loop {
+            let message = tokio::time::timeout(timeout, stream.next())
+                .await
+                .with_context(|| "Timed out waiting for authentication response")?
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Server closed connection during authentication")
+                })??;
+
+            let bytes = match &message {
+                Message::Binary(bytes) => bytes,
+                _ => continue,
+            };
+
+            match PhotonMessage::from_websocket_bytes(&mut bytes.as_slice()) {
+                Ok(PhotonMessage::OperationResponse(resp))
+                    if resp.operation_code == operation_code::AUTHENTICATE
+                        || resp.operation_code == operation_code::AUTHENTICATE_ONCE =>
+                {
+                    if resp.return_code != 0 {
+                        anyhow::bail!(
+                            "Server rejected replayed authentication with code {}: {:?}",
+                            resp.return_code,
+                            resp.debug_message
+                        );
+                    }
+                    debug!("Replayed authentication was accepted");
+                    break;
+                }
+                _ => trace!("Skipping message while waiting for authentication response"),
+            }
+        }
+    }
+
+    Ok((Box::new(sink), Box::new(stream)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use futures_util::{SinkExt, StreamExt};
+    use hyper::header::{HeaderName, HeaderValue};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::{reconnect_upstream, ConnectionWatchdog, UpstreamTarget};
+
+    /// An InternalOperationRequest ping with client time 0x000330de
+    const PING_REQUEST: &[u8] = &[
+        0xF3, 0x06, 0x01, 0x00, 0x01, 0x01, 0x69, 0x00, 0x03, 0x30, 0xDE,
+    ];
+    /// An InternalOperationResponse to [PING_REQUEST]
+    const PING_RESPONSE: &[u8] = &[
+        0xF3, 0x07, 0x01, 0x00, 0x00, 0x2A, 0x00, 0x02, 0x01, 0x69, 0x00, 0x03, 0x30, 0xDE, 0x02,
+        0x69, 0x38, 0xC2, 0x51, 0x0F,
+    ];
+    const AUTH_REQUEST: &[u8] = &[0xF3, 0x02, 0xE6, 0x00, 0x00];
+    const AUTH_RESPONSE: &[u8] = &[0xF3, 0x03, 0xE6, 0x00, 0x00, 0x2A, 0x00, 0x00];
+    const EVENT: &[u8] = &[0xF3, 0x04, 0xE2, 0x00, 0x00];
+
+    #[test]
+    fn unanswered_ping_is_silence() {
+        let start = Instant::now();
+        let mut watchdog = ConnectionWatchdog::default();
+
+        watchdog.observe_client_message(PING_REQUEST, start);
+        assert_eq!(watchdog.silent_for(start), Some(Duration::ZERO));
+        assert_eq!(
+            watchdog.silent_for(start + Duration::from_secs(5)),
+            Some(Duration::from_secs(5))
+        );
+
+        watchdog.observe_server_message(PING_RESPONSE, start + Duration::from_secs(1));
+        assert_eq!(watchdog.silent_for(start + Duration::from_secs(5)), None);
+    }
+
+    #[test]
+    fn captures_auth_request() {
+        let mut watchdog = ConnectionWatchdog::default();
+        watchdog.observe_client_message(EVENT, Instant::now());
+        assert_eq!(watchdog.auth_request(), None);
+
+        watchdog.observe_client_message(AUTH_REQUEST, Instant::now());
+        assert_eq!(watchdog.auth_request(), Some(AUTH_REQUEST));
+    }
+
+    #[tokio::test]
+    async fn reconnect_replays_authentication() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+
+            let message = ws.next().await.unwrap().unwrap();
+            assert_eq!(message, Message::Binary(AUTH_REQUEST.to_vec()));
+
+            ws.send(Message::Binary(AUTH_RESPONSE.to_vec()))
+                .await
+                .unwrap();
+            ws.send(Message::Binary(EVENT.to_vec())).await.unwrap();
+
+            // keep the connection open until the client is done
+            _ = ws.next().await;
+        });
+
+        let target = UpstreamTarget {
+            uri: format!("ws://{addr}/").parse().unwrap(),
+            headers: vec![(
+                HeaderName::from_static("sec-websocket-version"),
+                HeaderValue::from_static("13"),
+            )],
+        };
+        let (mut sink, mut stream) =
+            reconnect_upstream(&target, Some(AUTH_REQUEST.to_vec()), Duration::from_secs(5))
+                .await
+                .unwrap();
+
+        // the authentication response should have been consumed
+        let message = stream.next().await.unwrap().unwrap();
+        assert_eq!(message, Message::Binary(EVENT.to_vec()));
+
+        sink.close().await.unwrap();
+        server.await.unwrap();
+    }
+}
@@ -3,17 +3,21 @@ use std::str::FromStr;
 use std::sync::Arc;
 
 use anyhow::Result;
+use bytes::Bytes;
 use futures_util::lock::Mutex;
-use hyper::body::to_bytes;
+use hyper::body::{to_bytes, HttpBody};
 use hyper::header::CONTENT_TYPE;
 use hyper::{Body, Client, Request, Response};
 use tower::util::BoxCloneService;
-use tower::{Service, ServiceBuilder, ServiceExt};
+use tower::{BoxError, Service, ServiceBuilder, ServiceExt};
 use tower_http::cors::CorsLayer;
 use tower_http::decompression::DecompressionLayer;
-use tracing::{debug, error, trace, warn};
+use tracing::{debug, error, trace, warn, Instrument};
 
-use crate::hax::HaxState;
+use crate::hax::{
+    web_requests::{body_mode, BodyMode, SessionContext, StreamedBody, WebPhase},
+    HaxState,
+};
 
 pub fn create_service(
     shared_state: Arc<Mutex<HaxState>>,
@@ -66,14 +70,29 @@ async fn web_request_proxy(
         return Ok(Response::builder().body("Unity event blocked".into())?);
     }
 
-    let mut body_bytes = to_bytes(body).await?.to_vec();
-    if matches!(parts_req.method.as_str(), "GET" | "POST") {
-        let request_hook_res =
-            HaxState::webrequest_hook_onrequest(state.clone(), &proxied_uri, &mut body_bytes);
-        if let Err(error) = request_hook_res {
-            error!("Error during request hook: {error}");
+    // lives until the response is handled, so its span covers the whole request
+    let (join_span, session_id) = {
+        let mut state = state.lock().await;
+        let span = state.join_trace.web_request(&proxied_uri.to_string());
+        (span, state.join_trace.session_id().to_string())
+    };
+
+    // bodies no hook looks at keep their length
+    let hooked = matches!(parts_req.method.as_str(), "GET" | "POST")
+        && body_mode(&proxied_uri, WebPhase::Request) != BodyMode::Forward;
+    let body = match hooked {
+        true => {
+            hook_body(
+                state.clone(),
+                &session_id,
+                &proxied_uri,
+                WebPhase::Request,
+                body,
+            )
+            .await?
         }
-    }
+        false => body,
+    };
 
     let mut builder = Request::builder()
         .method(&parts_req.method)
@@ -84,41 +103,35 @@ async fn web_request_proxy(
         builder = builder.header(CONTENT_TYPE, content_type);
     }
 
-    let req = builder.body(body_bytes.into())?;
+    let req = builder.body(body)?;
 
     let client = Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
     let mut client = ServiceBuilder::new()
         .layer(DecompressionLayer::new())
         .service(client);
 
-    let response_result = client.ready().await?.call(req).await;
+    let response_result = client
+        .ready()
+        .await?
+        .call(req)
+        .instrument(join_span.clone())
+        .await;
 
     match response_result {
         Ok(response) => {
             let (parts_resp, body) = response.into_parts();
             // TODO: want to handle HEAD cleaner, this feels like a hack
-            let body_bytes = if matches!(parts_req.method.as_str(), "GET" | "POST" | "PUT") {
-                let mut body_bytes = to_bytes(body)
-                    .await
-                    .map_err(|e| anyhow::anyhow!("error reading proxied response body: {e:?}"))?
-                    .to_vec();
-                trace!(
-                    "got response from remote with body size {}",
-                    body_bytes.len()
-                );
-
-                let request_hook_res = HaxState::webrequest_hook_onresponse(
+            let body = if matches!(parts_req.method.as_str(), "GET" | "POST" | "PUT") {
+                hook_body(
                     state.clone(),
+                    &session_id,
                     &proxied_uri,
-                    &mut body_bytes,
-                );
-                if let Err(error) = request_hook_res {
-                    error!("Error during request hook: {error}");
-                }
-
-                body_bytes
+                    WebPhase::Response,
+                    body,
+                )
+                .await?
             } else {
-                vec![]
+                Body::empty()
             };
 
             let mut response = Response::builder();
@@ -127,7 +140,7 @@ async fn web_request_proxy(
                 response = response.header(CONTENT_TYPE, content_type);
             }
 
-            Ok(response.body(body_bytes.into())?)
+            Ok(response.body(body)?)
         }
         Err(error) => {
             error!("Error during proxied HTTP request: {error}");
@@ -137,3 +150,195 @@ async fn web_request_proxy(
         }
     }
 }
+
+/// Hands a body to the web request hooks in the [mode](BodyMode) they need it in. Only buffered bodies are read before
+/// this returns, the others are handed to the hooks as they're forwarded.
+async fn hook_body<B>(
+    state: Arc<Mutex<HaxState>>,
+    session_id: &str,
+    url: &hyper::Uri,
+    phase: WebPhase,
+    body: B,
+) -> anyhow::Result<Body>
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError> + Send,
+{
+    let session = SessionContext::now(session_id);
+    let streamed = match body_mode(url, phase) {
+        BodyMode::Forward => None,
+        BodyMode::Streaming => Some(StreamedBody::new(session, url.clone(), phase)),
+        BodyMode::Buffered => {
+            let mut body_bytes = to_bytes(body)
+                .await
+                .map_err(|e| anyhow::anyhow!("error reading proxied body: {:?}", e.into()))?
+                .to_vec();
+            trace!("got {phase:?} body with size {}", body_bytes.len());
+
+            let hook_res = match phase {
+                WebPhase::Request => {
+                    HaxState::webrequest_hook_onrequest(state, &session, url, &mut body_bytes)
+                }
+                WebPhase::Response => {
+                    HaxState::webrequest_hook_onresponse(state, &session, url, &mut body_bytes)
+                }
+            };
+            if let Err(error) = hook_res {
+                error!("Error during request hook: {error}");
+            }
+            return Ok(body_bytes.into());
+        }
+    };
+    Ok(stream_body(state, streamed, body))
+}
+
+/// Forwards a body a chunk at a time, running the streaming hooks on it if it's [streamed](BodyMode::Streaming).
+fn stream_body<B>(state: Arc<Mutex<HaxState>>, mut streamed: Option<StreamedBody>, body: B) -> Body
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError> + Send,
+{
+    let (mut sender, forwarded) = Body::channel();
+    tokio::spawn(async move {
+        let mut body = Box::pin(body);
+        while let Some(chunk) = body.data().await {
+            let mut chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    // the body is incomplete, so the hooks don't see its end
+                    warn!("Error reading proxied body: {:?}", e.into());
+                    sender.abort();
+                    return;
+                }
+            };
+            if let Some(streamed) = &mut streamed {
+                streamed.chunk(&mut chunk);
+            }
+            if sender.send_data(chunk).await.is_err() {
+                debug!("Proxied body was dropped before it was forwarded completely");
+                return;
+            }
+        }
+        if let Some(streamed) = streamed {
+            HaxState::webrequest_hook_onstreamed(state, streamed).await;
+        }
+    });
+    forwarded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use bytes::Bytes;
+    use futures_util::lock::Mutex;
+    use hyper::{body::HttpBody, server::conn::Http, service::service_fn, Body, Request, Response};
+    use sha2::{Digest, Sha256};
+    use tokio::net::TcpListener;
+
+    use super::web_request_proxy;
+    use crate::hax::{web_requests::WebPhase, HaxState};
+
+    const CHUNKS: usize = 8;
+    const CHUNK_DELAY: Duration = Duration::from_millis(100);
+
+    /// What the mock upstream serves, 2 MiB that differ in every chunk.
+    fn large_body() -> Vec<Bytes> {
+        (0..CHUNKS)
+            .map(|i| {
+                let chunk = (0..256 * 1024).map(|j| (i * 31 + j * 7) as u8);
+                Bytes::from(chunk.collect::<Vec<_>>())
+            })
+            .collect()
+    }
+
+    /// Starts a server that answers every request with [large_body], waiting before each chunk like a slow download.
+    async fn mock_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let service = service_fn(|_: Request<Body>| async {
+                    let (mut sender, body) = Body::channel();
+                    tokio::spawn(async move {
+                        for chunk in large_body() {
+                            tokio::time::sleep(CHUNK_DELAY).await;
+                            if sender.send_data(chunk).await.is_err() {
+                                return;
+                            }
+                        }
+                    });
+                    Ok::<_, Infallible>(Response::new(body))
+                });
+                tokio::spawn(async move {
+                    _ = Http::new().serve_connection(tcp, service).await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    /// Requests a url through the proxy, returning how long the first byte took and the whole body.
+    async fn fetch(state: &Arc<Mutex<HaxState>>, url: &str) -> (Duration, Vec<u8>) {
+        let request = Request::get(format!("http://localhost/?{url}"))
+            .body(Body::empty())
+            .unwrap();
+        let start = Instant::now();
+        let response = web_request_proxy(request, state.clone()).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let mut body = response.into_body();
+        let mut bytes = body.data().await.unwrap().unwrap().to_vec();
+        let first_byte = start.elapsed();
+        while let Some(chunk) = body.data().await {
+            bytes.extend(chunk.unwrap());
+        }
+        (first_byte, bytes)
+    }
+
+    #[tokio::test]
+    async fn only_buffers_bodies_for_buffered_hooks() {
+        let upstream = mock_upstream().await;
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let expected = large_body().concat();
+
+        // an asset, only seen by the streaming asset hook
+        let asset = format!("http://{upstream}/Build/WebGL.data");
+        let (streamed_first_byte, streamed) = fetch(&state, &asset).await;
+        // a tracked endpoint, whose exchanges are kept whole
+        let shop = format!("http://{upstream}/shop/prices");
+        let (buffered_first_byte, buffered) = fetch(&state, &shop).await;
+
+        assert_eq!(streamed, expected);
+        assert_eq!(buffered, expected);
+        // the upstream takes CHUNKS * CHUNK_DELAY to send the whole body
+        assert!(
+            buffered_first_byte >= CHUNK_DELAY * CHUNKS as u32,
+            "{buffered_first_byte:?}"
+        );
+        assert!(
+            streamed_first_byte < CHUNK_DELAY * CHUNKS as u32 / 2,
+            "{streamed_first_byte:?}"
+        );
+
+        // the body only ends once the hooks saw its end
+        let hax = state.lock().await;
+        let digest = hax.web_assets.get("127.0.0.1/Build/WebGL.data").unwrap();
+        assert_eq!(digest.size, expected.len() as u64);
+        assert_eq!(digest.sha256, format!("{:x}", Sha256::digest(&expected)));
+        let phases = hax
+            .web_requests
+            .entries()
+            .map(|exchange| exchange.phase)
+            .collect::<Vec<_>>();
+        assert_eq!(phases, [WebPhase::Request, WebPhase::Response]);
+    }
+}
@@ -1,27 +1,58 @@
 use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use futures_util::lock::Mutex;
 use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use hyper::header::{HeaderName, HeaderValue};
 use hyper::http::Request;
 use hyper::{Body, Response};
+use photon_lib::highlevel::constants::operation_code;
+use photon_lib::highlevel::structs::RaiseEvent;
+use photon_lib::highlevel::PhotonParameterMapConversion;
+use photon_lib::indexmap::IndexMap;
+use photon_lib::photon_message::{OperationRequest, PhotonMessage};
+use photon_lib::protocol_version::ProtocolVersion;
 use tokio::sync::{mpsc, Notify};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::Message;
 use tower::util::BoxCloneService;
 use tower::ServiceBuilder;
 use tower_http::cors::CorsLayer;
 use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 
+use super::listeners::ListenerAddr;
+use super::protocol_pin::{self, SUBPROTOCOL_HEADER};
+use super::relay_metrics::InFlight;
+use super::watchdog::{reconnect_upstream, ConnectionWatchdog, UpstreamTarget};
 use super::{Direction, WebSocketServer};
-use crate::hax::HaxState;
-
-type SocketStream =
+use crate::{
+    error::HaxError,
+    hax::{
+        bandwidth::BandwidthMeter,
+        disconnects::DisconnectSignal,
+        events::HaxEvent,
+        hold::{self, HoldOrdering, HoldOutcome, HookVerdict},
+        settings::SharedSettings,
+        HaxState, WatchdogMode,
+    },
+};
+
+pub(crate) type SocketStream =
     Box<dyn Stream<Item = tokio_tungstenite::tungstenite::Result<Message>> + Unpin + Send>;
-type SocketSink =
+pub(crate) type SocketSink =
     Box<dyn Sink<Message, Error = tokio_tungstenite::tungstenite::error::Error> + Unpin + Send>;
 
+/// The id of the next [WebSocketProxy], see [WebSocketProxy::id].
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
 /// A struct holding a conceptual websocket proxy connection
 pub struct WebSocketProxy {
     /// a sink to allow sending messages to the client at arbitrary times
@@ -29,40 +60,220 @@ pub struct WebSocketProxy {
     /// a sink to allow sending messages to the server at arbitrary times
     server_send: Arc<Mutex<SocketSink>>,
 
-    /// a handle to the task that controls client->server communication
+    /// a handle to the task that controls client->server communication, if the connection is live
     #[allow(dead_code)]
-    client_to_server: tokio::task::JoinHandle<()>,
-    /// a handle to the task that controls server->client communication
+    client_to_server: Option<tokio::task::JoinHandle<()>>,
+    /// a handle to the task that controls server->client communication, if the connection is live. It gets replaced
+    /// when the server connection is re-established by the watchdog.
     #[allow(dead_code)]
-    server_to_client: tokio::task::JoinHandle<()>,
+    server_to_client: Option<Arc<Mutex<tokio::task::JoinHandle<()>>>>,
 
+    /// Tells connections apart, eg. the old and new game server connection while the client is moved to another one.
+    id: u64,
     port: u16,
+    server: Option<WebSocketServer>,
+    /// The configured address of the listener the client connected to, if it came in through a
+    /// [listener](super::listeners).
+    listener: Option<SocketAddr>,
 
     notify_closed: Option<Arc<Notify>>,
+
+    /// records the size of injected messages
+    bandwidth: BandwidthMeter,
+    /// checked before injecting anything, see [Settings::is_inert](crate::hax::settings::Settings::is_inert)
+    settings: SharedSettings,
 }
 
 impl WebSocketProxy {
+    /// Creates a proxy that is not connected to anything, for replaying captured messages. Messages sent through it
+    /// are discarded.
+    pub(crate) fn detached(
+        server: WebSocketServer,
+        bandwidth: BandwidthMeter,
+        settings: SharedSettings,
+    ) -> Self {
+        let client_send: SocketSink =
+            Box::new(futures_util::sink::drain().sink_map_err(|e| match e {}));
+        let server_send: SocketSink =
+            Box::new(futures_util::sink::drain().sink_map_err(|e| match e {}));
+
+        Self {
+            client_send: Arc::new(Mutex::new(client_send)),
+            server_send: Arc::new(Mutex::new(server_send)),
+            client_to_server: None,
+            server_to_client: None,
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            port: 0,
+            server: Some(server),
+            listener: None,
+            notify_closed: None,
+            bandwidth,
+            settings,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
     pub fn get_port(&self) -> u16 {
         self.port
     }
 
     pub fn get_server_type(&self) -> Option<WebSocketServer> {
-        WebSocketServer::from_port(self.port)
+        self.server
+    }
+
+    pub fn get_listener(&self) -> Option<SocketAddr> {
+        self.listener
     }
 
     pub(crate) fn take_notify_closed(&mut self) -> Option<Arc<Notify>> {
         self.notify_closed.take()
     }
 
+    /// Every injection goes through here, so nothing can be injected while observing only.
+    fn check_injection_allowed(&self) -> Result<(), HaxError> {
+        match self.settings.load().is_inert() {
+            true => Err(HaxError::ObserveOnly),
+            false => Ok(()),
+        }
+    }
+
+    /// Sends a message to the client.
+    ///
+    /// The size of the message is attributed to the given feature, see [feature](crate::hax::bandwidth::feature).
     #[allow(dead_code)]
-    pub async fn send_client(&self, message: Message) -> anyhow::Result<()> {
-        self.client_send.lock().await.send(message).await?;
+    pub async fn send_client(
+        &self,
+        message: Message,
+        feature: &'static str,
+    ) -> Result<(), HaxError> {
+        self.check_injection_allowed()?;
+        let len = message.len();
+        self.client_send
+            .lock()
+            .await
+            .send(message)
+            .await
+            .map_err(|e| HaxError::InjectionUnavailable(format!("sending to client: {e}")))?;
+        self.bandwidth.record_injection(feature, len);
         Ok(())
     }
 
+    /// Sends a message to the server.
+    ///
+    /// The size of the message is attributed to the given feature, see [feature](crate::hax::bandwidth::feature).
     #[allow(dead_code)]
-    pub async fn send_server(&self, message: Message) -> anyhow::Result<()> {
-        self.server_send.lock().await.send(message).await?;
+    pub async fn send_server(
+        &self,
+        message: Message,
+        feature: &'static str,
+    ) -> Result<(), HaxError> {
+        self.check_injection_allowed()?;
+        let len = message.len();
+        self.server_send
+            .lock()
+            .await
+            .send(message)
+            .await
+            .map_err(|e| HaxError::InjectionUnavailable(format!("sending to server: {e}")))?;
+        self.bandwidth.record_injection(feature, len);
+        Ok(())
+    }
+
+    /// Raises an event in the current room, as if the client sent it.
+    ///
+    /// Use [RaiseEvent::with_cache] with [event_caching::ADD_TO_ROOM_CACHE] to make the event reach players that join
+    /// later.
+    ///
+    /// [event_caching::ADD_TO_ROOM_CACHE]: photon_lib::highlevel::constants::event_caching::ADD_TO_ROOM_CACHE
+    #[allow(dead_code)]
+    pub async fn raise_event(
+        &self,
+        event: RaiseEvent,
+        feature: &'static str,
+    ) -> Result<(), HaxError> {
+        let mut parameters = IndexMap::new();
+        event.into_map(&mut parameters);
+
+        let mut buf = vec![];
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: operation_code::RAISE_EVENT,
+            parameters,
+        })
+        .to_websocket_bytes(&mut buf)?;
+
+        self.send_server(Message::Binary(buf), feature).await
+    }
+
+    /// Closes the connection with a close frame to both the client and the server.
+    ///
+    /// The returned future doesn't borrow the proxy, so it can be awaited after the state's lock is released. This
+    /// isn't an injection, so it works while observing only.
+    pub(crate) fn close(&self, reason: &'static str) -> impl Future<Output = ()> + Send + 'static {
+        let sides = [
+            (self.client_send.clone(), "client"),
+            (self.server_send.clone(), "server"),
+        ];
+        async move {
+            for (send, side) in sides {
+                let mut send = send.lock().await;
+                let close_frame = CloseFrame {
+                    code: CloseCode::Away,
+                    reason: reason.into(),
+                };
+                if let Err(e) = send.send(Message::Close(Some(close_frame))).await {
+                    debug!("Could not send close frame to {side}: {e}");
+                }
+                _ = send.close().await;
+            }
+        }
+    }
+
+    /// Sends a message to the server in the background, for callers that hold the state lock and can't wait.
+    ///
+    /// Returns an error if the message can't be queued. Failures to send it are logged, as there's no one to return
+    /// them to by then.
+    pub fn queue_server(
+        &self,
+        message: PhotonMessage,
+        feature: &'static str,
+    ) -> Result<(), HaxError> {
+        self.queue(self.server_send.clone(), "server", message, feature)
+    }
+
+    /// Sends a message to the client in the background, like [Self::queue_server].
+    pub fn queue_client(
+        &self,
+        message: PhotonMessage,
+        feature: &'static str,
+    ) -> Result<(), HaxError> {
+        self.queue(self.client_send.clone(), "client", message, feature)
+    }
+
+    fn queue(
+        &self,
+        send: Arc<Mutex<SocketSink>>,
+        side: &'static str,
+        message: PhotonMessage,
+        feature: &'static str,
+    ) -> Result<(), HaxError> {
+        self.check_injection_allowed()?;
+        let mut buf = vec![];
+        message.to_websocket_bytes(&mut buf)?;
+        let runtime = tokio::runtime::Handle::try_current().map_err(|_| {
+            HaxError::InjectionUnavailable("no runtime to send the queued message on".into())
+        })?;
+
+        let bandwidth = self.bandwidth.clone();
+        let len = buf.len();
+        runtime.spawn(async move {
+            match send.lock().await.send(Message::Binary(buf)).await {
+                Ok(()) => bandwidth.record_injection(feature, len),
+                Err(e) => warn!(feature, "Could not send queued message to {side}: {e}"),
+            }
+        });
         Ok(())
     }
 }
@@ -110,50 +321,131 @@ async fn web_socket_proxy(
         return Ok(Response::new(Body::from("This is a websocket endpoint.")));
     }
 
-    let target_uri = {
-        let query_string = incoming_request
-            .uri()
-            .query()
-            .ok_or_else(|| anyhow::anyhow!("WebSocket handshake had no query string"))?;
-        hyper::Uri::from_str(query_string)
-            .with_context(|| "WebSocket handshake query string did not contain valid uri")?
+    let requested_uri = match incoming_request.uri().query() {
+        Some(query_string) => hyper::Uri::from_str(query_string)
+            .with_context(|| "WebSocket handshake query string did not contain valid uri")?,
+        // clients connecting to a rewritten game server address directly don't go through the url shim
+        None => incoming_request.uri().clone(),
+    };
+    let routed_uri = shared_state
+        .lock()
+        .await
+        .game_server_routes
+        .resolve(&requested_uri, Instant::now());
+    let (target_uri, target_server) = match routed_uri {
+        Some(upstream) => {
+            debug!("Resolved game server route {requested_uri} to {upstream}");
+            (upstream, Some(WebSocketServer::GameServer))
+        }
+        None if incoming_request.uri().query().is_none() => {
+            anyhow::bail!("WebSocket handshake had no query string")
+        }
+        None => {
+            let target_server = WebSocketServer::from_uri(&requested_uri);
+            (requested_uri, target_server)
+        }
     };
+    // the server type is taken from the address the client asked for, the upstream host may not tell it apart
+    let target_uri = shared_state
+        .lock()
+        .await
+        .proxy
+        .config()
+        .route_upstream(&target_uri);
     let target_port = target_uri.port_u16().unwrap_or(0);
+    let listener = incoming_request
+        .extensions()
+        .get::<ListenerAddr>()
+        .map(|l| l.0);
 
     info!("New incoming WebSocket request for {target_uri}");
 
     let (mut outgoing_response, websocket) =
         hyper_tungstenite::upgrade(&mut incoming_request, None)?;
 
-    let (server_send, server_recv) = {
-        // headers to send back to the client
-        let response_headers = ["sec-websocket-protocol"];
-
-        // headers to forward to the server
-        let forwarded_headers = [
-            "sec-websocket-protocol",
-            "sec-websocket-key",
-            "sec-websocket-version",
-            "sec-websocket-extensions",
-        ];
+    // headers to forward to the server. `sec-websocket-key` is forwarded separately, as it should not be reused when
+    // reconnecting.
+    let forwarded_headers = [
+        SUBPROTOCOL_HEADER,
+        "sec-websocket-version",
+        "sec-websocket-extensions",
+    ];
+    let mut upstream_target = UpstreamTarget {
+        uri: target_uri.clone(),
+        headers: forwarded_headers
+            .into_iter()
+            .filter_map(|header_name| {
+                incoming_request
+                    .headers()
+                    .get(header_name)
+                    .map(|value| (HeaderName::from_static(header_name), value.clone()))
+            })
+            .collect(),
+    };
 
-        let mut request = Request::builder()
-            .method("GET")
-            .header("host", target_uri.host().unwrap())
-            .header("connection", "Upgrade")
-            .header("upgrade", "websocket")
-            .uri(&target_uri);
-        for header_name in forwarded_headers {
-            if let Some(header_value) = incoming_request.headers().get(header_name) {
-                request = request.header(header_name, header_value);
+    // see protocol_pin
+    let forced_protocol = {
+        let settings = shared_state.lock().await.settings();
+        settings.forced_protocol.filter(|_| !settings.is_inert())
+    };
+    let pinned = match forced_protocol {
+        Some(forced) => {
+            let offered = incoming_request
+                .headers()
+                .get(SUBPROTOCOL_HEADER)
+                .and_then(|v| v.to_str().ok());
+            match protocol_pin::pin_offer(forced, offered) {
+                Ok(subprotocol) => {
+                    upstream_target
+                        .headers
+                        .retain(|(name, _)| name != SUBPROTOCOL_HEADER);
+                    upstream_target.headers.push((
+                        HeaderName::from_static(SUBPROTOCOL_HEADER),
+                        HeaderValue::from_static(subprotocol),
+                    ));
+                    Some(forced)
+                }
+                Err(e) => {
+                    error!("{e}");
+                    shared_state.lock().await.stats.record_error(e);
+                    None
+                }
             }
         }
-        let request = request.body(()).unwrap();
+        None => None,
+    };
+
+    let (server_send, server_recv) = {
+        // headers to send back to the client
+        let response_headers = [SUBPROTOCOL_HEADER];
+
+        let request = upstream_target
+            .to_request(incoming_request.headers().get("sec-websocket-key").cloned());
         let (ws_stream, response) = tokio_tungstenite::connect_async(request)
             .await
             .with_context(|| "Failed to connect to server")?;
         debug!("WebSocket handshake has been successfully completed");
 
+        if let Some(forced) = pinned {
+            let accepted = response
+                .headers()
+                .get(SUBPROTOCOL_HEADER)
+                .and_then(|v| v.to_str().ok());
+            if let Err(e) = protocol_pin::check_accepted(forced, accepted) {
+                shared_state.lock().await.stats.record_error(&e);
+                return Err(e.into());
+            }
+            info!("Pinned the connection to protocol {forced}");
+        }
+        let negotiated = response
+            .headers()
+            .get(SUBPROTOCOL_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(ProtocolVersion::from_subprotocol);
+        if let Some(negotiated) = negotiated.filter(|v| !v.is_parsed()) {
+            warn!("The connection uses protocol {negotiated}, whose messages can't be parsed");
+        }
+
         // copy response headers to outging response
         for header_name in response_headers {
             if let Some(header_value) = response.headers().get(header_name) {
@@ -178,64 +470,125 @@ async fn web_socket_proxy(
             }
         };
 
-        let (client_send, client_recv) = ws.split();
-        debug!("Created client streams");
-
-        let notify_closed = Arc::new(Notify::new());
-
-        // these explicit type definitions are required because it tells the compiler to use `Box<impl SomeTrait>`
-        let client_send: SocketSink = Box::new(client_send);
-        let server_send: SocketSink = Box::new(server_send);
-
-        let client_send = Arc::new(Mutex::new(client_send));
-        let server_send = Arc::new(Mutex::new(server_send));
-
-        let client_to_server = start_proxy_task(
-            Box::new(client_recv),
-            server_send.clone(),
+        proxy_connection(
+            ws,
+            (Box::new(server_send), Box::new(server_recv)),
+            upstream_target,
             target_port,
-            Direction::ClientToServer,
-            notify_closed.clone(),
-            shared_state.clone(),
-        );
-        let server_to_client = start_proxy_task(
-            Box::new(server_recv),
-            client_send.clone(),
-            target_port,
-            Direction::ServerToClient,
-            notify_closed.clone(),
-            shared_state.clone(),
-        );
-
-        debug!("Sending websocket proxy object over channel");
-        let send_result = new_connection_sender
-            .send(WebSocketProxy {
-                client_send,
-                server_send,
-                client_to_server,
-                server_to_client,
-                port: target_uri.port().map(|p| p.as_u16()).unwrap_or(0),
-                notify_closed: Some(notify_closed),
-            })
-            .await;
-
-        if let Err(e) = send_result {
-            error!("Failed to send new websocket proxy over channel: {e}");
-        };
+            target_server,
+            listener,
+            shared_state,
+            new_connection_sender,
+        )
+        .await;
     });
 
     Ok(outgoing_response)
 }
 
+/// Relays messages between an accepted client connection and its upstream server, and hands the [WebSocketProxy] for
+/// the connection to `new_connection_sender`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn proxy_connection<C>(
+    client: C,
+    (server_send, server_recv): (SocketSink, SocketStream),
+    upstream_target: UpstreamTarget,
+    target_port: u16,
+    target_server: Option<WebSocketServer>,
+    listener: Option<SocketAddr>,
+    shared_state: Arc<Mutex<HaxState>>,
+    new_connection_sender: mpsc::Sender<WebSocketProxy>,
+) where
+    C: Stream<Item = tokio_tungstenite::tungstenite::Result<Message>>
+        + Sink<Message, Error = tokio_tungstenite::tungstenite::error::Error>
+        + Unpin
+        + Send
+        + 'static,
+{
+    let (client_send, client_recv) = client.split();
+    debug!("Created client streams");
+
+    let notify_closed = Arc::new(Notify::new());
+    let watchdog = Arc::new(std::sync::Mutex::new(ConnectionWatchdog::default()));
+
+    // these explicit type definitions are required because it tells the compiler to use `Box<impl SomeTrait>`
+    let client_send: SocketSink = Box::new(client_send);
+
+    let client_send = Arc::new(Mutex::new(client_send));
+    let server_send = Arc::new(Mutex::new(server_send));
+
+    let client_to_server = start_proxy_task(
+        Box::new(client_recv),
+        server_send.clone(),
+        target_port,
+        target_server,
+        Direction::ClientToServer,
+        notify_closed.clone(),
+        shared_state.clone(),
+        watchdog.clone(),
+    );
+    let server_to_client = start_proxy_task(
+        Box::new(server_recv),
+        client_send.clone(),
+        target_port,
+        target_server,
+        Direction::ServerToClient,
+        notify_closed.clone(),
+        shared_state.clone(),
+        watchdog.clone(),
+    );
+    let server_to_client = Arc::new(Mutex::new(server_to_client));
+
+    tokio::spawn(run_watchdog(WatchdogContext {
+        watchdog,
+        upstream_target,
+        client_send: client_send.clone(),
+        server_send: server_send.clone(),
+        server_to_client: server_to_client.clone(),
+        server_port: target_port,
+        server: target_server,
+        notify_closed: notify_closed.clone(),
+        shared_state: shared_state.clone(),
+    }));
+
+    let (bandwidth, settings) = {
+        let state = shared_state.lock().await;
+        (state.bandwidth.clone(), state.shared_settings())
+    };
+
+    debug!("Sending websocket proxy object over channel");
+    let send_result = new_connection_sender
+        .send(WebSocketProxy {
+            client_send,
+            server_send,
+            client_to_server: Some(client_to_server),
+            server_to_client: Some(server_to_client),
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            port: target_port,
+            server: target_server,
+            listener,
+            notify_closed: Some(notify_closed),
+            bandwidth,
+            settings,
+        })
+        .await;
+
+    if let Err(e) = send_result {
+        error!("Failed to send new websocket proxy over channel: {e}");
+    };
+}
+
+#[allow(clippy::too_many_arguments)]
 fn start_proxy_task(
     mut stream: SocketStream,
     sink: Arc<Mutex<SocketSink>>,
     server_port: u16,
+    server: Option<WebSocketServer>,
     direction: Direction,
     notify_closed: Arc<Notify>,
     shared_state: Arc<Mutex<HaxState>>,
+    watchdog: Arc<std::sync::Mutex<ConnectionWatchdog>>,
 ) -> tokio::task::JoinHandle<()> {
-    let server = WebSocketServer::from_port(server_port);
     let span = match server {
         Some(server) => info_span!("WebSocketProxy", "{} {}", server, direction),
         None => info_span!("WebSocketProxy", "port:{} {}", server_port, direction),
@@ -243,44 +596,286 @@ fn start_proxy_task(
     tokio::spawn(
         async move {
             debug!("Starting new proxy task");
+            let relay = shared_state.lock().await.relay.clone();
 
             while let Some(message) = stream.next().await {
-                // TODO: don't unwrap. what can this error on?
-                let mut message = message.unwrap();
+                let mut message = match message {
+                    Ok(message) => message,
+                    Err(e) => {
+                        warn!("Could not read from the websocket: {e}");
+                        if let Some(server) = server {
+                            let signal = DisconnectSignal::StreamError(e.to_string());
+                            shared_state
+                                .lock()
+                                .await
+                                .record_disconnect_signal(server, signal);
+                        }
+                        break;
+                    }
+                };
                 trace!("Message: {:?}", message);
+                if let (Message::Close(frame), Some(server)) = (&message, server) {
+                    let signal = DisconnectSignal::Close {
+                        direction,
+                        code: frame.as_ref().map(|f| f.code.into()),
+                        reason: frame
+                            .as_ref()
+                            .map(|f| f.reason.to_string())
+                            .unwrap_or_default(),
+                    };
+                    shared_state
+                        .lock()
+                        .await
+                        .record_disconnect_signal(server, signal);
+                }
+                let mut in_flight = relay.read(direction, message.len(), Instant::now());
+                let mut held = None;
 
                 // handle hook
                 if let Some(server) = server {
                     // TODO: install proper hook
                     if let Message::Binary(bytes) = &mut message {
-                        let result = HaxState::websocket_hook(
+                        let result = HaxState::websocket_hook_verdict(
                             shared_state.clone(),
                             bytes,
                             server,
                             direction,
                         );
+                        match &result {
+                            Ok(_) | Err(HaxError::StateLock(_)) => (),
+                            Err(e) => shared_state.lock().await.stats.record_error(e),
+                        }
                         match result {
-                            Ok(true) => (),        // message should be forwarded
-                            Ok(false) => continue, // message should not be sent
+                            Ok(HookVerdict::Forward) => (),
+                            Ok(HookVerdict::Drop) => continue,
+                            Ok(HookVerdict::Hold(hold)) => held = Some(hold),
+                            // fail open, the game may still understand the message even if we don't
+                            Err(e @ HaxError::ProtocolParse { .. }) => {
+                                warn!("Forwarding message that could not be parsed: {e}");
+                            }
+                            // can happen when messages arrive while a connection is being torn down
+                            Err(e @ HaxError::StateLock(_)) => {
+                                debug!("Forwarding message with missing state: {e}");
+                            }
+                            Err(e @ HaxError::HandlerFailed { .. }) => {
+                                error!("Error during websocket hook handler, forwarding original message: {e}");
+                            }
                             Err(e) => {
-                                error!("Error during websocket hook handler: {}", e);
+                                error!("Error during websocket hook handler: {e}");
                             }
                         }
                     }
                 }
 
-                let send_result = sink.lock().await.send(message).await;
-
-                match send_result {
-                    Ok(_) => (),
-                    Err(tokio_tungstenite::tungstenite::Error::ConnectionClosed) => (), // this is fine
-                    Err(e) => error!("An error occured while sending a packet: {e}"),
+                if let (Some(hold), Some(server)) = (held, server) {
+                    // the time spent held isn't relay latency, so the message is timed again once it's released
+                    drop(in_flight);
+                    match hold.ordering {
+                        HoldOrdering::Block => {
+                            let outcome =
+                                hold::wait_for_release(&shared_state, &hold, server, direction)
+                                    .await;
+                            if outcome == HoldOutcome::Dropped {
+                                continue;
+                            }
+                            in_flight = relay.read(direction, message.len(), Instant::now());
+                        }
+                        HoldOrdering::Bypass => {
+                            let (sink, shared_state, watchdog) =
+                                (sink.clone(), shared_state.clone(), watchdog.clone());
+                            tokio::spawn(
+                                async move {
+                                    let outcome = hold::wait_for_release(
+                                        &shared_state,
+                                        &hold,
+                                        server,
+                                        direction,
+                                    )
+                                    .await;
+                                    if outcome == HoldOutcome::Dropped {
+                                        return;
+                                    }
+                                    let relay = shared_state.lock().await.relay.clone();
+                                    let in_flight =
+                                        relay.read(direction, message.len(), Instant::now());
+                                    forward(message, &sink, in_flight, &watchdog, direction).await;
+                                }
+                                .in_current_span(),
+                            );
+                            continue;
+                        }
+                    }
                 }
+
+                forward(message, &sink, in_flight, &watchdog, direction).await;
             }
 
             // signal death of the connection
+            watchdog.lock().expect("watchdog lock is poisoned").closed = true;
             notify_closed.notify_one();
         }
         .instrument(span),
     )
 }
+
+/// Sends a message the hooks are done with to the other side.
+async fn forward(
+    message: Message,
+    sink: &Mutex<SocketSink>,
+    mut in_flight: InFlight<'_>,
+    watchdog: &std::sync::Mutex<ConnectionWatchdog>,
+    direction: Direction,
+) {
+    if let Message::Binary(bytes) = &message {
+        let mut watchdog = watchdog.lock().expect("watchdog lock is poisoned");
+        match direction {
+            Direction::ClientToServer => watchdog.observe_client_message(bytes, Instant::now()),
+            Direction::ServerToClient => watchdog.observe_server_message(bytes, Instant::now()),
+        }
+    }
+
+    in_flight.writing(message.len());
+    let mut sink = sink.lock().await;
+    let mut send = sink.send(message);
+    // a send that doesn't complete on the first poll is waiting for the socket to drain
+    let send_result = match futures_util::poll!(&mut send) {
+        Poll::Ready(result) => result,
+        Poll::Pending => {
+            in_flight.blocked();
+            send.await
+        }
+    };
+    drop(sink);
+
+    match send_result {
+        Ok(_) => in_flight.written(Instant::now()),
+        Err(tokio_tungstenite::tungstenite::Error::ConnectionClosed) => (), // this is fine
+        Err(e) => error!("An error occured while sending a packet: {e}"),
+    }
+}
+
+/// Everything the watchdog needs to monitor and recover a proxied connection.
+struct WatchdogContext {
+    watchdog: Arc<std::sync::Mutex<ConnectionWatchdog>>,
+    upstream_target: UpstreamTarget,
+    client_send: Arc<Mutex<SocketSink>>,
+    server_send: Arc<Mutex<SocketSink>>,
+    server_to_client: Arc<Mutex<tokio::task::JoinHandle<()>>>,
+    server_port: u16,
+    server: Option<WebSocketServer>,
+    notify_closed: Arc<Notify>,
+    shared_state: Arc<Mutex<HaxState>>,
+}
+
+/// Periodically checks whether the upstream server is still answering pings, and recovers the connection according to
+/// the configured [WatchdogMode] if it isn't.
+async fn run_watchdog(ctx: WatchdogContext) {
+    let server = ctx.server;
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        interval.tick().await;
+
+        let settings = ctx.shared_state.lock().await.watchdog.clone();
+        let silent_for = {
+            let watchdog = ctx.watchdog.lock().expect("watchdog lock is poisoned");
+            if watchdog.closed {
+                break;
+            }
+            watchdog.silent_for(Instant::now())
+        };
+
+        if settings.mode == WatchdogMode::Disabled {
+            continue;
+        }
+        let silent_for = match silent_for {
+            Some(d) if d >= settings.timeout => d,
+            _ => continue,
+        };
+
+        warn!(
+            server = format!("{server:?}"),
+            "Upstream server has not answered pings for {silent_for:?}"
+        );
+        emit_event(
+            &ctx.shared_state,
+            HaxEvent::UpstreamSilent { server, silent_for },
+        )
+        .await;
+
+        // tear down the dead upstream. closing may never complete if the connection is dead, so don't wait for it
+        ctx.server_to_client.lock().await.abort();
+        _ = tokio::time::timeout(Duration::from_secs(1), async {
+            ctx.server_send.lock().await.close().await
+        })
+        .await;
+
+        if settings.mode == WatchdogMode::Reconnect {
+            let auth_request = ctx
+                .watchdog
+                .lock()
+                .expect("watchdog lock is poisoned")
+                .auth_request()
+                .map(|r| r.to_vec());
+
+            match reconnect_upstream(&ctx.upstream_target, auth_request, settings.timeout).await {
+                Ok((new_server_send, new_server_recv)) => {
+                    info!("Transparently reconnected to upstream server");
+                    *ctx.server_send.lock().await = new_server_send;
+                    *ctx.server_to_client.lock().await = start_proxy_task(
+                        new_server_recv,
+                        ctx.client_send.clone(),
+                        ctx.server_port,
+                        server,
+                        Direction::ServerToClient,
+                        ctx.notify_closed.clone(),
+                        ctx.shared_state.clone(),
+                        ctx.watchdog.clone(),
+                    );
+                    ctx.watchdog
+                        .lock()
+                        .expect("watchdog lock is poisoned")
+                        .reset();
+
+                    emit_event(&ctx.shared_state, HaxEvent::UpstreamReconnected { server }).await;
+                    continue;
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to reconnect to upstream server, closing client connection: {e:?}"
+                    );
+                    emit_event(
+                        &ctx.shared_state,
+                        HaxEvent::UpstreamReconnectFailed {
+                            server,
+                            reason: format!("{e:#}"),
+                        },
+                    )
+                    .await;
+                }
+            }
+        }
+
+        // let the client know, so the game can show its reconnect UI
+        info!("Closing client connection because the upstream server is unresponsive");
+        let close_frame = CloseFrame {
+            code: CloseCode::Away,
+            reason: "upstream server is unresponsive".into(),
+        };
+        let mut client_send = ctx.client_send.lock().await;
+        if let Err(e) = client_send.send(Message::Close(Some(close_frame))).await {
+            warn!("Failed to send close frame to client: {e}");
+        }
+        _ = client_send.close().await;
+        drop(client_send);
+
+        emit_event(&ctx.shared_state, HaxEvent::ClientDisconnected { server }).await;
+        break;
+    }
+
+    debug!("Watchdog stopped");
+}
+
+async fn emit_event(shared_state: &Mutex<HaxState>, event: HaxEvent) {
+    shared_state.lock().await.events.emit(event);
+}
@@ -0,0 +1,186 @@
+//! Exporting the [join flow](crate::hax::join_trace) spans to an OpenTelemetry collector.
+//!
+//! Add the layer from [otlp_layer] to the tracing subscriber. It only exports the join flow spans, the rest of the
+//! logs stay with the other layers. Any collector that accepts OTLP over HTTP works, eg. a local Jaeger:
+//! ```sh
+//! docker run --rm -p 16686:16686 -p 4318:4318 jaegertracing/all-in-one
+//! ```
+//! with `http://localhost:4318` as the endpoint, after which the joins show up under the `bulletforcehax2` service.
+
+use opentelemetry::{
+    trace::{TraceError, Tracer},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing::{Level, Subscriber};
+use tracing_opentelemetry::PreSampledTracer;
+use tracing_subscriber::{filter::Targets, registry::LookupSpan, Layer};
+
+use crate::hax::join_trace;
+
+/// The service the spans are exported under.
+pub const SERVICE_NAME: &str = "bulletforcehax2";
+
+/// A layer exporting the join flow spans over OTLP/HTTP to the collector at the endpoint, eg.
+/// `http://localhost:4318`. Must be called from within a tokio runtime, which the spans are exported on.
+pub fn otlp_layer<S>(endpoint: &str) -> Result<impl Layer<S>, TraceError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_resource(Resource::new([KeyValue::new("service.name", SERVICE_NAME)])),
+        )
+        .install_batch(runtime::Tokio)?;
+    Ok(join_flow_layer(tracer))
+}
+
+/// Exports the spans that haven't been yet. Call before exiting, or the last join may be lost.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+fn join_flow_layer<S, T>(tracer: T) -> impl Layer<S>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    T: Tracer + PreSampledTracer + Send + Sync + 'static,
+{
+    tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(Targets::new().with_target(join_trace::TARGET, Level::INFO))
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::{
+        trace::{SpanId, TracerProvider as _},
+        Value,
+    };
+    use opentelemetry_sdk::{testing::trace::InMemorySpanExporter, trace::TracerProvider};
+    use photon_lib::{
+        highlevel::constants::{event_code, operation_code, pun_event_code},
+        indexmap::indexmap,
+        photon_message::{EventData, OperationRequest, OperationResponse, PhotonMessage},
+    };
+    use tracing_subscriber::prelude::*;
+
+    use super::join_flow_layer;
+    use crate::{
+        hax::join_trace::JoinTrace,
+        proxy::{Direction, WebSocketServer},
+    };
+
+    fn request(operation_code: u8) -> PhotonMessage {
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code,
+            parameters: indexmap! {},
+        })
+    }
+
+    fn response(operation_code: u8) -> PhotonMessage {
+        PhotonMessage::OperationResponse(OperationResponse {
+            operation_code,
+            return_code: 0,
+            debug_message: None,
+            parameters: indexmap! {},
+        })
+    }
+
+    fn event(code: u8) -> PhotonMessage {
+        PhotonMessage::EventData(EventData {
+            code,
+            parameters: indexmap! {},
+        })
+    }
+
+    #[test]
+    fn join_flow_spans_share_a_parent() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber =
+            tracing_subscriber::registry().with(join_flow_layer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            use Direction::*;
+            use WebSocketServer::*;
+
+            // not part of the flow
+            tracing::info_span!("WebSocketProxy").in_scope(|| {
+                let mut trace = JoinTrace::new("session".into());
+                drop(trace.web_request("https://example.com/matchmaking"));
+                trace.observe(
+                    LobbyServer,
+                    ClientToServer,
+                    &request(operation_code::AUTHENTICATE),
+                );
+                trace.observe(
+                    LobbyServer,
+                    ServerToClient,
+                    &response(operation_code::AUTHENTICATE),
+                );
+                trace.observe(LobbyServer, ServerToClient, &event(event_code::GAME_LIST));
+                trace.observe(LobbyServer, ServerToClient, &event(event_code::GAME_LIST));
+                trace.observe(
+                    LobbyServer,
+                    ClientToServer,
+                    &request(operation_code::JOIN_GAME),
+                );
+                trace.observe(
+                    GameServer,
+                    ServerToClient,
+                    &event(pun_event_code::SEND_SERIALIZE),
+                );
+                assert!(!trace.in_progress());
+                // later serialize events don't start another flow
+                trace.observe(
+                    GameServer,
+                    ServerToClient,
+                    &event(pun_event_code::SEND_SERIALIZE),
+                );
+                assert!(!trace.in_progress());
+            });
+        });
+        provider.force_flush();
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let root = spans.iter().find(|s| s.name == "join flow").unwrap();
+        assert_eq!(root.parent_span_id, SpanId::INVALID);
+        let mut steps: Vec<_> = spans
+            .iter()
+            .filter(|s| s.parent_span_id == root.span_context.span_id())
+            .collect();
+        steps.sort_by_key(|s| s.start_time);
+        assert_eq!(
+            steps.iter().map(|s| s.name.as_ref()).collect::<Vec<_>>(),
+            [
+                "web request",
+                "lobby authenticate",
+                "game list",
+                "join request",
+                "first serialize"
+            ]
+        );
+        assert_eq!(spans.len(), steps.len() + 1);
+        assert!(spans
+            .iter()
+            .all(|s| s.span_context.trace_id() == root.span_context.trace_id()));
+        for span in &spans {
+            let session_id = span
+                .attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == "session_id")
+                .map(|kv| &kv.value);
+            assert_eq!(session_id, Some(&Value::from("session")));
+        }
+    }
+}
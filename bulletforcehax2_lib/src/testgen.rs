@@ -0,0 +1,217 @@
+//! Synthetic BulletForce traffic, to demo and test features without a live connection.
+//!
+//! Everything is generated from a seed, so the same settings always produce the same bytes.
+
+use photon_lib::{
+    highlevel::constants::{event_code, game_property_key, parameter_code},
+    photon_data_type::PhotonDataType,
+    photon_message::{EventData, PhotonMessage},
+    PhotonHashmap,
+};
+
+use crate::indexmap::indexmap;
+
+const MAP_NAMES: &[&str] = &[
+    "Urban", "Outpost", "Station", "Factory", "Downtown", "Harbor", "Liberty", "Heist", "Raid",
+];
+const ROOM_NAME_PREFIXES: &[&str] = &[
+    "Snipers only",
+    "No camping",
+    "Pros",
+    "Chill",
+    "Noobs welcome",
+    "Knife fight",
+    "1v1 me",
+    "Clan match",
+    "Fun",
+    "Tryhards",
+];
+const STORE_IDS: &[&str] = &["BALYZE_MOBILE", "BALYZE_IOS"];
+/// What rooms of other versions run, older versions are more common.
+const OTHER_VERSIONS: &[&str] = &["1.89.0", "1.89.0", "1.88.2", "1.91.0"];
+
+/// A small deterministic random number generator (SplitMix64), so runs can be reproduced from their seed.
+pub(crate) struct SplitMix64(pub u64);
+
+impl SplitMix64 {
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0.0..1.0`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    pub fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + self.next_f32() * (max - min)
+    }
+
+    fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[(self.next_u64() % items.len() as u64) as usize]
+    }
+}
+
+/// Builds [GAME_LIST](event_code::GAME_LIST) events with rooms that look like the ones BulletForce lists.
+///
+/// ```
+/// # use bulletforcehax2_lib::testgen::GameListBuilder;
+/// let bytes = GameListBuilder::new(50)
+///     .with_seed(7)
+///     .with_password_fraction(0.5)
+///     .to_websocket_bytes();
+/// assert_eq!(bytes, GameListBuilder::new(50).with_seed(7).with_password_fraction(0.5).to_websocket_bytes());
+/// ```
+#[derive(Debug, Clone)]
+pub struct GameListBuilder {
+    rooms: usize,
+    seed: u64,
+    game_version: String,
+    password_fraction: f32,
+    mobile_fraction: f32,
+    other_version_fraction: f32,
+}
+
+impl GameListBuilder {
+    /// A game list with the given amount of rooms. Defaults to seed 0 and version `1.90.0`, with a tenth of the rooms
+    /// passworded, a third from mobile and a fifth from other versions.
+    pub fn new(rooms: usize) -> Self {
+        Self {
+            rooms,
+            seed: 0,
+            game_version: "1.90.0".into(),
+            password_fraction: 0.1,
+            mobile_fraction: 0.3,
+            other_version_fraction: 0.2,
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// The version most rooms run, which the client should be on too.
+    pub fn with_game_version(mut self, game_version: impl Into<String>) -> Self {
+        self.game_version = game_version.into();
+        self
+    }
+
+    /// The chance for a room to have a password, between 0 and 1.
+    pub fn with_password_fraction(mut self, fraction: f32) -> Self {
+        self.password_fraction = fraction;
+        self
+    }
+
+    /// The chance for a room to be hosted from a mobile store, between 0 and 1.
+    pub fn with_mobile_fraction(mut self, fraction: f32) -> Self {
+        self.mobile_fraction = fraction;
+        self
+    }
+
+    /// The chance for a room to run another game version, between 0 and 1.
+    pub fn with_other_version_fraction(mut self, fraction: f32) -> Self {
+        self.other_version_fraction = fraction;
+        self
+    }
+
+    /// The rooms keyed by their id, as held by [RoomInfoList](photon_lib::highlevel::structs::RoomInfoList).
+    pub fn games(&self) -> PhotonHashmap {
+        let mut rng = SplitMix64(self.seed);
+        let string = |s: &str| PhotonDataType::String(s.into());
+        (0..self.rooms)
+            .map(|i| {
+                let id = format!("{:016x}", rng.next_u64());
+                let max_players = [8u8, 10, 12][(rng.next_u64() % 3) as usize];
+                let player_count = (rng.next_u64() % (max_players as u64 + 1)) as u8;
+                let room_name = format!("{} {}", rng.pick(ROOM_NAME_PREFIXES), i + 1);
+                let password = match rng.next_f32() < self.password_fraction {
+                    true => format!("{:04}", rng.next_u64() % 10000),
+                    false => String::new(),
+                };
+                let store_id = match rng.next_f32() < self.mobile_fraction {
+                    true => rng.pick(STORE_IDS),
+                    false => "BALYZE_WEB",
+                };
+                let game_version = match rng.next_f32() < self.other_version_fraction {
+                    true => rng.pick(OTHER_VERSIONS),
+                    false => &self.game_version,
+                };
+                let room = indexmap! {
+                    PhotonDataType::Byte(game_property_key::MAX_PLAYERS) => PhotonDataType::Byte(max_players),
+                    PhotonDataType::Byte(game_property_key::IS_OPEN) => PhotonDataType::Boolean(true),
+                    PhotonDataType::Byte(game_property_key::PLAYER_COUNT) => PhotonDataType::Byte(player_count),
+                    string("roomName") => string(&room_name),
+                    string("password") => string(&password),
+                    string("storeID") => string(store_id),
+                    string("gameVersion") => string(game_version),
+                    string("mapName") => string(rng.pick(MAP_NAMES)),
+                    string("switchingmap") => PhotonDataType::Boolean(false),
+                    string("meanKD") => PhotonDataType::Float(rng.range(0.5, 2.5).into()),
+                    string("seasonID") => string(""),
+                    string("eventcode") => PhotonDataType::Integer(0),
+                };
+                (string(&id), PhotonDataType::Hashtable(room))
+            })
+            .collect()
+    }
+
+    pub fn build(&self) -> PhotonMessage {
+        PhotonMessage::EventData(EventData {
+            code: event_code::GAME_LIST,
+            parameters: indexmap! {
+                parameter_code::GAME_LIST => PhotonDataType::Hashtable(self.games()),
+            },
+        })
+    }
+
+    /// The event as the lobby server sends it.
+    pub fn to_websocket_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.build()
+            .to_websocket_bytes(&mut bytes)
+            .expect("generated game list should serialize");
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use photon_lib::{highlevel::structs::RoomInfoView, photon_data_type::PhotonDataType};
+
+    use super::GameListBuilder;
+
+    #[test]
+    fn deterministic_rooms() {
+        let builder = GameListBuilder::new(200)
+            .with_seed(42)
+            .with_password_fraction(0.25);
+        let games = builder.games();
+        assert_eq!(games.len(), 200);
+        assert_eq!(games, builder.clone().games());
+        assert_ne!(games, builder.clone().with_seed(43).games());
+
+        let rooms = games
+            .values()
+            .map(|room| match room {
+                PhotonDataType::Hashtable(room) => RoomInfoView(room),
+                other => panic!("expected a room, got {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        let count = |f: &dyn Fn(&RoomInfoView<_>) -> bool| rooms.iter().filter(|r| f(r)).count();
+        let passworded = count(&|r| r.custom_str("password").is_some_and(|p| !p.is_empty()));
+        let mobile = count(&|r| r.custom_str("storeID") != Some("BALYZE_WEB"));
+        let other_version = count(&|r| r.custom_str("gameVersion") != Some("1.90.0"));
+        assert!((30..70).contains(&passworded), "{passworded} passworded");
+        assert!((40..80).contains(&mobile), "{mobile} mobile");
+        assert!(
+            (20..60).contains(&other_version),
+            "{other_version} other versions"
+        );
+        assert!(rooms.iter().all(|r| r.player_count() <= r.max_players()));
+    }
+}
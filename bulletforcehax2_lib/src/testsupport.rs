@@ -0,0 +1,452 @@
+//! An in-process Photon server to test the proxy end-to-end.
+//!
+//! [ProxiedConnection] runs a real proxy connection between a websocket client and a [MockPhotonServer], so tests can
+//! check what each side receives after the hooks ran.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use futures_util::{lock::Mutex, SinkExt, StreamExt};
+use photon_lib::{
+    indexmap::indexmap,
+    photon_data_type::PhotonDataType,
+    photon_message::{EventData, OperationRequest, PhotonMessage},
+    protocol_version::{subprotocols, ProtocolVersion},
+    ParameterMap,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc},
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::server::{Request, Response},
+        http::HeaderValue,
+        protocol::CloseFrame,
+        Message,
+    },
+    MaybeTlsStream, WebSocketStream,
+};
+
+use crate::{
+    hax::{events::HaxEvent, BulletForceHax, HaxState},
+    inspect::CapturedMessage,
+    proxy::{
+        protocol_pin::SUBPROTOCOL_HEADER,
+        watchdog::{UpstreamTarget, PING_CLIENT_TIME_PARAMETER, PING_OPERATION_CODE},
+        websocket_proxy::{proxy_connection, SocketSink},
+        Direction, WebSocketServer,
+    },
+};
+
+/// How long to wait for a message before failing the test.
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+/// The parameter that holds the server's timestamp in ping responses.
+const PING_SERVER_TIME_PARAMETER: u8 = 2;
+/// The server time sent in ping responses.
+pub(crate) const MOCK_SERVER_TIME: i32 = 0x38C2_510F;
+
+fn to_bytes(message: &PhotonMessage) -> Vec<u8> {
+    let mut bytes = vec![];
+    message
+        .to_websocket_bytes(&mut bytes)
+        .expect("test messages should serialize");
+    bytes
+}
+
+/// A string value, as used for most property keys.
+pub(crate) fn string(s: &str) -> PhotonDataType {
+    PhotonDataType::String(s.into())
+}
+
+/// A message as the proxy would have captured it from the game server just now.
+pub(crate) fn captured(direction: Direction, message: PhotonMessage) -> CapturedMessage {
+    captured_from(WebSocketServer::GameServer, direction, message)
+}
+
+/// A message as the proxy would have captured it from the given server just now.
+pub(crate) fn captured_from(
+    server: WebSocketServer,
+    direction: Direction,
+    message: PhotonMessage,
+) -> CapturedMessage {
+    CapturedMessage {
+        timestamp: SystemTime::now(),
+        server,
+        direction,
+        raw: to_bytes(&message),
+    }
+}
+
+/// An event as the proxy would have captured it from the game server just now.
+pub(crate) fn event(code: u8, parameters: ParameterMap) -> CapturedMessage {
+    captured(
+        Direction::ServerToClient,
+        PhotonMessage::EventData(EventData { code, parameters }),
+    )
+}
+
+/// A request as the proxy would have captured it from the client just now.
+pub(crate) fn request(operation_code: u8, parameters: ParameterMap) -> CapturedMessage {
+    captured(
+        Direction::ClientToServer,
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code,
+            parameters,
+        }),
+    )
+}
+
+/// The next event that `select` picks, skipping the others.
+pub(crate) async fn next_event<T>(
+    events: &mut broadcast::Receiver<HaxEvent>,
+    mut select: impl FnMut(HaxEvent) -> Option<T>,
+) -> T {
+    tokio::time::timeout(RECV_TIMEOUT, async {
+        loop {
+            if let Some(found) = select(events.recv().await.unwrap()) {
+                return found;
+            }
+        }
+    })
+    .await
+    .expect("event was not emitted in time")
+}
+
+/// Waits for the next binary message and parses it, skipping control frames.
+async fn recv_photon<S>(stream: &mut S, who: &str) -> PhotonMessage
+where
+    S: futures_util::Stream<Item = tokio_tungstenite::tungstenite::Result<Message>> + Unpin,
+{
+    loop {
+        let message = match tokio::time::timeout(RECV_TIMEOUT, stream.next()).await {
+            Ok(Some(message)) => message.unwrap(),
+            Ok(None) => panic!("connection to {who} closed"),
+            Err(_) => panic!("{who} did not receive a message in time"),
+        };
+        match message {
+            Message::Binary(bytes) => {
+                return PhotonMessage::from_websocket_bytes(&mut bytes.as_slice()).unwrap()
+            }
+            Message::Close(_) => panic!("connection to {who} closed"),
+            _ => continue,
+        }
+    }
+}
+
+/// What a [MockPhotonServer] is told to do next.
+#[derive(Debug)]
+enum ServerAction {
+    Send(PhotonMessage),
+    /// Sends a close frame and keeps reading, so the client's answer is read.
+    Close(u16, &'static str),
+    /// Closes the TCP connection without a close frame.
+    Drop,
+}
+
+/// A websocket server that accepts a single connection and speaks just enough Photon to keep the proxy happy.
+///
+/// Pings are answered on its own, every other message is recorded for [Self::recv].
+pub(crate) struct MockPhotonServer {
+    port: u16,
+    outgoing: mpsc::UnboundedSender<ServerAction>,
+    received: mpsc::UnboundedReceiver<PhotonMessage>,
+}
+
+impl MockPhotonServer {
+    pub async fn start() -> Self {
+        Self::start_speaking(&[]).await
+    }
+
+    /// Like [Self::start], accepting the first of the serialization protocols the client offers that is in
+    /// `protocols`. The connection is accepted without a protocol if there is none.
+    pub async fn start_speaking(protocols: &'static [ProtocolVersion]) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (outgoing, mut script) = mpsc::unbounded_channel::<ServerAction>();
+        let (record, received) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            // the error type is tungstenite's
+            #[allow(clippy::result_large_err)]
+            let pick = |request: &Request, mut response: Response| {
+                let offered = request
+                    .headers()
+                    .get(SUBPROTOCOL_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default();
+                let picked = subprotocols(offered)
+                    .filter_map(ProtocolVersion::from_subprotocol)
+                    .find(|v| protocols.contains(v));
+                if let Some(picked) = picked {
+                    response.headers_mut().insert(
+                        SUBPROTOCOL_HEADER,
+                        HeaderValue::from_static(picked.subprotocol()),
+                    );
+                }
+                Ok(response)
+            };
+            let (mut sink, mut stream) = tokio_tungstenite::accept_hdr_async(tcp, pick)
+                .await
+                .unwrap()
+                .split();
+            loop {
+                tokio::select! {
+                    action = script.recv() => match action {
+                        Some(ServerAction::Send(message)) => {
+                            sink.send(Message::Binary(to_bytes(&message))).await.unwrap()
+                        }
+                        Some(ServerAction::Close(code, reason)) => {
+                            let frame = CloseFrame {
+                                code: code.into(),
+                                reason: reason.into(),
+                            };
+                            // the client may be gone already
+                            _ = sink.send(Message::Close(Some(frame))).await;
+                        }
+                        Some(ServerAction::Drop) | None => break,
+                    },
+                    message = stream.next() => match message {
+                        Some(Ok(Message::Binary(bytes))) => {
+                            let message = PhotonMessage::from_websocket_bytes(&mut bytes.as_slice()).unwrap();
+                            match ping_response(&message) {
+                                Some(response) => sink.send(Message::Binary(to_bytes(&response))).await.unwrap(),
+                                None => _ = record.send(message),
+                            }
+                        }
+                        Some(Ok(_)) => (),
+                        _ => break,
+                    },
+                }
+            }
+        });
+
+        Self {
+            port,
+            outgoing,
+            received,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("ws://127.0.0.1:{}", self.port)
+    }
+
+    /// Sends a message to the connected client.
+    pub fn send(&self, message: PhotonMessage) {
+        self.outgoing.send(ServerAction::Send(message)).unwrap();
+    }
+
+    /// Sends the messages to the connected client, in order.
+    pub fn send_all(&self, messages: impl IntoIterator<Item = PhotonMessage>) {
+        for message in messages {
+            self.send(message);
+        }
+    }
+
+    /// Closes the connection with a close frame, like a server that kicks the client.
+    pub fn close(&self, code: u16, reason: &'static str) {
+        self.outgoing
+            .send(ServerAction::Close(code, reason))
+            .unwrap();
+    }
+
+    /// Drops the connection without closing it, like a server that crashed.
+    pub fn drop_connection(&self) {
+        self.outgoing.send(ServerAction::Drop).unwrap();
+    }
+
+    /// The next message the client sent that wasn't a ping.
+    pub async fn recv(&mut self) -> PhotonMessage {
+        tokio::time::timeout(RECV_TIMEOUT, self.received.recv())
+            .await
+            .expect("server did not receive a message in time")
+            .expect("server task stopped")
+    }
+}
+
+/// Answers a ping like the Photon server does, with the client's time and [MOCK_SERVER_TIME].
+fn ping_response(message: &PhotonMessage) -> Option<PhotonMessage> {
+    let request = match message {
+        PhotonMessage::InternalOperationRequest(r) if r.operation_code == PING_OPERATION_CODE => r,
+        _ => return None,
+    };
+    let client_time = request.parameters.get(&PING_CLIENT_TIME_PARAMETER)?.clone();
+    Some(PhotonMessage::InternalOperationResponse(
+        photon_lib::photon_message::OperationResponse {
+            operation_code: PING_OPERATION_CODE,
+            return_code: 0,
+            debug_message: None,
+            parameters: indexmap! {
+                PING_CLIENT_TIME_PARAMETER => client_time,
+                PING_SERVER_TIME_PARAMETER => PhotonDataType::Integer(MOCK_SERVER_TIME),
+            },
+        },
+    ))
+}
+
+/// A game client connected to a [MockPhotonServer] through the proxy.
+pub(crate) struct ProxiedConnection {
+    client: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    pub server: MockPhotonServer,
+}
+
+impl ProxiedConnection {
+    /// Proxies a new connection as if it was made to the given server, and waits until it is stored in the state.
+    ///
+    /// This skips the HTTP upgrade and the server detection, which only depend on the request.
+    pub async fn connect(state: Arc<Mutex<HaxState>>, server_type: WebSocketServer) -> Self {
+        Self::connect_with_upstream(state, server_type, |sink| sink).await
+    }
+
+    /// Like [Self::connect], with the proxy writing to the server through the sink that `wrap_upstream` returns, for
+    /// example to make the server slow to accept messages.
+    pub async fn connect_with_upstream(
+        state: Arc<Mutex<HaxState>>,
+        server_type: WebSocketServer,
+        wrap_upstream: impl FnOnce(SocketSink) -> SocketSink,
+    ) -> Self {
+        let server = MockPhotonServer::start().await;
+        let (upstream, _) = tokio_tungstenite::connect_async(server.url())
+            .await
+            .unwrap();
+        let (upstream_send, upstream_recv) = upstream.split();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = format!("ws://{}", listener.local_addr().unwrap());
+        let accepted = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            tokio_tungstenite::accept_async(tcp).await.unwrap()
+        });
+        let (client, _) = tokio_tungstenite::connect_async(proxy_url).await.unwrap();
+
+        let (new_connection_send, new_connection_recv) = mpsc::channel(1);
+        tokio::spawn(BulletForceHax::store_new_connections_in_state_vars(
+            state.clone(),
+            new_connection_recv,
+        ));
+        proxy_connection(
+            accepted.await.unwrap(),
+            (
+                wrap_upstream(Box::new(upstream_send)),
+                Box::new(upstream_recv),
+            ),
+            UpstreamTarget {
+                uri: server.url().parse().unwrap(),
+                headers: vec![],
+            },
+            server.port,
+            Some(server_type),
+            None,
+            state.clone(),
+            new_connection_send,
+        )
+        .await;
+
+        let port = server.port;
+        tokio::time::timeout(RECV_TIMEOUT, async {
+            while !is_stored(&*state.lock().await, server_type, port) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("connection was not stored in the state");
+
+        Self { client, server }
+    }
+
+    /// Sends a message from the client to the server.
+    pub async fn client_send(&mut self, message: PhotonMessage) {
+        self.client
+            .send(Message::Binary(to_bytes(&message)))
+            .await
+            .unwrap();
+    }
+
+    /// The next message the client received.
+    pub async fn client_recv(&mut self) -> PhotonMessage {
+        recv_photon(&mut self.client, "client").await
+    }
+
+    /// Waits for the close frame the client received, skipping any messages before it. Returns `None` if the
+    /// connection ended without one.
+    pub async fn client_close_frame(&mut self) -> Option<CloseFrame<'static>> {
+        loop {
+            let message = tokio::time::timeout(RECV_TIMEOUT, self.client.next())
+                .await
+                .expect("client was not closed in time");
+            match message {
+                Some(Ok(Message::Close(frame))) => return frame,
+                Some(Ok(_)) => continue,
+                Some(Err(_)) | None => return None,
+            }
+        }
+    }
+
+    /// Closes the connection from the client's side.
+    pub async fn close(mut self) {
+        self.client.close(None).await.unwrap();
+    }
+}
+
+/// Whether the connection to the server on the given port is the one stored in the state, rather than an earlier one.
+fn is_stored(hax: &HaxState, server: WebSocketServer, port: u16) -> bool {
+    let stored = match server {
+        WebSocketServer::NameServer => hax.nameserver_state.as_ref().map(|(c, _)| c),
+        WebSocketServer::LobbyServer => hax.lobby_state.as_ref().map(|(c, _)| c),
+        WebSocketServer::GameServer => hax.gameplay_state.as_ref().map(|(c, _)| c),
+    };
+    stored.is_some_and(|conn| conn.get_port() == port)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::lock::Mutex;
+    use photon_lib::{
+        indexmap::indexmap,
+        photon_data_type::PhotonDataType,
+        photon_message::{OperationRequest, PhotonMessage},
+    };
+
+    use super::{ProxiedConnection, MOCK_SERVER_TIME};
+    use crate::{
+        hax::HaxState,
+        proxy::{watchdog::PING_OPERATION_CODE, WebSocketServer},
+    };
+
+    #[tokio::test]
+    async fn answers_pings_and_records_messages() {
+        let state = Arc::new(Mutex::new(HaxState::default()));
+        let mut conn = ProxiedConnection::connect(state, WebSocketServer::NameServer).await;
+
+        conn.client_send(PhotonMessage::InternalOperationRequest(OperationRequest {
+            operation_code: PING_OPERATION_CODE,
+            parameters: indexmap! { 1 => PhotonDataType::Integer(1234) },
+        }))
+        .await;
+        match conn.client_recv().await {
+            PhotonMessage::InternalOperationResponse(response) => {
+                assert_eq!(response.parameters[&1], PhotonDataType::Integer(1234));
+                assert_eq!(
+                    response.parameters[&2],
+                    PhotonDataType::Integer(MOCK_SERVER_TIME)
+                );
+            }
+            other => panic!("expected a ping response, got {other:?}"),
+        }
+
+        let request = || {
+            PhotonMessage::OperationRequest(OperationRequest {
+                operation_code: 42,
+                parameters: indexmap! {},
+            })
+        };
+        conn.client_send(request()).await;
+        assert_eq!(conn.server.recv().await, request());
+    }
+}
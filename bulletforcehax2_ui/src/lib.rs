@@ -1,20 +1,69 @@
-use std::sync::Arc;
+use std::{path::Path, sync::Arc, time::Instant};
 
-use bulletforcehax2_lib::hax::HaxState;
-use egui::{ProgressBar, RichText, TextEdit};
+use bulletforcehax2_lib::{
+    hax::{
+        availability::Availability,
+        bandwidth::{feature, BandwidthReport},
+        drift::UpdateDriftReport,
+        drop_log::DroppedMessage,
+        dry_run::DryRunEntry,
+        lobby_sort::LobbySort,
+        platform_spoof::PlatformSpoof,
+        property_firewall::{format_keys, parse_keys},
+        room_notes::{RoomFlag, RoomKey, RoomNote},
+        settings::{Settings, SharedSettings},
+        ui_snapshot::UiSnapshots,
+        HaxState, WatchdogMode,
+    },
+    inspect::{capture::Capture, message_code, message_options, message_type_name, Query},
+};
+use egui::{CollapsingHeader, Color32, ComboBox, ProgressBar, RichText, TextEdit};
 use egui_extras::{Size, TableBuilder};
 use futures_util::lock::Mutex;
+use photon_lib::{
+    annotate::{Annotation, UNPARSED_LABEL},
+    protocol_version::ProtocolVersion,
+};
 
+/// Where the message inspector saves captures, relative to the working directory.
+const CAPTURE_FILE: &str = "capture.bfhc";
+/// The capture sink that records the messages matching the inspector's query.
+const FILTERED_CAPTURE_SINK: &str = "inspector";
+const FILTERED_CAPTURE_FILE: &str = "capture-filtered.bfhc";
+
+/// The overlay. It draws from the published [UiSnapshots] and the [SharedSettings], and only locks the state when a
+/// control changes it, or briefly to copy what an open section lists more of, see [read_state].
 pub struct BulletForceHaxMenu {
     hax: Arc<Mutex<HaxState>>,
+    snapshots: UiSnapshots,
+    settings: SharedSettings,
     first_frame: bool,
+    message_query: String,
+    room_note_name: String,
+    room_note_text: String,
+    /// The actor and room property blocklists being edited, read from the state when the firewall section is shown.
+    property_blocklists: Option<(String, String)>,
+    profile_name: String,
+    room_rename: String,
+    /// The outcome of the last rename, shown until the next one.
+    room_rename_status: String,
 }
 
 impl BulletForceHaxMenu {
-    pub fn new(hax: Arc<Mutex<HaxState>>) -> Self {
+    pub fn new(hax: Arc<Mutex<HaxState>>, snapshots: UiSnapshots) -> Self {
+        let settings = futures::executor::block_on(hax.lock()).shared_settings();
         Self {
             hax,
+            snapshots,
+            settings,
             first_frame: true,
+            message_query: String::new(),
+            room_note_name: String::new(),
+            room_note_text: String::new(),
+            property_blocklists: None,
+            profile_name: String::new(),
+            room_rename: String::new(),
+            room_rename_status: String::new(),
         }
     }
 
@@ -28,32 +77,425 @@ impl BulletForceHaxMenu {
         ctx.request_repaint_after(std::time::Duration::from_secs_f32(1f32 / 10f32));
 
         egui::CentralPanel::default().show(ctx, |ui| {
-            let mut hax = futures::executor::block_on(self.hax.lock());
+            let snapshot = self.snapshots.load();
+            let availability = &snapshot.availability;
+            let status = &snapshot.status;
+            // edited as a copy and stored at the end of the frame, so the hook isn't handed half an edit
+            let shown_settings = self.settings.load();
+            let mut settings = Settings::clone(&shown_settings);
 
             ui.heading("General info");
-            if let Some(user_id) = &hax.global_state.user_id {
+            ui.horizontal(|ui| match settings.paused.is_some() {
+                true => {
+                    ui.colored_label(egui::Color32::YELLOW, "All features are paused");
+                    if ui.button("Resume").clicked() {
+                        change_state(&self.hax, |hax| hax.set_global_pause(false));
+                    }
+                }
+                false => {
+                    if ui.button("Pause all features").clicked() {
+                        change_state(&self.hax, |hax| hax.set_global_pause(true));
+                    }
+                }
+            });
+            ui.checkbox(
+                &mut settings.observe_only,
+                "Observe only: forward all traffic unchanged and inject nothing",
+            );
+            if settings.observe_only {
+                ui.colored_label(
+                    egui::Color32::YELLOW,
+                    "Observing only, the features below log what they would do instead",
+                );
+            }
+            if let Some(user_id) = &status.user_id {
                 ui.label(format!("User ID: {user_id}"));
             }
-            if let Some(version) = &hax.global_state.version {
+            if let Some(version) = &status.version {
                 ui.label(format!("Game version: {}", version.game_version));
                 ui.label(format!("Photon version: {}", version.photon_version));
             }
+            ComboBox::from_label("Serialization protocol of new connections")
+                .selected_text(match settings.forced_protocol {
+                    Some(version) => version.to_string(),
+                    None => "as negotiated".into(),
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut settings.forced_protocol, None, "as negotiated");
+                    for version in ProtocolVersion::ALL {
+                        ui.selectable_value(
+                            &mut settings.forced_protocol,
+                            Some(version),
+                            version.to_string(),
+                        );
+                    }
+                });
+            ui.horizontal(|ui| {
+                if status.selftest_running {
+                    ui.label("Self-test running...");
+                } else if ui.button("Run self-test").clicked() {
+                    change_state(&self.hax, |hax| hax.run_selftest());
+                }
+            });
+            if let Some(report) = &status.selftest_report {
+                if report.passed() {
+                    ui.label("Self-test passed");
+                }
+                for check in report.failed_checks() {
+                    ui.colored_label(
+                        egui::Color32::RED,
+                        format!(
+                            "The game may have updated, {} may be broken: {} check failed ({})",
+                            check.affects, check.name, check.summary
+                        ),
+                    );
+                }
+            }
+            if !status.encrypted.is_empty() {
+                let servers = status
+                    .encrypted
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                ui.colored_label(
+                    egui::Color32::YELLOW,
+                    format!(
+                        "Encrypted traffic on {servers}: these messages can't be read, rewritten or injected into"
+                    ),
+                );
+            }
+            for server in &status.passthrough {
+                ui.colored_label(
+                    egui::Color32::RED,
+                    format!(
+                        "Messages on {server} don't parse anymore, the game may have updated. They're forwarded without being handled"
+                    ),
+                );
+            }
             ui.add_space(16f32);
 
             ui.heading("Lobby");
-            ui.checkbox(&mut hax.show_mobile_games, "Show mobile games");
-            ui.checkbox(
-                &mut hax.show_other_versions,
+            feature_checkbox(
+                ui,
+                &mut settings.show_mobile_games,
+                "Show mobile games",
+                availability.get(feature::MOBILE_GAMES),
+            );
+            feature_checkbox(
+                ui,
+                &mut settings.show_other_versions,
                 "Show games for other versions",
+                availability.get(feature::VERSION_FORCING),
+            );
+            feature_checkbox(
+                ui,
+                &mut settings.strip_passwords,
+                "Strip passwords",
+                availability.get(feature::PASSWORD_STRIPPING),
+            );
+            feature_checkbox(
+                ui,
+                &mut settings.lobby_phase_annotations,
+                "Show how far along rounds are",
+                availability.get(feature::MATCH_PHASE),
+            );
+            feature_checkbox(
+                ui,
+                &mut settings.queue_jump,
+                "Wait for a free slot when joining full rooms",
+                availability.get(feature::QUEUE_JUMP),
+            );
+            ui.checkbox(
+                &mut settings.lobby_variants.newfps,
+                "Also rewrite newfps rooms",
             );
-            ui.checkbox(&mut hax.strip_passwords, "Strip passwords");
+            ui.horizontal(|ui| {
+                let enabled = &mut settings.forced_region.0;
+                feature_checkbox(
+                    ui,
+                    enabled,
+                    "Force region",
+                    availability.get(feature::REGION_FORCING),
+                );
+                ui.add_enabled(*enabled, TextEdit::singleline(&mut settings.forced_region.1));
+            });
+            ComboBox::from_label("Room order")
+                .selected_text(match settings.lobby_sort {
+                    Some(sort) => sort.to_string(),
+                    None => "server order".into(),
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut settings.lobby_sort, None, "server order");
+                    for sort in LobbySort::ALL {
+                        ui.selectable_value(&mut settings.lobby_sort, Some(sort), sort.to_string());
+                    }
+                });
+            if !status.regions.is_empty() {
+                ui.label(format!("Known regions: {}", status.regions.join(", ")));
+            }
+            ui.collapsing("Room notes", |ui| {
+                let notes = read_state(&self.hax, |hax| {
+                    hax.room_notes()
+                        .map(|(key, note)| (key, note.clone()))
+                        .collect::<Vec<_>>()
+                });
+                for (key, note) in notes {
+                    ui.horizontal(|ui| {
+                        let name = match &key {
+                            RoomKey::RoomName(name) => format!("room {name}"),
+                            RoomKey::HostUserId(user_id) => format!("host {user_id}"),
+                        };
+                        let flag = match note.flag {
+                            Some(RoomFlag::Favorite) => "[*] ",
+                            Some(RoomFlag::Blocked) => "[blocked] ",
+                            None => "",
+                        };
+                        ui.label(format!("{flag}{name}: {}", note.note));
+                        if ui.small_button("x").clicked() {
+                            if let Err(e) = change_state(&self.hax, |hax| hax.remove_room_note(&key)) {
+                                tracing::warn!("Failed to remove room note: {e}");
+                            }
+                        }
+                    });
+                }
+
+                ui.add(TextEdit::singleline(&mut self.room_note_name).hint_text("room name"));
+                ui.add(TextEdit::singleline(&mut self.room_note_text).hint_text("note"));
+                ui.horizontal(|ui| {
+                    let flag = if ui.button("Favorite").clicked() {
+                        Some(Some(RoomFlag::Favorite))
+                    } else if ui.button("Block").clicked() {
+                        Some(Some(RoomFlag::Blocked))
+                    } else if ui.button("Note only").clicked() {
+                        Some(None)
+                    } else {
+                        None
+                    };
+
+                    if let Some(flag) = flag {
+                        if !self.room_note_name.is_empty() {
+                            let note = RoomNote {
+                                flag,
+                                note: std::mem::take(&mut self.room_note_text),
+                            };
+                            let key = RoomKey::RoomName(std::mem::take(&mut self.room_note_name));
+                            if let Err(e) = change_state(&self.hax, |hax| hax.set_room_note(key, note)) {
+                                tracing::warn!("Failed to save room note: {e}");
+                            }
+                        }
+                    }
+                });
+            });
             ui.add_space(16f32);
 
             ui.heading("Gameplay");
             ui.horizontal(|ui| {
-                let enabled = &mut hax.spoofed_name.0;
-                ui.checkbox(enabled, "Spoof name");
-                ui.add_enabled(*enabled, TextEdit::singleline(&mut hax.spoofed_name.1));
+                let enabled = &mut settings.spoofed_name.0;
+                feature_checkbox(
+                    ui,
+                    enabled,
+                    "Spoof name",
+                    availability.get(feature::NAME_SPOOFING),
+                );
+                ui.add_enabled(*enabled, TextEdit::singleline(&mut settings.spoofed_name.1));
+            });
+            ComboBox::from_label("Report platform")
+                .selected_text(match &settings.platform_spoof {
+                    Some(spoof) => spoof.platform.clone().unwrap_or_default(),
+                    None => "as is".into(),
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut settings.platform_spoof, None, "as is");
+                    ui.selectable_value(
+                        &mut settings.platform_spoof,
+                        Some(PlatformSpoof::web()),
+                        "web",
+                    );
+                    ui.selectable_value(
+                        &mut settings.platform_spoof,
+                        Some(PlatformSpoof::android()),
+                        "Android",
+                    );
+                });
+            feature_checkbox(
+                ui,
+                &mut settings.identity_randomizer,
+                "Randomize identity (new user id and name every session)",
+                availability.get(feature::IDENTITY_RANDOMIZER),
+            );
+            feature_checkbox(
+                ui,
+                &mut settings.mute_all_cosmetic,
+                "Mute cosmetic RPCs",
+                availability.get(feature::RPC_MUTING),
+            );
+            feature_checkbox(
+                ui,
+                &mut settings.ghost_join,
+                "Ghost join (don't spawn for other players)",
+                availability.get(feature::GHOST_JOIN),
+            );
+            let mut stealth_host = snapshot.stealth_host;
+            feature_checkbox(
+                ui,
+                &mut stealth_host,
+                "Stealth host (hide my rooms from the lobby)",
+                availability.get(feature::STEALTH_HOST),
+            );
+            if stealth_host != snapshot.stealth_host {
+                change_state(&self.hax, |hax| hax.set_stealth_host(stealth_host));
+            }
+            let hosting = snapshot
+                .room
+                .as_ref()
+                .and_then(|room| room.hosting.then(|| room.title.clone()));
+            if let Some(title) = hosting {
+                ui.horizontal(|ui| {
+                    ui.label("Room name:");
+                    let edit = TextEdit::singleline(&mut self.room_rename)
+                        .hint_text(title.unwrap_or_default());
+                    ui.add(edit);
+                    if ui.button("Rename").clicked() {
+                        let renamed = change_state(&self.hax, |hax| {
+                            hax.rename_my_room(&self.room_rename)
+                        });
+                        self.room_rename_status = match renamed {
+                            Ok(()) => "Renamed, the game keeps showing the old name".into(),
+                            Err(e) => e.to_string(),
+                        };
+                    }
+                    ui.label(&self.room_rename_status);
+                });
+            }
+            let mut receive_all_groups = snapshot.receive_all_groups;
+            feature_checkbox(
+                ui,
+                &mut receive_all_groups,
+                "Receive all interest groups",
+                availability.get(feature::ALL_INTEREST_GROUPS),
+            );
+            if receive_all_groups != snapshot.receive_all_groups {
+                change_state(&self.hax, |hax| hax.set_receive_all_groups(receive_all_groups));
+            }
+            if let Availability::Unavailable(reason) = availability.get(feature::ESP) {
+                ui.label(format!("Player positions unavailable: {reason}"));
+            }
+            ui.collapsing("Property firewall", |ui| {
+                let stripped = read_state(&self.hax, |hax| {
+                    hax.property_firewall
+                        .stripped()
+                        .rev()
+                        .take(10)
+                        .cloned()
+                        .collect::<Vec<_>>()
+                });
+                let (actor_blocklist, game_blocklist) =
+                    self.property_blocklists.get_or_insert_with(|| {
+                        read_state(&self.hax, |hax| {
+                            (
+                                format_keys(&hax.property_firewall.actor_blocklist),
+                                format_keys(&hax.property_firewall.game_blocklist),
+                            )
+                        })
+                    });
+                ui.horizontal(|ui| {
+                    ui.label("Blocked actor properties:");
+                    let edit = TextEdit::singleline(actor_blocklist).hint_text("platform, 255");
+                    if ui.add(edit).changed() {
+                        let keys = parse_keys(actor_blocklist);
+                        change_state(&self.hax, |hax| hax.property_firewall.actor_blocklist = keys);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Blocked room properties:");
+                    let edit = TextEdit::singleline(game_blocklist);
+                    if ui.add(edit).changed() {
+                        let keys = parse_keys(game_blocklist);
+                        change_state(&self.hax, |hax| hax.property_firewall.game_blocklist = keys);
+                    }
+                });
+                for stripped in stripped {
+                    ui.label(format!(
+                        "{}: {:?} = {:?}",
+                        stripped.target, stripped.key, stripped.value
+                    ));
+                }
+            });
+            for player in &snapshot.players {
+                let actor_id = player.actor_id;
+                let mut muted = settings.muted_actors.contains(&actor_id);
+                if ui
+                    .checkbox(
+                        &mut muted,
+                        format!(
+                            "Mute {} ({actor_id}), {} muted",
+                            player.nickname.as_deref().unwrap_or_default(),
+                            player.muted_rpcs
+                        ),
+                    )
+                    .changed()
+                {
+                    match muted {
+                        true => settings.muted_actors.insert(actor_id),
+                        false => settings.muted_actors.remove(&actor_id),
+                    };
+                }
+            }
+            ui.add_space(16f32);
+
+            ui.heading("Connection");
+            let mut watchdog_mode = snapshot.watchdog_mode;
+            ui.horizontal(|ui| {
+                ui.label("When server stops responding:");
+                ui.radio_value(&mut watchdog_mode, WatchdogMode::Disabled, "Wait");
+                ui.radio_value(&mut watchdog_mode, WatchdogMode::Reconnect, "Reconnect");
+                ui.radio_value(&mut watchdog_mode, WatchdogMode::CloseClient, "Disconnect");
+            });
+            if watchdog_mode != snapshot.watchdog_mode {
+                change_state(&self.hax, |hax| hax.watchdog.mode = watchdog_mode);
+            }
+            ui.add_space(16f32);
+
+            ui.collapsing("Profiles", |ui| {
+                // listing them reads the profile directory, which is done without the state
+                let profiles = read_state(&self.hax, |hax| hax.profiles().clone());
+                let mut changed = false;
+                ui.horizontal(|ui| {
+                    for name in profiles.names() {
+                        if ui.button(&name).clicked() {
+                            match change_state(&self.hax, |hax| hax.load_profile(&name)) {
+                                Ok(warnings) => {
+                                    for warning in warnings {
+                                        tracing::warn!("Profile {name}: {warning}");
+                                    }
+                                    changed = true;
+                                }
+                                Err(e) => tracing::warn!("Failed to load profile {name}: {e}"),
+                            }
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.add(TextEdit::singleline(&mut self.profile_name).hint_text("profile name"));
+                    if ui.button("Save").clicked() {
+                        if let Err(e) = read_state(&self.hax, |hax| hax.save_profile(&self.profile_name)) {
+                            tracing::warn!("Failed to save profile: {e}");
+                        }
+                    }
+                    if ui.button("Delete").clicked() {
+                        if let Err(e) = profiles.delete(&self.profile_name) {
+                            tracing::warn!("Failed to delete profile: {e}");
+                        }
+                    }
+                    if ui.button("Reset to defaults").clicked() {
+                        change_state(&self.hax, |hax| hax.reset_to_defaults());
+                        changed = true;
+                    }
+                });
+                if changed {
+                    self.property_blocklists = None;
+                }
             });
             ui.add_space(16f32);
 
@@ -79,14 +521,19 @@ impl BulletForceHaxMenu {
             });
             ui.add_space(16f32);
 
-            if let Some((_, state)) = &hax.gameplay_state {
+            if snapshot.room.is_some() {
                 ui.heading("Info - Players");
+                ui.label(format!("Round: {}", snapshot.round));
                 TableBuilder::new(ui)
                     .striped(true)
                     .column(Size::initial(45.0))
                     .column(Size::initial(60.0))
                     .column(Size::initial(100.0))
                     .column(Size::initial(150.0))
+                    .column(Size::initial(80.0))
+                    .column(Size::initial(70.0))
+                    .column(Size::initial(160.0))
+                    .column(Size::initial(60.0))
                     .column(Size::remainder())
                     .resizable(true)
                     .header(20.0, |mut header| {
@@ -102,15 +549,27 @@ impl BulletForceHaxMenu {
                         header.col(|ui| {
                             ui.label(RichText::new("Position").strong());
                         });
+                        header.col(|ui| {
+                            ui.label(RichText::new("Link").strong());
+                        });
+                        header.col(|ui| {
+                            ui.label(RichText::new("Suspicion").strong());
+                        });
+                        header.col(|ui| {
+                            ui.label(RichText::new("Loadout").strong());
+                        });
+                        header.col(|ui| {
+                            ui.label(RichText::new("Platform").strong());
+                        });
                         header.col(|ui| {
                             ui.label(RichText::new("Name").strong());
                         });
                     })
                     .body(|mut body| {
-                        for (actor_id, player) in &state.players {
+                        for player in &snapshot.players {
                             body.row(18.0, |mut row| {
                                 row.col(|ui| {
-                                    ui.label(actor_id.to_string());
+                                    ui.label(player.actor_id.to_string());
                                 });
                                 row.col(|ui| {
                                     if let Some(x) = &player.team_number {
@@ -124,8 +583,44 @@ impl BulletForceHaxMenu {
                                 });
                                 row.col(|ui| {
                                     if let Some(x) = &player.position {
-                                        ui.label(format!("{:.2}, {:.2}, {:.2}", x.0, x.1, x.2));
+                                        let label = ui.label(format!("{:.2}, {:.2}, {:.2}", x.0, x.1, x.2));
+                                        if let Some(callout) = &player.callout {
+                                            label.on_hover_text(callout);
+                                        }
+                                    };
+                                });
+                                row.col(|ui| {
+                                    if let Some(stats) = &player.link {
+                                        ui.label(stats.quality.to_string())
+                                            .on_hover_text(format!(
+                                                "jitter {:?}, {} gaps",
+                                                stats.jitter, stats.gaps
+                                            ));
+                                    }
+                                });
+                                row.col(|ui| {
+                                    if let Some(score) = &player.suspicion {
+                                        let by_heuristic = score
+                                            .by_heuristic
+                                            .iter()
+                                            .map(|(h, s)| format!("{h}: {s:.1}"))
+                                            .collect::<Vec<_>>();
+                                        ui.label(format!("{:.1}", score.score))
+                                            .on_hover_text(by_heuristic.join("\n"));
+                                    }
+                                });
+                                row.col(|ui| {
+                                    if !player.loadout.is_empty() {
+                                        ui.label(player.loadout.to_string());
+                                    }
+                                });
+                                row.col(|ui| {
+                                    let kind = match player.platform.is_mobile() {
+                                        Some(true) => "mobile",
+                                        Some(false) => "web",
+                                        None => "",
                                     };
+                                    ui.label(kind).on_hover_text(player.platform.to_string());
                                 });
                                 row.col(|ui| {
                                     ui.label(match &player.nickname {
@@ -137,21 +632,337 @@ impl BulletForceHaxMenu {
                         }
                     });
 
-                ui.add_space(16f32);
-            }
+                let now = Instant::now();
+                for projectile in &snapshot.projectiles {
+                    let position = match &projectile.position {
+                        Some(x) => format!("{:.2}, {:.2}, {:.2}", x.0, x.1, x.2),
+                        None => "?".into(),
+                    };
+                    ui.label(format!(
+                        "{} from actor {} at {position} ({:.1}s)",
+                        projectile.prefab,
+                        projectile.owner_actor,
+                        projectile.age(now).as_secs_f32()
+                    ));
+                }
+
+                for line in snapshot.kill_feed.iter().rev() {
+                    ui.label(line);
+                }
 
-            #[cfg(debug_assertions)]
-            {
-                ui.heading("Debug");
-                ui.label(format!("lobby socket: {}", hax.lobby_state.is_some()));
-                ui.label(format!("gameplay socket: {}", hax.gameplay_state.is_some()));
                 ui.add_space(16f32);
             }
 
-            drop(hax);
+            ui.collapsing("Message inspector", |ui| {
+                let (recording, capture_sinks) = read_state(&self.hax, |hax| {
+                    (
+                        hax.capture_sinks.get(FILTERED_CAPTURE_SINK).is_some(),
+                        hax.capture_sinks.len(),
+                    )
+                });
+                ui.add(
+                    TextEdit::singleline(&mut self.message_query)
+                        .hint_text(r#"direction:server code:253 param[245].contains("Rifle")"#),
+                );
+                if ui.button("Save capture").clicked() {
+                    let messages = read_state(&self.hax, |hax| hax.recent_messages.clone());
+                    let capture = Capture::from(&messages);
+                    match capture.save(Path::new(CAPTURE_FILE)) {
+                        Ok(()) => tracing::info!(
+                            "Saved {} messages to {CAPTURE_FILE}",
+                            capture.messages.len()
+                        ),
+                        Err(e) => tracing::warn!("Failed to save capture: {e}"),
+                    }
+                }
+                ui.horizontal(|ui| {
+                    match recording {
+                        true => {
+                            if ui.button("Stop recording matches").clicked() {
+                                change_state(&self.hax, |hax| {
+                                    hax.remove_capture_sink(FILTERED_CAPTURE_SINK)
+                                });
+                            }
+                        }
+                        false => {
+                            if ui.button("Record matches").clicked() {
+                                let filter = match self.message_query.trim() {
+                                    "" => Ok(None),
+                                    query => Query::parse(query).map(Some),
+                                };
+                                match filter {
+                                    Ok(filter) => {
+                                        if let Err(e) = change_state(&self.hax, |hax| {
+                                            hax.add_capture_sink(
+                                                FILTERED_CAPTURE_SINK,
+                                                filter,
+                                                FILTERED_CAPTURE_FILE,
+                                            )
+                                        }) {
+                                            tracing::warn!("Failed to start recording: {e}");
+                                        }
+                                    }
+                                    Err(e) => tracing::warn!("Invalid query: {e}"),
+                                }
+                            }
+                        }
+                    }
+                    ui.label(format!("{capture_sinks} capture sinks"));
+                });
+                if self.message_query.trim().is_empty() {
+                    return;
+                }
+
+                match Query::parse(&self.message_query) {
+                    Ok(query) => {
+                        // searching restores the messages stored as diffs, so it's done on a copy
+                        let messages = read_state(&self.hax, |hax| hax.recent_messages.clone());
+                        let matches = messages.search(&query).collect::<Vec<_>>();
+                        ui.label(format!(
+                            "{} of {} messages match",
+                            matches.len(),
+                            messages.len()
+                        ));
+                        for (i, message) in matches.iter().rev().take(20).enumerate() {
+                            let (name, code, options) = match message.parse() {
+                                Some(parsed) => (
+                                    message_type_name(&parsed),
+                                    message_code(&parsed),
+                                    message_options(&parsed),
+                                ),
+                                None => ("?", None, None),
+                            };
+                            let title = format!(
+                                "{} {} {name} {} {}",
+                                message.server,
+                                message.direction,
+                                code.map(|c| c.to_string()).unwrap_or_default(),
+                                options.unwrap_or_default(),
+                            );
+                            // only annotated while expanded
+                            CollapsingHeader::new(title).id_source(("message", i)).show(
+                                ui,
+                                |ui| {
+                                    let annotation = message.annotate();
+                                    annotation_ui(ui, &message.raw, &annotation, 0);
+                                },
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        ui.colored_label(egui::Color32::RED, e.to_string());
+                    }
+                }
+            });
+            ui.add_space(16f32);
+
+            ui.collapsing("Debug", |ui| {
+                let debug = read_state(&self.hax, DebugReadout::copy);
+                ui.checkbox(
+                    &mut settings.debug.validate_rewrites,
+                    "Validate modified messages",
+                );
+                ui.label(format!(
+                    "rejected modifications: {}",
+                    debug.rejected_rewrites
+                ));
+                ui.label(format!("handler panics: {}", debug.handler_panics));
+                for line in &debug.relay {
+                    ui.label(line);
+                }
+                for (feature, usage) in &debug.bandwidth.totals {
+                    ui.label(format!(
+                        "{feature}: {} bytes extra ({} rewritten, {} injected)",
+                        usage.extra_bytes(),
+                        usage.rewritten_messages,
+                        usage.injected_messages
+                    ));
+                }
+                ui.horizontal(|ui| {
+                    ui.label(match debug.drift.has_unknowns() {
+                        true => "Traffic does not match the protocol profile",
+                        false => "Traffic matches the protocol profile",
+                    });
+                    if ui.button("Copy drift report").clicked() {
+                        ui.output().copied_text = debug.drift.to_json();
+                    }
+                });
+                ui.label(format!("dropped messages: {}", debug.dropped_total));
+                for (feature, count) in &debug.drop_counts {
+                    ui.label(format!("{feature}: {count} dropped"));
+                }
+                for dropped in &debug.dropped {
+                    ui.label(format!(
+                        "{} {} {} {}",
+                        dropped.server,
+                        dropped.direction,
+                        dropped.code.map(|c| c.to_string()).unwrap_or_default(),
+                        dropped.reason
+                    ));
+                }
+                ui.horizontal(|ui| {
+                    ui.checkbox(
+                        &mut settings.dry_run.enabled,
+                        "Dry run: only log what features would change",
+                    );
+                    if ui.button("Clear").clicked() {
+                        change_state(&self.hax, |hax| hax.dry_run_log.clear());
+                    }
+                });
+                for entry in &debug.dry_run {
+                    ui.label(format!(
+                        "{} {} {}",
+                        entry.server, entry.direction, entry.outcome
+                    ));
+                }
+                #[cfg(debug_assertions)]
+                {
+                    let [nameserver, lobby, gameplay] = debug.sockets;
+                    ui.label(format!("name server socket: {nameserver}"));
+                    ui.label(format!("lobby socket: {lobby}"));
+                    ui.label(format!("gameplay socket: {gameplay}"));
+                }
+            });
+            ui.add_space(16f32);
+
+            // a profile applied during this frame changed the settings too, only overwrite them if we changed something
+            if settings != *shown_settings {
+                self.settings.store(settings);
+            }
 
             // TODO: add back FPS counter
             ui.label(format!("Time: {}", ctx.input().time));
         });
     }
 }
+
+/// Changes the state for a control and publishes a new snapshot, so the next frame already shows the change.
+fn change_state<T>(hax: &Mutex<HaxState>, f: impl FnOnce(&mut HaxState) -> T) -> T {
+    let mut hax = futures::executor::block_on(hax.lock());
+    let result = f(&mut hax);
+    hax.publish_ui_snapshot(Instant::now());
+    result
+}
+
+/// Copies what a section shows out of the state, so the state is only locked for as long as that takes and not while
+/// the section is drawn.
+fn read_state<T>(hax: &Mutex<HaxState>, f: impl FnOnce(&HaxState) -> T) -> T {
+    f(&futures::executor::block_on(hax.lock()))
+}
+
+/// What the debug section shows, see [read_state].
+struct DebugReadout {
+    rejected_rewrites: u64,
+    handler_panics: u64,
+    /// How the proxy relays messages in each direction.
+    relay: Vec<String>,
+    bandwidth: BandwidthReport,
+    drift: UpdateDriftReport,
+    dropped_total: u64,
+    drop_counts: Vec<(&'static str, u64)>,
+    /// The newest dropped messages and dry run entries, newest first.
+    dropped: Vec<DroppedMessage>,
+    dry_run: Vec<DryRunEntry>,
+    /// Whether the name server, lobby and gameplay connections are open.
+    sockets: [bool; 3],
+}
+
+impl DebugReadout {
+    fn copy(hax: &HaxState) -> Self {
+        Self {
+            rejected_rewrites: hax.stats.rejected_rewrites,
+            handler_panics: hax.stats.handler_panics,
+            relay: {
+                let relay = hax.relay_report();
+                [
+                    ("c->s", relay.client_to_server),
+                    ("s->c", relay.server_to_client),
+                ]
+                .into_iter()
+                .map(|(direction, report)| {
+                    format!(
+                        "proxy transit {direction}: p99 <= {:?}, {} blocked writes, {} waiting",
+                        report.transit.percentile(0.99).unwrap_or_default(),
+                        report.backpressure,
+                        report.hook.messages + report.write.messages
+                    )
+                })
+                .collect()
+            },
+            bandwidth: hax.bandwidth_report(),
+            drift: hax.drift_report(),
+            dropped_total: hax.drop_log.total(),
+            drop_counts: hax
+                .drop_log
+                .counts()
+                .iter()
+                .map(|(feature, count)| (*feature, *count))
+                .collect(),
+            dropped: hax.drop_log.entries().rev().take(10).cloned().collect(),
+            dry_run: hax.dry_run_log.entries().rev().take(10).cloned().collect(),
+            sockets: [
+                hax.nameserver_state.is_some(),
+                hax.lobby_state.is_some(),
+                hax.gameplay_state.is_some(),
+            ],
+        }
+    }
+}
+
+/// A checkbox for a feature that is greyed out while the feature can't work, with the reason as tooltip. It stays
+/// enabled while checked, so it can always be turned off.
+/// The colors of the bytes of each nesting level in the message inspector.
+const ANNOTATION_COLORS: [Color32; 4] = [
+    Color32::LIGHT_BLUE,
+    Color32::LIGHT_GREEN,
+    Color32::GOLD,
+    Color32::LIGHT_RED,
+];
+
+/// Shows the fields of an annotated message as a tree, with the bytes of each field colored by its depth.
+fn annotation_ui(ui: &mut egui::Ui, raw: &[u8], node: &Annotation, depth: usize) {
+    for child in &node.children {
+        let text = match &child.value_preview {
+            Some(preview) => format!("{}: {preview}", child.label),
+            None => child.label.clone(),
+        };
+        if child.children.is_empty() {
+            let hex = raw[child.range.clone()]
+                .iter()
+                .take(32)
+                .map(|b| format!("{b:02x}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let color = match child.label == UNPARSED_LABEL {
+                true => Color32::RED,
+                false => ANNOTATION_COLORS[depth % ANNOTATION_COLORS.len()],
+            };
+            ui.horizontal(|ui| {
+                ui.label(
+                    RichText::new(format!("{:04x}", child.range.start))
+                        .monospace()
+                        .weak(),
+                );
+                ui.label(RichText::new(hex).monospace().color(color));
+                ui.label(text);
+            });
+        } else {
+            CollapsingHeader::new(text)
+                .id_source((child.range.start, depth, &child.label))
+                .default_open(depth < 2)
+                .show(ui, |ui| annotation_ui(ui, raw, child, depth + 1));
+        }
+    }
+}
+
+fn feature_checkbox(ui: &mut egui::Ui, value: &mut bool, label: &str, availability: &Availability) {
+    let response = ui.add_enabled(
+        *value || availability.is_available(),
+        egui::Checkbox::new(value, label),
+    );
+    if let Availability::Unavailable(reason) = availability {
+        response
+            .on_hover_text(reason)
+            .on_disabled_hover_text(reason);
+    }
+}
@@ -0,0 +1,650 @@
+//! A byte-level breakdown of messages, for figuring out unknown or broken traffic.
+//!
+//! [PhotonMessage::annotate] walks a message the same way [PhotonMessage::from_websocket_bytes] does, but records the
+//! bytes every field was read from as a tree of [Annotation]s. It is a separate parser so the regular one doesn't pay
+//! for the bookkeeping, and is only compiled with the `annotate` feature.
+
+use std::ops::Range;
+
+use bytes::Buf;
+
+use crate::{photon_data_type::CustomData, photon_message::PhotonMessage, ReadError};
+
+/// The label of the node covering the bytes after a parse error.
+pub const UNPARSED_LABEL: &str = "unparsed";
+
+/// How many characters of a string are shown in a preview.
+const MAX_STRING_PREVIEW: usize = 48;
+
+/// A part of a message and the bytes it was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Annotation {
+    /// The offsets of the bytes in the message.
+    pub range: Range<usize>,
+    pub label: String,
+    /// A short rendering of the value, if the node holds one.
+    pub value_preview: Option<String>,
+    /// The fields this node consists of, in order. If there are any, they cover [Self::range] without gaps.
+    pub children: Vec<Annotation>,
+}
+
+impl Annotation {
+    fn new(start: usize, label: String) -> Self {
+        Self {
+            range: start..start,
+            label,
+            value_preview: None,
+            children: vec![],
+        }
+    }
+
+    /// Whether the whole message could be parsed. Otherwise the last child is an [UNPARSED_LABEL] node.
+    pub fn is_complete(&self) -> bool {
+        !matches!(self.children.last(), Some(last) if last.label == UNPARSED_LABEL)
+    }
+
+    /// The nodes without children, in order. Together they cover all bytes of the message.
+    pub fn leaves(&self) -> Vec<&Annotation> {
+        match self.children.is_empty() {
+            true => vec![self],
+            false => self.children.iter().flat_map(|c| c.leaves()).collect(),
+        }
+    }
+
+    /// The innermost node holding the byte at the given offset.
+    pub fn node_at(&self, offset: usize) -> Option<&Annotation> {
+        if !self.range.contains(&offset) {
+            return None;
+        }
+        self.children
+            .iter()
+            .find_map(|c| c.node_at(offset))
+            .or(Some(self))
+    }
+}
+
+impl PhotonMessage {
+    /// Breaks a websocket message down into its fields, see [crate::annotate].
+    ///
+    /// Messages that fail to parse are annotated up to the error, the rest of the bytes are covered by an
+    /// [UNPARSED_LABEL] node with the error as its preview.
+    pub fn annotate(bytes: &[u8]) -> Annotation {
+        let mut annotator = Annotator {
+            bytes,
+            pos: 0,
+            open: vec![Annotation::new(0, "message".into())],
+        };
+        match annotator.message() {
+            Ok(()) if annotator.pos < bytes.len() => {
+                annotator.leaf(bytes.len() - annotator.pos, "trailing bytes", None);
+            }
+            Ok(()) => (),
+            Err(e) => {
+                // close the fields that were being read when the error happened
+                while annotator.open.len() > 1 {
+                    annotator.close(Some("incomplete".into()));
+                }
+                annotator.leaf(
+                    bytes.len() - annotator.pos,
+                    UNPARSED_LABEL,
+                    Some(e.to_string()),
+                );
+            }
+        }
+
+        let mut root = annotator.open.pop().expect("root node should be open");
+        root.range.end = bytes.len();
+        root
+    }
+}
+
+/// Keeps the nodes that are still being read, innermost last.
+struct Annotator<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    open: Vec<Annotation>,
+}
+
+impl<'a> Annotator<'a> {
+    fn open(&mut self, label: impl Into<String>) {
+        self.open.push(Annotation::new(self.pos, label.into()));
+    }
+
+    fn close(&mut self, preview: Option<String>) {
+        let mut node = self.open.pop().expect("closed more nodes than were opened");
+        node.range.end = self.pos;
+        node.value_preview = preview;
+        self.open
+            .last_mut()
+            .expect("the root node is never closed")
+            .children
+            .push(node);
+    }
+
+    /// Adds a node for the next `len` bytes, or as many as are left.
+    fn leaf(&mut self, len: usize, label: &str, preview: Option<String>) {
+        let end = (self.pos + len).min(self.bytes.len());
+        let node = Annotation {
+            range: self.pos..end,
+            label: label.into(),
+            value_preview: preview,
+            children: vec![],
+        };
+        self.pos = end;
+        self.open
+            .last_mut()
+            .expect("the root node is never closed")
+            .children
+            .push(node);
+    }
+
+    /// Takes the next `len` bytes as a single field, previewed with `preview`.
+    fn take(
+        &mut self,
+        len: usize,
+        label: &str,
+        preview: impl FnOnce(&[u8]) -> String,
+    ) -> Result<&'a [u8], ReadError> {
+        if self.bytes.len() - self.pos < len {
+            return Err(ReadError::NotEnoughBytesLeft);
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.leaf(len, label, Some(preview(bytes)));
+        Ok(bytes)
+    }
+
+    fn u8(&mut self, label: &str, preview: impl FnOnce(u8) -> String) -> Result<u8, ReadError> {
+        Ok(self.take(1, label, |b| preview(b[0]))?[0])
+    }
+
+    fn i16(&mut self, label: &str) -> Result<i16, ReadError> {
+        Ok(self
+            .take(2, label, |mut b| b.get_i16().to_string())?
+            .get_i16())
+    }
+
+    fn i32(&mut self, label: &str) -> Result<i32, ReadError> {
+        Ok(self
+            .take(4, label, |mut b| b.get_i32().to_string())?
+            .get_i32())
+    }
+
+    fn message(&mut self) -> Result<(), ReadError> {
+        let magic_number = self.u8("signature", |b| format!("{b:#04X}"))?;
+        match magic_number {
+            0xF3 => (),
+            0xF0 => {
+                self.i32("server sent time")?;
+                self.i32("client sent time")?;
+                return Ok(());
+            }
+            _ => return Err(ReadError::InvalidMagicNumber(magic_number)),
+        }
+
+        let type_byte = self.u8("message type", message_type_name)?;
+        if type_byte & crate::photon_message::ENCRYPTED_FLAG != 0 {
+            let len = self.bytes.len() - self.pos;
+            self.leaf(len, "encrypted payload", Some(format!("{len} bytes")));
+            return Ok(());
+        }
+
+        match type_byte {
+            1 => {
+                self.u8("unused", |b| b.to_string())?;
+            }
+            2 | 6 => self.operation_request()?,
+            3 | 7 => self.operation_response()?,
+            4 => self.event_data()?,
+            5 => {
+                self.i16("disconnect code")?;
+                self.debug_message()?;
+                self.parameters()?;
+            }
+            8 => self.value("message")?,
+            9 => {
+                let len = self.bytes.len() - self.pos;
+                self.leaf(len, "raw payload", Some(format!("{len} bytes")));
+            }
+            _ => return Err(ReadError::UnknownMessageType(type_byte)),
+        }
+        Ok(())
+    }
+
+    fn operation_request(&mut self) -> Result<(), ReadError> {
+        self.u8("operation code", |b| b.to_string())?;
+        self.parameters()
+    }
+
+    fn operation_response(&mut self) -> Result<(), ReadError> {
+        self.u8("operation code", |b| b.to_string())?;
+        self.i16("return code")?;
+        self.debug_message()?;
+        self.parameters()
+    }
+
+    fn event_data(&mut self) -> Result<(), ReadError> {
+        self.u8("event code", |b| b.to_string())?;
+        self.parameters()
+    }
+
+    fn debug_message(&mut self) -> Result<(), ReadError> {
+        if !matches!(self.bytes.get(self.pos), Some(0 | 0x2A | 0x73) | None) {
+            return Err(ReadError::UnexpectedData(
+                "expected string or null in operation response debug message",
+            ));
+        }
+        self.value("debug message")
+    }
+
+    fn parameters(&mut self) -> Result<(), ReadError> {
+        self.open("parameters");
+        let count = self.i16("parameter count")?;
+        for _ in 0..count {
+            let key = *self
+                .bytes
+                .get(self.pos)
+                .ok_or(ReadError::NotEnoughBytesLeft)?;
+            self.open(format!("parameter {key}"));
+            self.u8("key", |b| b.to_string())?;
+            self.value("value")?;
+            self.close(None);
+        }
+        self.close(Some(format!("{} parameters", count.max(0))));
+        Ok(())
+    }
+
+    /// A value that starts with its type byte.
+    fn value(&mut self, label: impl Into<String>) -> Result<(), ReadError> {
+        self.open(label);
+        let data_type = self.u8("type", |t| data_type_name(t).to_string())?;
+        let preview = self.payload(data_type)?;
+        self.close(Some(preview));
+        Ok(())
+    }
+
+    /// A value whose type is known from its container.
+    fn typed_value(&mut self, label: impl Into<String>, data_type: u8) -> Result<(), ReadError> {
+        self.open(label);
+        let preview = self.payload(data_type)?;
+        self.close(Some(preview));
+        Ok(())
+    }
+
+    /// Reads a value without its type byte and returns its preview.
+    fn payload(&mut self, data_type: u8) -> Result<String, ReadError> {
+        let preview = match data_type {
+            0 | 0x2A => "null".into(),
+            0x44 => {
+                let key_type = self.u8("key type", |t| data_type_name(t).to_string())?;
+                let val_type = self.u8("value type", |t| data_type_name(t).to_string())?;
+                let len = self.i16("length")?;
+                for i in 0..len {
+                    self.open(format!("entry {i}"));
+                    match key_type {
+                        0 | 0x2A => self.value("key")?,
+                        _ => self.typed_value("key", key_type)?,
+                    }
+                    match val_type {
+                        0 | 0x2A => self.value("value")?,
+                        _ => self.typed_value("value", val_type)?,
+                    }
+                    self.close(None);
+                }
+                format!("Dictionary, {} entries", len.max(0))
+            }
+            0x61 => {
+                let len = self.i16("length")?;
+                for i in 0..len {
+                    self.typed_value(format!("[{i}]"), 0x73)?;
+                }
+                format!("StringArray, {} strings", len.max(0))
+            }
+            0x62 => self.u8("value", |b| b.to_string())?.to_string(),
+            0x63 => {
+                let start = self.pos;
+                let type_code = self.u8("custom type", |t| format!("{:?}", t as char))?;
+                let len = self.i16("length")?;
+                if len < 0 {
+                    return Err(ReadError::UnexpectedData("negative length for custom data"));
+                }
+                // the regular parser checks the length of known types
+                let data = CustomData::from_bytes(&mut &self.bytes[start..])?;
+                let preview = custom_data_preview(&data);
+                self.take(len as usize, "data", |_| preview.clone())?;
+                match type_code {
+                    b'W' | b'V' | b'Q' | b'P' => preview,
+                    _ => format!("Custom {:?}, {len} bytes", type_code as char),
+                }
+            }
+            0x64 => self
+                .take(8, "value", |mut b| b.get_f64().to_string())?
+                .get_f64()
+                .to_string(),
+            0x65 => {
+                self.event_data()?;
+                "EventData".into()
+            }
+            0x66 => self
+                .take(4, "value", |mut b| b.get_f32().to_string())?
+                .get_f32()
+                .to_string(),
+            0x68 => {
+                let len = self.i16("length")?;
+                for i in 0..len {
+                    self.open(format!("entry {i}"));
+                    self.value("key")?;
+                    self.value("value")?;
+                    self.close(None);
+                }
+                format!("Hashtable, {} entries", len.max(0))
+            }
+            0x69 => self.i32("value")?.to_string(),
+            0x6B => self.i16("value")?.to_string(),
+            0x6C => self
+                .take(8, "value", |mut b| b.get_i64().to_string())?
+                .get_i64()
+                .to_string(),
+            0x6E => {
+                let len = self.i32("length")?;
+                for i in 0..len {
+                    self.i32(&format!("[{i}]"))?;
+                }
+                format!("IntArray, {} ints", len.max(0))
+            }
+            0x6F => (self.u8("value", |b| (b != 0).to_string())? != 0).to_string(),
+            0x70 => {
+                self.operation_response()?;
+                "OperationResponse".into()
+            }
+            0x71 => {
+                self.operation_request()?;
+                "OperationRequest".into()
+            }
+            0x73 => {
+                let len = self.i16("length")?;
+                match len {
+                    0 => "\"\"".into(),
+                    len if len < 0 => {
+                        return Err(ReadError::UnexpectedData("string length less than 0"))
+                    }
+                    len => {
+                        let bytes = self.take(len as usize, "value", string_preview)?;
+                        string_preview(bytes)
+                    }
+                }
+            }
+            0x78 => {
+                let len = self.i32("length")?;
+                if len < 0 {
+                    return Err(ReadError::UnexpectedData("byte[] length less than 0"));
+                }
+                if len > 0 {
+                    self.take(len as usize, "value", hex_preview)?;
+                }
+                format!("ByteArray, {len} bytes")
+            }
+            0x79 => {
+                let len = self.i16("length")?;
+                let element_type = self.u8("element type", |t| data_type_name(t).to_string())?;
+                for i in 0..len {
+                    self.typed_value(format!("[{i}]"), element_type)?;
+                }
+                format!(
+                    "Array of {}, {} elements",
+                    data_type_name(element_type),
+                    len.max(0)
+                )
+            }
+            0x7A => {
+                let len = self.i16("length")?;
+                if len < 0 {
+                    return Err(ReadError::UnexpectedData("object[] length less than 0"));
+                }
+                for i in 0..len {
+                    self.value(format!("[{i}]"))?;
+                }
+                format!("ObjectArray, {len} elements")
+            }
+            _ => return Err(ReadError::UnknownDataType(data_type)),
+        };
+        Ok(preview)
+    }
+}
+
+fn message_type_name(type_byte: u8) -> String {
+    let name = match type_byte & !crate::photon_message::ENCRYPTED_FLAG {
+        0 => "Init",
+        1 => "InitResponse",
+        2 => "OperationRequest",
+        3 => "OperationResponse",
+        4 => "EventData",
+        5 => "DisconnectMessage",
+        6 => "InternalOperationRequest",
+        7 => "InternalOperationResponse",
+        8 => "Message",
+        9 => "RawMessage",
+        _ => "unknown",
+    };
+    match type_byte & crate::photon_message::ENCRYPTED_FLAG != 0 {
+        true => format!("{name} (encrypted)"),
+        false => name.into(),
+    }
+}
+
+/// The name of a data type byte, see [PhotonDataType](crate::photon_data_type::PhotonDataType).
+pub fn data_type_name(data_type: u8) -> &'static str {
+    match data_type {
+        0 | 0x2A => "Null",
+        0x44 => "Dictionary",
+        0x61 => "StringArray",
+        0x62 => "Byte",
+        0x63 => "Custom",
+        0x64 => "Double",
+        0x65 => "EventData",
+        0x66 => "Float",
+        0x68 => "Hashtable",
+        0x69 => "Integer",
+        0x6B => "Short",
+        0x6C => "Long",
+        0x6E => "IntArray",
+        0x6F => "Boolean",
+        0x70 => "OperationResponse",
+        0x71 => "OperationRequest",
+        0x73 => "String",
+        0x78 => "ByteArray",
+        0x79 => "Array",
+        0x7A => "ObjectArray",
+        _ => "unknown",
+    }
+}
+
+fn custom_data_preview(data: &CustomData) -> String {
+    match data {
+        CustomData::Vector2(v) => format!("Vector2 {:?}", v.floats()),
+        CustomData::Vector3(v) => format!("Vector3 {:?}", v.floats()),
+        CustomData::Quaternion(q) => format!("Quaternion {:?}", q.floats()),
+        CustomData::PhotonPlayer(actor) => format!("PhotonPlayer {actor}"),
+        CustomData::Unrecognized(_, bytes) => hex_preview(bytes),
+    }
+}
+
+fn string_preview(bytes: &[u8]) -> String {
+    let string = String::from_utf8_lossy(bytes);
+    match string.char_indices().nth(MAX_STRING_PREVIEW) {
+        Some((end, _)) => format!("{:?}…", &string[..end]),
+        None => format!("{string:?}"),
+    }
+}
+
+fn hex_preview(bytes: &[u8]) -> String {
+    let shown = bytes.iter().take(16).map(|b| format!("{b:02x}"));
+    let mut preview = shown.collect::<Vec<_>>().join(" ");
+    if bytes.len() > 16 {
+        preview.push_str(" …");
+    }
+    preview
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::indexmap;
+
+    use super::{Annotation, UNPARSED_LABEL};
+    use crate::{
+        photon_data_type::{CustomData, PhotonDataType},
+        photon_message::{EventData, OperationRequest, OperationResponse, PhotonMessage},
+        primitives::Vector3,
+    };
+
+    /// Checks that the children of every node cover it without gaps or overlaps.
+    fn assert_contiguous(node: &Annotation) {
+        if node.children.is_empty() {
+            return;
+        }
+        let mut pos = node.range.start;
+        for child in &node.children {
+            assert_eq!(
+                child.range.start, pos,
+                "gap or overlap before {:?} in {:?}",
+                child.label, node.label
+            );
+            pos = child.range.end;
+            assert_contiguous(child);
+        }
+        assert_eq!(pos, node.range.end, "children don't cover {:?}", node.label);
+    }
+
+    fn annotate(bytes: &[u8]) -> Annotation {
+        let annotation = PhotonMessage::annotate(bytes);
+        assert_eq!(annotation.range, 0..bytes.len());
+        assert_contiguous(&annotation);
+        annotation
+    }
+
+    /// Messages with every data type, serialized by the regular writer.
+    fn serialized_fixtures() -> Vec<Vec<u8>> {
+        let nested = PhotonMessage::EventData(EventData {
+            code: 200,
+            parameters: indexmap! {
+                245 => PhotonDataType::Hashtable(indexmap! {
+                    PhotonDataType::Byte(0) => PhotonDataType::Integer(1001),
+                    PhotonDataType::String("room".into()) => PhotonDataType::ObjectArray(vec![
+                        PhotonDataType::Null,
+                        PhotonDataType::Boolean(true),
+                        PhotonDataType::Custom(CustomData::Vector3(Vector3(1.0.into(), 2.0.into(), 3.0.into()))),
+                        PhotonDataType::Custom(CustomData::Unrecognized(b'X', vec![1, 2, 3])),
+                    ]),
+                    PhotonDataType::Short(-2) => PhotonDataType::Long(1 << 40),
+                }),
+                252 => PhotonDataType::IntArray(vec![1, 2, 3]),
+            },
+        });
+        let containers = PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: 252,
+            parameters: indexmap! {
+                1 => PhotonDataType::Dictionary((0x73, 0), indexmap! {
+                    PhotonDataType::String("a".into()) => PhotonDataType::Float(0.5.into()),
+                    PhotonDataType::String("b".into()) => PhotonDataType::Double(0.25.into()),
+                }),
+                2 => PhotonDataType::Array(vec![
+                    PhotonDataType::String("x".into()),
+                    PhotonDataType::String(String::new()),
+                ]),
+                3 => PhotonDataType::StringArray(vec!["y".into()]),
+                4 => PhotonDataType::ByteArray(vec![9; 20]),
+                5 => PhotonDataType::OperationResponse(OperationResponse {
+                    operation_code: 1,
+                    return_code: -1,
+                    debug_message: Some("nope".into()),
+                    parameters: indexmap! {},
+                }),
+            },
+        });
+
+        [nested, containers]
+            .iter()
+            .map(|message| {
+                let mut bytes = vec![];
+                message.to_websocket_bytes(&mut bytes).unwrap();
+                bytes
+            })
+            .collect()
+    }
+
+    #[test]
+    fn covers_fixtures() {
+        let captured = [
+            "f30100",
+            "f302e50000",
+            "f303e500002a0000",
+            "f304e20003e36900000011e5690000006ee46900000016",
+            "f3070100002a0002016900002efd026938c2510f",
+            "f382a1b2c3d4",
+            "f00000000100000002",
+        ]
+        .map(|hex| hex::decode(hex).unwrap());
+
+        for bytes in captured.into_iter().chain(serialized_fixtures()) {
+            let annotation = annotate(&bytes);
+            assert!(annotation.is_complete(), "{annotation:#?}");
+            assert_eq!(
+                annotation
+                    .leaves()
+                    .iter()
+                    .map(|l| l.range.len())
+                    .sum::<usize>(),
+                bytes.len()
+            );
+        }
+    }
+
+    #[test]
+    fn labels_fields() {
+        let bytes = hex::decode("f304e20003e36900000011e5690000006ee46900000016").unwrap();
+        let annotation = annotate(&bytes);
+        let labels = annotation
+            .children
+            .iter()
+            .map(|c| c.label.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            ["signature", "message type", "event code", "parameters"]
+        );
+        assert_eq!(
+            annotation.children[1].value_preview.as_deref(),
+            Some("EventData")
+        );
+        assert_eq!(
+            annotation.children[3].value_preview.as_deref(),
+            Some("3 parameters")
+        );
+
+        // the second byte of the value of parameter 229
+        let node = annotation.node_at(14).unwrap();
+        assert_eq!(node.label, "value");
+        assert_eq!(node.range, 13..17);
+        assert_eq!(node.value_preview.as_deref(), Some("110"));
+        let parameter = &annotation.children[3].children[2];
+        assert_eq!(parameter.label, "parameter 229");
+        assert_eq!(parameter.range, 11..17);
+    }
+
+    #[test]
+    fn marks_unparsed_bytes() {
+        // the second parameter has an unknown type
+        let annotation = annotate(&hex::decode("f304e20002e3690000001101ff0203").unwrap());
+        assert!(!annotation.is_complete());
+        let unparsed = annotation.children.last().unwrap();
+        assert_eq!(unparsed.label, UNPARSED_LABEL);
+        assert_eq!(unparsed.range, 13..15);
+        assert_eq!(
+            unparsed.value_preview.as_deref(),
+            Some("data type is unknown: 0xFF")
+        );
+
+        let parameters = &annotation.children[3];
+        assert_eq!(parameters.value_preview.as_deref(), Some("incomplete"));
+        assert_eq!(parameters.children[1].value_preview, None);
+    }
+}
@@ -0,0 +1,580 @@
+//! Formatting [PhotonDataType]s and [PhotonMessage]s with limits, for logging messages that may be huge.
+//!
+//! The derived [Debug] output of a game list holds every room with all of its properties, which easily makes
+//! megabytes. [PhotonDataType::display_bounded] and [PhotonMessage::display_bounded] limit how deep nested containers
+//! are shown, how many items of each container and how many characters of each string. Whatever is left out is
+//! marked as `…(+N more)`. The output looks like the pretty-printed [Debug] output, or like the plain one in
+//! [compact](Bounded::compact) mode.
+
+use std::fmt::{self, Display, Formatter, Write};
+
+use crate::{
+    photon_data_type::{CustomData, PhotonDataType},
+    photon_message::{EventData, OperationRequest, OperationResponse, PhotonMessage},
+    ParameterMap,
+};
+
+/// What is left out.
+const MORE: &str = "…";
+
+/// How much of a value [Bounded] shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayLimits {
+    /// How many levels of nested containers show their items. Deeper containers only show how many they have.
+    pub max_depth: usize,
+    /// How many items of each container are shown.
+    pub max_items: usize,
+    /// How many characters of each string are shown.
+    pub max_string_len: usize,
+    /// Whether to write everything on a single line.
+    pub compact: bool,
+}
+
+impl Default for DisplayLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 4,
+            max_items: 16,
+            max_string_len: 128,
+            compact: false,
+        }
+    }
+}
+
+/// Shows a value within [DisplayLimits], see [display](self).
+#[derive(Debug, Clone, Copy)]
+pub struct Bounded<'a, T> {
+    value: &'a T,
+    limits: DisplayLimits,
+}
+
+impl<'a, T> Bounded<'a, T> {
+    pub fn new(value: &'a T, limits: DisplayLimits) -> Self {
+        Self { value, limits }
+    }
+
+    /// Writes everything on a single line.
+    pub fn compact(self) -> Self {
+        Self {
+            limits: DisplayLimits {
+                compact: true,
+                ..self.limits
+            },
+            ..self
+        }
+    }
+}
+
+impl PhotonDataType {
+    /// Shows this value with the given limits, see [display](crate::display).
+    pub fn display_bounded(
+        &self,
+        max_depth: usize,
+        max_items: usize,
+        max_string_len: usize,
+    ) -> Bounded<'_, Self> {
+        Bounded::new(
+            self,
+            DisplayLimits {
+                max_depth,
+                max_items,
+                max_string_len,
+                compact: false,
+            },
+        )
+    }
+}
+
+impl PhotonMessage {
+    /// Shows this message with the given limits, see [display](crate::display).
+    pub fn display_bounded(
+        &self,
+        max_depth: usize,
+        max_items: usize,
+        max_string_len: usize,
+    ) -> Bounded<'_, Self> {
+        Bounded::new(
+            self,
+            DisplayLimits {
+                max_depth,
+                max_items,
+                max_string_len,
+                compact: false,
+            },
+        )
+    }
+}
+
+impl Display for Bounded<'_, PhotonDataType> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Printer::new(f, self.limits).value(self.value, 0)
+    }
+}
+
+impl Display for Bounded<'_, PhotonMessage> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Printer::new(f, self.limits).message(self.value)
+    }
+}
+
+struct Printer<'a, 'b> {
+    f: &'a mut Formatter<'b>,
+    limits: DisplayLimits,
+    indent: usize,
+}
+
+impl<'a, 'b> Printer<'a, 'b> {
+    fn new(f: &'a mut Formatter<'b>, limits: DisplayLimits) -> Self {
+        Self {
+            f,
+            limits,
+            indent: 0,
+        }
+    }
+
+    fn message(&mut self, message: &PhotonMessage) -> fmt::Result {
+        match message {
+            PhotonMessage::Init => self.f.write_str("Init"),
+            PhotonMessage::InitResponse => self.f.write_str("InitResponse"),
+            PhotonMessage::OperationRequest(request) => {
+                self.request("OperationRequest", request, 0)
+            }
+            PhotonMessage::OperationResponse(response) => {
+                self.response("OperationResponse", response, 0)
+            }
+            PhotonMessage::EventData(event) => self.event(event, 0),
+            PhotonMessage::DisconnectMessage(disconnect) => {
+                self.f.write_str("DisconnectMessage {")?;
+                self.field(true, "code", |p| write!(p.f, "{}", disconnect.code))?;
+                self.field(false, "debug_message", |p| {
+                    p.optional_string(disconnect.debug_message.as_deref())
+                })?;
+                self.field(false, "parameters", |p| {
+                    p.parameters(&disconnect.parameters, 0)
+                })?;
+                self.end("}")
+            }
+            PhotonMessage::InternalOperationRequest(request) => {
+                self.request("InternalOperationRequest", request, 0)
+            }
+            PhotonMessage::InternalOperationResponse(response) => {
+                self.response("InternalOperationResponse", response, 0)
+            }
+            PhotonMessage::Message(value) => {
+                self.f.write_str("Message(")?;
+                self.value(value, 0)?;
+                self.f.write_str(")")
+            }
+            PhotonMessage::RawMessage(bytes) => {
+                self.f.write_str("RawMessage(")?;
+                self.inline(bytes)?;
+                self.f.write_str(")")
+            }
+            PhotonMessage::PingResult(ping) => write!(self.f, "{ping:?}"),
+            PhotonMessage::Encrypted(encrypted) => {
+                self.f.write_str("Encrypted {")?;
+                self.field(true, "message_type", |p| {
+                    write!(p.f, "{}", encrypted.message_type)
+                })?;
+                self.field(false, "payload", |p| p.inline(&encrypted.payload))?;
+                self.end("}")
+            }
+        }
+    }
+
+    fn value(&mut self, value: &PhotonDataType, depth: usize) -> fmt::Result {
+        match value {
+            PhotonDataType::Null => self.f.write_str("Null"),
+            PhotonDataType::Dictionary((key_type, value_type), map) => {
+                write!(self.f, "Dictionary({key_type:#04x}, {value_type:#04x}) ")?;
+                self.map(map, depth)
+            }
+            PhotonDataType::StringArray(strings) => {
+                self.f.write_str("StringArray(")?;
+                self.container(("[", "]"), depth, strings.iter(), |p, s| p.string(s))?;
+                self.f.write_str(")")
+            }
+            PhotonDataType::Byte(n) => write!(self.f, "Byte({n})"),
+            PhotonDataType::Custom(CustomData::Unrecognized(type_code, bytes)) => {
+                write!(self.f, "Custom(Unrecognized({type_code}, ")?;
+                self.inline(bytes)?;
+                self.f.write_str("))")
+            }
+            PhotonDataType::Custom(custom) => write!(self.f, "Custom({custom:?})"),
+            PhotonDataType::Double(n) => write!(self.f, "Double({:?})", n.0),
+            PhotonDataType::EventData(event) => self.event(event, depth),
+            PhotonDataType::Float(n) => write!(self.f, "Float({:?})", n.0),
+            PhotonDataType::Hashtable(map) => {
+                self.f.write_str("Hashtable ")?;
+                self.map(map, depth)
+            }
+            PhotonDataType::Integer(n) => write!(self.f, "Integer({n})"),
+            PhotonDataType::Short(n) => write!(self.f, "Short({n})"),
+            PhotonDataType::Long(n) => write!(self.f, "Long({n})"),
+            PhotonDataType::IntArray(ints) => {
+                self.f.write_str("IntArray(")?;
+                self.inline(ints)?;
+                self.f.write_str(")")
+            }
+            PhotonDataType::Boolean(b) => write!(self.f, "Boolean({b})"),
+            PhotonDataType::OperationResponse(response) => {
+                self.response("OperationResponse", response, depth)
+            }
+            PhotonDataType::OperationRequest(request) => {
+                self.request("OperationRequest", request, depth)
+            }
+            PhotonDataType::String(s) => {
+                self.f.write_str("String(")?;
+                self.string(s)?;
+                self.f.write_str(")")
+            }
+            PhotonDataType::ByteArray(bytes) => {
+                self.f.write_str("ByteArray(")?;
+                self.inline(bytes)?;
+                self.f.write_str(")")
+            }
+            PhotonDataType::Array(values) => {
+                self.f.write_str("Array ")?;
+                self.container(("[", "]"), depth, values.iter(), |p, v| {
+                    p.value(v, depth + 1)
+                })
+            }
+            PhotonDataType::ObjectArray(values) => {
+                self.f.write_str("ObjectArray ")?;
+                self.container(("[", "]"), depth, values.iter(), |p, v| {
+                    p.value(v, depth + 1)
+                })
+            }
+            PhotonDataType::Unknown {
+                type_code,
+                raw_bytes,
+            } => {
+                write!(self.f, "Unknown({type_code:#04x}, ")?;
+                self.inline(raw_bytes)?;
+                self.f.write_str(")")
+            }
+        }
+    }
+
+    fn request(&mut self, name: &str, request: &OperationRequest, depth: usize) -> fmt::Result {
+        write!(self.f, "{name} {{")?;
+        self.field(true, "operation_code", |p| {
+            write!(p.f, "{}", request.operation_code)
+        })?;
+        self.field(false, "parameters", |p| {
+            p.parameters(&request.parameters, depth)
+        })?;
+        self.end("}")
+    }
+
+    fn response(&mut self, name: &str, response: &OperationResponse, depth: usize) -> fmt::Result {
+        write!(self.f, "{name} {{")?;
+        self.field(true, "operation_code", |p| {
+            write!(p.f, "{}", response.operation_code)
+        })?;
+        self.field(false, "return_code", |p| {
+            write!(p.f, "{}", response.return_code)
+        })?;
+        self.field(false, "debug_message", |p| {
+            p.optional_string(response.debug_message.as_deref())
+        })?;
+        self.field(false, "parameters", |p| {
+            p.parameters(&response.parameters, depth)
+        })?;
+        self.end("}")
+    }
+
+    fn event(&mut self, event: &EventData, depth: usize) -> fmt::Result {
+        self.f.write_str("EventData {")?;
+        self.field(true, "code", |p| write!(p.f, "{}", event.code))?;
+        self.field(false, "parameters", |p| {
+            p.parameters(&event.parameters, depth)
+        })?;
+        self.end("}")
+    }
+
+    fn parameters(&mut self, parameters: &ParameterMap, depth: usize) -> fmt::Result {
+        self.container(("{", "}"), depth, parameters.iter(), |p, (key, value)| {
+            write!(p.f, "{key}: ")?;
+            p.value(value, depth + 1)
+        })
+    }
+
+    fn map(
+        &mut self,
+        map: &indexmap::IndexMap<PhotonDataType, PhotonDataType>,
+        depth: usize,
+    ) -> fmt::Result {
+        self.container(("{", "}"), depth, map.iter(), |p, (key, value)| {
+            p.value(key, depth + 1)?;
+            p.f.write_str(": ")?;
+            p.value(value, depth + 1)
+        })
+    }
+
+    /// Writes the items of a container, each on its own line unless compact. Containers deeper than
+    /// [DisplayLimits::max_depth] only show how many items they have.
+    fn container<I: ExactSizeIterator>(
+        &mut self,
+        (open, close): (&str, &str),
+        depth: usize,
+        items: I,
+        mut item: impl FnMut(&mut Self, I::Item) -> fmt::Result,
+    ) -> fmt::Result {
+        let len = items.len();
+        self.f.write_str(open)?;
+        if len == 0 {
+            return self.f.write_str(close);
+        }
+        if depth >= self.limits.max_depth {
+            write!(self.f, "{MORE}(+{len} more)")?;
+            return self.f.write_str(close);
+        }
+
+        // `{ a, b }` and `[a, b]`, as in the plain Debug output
+        let padding = match open {
+            "{" => " ",
+            _ => "",
+        };
+        let shown = len.min(self.limits.max_items);
+        self.indent += 1;
+        for (i, it) in items.take(shown).enumerate() {
+            self.next_item(i == 0, padding)?;
+            item(self, it)?;
+        }
+        if shown < len {
+            self.next_item(shown == 0, padding)?;
+            write!(self.f, "{MORE}(+{} more)", len - shown)?;
+        }
+        self.indent -= 1;
+        match self.limits.compact {
+            true => write!(self.f, "{padding}{close}"),
+            false => self.end(close),
+        }
+    }
+
+    /// Writes a field of a struct, whose opening brace was already written.
+    fn field(
+        &mut self,
+        first: bool,
+        name: &str,
+        value: impl FnOnce(&mut Self) -> fmt::Result,
+    ) -> fmt::Result {
+        self.indent += 1;
+        self.next_item(first, " ")?;
+        write!(self.f, "{name}: ")?;
+        value(self)?;
+        self.indent -= 1;
+        Ok(())
+    }
+
+    fn next_item(&mut self, first: bool, padding: &str) -> fmt::Result {
+        match (self.limits.compact, first) {
+            (true, true) => self.f.write_str(padding),
+            (true, false) => self.f.write_str(", "),
+            (false, true) => self.newline(),
+            (false, false) => {
+                self.f.write_str(",")?;
+                self.newline()
+            }
+        }
+    }
+
+    /// Closes a struct or container after its last item.
+    fn end(&mut self, close: &str) -> fmt::Result {
+        match self.limits.compact {
+            true => write!(self.f, " {close}"),
+            false => {
+                self.f.write_str(",")?;
+                self.newline()?;
+                self.f.write_str(close)
+            }
+        }
+    }
+
+    fn newline(&mut self) -> fmt::Result {
+        self.f.write_char('\n')?;
+        for _ in 0..self.indent {
+            self.f.write_str("    ")?;
+        }
+        Ok(())
+    }
+
+    /// Writes a list of numbers on a single line, even when not compact.
+    fn inline<T: Display>(&mut self, items: &[T]) -> fmt::Result {
+        let shown = items.len().min(self.limits.max_items);
+        self.f.write_str("[")?;
+        for (i, item) in items[..shown].iter().enumerate() {
+            if i > 0 {
+                self.f.write_str(", ")?;
+            }
+            write!(self.f, "{item}")?;
+        }
+        if shown < items.len() {
+            if shown > 0 {
+                self.f.write_str(", ")?;
+            }
+            write!(self.f, "{MORE}(+{} more)", items.len() - shown)?;
+        }
+        self.f.write_str("]")
+    }
+
+    fn string(&mut self, s: &str) -> fmt::Result {
+        match s.char_indices().nth(self.limits.max_string_len) {
+            Some((end, _)) => {
+                let more = s[end..].chars().count();
+                write!(self.f, "{:?}{MORE}(+{more} more)", &s[..end])
+            }
+            None => write!(self.f, "{s:?}"),
+        }
+    }
+
+    fn optional_string(&mut self, s: Option<&str>) -> fmt::Result {
+        match s {
+            Some(s) => {
+                self.f.write_str("Some(")?;
+                self.string(s)?;
+                self.f.write_str(")")
+            }
+            None => self.f.write_str("None"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::{indexmap, IndexMap};
+
+    use crate::{
+        photon_data_type::PhotonDataType,
+        photon_message::{EventData, PhotonMessage},
+    };
+
+    fn nested(levels: usize) -> PhotonDataType {
+        (0..levels).fold(PhotonDataType::Integer(7), |inner, level| {
+            PhotonDataType::Hashtable(indexmap! {
+                PhotonDataType::Byte(level as u8) => inner,
+            })
+        })
+    }
+
+    fn game_list(rooms: usize) -> PhotonMessage {
+        let rooms: IndexMap<_, _> = (0..rooms)
+            .map(|i| {
+                (
+                    PhotonDataType::String(format!("room {i}")),
+                    PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::Byte(255) => PhotonDataType::Byte(12),
+                        PhotonDataType::String("mapName".into()) => PhotonDataType::String("Urban".repeat(40)),
+                        PhotonDataType::String("password".into()) => PhotonDataType::String(String::new()),
+                    }),
+                )
+            })
+            .collect();
+        PhotonMessage::EventData(EventData {
+            code: 230,
+            parameters: indexmap! { 222 => PhotonDataType::Hashtable(rooms) },
+        })
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let value = nested(5);
+        assert_eq!(
+            value.display_bounded(2, 8, 16).to_string(),
+            "\
+Hashtable {
+    Byte(4): Hashtable {
+        Byte(3): Hashtable {…(+1 more)},
+    },
+}"
+        );
+        assert_eq!(
+            value.display_bounded(2, 8, 16).compact().to_string(),
+            "Hashtable { Byte(4): Hashtable { Byte(3): Hashtable {…(+1 more)} } }"
+        );
+        assert_eq!(
+            value.display_bounded(0, 8, 16).to_string(),
+            "Hashtable {…(+1 more)}"
+        );
+    }
+
+    #[test]
+    fn wide_structures() {
+        let value = PhotonDataType::ObjectArray(vec![
+            PhotonDataType::ByteArray((0..100).collect()),
+            PhotonDataType::String("abcdefghijklmnopqrstuvwxyz".into()),
+            PhotonDataType::StringArray(vec!["a".into(); 5]),
+            PhotonDataType::Hashtable(IndexMap::new()),
+            PhotonDataType::Null,
+            PhotonDataType::Integer(1),
+        ]);
+        assert_eq!(
+            value.display_bounded(3, 4, 10).to_string(),
+            "\
+ObjectArray [
+    ByteArray([0, 1, 2, 3, …(+96 more)]),
+    String(\"abcdefghij\"…(+16 more)),
+    StringArray([
+        \"a\",
+        \"a\",
+        \"a\",
+        \"a\",
+        …(+1 more),
+    ]),
+    Hashtable {},
+    …(+2 more),
+]"
+        );
+        assert_eq!(
+            value.display_bounded(3, 2, 3).compact().to_string(),
+            "ObjectArray [ByteArray([0, 1, …(+98 more)]), String(\"abc\"…(+23 more)), …(+4 more)]"
+        );
+        assert_eq!(
+            PhotonDataType::IntArray(vec![1, 2])
+                .display_bounded(0, 0, 0)
+                .to_string(),
+            "IntArray([…(+2 more)])"
+        );
+    }
+
+    #[test]
+    fn messages() {
+        let message = game_list(3);
+        assert_eq!(
+            message.display_bounded(2, 2, 8).to_string(),
+            "\
+EventData {
+    code: 230,
+    parameters: {
+        222: Hashtable {
+            String(\"room 0\"): Hashtable {…(+3 more)},
+            String(\"room 1\"): Hashtable {…(+3 more)},
+            …(+1 more),
+        },
+    },
+}"
+        );
+        assert_eq!(
+            message.display_bounded(3, 2, 8).compact().to_string(),
+            "EventData { code: 230, parameters: { 222: Hashtable { String(\"room 0\"): Hashtable { \
+             Byte(255): Byte(12), String(\"mapName\"): String(\"UrbanUrb\"…(+192 more)), …(+1 more) }, \
+             String(\"room 1\"): Hashtable { Byte(255): Byte(12), String(\"mapName\"): \
+             String(\"UrbanUrb\"…(+192 more)), …(+1 more) }, …(+1 more) } } }"
+        );
+    }
+
+    #[test]
+    fn output_stays_small() {
+        let message = game_list(2000);
+        let full = format!("{message:#?}");
+        assert!(full.len() > 1_000_000);
+
+        let bounded = message.display_bounded(4, 16, 64).to_string();
+        assert!(bounded.len() < 8 * 1024, "{} bytes", bounded.len());
+        assert!(bounded.ends_with("…(+1984 more),\n        },\n    },\n}"));
+        let compact = message.display_bounded(4, 16, 64).compact().to_string();
+        assert!(!compact.contains('\n'));
+        assert!(compact.len() < bounded.len());
+    }
+}
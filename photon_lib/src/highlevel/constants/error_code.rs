@@ -0,0 +1,41 @@
+//! Return codes of [OperationResponse](crate::photon_message::OperationResponse), sent as its `return_code`.
+//!
+//! Note that the documentation comes from Photon with only minor edits.
+
+/// (0) The operation succeeded.
+pub const OK: i16 = 0;
+/// (-3) Operation can't be executed yet (e.g. OpJoin can't be called before being authenticated, RaiseEvent cant be
+/// used before getting into a room).
+pub const OPERATION_NOT_ALLOWED_IN_CURRENT_STATE: i16 = -3;
+/// (-2) The operation you called is not implemented on the server (application) you connect to.
+pub const INVALID_OPERATION: i16 = -2;
+/// (-1) Something went wrong in the server. Try to reproduce and contact Exit Games.
+pub const INTERNAL_SERVER_ERROR: i16 = -1;
+/// (32767) Authentication failed. Possible cause: AppId is unknown to Photon (in cloud service).
+pub const INVALID_AUTHENTICATION: i16 = 32767;
+/// (32766) GameId (name) already in use (can't create another). Change name.
+pub const GAME_ID_ALREADY_EXISTS: i16 = 32766;
+/// (32765) Game is full. This rarely happens when some player joined the room before your join completed.
+pub const GAME_FULL: i16 = 32765;
+/// (32764) Game is closed and can't be joined. Join another game.
+pub const GAME_CLOSED: i16 = 32764;
+/// (32762) All servers are busy. This is a temporary issue and the game logic should try again after a brief wait
+/// time.
+pub const SERVER_FULL: i16 = 32762;
+/// (32761) Not in use currently.
+pub const USER_BLOCKED: i16 = 32761;
+/// (32760) Random matchmaking only succeeds if a room exists thats neither closed nor full. Repeat in a few seconds
+/// or create a new room.
+pub const NO_RANDOM_MATCH_FOUND: i16 = 32760;
+/// (32758) Join can fail if the room (name) is not existing (anymore). This can happen when players leave while you
+/// join.
+pub const GAME_DOES_NOT_EXIST: i16 = 32758;
+/// (32757) Authorization on the Photon Cloud failed because the concurrent users (CCU) limit of the app's
+/// subscription is reached.
+pub const MAX_CCU_REACHED: i16 = 32757;
+/// (32756) Authorization on the Photon Cloud failed because the app's subscription does not allow to use a
+/// particular region's server.
+pub const INVALID_REGION: i16 = 32756;
+/// (32755) Custom Authentication of the user failed due to setup reasons (see Cloud Dashboard) or the provided user
+/// data (like username or token).
+pub const CUSTOM_AUTHENTICATION_FAILED: i16 = 32755;
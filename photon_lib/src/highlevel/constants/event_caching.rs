@@ -0,0 +1,36 @@
+//! Caching options for [operation_code::RAISE_EVENT], sent as [parameter_code::CACHE].
+//!
+//! Note that the documentation comes from Photon with only minor edits.
+
+#[allow(unused)]
+use crate::highlevel::constants::*;
+
+/// Default value (not sent).
+pub const DO_NOT_CACHE: u8 = 0;
+/// Will merge this event's keys with those already cached.
+#[deprecated]
+pub const MERGE_CACHE: u8 = 1;
+/// Replaces the event cache for this eventCode with this event's content.
+#[deprecated]
+pub const REPLACE_CACHE: u8 = 2;
+/// Removes this event (by eventCode) from the cache.
+#[deprecated]
+pub const REMOVE_CACHE: u8 = 3;
+/// Adds an event to the room's cache, so players that join later also receive it.
+pub const ADD_TO_ROOM_CACHE: u8 = 4;
+/// Adds this event to the cache for actor 0 (becoming a "globally owned" event in the cache).
+pub const ADD_TO_ROOM_CACHE_GLOBAL: u8 = 5;
+/// Remove fitting event from the room's cache.
+pub const REMOVE_FROM_ROOM_CACHE: u8 = 6;
+/// Removes events of players who already left the room (cleaning up).
+pub const REMOVE_FROM_ROOM_CACHE_FOR_ACTORS_LEFT: u8 = 7;
+/// Increase the index of the sliced cache.
+pub const SLICE_INC_INDEX: u8 = 10;
+/// Set the index of the sliced cache. You must set [parameter_code::CACHE_SLICE_INDEX] for this.
+pub const SLICE_SET_INDEX: u8 = 11;
+/// Purge cache slice with index. Exactly one slice is removed from cache. You must set
+/// [parameter_code::CACHE_SLICE_INDEX] for this.
+pub const SLICE_PURGE_INDEX: u8 = 12;
+/// Purge cache slices with specified index and anything lower than that. You must set
+/// [parameter_code::CACHE_SLICE_INDEX] for this.
+pub const SLICE_PURGE_UP_TO_INDEX: u8 = 13;
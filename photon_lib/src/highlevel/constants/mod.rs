@@ -1,8 +1,12 @@
 //! Contains constants used by Photon
 
 pub mod actor_properties;
+pub mod error_code;
+pub mod event_caching;
 pub mod event_code;
 pub mod game_property_key;
 pub mod operation_code;
 pub mod parameter_code;
+pub mod property_type_flag;
 pub mod pun_event_code;
+pub mod receiver_group;
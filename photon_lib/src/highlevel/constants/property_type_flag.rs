@@ -0,0 +1,13 @@
+//! Which properties [operation_code::GET_PROPERTIES] asks for, sent as [parameter_code::PROPERTIES].
+
+#[allow(unused)]
+use crate::highlevel::constants::*;
+
+/// No properties.
+pub const NONE: u8 = 0;
+/// The properties of the room.
+pub const GAME: u8 = 1;
+/// The properties of the actors.
+pub const ACTOR: u8 = 2;
+/// The properties of both the room and the actors.
+pub const GAME_AND_ACTOR: u8 = GAME | ACTOR;
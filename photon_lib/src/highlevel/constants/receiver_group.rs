@@ -0,0 +1,13 @@
+//! Receivers of [operation_code::RAISE_EVENT], sent as [parameter_code::RECEIVER_GROUP].
+//!
+//! Note that the documentation comes from Photon with only minor edits.
+
+#[allow(unused)]
+use crate::highlevel::constants::*;
+
+/// Default value (not sent). Anyone else gets my event.
+pub const OTHERS: u8 = 0;
+/// Everyone in the current room (including this peer) will get this event.
+pub const ALL: u8 = 1;
+/// The server sends this event only to the actor with the lowest actorNumber.
+pub const MASTER_CLIENT: u8 = 2;
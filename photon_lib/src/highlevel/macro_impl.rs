@@ -193,6 +193,100 @@ macro_rules! impl_photon_map_conversion {
                     }
                 }
             }
+
+            paste::paste! {
+                #[doc = concat!("A typed view over the hashtable of a [", stringify!($type_name), "], to read or edit it in place.")]
+                ///
+                /// Unlike converting with `from_map` and `into_map`, nothing is cloned and the keys that are not
+                /// touched keep their value and position in the map.
+                pub struct [<$type_name View>]<M = crate::PhotonHashmap>(pub M);
+
+                impl<M> [<$type_name View>]<M> {
+                    /// Wraps a map, also usable through type aliases of the view.
+                    pub fn new(map: M) -> Self {
+                        Self(map)
+                    }
+                }
+
+                impl<M: std::borrow::Borrow<crate::PhotonHashmap>> [<$type_name View>]<M> {
+                    $(
+                        $(
+                            pub fn $field_name_req(&self) -> Option<&$field_type_req> {
+                                match self.0.borrow().get(&$map_key_req) {
+                                    #[allow(unused_parens)]
+                                    Some($($map_type_req)?(b)) => Some(b),
+                                    #[allow(unreachable_patterns)]
+                                    _ => None,
+                                }
+                            }
+                        )?
+                        $(
+                            pub fn $field_name_opt(&self) -> Option<&$field_type_opt> {
+                                match self.0.borrow().get(&$map_key_opt) {
+                                    #[allow(unused_parens)]
+                                    Some($($map_type_opt)?(b)) => Some(b),
+                                    #[allow(unreachable_patterns)]
+                                    _ => None,
+                                }
+                            }
+                        )?
+                    )*
+
+                    pub fn custom_property(&self, key: &str) -> Option<&PhotonDataType> {
+                        self.0.borrow().get(&PhotonDataType::String(key.to_string()))
+                    }
+
+                    /// Gets a custom property if it is a string.
+                    pub fn custom_str(&self, key: &str) -> Option<&str> {
+                        match self.custom_property(key) {
+                            Some(PhotonDataType::String(s)) => Some(s.as_str()),
+                            _ => None,
+                        }
+                    }
+                }
+
+                impl<M: std::borrow::BorrowMut<crate::PhotonHashmap>> [<$type_name View>]<M> {
+                    $(
+                        $(
+                            pub fn [<set_ $field_name_req>](&mut self, value: $field_type_req) {
+                                self.0.borrow_mut().insert($map_key_req, $($map_type_req)?(value));
+                            }
+                        )?
+                        $(
+                            pub fn [<set_ $field_name_opt>](&mut self, value: $field_type_opt) {
+                                self.0.borrow_mut().insert($map_key_opt, $($map_type_opt)?(value));
+                            }
+                        )?
+                    )*
+
+                    pub fn custom_property_mut(&mut self, key: &str) -> Option<&mut PhotonDataType> {
+                        self.0.borrow_mut().get_mut(&PhotonDataType::String(key.to_string()))
+                    }
+
+                    /// Gets a custom property for editing if it is a string.
+                    pub fn custom_str_mut(&mut self, key: &str) -> Option<&mut String> {
+                        match self.custom_property_mut(key) {
+                            Some(PhotonDataType::String(s)) => Some(s),
+                            _ => None,
+                        }
+                    }
+
+                    /// Sets a custom property, keeping its position if it already exists. Returns the old value.
+                    pub fn set_custom_property(&mut self, key: &str, value: PhotonDataType) -> Option<PhotonDataType> {
+                        self.0.borrow_mut().insert(PhotonDataType::String(key.to_string()), value)
+                    }
+                }
+
+                impl $type_name {
+                    #[doc = concat!("Edits the hashtable of a [", stringify!($type_name), "] in place through a [", stringify!([<$type_name View>]), "].")]
+                    pub fn edit_map<R>(
+                        map: &mut crate::PhotonHashmap,
+                        f: impl FnOnce(&mut [<$type_name View>]<&mut crate::PhotonHashmap>) -> R,
+                    ) -> R {
+                        f(&mut [<$type_name View>](map))
+                    }
+                }
+            }
         )*
     };
 }
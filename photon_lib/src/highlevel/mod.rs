@@ -4,6 +4,7 @@
 mod macro_impl;
 
 pub mod constants;
+pub mod parameters;
 pub mod structs;
 mod structs_impl;
 
@@ -0,0 +1,189 @@
+//! Typed access to the parameters of photon messages.
+
+use std::{
+    borrow::{Borrow, BorrowMut},
+    ops::{Deref, DerefMut},
+};
+
+use crate::{
+    highlevel::constants::parameter_code, photon_data_type::PhotonDataType, ParameterMap,
+    PhotonHashmap,
+};
+
+/// A value that can be read from a parameter without converting it.
+pub trait ParameterValue<'a>: Sized {
+    /// Returns `None` if the data is of another type.
+    fn from_data(data: &'a PhotonDataType) -> Option<Self>;
+}
+
+/// A value that can be stored in a parameter.
+pub trait IntoParameterValue {
+    fn into_data(self) -> PhotonDataType;
+}
+
+macro_rules! impl_parameter_value {
+    ($($variant:ident: $get_type:ty, $set_type:ty => |$v:ident| $get:expr;)*) => {
+        $(
+            impl<'a> ParameterValue<'a> for $get_type {
+                fn from_data(data: &'a PhotonDataType) -> Option<Self> {
+                    match data {
+                        PhotonDataType::$variant($v) => Some($get),
+                        _ => None,
+                    }
+                }
+            }
+
+            impl IntoParameterValue for $set_type {
+                fn into_data(self) -> PhotonDataType {
+                    PhotonDataType::$variant(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_parameter_value! {
+    Boolean: bool, bool => |v| *v;
+    Byte: u8, u8 => |v| *v;
+    Short: i16, i16 => |v| *v;
+    Integer: i32, i32 => |v| *v;
+    Long: i64, i64 => |v| *v;
+    String: &'a str, String => |v| v.as_str();
+    StringArray: &'a [String], Vec<String> => |v| v.as_slice();
+    IntArray: &'a [i32], Vec<i32> => |v| v.as_slice();
+    Array: &'a [PhotonDataType], Vec<PhotonDataType> => |v| v.as_slice();
+    Hashtable: &'a PhotonHashmap, PhotonHashmap => |v| v;
+}
+
+/// A wrapper around a [ParameterMap] with typed getters and setters for known parameter codes.
+///
+/// It can wrap an owned map or a (mutable) reference to one, and derefs to the map for parameters that have no
+/// accessor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Parameters<M = ParameterMap>(pub M);
+
+impl<M: Borrow<ParameterMap>> Parameters<M> {
+    /// Gets a parameter, or `None` if it is missing or of another type.
+    pub fn get_as<'a, T: ParameterValue<'a>>(&'a self, code: u8) -> Option<T> {
+        self.0.borrow().get(&code).and_then(T::from_data)
+    }
+
+    pub fn into_inner(self) -> M {
+        self.0
+    }
+}
+
+impl<M: BorrowMut<ParameterMap>> Parameters<M> {
+    /// Sets a parameter, replacing any existing value.
+    pub fn set(&mut self, code: u8, value: impl IntoParameterValue) {
+        self.0.borrow_mut().insert(code, value.into_data());
+    }
+}
+
+impl<M: Borrow<ParameterMap>> Deref for Parameters<M> {
+    type Target = ParameterMap;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.borrow()
+    }
+}
+
+impl<M: BorrowMut<ParameterMap>> DerefMut for Parameters<M> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.borrow_mut()
+    }
+}
+
+impl From<ParameterMap> for Parameters {
+    fn from(map: ParameterMap) -> Self {
+        Self(map)
+    }
+}
+
+macro_rules! impl_parameter_accessors {
+    ($($code:ident => $getter:ident: $get_type:ty, $setter:ident: $set_type:ty;)*) => {
+        impl<M: Borrow<ParameterMap>> Parameters<M> {
+            $(
+                #[doc = concat!("Gets [parameter_code::", stringify!($code), "].")]
+                pub fn $getter(&self) -> Option<$get_type> {
+                    self.get_as(parameter_code::$code)
+                }
+            )*
+        }
+
+        impl<M: BorrowMut<ParameterMap>> Parameters<M> {
+            $(
+                #[doc = concat!("Sets [parameter_code::", stringify!($code), "].")]
+                pub fn $setter(&mut self, value: $set_type) {
+                    self.set(parameter_code::$code, value);
+                }
+            )*
+        }
+    };
+}
+
+impl_parameter_accessors! {
+    ROOM_NAME => room_name: &str, set_room_name: String;
+    ACTOR_NR => actor_nr: i32, set_actor_nr: i32;
+    TARGET_ACTOR_NR => target_actor_nr: i32, set_target_actor_nr: i32;
+    ACTOR_LIST => actor_list: &[PhotonDataType], set_actor_list: Vec<PhotonDataType>;
+    PROPERTIES => properties: &PhotonHashmap, set_properties: PhotonHashmap;
+    BROADCAST => broadcast: bool, set_broadcast: bool;
+    PLAYER_PROPERTIES => player_properties: &PhotonHashmap, set_player_properties: PhotonHashmap;
+    GAME_PROPERTIES => game_properties: &PhotonHashmap, set_game_properties: PhotonHashmap;
+    CODE => code: u8, set_code: u8;
+    GROUP => group: u8, set_group: u8;
+    ADDRESS => address: &str, set_address: String;
+    USER_ID => user_id: &str, set_user_id: String;
+    APP_VERSION => app_version: &str, set_app_version: String;
+    REGION => region: &str, set_region: String;
+    NICK_NAME => nickname: &str, set_nickname: String;
+    CLUSTER => cluster: &str, set_cluster: String;
+    PEER_COUNT => peer_count: i32, set_peer_count: i32;
+    GAME_COUNT => game_count: i32, set_game_count: i32;
+    MASTER_PEER_COUNT => master_peer_count: i32, set_master_peer_count: i32;
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::indexmap;
+
+    use super::Parameters;
+    use crate::{highlevel::constants::parameter_code, photon_data_type::PhotonDataType};
+
+    #[test]
+    fn typed_getters() {
+        let params = Parameters(indexmap! {
+            parameter_code::APP_VERSION => PhotonDataType::String("1.89.0_1.99".into()),
+            parameter_code::ACTOR_NR => PhotonDataType::Integer(3),
+            parameter_code::USER_ID => PhotonDataType::Integer(4),
+        });
+
+        assert_eq!(params.app_version(), Some("1.89.0_1.99"));
+        assert_eq!(params.actor_nr(), Some(3));
+        // wrong type
+        assert_eq!(params.user_id(), None);
+        // missing
+        assert_eq!(params.region(), None);
+        // raw access through deref
+        assert_eq!(params.len(), 3);
+    }
+
+    #[test]
+    fn setters_on_borrowed_map() {
+        let mut map = indexmap! {
+            parameter_code::USER_ID => PhotonDataType::String("old".into()),
+        };
+
+        let mut params = Parameters(&mut map);
+        params.set_user_id("new".into());
+        params.set_actor_nr(7);
+        assert_eq!(params.user_id(), Some("new"));
+
+        assert_eq!(
+            map.get(&parameter_code::ACTOR_NR),
+            Some(&PhotonDataType::Integer(7))
+        );
+        assert_eq!(Parameters(&map).user_id(), Some("new"));
+    }
+}
@@ -3,7 +3,9 @@
 pub use super::structs_impl::*;
 use crate::highlevel::constants::{actor_properties, game_property_key, parameter_code};
 #[allow(unused)]
-use crate::highlevel::constants::{event_code, operation_code, pun_event_code};
+use crate::highlevel::constants::{
+    event_caching, event_code, operation_code, property_type_flag, pun_event_code, receiver_group,
+};
 use crate::photon_data_type::{CustomData, PhotonDataType};
 use crate::PhotonHashmap;
 
@@ -129,8 +131,45 @@ impl_u8_map_conversion! {
         room_option_flags: i32, // could add an impl to map this to an enum or something
     }
 
-    /// Request parameter of [operation_code::RAISE_EVENT]
+    /// Response parameter of [operation_code::GET_REGIONS], sent by the name server.
+    #[derive(Debug)]
+    GetRegionsResponse {
+        /// The region codes, such as `eu` or `us`. Should be of same length as [Self::addresses].
+        @required
+        [parameter_code::REGION => PhotonDataType::StringArray]
+        regions: Vec<String>,
+
+        /// The master server address for each region. Should be of same length as [Self::regions].
+        @required
+        [parameter_code::ADDRESS => PhotonDataType::StringArray]
+        addresses: Vec<String>,
+    }
+
+    /// Response parameter of [operation_code::AUTHENTICATE] and [operation_code::AUTHENTICATE_ONCE] on success.
+    ///
+    /// When sent by the name server, this contains the address of the master server the client should connect to.
     #[derive(Debug)]
+    AuthenticateResponse {
+        /// The address of the (master) server to connect to next. Only sent by the name server.
+        [parameter_code::ADDRESS => PhotonDataType::String]
+        address: String,
+
+        [parameter_code::USER_ID => PhotonDataType::String]
+        user_id: String,
+
+        [parameter_code::NICK_NAME => PhotonDataType::String]
+        nickname: String,
+
+        /// Can be either a `String` or a `byte[]`.
+        [parameter_code::TOKEN]
+        token: PhotonDataType,
+
+        [parameter_code::CLUSTER => PhotonDataType::String]
+        cluster: String,
+    }
+
+    /// Request parameter of [operation_code::RAISE_EVENT]
+    #[derive(Debug, Clone, PartialEq)]
     RaiseEvent {
         @required
         [parameter_code::CODE => PhotonDataType::Byte]
@@ -139,15 +178,23 @@ impl_u8_map_conversion! {
         [parameter_code::DATA]
         data: PhotonDataType,
 
+        /// One of [event_caching]. Not sent if [event_caching::DO_NOT_CACHE].
         [parameter_code::CACHE => PhotonDataType::Byte]
         cache: u8,
 
+        /// Only used with the `SLICE_*` options of [event_caching].
+        [parameter_code::CACHE_SLICE_INDEX => PhotonDataType::Integer]
+        cache_slice_index: i32,
+
+        /// One of [receiver_group]. Not sent if [receiver_group::OTHERS].
         [parameter_code::RECEIVER_GROUP => PhotonDataType::Byte]
         receiver_group: u8,
 
+        /// The interest group to send this event to. Not sent if 0, which means everyone.
         [parameter_code::GROUP => PhotonDataType::Byte]
         interest_group: u8,
 
+        /// Only send the event to these actors. Overrides [Self::receiver_group].
         [parameter_code::ACTOR_LIST => PhotonDataType::IntArray]
         actor_list: Vec<i32>,
 
@@ -155,6 +202,44 @@ impl_u8_map_conversion! {
         event_forward: bool,
     }
 
+    /// Request parameter of [operation_code::CHANGE_GROUPS]. Groups are removed before others are added.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    ChangeGroupsRequest {
+        /// The interest groups to leave. An empty list leaves all groups.
+        [parameter_code::REMOVE => PhotonDataType::ByteArray]
+        remove: Vec<u8>,
+
+        /// The interest groups to join. An empty list joins all groups that currently exist.
+        [parameter_code::ADD => PhotonDataType::ByteArray]
+        add: Vec<u8>,
+    }
+
+    /// Request parameter of [operation_code::GET_PROPERTIES].
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    GetPropertiesRequest {
+        /// Which properties to get, one of [property_type_flag].
+        @required
+        [parameter_code::PROPERTIES => PhotonDataType::Byte]
+        properties_type: u8,
+
+        /// The actors to get the properties of. The properties of all actors are sent if this is left out.
+        [parameter_code::ACTOR_LIST => PhotonDataType::IntArray]
+        actor_list: Vec<i32>,
+    }
+
+    /// Response parameter of [operation_code::GET_PROPERTIES] on success (return code 0). Only the properties that
+    /// were asked for are sent.
+    #[derive(Debug)]
+    GetPropertiesResponse {
+        /// A hashmap over serialized [Player]s. The keys in this hashmap are integer actor ids.
+        [parameter_code::PLAYER_PROPERTIES => PhotonDataType::Hashtable]
+        player_properties: PhotonHashmap,
+
+        /// A serialized instance of [RoomInfo]
+        [parameter_code::GAME_PROPERTIES => PhotonDataType::Hashtable]
+        game_properties: PhotonHashmap,
+    }
+
     /// Parameter for [event_code::LEAVE].
     #[derive(Debug)]
     LeaveEvent {
@@ -374,6 +459,15 @@ impl_photon_map_conversion! {
     }
 }
 
+/// The room properties defined by Photon itself, which rooms of every Photon game share.
+///
+/// What a game keeps in its custom properties is up to the game, they are accessible through
+/// [RoomInfoView::custom_property] without being interpreted.
+pub type WellKnownRoomProperties<M = PhotonHashmap> = RoomInfoView<M>;
+
+/// The actor properties defined by Photon itself, see [WellKnownRoomProperties].
+pub type WellKnownActorProperties<M = PhotonHashmap> = PlayerView<M>;
+
 /// A serialized object stream. Can represent a `Monobehavior`, a `Transform`, a `Rigidbody` or a `RigidBody2D`.
 ///
 /// See [SendSerializeEvent].
@@ -387,10 +481,17 @@ mod tests {
     use indexmap::{indexmap, IndexMap};
     use ordered_float::OrderedFloat;
 
-    use super::RoomInfo;
-    use crate::highlevel::constants::game_property_key;
-    use crate::highlevel::PhotonMapConversion;
+    use super::{
+        ChangeGroupsRequest, EventTarget, GetPropertiesRequest, Player, RaiseEvent, RoomInfo,
+        WellKnownActorProperties, WellKnownRoomProperties,
+    };
+    use crate::highlevel::constants::{
+        actor_properties, event_caching, game_property_key, operation_code, parameter_code,
+        property_type_flag, receiver_group,
+    };
+    use crate::highlevel::{PhotonMapConversion, PhotonParameterMapConversion};
     use crate::photon_data_type::PhotonDataType;
+    use crate::photon_message::{OperationRequest, PhotonMessage};
 
     #[test]
     fn room_info() {
@@ -435,4 +536,265 @@ mod tests {
             assert_eq!(serialized, room_info);
         }
     }
+
+    /// Properties as any Photon game may send them, with custom properties that mean nothing to this crate.
+    #[test]
+    fn generic_properties() {
+        let room = indexmap! {
+            PhotonDataType::Byte(game_property_key::MAX_PLAYERS) => PhotonDataType::Byte(4),
+            PhotonDataType::Byte(game_property_key::IS_OPEN) => PhotonDataType::Boolean(false),
+            PhotonDataType::String("C0".into()) => PhotonDataType::Integer(2),
+            PhotonDataType::String("difficulty".into()) => PhotonDataType::String("hard".into()),
+        };
+        let view = WellKnownRoomProperties::new(&room);
+        assert_eq!(view.max_players(), Some(&4));
+        assert_eq!(view.is_open(), Some(&false));
+        assert_eq!(view.is_visible(), None);
+        assert_eq!(
+            view.custom_property("C0"),
+            Some(&PhotonDataType::Integer(2))
+        );
+        assert_eq!(view.custom_str("difficulty"), Some("hard"));
+
+        let parsed = RoomInfo::from_map(&mut room.clone()).unwrap();
+        assert_eq!(parsed.max_players, Some(4));
+        assert_eq!(
+            parsed.custom_properties.keys().collect::<Vec<_>>(),
+            ["C0", "difficulty"]
+        );
+
+        let actor = indexmap! {
+            PhotonDataType::Byte(actor_properties::PLAYER_NAME) => PhotonDataType::String("guest".into()),
+            PhotonDataType::String("avatar".into()) => PhotonDataType::Byte(7),
+        };
+        let view = WellKnownActorProperties::new(&actor);
+        assert_eq!(view.nickname().map(String::as_str), Some("guest"));
+        assert_eq!(view.user_id(), None);
+        let parsed = Player::from_map(&mut actor.clone()).unwrap();
+        assert_eq!(
+            parsed.custom_properties.get("avatar"),
+            Some(&PhotonDataType::Byte(7))
+        );
+    }
+
+    #[test]
+    fn room_info_edit_keeps_untouched_keys() {
+        let original = indexmap! {
+            PhotonDataType::String("roomName".into()) => PhotonDataType::String("room".into()),
+            PhotonDataType::Byte(game_property_key::MAX_PLAYERS) => PhotonDataType::Byte(15),
+            PhotonDataType::String("meanKD".into()) => PhotonDataType::Float(OrderedFloat(0.72795415)),
+            PhotonDataType::Byte(game_property_key::IS_OPEN) => PhotonDataType::Boolean(true),
+            PhotonDataType::String("seasonID".into()) => PhotonDataType::String("".into()),
+        };
+        let entry_bytes = |map: &IndexMap<PhotonDataType, PhotonDataType>| {
+            map.iter()
+                .map(|(k, v)| {
+                    let mut buf = vec![];
+                    k.to_bytes(&mut buf).unwrap();
+                    v.to_bytes(&mut buf).unwrap();
+                    buf
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut edited = original.clone();
+        RoomInfo::edit_map(&mut edited, |room| {
+            assert_eq!(room.max_players(), Some(&15));
+            assert_eq!(room.player_count(), None);
+            room.set_is_open(false);
+            if let Some(name) = room.custom_str_mut("roomName") {
+                *name = format!("[*] {name}");
+            }
+        });
+
+        assert_eq!(
+            edited.keys().collect::<Vec<_>>(),
+            original.keys().collect::<Vec<_>>()
+        );
+        let before = entry_bytes(&original);
+        let after = entry_bytes(&edited);
+        for i in [1, 2, 4] {
+            assert_eq!(before[i], after[i], "entry {i} should be untouched");
+        }
+        assert_eq!(edited[3], PhotonDataType::Boolean(false));
+        assert_eq!(edited[0], PhotonDataType::String("[*] room".into()));
+    }
+
+    /// Parses a RAISE_EVENT request, compares it to the expected struct and checks that it serializes back to the same
+    /// bytes.
+    fn raise_event_round_trip(hex: &str, expected: RaiseEvent) {
+        let bytes = hex::decode(hex).expect("valid hex data in test");
+
+        let mut request = match PhotonMessage::from_websocket_bytes(&mut bytes.as_slice()).unwrap()
+        {
+            PhotonMessage::OperationRequest(r) => r,
+            m => panic!("expected operation request, found {m:?}"),
+        };
+        assert_eq!(request.operation_code, operation_code::RAISE_EVENT);
+
+        let event = RaiseEvent::from_map(&mut request.parameters).unwrap();
+        assert_eq!(event, expected);
+        assert!(
+            request.parameters.is_empty(),
+            "all parameters should be consumed"
+        );
+
+        let mut parameters = IndexMap::new();
+        event.into_map(&mut parameters);
+        let mut buf = vec![];
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: operation_code::RAISE_EVENT,
+            parameters,
+        })
+        .to_websocket_bytes(&mut buf)
+        .unwrap();
+        assert_eq!(hex::encode(buf), hex);
+    }
+
+    #[test]
+    fn raise_event_cache() {
+        raise_event_round_trip(
+            "f302fd0003f462c8f5680000f76204",
+            RaiseEvent::new(200, PhotonDataType::Hashtable(IndexMap::new()))
+                .with_cache(event_caching::ADD_TO_ROOM_CACHE),
+        );
+    }
+
+    #[test]
+    fn raise_event_cache_slice_index() {
+        raise_event_round_trip(
+            "f302fd0004f462c8f5680000f7620acd6900000003",
+            RaiseEvent::new(200, PhotonDataType::Hashtable(IndexMap::new()))
+                .with_cache(event_caching::SLICE_INC_INDEX)
+                .with_cache_slice_index(3),
+        );
+    }
+
+    #[test]
+    fn raise_event_receiver_group() {
+        raise_event_round_trip(
+            "f302fd0003f462c8f5680000f66201",
+            RaiseEvent::new(200, PhotonDataType::Hashtable(IndexMap::new()))
+                .with_receiver_group(receiver_group::ALL),
+        );
+    }
+
+    #[test]
+    fn raise_event_interest_group() {
+        raise_event_round_trip(
+            "f302fd0003f462c8f5680000f06203",
+            RaiseEvent::new(200, PhotonDataType::Hashtable(IndexMap::new())).with_interest_group(3),
+        );
+    }
+
+    #[test]
+    fn raise_event_target_actors() {
+        raise_event_round_trip(
+            "f302fd0004f462c8f5680000fc6e000000020000000200000005ea6f01",
+            RaiseEvent {
+                event_forward: Some(true),
+                ..RaiseEvent::new(200, PhotonDataType::Hashtable(IndexMap::new()))
+                    .with_target_actors(vec![2, 5])
+            },
+        );
+    }
+
+    #[test]
+    fn raise_event_target() {
+        let parse = |hex: &str| {
+            let bytes = hex::decode(hex).unwrap();
+            match PhotonMessage::from_websocket_bytes(&mut bytes.as_slice()).unwrap() {
+                PhotonMessage::OperationRequest(mut r) => {
+                    RaiseEvent::from_map(&mut r.parameters).unwrap().target()
+                }
+                m => panic!("expected operation request, found {m:?}"),
+            }
+        };
+
+        assert_eq!(
+            parse("f302fd0003f462c8f5680000f76204"),
+            EventTarget::ReceiverGroup(receiver_group::OTHERS)
+        );
+        assert_eq!(
+            parse("f302fd0003f462c8f5680000f66201"),
+            EventTarget::ReceiverGroup(receiver_group::ALL)
+        );
+        assert_eq!(
+            parse("f302fd0003f462c8f5680000f06203"),
+            EventTarget::InterestGroup(3)
+        );
+        assert_eq!(
+            parse("f302fd0004f462c8f5680000fc6e000000020000000200000005ea6f01"),
+            EventTarget::Actors(vec![2, 5])
+        );
+
+        // target actors take precedence over everything else
+        let event = RaiseEvent::new(200, PhotonDataType::Null)
+            .with_receiver_group(receiver_group::MASTER_CLIENT)
+            .with_interest_group(3)
+            .with_target_actors(vec![4]);
+        assert_eq!(event.target().to_string(), "actors [4]");
+        assert_eq!(
+            RaiseEvent::new(200, PhotonDataType::Null)
+                .with_receiver_group(receiver_group::MASTER_CLIENT)
+                .with_interest_group(0)
+                .target()
+                .to_string(),
+            "master client"
+        );
+    }
+
+    #[test]
+    fn change_groups() {
+        // leaving all groups and joining groups 1 and 2
+        let hex = "f302f80002ef7800000000ee78000000020102";
+        let bytes = hex::decode(hex).unwrap();
+        let mut request = match PhotonMessage::from_websocket_bytes(&mut bytes.as_slice()).unwrap()
+        {
+            PhotonMessage::OperationRequest(r) => r,
+            m => panic!("expected operation request, found {m:?}"),
+        };
+        assert_eq!(request.operation_code, operation_code::CHANGE_GROUPS);
+
+        let change = ChangeGroupsRequest::from_map(&mut request.parameters).unwrap();
+        assert_eq!(
+            change,
+            ChangeGroupsRequest {
+                remove: Some(vec![]),
+                add: Some(vec![1, 2]),
+            }
+        );
+
+        let mut parameters = IndexMap::new();
+        change.into_map(&mut parameters);
+        let mut buf = vec![];
+        PhotonMessage::OperationRequest(OperationRequest {
+            operation_code: operation_code::CHANGE_GROUPS,
+            parameters,
+        })
+        .to_websocket_bytes(&mut buf)
+        .unwrap();
+        assert_eq!(hex::encode(buf), hex);
+    }
+
+    #[test]
+    fn get_properties() {
+        let mut parameters = IndexMap::new();
+        GetPropertiesRequest {
+            properties_type: property_type_flag::ACTOR,
+            actor_list: Some(vec![1, 4]),
+        }
+        .into_map(&mut parameters);
+        assert_eq!(
+            parameters,
+            indexmap! {
+                parameter_code::PROPERTIES => PhotonDataType::Byte(2),
+                parameter_code::ACTOR_LIST => PhotonDataType::IntArray(vec![1, 4]),
+            }
+        );
+
+        let request = GetPropertiesRequest::from_map(&mut parameters).unwrap();
+        assert_eq!(request.properties_type, property_type_flag::ACTOR);
+        assert_eq!(request.actor_list, Some(vec![1, 4]));
+    }
 }
@@ -1,4 +1,6 @@
-use super::{structs::*, FromMapError, PhotonMapConversion};
+use std::fmt::Display;
+
+use super::{constants::receiver_group, structs::*, FromMapError, PhotonMapConversion};
 use crate::{photon_data_type::PhotonDataType, PhotonHashmap};
 
 const PHOTON_NETWORK_MAX_VIEW_IDS: i32 = 1000;
@@ -16,6 +18,85 @@ impl RpcEvent {
     }
 }
 
+impl RaiseEvent {
+    /// Creates an event that is sent to all other players in the room, without caching.
+    pub fn new(event_code: u8, data: PhotonDataType) -> Self {
+        Self {
+            event_code,
+            data: Some(data),
+            cache: None,
+            cache_slice_index: None,
+            receiver_group: None,
+            interest_group: None,
+            actor_list: None,
+            event_forward: None,
+        }
+    }
+
+    /// Sets the caching option, see [event_caching](super::constants::event_caching).
+    pub fn with_cache(mut self, cache: u8) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Sets the cache slice to use with the `SLICE_*` caching options.
+    pub fn with_cache_slice_index(mut self, index: i32) -> Self {
+        self.cache_slice_index = Some(index);
+        self
+    }
+
+    /// Sets the receivers, see [receiver_group](super::constants::receiver_group).
+    pub fn with_receiver_group(mut self, receiver_group: u8) -> Self {
+        self.receiver_group = Some(receiver_group);
+        self
+    }
+
+    pub fn with_interest_group(mut self, interest_group: u8) -> Self {
+        self.interest_group = Some(interest_group);
+        self
+    }
+
+    /// Only sends the event to the given actors.
+    pub fn with_target_actors(mut self, actors: Vec<i32>) -> Self {
+        self.actor_list = Some(actors);
+        self
+    }
+
+    /// Who the server will deliver this event to.
+    pub fn target(&self) -> EventTarget {
+        match (&self.actor_list, self.interest_group) {
+            (Some(actors), _) => EventTarget::Actors(actors.clone()),
+            (None, Some(group)) if group != 0 => EventTarget::InterestGroup(group),
+            _ => EventTarget::ReceiverGroup(self.receiver_group.unwrap_or(receiver_group::OTHERS)),
+        }
+    }
+}
+
+/// The receivers of a [RaiseEvent]. Target actors take precedence over the interest group, which takes precedence
+/// over the receiver group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventTarget {
+    /// Only these actors.
+    Actors(Vec<i32>),
+    /// The actors subscribed to this interest group.
+    InterestGroup(u8),
+    /// One of [receiver_group].
+    ReceiverGroup(u8),
+}
+
+impl Display for EventTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventTarget::Actors(actors) => write!(f, "actors {actors:?}"),
+            EventTarget::InterestGroup(group) => write!(f, "interest group {group}"),
+            EventTarget::ReceiverGroup(receiver_group::OTHERS) => write!(f, "others"),
+            EventTarget::ReceiverGroup(receiver_group::ALL) => write!(f, "all"),
+            EventTarget::ReceiverGroup(receiver_group::MASTER_CLIENT) => write!(f, "master client"),
+            EventTarget::ReceiverGroup(group) => write!(f, "receiver group {group}"),
+        }
+    }
+}
+
 impl SendSerializeEvent {
     // TODO: proper error type. probably want something generic like InvalidDataError
     /// Gets a copy of the serialized data in this event
@@ -23,6 +104,18 @@ impl SendSerializeEvent {
         Self::parse_serialized_data(&self.data)
     }
 
+    /// Gets the server timestamp at which the data was serialized, in milliseconds.
+    pub fn get_server_timestamp(&self) -> Option<i32> {
+        Self::parse_server_timestamp(&self.data)
+    }
+
+    pub fn parse_server_timestamp(data: &PhotonHashmap) -> Option<i32> {
+        match data.get(&PhotonDataType::Byte(0)) {
+            Some(PhotonDataType::Integer(timestamp)) => Some(*timestamp),
+            _ => None,
+        }
+    }
+
     pub fn parse_serialized_data(data: &PhotonHashmap) -> Option<Vec<SerializedData>> {
         _ = data.get(&PhotonDataType::Byte(1))?;
 
@@ -104,3 +197,10 @@ impl RpcCall {
         ViewId(self.net_view_id)
     }
 }
+
+impl GetRegionsResponse {
+    /// Iterates over pairs of region codes and their master server addresses.
+    pub fn iter_regions(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.regions.iter().zip(self.addresses.iter())
+    }
+}
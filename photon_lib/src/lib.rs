@@ -1,10 +1,14 @@
 //! This library aims to help with parsing Photon Unity Networking v1.99 network packets. Other versions may work but
 //! are unsupported.
 
+#[cfg(feature = "annotate")]
+pub mod annotate;
+pub mod display;
 pub mod highlevel;
 pub mod photon_data_type;
 pub mod photon_message;
 pub mod primitives;
+pub mod protocol_version;
 pub mod utils;
 
 pub use indexmap;
@@ -30,6 +34,17 @@ pub type PhotonHashmap = IndexMap<PhotonDataType, PhotonDataType>;
 /// An alias for the parameter hashmap used in photon messages.
 pub type ParameterMap = IndexMap<u8, PhotonDataType>;
 
+/// How parsing treats data types it doesn't know.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// An unknown data type fails the whole parse.
+    #[default]
+    Strict,
+    /// An unknown data type is kept as [PhotonDataType::Unknown] if its bytes can be told apart from what follows,
+    /// see there.
+    Lenient,
+}
+
 /// An error that can occur when parsing a message
 #[derive(Debug, Error)]
 pub enum ReadError {
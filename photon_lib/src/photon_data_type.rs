@@ -15,7 +15,7 @@ use crate::{
     check_remaining,
     photon_message::{EventData, OperationRequest, OperationResponse},
     primitives::*,
-    PhotonHashmap, ReadError, WriteError,
+    ParameterMap, ParseMode, PhotonHashmap, ReadError, WriteError,
 };
 
 /// A serialized .NET object
@@ -68,19 +68,41 @@ pub enum PhotonDataType {
     Array(Vec<PhotonDataType>),
     /// Data type 0x7A, holds an `object[]`
     ObjectArray(Vec<PhotonDataType>),
+    /// A data type this library doesn't know, only read in [ParseMode::Lenient]. Written back as it was read.
+    ///
+    /// Containers only store how many elements they hold, not how many bytes, so the end of an unknown value can't be
+    /// found by itself. It is only read if nothing follows it: it must be the last element of every container it's in
+    /// and the last value of the message. It then holds all remaining bytes. Unknown data anywhere else still fails
+    /// to parse, as whatever follows it can't be found either.
+    Unknown { type_code: u8, raw_bytes: Vec<u8> },
 }
 
 impl PhotonDataType {
     pub fn from_bytes(bytes: &mut impl Buf) -> Result<PhotonDataType, ReadError> {
+        Self::from_bytes_with_mode(bytes, ParseMode::Strict)
+    }
+
+    pub fn from_bytes_with_mode(
+        bytes: &mut impl Buf,
+        mode: ParseMode,
+    ) -> Result<PhotonDataType, ReadError> {
         check_remaining!(bytes, 1);
 
         let data_type = bytes.get_u8();
-        Self::from_bytes_with_type(bytes, data_type)
+        Self::read_with_type(bytes, data_type, mode)
     }
 
     pub fn from_bytes_with_type(
         bytes: &mut impl Buf,
         data_type: u8,
+    ) -> Result<PhotonDataType, ReadError> {
+        Self::read_with_type(bytes, data_type, ParseMode::Strict)
+    }
+
+    fn read_with_type(
+        bytes: &mut impl Buf,
+        data_type: u8,
+        mode: ParseMode,
     ) -> Result<PhotonDataType, ReadError> {
         match data_type {
             0 | 0x2A => Ok(PhotonDataType::Null),
@@ -97,12 +119,12 @@ impl PhotonDataType {
                 let mut map = indexmap::IndexMap::new();
                 for _ in 0..len {
                     let key = match read_key {
-                        true => Self::from_bytes(bytes)?,
-                        false => Self::from_bytes_with_type(bytes, key_type)?,
+                        true => Self::from_bytes_with_mode(bytes, mode)?,
+                        false => Self::read_with_type(bytes, key_type, mode)?,
                     };
                     let val = match read_val {
-                        true => Self::from_bytes(bytes)?,
-                        false => Self::from_bytes_with_type(bytes, val_type)?,
+                        true => Self::from_bytes_with_mode(bytes, mode)?,
+                        false => Self::read_with_type(bytes, val_type, mode)?,
                     };
 
                     if key != PhotonDataType::Null {
@@ -138,7 +160,7 @@ impl PhotonDataType {
                 check_remaining!(bytes, 8);
                 Ok(PhotonDataType::Double(bytes.get_f64().into()))
             }
-            0x65 => Ok(PhotonDataType::EventData(EventData::from_bytes(bytes)?)),
+            0x65 => Ok(PhotonDataType::EventData(EventData::read(bytes, mode)?)),
             0x66 => {
                 check_remaining!(bytes, 4);
                 Ok(PhotonDataType::Float(bytes.get_f32().into()))
@@ -150,8 +172,8 @@ impl PhotonDataType {
 
                 let mut map = indexmap::IndexMap::new();
                 for _ in 0..len {
-                    let key = Self::from_bytes(bytes)?;
-                    let val = Self::from_bytes(bytes)?;
+                    let key = Self::from_bytes_with_mode(bytes, mode)?;
+                    let val = Self::from_bytes_with_mode(bytes, mode)?;
 
                     if key != PhotonDataType::Null {
                         map.insert(key, val);
@@ -191,12 +213,12 @@ impl PhotonDataType {
                 check_remaining!(bytes, 1);
                 Ok(PhotonDataType::Boolean(bytes.get_u8() != 0))
             }
-            0x70 => Ok(PhotonDataType::OperationResponse(
-                OperationResponse::from_bytes(bytes)?,
-            )),
-            0x71 => Ok(PhotonDataType::OperationRequest(
-                OperationRequest::from_bytes(bytes)?,
-            )),
+            0x70 => Ok(PhotonDataType::OperationResponse(OperationResponse::read(
+                bytes, mode,
+            )?)),
+            0x71 => Ok(PhotonDataType::OperationRequest(OperationRequest::read(
+                bytes, mode,
+            )?)),
             0x73 => {
                 check_remaining!(bytes, 2);
                 let len = bytes.get_i16();
@@ -242,7 +264,7 @@ impl PhotonDataType {
                     let mut vec = Vec::with_capacity(len as usize);
 
                     for _ in 0..len {
-                        vec.push(Self::from_bytes_with_type(bytes, data_type)?);
+                        vec.push(Self::read_with_type(bytes, data_type, mode)?);
                     }
 
                     vec
@@ -262,11 +284,18 @@ impl PhotonDataType {
 
                 let mut v = Vec::with_capacity(len as usize);
                 for _ in 0..len {
-                    v.push(Self::from_bytes(bytes)?);
+                    v.push(Self::from_bytes_with_mode(bytes, mode)?);
                 }
 
                 Ok(PhotonDataType::ObjectArray(v))
             }
+            // nothing left means something was supposed to follow, see Unknown
+            _ if mode == ParseMode::Lenient && bytes.has_remaining() => {
+                Ok(PhotonDataType::Unknown {
+                    type_code: data_type,
+                    raw_bytes: bytes.copy_to_bytes(bytes.remaining()).to_vec(),
+                })
+            }
             _ => Err(ReadError::UnknownDataType(data_type)),
         }
     }
@@ -404,11 +433,30 @@ impl PhotonDataType {
                     item.to_bytes(buf)?;
                 }
             }
+            PhotonDataType::Unknown { raw_bytes, .. } => buf.put_slice(raw_bytes),
         }
 
         Ok(())
     }
 
+    /// How many [Unknown](PhotonDataType::Unknown) values this holds, including itself.
+    pub fn unknown_count(&self) -> usize {
+        match self {
+            PhotonDataType::Unknown { .. } => 1,
+            PhotonDataType::Dictionary(_, map) | PhotonDataType::Hashtable(map) => map
+                .iter()
+                .map(|(k, v)| k.unknown_count() + v.unknown_count())
+                .sum(),
+            PhotonDataType::Array(v) | PhotonDataType::ObjectArray(v) => {
+                v.iter().map(PhotonDataType::unknown_count).sum()
+            }
+            PhotonDataType::EventData(e) => parameters_unknown_count(&e.parameters),
+            PhotonDataType::OperationRequest(r) => parameters_unknown_count(&r.parameters),
+            PhotonDataType::OperationResponse(r) => parameters_unknown_count(&r.parameters),
+            _ => 0,
+        }
+    }
+
     pub fn get_type_byte(&self) -> u8 {
         match self {
             PhotonDataType::Null => 0x2A,
@@ -431,10 +479,15 @@ impl PhotonDataType {
             PhotonDataType::ByteArray(_) => 0x78,
             PhotonDataType::Array(_) => 0x79,
             PhotonDataType::ObjectArray(_) => 0x7A,
+            PhotonDataType::Unknown { type_code, .. } => *type_code,
         }
     }
 }
 
+pub(crate) fn parameters_unknown_count(parameters: &ParameterMap) -> usize {
+    parameters.values().map(PhotonDataType::unknown_count).sum()
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum CustomData {
     Vector2(Vector2),
@@ -10,7 +10,9 @@ use derivative::Derivative;
 use indexmap::IndexMap;
 
 use crate::{
-    check_remaining, photon_data_type::PhotonDataType, ParameterMap, ReadError, WriteError,
+    check_remaining,
+    photon_data_type::{parameters_unknown_count, PhotonDataType},
+    ParameterMap, ParseMode, ReadError, WriteError,
 };
 
 /// Describes a low-level message that comes in or goes out over the wire.
@@ -40,10 +42,35 @@ pub enum PhotonMessage {
     RawMessage(Vec<u8>),
     /// S->C message with magic number 0xF0, the client will calculate roundtrip time and server time offset.
     PingResult(PingResult),
+    /// A message with [ENCRYPTED_FLAG] set in its type byte. We don't have the key, so the payload is kept as-is.
+    ///
+    /// Note that the websocket framing has no channel or reliability flags, Photon only sends those in the headers of
+    /// its TCP and UDP framing. The encryption flag is the only flag there is.
+    Encrypted(EncryptedMessage),
+}
+
+/// The bit in the message type byte that indicates the rest of the message is encrypted.
+pub const ENCRYPTED_FLAG: u8 = 0x80;
+
+/// An encrypted message, see [PhotonMessage::Encrypted].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptedMessage {
+    /// The message type, without [ENCRYPTED_FLAG].
+    pub message_type: u8,
+    /// Everything after the type byte.
+    pub payload: Vec<u8>,
 }
 
 impl PhotonMessage {
     pub fn from_websocket_bytes(data: &mut impl Buf) -> Result<PhotonMessage, ReadError> {
+        Self::from_websocket_bytes_with_mode(data, ParseMode::Strict)
+    }
+
+    /// Parses a message, keeping the data types this library doesn't know if `mode` is [ParseMode::Lenient].
+    pub fn from_websocket_bytes_with_mode(
+        data: &mut impl Buf,
+        mode: ParseMode,
+    ) -> Result<PhotonMessage, ReadError> {
         if data.remaining() < 1 {
             return Err(ReadError::NotEnoughBytesLeft);
         }
@@ -55,7 +82,7 @@ impl PhotonMessage {
                 // photon checks if for `msg_type == 7 && op_code == 1 (ping)` and immediately handles the message if true
                 // we dont need to do that, however
 
-                Ok(PhotonMessage::from_bytes_f3(data)?)
+                Ok(PhotonMessage::from_bytes_f3(data, mode)?)
             }
             0xF0 => Ok(PhotonMessage::PingResult(PingResult::from_bytes(data)?)),
             _ => Err(ReadError::InvalidMagicNumber(magic_number)),
@@ -63,18 +90,21 @@ impl PhotonMessage {
     }
 
     /// parse a message that uses magic number 0xF3
-    fn from_bytes_f3(data: &mut impl Buf) -> Result<Self, ReadError> {
+    fn from_bytes_f3(data: &mut impl Buf, mode: ParseMode) -> Result<Self, ReadError> {
         if data.remaining() < 2 {
             return Err(ReadError::NotEnoughBytesLeft);
         }
 
         let (msg_type, is_encrypted) = {
             let msg_byte = data.get_u8();
-            (msg_byte & 0x7F, (msg_byte & 0x80) > 0)
+            (msg_byte & !ENCRYPTED_FLAG, (msg_byte & ENCRYPTED_FLAG) > 0)
         };
 
         if is_encrypted {
-            return Err(ReadError::Unimplemented("encryption"));
+            return Ok(PhotonMessage::Encrypted(EncryptedMessage {
+                message_type: msg_type,
+                payload: data.copy_to_bytes(data.remaining()).to_vec(),
+            }));
         }
 
         match msg_type {
@@ -82,23 +112,25 @@ impl PhotonMessage {
                 _ = data.get_u8();
                 Ok(PhotonMessage::InitResponse)
             }
-            2 => Ok(PhotonMessage::OperationRequest(
-                OperationRequest::from_bytes(data)?,
-            )),
-            3 => Ok(PhotonMessage::OperationResponse(
-                OperationResponse::from_bytes(data)?,
-            )),
-            4 => Ok(PhotonMessage::EventData(EventData::from_bytes(data)?)),
-            5 => Ok(PhotonMessage::DisconnectMessage(
-                DisconnectMessage::from_bytes(data)?,
-            )),
+            2 => Ok(PhotonMessage::OperationRequest(OperationRequest::read(
+                data, mode,
+            )?)),
+            3 => Ok(PhotonMessage::OperationResponse(OperationResponse::read(
+                data, mode,
+            )?)),
+            4 => Ok(PhotonMessage::EventData(EventData::read(data, mode)?)),
+            5 => Ok(PhotonMessage::DisconnectMessage(DisconnectMessage::read(
+                data, mode,
+            )?)),
             6 => Ok(PhotonMessage::InternalOperationRequest(
-                OperationRequest::from_bytes(data)?,
+                OperationRequest::read(data, mode)?,
             )),
             7 => Ok(PhotonMessage::InternalOperationResponse(
-                OperationResponse::from_bytes(data)?,
+                OperationResponse::read(data, mode)?,
+            )),
+            8 => Ok(PhotonMessage::Message(
+                PhotonDataType::from_bytes_with_mode(data, mode)?,
             )),
-            8 => Ok(PhotonMessage::Message(PhotonDataType::from_bytes(data)?)),
             9 => Ok(PhotonMessage::RawMessage(
                 data.copy_to_bytes(data.remaining()).to_vec(),
             )),
@@ -111,7 +143,7 @@ impl PhotonMessage {
             debug_assert!(!matches!(self, PhotonMessage::PingResult(_)));
 
             buf.put_u8(0xF3); // magic byte
-            buf.put_u8(type_byte); // message type | is_encrypted
+            buf.put_u8(type_byte); // message type | ENCRYPTED_FLAG
             self.to_bytes_without_type_byte(buf)?;
         } else {
             debug_assert!(matches!(self, PhotonMessage::PingResult(_)));
@@ -143,6 +175,7 @@ impl PhotonMessage {
                 buf.put_slice(x);
             }
             PhotonMessage::PingResult(x) => x.to_bytes(buf)?,
+            PhotonMessage::Encrypted(x) => buf.put_slice(&x.payload),
         }
 
         Ok(())
@@ -161,6 +194,33 @@ impl PhotonMessage {
             PhotonMessage::Message(_) => Some(8),
             PhotonMessage::RawMessage(_) => Some(9),
             PhotonMessage::PingResult(_) => None,
+            PhotonMessage::Encrypted(x) => Some(x.message_type | ENCRYPTED_FLAG),
+        }
+    }
+
+    /// Whether the message was sent encrypted, see [PhotonMessage::Encrypted].
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, PhotonMessage::Encrypted(_))
+    }
+
+    /// Whether raw websocket bytes hold an encrypted message, checked without parsing them.
+    pub fn is_encrypted_websocket_bytes(data: &[u8]) -> bool {
+        matches!(data, [0xF3, type_byte, ..] if type_byte & ENCRYPTED_FLAG != 0)
+    }
+
+    /// How many [Unknown](PhotonDataType::Unknown) values the message holds, see [ParseMode::Lenient].
+    pub fn unknown_count(&self) -> usize {
+        match self {
+            PhotonMessage::OperationRequest(r) | PhotonMessage::InternalOperationRequest(r) => {
+                parameters_unknown_count(&r.parameters)
+            }
+            PhotonMessage::OperationResponse(r) | PhotonMessage::InternalOperationResponse(r) => {
+                parameters_unknown_count(&r.parameters)
+            }
+            PhotonMessage::EventData(e) => parameters_unknown_count(&e.parameters),
+            PhotonMessage::DisconnectMessage(d) => parameters_unknown_count(&d.parameters),
+            PhotonMessage::Message(data) => data.unknown_count(),
+            _ => 0,
         }
     }
 }
@@ -185,6 +245,16 @@ impl PingResult {
         buf.put_i32(self.client_sent_time);
         Ok(())
     }
+
+    /// The timestamp of the server when it sent this response.
+    pub fn server_sent_time(&self) -> i32 {
+        self.server_sent_time
+    }
+
+    /// The timestamp of the client when it sent the ping this message is a response to.
+    pub fn client_sent_time(&self) -> i32 {
+        self.client_sent_time
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Derivative)]
@@ -197,10 +267,14 @@ pub struct OperationRequest {
 
 impl OperationRequest {
     pub fn from_bytes(bytes: &mut impl Buf) -> Result<Self, ReadError> {
+        Self::read(bytes, ParseMode::Strict)
+    }
+
+    pub(crate) fn read(bytes: &mut impl Buf, mode: ParseMode) -> Result<Self, ReadError> {
         check_remaining!(bytes, 1);
         let operation_code = bytes.get_u8();
 
-        let parameters = deserialize_parameter_dictionary(bytes)?;
+        let parameters = deserialize_parameter_dictionary(bytes, mode)?;
         Ok(Self {
             operation_code,
             parameters,
@@ -226,6 +300,10 @@ pub struct OperationResponse {
 
 impl OperationResponse {
     pub fn from_bytes(bytes: &mut impl Buf) -> Result<Self, ReadError> {
+        Self::read(bytes, ParseMode::Strict)
+    }
+
+    pub(crate) fn read(bytes: &mut impl Buf, mode: ParseMode) -> Result<Self, ReadError> {
         check_remaining!(bytes, 3);
         let operation_code = bytes.get_u8();
         let return_code = bytes.get_i16();
@@ -239,7 +317,7 @@ impl OperationResponse {
             }
         };
 
-        let parameters = deserialize_parameter_dictionary(bytes)?;
+        let parameters = deserialize_parameter_dictionary(bytes, mode)?;
         Ok(Self {
             operation_code,
             return_code,
@@ -273,10 +351,14 @@ pub struct EventData {
 
 impl EventData {
     pub fn from_bytes(bytes: &mut impl Buf) -> Result<Self, ReadError> {
+        Self::read(bytes, ParseMode::Strict)
+    }
+
+    pub(crate) fn read(bytes: &mut impl Buf, mode: ParseMode) -> Result<Self, ReadError> {
         check_remaining!(bytes, 1);
         let code = bytes.get_u8();
 
-        let parameters = deserialize_parameter_dictionary(bytes)?;
+        let parameters = deserialize_parameter_dictionary(bytes, mode)?;
         Ok(Self { code, parameters })
     }
 
@@ -298,6 +380,10 @@ pub struct DisconnectMessage {
 
 impl DisconnectMessage {
     pub fn from_bytes(bytes: &mut impl Buf) -> Result<Self, ReadError> {
+        Self::read(bytes, ParseMode::Strict)
+    }
+
+    pub(crate) fn read(bytes: &mut impl Buf, mode: ParseMode) -> Result<Self, ReadError> {
         check_remaining!(bytes, 2);
         let code = bytes.get_i16();
         let debug_message = match PhotonDataType::from_bytes(bytes)? {
@@ -310,7 +396,7 @@ impl DisconnectMessage {
             }
         };
 
-        let parameters = deserialize_parameter_dictionary(bytes)?;
+        let parameters = deserialize_parameter_dictionary(bytes, mode)?;
         Ok(Self {
             code,
             debug_message,
@@ -331,13 +417,19 @@ impl DisconnectMessage {
     }
 }
 
-fn deserialize_parameter_dictionary(bytes: &mut impl Buf) -> Result<ParameterMap, ReadError> {
+fn deserialize_parameter_dictionary(
+    bytes: &mut impl Buf,
+    mode: ParseMode,
+) -> Result<ParameterMap, ReadError> {
     check_remaining!(bytes, 2);
     let params_count = bytes.get_i16();
     let mut parameters = IndexMap::with_capacity(params_count as usize);
     for _ in 0..params_count {
         check_remaining!(bytes, 1);
-        parameters.insert(bytes.get_u8(), PhotonDataType::from_bytes(bytes)?);
+        parameters.insert(
+            bytes.get_u8(),
+            PhotonDataType::from_bytes_with_mode(bytes, mode)?,
+        );
     }
     Ok(parameters)
 }
@@ -365,7 +457,7 @@ mod tests {
     use indexmap::indexmap;
 
     use super::PhotonMessage;
-    use crate::{photon_data_type::PhotonDataType, photon_message::*};
+    use crate::{photon_data_type::PhotonDataType, photon_message::*, ParseMode, ReadError};
 
     macro_rules! test_message {
         ($name:ident, $hex:literal, $val:expr) => {
@@ -459,4 +551,103 @@ mod tests {
             }
         })
     );
+
+    // an encrypted operation request, as sent for the token exchange
+    test_message!(
+        encrypted,
+        "f382a1b2c3d4",
+        PhotonMessage::Encrypted(EncryptedMessage {
+            message_type: 2,
+            payload: vec![0xa1, 0xb2, 0xc3, 0xd4],
+        })
+    );
+
+    /// An event whose last parameter is a hashtable holding a value of the unknown type 0x99 last.
+    const TRAILING_UNKNOWN: &str = "f304c80002016900000005f56800027300016169000000016201990a0b0c";
+
+    #[test]
+    fn strict_parsing_rejects_unknown_types() {
+        let bytes = hex::decode(TRAILING_UNKNOWN).unwrap();
+        assert!(matches!(
+            PhotonMessage::from_websocket_bytes(&mut bytes.as_slice()),
+            Err(ReadError::UnknownDataType(0x99))
+        ));
+    }
+
+    #[test]
+    fn lenient_parsing_keeps_trailing_unknown_types() {
+        let bytes = hex::decode(TRAILING_UNKNOWN).unwrap();
+        let message = PhotonMessage::from_websocket_bytes_with_mode(
+            &mut bytes.as_slice(),
+            ParseMode::Lenient,
+        )
+        .unwrap();
+        assert_eq!(
+            message,
+            PhotonMessage::EventData(EventData {
+                code: 0xc8,
+                parameters: indexmap! {
+                    0x01 => PhotonDataType::Integer(5),
+                    0xf5 => PhotonDataType::Hashtable(indexmap! {
+                        PhotonDataType::String("a".into()) => PhotonDataType::Integer(1),
+                        PhotonDataType::Byte(1) => PhotonDataType::Unknown {
+                            type_code: 0x99,
+                            raw_bytes: vec![0x0a, 0x0b, 0x0c],
+                        },
+                    }),
+                }
+            })
+        );
+        assert_eq!(message.unknown_count(), 1);
+
+        // written back byte for byte
+        let mut written = vec![];
+        message.to_websocket_bytes(&mut written).unwrap();
+        assert_eq!(written, bytes);
+    }
+
+    #[test]
+    fn lenient_parsing_rejects_unframeable_data() {
+        // the unknown value is followed by another parameter, so its end can't be found
+        let followed = hex::decode("f304c800020199aabb026900000005").unwrap();
+        assert!(matches!(
+            PhotonMessage::from_websocket_bytes_with_mode(
+                &mut followed.as_slice(),
+                ParseMode::Lenient
+            ),
+            Err(ReadError::NotEnoughBytesLeft)
+        ));
+        // the message ends right after the type code
+        let truncated = hex::decode("f304c800010199").unwrap();
+        assert!(matches!(
+            PhotonMessage::from_websocket_bytes_with_mode(
+                &mut truncated.as_slice(),
+                ParseMode::Lenient
+            ),
+            Err(ReadError::UnknownDataType(0x99))
+        ));
+        // known types parse the same in both modes
+        let known = hex::decode("f304e20003e36900000011e5690000006ee46900000016").unwrap();
+        let lenient = PhotonMessage::from_websocket_bytes_with_mode(
+            &mut known.as_slice(),
+            ParseMode::Lenient,
+        )
+        .unwrap();
+        assert_eq!(
+            lenient,
+            PhotonMessage::from_websocket_bytes(&mut known.as_slice()).unwrap()
+        );
+        assert_eq!(lenient.unknown_count(), 0);
+    }
+
+    #[test]
+    fn detect_encrypted_bytes() {
+        let encrypted = hex::decode("f382a1b2c3d4").unwrap();
+        assert!(PhotonMessage::is_encrypted_websocket_bytes(&encrypted));
+
+        for plain in ["f30100", "f3020100", "f0000000010000000200", "f3", ""] {
+            let plain = hex::decode(plain).unwrap();
+            assert!(!PhotonMessage::is_encrypted_websocket_bytes(&plain));
+        }
+    }
 }
@@ -0,0 +1,105 @@
+//! The serialization protocols a Photon client and server can agree on.
+//!
+//! Over websockets the protocol isn't negotiated in an [Init](crate::photon_message::PhotonMessage::Init) message like
+//! over UDP and TCP. The client names the protocols it can speak as websocket subprotocols in the
+//! `Sec-WebSocket-Protocol` header of its upgrade request, and the server picks one in the same header of its
+//! response. Everything sent afterwards is serialized with that protocol.
+//!
+//! This library only parses [ProtocolVersion::GpBinaryV16].
+
+use std::{fmt::Display, str::FromStr};
+
+use thiserror::Error;
+
+/// A serialization protocol, see [protocol_version](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProtocolVersion {
+    /// Protocol 1.6, used by PUN 1.
+    GpBinaryV16,
+    /// Protocol 1.8, used by PUN 2.
+    GpBinaryV18,
+}
+
+impl ProtocolVersion {
+    pub const ALL: [ProtocolVersion; 2] =
+        [ProtocolVersion::GpBinaryV16, ProtocolVersion::GpBinaryV18];
+
+    /// The name of the protocol as a websocket subprotocol.
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            ProtocolVersion::GpBinaryV16 => "GpBinaryV16",
+            ProtocolVersion::GpBinaryV18 => "GpBinaryV18",
+        }
+    }
+
+    /// The protocol a websocket subprotocol stands for, if it is one of Photon's.
+    pub fn from_subprotocol(subprotocol: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|v| v.subprotocol().eq_ignore_ascii_case(subprotocol.trim()))
+    }
+
+    /// Whether this library can parse messages serialized with the protocol.
+    pub fn is_parsed(self) -> bool {
+        self == ProtocolVersion::GpBinaryV16
+    }
+}
+
+/// The subprotocols listed in a `Sec-WebSocket-Protocol` header, in the order they were listed.
+pub fn subprotocols(header: &str) -> impl Iterator<Item = &str> {
+    header.split(',').map(str::trim).filter(|s| !s.is_empty())
+}
+
+impl Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolVersion::GpBinaryV16 => write!(f, "1.6"),
+            ProtocolVersion::GpBinaryV18 => write!(f, "1.8"),
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("unknown protocol version {0:?}, expected 1.6 or 1.8")]
+pub struct UnknownProtocolVersion(String);
+
+impl FromStr for ProtocolVersion {
+    type Err = UnknownProtocolVersion;
+
+    /// Parses a version as it is [displayed](Display), or as a websocket subprotocol.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "1.6" | "16" => Ok(ProtocolVersion::GpBinaryV16),
+            "1.8" | "18" => Ok(ProtocolVersion::GpBinaryV18),
+            other => Self::from_subprotocol(other).ok_or_else(|| UnknownProtocolVersion(s.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{subprotocols, ProtocolVersion};
+
+    #[test]
+    fn parse_versions() {
+        for version in ProtocolVersion::ALL {
+            assert_eq!(version.to_string().parse(), Ok(version));
+            assert_eq!(version.subprotocol().parse(), Ok(version));
+        }
+        assert_eq!(
+            ProtocolVersion::from_subprotocol(" gpbinaryv18"),
+            Some(ProtocolVersion::GpBinaryV18)
+        );
+        assert!("1.7".parse::<ProtocolVersion>().is_err());
+        assert_eq!(ProtocolVersion::from_subprotocol("chat"), None);
+    }
+
+    #[test]
+    fn split_header() {
+        assert_eq!(
+            subprotocols("GpBinaryV18, GpBinaryV16,,").collect::<Vec<_>>(),
+            ["GpBinaryV18", "GpBinaryV16"]
+        );
+        assert_eq!(subprotocols(" ").count(), 0);
+    }
+}